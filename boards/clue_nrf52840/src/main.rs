@@ -73,6 +73,10 @@ const ST7789H2_CS: Pin = Pin::P0_12;
 const ST7789H2_DC: Pin = Pin::P0_13;
 const ST7789H2_RESET: Pin = Pin::P1_03;
 
+/// PDM microphone
+const MIC_DATA: Pin = Pin::P0_00;
+const MIC_CLK: Pin = Pin::P0_01;
+
 /// TFT backlight
 const _ST7789H2_LITE: Pin = Pin::P1_05;
 
@@ -130,6 +134,7 @@ pub struct Platform {
     >,
     temperature: &'static capsules::temperature::TemperatureSensor<'static>,
     humidity: &'static capsules::humidity::HumiditySensor<'static>,
+    pcm: &'static capsules::pcm::PcmDriver<'static>,
 }
 
 impl kernel::Platform for Platform {
@@ -152,6 +157,7 @@ impl kernel::Platform for Platform {
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temperature)),
             capsules::humidity::DRIVER_NUM => f(Some(self.humidity)),
+            capsules::pcm::DRIVER_NUM => f(Some(self.pcm)),
             _ => f(None),
         }
     }
@@ -414,6 +420,16 @@ pub unsafe fn reset_handler() {
 
     let humidity = components::humidity::HumidityComponent::new(board_kernel, sht3x).finalize(());
 
+    //--------------------------------------------------------------------------
+    // MICROPHONE
+    //--------------------------------------------------------------------------
+
+    base_peripherals.pdm.set_pins(
+        nrf52840::pinmux::Pinmux::new(MIC_CLK as u32),
+        nrf52840::pinmux::Pinmux::new(MIC_DATA as u32),
+    );
+    let pcm = components::pcm::PcmComponent::new(board_kernel, &base_peripherals.pdm).finalize(());
+
     //--------------------------------------------------------------------------
     // TFT
     //--------------------------------------------------------------------------
@@ -524,6 +540,7 @@ pub unsafe fn reset_handler() {
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
         temperature: temperature,
         humidity: humidity,
+        pcm: pcm,
     };
 
     let chip = static_init!(
@@ -0,0 +1,88 @@
+//! Components for the CAN userspace driver and for multiplexed access to a
+//! CAN controller.
+//!
+//! `CanMuxComponent` shares one controller between several users and
+//! `CanDriverComponent` provides the userspace syscall interface on a
+//! virtual device of that controller.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let can_mux = components::can::CanMuxComponent::new(&base_peripherals.can1).finalize(());
+//! let can = components::can::CanDriverComponent::new(board_kernel, can_mux).finalize(());
+//! ```
+
+use capsules::can::CanDriver;
+use capsules::virtual_can::{CanDevice, MuxCan};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::can;
+use kernel::static_init;
+
+pub struct CanMuxComponent {
+    can: &'static dyn can::Can<'static>,
+}
+
+impl CanMuxComponent {
+    pub fn new(can: &'static dyn can::Can<'static>) -> CanMuxComponent {
+        CanMuxComponent { can }
+    }
+}
+
+impl Component for CanMuxComponent {
+    type StaticInput = ();
+    type Output = &'static MuxCan<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let can_mux = static_init!(MuxCan<'static>, MuxCan::new(self.can));
+        self.can.set_transmit_client(can_mux);
+        self.can.set_receive_client(can_mux);
+        self.can.set_state_client(can_mux);
+
+        can_mux
+    }
+}
+
+pub struct CanDriverComponent {
+    board_kernel: &'static kernel::Kernel,
+    can_mux: &'static MuxCan<'static>,
+}
+
+impl CanDriverComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        can_mux: &'static MuxCan<'static>,
+    ) -> CanDriverComponent {
+        CanDriverComponent {
+            board_kernel,
+            can_mux,
+        }
+    }
+}
+
+impl Component for CanDriverComponent {
+    type StaticInput = ();
+    type Output = &'static CanDriver<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let can_device = static_init!(CanDevice<'static>, CanDevice::new(self.can_mux));
+        can_device.setup();
+
+        let can_driver = static_init!(
+            CanDriver<'static>,
+            CanDriver::new(
+                can_device,
+                &mut capsules::can::BUFFER,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        can::Can::set_transmit_client(can_device, can_driver);
+        can::Can::set_receive_client(can_device, can_driver);
+        can::Can::set_state_client(can_device, can_driver);
+
+        can_driver
+    }
+}
@@ -1,7 +1,9 @@
 //! Component for CDC-ACM over USB support.
 //!
 //! This provides a component for using the CDC-ACM driver. This allows for
-//! serial communication over USB.
+//! serial communication over USB. `CdcAcmComponent` creates a device with a
+//! single port, and `CdcAcmPortComponent` creates a port to add to a
+//! composite device, which can have several.
 //!
 //! Usage
 //! -----
@@ -18,6 +20,18 @@
 //!     0x005a,
 //!     STRINGS)
 //! .finalize(components::usb_cdc_acm_component_helper!(nrf52::usbd::Usbd));
+//!
+//! let process_console_port = components::cdc::CdcAcmPortComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     &"Process console",
+//!     mux_alarm,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::usb_cdc_acm_component_helper!(
+//!     nrf52::usbd::Usbd,
+//!     nrf52::rtc::Rtc
+//! ));
+//! composite.add_function(process_console_port);
 //! ```
 
 use core::mem::MaybeUninit;
@@ -125,3 +139,79 @@ impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
         cdc
     }
 }
+
+/// Strings of the device descriptor of a port, which a composite device
+/// does not use.
+static NO_STRINGS: &'static [&'static str; 3] = &["", "", ""];
+
+pub struct CdcAcmPortComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    A: 'static + Alarm<'static>,
+> {
+    usb: &'static U,
+    name: &'static &'static str,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+    CdcAcmPortComponent<U, A>
+{
+    /// `name` is the name of the port shown by the host.
+    pub fn new(
+        usb: &'static U,
+        name: &'static &'static str,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        Self {
+            usb,
+            name,
+            alarm_mux,
+            deferred_caller,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>> Component
+    for CdcAcmPortComponent<U, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            capsules::usb::cdc::CdcAcm<'static, U, VirtualMuxAlarm<'static, A>>,
+        >,
+    );
+    type Output = &'static capsules::usb::cdc::CdcAcm<'static, U, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let cdc_alarm = static_init_half!(
+            s.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let cdc = static_init_half!(
+            s.1,
+            capsules::usb::cdc::CdcAcm<'static, U, VirtualMuxAlarm<'static, A>>,
+            capsules::usb::cdc::CdcAcm::new(
+                self.usb,
+                capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+                0,
+                0,
+                NO_STRINGS,
+                cdc_alarm,
+                self.deferred_caller,
+                None,
+            )
+        );
+        cdc.set_interface_name(self.name);
+        cdc.initialize_callback_handle(
+            self.deferred_caller
+                .register(cdc)
+                .expect("no deferred call slot available for USB-CDC"),
+        );
+        cdc_alarm.set_alarm_client(cdc);
+
+        cdc
+    }
+}
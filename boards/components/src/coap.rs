@@ -0,0 +1,170 @@
+//! Component for a CoAP endpoint and its userspace driver.
+//!
+//! The endpoint is bound to `COAP_PORT` of the 6LoWPAN UDP stack. It must be
+//! created after the UDP driver, as kernel ports can only be bound once the
+//! port table knows about userspace bindings.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (coap_endpoint, coap_driver) = CoapComponent::new(
+//!     board_kernel,
+//!     udp_send_mux,
+//!     udp_recv_mux,
+//!     udp_port_table,
+//!     mux_alarm,
+//! )
+//! .finalize(components::coap_component_helper!(sam4l::ast::Ast));
+//! ```
+
+use capsules::net::coap::driver::CoapDriver;
+use capsules::net::coap::endpoint::{CoapEndpoint, COAP_BUF_LEN};
+use capsules::net::coap::message::COAP_PORT;
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+static mut COAP_TX_BUF: [u8; COAP_BUF_LEN] = [0; COAP_BUF_LEN];
+static mut COAP_OUT_BUF: [u8; COAP_BUF_LEN] = [0; COAP_BUF_LEN];
+static mut COAP_RESP_BUF: [u8; COAP_BUF_LEN] = [0; COAP_BUF_LEN];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! coap_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::coap::driver::CoapDriver;
+        use capsules::net::coap::endpoint::CoapEndpoint;
+        use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+        use capsules::net::udp::udp_send::UDPSendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<CoapEndpoint<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<CoapDriver<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3)
+    };};
+}
+
+pub struct CoapComponent<A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    udp_send_mux:
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> CoapComponent<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        udp_send_mux: &'static MuxUdpSender<
+            'static,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        >,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for CoapComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+        &'static mut MaybeUninit<CoapEndpoint<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<CoapDriver<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = (
+        &'static CoapEndpoint<'static, VirtualMuxAlarm<'static, A>>,
+        &'static CoapDriver<'static, VirtualMuxAlarm<'static, A>>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let coap_send = static_init_half!(
+            static_buffer.1,
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let coap_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table
+                    .create_socket()
+                    .expect("no free socket for the CoAP endpoint"),
+                COAP_PORT,
+                net_cap,
+            )
+            .expect("CoAP port is already bound");
+        coap_send.set_binding(tx);
+        coap_recv.set_binding(rx);
+        self.udp_recv_mux.add_client(coap_recv);
+
+        let coap_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let endpoint = static_init_half!(
+            static_buffer.2,
+            CoapEndpoint<'static, VirtualMuxAlarm<'static, A>>,
+            CoapEndpoint::new(
+                coap_send,
+                coap_alarm,
+                LeasableBuffer::new(&mut COAP_TX_BUF),
+                &mut COAP_OUT_BUF,
+                &mut COAP_RESP_BUF,
+                net_cap,
+            )
+        );
+        coap_send.set_client(endpoint);
+        coap_recv.set_client(endpoint);
+        coap_alarm.set_alarm_client(endpoint);
+
+        let coap_driver = static_init_half!(
+            static_buffer.3,
+            CoapDriver<'static, VirtualMuxAlarm<'static, A>>,
+            CoapDriver::new(endpoint, self.board_kernel.create_grant(&grant_cap))
+        );
+        endpoint.set_client(coap_driver);
+        (endpoint, coap_driver)
+    }
+}
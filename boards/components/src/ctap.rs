@@ -1,7 +1,8 @@
 //! Component for CTAP HID over USB support.
 //!
 //! This provides a component for using the CTAP driver. This allows for
-//! Client to Authenticator Protool Authentication
+//! Client to Authenticator Protool Authentication. The kernel handles the
+//! CTAPHID framing and passes complete requests to the application.
 //!
 //! Usage
 //! -----
@@ -11,116 +12,159 @@
 //!     "FIDO Key",      // Product
 //!     "Serial No. 5",  // Serial number
 //! ];
-//!     let ctap_send_buffer = static_init!([u8; 64], [0; 64]);
-//!     let ctap_recv_buffer = static_init!([u8; 64], [0; 64]);
-//!
 //!     let (ctap, ctap_driver) = components::ctap::CtapComponent::new(
 //!         &earlgrey::usbdev::USB,
+//!         mux_alarm,
 //!         0x1337, // My important company
 //!         0x0DEC, // My device name
 //!         strings,
 //!         board_kernel,
-//!         ctap_send_buffer,
-//!         ctap_recv_buffer,
 //!     )
-//!     .finalize(components::usb_ctap_component_helper!(lowrisc::usbdev::Usb));
+//!     .finalize(components::usb_ctap_component_helper!(
+//!         lowrisc::usbdev::Usb,
+//!         earlgrey::timer::RvTimer
+//!     ));
 //!
 //!     ctap.enable();
 //!     ctap.attach();
 //! ```
 
+use capsules::ctap::CtapDriver;
+use capsules::ctaphid::{Ctap, CtapHidFraming, PacketTransport, UsbHidTransport};
+use capsules::usb::ctap::CtapHid;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
-use kernel::static_init_half;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
 
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! usb_ctap_component_helper {
-    ($U:ty $(,)?) => {{
+    ($U:ty, $A:ty $(,)?) => {{
+        use capsules::ctap::CtapDriver;
+        use capsules::ctaphid::{CtapHidFraming, UsbHidTransport};
+        use capsules::usb::ctap::CtapHid;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
         use core::mem::MaybeUninit;
-        static mut BUF1: MaybeUninit<capsules::usb::ctap::CtapHid<'static, $U>> =
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<CtapHid<'static, $U>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<UsbHidTransport<'static, CtapHid<'static, $U>>> =
             MaybeUninit::uninit();
-        static mut BUF2: MaybeUninit<
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, $U>>,
+        static mut BUF3: MaybeUninit<
+            CtapHidFraming<
+                'static,
+                UsbHidTransport<'static, CtapHid<'static, $U>>,
+                VirtualMuxAlarm<'static, $A>,
+            >,
+        > = MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<
+            CtapDriver<
+                'static,
+                CtapHidFraming<
+                    'static,
+                    UsbHidTransport<'static, CtapHid<'static, $U>>,
+                    VirtualMuxAlarm<'static, $A>,
+                >,
+            >,
         > = MaybeUninit::uninit();
-        (&mut BUF1, &mut BUF2)
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4)
     };};
 }
 
-pub struct CtapComponent<U: 'static + hil::usb::UsbController<'static>> {
+type Transport<U> = UsbHidTransport<'static, CtapHid<'static, U>>;
+type Framing<U, A> = CtapHidFraming<'static, Transport<U>, VirtualMuxAlarm<'static, A>>;
+
+pub struct CtapComponent<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+{
     usb: &'static U,
+    alarm_mux: &'static MuxAlarm<'static, A>,
     vendor_id: u16,
     product_id: u16,
     strings: &'static [&'static str; 3],
     board_kernel: &'static kernel::Kernel,
-    send_buffer: &'static mut [u8; 64],
-    recv_buffer: &'static mut [u8; 64],
 }
 
-impl<U: 'static + hil::usb::UsbController<'static>> CtapComponent<U> {
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+    CtapComponent<U, A>
+{
     pub fn new(
         usb: &'static U,
+        alarm_mux: &'static MuxAlarm<'static, A>,
         vendor_id: u16,
         product_id: u16,
         strings: &'static [&'static str; 3],
         board_kernel: &'static kernel::Kernel,
-        send_buffer: &'static mut [u8; 64],
-        recv_buffer: &'static mut [u8; 64],
-    ) -> CtapComponent<U> {
+    ) -> CtapComponent<U, A> {
         CtapComponent {
             usb,
+            alarm_mux,
             vendor_id,
             product_id,
             strings,
             board_kernel,
-            send_buffer,
-            recv_buffer,
         }
     }
 }
 
-impl<U: 'static + hil::usb::UsbController<'static>> Component for CtapComponent<U> {
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>> Component
+    for CtapComponent<U, A>
+{
     type StaticInput = (
-        &'static mut MaybeUninit<capsules::usb::ctap::CtapHid<'static, U>>,
-        &'static mut MaybeUninit<
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
-        >,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<CtapHid<'static, U>>,
+        &'static mut MaybeUninit<Transport<U>>,
+        &'static mut MaybeUninit<Framing<U, A>>,
+        &'static mut MaybeUninit<CtapDriver<'static, Framing<U, A>>>,
     );
     type Output = (
-        &'static capsules::usb::ctap::CtapHid<'static, U>,
-        &'static capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
+        &'static CtapHid<'static, U>,
+        &'static CtapDriver<'static, Framing<U, A>>,
     );
 
     unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
-        let ctap = static_init_half!(
+        let ctap_alarm = static_init_half!(
             s.0,
-            capsules::usb::ctap::CtapHid<'static, U>,
-            capsules::usb::ctap::CtapHid::new(
-                self.usb,
-                self.vendor_id,
-                self.product_id,
-                self.strings
-            )
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let ctap = static_init_half!(
+            s.1,
+            CtapHid<'static, U>,
+            CtapHid::new(self.usb, self.vendor_id, self.product_id, self.strings)
         );
         self.usb.set_client(ctap);
 
-        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let transport = static_init_half!(s.2, Transport<U>, UsbHidTransport::new(ctap));
+        ctap.set_client(transport);
 
-        let ctap_driver = static_init_half!(
-            s.1,
-            capsules::ctap::CtapDriver<'static, capsules::usb::ctap::CtapHid<'static, U>>,
-            capsules::ctap::CtapDriver::new(
-                Some(ctap),
-                self.send_buffer,
-                self.recv_buffer,
-                self.board_kernel.create_grant(&grant_cap),
+        let framing = static_init_half!(
+            s.3,
+            Framing<U, A>,
+            CtapHidFraming::new(
+                transport,
+                ctap_alarm,
+                static_init!([u8; 64], [0; 64]),
+                static_init!([u8; 64], [0; 64]),
+                &mut capsules::ctaphid::MESSAGE_BUFFER,
             )
         );
+        transport.set_client(framing);
+        ctap_alarm.set_alarm_client(framing);
 
-        ctap.set_client(ctap_driver);
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let ctap_driver = static_init_half!(
+            s.4,
+            CtapDriver<'static, Framing<U, A>>,
+            CtapDriver::new(framing, self.board_kernel.create_grant(&grant_cap))
+        );
+        framing.set_client(ctap_driver);
+        framing.start();
 
         (ctap, ctap_driver)
     }
@@ -0,0 +1,114 @@
+//! Components for the date-time userspace driver and the software clock.
+//!
+//! `SoftwareClockComponent` builds a calendar clock on a counter that also
+//! drives the alarm mux, for chips without a real-time clock.
+//! `DateTimeComponent` provides the userspace syscall interface on a clock.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let clock = components::date_time::SoftwareClockComponent::new(rtc, mux_alarm)
+//!     .finalize(components::software_clock_component_helper!(nrf52::rtc::Rtc));
+//! let date_time = components::date_time::DateTimeComponent::new(board_kernel, clock)
+//!     .finalize(());
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::date_time::DateTimeDriver;
+use capsules::software_clock::SoftwareClock;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::date_time::Clock;
+use kernel::hil::time::{Alarm, Counter};
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! software_clock_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::software_clock::SoftwareClock;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<SoftwareClock<'static, $A, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct SoftwareClockComponent<A: 'static + Alarm<'static> + Counter<'static>> {
+    counter: &'static A,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: 'static + Alarm<'static> + Counter<'static>> SoftwareClockComponent<A> {
+    pub fn new(
+        counter: &'static A,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> SoftwareClockComponent<A> {
+        SoftwareClockComponent { counter, alarm_mux }
+    }
+}
+
+impl<A: 'static + Alarm<'static> + Counter<'static>> Component for SoftwareClockComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<SoftwareClock<'static, A, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static SoftwareClock<'static, A, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let virtual_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let clock = static_init_half!(
+            static_buffer.1,
+            SoftwareClock<'static, A, VirtualMuxAlarm<'static, A>>,
+            SoftwareClock::new(self.counter, virtual_alarm)
+        );
+
+        virtual_alarm.set_alarm_client(clock);
+        self.counter.set_overflow_client(clock);
+        clock.start();
+        clock
+    }
+}
+
+pub struct DateTimeComponent {
+    board_kernel: &'static kernel::Kernel,
+    clock: &'static dyn Clock<'static>,
+}
+
+impl DateTimeComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        clock: &'static dyn Clock<'static>,
+    ) -> DateTimeComponent {
+        DateTimeComponent {
+            board_kernel,
+            clock,
+        }
+    }
+}
+
+impl Component for DateTimeComponent {
+    type StaticInput = ();
+    type Output = &'static DateTimeDriver<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let date_time = static_init!(
+            DateTimeDriver<'static>,
+            DateTimeDriver::new(self.clock, self.board_kernel.create_grant(&grant_cap))
+        );
+        self.clock.set_client(date_time);
+
+        date_time
+    }
+}
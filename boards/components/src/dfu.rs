@@ -0,0 +1,172 @@
+//! Component for USB Device Firmware Upgrade.
+//!
+//! `DfuComponent` builds a DFU function that writes downloaded images to the
+//! board's flash. It is added to a composite USB device. The DFU function
+//! stops the processes of an application target before overwriting it, so
+//! it is given a process management capability.
+//!
+//! If the board passes a function that resets the chip, the chip is reset
+//! shortly after each download, so the kernel loads the new applications or
+//! starts without the ones a failed download invalidated. Otherwise the
+//! processes of the target stay stopped until the board is reset.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let dfu_targets = static_init!(
+//!     [capsules::usb::dfu::Target; 1],
+//!     [capsules::usb::dfu::Target {
+//!         kind: capsules::usb::dfu::ImageKind::Apps,
+//!         start: &_sapps as *const u8 as usize,
+//!         length: &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+//!     }]
+//! );
+//! let dfu = components::dfu::DfuComponent::new(
+//!     board_kernel,
+//!     &nrf52840_peripherals.usbd,
+//!     &base_peripherals.nvmc,
+//!     mux_alarm,
+//!     dfu_targets,
+//!     Some(&reset),
+//! )
+//! .finalize(components::dfu_component_helper!(
+//!     nrf52840::usbd::Usbd<'static>,
+//!     nrf52840::nvmc::Nvmc,
+//!     nrf52840::rtc::Rtc<'static>
+//! ));
+//! composite.add_function(dfu);
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::dfu::{Dfu, DfuReset, Target};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::time::Alarm;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dfu_component_helper {
+    ($U:ty, $F:ty, $A:ty $(,)?) => {{
+        use capsules::usb::dfu::{Dfu, DfuReset};
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        use kernel::hil;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            Dfu<'static, $U, $F, VirtualMuxAlarm<'static, $A>, $crate::dfu::Capability>,
+        > = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<DfuReset<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4)
+    };};
+}
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+type DfuDevice<U, F, A> = Dfu<'static, U, F, VirtualMuxAlarm<'static, A>, Capability>;
+
+pub struct DfuComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    F: 'static + hil::flash::Flash,
+    A: 'static + Alarm<'static>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    usb: &'static U,
+    flash: &'static F,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    targets: &'static [Target],
+    reset: Option<&'static (dyn Fn() + 'static)>,
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, DfuDevice<U, F, A>>,
+        A: 'static + Alarm<'static>,
+    > DfuComponent<U, F, A>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        usb: &'static U,
+        flash: &'static F,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        targets: &'static [Target],
+        reset: Option<&'static (dyn Fn() + 'static)>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            usb,
+            flash,
+            alarm_mux,
+            targets,
+            reset,
+        }
+    }
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, DfuDevice<U, F, A>>,
+        A: 'static + Alarm<'static>,
+    > Component for DfuComponent<U, F, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<DfuDevice<U, F, A>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<DfuReset<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static DfuDevice<U, F, A>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let dfu_alarm = static_init_half!(
+            s.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let page = static_init_half!(
+            s.1,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+        let dfu = static_init_half!(
+            s.2,
+            DfuDevice<U, F, A>,
+            Dfu::new(
+                self.usb,
+                self.flash,
+                dfu_alarm,
+                self.targets,
+                page,
+                self.board_kernel,
+                Capability,
+            )
+        );
+        hil::flash::HasClient::set_client(self.flash, dfu);
+        dfu_alarm.set_alarm_client(dfu);
+
+        if let Some(reset) = self.reset {
+            let reset_alarm = static_init_half!(
+                s.3,
+                VirtualMuxAlarm<'static, A>,
+                VirtualMuxAlarm::new(self.alarm_mux)
+            );
+            let dfu_reset = static_init_half!(
+                s.4,
+                DfuReset<'static, VirtualMuxAlarm<'static, A>>,
+                DfuReset::new(reset_alarm, reset)
+            );
+            reset_alarm.set_alarm_client(dfu_reset);
+            dfu.set_client(dfu_reset);
+        }
+
+        dfu
+    }
+}
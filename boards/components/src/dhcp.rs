@@ -0,0 +1,269 @@
+//! Components to initialize DHCP clients.
+//!
+//! This provides two Components:
+//!
+//! - Dhcp4Component creates a DHCPv4 client for an IPv4 interface, with a
+//!   `UDP4Socket` bound to the DHCP client port. The client is not started
+//!   automatically; call `start()` on the returned object.
+//! - Dhcp6Component creates a DHCPv6 client for a 6LoWPAN interface, with a
+//!   UDP sender and receiver bound to the DHCPv6 client port, and makes it
+//!   the client of Neighbor Discovery. The client acts on the Router
+//!   Advertisements Neighbor Discovery receives, so it needs no starting.
+//!   It must be created after the UDP driver, as kernel ports can only be
+//!   bound once the port table knows about userspace bindings.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let dhcp4 = Dhcp4Component::new(udp4_mux, udp4_port_table, ip4, mux_alarm)
+//!     .finalize(components::dhcp4_component_helper!(LiteXAlarm));
+//! dhcp4.start();
+//!
+//! let dhcp6 = Dhcp6Component::new(
+//!     udp_send_mux,
+//!     udp_recv_mux,
+//!     udp_port_table,
+//!     neighbor_discovery,
+//!     &local_ip_ifaces[2],
+//!     eui64,
+//!     mux_alarm,
+//! )
+//! .finalize(components::dhcp6_component_helper!(sam4l::ast::Ast));
+//! ```
+
+use capsules::net::icmpv6::icmpv6_nd::NeighborDiscovery;
+use capsules::net::ipv4::dhcp::{Dhcp4Client, DHCP_BUF_LEN, DHCP_CLIENT_PORT};
+use capsules::net::ipv4::ipv4_interface::IP4Config;
+use capsules::net::ipv4::udp4::{MuxUdp4, UDP4Socket};
+use capsules::net::ipv6::dhcp6::{Dhcp6Client, DHCP6_BUF_LEN, DHCP6_CLIENT_PORT};
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+static mut DHCP4_BUF: [u8; DHCP_BUF_LEN] = [0; DHCP_BUF_LEN];
+static mut DHCP6_BUF: [u8; DHCP6_BUF_LEN] = [0; DHCP6_BUF_LEN];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dhcp4_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::ipv4::dhcp::Dhcp4Client;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<Dhcp4Client<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1)
+    };};
+}
+
+#[macro_export]
+macro_rules! dhcp6_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::ipv6::dhcp6::Dhcp6Client;
+        use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+        use capsules::net::udp::udp_send::UDPSendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<Dhcp6Client<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct Dhcp4Component<A: Alarm<'static> + 'static> {
+    udp4_mux: &'static MuxUdp4<'static>,
+    port_table: &'static UdpPortManager,
+    iface: &'static dyn IP4Config,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> Dhcp4Component<A> {
+    pub fn new(
+        udp4_mux: &'static MuxUdp4<'static>,
+        port_table: &'static UdpPortManager,
+        iface: &'static dyn IP4Config,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            udp4_mux,
+            port_table,
+            iface,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for Dhcp4Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<Dhcp4Client<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static Dhcp4Client<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let socket = static_init!(UDP4Socket<'static>, UDP4Socket::new(self.udp4_mux));
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table
+                    .create_socket()
+                    .expect("no free socket for the DHCP client"),
+                DHCP_CLIENT_PORT,
+                net_cap,
+            )
+            .expect("DHCP client port is already bound");
+        socket.set_bindings(tx, rx);
+        self.udp4_mux.add_socket(socket);
+
+        let dhcp_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let dhcp = static_init_half!(
+            static_buffer.1,
+            Dhcp4Client<'static, VirtualMuxAlarm<'static, A>>,
+            Dhcp4Client::new(
+                socket,
+                dhcp_alarm,
+                self.iface,
+                LeasableBuffer::new(&mut DHCP4_BUF),
+                net_cap,
+            )
+        );
+        socket.set_send_client(dhcp);
+        socket.set_receive_client(dhcp);
+        dhcp_alarm.set_alarm_client(dhcp);
+        dhcp
+    }
+}
+
+pub struct Dhcp6Component<A: Alarm<'static> + 'static> {
+    udp_send_mux:
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    nd: &'static NeighborDiscovery<'static, VirtualMuxAlarm<'static, A>>,
+    iface_addr: &'static Cell<IPAddr>,
+    eui64: [u8; 8],
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> Dhcp6Component<A> {
+    /// `iface_addr` is the entry of the UDP driver interface list that is
+    /// kept set to the registered address of the interface.
+    pub fn new(
+        udp_send_mux: &'static MuxUdpSender<
+            'static,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        >,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        nd: &'static NeighborDiscovery<'static, VirtualMuxAlarm<'static, A>>,
+        iface_addr: &'static Cell<IPAddr>,
+        eui64: [u8; 8],
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            nd,
+            iface_addr,
+            eui64,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for Dhcp6Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+        &'static mut MaybeUninit<Dhcp6Client<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static Dhcp6Client<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let udp_send = static_init_half!(
+            static_buffer.1,
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table
+                    .create_socket()
+                    .expect("no free socket for the DHCPv6 client"),
+                DHCP6_CLIENT_PORT,
+                net_cap,
+            )
+            .expect("DHCPv6 client port is already bound");
+        udp_send.set_binding(tx);
+        udp_recv.set_binding(rx);
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let dhcp_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let dhcp = static_init_half!(
+            static_buffer.2,
+            Dhcp6Client<'static, VirtualMuxAlarm<'static, A>>,
+            Dhcp6Client::new(
+                udp_send,
+                dhcp_alarm,
+                self.nd,
+                self.iface_addr,
+                self.eui64,
+                LeasableBuffer::new(&mut DHCP6_BUF),
+                net_cap,
+            )
+        );
+        udp_send.set_client(dhcp);
+        udp_recv.set_client(dhcp);
+        dhcp_alarm.set_alarm_client(dhcp);
+        self.nd.set_client(dhcp);
+        dhcp
+    }
+}
@@ -0,0 +1,265 @@
+//! Components to initialize a DNS stub resolver and its userspace driver.
+//!
+//! This provides two Components, which differ only in how name servers are
+//! reached:
+//!
+//! - Dns4Component queries servers over IPv4, through a `UDP4Socket`.
+//! - Dns6Component queries servers over the 6LoWPAN UDP stack. It must be
+//!   created after the UDP driver, as kernel ports can only be bound once
+//!   the port table knows about userspace bindings.
+//!
+//! Both return the resolver, so that it can be given to a DHCP client as
+//! its `DnsConfig`, and the driver. Queries are sent from `DNS_CLIENT_PORT`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (resolver, dns_driver) = Dns4Component::new(board_kernel, udp4_mux, udp4_port_table, mux_alarm)
+//!     .finalize(components::dns4_component_helper!(LiteXAlarm));
+//! dhcp4.set_dns_config(resolver);
+//! ```
+
+use capsules::net::dns::driver::DnsDriver;
+use capsules::net::dns::resolver::{DnsResolver, StubResolver, DNS_BUF_LEN};
+use capsules::net::ipv4::udp4::{MuxUdp4, UDP4Socket};
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+/// The local port DNS queries are sent from.
+pub const DNS_CLIENT_PORT: u16 = 49153;
+
+static mut DNS_BUF: [u8; DNS_BUF_LEN] = [0; DNS_BUF_LEN];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! dns4_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::dns::resolver::StubResolver;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<StubResolver<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1)
+    };};
+}
+
+#[macro_export]
+macro_rules! dns6_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::dns::resolver::StubResolver;
+        use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+        use capsules::net::udp::udp_send::UDPSendStruct;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<StubResolver<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2)
+    };};
+}
+
+/// Creates the resolver and the driver on top of it.
+unsafe fn create_resolver<A: Alarm<'static> + 'static>(
+    board_kernel: &'static kernel::Kernel,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    net_cap: &'static NetworkCapability,
+    alarm_buf: &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+    resolver_buf: &'static mut MaybeUninit<StubResolver<'static, VirtualMuxAlarm<'static, A>>>,
+) -> (
+    &'static StubResolver<'static, VirtualMuxAlarm<'static, A>>,
+    &'static DnsDriver<'static>,
+) {
+    let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+    let dns_alarm = static_init_half!(
+        alarm_buf,
+        VirtualMuxAlarm<'static, A>,
+        VirtualMuxAlarm::new(alarm_mux)
+    );
+    let resolver = static_init_half!(
+        resolver_buf,
+        StubResolver<'static, VirtualMuxAlarm<'static, A>>,
+        StubResolver::new(dns_alarm, LeasableBuffer::new(&mut DNS_BUF), net_cap)
+    );
+    dns_alarm.set_alarm_client(resolver);
+
+    let dns_driver = static_init!(
+        DnsDriver<'static>,
+        DnsDriver::new(resolver, board_kernel.create_grant(&grant_cap))
+    );
+    resolver.set_client(dns_driver);
+    (resolver, dns_driver)
+}
+
+pub struct Dns4Component<A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    udp4_mux: &'static MuxUdp4<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> Dns4Component<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        udp4_mux: &'static MuxUdp4<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            udp4_mux,
+            port_table,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for Dns4Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<StubResolver<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = (
+        &'static StubResolver<'static, VirtualMuxAlarm<'static, A>>,
+        &'static DnsDriver<'static>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let socket = static_init!(UDP4Socket<'static>, UDP4Socket::new(self.udp4_mux));
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table
+                    .create_socket()
+                    .expect("no free socket for the DNS resolver"),
+                DNS_CLIENT_PORT,
+                net_cap,
+            )
+            .expect("DNS client port is already bound");
+        socket.set_bindings(tx, rx);
+        self.udp4_mux.add_socket(socket);
+
+        let (resolver, dns_driver) = create_resolver(
+            self.board_kernel,
+            self.alarm_mux,
+            net_cap,
+            static_buffer.0,
+            static_buffer.1,
+        );
+        resolver.set_udp4_socket(socket);
+        socket.set_send_client(resolver);
+        socket.set_receive_client(resolver);
+        (resolver, dns_driver)
+    }
+}
+
+pub struct Dns6Component<A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    udp_send_mux:
+        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> Dns6Component<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        udp_send_mux: &'static MuxUdpSender<
+            'static,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        >,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            udp_send_mux,
+            udp_recv_mux,
+            port_table,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for Dns6Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<StubResolver<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+    );
+    type Output = (
+        &'static StubResolver<'static, VirtualMuxAlarm<'static, A>>,
+        &'static DnsDriver<'static>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let udp_send = static_init_half!(
+            static_buffer.2,
+            UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table
+                    .create_socket()
+                    .expect("no free socket for the DNS resolver"),
+                DNS_CLIENT_PORT,
+                net_cap,
+            )
+            .expect("DNS client port is already bound");
+        udp_send.set_binding(tx);
+        udp_recv.set_binding(rx);
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let (resolver, dns_driver) = create_resolver(
+            self.board_kernel,
+            self.alarm_mux,
+            net_cap,
+            static_buffer.0,
+            static_buffer.1,
+        );
+        resolver.set_udp6_sender(udp_send);
+        udp_send.set_client(resolver);
+        udp_recv.set_client(resolver);
+        (resolver, dns_driver)
+    }
+}
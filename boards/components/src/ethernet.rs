@@ -0,0 +1,68 @@
+//! Component to virtualize an Ethernet adapter.
+//!
+//! This provides one Component, EthernetComponent, which creates the
+//! Ethernet multiplexer for an adapter along with the raw frame userspace
+//! driver. Other components, such as the IPv4 stack, add their own users to
+//! the returned `MuxEthernet`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (eth_mux, eth_driver) = EthernetComponent::new(board_kernel, ethmac0).finalize(());
+//! ```
+
+use capsules::ethernet::virtual_ethernet::{EthernetUser, MuxEthernet};
+use capsules::ethernet::EthernetDriver;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::hil::ethernet::{EthernetAdapter, MAX_FRAME_LEN};
+use kernel::{create_capability, static_init};
+
+static mut DRIVER_BUF: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+
+pub struct EthernetComponent {
+    board_kernel: &'static kernel::Kernel,
+    adapter: &'static dyn EthernetAdapter<'static>,
+}
+
+impl EthernetComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        adapter: &'static dyn EthernetAdapter<'static>,
+    ) -> EthernetComponent {
+        EthernetComponent {
+            board_kernel: board_kernel,
+            adapter: adapter,
+        }
+    }
+}
+
+impl Component for EthernetComponent {
+    type StaticInput = ();
+    type Output = (
+        &'static MuxEthernet<'static>,
+        &'static EthernetDriver<'static>,
+    );
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let eth_mux = static_init!(MuxEthernet<'static>, MuxEthernet::new(self.adapter));
+        self.adapter.set_client(eth_mux);
+
+        let driver_user = static_init!(EthernetUser<'static>, EthernetUser::new(eth_mux));
+        eth_mux.add_user(driver_user);
+        let eth_driver = static_init!(
+            EthernetDriver<'static>,
+            EthernetDriver::new(
+                driver_user,
+                &mut DRIVER_BUF,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        driver_user.set_transmit_client(eth_driver);
+        driver_user.set_receive_client(eth_driver);
+
+        (eth_mux, eth_driver)
+    }
+}
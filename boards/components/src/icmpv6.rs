@@ -0,0 +1,322 @@
+//! Component to initialize the ICMPv6 stack on a 6LoWPAN interface.
+//!
+//! This provides one Component, ICMP6Component. It attaches an ICMPv6
+//! receive demultiplexer to the IPv6 receiver of an existing UDP/6LoWPAN
+//! interface, and creates an echo responder, a 6LoWPAN Neighbor Discovery
+//! host and the userspace ping driver. ICMPv6 messages are sent over their
+//! own virtual MAC user.
+//!
+//! Neighbor Discovery keeps the gateway and source address of both the
+//! ICMPv6 sender and the passed UDP IPv6 sender up to date. It is not
+//! started automatically; call `start()` on the returned object.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let (nd, ping_driver) = ICMP6Component::new(
+//!        board_kernel,
+//!        mux_mac,
+//!        DEFAULT_CTX_PREFIX_LEN,
+//!        DEFAULT_CTX_PREFIX,
+//!        DST_MAC_ADDR,
+//!        src_mac_from_serial_num,
+//!        eui64,
+//!        ip_recv,
+//!        ip_send,
+//!        mux_alarm,
+//!    )
+//!    .finalize(components::icmp6_component_helper!(sam4l::ast::Ast));
+//!    nd.start();
+//! ```
+
+use capsules;
+use capsules::ieee802154::device::MacDevice;
+use capsules::net::icmpv6::driver::PingDriver;
+use capsules::net::icmpv6::icmpv6_echo::ICMP6EchoResponder;
+use capsules::net::icmpv6::icmpv6_nd::{NeighborDiscovery, ND_BUF_LEN};
+use capsules::net::icmpv6::icmpv6_recv::{ICMP6Receiver, MuxIcmp6Receiver};
+use capsules::net::icmpv6::icmpv6_send::{ICMP6SendStruct, ICMP6Sender, MuxIcmp6Sender};
+use capsules::net::icmpv6::{ICMP6Header, ICMP6Type};
+use capsules::net::ieee802154::MacAddress;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::{
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange,
+};
+use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel;
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::radio;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+// The ICMPv6 stack requires its own packet buffers:
+//
+//   1. RADIO_BUF: buffer the IP6_Sender uses to pass frames to the radio after fragmentation
+//   2. ICMP_DGRAM: The payload of the IP6_Packet, which holds ICMPv6 messages before they are tx'd.
+//   3. ECHO_BUF, ND_BUF, PING_BUF: buffers used by each ICMPv6 sender to craft messages.
+
+static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+
+/// The maximum size of the body of an ICMPv6 message (excluding the 8 byte
+/// ICMPv6 header) sent by the kernel.
+pub const MAX_ICMP_PAYLOAD_LEN: usize = 192;
+static mut ICMP_DGRAM: [u8; MAX_ICMP_PAYLOAD_LEN] = [0; MAX_ICMP_PAYLOAD_LEN];
+static mut ECHO_BUF: [u8; MAX_ICMP_PAYLOAD_LEN] = [0; MAX_ICMP_PAYLOAD_LEN];
+static mut ND_BUF: [u8; ND_BUF_LEN] = [0; ND_BUF_LEN];
+static mut PING_BUF: [u8; 64] = [0; 64];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! icmp6_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules;
+        use capsules::net::icmpv6::driver::PingDriver;
+        use capsules::net::icmpv6::icmpv6_nd::NeighborDiscovery;
+        use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<
+            sixlowpan_state::Sixlowpan<
+                'static,
+                VirtualMuxAlarm<'static, $A>,
+                sixlowpan_compression::Context,
+            >,
+        > = MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<
+            capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, $A>>,
+        > = MaybeUninit::uninit();
+        static mut BUF5: MaybeUninit<NeighborDiscovery<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF6: MaybeUninit<PingDriver<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (
+            &mut BUF0, &mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4, &mut BUF5, &mut BUF6,
+        )
+    };};
+}
+
+pub struct ICMP6Component<A: Alarm<'static> + 'static> {
+    board_kernel: &'static kernel::Kernel,
+    mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+    ctx_pfix_len: u8,
+    ctx_pfix: [u8; 16],
+    dst_mac_addr: MacAddress,
+    src_mac_addr: MacAddress,
+    eui64: [u8; 8],
+    ip_receive: &'static IP6RecvStruct<'static>,
+    udp_ip_send: &'static IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> ICMP6Component<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+        ctx_pfix_len: u8,
+        ctx_pfix: [u8; 16],
+        dst_mac_addr: MacAddress,
+        src_mac_addr: MacAddress,
+        eui64: [u8; 8],
+        ip_receive: &'static IP6RecvStruct<'static>,
+        udp_ip_send: &'static IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            mux_mac,
+            ctx_pfix_len,
+            ctx_pfix,
+            dst_mac_addr,
+            src_mac_addr,
+            eui64,
+            ip_receive,
+            udp_ip_send,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for ICMP6Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            sixlowpan_state::Sixlowpan<
+                'static,
+                VirtualMuxAlarm<'static, A>,
+                sixlowpan_compression::Context,
+            >,
+        >,
+        &'static mut MaybeUninit<IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<NeighborDiscovery<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<PingDriver<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = (
+        &'static NeighborDiscovery<'static, VirtualMuxAlarm<'static, A>>,
+        &'static PingDriver<'static, VirtualMuxAlarm<'static, A>>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let ipsender_virtual_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let icmp_mac = static_init!(
+            capsules::ieee802154::virtual_mac::MacUser<'static>,
+            capsules::ieee802154::virtual_mac::MacUser::new(self.mux_mac)
+        );
+        self.mux_mac.add_user(icmp_mac);
+
+        // Packets are received through the IPv6 receiver of the UDP stack, so
+        // this 6LoWPAN instance is only used for compression on transmit.
+        let sixlowpan = static_init_half!(
+            static_buffer.3,
+            sixlowpan_state::Sixlowpan<
+                'static,
+                VirtualMuxAlarm<'static, A>,
+                sixlowpan_compression::Context,
+            >,
+            sixlowpan_state::Sixlowpan::new(
+                sixlowpan_compression::Context {
+                    prefix: self.ctx_pfix,
+                    prefix_len: self.ctx_pfix_len,
+                    id: 0,
+                    compress: false,
+                },
+                ipsender_virtual_alarm, // OK to reuse bc only used to get time, not set alarms
+            )
+        );
+        let sixlowpan_state = sixlowpan as &dyn sixlowpan_state::SixlowpanState;
+        let sixlowpan_tx = sixlowpan_state::TxState::new(sixlowpan_state);
+
+        let ip_pyld: IPPayload = IPPayload {
+            header: TransportHeader::ICMP(ICMP6Header::new(ICMP6Type::Type128)),
+            payload: &mut ICMP_DGRAM,
+        };
+        let ip6_dg = static_init!(IP6Packet<'static>, IP6Packet::new(ip_pyld));
+
+        let ip_send = static_init_half!(
+            static_buffer.4,
+            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
+            IP6SendStruct::new(
+                ip6_dg,
+                ipsender_virtual_alarm,
+                &mut RADIO_BUF,
+                sixlowpan_tx,
+                icmp_mac,
+                self.dst_mac_addr,
+                self.src_mac_addr,
+                ip_vis,
+            )
+        );
+        ipsender_virtual_alarm.set_alarm_client(ip_send);
+        ip_send.set_addr(capsules::net::ipv6::ip_utils::IPAddr::generate_from_mac(
+            self.src_mac_addr,
+        ));
+        icmp_mac.set_transmit_client(ip_send);
+
+        let icmp_send_mux = static_init!(MuxIcmp6Sender<'static>, MuxIcmp6Sender::new(ip_send));
+        ip_send.set_client(icmp_send_mux);
+        let icmp_recv_mux = static_init!(MuxIcmp6Receiver<'static>, MuxIcmp6Receiver::new());
+        self.ip_receive.set_icmp_client(icmp_recv_mux);
+
+        // Echo responder
+        let echo_send = static_init!(
+            ICMP6SendStruct<'static>,
+            ICMP6SendStruct::new(icmp_send_mux)
+        );
+        let echo_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+        let echo_responder = static_init!(
+            ICMP6EchoResponder<'static>,
+            ICMP6EchoResponder::new(echo_send, LeasableBuffer::new(&mut ECHO_BUF), net_cap)
+        );
+        echo_send.set_client(echo_responder);
+        echo_recv.set_client(echo_responder);
+        icmp_recv_mux.add_client(echo_recv);
+
+        // Neighbor Discovery
+        let nd_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let nd_send = static_init!(
+            ICMP6SendStruct<'static>,
+            ICMP6SendStruct::new(icmp_send_mux)
+        );
+        let nd_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+        let ip_senders = static_init!(
+            [&'static dyn IP6Sender<'static>; 2],
+            [self.udp_ip_send, ip_send]
+        );
+        let nd = static_init_half!(
+            static_buffer.5,
+            NeighborDiscovery<'static, VirtualMuxAlarm<'static, A>>,
+            NeighborDiscovery::new(
+                nd_alarm,
+                nd_send,
+                LeasableBuffer::new(&mut ND_BUF),
+                net_cap,
+                ip_senders,
+                self.src_mac_addr,
+                self.eui64,
+            )
+        );
+        nd_send.set_client(nd);
+        nd_recv.set_client(nd);
+        nd_alarm.set_alarm_client(nd);
+        icmp_recv_mux.add_client(nd_recv);
+
+        // Userspace ping driver
+        let ping_alarm = static_init_half!(
+            static_buffer.2,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let ping_send = static_init!(
+            ICMP6SendStruct<'static>,
+            ICMP6SendStruct::new(icmp_send_mux)
+        );
+        let ping_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+        let ping_driver = static_init_half!(
+            static_buffer.6,
+            PingDriver<'static, VirtualMuxAlarm<'static, A>>,
+            PingDriver::new(
+                ping_send,
+                ping_alarm,
+                LeasableBuffer::new(&mut PING_BUF),
+                self.board_kernel.create_grant(&grant_cap),
+                net_cap,
+            )
+        );
+        ping_send.set_client(ping_driver);
+        ping_recv.set_client(ping_driver);
+        ping_alarm.set_alarm_client(ping_driver);
+        icmp_recv_mux.add_client(ping_recv);
+
+        (nd, ping_driver)
+    }
+}
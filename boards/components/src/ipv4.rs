@@ -0,0 +1,161 @@
+//! Component to initialize the IPv4 stack on an Ethernet interface.
+//!
+//! This provides one Component, IP4Component, which creates the IPv4
+//! interface (with ARP and ICMP echo replies) on top of a `MuxEthernet`, and
+//! a UDP multiplexer that kernel capsules can attach `UDP4Socket`s to. It
+//! also returns the port table used to bind those sockets.
+//!
+//! The interface starts unconfigured unless a static address is given.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (ip4, udp4_mux, udp4_port_table) = IP4Component::new(
+//!     eth_mux,
+//!     MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+//!     Some((
+//!         IPv4Addr::new(192, 168, 1, 50),
+//!         IPv4Addr::new(255, 255, 255, 0),
+//!         IPv4Addr::new(192, 168, 1, 1),
+//!     )),
+//!     mux_alarm,
+//! )
+//! .finalize(components::ip4_component_helper!(LiteXAlarm));
+//! ```
+
+use capsules::ethernet::virtual_ethernet::{EthernetUser, MuxEthernet};
+use capsules::net::ethernet::MacAddr;
+use capsules::net::ipv4::ipv4::IPv4Addr;
+use capsules::net::ipv4::ipv4_interface::{IP4Config, IP4Interface, IP4Sender};
+use capsules::net::ipv4::udp4::MuxUdp4;
+use capsules::net::network_capabilities::{IpVisibilityCapability, UdpVisibilityCapability};
+use capsules::net::udp::udp_port_table::{
+    NoUserPorts, SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS,
+};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::ethernet::MAX_FRAME_LEN;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+// The IPv4 stack uses two frame buffers:
+//
+//   1. TX_BUF: holds the outgoing packet of the transport layer
+//   2. CTL_BUF: holds ARP packets and ICMP echo replies generated by the stack
+static mut TX_BUF: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+static mut CTL_BUF: [u8; 256] = [0; 256];
+
+static mut USED_KERNEL_PORTS: [Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS] =
+    [None; MAX_NUM_BOUND_PORTS];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ip4_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::ipv4::ipv4_interface::IP4Interface;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<IP4Interface<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1)
+    };};
+}
+
+pub struct IP4Component<A: Alarm<'static> + 'static> {
+    eth_mux: &'static MuxEthernet<'static>,
+    mac: MacAddr,
+    static_config: Option<(IPv4Addr, IPv4Addr, IPv4Addr)>,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> IP4Component<A> {
+    /// `static_config` is the address, subnet mask and gateway to configure
+    /// the interface with, if any.
+    pub fn new(
+        eth_mux: &'static MuxEthernet<'static>,
+        mac: MacAddr,
+        static_config: Option<(IPv4Addr, IPv4Addr, IPv4Addr)>,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            eth_mux,
+            mac,
+            static_config,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for IP4Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<IP4Interface<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = (
+        &'static IP4Interface<'static, VirtualMuxAlarm<'static, A>>,
+        &'static MuxUdp4<'static>,
+        &'static UdpPortManager,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+
+        let ip_eth_user = static_init!(EthernetUser<'static>, EthernetUser::new(self.eth_mux));
+        self.eth_mux.add_user(ip_eth_user);
+
+        let arp_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let ip4 = static_init_half!(
+            static_buffer.1,
+            IP4Interface<'static, VirtualMuxAlarm<'static, A>>,
+            IP4Interface::new(
+                ip_eth_user,
+                arp_alarm,
+                self.mac,
+                &mut TX_BUF,
+                &mut CTL_BUF,
+                ip_vis,
+            )
+        );
+        ip_eth_user.set_transmit_client(ip4);
+        ip_eth_user.set_receive_client(ip4);
+        arp_alarm.set_alarm_client(ip4);
+        if let Some((addr, netmask, gateway)) = self.static_config {
+            ip4.configure(addr, netmask, gateway);
+        }
+
+        let udp4_mux = static_init!(MuxUdp4<'static>, MuxUdp4::new(ip4, udp_vis));
+        ip4.set_client(udp4_mux);
+        ip4.set_receive_client(udp4_mux);
+
+        // There is no userspace UDP driver for this stack, so all ports are
+        // available to kernel capsules.
+        struct DriverCap;
+        unsafe impl capabilities::UdpDriverCapability for DriverCap {}
+        static NO_USER_PORTS: NoUserPorts = NoUserPorts;
+
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let port_table = static_init!(
+            UdpPortManager,
+            UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
+        );
+        port_table.set_user_ports(&NO_USER_PORTS, &DriverCap);
+
+        (ip4, udp4_mux, port_table)
+    }
+}
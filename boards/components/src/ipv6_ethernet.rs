@@ -0,0 +1,210 @@
+//! Component to initialize the IPv6 stack on an Ethernet interface.
+//!
+//! This provides one Component, IP6EthernetComponent, which creates an IPv6
+//! interface (with Neighbor Discovery and an ICMPv6 echo responder) on top of
+//! a `MuxEthernet`, such as a USB CDC-ECM link. It exposes the same UDP
+//! multiplexers, port table and IPv6 receiver as `UDPMuxComponent`, so the
+//! userspace UDP driver and kernel UDP capsules can run on either link.
+//!
+//! The interface address is set to the first address in the interface list;
+//! the link-local address derived from the MAC address is always accepted.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (ip6, udp_send_mux, udp_recv_mux, udp_port_table, ip_recv) = IP6EthernetComponent::new(
+//!     eth_mux,
+//!     MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+//!     local_ip_ifaces,
+//!     mux_alarm,
+//! )
+//! .finalize(components::ip6_ethernet_component_helper!(nrf52840::rtc::Rtc));
+//! ```
+
+use capsules::ethernet::virtual_ethernet::{EthernetUser, MuxEthernet};
+use capsules::net::ethernet::MacAddr;
+use capsules::net::icmpv6::icmpv6_echo::ICMP6EchoResponder;
+use capsules::net::icmpv6::icmpv6_recv::{ICMP6Receiver, MuxIcmp6Receiver};
+use capsules::net::icmpv6::icmpv6_send::ICMP6Sender;
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_ethernet::IP6EthernetInterface;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::IP6Sender;
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::{
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::{SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS};
+use capsules::net::udp::udp_recv::MuxUdpReceiver;
+use capsules::net::udp::udp_send::MuxUdpSender;
+use capsules::net::udp::UDPHeader;
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::ethernet::MAX_FRAME_LEN;
+use kernel::hil::time::Alarm;
+use kernel::{static_init, static_init_half};
+
+// The IPv6 stack uses four packet buffers:
+//
+//   1. TX_BUF: holds the frame of the outgoing packet of the transport layer
+//   2. CTL_BUF: holds Neighbor Discovery messages and ICMPv6 echo replies
+//   3. UDP_DGRAM: the payload of the IP6Packet the transport layer builds
+//   4. ECHO_BUF: the data of the echo reply being sent
+static mut TX_BUF: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+static mut CTL_BUF: [u8; 256] = [0; 256];
+static mut ECHO_BUF: [u8; 192] = [0; 192];
+
+const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
+const UDP_HDR_SIZE: usize = 8;
+static mut UDP_DGRAM: [u8; MAX_PAYLOAD_LEN - UDP_HDR_SIZE] = [0; MAX_PAYLOAD_LEN - UDP_HDR_SIZE];
+
+static mut USED_KERNEL_PORTS: [Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS] =
+    [None; MAX_NUM_BOUND_PORTS];
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ip6_ethernet_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::net::ipv6::ipv6_ethernet::IP6EthernetInterface;
+        use capsules::net::udp::udp_send::MuxUdpSender;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF0: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF1: MaybeUninit<IP6EthernetInterface<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<
+            MuxUdpSender<'static, IP6EthernetInterface<'static, VirtualMuxAlarm<'static, $A>>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF0, &mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct IP6EthernetComponent<A: Alarm<'static> + 'static> {
+    eth_mux: &'static MuxEthernet<'static>,
+    mac: MacAddr,
+    interface_list: &'static [Cell<IPAddr>],
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: Alarm<'static> + 'static> IP6EthernetComponent<A> {
+    pub fn new(
+        eth_mux: &'static MuxEthernet<'static>,
+        mac: MacAddr,
+        interface_list: &'static [Cell<IPAddr>],
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            eth_mux,
+            mac,
+            interface_list,
+            alarm_mux,
+        }
+    }
+}
+
+impl<A: Alarm<'static> + 'static> Component for IP6EthernetComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<
+            MuxUdpSender<'static, IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>>,
+        >,
+    );
+    type Output = (
+        &'static IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>,
+        &'static MuxUdpSender<'static, IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>>,
+        &'static MuxUdpReceiver<'static>,
+        &'static UdpPortManager,
+        &'static IP6RecvStruct<'static>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let ip_eth_user = static_init!(EthernetUser<'static>, EthernetUser::new(self.eth_mux));
+        self.eth_mux.add_user(ip_eth_user);
+
+        let ip_pyld: IPPayload = IPPayload {
+            header: TransportHeader::UDP(UDPHeader::new()),
+            payload: &mut UDP_DGRAM,
+        };
+        let ip6_dg = static_init!(IP6Packet<'static>, IP6Packet::new(ip_pyld));
+
+        let nd_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let ip6 = static_init_half!(
+            static_buffer.1,
+            IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>,
+            IP6EthernetInterface::new(
+                ip_eth_user,
+                nd_alarm,
+                self.mac,
+                ip6_dg,
+                &mut TX_BUF,
+                &mut CTL_BUF,
+                ip_vis,
+            )
+        );
+        ip_eth_user.set_transmit_client(ip6);
+        ip_eth_user.set_receive_client(ip6);
+        nd_alarm.set_alarm_client(ip6);
+        ip6.set_addr(self.interface_list[0].get());
+
+        let ip_receive = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
+        ip6.set_receiver(ip_receive);
+        let udp_recv_mux = static_init!(MuxUdpReceiver<'static>, MuxUdpReceiver::new());
+        ip_receive.set_client(udp_recv_mux);
+
+        // Neighbor Discovery messages and echo requests are passed to the
+        // interface and the echo responder through the ICMPv6 receiver
+        let icmp_recv_mux = static_init!(MuxIcmp6Receiver<'static>, MuxIcmp6Receiver::new());
+        ip_receive.set_icmp_client(icmp_recv_mux);
+        let nd_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+        nd_recv.set_client(ip6);
+        icmp_recv_mux.add_client(nd_recv);
+
+        let echo_responder = static_init!(
+            ICMP6EchoResponder<'static>,
+            ICMP6EchoResponder::new(ip6, LeasableBuffer::new(&mut ECHO_BUF), net_cap)
+        );
+        ICMP6Sender::set_client(ip6, echo_responder);
+        let echo_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+        echo_recv.set_client(echo_responder);
+        icmp_recv_mux.add_client(echo_recv);
+
+        let udp_send_mux = static_init_half!(
+            static_buffer.2,
+            MuxUdpSender<'static, IP6EthernetInterface<'static, VirtualMuxAlarm<'static, A>>>,
+            MuxUdpSender::new(ip6)
+        );
+        IP6Sender::set_client(ip6, udp_send_mux);
+
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let udp_port_table = static_init!(
+            UdpPortManager,
+            UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
+        );
+
+        (ip6, udp_send_mux, udp_recv_mux, udp_port_table, ip_receive)
+    }
+}
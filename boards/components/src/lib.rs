@@ -7,12 +7,19 @@ pub mod analog_comparator;
 pub mod app_flash_driver;
 pub mod bus;
 pub mod button;
+pub mod can;
 pub mod cdc;
+pub mod coap;
 pub mod console;
 pub mod crc;
 pub mod ctap;
+pub mod date_time;
 pub mod debug_queue;
 pub mod debug_writer;
+pub mod dfu;
+pub mod dhcp;
+pub mod dns;
+pub mod ethernet;
 pub mod ft6x06;
 pub mod fxos8700;
 pub mod gpio;
@@ -20,7 +27,10 @@ pub mod hd44780;
 pub mod hmac;
 pub mod humidity;
 pub mod i2c;
+pub mod icmpv6;
 pub mod ieee802154;
+pub mod ipv4;
+pub mod ipv6_ethernet;
 pub mod isl29035;
 pub mod l3gd20;
 pub mod led;
@@ -29,12 +39,16 @@ pub mod lldb;
 pub mod lsm303agr;
 pub mod lsm303dlhc;
 pub mod mlx90614;
+pub mod msc;
 pub mod mx25r6435f;
 pub mod ninedof;
 pub mod nonvolatile_storage;
 pub mod nrf51822;
+pub mod one_wire;
 pub mod panic_button;
+pub mod pcm;
 pub mod process_console;
+pub mod public_key_crypto;
 pub mod rng;
 pub mod sched;
 pub mod screen;
@@ -51,3 +65,5 @@ pub mod text_screen;
 pub mod touch;
 pub mod udp_driver;
 pub mod udp_mux;
+pub mod usb_composite;
+pub mod usb_hid;
@@ -0,0 +1,116 @@
+//! Component for USB mass storage over nonvolatile storage.
+//!
+//! This provides a component for exposing a region of nonvolatile storage to
+//! a USB host as a removable drive.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Flash Drive",   // Product
+//!     "Serial No. 5",  // Serial number
+//! ];
+//! let msc = components::msc::MscComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x521f,
+//!     STRINGS,
+//!     nv_to_page,
+//!     0x80000,
+//!     0x40000,
+//! )
+//! .finalize(components::usb_msc_component_helper!(nrf52::usbd::Usbd));
+//!
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use capsules::usb::msc::{MassStorage, NonvolatileBlocks};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<capsules::usb::msc::MassStorage<'static, $U>> =
+            MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct MscComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    start: usize,
+    length: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> MscComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start: usize,
+        length: usize,
+    ) -> MscComponent<U> {
+        MscComponent {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            storage,
+            start,
+            length,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for MscComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<MassStorage<'static, U>>;
+    type Output = &'static MassStorage<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let blocks = static_init!(
+            NonvolatileBlocks<'static>,
+            NonvolatileBlocks::new(
+                self.storage,
+                self.start,
+                self.length,
+                &mut capsules::usb::msc::STORAGE_BUFFER
+            )
+        );
+        self.storage.set_client(blocks);
+
+        let msc = static_init_half!(
+            s,
+            MassStorage<'static, U>,
+            MassStorage::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                blocks,
+                &mut capsules::usb::msc::BLOCK_BUFFER
+            )
+        );
+        capsules::usb::msc::BlockStorage::set_client(blocks, msc);
+        self.usb.set_client(msc);
+
+        msc
+    }
+}
@@ -16,7 +16,7 @@
 //!     nrf52::rtc::Rtc
 //! ));
 //! ```
-use capsules::mx25r6435f::MX25R6435F;
+use capsules::mx25r6435f::{MX25R6435FQspi, MX25R6435F};
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use core::mem::MaybeUninit;
@@ -137,3 +137,80 @@ impl<
         mx25r6435f
     }
 }
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! mx25r6435f_qspi_component_helper {
+    ($Q:ty, $A:ty $(,)?) => {{
+        use capsules::mx25r6435f::MX25R6435FQspi;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<MX25R6435FQspi<'static, $Q, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+/// MX25R6435F connected to a quad-SPI controller.
+///
+/// ```rust
+/// let mx25r6435f = components::mx25r6435f::Mx25r6435fQspiComponent::new(
+///     &base_peripherals.qspi,
+///     mux_alarm,
+/// )
+/// .finalize(components::mx25r6435f_qspi_component_helper!(
+///     nrf52840::qspi::Qspi,
+///     nrf52840::rtc::Rtc
+/// ));
+/// ```
+pub struct Mx25r6435fQspiComponent<
+    Q: 'static + hil::qspi::Qspi<'static>,
+    A: 'static + hil::time::Alarm<'static>,
+> {
+    qspi: &'static Q,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+}
+
+impl<Q: 'static + hil::qspi::Qspi<'static>, A: 'static + hil::time::Alarm<'static>>
+    Mx25r6435fQspiComponent<Q, A>
+{
+    pub fn new(
+        qspi: &'static Q,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+    ) -> Mx25r6435fQspiComponent<Q, A> {
+        Mx25r6435fQspiComponent { qspi, mux_alarm }
+    }
+}
+
+impl<Q: 'static + hil::qspi::Qspi<'static>, A: 'static + hil::time::Alarm<'static>> Component
+    for Mx25r6435fQspiComponent<Q, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<MX25R6435FQspi<'static, Q, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static MX25R6435FQspi<'static, Q, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let mx25r6435f_virtual_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.mux_alarm)
+        );
+
+        let mx25r6435f = static_init_half!(
+            static_buffer.1,
+            MX25R6435FQspi<'static, Q, VirtualMuxAlarm<'static, A>>,
+            MX25R6435FQspi::new(
+                self.qspi,
+                mx25r6435f_virtual_alarm,
+                &mut capsules::mx25r6435f::QSPI_PAGE.0
+            )
+        );
+        self.qspi.set_client(mx25r6435f);
+        mx25r6435f_virtual_alarm.set_alarm_client(mx25r6435f);
+        mx25r6435f.init();
+        mx25r6435f
+    }
+}
@@ -0,0 +1,142 @@
+//! Components for the 1-Wire bus master and the DS18B20 thermometer.
+//!
+//! `OneWireComponent` bit-bangs a 1-Wire bus on a GPIO pin, timed with a
+//! virtual alarm. As it busy-waits on the alarm counter during time slots,
+//! the alarm must count at 1 MHz or faster, so it is usually multiplexed
+//! from a hardware timer that no other driver uses, rather than the board's
+//! RTC. On the nRF52 that is TIMER3, as TIMER0 and TIMER1 belong to the
+//! radios. `Ds18b20Component` builds a DS18B20 driver on top of the bus,
+//! which can then be passed to `TemperatureComponent`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! base_peripherals.timer3.start();
+//! let timer_mux_alarm = components::alarm::AlarmMuxComponent::new(&base_peripherals.timer3)
+//!     .finalize(components::alarm_mux_component_helper!(nrf52840::timer::TimerAlarm));
+//! let one_wire = components::one_wire::OneWireComponent::new(
+//!     timer_mux_alarm,
+//!     &nrf52840::gpio::PORT[Pin::P1_10],
+//! )
+//! .finalize(components::one_wire_component_helper!(nrf52840::timer::TimerAlarm));
+//! let ds18b20 = components::one_wire::Ds18b20Component::new(one_wire, timer_mux_alarm)
+//!     .finalize(components::ds18b20_component_helper!(nrf52840::timer::TimerAlarm));
+//! let temp = TemperatureComponent::new(board_kernel, ds18b20).finalize(());
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::ds18b20::Ds18b20;
+use capsules::one_wire::{OneWire, OneWireMaster};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::component::Component;
+use kernel::hil::gpio;
+use kernel::hil::time::{self, Alarm};
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! one_wire_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::one_wire::OneWireMaster;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<OneWireMaster<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+#[macro_export]
+macro_rules! ds18b20_component_helper {
+    ($A:ty $(,)?) => {{
+        use capsules::ds18b20::Ds18b20;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualMuxAlarm<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<Ds18b20<'static, VirtualMuxAlarm<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct OneWireComponent<A: 'static + time::Alarm<'static>> {
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    pin: &'static dyn gpio::InterruptPin<'static>,
+}
+
+impl<A: 'static + time::Alarm<'static>> OneWireComponent<A> {
+    pub fn new(
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        pin: &'static dyn gpio::InterruptPin<'static>,
+    ) -> OneWireComponent<A> {
+        OneWireComponent { alarm_mux, pin }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>> Component for OneWireComponent<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<OneWireMaster<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static OneWireMaster<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let one_wire_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let one_wire = static_init_half!(
+            static_buffer.1,
+            OneWireMaster<'static, VirtualMuxAlarm<'static, A>>,
+            OneWireMaster::new(self.pin, one_wire_alarm)
+        );
+
+        one_wire_alarm.set_alarm_client(one_wire);
+        self.pin.set_client(one_wire);
+        one_wire
+    }
+}
+
+pub struct Ds18b20Component<A: 'static + time::Alarm<'static>> {
+    bus: &'static dyn OneWire<'static>,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<A: 'static + time::Alarm<'static>> Ds18b20Component<A> {
+    pub fn new(
+        bus: &'static dyn OneWire<'static>,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Ds18b20Component<A> {
+        Ds18b20Component { bus, alarm_mux }
+    }
+}
+
+static mut DS18B20_BUF: [u8; 10] = [0; 10];
+
+impl<A: 'static + time::Alarm<'static>> Component for Ds18b20Component<A> {
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<Ds18b20<'static, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static Ds18b20<'static, VirtualMuxAlarm<'static, A>>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let ds18b20_alarm = static_init_half!(
+            static_buffer.0,
+            VirtualMuxAlarm<'static, A>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let ds18b20 = static_init_half!(
+            static_buffer.1,
+            Ds18b20<'static, VirtualMuxAlarm<'static, A>>,
+            Ds18b20::new(self.bus, ds18b20_alarm, &mut DS18B20_BUF)
+        );
+
+        ds18b20_alarm.set_alarm_client(ds18b20);
+        self.bus.set_client(ds18b20);
+        ds18b20
+    }
+}
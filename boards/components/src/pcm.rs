@@ -0,0 +1,49 @@
+//! Component for the PCM audio streaming userspace driver.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let pcm = components::pcm::PcmComponent::new(board_kernel, &base_peripherals.pdm).finalize(());
+//! ```
+
+use capsules::pcm::PcmDriver;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::i2s::I2s;
+use kernel::static_init;
+
+pub struct PcmComponent {
+    board_kernel: &'static kernel::Kernel,
+    i2s: &'static dyn I2s<'static>,
+}
+
+impl PcmComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        i2s: &'static dyn I2s<'static>,
+    ) -> PcmComponent {
+        PcmComponent { board_kernel, i2s }
+    }
+}
+
+impl Component for PcmComponent {
+    type StaticInput = ();
+    type Output = &'static PcmDriver<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let pcm = static_init!(
+            PcmDriver<'static>,
+            PcmDriver::new(
+                self.i2s,
+                &mut capsules::pcm::BUFFERS,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        self.i2s.set_client(pcm);
+
+        pcm
+    }
+}
@@ -0,0 +1,134 @@
+//! Components for the public-key cryptography userspace driver and the
+//! software P-256 engine.
+//!
+//! `SoftwareP256Component` builds the software ECDSA and ECDH engine, for
+//! chips without a public-key accelerator. `PublicKeyCryptoComponent`
+//! provides the userspace syscall interface with a number of kernel key
+//! slots; it takes over the client of the random number generator, which
+//! generates the keys.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let p256 = components::public_key_crypto::SoftwareP256Component::new(dynamic_deferred_caller)
+//!     .finalize(());
+//! let pkc = components::public_key_crypto::PublicKeyCryptoComponent::new(board_kernel, p256, rng)
+//!     .finalize(components::public_key_crypto_component_helper!(
+//!         capsules::software_p256::SoftwareP256<'static>,
+//!         4
+//!     ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::public_key_crypto::{KeySlot, PublicKeyCryptoDriver};
+use capsules::software_p256::SoftwareP256;
+use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::public_key_crypto::{
+    EcdhP256, EcdsaP256, HASH_LEN, PUBLIC_KEY_LEN, SHARED_SECRET_LEN, SIGNATURE_LEN,
+};
+use kernel::hil::rng::Rng;
+use kernel::{static_init, static_init_half};
+
+pub struct SoftwareP256Component {
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl SoftwareP256Component {
+    pub fn new(deferred_caller: &'static DynamicDeferredCall) -> SoftwareP256Component {
+        SoftwareP256Component { deferred_caller }
+    }
+}
+
+impl Component for SoftwareP256Component {
+    type StaticInput = ();
+    type Output = &'static SoftwareP256<'static>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let p256 = static_init!(
+            SoftwareP256<'static>,
+            SoftwareP256::new(self.deferred_caller)
+        );
+        p256.initialize_callback_handle(
+            self.deferred_caller
+                .register(p256)
+                .expect("no deferred call slot available for P-256"),
+        );
+
+        p256
+    }
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! public_key_crypto_component_helper {
+    ($C:ty, $slots:expr $(,)?) => {{
+        use capsules::public_key_crypto::{KeySlot, PublicKeyCryptoDriver};
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<PublicKeyCryptoDriver<'static, $C>> = MaybeUninit::uninit();
+        static mut SLOTS: [KeySlot; $slots] = [KeySlot::empty(); $slots];
+        (&mut BUF1, &mut SLOTS)
+    };};
+}
+
+pub struct PublicKeyCryptoComponent<C: 'static + EcdsaP256<'static> + EcdhP256<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    crypto: &'static C,
+    rng: &'static dyn Rng<'static>,
+}
+
+impl<C: 'static + EcdsaP256<'static> + EcdhP256<'static>> PublicKeyCryptoComponent<C> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        crypto: &'static C,
+        rng: &'static dyn Rng<'static>,
+    ) -> PublicKeyCryptoComponent<C> {
+        PublicKeyCryptoComponent {
+            board_kernel,
+            crypto,
+            rng,
+        }
+    }
+}
+
+impl<C: 'static + EcdsaP256<'static> + EcdhP256<'static>> Component
+    for PublicKeyCryptoComponent<C>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<PublicKeyCryptoDriver<'static, C>>,
+        &'static mut [KeySlot],
+    );
+    type Output = &'static PublicKeyCryptoDriver<'static, C>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hash = static_init!([u8; HASH_LEN], [0; HASH_LEN]);
+        let signature = static_init!([u8; SIGNATURE_LEN], [0; SIGNATURE_LEN]);
+        let public_key = static_init!([u8; PUBLIC_KEY_LEN], [0; PUBLIC_KEY_LEN]);
+        let secret = static_init!([u8; SHARED_SECRET_LEN], [0; SHARED_SECRET_LEN]);
+
+        let pkc = static_init_half!(
+            static_buffer.0,
+            PublicKeyCryptoDriver<'static, C>,
+            PublicKeyCryptoDriver::new(
+                self.crypto,
+                self.rng,
+                static_buffer.1,
+                hash,
+                signature,
+                public_key,
+                secret,
+                self.board_kernel.create_grant(&grant_cap),
+            )
+        );
+        EcdsaP256::set_client(self.crypto, pkc);
+        EcdhP256::set_client(self.crypto, pkc);
+        self.rng.set_client(pkc);
+
+        pkc
+    }
+}
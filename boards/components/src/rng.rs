@@ -1,7 +1,9 @@
-//! Component for random number generator using `Entropy32ToRandom`.
+//! Component for random number generator using `HmacDrbg`.
 //!
 //! This provides one Component, RngComponent, which implements a
-//! userspace syscall interface to the RNG peripheral (TRNG).
+//! userspace syscall interface to the RNG peripheral (TRNG). Apps get the
+//! output of an HMAC_DRBG seeded from the health-tested TRNG, not the raw
+//! TRNG output.
 //!
 //! Usage
 //! -----
@@ -12,6 +14,7 @@
 // Author: Hudson Ayers <hayers@cs.stanford.edu>
 // Last modified: 07/12/2019
 
+use capsules::drbg::HmacDrbg;
 use capsules::rng;
 use kernel::capabilities;
 use kernel::component::Component;
@@ -20,6 +23,10 @@ use kernel::hil::entropy::Entropy32;
 use kernel::hil::rng::Rng;
 use kernel::static_init;
 
+/// Min-entropy claimed for the TRNG, in bits per byte. A lower claim than
+/// the real one only makes the health tests less sensitive.
+const TRNG_MIN_ENTROPY: usize = 4;
+
 pub struct RngComponent {
     board_kernel: &'static kernel::Kernel,
     trng: &'static dyn Entropy32<'static>,
@@ -44,19 +51,15 @@ impl Component for RngComponent {
     unsafe fn finalize(self, _static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
-        let entropy_to_random = static_init!(
-            rng::Entropy32ToRandom<'static>,
-            rng::Entropy32ToRandom::new(self.trng)
+        let drbg = static_init!(
+            HmacDrbg<'static>,
+            HmacDrbg::new(self.trng, TRNG_MIN_ENTROPY)
         );
         let rng = static_init!(
             rng::RngDriver<'static>,
-            rng::RngDriver::new(
-                entropy_to_random,
-                self.board_kernel.create_grant(&grant_cap)
-            )
+            rng::RngDriver::new(drbg, self.board_kernel.create_grant(&grant_cap))
         );
-        self.trng.set_client(entropy_to_random);
-        entropy_to_random.set_client(rng);
+        drbg.set_client(rng);
 
         rng
     }
@@ -1,7 +1,9 @@
 //! Component to initialize the userland UDP driver.
 //!
 //! This provides one Component, UDPDriverComponent. This component initializes a userspace
-//! UDP driver that allows apps to use the UDP stack.
+//! UDP driver that allows apps to use the UDP stack. The driver runs on any IPv6 sender, such
+//! as the 6LoWPAN sender created by `UDPMuxComponent` or the Ethernet interface created by
+//! `IP6EthernetComponent`.
 //!
 //! Usage
 //! -----
@@ -14,12 +16,20 @@
 //!        local_ip_ifaces,
 //!        PAYLOAD_LEN,
 //!     )
-//!     .finalize();
+//!     .finalize(components::udp_driver_component_helper!(nrf52840::rtc::Rtc));
+//! ```
+//!
+//! For a sender other than the 6LoWPAN one, name its type in the helper:
+//!
+//! ```rust
+//!     .finalize(components::udp_driver_component_helper!(
+//!         sender: IP6EthernetInterface<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>
+//!     ));
 //! ```
 
 use capsules;
 use capsules::net::ipv6::ip_utils::IPAddr;
-use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::ipv6::ipv6_send::IP6Sender;
 use capsules::net::network_capabilities::{
     AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
 };
@@ -27,13 +37,12 @@ use capsules::net::udp::udp_port_table::UdpPortManager;
 use capsules::net::udp::udp_recv::MuxUdpReceiver;
 use capsules::net::udp::udp_recv::UDPReceiver;
 use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
-use capsules::virtual_alarm::VirtualMuxAlarm;
+use core::cell::Cell;
 use core::mem::MaybeUninit;
 use kernel;
 use kernel::capabilities;
 use kernel::capabilities::NetworkCapabilityCreationCapability;
 use kernel::component::Component;
-use kernel::hil::time::Alarm;
 use kernel::{create_capability, static_init, static_init_half};
 
 const UDP_HDR_SIZE: usize = 8;
@@ -44,41 +53,38 @@ static mut DRIVER_BUF: [u8; MAX_PAYLOAD_LEN - UDP_HDR_SIZE] = [0; MAX_PAYLOAD_LE
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! udp_driver_component_helper {
-    ($A:ty $(,)?) => {{
+    (sender: $S:ty $(,)?) => {{
         use capsules::net::udp::udp_send::UDPSendStruct;
         use core::mem::MaybeUninit;
-        static mut BUF0: MaybeUninit<
-            UDPSendStruct<
-                'static,
+        static mut BUF0: MaybeUninit<UDPSendStruct<'static, $S>> = MaybeUninit::uninit();
+        (&mut BUF0,)
+    };};
+    ($A:ty $(,)?) => {{
+        $crate::udp_driver_component_helper!(
+            sender:
                 capsules::net::ipv6::ipv6_send::IP6SendStruct<
                     'static,
-                    VirtualMuxAlarm<'static, $A>,
-                >,
-            >,
-        > = MaybeUninit::uninit();
-        (&mut BUF0,)
+                    capsules::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+                >
+        )
     };};
 }
 
-pub struct UDPDriverComponent<A: Alarm<'static> + 'static> {
+pub struct UDPDriverComponent<S: IP6Sender<'static> + 'static> {
     board_kernel: &'static kernel::Kernel,
-    udp_send_mux:
-        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_send_mux: &'static MuxUdpSender<'static, S>,
     udp_recv_mux: &'static MuxUdpReceiver<'static>,
     port_table: &'static UdpPortManager,
-    interface_list: &'static [IPAddr],
+    interface_list: &'static [Cell<IPAddr>],
 }
 
-impl<A: Alarm<'static>> UDPDriverComponent<A> {
+impl<S: IP6Sender<'static>> UDPDriverComponent<S> {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
-        udp_send_mux: &'static MuxUdpSender<
-            'static,
-            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
-        >,
+        udp_send_mux: &'static MuxUdpSender<'static, S>,
         udp_recv_mux: &'static MuxUdpReceiver<'static>,
         port_table: &'static UdpPortManager,
-        interface_list: &'static [IPAddr],
+        interface_list: &'static [Cell<IPAddr>],
     ) -> Self {
         Self {
             board_kernel,
@@ -90,15 +96,8 @@ impl<A: Alarm<'static>> UDPDriverComponent<A> {
     }
 }
 
-impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
-    type StaticInput = (
-        &'static mut MaybeUninit<
-            UDPSendStruct<
-                'static,
-                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
-            >,
-        >,
-    );
+impl<S: IP6Sender<'static>> Component for UDPDriverComponent<S> {
+    type StaticInput = (&'static mut MaybeUninit<UDPSendStruct<'static, S>>,);
     type Output = &'static capsules::net::udp::UDPDriver<'static>;
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
@@ -111,10 +110,7 @@ impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
         );
         let udp_send = static_init_half!(
             static_buffer.0,
-            UDPSendStruct<
-                'static,
-                capsules::net::ipv6::ipv6_send::IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
-            >,
+            UDPSendStruct<'static, S>,
             UDPSendStruct::new(self.udp_send_mux, udp_vis)
         );
 
@@ -2,12 +2,14 @@
 //!
 //! This provides one Component, UDPMuxComponent. This component
 //! exposes a MuxUdpSender that other components can implement
-//! UDPSenders on top of to use the UDP/6Lowpan stack.
+//! UDPSenders on top of to use the UDP/6Lowpan stack. It also exposes
+//! the IPv6 receiver and sender, so that other protocols (e.g. ICMPv6)
+//! can be attached to the same interface.
 //!
 //! Usage
 //! -----
 //! ```rust
-//!    let (udp_mux, udp_recv) = UDPMuxComponent::new(
+//!    let (udp_mux, udp_recv, udp_port_table, ip_recv, ip_send) = UDPMuxComponent::new(
 //!        mux_mac,
 //!        DEFAULT_CTX_PREFIX_LEN,
 //!        DEFAULT_CTX_PREFIX,
@@ -27,7 +29,7 @@ use capsules;
 use capsules::ieee802154::device::MacDevice;
 use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
-use capsules::net::ipv6::ipv6_recv::IP6Receiver;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
 use capsules::net::ipv6::ipv6_send::IP6SendStruct;
 use capsules::net::ipv6::ipv6_send::IP6Sender;
 use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
@@ -38,6 +40,7 @@ use capsules::net::udp::udp_recv::MuxUdpReceiver;
 use capsules::net::udp::udp_send::MuxUdpSender;
 use capsules::net::udp::UDPHeader;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use core::cell::Cell;
 use core::mem::MaybeUninit;
 use kernel;
 use kernel::capabilities;
@@ -119,7 +122,7 @@ pub struct UDPMuxComponent<A: Alarm<'static> + 'static> {
     ctx_pfix: [u8; 16],
     dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
-    interface_list: &'static [IPAddr],
+    interface_list: &'static [Cell<IPAddr>],
     alarm_mux: &'static MuxAlarm<'static, A>,
 }
 
@@ -130,7 +133,7 @@ impl<A: Alarm<'static> + 'static> UDPMuxComponent<A> {
         ctx_pfix: [u8; 16],
         dst_mac_addr: MacAddress,
         src_mac_addr: MacAddress,
-        interface_list: &'static [IPAddr],
+        interface_list: &'static [Cell<IPAddr>],
         alarm_mux: &'static MuxAlarm<'static, A>,
     ) -> Self {
         Self {
@@ -171,6 +174,8 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
         &'static MuxUdpReceiver<'static>,
         &'static UdpPortManager,
+        &'static IP6RecvStruct<'static>,
+        &'static IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
     );
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
@@ -257,13 +262,10 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
         // list. Userland apps can change this if they so choose.
         // Notably, the src addr is the same regardless of if messages are sent from
         // userland or capsules.
-        ip_send.set_addr(self.interface_list[0]);
+        ip_send.set_addr(self.interface_list[0].get());
         udp_mac.set_transmit_client(ip_send);
 
-        let ip_receive = static_init!(
-            capsules::net::ipv6::ipv6_recv::IP6RecvStruct<'static>,
-            capsules::net::ipv6::ipv6_recv::IP6RecvStruct::new()
-        );
+        let ip_receive = static_init!(IP6RecvStruct<'static>, IP6RecvStruct::new());
         sixlowpan_state.set_rx_client(ip_receive);
         let udp_recv_mux = static_init!(MuxUdpReceiver<'static>, MuxUdpReceiver::new());
         ip_receive.set_client(udp_recv_mux);
@@ -284,6 +286,12 @@ impl<A: Alarm<'static> + 'static> Component for UDPMuxComponent<A> {
             UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
         );
 
-        (udp_send_mux, udp_recv_mux, udp_port_table)
+        (
+            udp_send_mux,
+            udp_recv_mux,
+            udp_port_table,
+            ip_receive,
+            ip_send,
+        )
     }
 }
@@ -0,0 +1,93 @@
+//! Component for a composite USB device.
+//!
+//! `CompositeDeviceComponent` makes a composite device the client of a USB
+//! controller. The board then adds functions, such as CDC-ACM ports or DFU,
+//! and enables and attaches the device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let composite = components::usb_composite::CompositeDeviceComponent::new(
+//!     &nrf52840_peripherals.usbd,
+//!     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x503a,
+//!     STRINGS,
+//! )
+//! .finalize(components::usb_composite_component_helper!(nrf52840::usbd::Usbd));
+//! composite.add_function(dfu);
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::composite::CompositeDevice;
+use kernel::component::Component;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_composite_component_helper {
+    ($U:ty $(,)?) => {{
+        use capsules::usb::composite::CompositeDevice;
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<CompositeDevice<'static, $U>> = MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct CompositeDeviceComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CompositeDeviceComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CompositeDeviceComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<CompositeDevice<'static, U>>;
+    type Output = &'static CompositeDevice<'static, U>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let composite = static_init_half!(
+            s,
+            CompositeDevice<'static, U>,
+            CompositeDevice::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                &mut capsules::usb::composite::DESCRIPTOR_BUFFER,
+            )
+        );
+        self.usb.set_client(composite);
+
+        composite
+    }
+}
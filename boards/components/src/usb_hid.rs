@@ -0,0 +1,151 @@
+//! Component for USB HID devices.
+//!
+//! This provides a component for a USB HID device with a report descriptor
+//! given by the board, and the driver that lets an application send and
+//! receive its reports.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Keyboard",      // Product
+//!     "Serial No. 5",  // Serial number
+//! ];
+//! let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!     board_kernel,
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x1915,
+//!     0x520b,
+//!     STRINGS,
+//!     capsules::usb::hid::BootInterface::Keyboard,
+//!     capsules::usb::hid::KEYBOARD_REPORT_DESCRIPTOR,
+//! )
+//! .finalize(components::usb_hid_component_helper!(nrf52::usbd::Usbd));
+//!
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use capsules::usb::descriptors::{
+    DescriptorType, HIDCountryCode, HIDDescriptor, HIDSubordinateDescriptor, ReportDescriptor,
+};
+use capsules::usb::hid::{BootInterface, Hid};
+use capsules::usb::hid_driver::HidDriver;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_hid_component_helper {
+    ($U:ty $(,)?) => {{
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<capsules::usb::hid::Hid<'static, $U>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<capsules::usb::hid_driver::HidDriver<'static, $U>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct UsbHidComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    boot: BootInterface,
+    report_descriptor: &'static [u8],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        boot: BootInterface,
+        report_descriptor: &'static [u8],
+    ) -> UsbHidComponent<U> {
+        UsbHidComponent {
+            board_kernel,
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            boot,
+            report_descriptor,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<Hid<'static, U>>,
+        &'static mut MaybeUninit<HidDriver<'static, U>>,
+    );
+    type Output = (&'static Hid<'static, U>, &'static HidDriver<'static, U>);
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let sub_descriptors = static_init!(
+            [HIDSubordinateDescriptor; 1],
+            [HIDSubordinateDescriptor {
+                typ: DescriptorType::Report,
+                len: self.report_descriptor.len() as u16,
+            }]
+        );
+        let hid_descriptor = static_init!(
+            HIDDescriptor<'static>,
+            HIDDescriptor {
+                hid_class: 0x0111,
+                country_code: HIDCountryCode::NotSupported,
+                sub_descriptors: sub_descriptors,
+            }
+        );
+        let report_descriptor = static_init!(
+            ReportDescriptor<'static>,
+            ReportDescriptor {
+                desc: self.report_descriptor,
+            }
+        );
+
+        let hid = static_init_half!(
+            s.0,
+            Hid<'static, U>,
+            Hid::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                self.boot,
+                hid_descriptor,
+                report_descriptor
+            )
+        );
+        self.usb.set_client(hid);
+
+        let hid_driver = static_init_half!(
+            s.1,
+            HidDriver<'static, U>,
+            HidDriver::new(
+                hid,
+                &mut capsules::usb::hid_driver::BUFFER,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        hid.set_client(hid_driver);
+
+        (hid, hid_driver)
+    }
+}
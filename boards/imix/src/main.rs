@@ -17,6 +17,7 @@ use capsules::virtual_aes_ccm::MuxAES128CCM;
 use capsules::virtual_alarm::VirtualMuxAlarm;
 use capsules::virtual_i2c::MuxI2C;
 use capsules::virtual_spi::VirtualSpiMasterDevice;
+use core::cell::Cell;
 //use capsules::virtual_timer::MuxTimer;
 use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
@@ -131,6 +132,15 @@ struct Imix {
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     udp_driver: &'static capsules::net::udp::UDPDriver<'static>,
+    ping_driver: &'static capsules::net::icmpv6::driver::PingDriver<
+        'static,
+        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    >,
+    dns_driver: &'static capsules::net::dns::driver::DnsDriver<'static>,
+    coap_driver: &'static capsules::net::coap::driver::CoapDriver<
+        'static,
+        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    >,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
     usb_driver: &'static capsules::usb::usb_user::UsbSyscallDriver<
         'static,
@@ -175,6 +185,9 @@ impl kernel::Platform for Imix {
             capsules::usb::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
             capsules::net::udp::DRIVER_NUM => f(Some(self.udp_driver)),
+            capsules::net::icmpv6::driver::DRIVER_NUM => f(Some(self.ping_driver)),
+            capsules::net::dns::driver::DRIVER_NUM => f(Some(self.dns_driver)),
+            capsules::net::coap::driver::DRIVER_NUM => f(Some(self.coap_driver)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
@@ -333,6 +346,13 @@ pub unsafe fn reset_handler() {
     let mux_alarm = AlarmMuxComponent::new(&peripherals.ast)
         .finalize(components::alarm_mux_component_helper!(sam4l::ast::Ast));
     peripherals.ast.configure(mux_alarm);
+    let power_manager = static_init!(
+        kernel::power::PowerManager<'static, sam4l::ast::Ast<'static>>,
+        kernel::power::PowerManager::new(&peripherals.ast)
+    );
+    power_manager.add_constraint(peripherals.usart3.power_constraint());
+    power_manager.add_constraint(peripherals.usart2.power_constraint());
+    board_kernel.set_power_manager(power_manager);
     let alarm = AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(sam4l::ast::Ast));
 
@@ -369,6 +389,7 @@ pub unsafe fn reset_handler() {
         RADIO_CHANNEL,
     )
     .finalize(());
+    power_manager.add_constraint(rf233.power_constraint());
 
     let adc = AdcComponent::new(board_kernel, &peripherals.adc).finalize(());
     let gpio = GpioComponent::new(
@@ -484,44 +505,140 @@ pub unsafe fn reset_handler() {
         static _estorage: u8;
     }
 
+    // The flash is shared between the nonvolatile storage driver and, if it
+    // is enabled below, DFU.
+    let mux_flash = static_init!(
+        capsules::virtual_flash::MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::virtual_flash::MuxFlash::new(&peripherals.flash_controller)
+    );
+    kernel::hil::flash::HasClient::set_client(&peripherals.flash_controller, mux_flash);
+
+    let nv_flash = static_init!(
+        capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::virtual_flash::FlashUser::new(mux_flash)
+    );
     let nonvolatile_storage = components::nonvolatile_storage::NonvolatileStorageComponent::new(
         board_kernel,
-        &peripherals.flash_controller,
+        nv_flash,
         0x60000,                          // Start address for userspace accessible region
         0x20000,                          // Length of userspace accessible region
         &_sstorage as *const u8 as usize, //start address of kernel region
         &_estorage as *const u8 as usize - &_sstorage as *const u8 as usize, // length of kernel region
     )
     .finalize(components::nv_storage_component_helper!(
-        sam4l::flashcalw::FLASHCALW
+        capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>
     ));
 
+    //--------------------------------------------------------------------------
+    // USB DFU
+    //--------------------------------------------------------------------------
+    // Uncomment to let `dfu-util` replace the applications over the TARGET USB
+    // port. The board starts in DFU runtime mode, switches to DFU mode on
+    // request and resets once a download ends. DFU does not authenticate the
+    // images it receives, so anyone with access to the USB port can replace
+    // the applications. DFU needs the USB controller, so also remove the USB
+    // syscall driver (`usb_driver`).
+
+    // use kernel::hil::usb::Client;
+    //
+    // // Resets the chip after a download, so the kernel loads the new
+    // // applications.
+    // fn dfu_reset() {
+    //     unsafe {
+    //         cortexm4::scb::reset();
+    //     }
+    // }
+    //
+    // let dfu_flash = static_init!(
+    //     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+    //     capsules::virtual_flash::FlashUser::new(mux_flash)
+    // );
+    // let dfu_targets = static_init!(
+    //     [capsules::usb::dfu::Target; 1],
+    //     [capsules::usb::dfu::Target {
+    //         kind: capsules::usb::dfu::ImageKind::Apps,
+    //         start: &_sapps as *const u8 as usize,
+    //         length: &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+    //     }]
+    // );
+    // let dfu = components::dfu::DfuComponent::new(
+    //     board_kernel,
+    //     &peripherals.usbc,
+    //     dfu_flash,
+    //     mux_alarm,
+    //     dfu_targets,
+    //     Some(&dfu_reset),
+    // )
+    // .finalize(components::dfu_component_helper!(
+    //     sam4l::usbc::Usbc<'static>,
+    //     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+    //     sam4l::ast::Ast<'static>
+    // ));
+    //
+    // let usb_strings = static_init!(
+    //     [&str; 3],
+    //     [
+    //         "University of California", // Manufacturer
+    //         "imix - TockOS",            // Product
+    //         "serial0001",               // Serial number
+    //     ]
+    // );
+    // let composite = components::usb_composite::CompositeDeviceComponent::new(
+    //     &peripherals.usbc,
+    //     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_SAM4L,
+    //     0x6667, // Arbitrary (test)
+    //     0xabcd, // Arbitrary (test)
+    //     usb_strings,
+    // )
+    // .finalize(components::usb_composite_component_helper!(
+    //     sam4l::usbc::Usbc<'static>
+    // ));
+    // composite.add_function(dfu);
+    // composite.enable();
+    // composite.attach();
+
     let local_ip_ifaces = static_init!(
-        [IPAddr; 3],
+        [Cell<IPAddr>; 3],
         [
-            IPAddr([
+            Cell::new(IPAddr([
                 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
                 0x0e, 0x0f,
-            ]),
-            IPAddr([
+            ])),
+            Cell::new(IPAddr([
                 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
                 0x1e, 0x1f,
-            ]),
-            IPAddr::generate_from_mac(src_mac_from_serial_num),
+            ])),
+            Cell::new(IPAddr::generate_from_mac(src_mac_from_serial_num)),
         ]
     );
 
-    let (udp_send_mux, udp_recv_mux, udp_port_table) = components::udp_mux::UDPMuxComponent::new(
+    let (udp_send_mux, udp_recv_mux, udp_port_table, ip_recv, udp_ip_send) =
+        components::udp_mux::UDPMuxComponent::new(
+            mux_mac,
+            DEFAULT_CTX_PREFIX_LEN,
+            DEFAULT_CTX_PREFIX,
+            DST_MAC_ADDR,
+            src_mac_from_serial_num, //comment out for dual rx test only
+            //MacAddress::Short(49138), //comment in for dual rx test only
+            local_ip_ifaces,
+            mux_alarm,
+        )
+        .finalize(components::udp_mux_component_helper!(sam4l::ast::Ast));
+
+    // ICMPv6 echo replies, 6LoWPAN Neighbor Discovery and the ping driver
+    let (neighbor_discovery, ping_driver) = components::icmpv6::ICMP6Component::new(
+        board_kernel,
         mux_mac,
         DEFAULT_CTX_PREFIX_LEN,
         DEFAULT_CTX_PREFIX,
         DST_MAC_ADDR,
-        src_mac_from_serial_num, //comment out for dual rx test only
-        //MacAddress::Short(49138), //comment in for dual rx test only
-        local_ip_ifaces,
+        src_mac_from_serial_num,
+        serial_num.get_lower_64().to_be_bytes(),
+        ip_recv,
+        udp_ip_send,
         mux_alarm,
     )
-    .finalize(components::udp_mux_component_helper!(sam4l::ast::Ast));
+    .finalize(components::icmp6_component_helper!(sam4l::ast::Ast));
 
     // UDP driver initialization happens here
     let udp_driver = components::udp_driver::UDPDriverComponent::new(
@@ -533,6 +650,36 @@ pub unsafe fn reset_handler() {
     )
     .finalize(components::udp_driver_component_helper!(sam4l::ast::Ast));
 
+    // DHCPv6 keeps the last interface address set to the registered address
+    // and passes the name servers it learns to the DNS resolver
+    let dhcp6 = components::dhcp::Dhcp6Component::new(
+        udp_send_mux,
+        udp_recv_mux,
+        udp_port_table,
+        neighbor_discovery,
+        &local_ip_ifaces[2],
+        serial_num.get_lower_64().to_be_bytes(),
+        mux_alarm,
+    )
+    .finalize(components::dhcp6_component_helper!(sam4l::ast::Ast));
+    let (dns_resolver, dns_driver) = components::dns::Dns6Component::new(
+        board_kernel,
+        udp_send_mux,
+        udp_recv_mux,
+        udp_port_table,
+        mux_alarm,
+    )
+    .finalize(components::dns6_component_helper!(sam4l::ast::Ast));
+    dhcp6.set_dns_config(dns_resolver);
+    let (_coap_endpoint, coap_driver) = components::coap::CoapComponent::new(
+        board_kernel,
+        udp_send_mux,
+        udp_recv_mux,
+        udp_port_table,
+        mux_alarm,
+    )
+    .finalize(components::coap_component_helper!(sam4l::ast::Ast));
+
     let imix = Imix {
         pconsole,
         console,
@@ -552,6 +699,9 @@ pub unsafe fn reset_handler() {
         ninedof,
         radio_driver,
         udp_driver,
+        ping_driver,
+        dns_driver,
+        coap_driver,
         usb_driver,
         nrf51822: nrf_serialization,
         nonvolatile_storage,
@@ -564,6 +714,7 @@ pub unsafe fn reset_handler() {
     // initialization to work.
     rf233.reset();
     rf233.start();
+    neighbor_discovery.start();
 
     imix.pconsole.start();
 
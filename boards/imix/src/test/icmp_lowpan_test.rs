@@ -11,7 +11,7 @@
 //! ```
 
 use capsules::ieee802154::device::MacDevice;
-use capsules::net::icmpv6::icmpv6_send::{ICMP6SendStruct, ICMP6Sender};
+use capsules::net::icmpv6::icmpv6_send::{ICMP6SendStruct, ICMP6Sender, MuxIcmp6Sender};
 use capsules::net::icmpv6::{ICMP6Header, ICMP6Type};
 use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
@@ -26,6 +26,8 @@ use capsules::net::sixlowpan::sixlowpan_state::{Sixlowpan, SixlowpanState, TxSta
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::cell::Cell;
 use kernel::capabilities::NetworkCapabilityCreationCapability;
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::create_capability;
 use kernel::debug;
 use kernel::hil::radio;
@@ -51,6 +53,7 @@ pub const TEST_DELAY_MS: u32 = 10000;
 pub const TEST_LOOP: bool = false;
 
 static mut ICMP_PAYLOAD: [u8; 10] = [0; 10];
+static mut ECHO_DATA: [u8; 10] = [0; 10];
 
 pub static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0 as u8; radio::MAX_BUF_SIZE];
 
@@ -60,6 +63,7 @@ pub struct LowpanICMPTest<'a, A: time::Alarm<'a>> {
     alarm: A,
     test_counter: Cell<usize>,
     icmp_sender: &'a dyn ICMP6Sender<'a>,
+    echo_data: MapCell<LeasableBuffer<'static, u8>>,
     net_cap: &'static NetworkCapability,
 }
 
@@ -129,12 +133,10 @@ pub unsafe fn run(
     );
     radio_mac.set_transmit_client(ip6_sender);
 
+    let icmp_send_mux = static_init!(MuxIcmp6Sender<'static>, MuxIcmp6Sender::new(ip6_sender));
     let icmp_send_struct = static_init!(
-        ICMP6SendStruct<
-            'static,
-            IP6SendStruct<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
-        >,
-        ICMP6SendStruct::new(ip6_sender)
+        ICMP6SendStruct<'static>,
+        ICMP6SendStruct::new(icmp_send_mux)
     );
 
     let icmp_lowpan_test = static_init!(
@@ -144,11 +146,12 @@ pub unsafe fn run(
             //radio_mac,
             VirtualMuxAlarm::new(mux_alarm),
             icmp_send_struct,
+            LeasableBuffer::new(&mut ECHO_DATA),
             net_cap
         )
     );
 
-    ip6_sender.set_client(icmp_send_struct);
+    ip6_sender.set_client(icmp_send_mux);
     icmp_send_struct.set_client(icmp_lowpan_test);
     icmp_lowpan_test.alarm.set_alarm_client(icmp_lowpan_test);
     ipsender_virtual_alarm.set_alarm_client(ip6_sender);
@@ -158,7 +161,8 @@ pub unsafe fn run(
 impl<'a, A: time::Alarm<'a>> capsules::net::icmpv6::icmpv6_send::ICMP6SendClient
     for LowpanICMPTest<'a, A>
 {
-    fn send_done(&self, result: ReturnCode) {
+    fn send_done(&self, result: ReturnCode, buf: LeasableBuffer<'static, u8>) {
+        self.echo_data.replace(buf);
         match result {
             ReturnCode::SUCCESS => {
                 debug!("ICMP Echo Request Packet Sent!");
@@ -176,12 +180,14 @@ impl<'a, A: time::Alarm<'a>> LowpanICMPTest<'a, A> {
     pub fn new(
         alarm: A,
         icmp_sender: &'a dyn ICMP6Sender<'a>,
+        echo_data: LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
     ) -> LowpanICMPTest<'a, A> {
         LowpanICMPTest {
             alarm: alarm,
             test_counter: Cell::new(0),
             icmp_sender: icmp_sender,
+            echo_data: MapCell::new(echo_data),
             net_cap: net_cap,
         }
     }
@@ -230,10 +236,12 @@ impl<'a, A: time::Alarm<'a>> LowpanICMPTest<'a, A> {
 
     fn send_next(&self) {
         let icmp_hdr = ICMP6Header::new(ICMP6Type::Type128); // Echo Request
-        unsafe {
-            self.icmp_sender
-                .send(DST_ADDR, icmp_hdr, &mut ICMP_PAYLOAD, self.net_cap)
-        };
+        self.echo_data.take().map(|buf| {
+            if let Err(buf) = self.icmp_sender.send(DST_ADDR, icmp_hdr, buf, self.net_cap) {
+                debug!("Failed to queue ICMP Packet!");
+                self.echo_data.replace(buf);
+            }
+        });
     }
 }
 
@@ -5,6 +5,7 @@
 // https://github.com/rust-lang/rust/issues/62184.
 #![cfg_attr(not(doc), no_main)]
 
+use capsules::net::ethernet::MacAddr;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
@@ -71,6 +72,10 @@ impl InterruptService<()> for LiteXSimInterruptablePeripherals {
 
 const NUM_PROCS: usize = 4;
 
+// Default LiteX MAC address, matching the one used by the LiteX BIOS for
+// network boot. The IPv4 address is obtained with DHCP.
+const ETHMAC_MAC_ADDR: MacAddr = MacAddr([0x10, 0xe2, 0xd5, 0x00, 0x00, 0x00]);
+
 // Actual memory for holding the active process structures. Need an
 // empty list at least.
 static mut PROCESSES: [Option<&'static dyn kernel::procs::ProcessType>; NUM_PROCS] =
@@ -127,6 +132,8 @@ struct LiteXSim {
             >,
         >,
     >,
+    eth_driver: &'static capsules::ethernet::EthernetDriver<'static>,
+    dns_driver: &'static capsules::net::dns::driver::DnsDriver<'static>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -139,6 +146,8 @@ impl Platform for LiteXSim {
             capsules::console::DRIVER_NUM => f(Some(self.console)),
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::low_level_debug::DRIVER_NUM => f(Some(self.lldb)),
+            capsules::ethernet::DRIVER_NUM => f(Some(self.eth_driver)),
+            capsules::net::dns::driver::DRIVER_NUM => f(Some(self.dns_driver)),
             _ => f(None),
         }
     }
@@ -323,6 +332,43 @@ pub unsafe fn reset_handler() {
     // Initialize the ETHMAC controller
     ethmac0.initialize();
 
+    // Share the adapter between the raw frame driver and the IPv4 stack
+    let (eth_mux, eth_driver) =
+        components::ethernet::EthernetComponent::new(board_kernel, ethmac0).finalize(());
+
+    // IPv4 configured with DHCP, answering ARP and ICMP echo requests
+    let (ip4, udp4_mux, udp4_port_table) =
+        components::ipv4::IP4Component::new(eth_mux, ETHMAC_MAC_ADDR, None, mux_alarm).finalize(
+            components::ip4_component_helper!(
+                litex_vexriscv::timer::LiteXAlarm<
+                    'static,
+                    'static,
+                    socc::SoCRegisterFmt,
+                    socc::ClockFrequency,
+                >
+            ),
+        );
+    let dhcp = components::dhcp::Dhcp4Component::new(udp4_mux, udp4_port_table, ip4, mux_alarm)
+        .finalize(components::dhcp4_component_helper!(
+            litex_vexriscv::timer::LiteXAlarm<
+                'static,
+                'static,
+                socc::SoCRegisterFmt,
+                socc::ClockFrequency,
+            >
+        ));
+    let (dns_resolver, dns_driver) =
+        components::dns::Dns4Component::new(board_kernel, udp4_mux, udp4_port_table, mux_alarm)
+            .finalize(components::dns4_component_helper!(
+                litex_vexriscv::timer::LiteXAlarm<
+                    'static,
+                    'static,
+                    socc::SoCRegisterFmt,
+                    socc::ClockFrequency,
+                >
+            ));
+    dhcp.set_dns_config(dns_resolver);
+
     // ---------- INITIALIZE CHIP, ENABLE INTERRUPTS ----------
 
     let interrupt_service = static_init!(
@@ -366,6 +412,9 @@ pub unsafe fn reset_handler() {
     // Unmask all interrupt sources in the interrupt controller
     chip.unmask_interrupts();
 
+    // Obtain an IPv4 address now that the Ethernet adapter can interrupt
+    dhcp.start();
+
     // Setup the console.
     let console = components::console::ConsoleComponent::new(board_kernel, uart_mux).finalize(());
     // Create the debugger object that handles calls to `debug!()`.
@@ -391,6 +440,8 @@ pub unsafe fn reset_handler() {
         console: console,
         alarm: alarm,
         lldb: lldb,
+        eth_driver,
+        dns_driver,
     };
 
     kernel::procs::load_processes(
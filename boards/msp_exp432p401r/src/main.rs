@@ -58,6 +58,7 @@ struct MspExp432P401R {
     >,
     ipc: kernel::ipc::IPC<NUM_PROCS>,
     adc: &'static capsules::adc::AdcDedicated<'static, msp432::adc::Adc<'static>>,
+    date_time: &'static capsules::date_time::DateTimeDriver<'static>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -74,6 +75,7 @@ impl Platform for MspExp432P401R {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             capsules::adc::DRIVER_NUM => f(Some(self.adc)),
+            capsules::date_time::DRIVER_NUM => f(Some(self.date_time)),
             _ => f(None),
         }
     }
@@ -311,6 +313,10 @@ pub unsafe fn reset_handler() {
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(msp432::timer::TimerA));
 
+    // Setup the real-time clock
+    let date_time =
+        components::date_time::DateTimeComponent::new(board_kernel, &peripherals.rtc).finalize(());
+
     // Setup ADC
 
     setup_adc_pins(&peripherals.gpio);
@@ -376,6 +382,7 @@ pub unsafe fn reset_handler() {
         alarm: alarm,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
         adc: adc,
+        date_time: date_time,
     };
 
     debug!("Initialization complete. Entering main loop");
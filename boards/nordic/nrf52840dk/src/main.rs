@@ -70,6 +70,7 @@ use capsules::net::ieee802154::MacAddress;
 use capsules::net::ipv6::ip_utils::IPAddr;
 use capsules::virtual_aes_ccm::MuxAES128CCM;
 use capsules::virtual_alarm::VirtualMuxAlarm;
+use core::cell::Cell;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
 use kernel::hil::led::LedLow;
@@ -101,13 +102,12 @@ const UART_TXD: Pin = Pin::P0_06;
 const UART_CTS: Option<Pin> = Some(Pin::P0_07);
 const UART_RXD: Pin = Pin::P0_08;
 
-const SPI_MOSI: Pin = Pin::P0_20;
-const SPI_MISO: Pin = Pin::P0_21;
-const SPI_CLK: Pin = Pin::P0_19;
-
-const SPI_MX25R6435F_CHIP_SELECT: Pin = Pin::P0_17;
-const SPI_MX25R6435F_WRITE_PROTECT_PIN: Pin = Pin::P0_22;
-const SPI_MX25R6435F_HOLD_PIN: Pin = Pin::P0_23;
+const QSPI_SCK: Pin = Pin::P0_19;
+const QSPI_CSN: Pin = Pin::P0_17;
+const QSPI_IO0: Pin = Pin::P0_20;
+const QSPI_IO1: Pin = Pin::P0_21;
+const QSPI_IO2: Pin = Pin::P0_22;
+const QSPI_IO3: Pin = Pin::P0_23;
 
 // Constants related to the configuration of the 15.4 network stack
 const PAN_ID: u16 = 0xABCD;
@@ -173,6 +173,7 @@ pub struct Platform {
     >,
     nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
     udp_driver: &'static capsules::net::udp::UDPDriver<'static>,
+    date_time: &'static capsules::date_time::DateTimeDriver<'static>,
 }
 
 impl kernel::Platform for Platform {
@@ -193,6 +194,7 @@ impl kernel::Platform for Platform {
             capsules::analog_comparator::DRIVER_NUM => f(Some(self.analog_comparator)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
             capsules::net::udp::DRIVER_NUM => f(Some(self.udp_driver)),
+            capsules::date_time::DRIVER_NUM => f(Some(self.date_time)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -327,9 +329,21 @@ pub unsafe fn reset_handler() {
     rtc.start();
     let mux_alarm = components::alarm::AlarmMuxComponent::new(rtc)
         .finalize(components::alarm_mux_component_helper!(nrf52840::rtc::Rtc));
+    let power_manager = static_init!(
+        kernel::power::PowerManager<'static, nrf52840::rtc::Rtc<'static>>,
+        kernel::power::PowerManager::new(rtc)
+    );
+    power_manager.add_constraint(base_peripherals.ieee802154_radio.power_constraint());
+    board_kernel.set_power_manager(power_manager);
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(nrf52840::rtc::Rtc));
 
+    // The RTC keeps running in System ON sleep, so it also keeps the date
+    let clock = components::date_time::SoftwareClockComponent::new(rtc, mux_alarm).finalize(
+        components::software_clock_component_helper!(nrf52840::rtc::Rtc),
+    );
+    let date_time = components::date_time::DateTimeComponent::new(board_kernel, clock).finalize(());
+
     let channel = nrf52_components::UartChannelComponent::new(
         uart_channel,
         mux_alarm,
@@ -391,32 +405,33 @@ pub unsafe fn reset_handler() {
     ));
 
     let local_ip_ifaces = static_init!(
-        [IPAddr; 3],
+        [Cell<IPAddr>; 3],
         [
-            IPAddr([
+            Cell::new(IPAddr([
                 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
                 0x0e, 0x0f,
-            ]),
-            IPAddr([
+            ])),
+            Cell::new(IPAddr([
                 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
                 0x1e, 0x1f,
-            ]),
-            IPAddr::generate_from_mac(capsules::net::ieee802154::MacAddress::Short(
-                serial_num_bottom_16
+            ])),
+            Cell::new(IPAddr::generate_from_mac(
+                capsules::net::ieee802154::MacAddress::Short(serial_num_bottom_16)
             )),
         ]
     );
 
-    let (udp_send_mux, udp_recv_mux, udp_port_table) = components::udp_mux::UDPMuxComponent::new(
-        mux_mac,
-        DEFAULT_CTX_PREFIX_LEN,
-        DEFAULT_CTX_PREFIX,
-        DST_MAC_ADDR,
-        src_mac_from_serial_num,
-        local_ip_ifaces,
-        mux_alarm,
-    )
-    .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
+    let (udp_send_mux, udp_recv_mux, udp_port_table, _, _) =
+        components::udp_mux::UDPMuxComponent::new(
+            mux_mac,
+            DEFAULT_CTX_PREFIX_LEN,
+            DEFAULT_CTX_PREFIX,
+            DST_MAC_ADDR,
+            src_mac_from_serial_num,
+            local_ip_ifaces,
+            mux_alarm,
+        )
+        .finalize(components::udp_mux_component_helper!(nrf52840::rtc::Rtc));
 
     // UDP driver initialization happens here
     let udp_driver = components::udp_driver::UDPDriverComponent::new(
@@ -434,28 +449,24 @@ pub unsafe fn reset_handler() {
 
     let rng = components::rng::RngComponent::new(board_kernel, &base_peripherals.trng).finalize(());
 
-    // SPI
-    let mux_spi = components::spi::SpiMuxComponent::new(&base_peripherals.spim0)
-        .finalize(components::spi_mux_component_helper!(nrf52840::spi::SPIM));
-
-    base_peripherals.spim0.configure(
-        nrf52840::pinmux::Pinmux::new(SPI_MOSI as u32),
-        nrf52840::pinmux::Pinmux::new(SPI_MISO as u32),
-        nrf52840::pinmux::Pinmux::new(SPI_CLK as u32),
+    // QSPI
+    nrf52840_peripherals.qspi.set_pins(
+        nrf52840::pinmux::Pinmux::new(QSPI_SCK as u32),
+        nrf52840::pinmux::Pinmux::new(QSPI_CSN as u32),
+        [
+            nrf52840::pinmux::Pinmux::new(QSPI_IO0 as u32),
+            nrf52840::pinmux::Pinmux::new(QSPI_IO1 as u32),
+            nrf52840::pinmux::Pinmux::new(QSPI_IO2 as u32),
+            nrf52840::pinmux::Pinmux::new(QSPI_IO3 as u32),
+        ],
     );
 
-    let mx25r6435f = components::mx25r6435f::Mx25r6435fComponent::new(
-        &gpio_port[SPI_MX25R6435F_WRITE_PROTECT_PIN],
-        &gpio_port[SPI_MX25R6435F_HOLD_PIN],
-        &gpio_port[SPI_MX25R6435F_CHIP_SELECT] as &dyn kernel::hil::gpio::Pin,
-        mux_alarm,
-        mux_spi,
-    )
-    .finalize(components::mx25r6435f_component_helper!(
-        nrf52840::spi::SPIM,
-        nrf52840::gpio::GPIOPin,
-        nrf52840::rtc::Rtc
-    ));
+    let mx25r6435f =
+        components::mx25r6435f::Mx25r6435fQspiComponent::new(&nrf52840_peripherals.qspi, mux_alarm)
+            .finalize(components::mx25r6435f_qspi_component_helper!(
+                nrf52840::qspi::Qspi,
+                nrf52840::rtc::Rtc
+            ));
 
     let nonvolatile_storage = components::nonvolatile_storage::NonvolatileStorageComponent::new(
         board_kernel,
@@ -466,10 +477,9 @@ pub unsafe fn reset_handler() {
         0x60000, // Length of kernel region
     )
     .finalize(components::nv_storage_component_helper!(
-        capsules::mx25r6435f::MX25R6435F<
+        capsules::mx25r6435f::MX25R6435FQspi<
             'static,
-            capsules::virtual_spi::VirtualSpiMasterDevice<'static, nrf52840::spi::SPIM>,
-            nrf52840::gpio::GPIOPin,
+            nrf52840::qspi::Qspi,
             VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
         >
     ));
@@ -494,10 +504,73 @@ pub unsafe fn reset_handler() {
     //         components::multi_alarm_test_component_buf!(nrf52840::rtc::Rtc),
     //     );
 
+    //--------------------------------------------------------------------------
+    // USB DFU
+    //--------------------------------------------------------------------------
+
+    // Uncomment to let `dfu-util` replace the applications over the nRF USB
+    // port. The board starts in DFU runtime mode, switches to DFU mode on
+    // request and resets once a download ends. DFU does not authenticate
+    // the images it receives, so anyone with access to the USB port can
+    // replace the applications.
+
+    // // Resets the chip after a download, so the kernel loads the new
+    // // applications.
+    // fn dfu_reset() {
+    //     unsafe {
+    //         cortexm4::scb::reset();
+    //     }
+    // }
+    //
+    // let dfu_targets = static_init!(
+    //     [capsules::usb::dfu::Target; 1],
+    //     [capsules::usb::dfu::Target {
+    //         kind: capsules::usb::dfu::ImageKind::Apps,
+    //         start: &_sapps as *const u8 as usize,
+    //         length: &_eapps as *const u8 as usize - &_sapps as *const u8 as usize,
+    //     }]
+    // );
+    // let dfu = components::dfu::DfuComponent::new(
+    //     board_kernel,
+    //     &nrf52840_peripherals.usbd,
+    //     &base_peripherals.nvmc,
+    //     mux_alarm,
+    //     dfu_targets,
+    //     Some(&dfu_reset),
+    // )
+    // .finalize(components::dfu_component_helper!(
+    //     nrf52840::usbd::Usbd<'static>,
+    //     nrf52840::nvmc::Nvmc,
+    //     nrf52840::rtc::Rtc<'static>
+    // ));
+    //
+    // let usb_strings = static_init!(
+    //     [&str; 3],
+    //     [
+    //         "Nordic Semiconductor", // Manufacturer
+    //         "nRF52840dk - TockOS",  // Product
+    //         "serial0001",           // Serial number
+    //     ]
+    // );
+    // let composite = components::usb_composite::CompositeDeviceComponent::new(
+    //     &nrf52840_peripherals.usbd,
+    //     capsules::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+    //     0x1915, // Nordic Semiconductor
+    //     0x503a, // lowRISC generic FS USB
+    //     usb_strings,
+    // )
+    // .finalize(components::usb_composite_component_helper!(
+    //     nrf52840::usbd::Usbd<'static>
+    // ));
+    // composite.add_function(dfu);
+    // composite.enable();
+    // composite.attach();
+
     //--------------------------------------------------------------------------
     // USB CTAP EXAMPLE
     //--------------------------------------------------------------------------
-    // Uncomment to experiment with this.
+    // Uncomment to experiment with this. It needs the USB controller, so
+    // do not also enable the USB DFU example above.
 
     // // Create the strings we include in the USB descriptor.
     // let strings = static_init!(
@@ -509,19 +582,18 @@ pub unsafe fn reset_handler() {
     //     ]
     // );
 
-    // let ctap_send_buffer = static_init!([u8; 64], [0; 64]);
-    // let ctap_recv_buffer = static_init!([u8; 64], [0; 64]);
-
     // let (ctap, _ctap_driver) = components::ctap::CtapComponent::new(
-    //     &peripherals.usbd,
+    //     &nrf52840_peripherals.usbd,
+    //     mux_alarm,
     //     0x1915, // Nordic Semiconductor
     //     0x503a, // lowRISC generic FS USB
     //     strings,
     //     board_kernel,
-    //     ctap_send_buffer,
-    //     ctap_recv_buffer,
     // )
-    // .finalize(components::usb_ctap_component_helper!(nrf52840::usbd::Usbd));
+    // .finalize(components::usb_ctap_component_helper!(
+    //     nrf52840::usbd::Usbd,
+    //     nrf52840::rtc::Rtc<'static>
+    // ));
 
     // ctap.enable();
     // ctap.attach();
@@ -540,6 +612,7 @@ pub unsafe fn reset_handler() {
         analog_comparator,
         nonvolatile_storage,
         udp_driver,
+        date_time,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
     };
 
@@ -1,9 +1,22 @@
-//! Component for BLE radio on nRF52 based platforms.
+//! Components for BLE radio on nRF52 based platforms.
+//!
+//! `BLEComponent` provides the advertising driver and `BLEGattComponent` a
+//! connectable GATT server. Both drive the radio on their own, so a board
+//! uses one or the other.
 //!
 //! Usage
 //! -----
 //! ```rust
 //! let ble_radio = BLEComponent::new(board_kernel, &nrf52::ble_radio::RADIO, mux_alarm).finalize();
+//!
+//! let ble_gatt = BLEGattComponent::new(
+//!     board_kernel,
+//!     &nrf52::ble_radio::RADIO,
+//!     mux_alarm,
+//!     [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6],
+//!     b"Tock",
+//! )
+//! .finalize(());
 //! ```
 
 use capsules;
@@ -78,3 +91,126 @@ impl Component for BLEComponent {
         ble_radio
     }
 }
+
+/// Attributes in the GATT database, including the 9 of the GAP and GATT
+/// services.
+const GATT_ATTRIBUTES: usize = 48;
+/// Bytes of attribute values in the GATT database.
+const GATT_VALUES_LEN: usize = 512;
+const L2CAP_BUF_LEN: usize = capsules::ble::gatt::MAX_MTU + capsules::ble::l2cap::L2CAP_HEADER_LEN;
+
+static mut LL_TX_BUF: [u8; capsules::ble::link_layer::PDU_BUF_LEN] =
+    [0; capsules::ble::link_layer::PDU_BUF_LEN];
+static mut LL_RX_BUF: [u8; capsules::ble::link_layer::PDU_BUF_LEN] =
+    [0; capsules::ble::link_layer::PDU_BUF_LEN];
+static mut L2CAP_TX_BUF: [u8; L2CAP_BUF_LEN] = [0; L2CAP_BUF_LEN];
+static mut L2CAP_RX_BUF: [u8; L2CAP_BUF_LEN] = [0; L2CAP_BUF_LEN];
+static mut ATT_BUF: [u8; capsules::ble::gatt::MAX_MTU] = [0; capsules::ble::gatt::MAX_MTU];
+static mut GATT_ATTRIBUTE_TABLE: [Option<capsules::ble::gatt::Attribute>; GATT_ATTRIBUTES] =
+    [None; GATT_ATTRIBUTES];
+static mut GATT_VALUES: [u8; GATT_VALUES_LEN] = [0; GATT_VALUES_LEN];
+
+type LinkLayer = capsules::ble::link_layer::LinkLayer<
+    'static,
+    nrf52::ble_radio::Radio<'static>,
+    VirtualMuxAlarm<'static, Rtc<'static>>,
+>;
+type L2cap = capsules::ble::l2cap::L2cap<
+    'static,
+    nrf52::ble_radio::Radio<'static>,
+    VirtualMuxAlarm<'static, Rtc<'static>>,
+>;
+
+pub struct BLEGattComponent {
+    board_kernel: &'static kernel::Kernel,
+    radio: &'static nrf52::ble_radio::Radio<'static>,
+    mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc<'static>>,
+    address: [u8; capsules::ble::link_layer::ADDRESS_LEN],
+    device_name: &'static [u8],
+}
+
+impl BLEGattComponent {
+    /// `address` is the static random device address, least significant
+    /// byte first, and `device_name` the name the GAP service reports.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        radio: &'static nrf52::ble_radio::Radio,
+        mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc>,
+        address: [u8; capsules::ble::link_layer::ADDRESS_LEN],
+        device_name: &'static [u8],
+    ) -> BLEGattComponent {
+        BLEGattComponent {
+            board_kernel: board_kernel,
+            radio: radio,
+            mux_alarm: mux_alarm,
+            address: address,
+            device_name: device_name,
+        }
+    }
+}
+
+impl Component for BLEGattComponent {
+    type StaticInput = ();
+    type Output = &'static capsules::ble::driver::GattDriver<
+        'static,
+        nrf52::ble_radio::Radio<'static>,
+        VirtualMuxAlarm<'static, Rtc<'static>>,
+    >;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let ll_virtual_alarm = static_init!(
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+            capsules::virtual_alarm::VirtualMuxAlarm::new(self.mux_alarm)
+        );
+        let link_layer = static_init!(
+            LinkLayer,
+            capsules::ble::link_layer::LinkLayer::new(
+                self.radio,
+                ll_virtual_alarm,
+                self.address,
+                &mut LL_TX_BUF,
+                &mut LL_RX_BUF
+            )
+        );
+        ll_virtual_alarm.set_alarm_client(link_layer);
+        kernel::hil::ble_connection::BleConnectionDriver::set_connection_client(
+            self.radio, link_layer,
+        );
+
+        let l2cap = static_init!(
+            L2cap,
+            capsules::ble::l2cap::L2cap::new(link_layer, &mut L2CAP_RX_BUF, &mut L2CAP_TX_BUF)
+        );
+        link_layer.set_client(l2cap);
+
+        let gatt = static_init!(
+            capsules::ble::gatt::GattServer<'static>,
+            capsules::ble::gatt::GattServer::new(
+                l2cap,
+                &mut GATT_ATTRIBUTE_TABLE,
+                &mut GATT_VALUES,
+                &mut ATT_BUF
+            )
+        );
+        l2cap.set_att_client(gatt);
+        gatt.add_gap_service(self.device_name, 0);
+
+        let gatt_driver = static_init!(
+            capsules::ble::driver::GattDriver<
+                'static,
+                nrf52::ble_radio::Radio,
+                VirtualMuxAlarm<'static, Rtc>,
+            >,
+            capsules::ble::driver::GattDriver::new(
+                gatt,
+                link_layer,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        gatt.set_client(gatt_driver);
+
+        gatt_driver
+    }
+}
@@ -61,6 +61,7 @@ struct NucleoF429ZI {
     >,
     temperature: &'static capsules::temperature::TemperatureSensor<'static>,
     gpio: &'static capsules::gpio::GPIO<'static, stm32f429zi::gpio::Pin<'static>>,
+    can: &'static capsules::can::CanDriver<'static>,
 }
 
 /// Mapping of integer syscalls to objects that implement syscalls.
@@ -78,6 +79,7 @@ impl Platform for NucleoF429ZI {
             capsules::temperature::DRIVER_NUM => f(Some(self.temperature)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
+            capsules::can::DRIVER_NUM => f(Some(self.can)),
             _ => f(None),
         }
     }
@@ -148,6 +150,18 @@ unsafe fn set_pin_primary_functions(
         pin.set_alternate_function(AlternateFunction::AF7);
     });
 
+    // pd0 and pd1 are CAN1_RX and CAN1_TX, to be connected to a transceiver
+    gpio_ports.get_pin(PinId::PD00).map(|pin| {
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF9 is CAN1_RX
+        pin.set_alternate_function(AlternateFunction::AF9);
+    });
+    gpio_ports.get_pin(PinId::PD01).map(|pin| {
+        pin.set_mode(Mode::AlternateFunctionMode);
+        // AF9 is CAN1_TX
+        pin.set_alternate_function(AlternateFunction::AF9);
+    });
+
     gpio_ports.get_port_from_port_id(PortId::C).enable_clock();
 
     // button is connected on pc13
@@ -509,6 +523,10 @@ pub unsafe fn reset_handler() {
         ),
     );
 
+    // CAN
+    let can_mux = components::can::CanMuxComponent::new(&base_peripherals.can1).finalize(());
+    let can = components::can::CanDriverComponent::new(board_kernel, can_mux).finalize(());
+
     let nucleo_f429zi = NucleoF429ZI {
         console: console,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
@@ -518,6 +536,7 @@ pub unsafe fn reset_handler() {
         button: button,
         alarm: alarm,
         gpio: gpio,
+        can: can,
     };
 
     // // Optional kernel tests
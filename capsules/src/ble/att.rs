@@ -0,0 +1,103 @@
+//! Attribute protocol definitions.
+//!
+//! BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F]
+
+/// The default and smallest ATT_MTU of LE.
+pub const DEFAULT_MTU: usize = 23;
+
+// Opcodes, section 3.4.8
+pub const ERROR_RSP: u8 = 0x01;
+pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+pub const FIND_INFORMATION_REQ: u8 = 0x04;
+pub const FIND_INFORMATION_RSP: u8 = 0x05;
+pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+pub const READ_BY_TYPE_REQ: u8 = 0x08;
+pub const READ_BY_TYPE_RSP: u8 = 0x09;
+pub const READ_REQ: u8 = 0x0a;
+pub const READ_RSP: u8 = 0x0b;
+pub const READ_BLOB_REQ: u8 = 0x0c;
+pub const READ_BLOB_RSP: u8 = 0x0d;
+pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+pub const WRITE_REQ: u8 = 0x12;
+pub const WRITE_RSP: u8 = 0x13;
+pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+pub const HANDLE_VALUE_IND: u8 = 0x1d;
+pub const HANDLE_VALUE_CFM: u8 = 0x1e;
+pub const WRITE_CMD: u8 = 0x52;
+/// Set in the opcodes of commands, which are never answered.
+pub const COMMAND_FLAG: u8 = 0x40;
+
+// Error codes, section 3.4.1.1
+pub const INVALID_HANDLE: u8 = 0x01;
+pub const READ_NOT_PERMITTED: u8 = 0x02;
+pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+pub const INVALID_PDU: u8 = 0x04;
+pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+pub const INVALID_OFFSET: u8 = 0x07;
+pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+
+/// The Bluetooth Base UUID, 00000000-0000-1000-8000-00805F9B34FB, least
+/// significant byte first as on the air. 16-bit UUIDs replace bytes 12 and
+/// 13.
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// A 128-bit UUID, least significant byte first
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Parses a 2 or 16 byte UUID.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Uuid> {
+        match bytes.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(bytes);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Writes the UUID to the start of `buf`, which must fit it.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        match *self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(&uuid),
+        }
+    }
+
+    fn to_128(&self) -> [u8; 16] {
+        match *self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                full[12..14].copy_from_slice(&uuid.to_le_bytes());
+                full
+            }
+            Uuid::Uuid128(uuid) => uuid,
+        }
+    }
+
+    /// Whether the UUIDs are the same, including a 16-bit UUID and its
+    /// 128-bit form.
+    pub fn matches(&self, other: &Uuid) -> bool {
+        self.to_128() == other.to_128()
+    }
+}
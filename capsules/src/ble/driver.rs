@@ -0,0 +1,296 @@
+//! Userspace interface to a GATT server.
+//!
+//! Lets processes add services and characteristics to the attribute
+//! database of a [GattServer](../gatt/struct.GattServer.html), update and
+//! notify their values, learn about the values the peer writes, and control
+//! connectable advertising of the [LinkLayer](../link_layer/struct.LinkLayer.html).
+//!
+//! Characteristics belong to the process that added them: only it can set
+//! their values, and only it is told when the peer writes them. Attributes
+//! cannot be removed, so the database keeps the services of processes that
+//! exited until the board restarts.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let gatt_driver = static_init!(
+//!     capsules::ble::driver::GattDriver<'static, nrf52::ble_radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::driver::GattDriver::new(
+//!         gatt,
+//!         link_layer,
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! gatt.set_client(gatt_driver);
+//! ```
+
+use crate::ble::att::Uuid;
+use crate::ble::gatt::{GattServer, GattServerClient};
+use crate::ble::link_layer::LinkLayer;
+use core::cmp;
+use kernel::hil::ble_connection::BleConnectionDriver;
+use kernel::hil::time::Alarm;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+#[derive(Default)]
+pub struct App {
+    write_callback: Option<Callback>,
+    connection_callback: Option<Callback>,
+    notify_callback: Option<Callback>,
+    input: Option<AppSlice<Shared, u8>>,
+    rx: Option<AppSlice<Shared, u8>>,
+    advertising_data: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct GattDriver<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> {
+    gatt: &'a GattServer<'a>,
+    link_layer: &'a LinkLayer<'a, R, A>,
+    apps: Grant<App>,
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> GattDriver<'a, R, A> {
+    pub fn new(
+        gatt: &'a GattServer<'a>,
+        link_layer: &'a LinkLayer<'a, R, A>,
+        grant: Grant<App>,
+    ) -> GattDriver<'a, R, A> {
+        GattDriver {
+            gatt: gatt,
+            link_layer: link_layer,
+            apps: grant,
+        }
+    }
+
+    /// The attribute owner of a process. Owner 0 is the kernel.
+    fn owner(appid: AppId) -> usize {
+        appid.id() + 1
+    }
+
+    /// The UUID `uuid16`, or the 128-bit UUID at the start of the input
+    /// buffer if it is 0.
+    fn uuid(&self, appid: AppId, uuid16: usize) -> Option<Uuid> {
+        if uuid16 != 0 {
+            return Some(Uuid::Uuid16(uuid16 as u16));
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                app.input
+                    .as_ref()
+                    .and_then(|input| input.as_ref().get(..16).and_then(Uuid::from_bytes))
+            })
+            .unwrap_or(None)
+    }
+
+    fn add_service(&self, appid: AppId, uuid16: usize) -> ReturnCode {
+        match self.uuid(appid, uuid16) {
+            Some(uuid) => match self.gatt.add_service(uuid, Self::owner(appid)) {
+                Ok(handle) => ReturnCode::SuccessWithValue {
+                    value: handle as usize,
+                },
+                Err(e) => e,
+            },
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn add_characteristic(&self, appid: AppId, uuid16: usize, arg2: usize) -> ReturnCode {
+        let properties = arg2 as u8;
+        let capacity = arg2 >> 8;
+        match self.uuid(appid, uuid16) {
+            Some(uuid) => {
+                match self
+                    .gatt
+                    .add_characteristic(uuid, properties, capacity, Self::owner(appid))
+                {
+                    Ok(handle) => ReturnCode::SuccessWithValue {
+                        value: handle as usize,
+                    },
+                    Err(e) => e,
+                }
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn set_value(&self, appid: AppId, handle: usize, len: usize) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                app.input.as_ref().map_or(ReturnCode::EINVAL, |input| {
+                    if len > input.len() {
+                        return ReturnCode::EINVAL;
+                    }
+                    self.gatt
+                        .set_value(handle as u16, &input.as_ref()[..len], Self::owner(appid))
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_advertising(&self, appid: AppId, interval_ms: usize, len: usize) -> ReturnCode {
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                app.advertising_data
+                    .as_ref()
+                    .map_or(ReturnCode::EINVAL, |data| {
+                        if len > data.len() {
+                            ReturnCode::EINVAL
+                        } else {
+                            self.link_layer.set_advertising_data(&data.as_ref()[..len])
+                        }
+                    })
+            })
+            .unwrap_or_else(|err| err.into());
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.link_layer.start_advertising(interval_ms as u32)
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> GattServerClient for GattDriver<'a, R, A> {
+    fn connected(&self) {
+        self.apps.each(|app| {
+            app.connection_callback.map(|mut cb| cb.schedule(1, 0, 0));
+        });
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.apps.each(|app| {
+            app.connection_callback
+                .map(|mut cb| cb.schedule(0, reason as usize, 0));
+        });
+    }
+
+    fn write(&self, owner: usize, handle: u16, value: &[u8]) {
+        self.apps.each(|app| {
+            if Self::owner(app.appid()) != owner {
+                return;
+            }
+            let len = app.rx.as_mut().map_or(0, |rx| {
+                let len = cmp::min(rx.len(), value.len());
+                rx.as_mut()[..len].copy_from_slice(&value[..len]);
+                len
+            });
+            app.write_callback
+                .map(|mut cb| cb.schedule(handle as usize, len, 0));
+        });
+    }
+
+    fn notify_done(&self, owner: usize, handle: u16, result: ReturnCode) {
+        self.apps.each(|app| {
+            if Self::owner(app.appid()) == owner {
+                app.notify_callback
+                    .map(|mut cb| cb.schedule(handle as usize, usize::from(result), 0));
+            }
+        });
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> Driver for GattDriver<'a, R, A> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Input buffer. Holds 128-bit UUIDs of services and
+    ///        characteristics to add, least significant byte first, and
+    ///        the values to set.
+    /// - `1`: Receive buffer. Values the peer writes to characteristics of
+    ///        the process are copied here, truncated to fit.
+    /// - `2`: Advertising data buffer.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0..=2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    match allow_num {
+                        0 => app.input = slice,
+                        1 => app.rx = slice,
+                        _ => app.advertising_data = slice,
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Write callback. Called with the handle and the length of the
+    ///        value in the receive buffer when the peer writes a
+    ///        characteristic of the process.
+    /// - `1`: Connection callback. Called with 1 when a peer connects, and
+    ///        with 0 and the link layer error code of the reason when the
+    ///        connection ends.
+    /// - `2`: Notify callback. Called with the handle and the result when a
+    ///        notification was sent or an indication was confirmed.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0..=2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    match subscribe_num {
+                        0 => app.write_callback = callback,
+                        1 => app.connection_callback = callback,
+                        _ => app.notify_callback = callback,
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// GATT server control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Add a primary service with the 16-bit UUID `arg1`, or the
+    ///        128-bit UUID in the input buffer if `arg1` is 0. Returns the
+    ///        handle of the service.
+    /// - `2`: Add a characteristic to the last service, which the process
+    ///        must have added, with the UUID `arg1` as for services. The
+    ///        low byte of `arg2` holds the characteristic properties and
+    ///        the rest the size of its value. Returns the handle of the
+    ///        value.
+    /// - `3`: Set the value of characteristic `arg1` to the first `arg2`
+    ///        bytes of the input buffer.
+    /// - `4`: Notify or indicate the value of characteristic `arg1`, as
+    ///        the peer subscribed to. Returns EOFF if it did not.
+    /// - `5`: Advertise with the first `arg2` bytes of the advertising data
+    ///        buffer every `arg1` milliseconds until a peer connects.
+    /// - `6`: Stop advertising.
+    /// - `7`: Disconnect from the peer.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.add_service(appid, arg1),
+            2 => self.add_characteristic(appid, arg1, arg2),
+            3 => self.set_value(appid, arg1, arg2),
+            4 => self.gatt.notify(arg1 as u16, Self::owner(appid)),
+            5 => self.start_advertising(appid, arg1, arg2),
+            6 => self.link_layer.stop_advertising(),
+            7 => self.link_layer.disconnect(),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
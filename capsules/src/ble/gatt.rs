@@ -0,0 +1,790 @@
+//! GATT server.
+//!
+//! Holds an attribute database of services, characteristics and their
+//! values, and answers the attribute protocol requests of the peer on the
+//! ATT channel of an L2CAP connection (BLUETOOTH SPECIFICATION Version 4.2
+//! [Vol 3, Part G]). Services are added at runtime, either by the kernel
+//! (such as the mandatory GAP service, see `add_gap_service`) or by
+//! processes through the userspace driver, and each attribute records its
+//! owner so that only the owner can change its value.
+//!
+//! Supported are the discovery of primary services, characteristics and
+//! descriptors, reading (including long values with Read Blob), writing
+//! with and without response, and notifications and indications of values
+//! the peer subscribed to through the Client Characteristic Configuration
+//! descriptor. Subscriptions are not remembered across connections, and
+//! attribute values are limited to the current ATT_MTU when written.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let gatt = static_init!(
+//!     capsules::ble::gatt::GattServer<'static>,
+//!     capsules::ble::gatt::GattServer::new(l2cap, attributes, values, att_buf)
+//! );
+//! l2cap.set_att_client(gatt);
+//! gatt.add_gap_service(b"Tock", 0);
+//! ```
+
+use crate::ble::att::{self, Uuid};
+use crate::ble::l2cap::{L2capClient, L2capSender, CID_ATT};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::ReturnCode;
+
+// Attribute types, [Vol 3, Part G], section 3
+pub const PRIMARY_SERVICE: u16 = 0x2800;
+pub const CHARACTERISTIC: u16 = 0x2803;
+pub const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+
+// GAP and GATT services, Bluetooth assigned numbers
+const GAP_SERVICE: u16 = 0x1800;
+const GATT_SERVICE: u16 = 0x1801;
+const DEVICE_NAME: u16 = 0x2a00;
+const APPEARANCE: u16 = 0x2a01;
+
+// Characteristic properties, [Vol 3, Part G], section 3.3.1.1
+pub const PROPERTY_READ: u8 = 0x02;
+pub const PROPERTY_WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+pub const PROPERTY_WRITE: u8 = 0x08;
+pub const PROPERTY_NOTIFY: u8 = 0x10;
+pub const PROPERTY_INDICATE: u8 = 0x20;
+const SUPPORTED_PROPERTIES: u8 = PROPERTY_READ
+    | PROPERTY_WRITE_WITHOUT_RESPONSE
+    | PROPERTY_WRITE
+    | PROPERTY_NOTIFY
+    | PROPERTY_INDICATE;
+
+// Client Characteristic Configuration bits, [Vol 3, Part G], section 3.3.3.3
+const CCCD_NOTIFICATION: u8 = 0x01;
+const CCCD_INDICATION: u8 = 0x02;
+
+/// The largest ATT_MTU the server agrees to. The L2CAP buffers must fit a
+/// PDU of this size plus the L2CAP header.
+pub const MAX_MTU: usize = 64;
+
+/// The owner of attributes added by the kernel.
+pub const KERNEL_OWNER: usize = 0;
+
+/// The largest attribute value, [Vol 3, Part F], section 3.2.9
+const MAX_VALUE_LEN: usize = 512;
+
+/// An entry of the attribute database. Its handle is its index plus one.
+#[derive(Copy, Clone)]
+pub struct Attribute {
+    uuid: Uuid,
+    /// The PROPERTY_READ, PROPERTY_WRITE and PROPERTY_WRITE_WITHOUT_RESPONSE
+    /// bits that apply to the peer's accesses
+    permissions: u8,
+    /// Offset of the value in the value pool
+    offset: usize,
+    len: usize,
+    capacity: usize,
+    owner: usize,
+}
+
+impl Attribute {
+    fn is_type(&self, uuid: u16) -> bool {
+        self.uuid == Uuid::Uuid16(uuid)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Outgoing {
+    Response,
+    Notification(usize, u16),
+    Indication(usize, u16),
+}
+
+pub trait GattServerClient {
+    fn connected(&self);
+
+    /// The connection ended for `reason`, one of the link layer error codes.
+    fn disconnected(&self, reason: u8);
+
+    /// The peer wrote `value` to the characteristic value `handle` owned by
+    /// `owner`.
+    fn write(&self, owner: usize, handle: u16, value: &[u8]);
+
+    /// The notification or indication of `handle` requested by `owner`
+    /// was sent, or confirmed in the case of an indication.
+    fn notify_done(&self, owner: usize, handle: u16, result: ReturnCode);
+}
+
+pub struct GattServer<'a> {
+    l2cap: &'a dyn L2capSender,
+    client: OptionalCell<&'a dyn GattServerClient>,
+    attributes: TakeCell<'static, [Option<Attribute>]>,
+    num_attributes: Cell<usize>,
+    values: TakeCell<'static, [u8]>,
+    values_used: Cell<usize>,
+    /// Holds a PDU that could not be passed to L2CAP right away
+    att_buf: TakeCell<'static, [u8]>,
+    pending: Cell<Option<Outgoing>>,
+    pending_len: Cell<usize>,
+    sending: Cell<Option<Outgoing>>,
+    /// The indication waiting for a confirmation
+    indicating: Cell<Option<(usize, u16)>>,
+    mtu: Cell<usize>,
+    connected: Cell<bool>,
+}
+
+impl<'a> GattServer<'a> {
+    pub fn new(
+        l2cap: &'a dyn L2capSender,
+        attributes: &'static mut [Option<Attribute>],
+        values: &'static mut [u8],
+        att_buf: &'static mut [u8],
+    ) -> GattServer<'a> {
+        GattServer {
+            l2cap: l2cap,
+            client: OptionalCell::empty(),
+            attributes: TakeCell::new(attributes),
+            num_attributes: Cell::new(0),
+            values: TakeCell::new(values),
+            values_used: Cell::new(0),
+            att_buf: TakeCell::new(att_buf),
+            pending: Cell::new(None),
+            pending_len: Cell::new(0),
+            sending: Cell::new(None),
+            indicating: Cell::new(None),
+            mtu: Cell::new(att::DEFAULT_MTU),
+            connected: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn GattServerClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.get()
+    }
+
+    /// Adds the GAP service with the device name and appearance
+    /// characteristics, and the GATT service, which a server must have.
+    pub fn add_gap_service(&self, device_name: &[u8], appearance: u16) -> ReturnCode {
+        match self.add_gap_attributes(device_name, appearance) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(e) => e,
+        }
+    }
+
+    fn add_gap_attributes(&self, device_name: &[u8], appearance: u16) -> Result<(), ReturnCode> {
+        self.add_service(Uuid::Uuid16(GAP_SERVICE), KERNEL_OWNER)?;
+        let name = self.add_characteristic(
+            Uuid::Uuid16(DEVICE_NAME),
+            PROPERTY_READ,
+            device_name.len(),
+            KERNEL_OWNER,
+        )?;
+        self.set_value(name, device_name, KERNEL_OWNER);
+        let appearance_handle =
+            self.add_characteristic(Uuid::Uuid16(APPEARANCE), PROPERTY_READ, 2, KERNEL_OWNER)?;
+        self.set_value(appearance_handle, &appearance.to_le_bytes(), KERNEL_OWNER);
+        self.add_service(Uuid::Uuid16(GATT_SERVICE), KERNEL_OWNER)?;
+        Ok(())
+    }
+
+    /// Adds a primary service and returns the handle of its declaration.
+    /// The characteristics added next belong to it.
+    pub fn add_service(&self, uuid: Uuid, owner: usize) -> Result<u16, ReturnCode> {
+        let mut value = [0; 16];
+        uuid.write_to(&mut value);
+        self.add_attribute(
+            Uuid::Uuid16(PRIMARY_SERVICE),
+            PROPERTY_READ,
+            &value[..uuid.len()],
+            uuid.len(),
+            owner,
+        )
+    }
+
+    /// Adds a characteristic with `properties` and room for a `capacity`
+    /// byte value to the last service, which `owner` must have added.
+    /// Characteristics that can be notified or indicated get a Client
+    /// Characteristic Configuration descriptor. Returns the handle of the
+    /// characteristic value.
+    pub fn add_characteristic(
+        &self,
+        uuid: Uuid,
+        properties: u8,
+        capacity: usize,
+        owner: usize,
+    ) -> Result<u16, ReturnCode> {
+        if properties == 0 || properties & !SUPPORTED_PROPERTIES != 0 || capacity > MAX_VALUE_LEN {
+            return Err(ReturnCode::EINVAL);
+        }
+        let service_owner = (1..=self.num_attributes.get() as u16)
+            .rev()
+            .filter_map(|handle| self.attribute(handle))
+            .find(|attribute| attribute.is_type(PRIMARY_SERVICE))
+            .map(|service| service.owner);
+        if service_owner != Some(owner) {
+            return Err(ReturnCode::EINVAL);
+        }
+        let needed = if properties & (PROPERTY_NOTIFY | PROPERTY_INDICATE) != 0 {
+            3
+        } else {
+            2
+        };
+        if self.num_attributes.get() + needed > self.attributes.map_or(0, |a| a.len()) {
+            return Err(ReturnCode::ENOMEM);
+        }
+
+        let value_handle = self.num_attributes.get() as u16 + 2;
+        let mut declaration = [0; 19];
+        declaration[0] = properties;
+        declaration[1..3].copy_from_slice(&value_handle.to_le_bytes());
+        uuid.write_to(&mut declaration[3..]);
+        let declaration_len = 3 + uuid.len();
+        self.add_attribute(
+            Uuid::Uuid16(CHARACTERISTIC),
+            PROPERTY_READ,
+            &declaration[..declaration_len],
+            declaration_len,
+            owner,
+        )?;
+        let permissions =
+            properties & (PROPERTY_READ | PROPERTY_WRITE | PROPERTY_WRITE_WITHOUT_RESPONSE);
+        self.add_attribute(uuid, permissions, &[], capacity, owner)?;
+        if needed == 3 {
+            self.add_attribute(
+                Uuid::Uuid16(CLIENT_CHARACTERISTIC_CONFIGURATION),
+                PROPERTY_READ | PROPERTY_WRITE,
+                &[0, 0],
+                2,
+                owner,
+            )?;
+        }
+        Ok(value_handle)
+    }
+
+    /// Sets the value of the characteristic value `handle`, which `owner`
+    /// must own.
+    pub fn set_value(&self, handle: u16, value: &[u8], owner: usize) -> ReturnCode {
+        match self.attribute(handle) {
+            Some(attribute) if attribute.owner == owner && !Self::is_declaration(&attribute) => {
+                if value.len() > attribute.capacity {
+                    ReturnCode::ESIZE
+                } else {
+                    self.store_value(handle, value);
+                    ReturnCode::SUCCESS
+                }
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Sends the value of the characteristic value `handle`, which `owner`
+    /// must own, as an indication if the peer subscribed to indications and
+    /// as a notification otherwise. Returns EOFF if there is no connection
+    /// or the peer did not subscribe, and EBUSY if an indication is not
+    /// confirmed yet or another PDU is waiting to be sent.
+    pub fn notify(&self, handle: u16, owner: usize) -> ReturnCode {
+        let attribute = match self.attribute(handle) {
+            Some(attribute) if attribute.owner == owner && !Self::is_declaration(&attribute) => {
+                attribute
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        let properties = self
+            .attribute(handle - 1)
+            .filter(|declaration| declaration.is_type(CHARACTERISTIC))
+            .map_or(0, |declaration| self.read_byte(&declaration, 0));
+        let configuration = self
+            .attribute(handle + 1)
+            .filter(|cccd| cccd.is_type(CLIENT_CHARACTERISTIC_CONFIGURATION))
+            .map_or(0, |cccd| self.read_byte(&cccd, 0));
+        if !self.connected.get() {
+            return ReturnCode::EOFF;
+        }
+
+        let (opcode, kind) =
+            if properties & PROPERTY_INDICATE != 0 && configuration & CCCD_INDICATION != 0 {
+                if self.indicating.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                (att::HANDLE_VALUE_IND, Outgoing::Indication(owner, handle))
+            } else if properties & PROPERTY_NOTIFY != 0 && configuration & CCCD_NOTIFICATION != 0 {
+                (att::HANDLE_VALUE_NTF, Outgoing::Notification(owner, handle))
+            } else {
+                return ReturnCode::EOFF;
+            };
+
+        let mut pdu = [0; MAX_MTU];
+        pdu[0] = opcode;
+        pdu[1..3].copy_from_slice(&handle.to_le_bytes());
+        let len = 3 + self.read_value(&attribute, 0, &mut pdu[3..self.mtu.get()]);
+        let result = self.transmit(&pdu[..len], kind);
+        if result == ReturnCode::SUCCESS {
+            if let Outgoing::Indication(owner, handle) = kind {
+                self.indicating.set(Some((owner, handle)));
+            }
+        }
+        result
+    }
+
+    fn is_declaration(attribute: &Attribute) -> bool {
+        attribute.is_type(PRIMARY_SERVICE)
+            || attribute.is_type(CHARACTERISTIC)
+            || attribute.is_type(CLIENT_CHARACTERISTIC_CONFIGURATION)
+    }
+
+    fn add_attribute(
+        &self,
+        uuid: Uuid,
+        permissions: u8,
+        value: &[u8],
+        capacity: usize,
+        owner: usize,
+    ) -> Result<u16, ReturnCode> {
+        let index = self.num_attributes.get();
+        let offset = self.values_used.get();
+        if offset + capacity > self.values.map_or(0, |values| values.len()) {
+            return Err(ReturnCode::ENOMEM);
+        }
+        let added = self.attributes.map_or(false, |attributes| {
+            attributes.get_mut(index).map_or(false, |entry| {
+                *entry = Some(Attribute {
+                    uuid: uuid,
+                    permissions: permissions,
+                    offset: offset,
+                    len: 0,
+                    capacity: capacity,
+                    owner: owner,
+                });
+                true
+            })
+        });
+        if !added {
+            return Err(ReturnCode::ENOMEM);
+        }
+        self.num_attributes.set(index + 1);
+        self.values_used.set(offset + capacity);
+        let handle = index as u16 + 1;
+        self.store_value(handle, value);
+        Ok(handle)
+    }
+
+    fn attribute(&self, handle: u16) -> Option<Attribute> {
+        if handle == 0 || handle as usize > self.num_attributes.get() {
+            return None;
+        }
+        self.attributes
+            .map_or(None, |attributes| attributes[handle as usize - 1])
+    }
+
+    /// Copies the value of `attribute` from `offset` into `buf` and returns
+    /// how many bytes were copied.
+    fn read_value(&self, attribute: &Attribute, offset: usize, buf: &mut [u8]) -> usize {
+        let len = cmp::min(attribute.len.saturating_sub(offset), buf.len());
+        self.values.map(|values| {
+            let start = attribute.offset + offset;
+            buf[..len].copy_from_slice(&values[start..start + len]);
+        });
+        len
+    }
+
+    fn read_byte(&self, attribute: &Attribute, offset: usize) -> u8 {
+        let mut byte = [0];
+        self.read_value(attribute, offset, &mut byte);
+        byte[0]
+    }
+
+    /// Stores `value`, which must fit, as the value of the attribute
+    /// `handle`.
+    fn store_value(&self, handle: u16, value: &[u8]) {
+        self.attributes.map(|attributes| {
+            if let Some(ref mut attribute) = attributes[handle as usize - 1] {
+                attribute.len = value.len();
+                self.values.map(|values| {
+                    values[attribute.offset..attribute.offset + value.len()].copy_from_slice(value);
+                });
+            }
+        });
+    }
+
+    /// The last handle of the service whose declaration is `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        let last = self.num_attributes.get() as u16;
+        (handle + 1..=last)
+            .find(|&h| {
+                self.attribute(h)
+                    .map_or(false, |a| a.is_type(PRIMARY_SERVICE))
+            })
+            .map_or(last, |next| next - 1)
+    }
+
+    /// Passes `pdu` to L2CAP, or keeps it until L2CAP is free.
+    fn transmit(&self, pdu: &[u8], kind: Outgoing) -> ReturnCode {
+        if self.pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        match self.l2cap.send(CID_ATT, pdu) {
+            ReturnCode::SUCCESS => {
+                self.sending.set(Some(kind));
+                ReturnCode::SUCCESS
+            }
+            ReturnCode::EBUSY => self.att_buf.map_or(ReturnCode::EBUSY, |buf| {
+                buf[..pdu.len()].copy_from_slice(pdu);
+                self.pending_len.set(pdu.len());
+                self.pending.set(Some(kind));
+                ReturnCode::SUCCESS
+            }),
+            result => result,
+        }
+    }
+
+    fn respond(&self, pdu: &[u8]) {
+        self.transmit(pdu, Outgoing::Response);
+    }
+
+    fn respond_error(&self, opcode: u8, handle: u16, error: u8) {
+        let handle = handle.to_le_bytes();
+        self.respond(&[att::ERROR_RSP, opcode, handle[0], handle[1], error]);
+    }
+
+    /// Parses the handle range of a request, checking it as section 3.4.3.1
+    /// describes for Find Information and the requests that share the rule.
+    fn handle_range(&self, opcode: u8, pdu: &[u8]) -> Option<(u16, u16)> {
+        let start = u16::from_le_bytes([pdu[1], pdu[2]]);
+        let end = u16::from_le_bytes([pdu[3], pdu[4]]);
+        if start == 0 || start > end {
+            self.respond_error(opcode, start, att::INVALID_HANDLE);
+            None
+        } else {
+            Some((start, cmp::min(end, self.num_attributes.get() as u16)))
+        }
+    }
+
+    fn exchange_mtu(&self, pdu: &[u8]) {
+        if pdu.len() != 3 {
+            return self.respond_error(pdu[0], 0, att::INVALID_PDU);
+        }
+        let client_mtu = u16::from_le_bytes([pdu[1], pdu[2]]) as usize;
+        let mtu = cmp::min(MAX_MTU, self.l2cap.max_payload_len());
+        self.mtu
+            .set(cmp::max(att::DEFAULT_MTU, cmp::min(client_mtu, mtu)));
+        let server_mtu = (mtu as u16).to_le_bytes();
+        self.respond(&[att::EXCHANGE_MTU_RSP, server_mtu[0], server_mtu[1]]);
+    }
+
+    fn find_information(&self, pdu: &[u8]) {
+        if pdu.len() != 5 {
+            return self.respond_error(pdu[0], 0, att::INVALID_PDU);
+        }
+        let (start, end) = match self.handle_range(pdu[0], pdu) {
+            Some(range) => range,
+            None => return,
+        };
+        let mut rsp = [0; MAX_MTU];
+        rsp[0] = att::FIND_INFORMATION_RSP;
+        let mut len = 2;
+        let mut uuid_len = 0;
+        for handle in start..=end {
+            if let Some(attribute) = self.attribute(handle) {
+                if uuid_len == 0 {
+                    uuid_len = attribute.uuid.len();
+                    // Format 1 lists 16-bit UUIDs, format 2 128-bit UUIDs
+                    rsp[1] = if uuid_len == 2 { 1 } else { 2 };
+                }
+                if attribute.uuid.len() != uuid_len || len + 2 + uuid_len > self.mtu.get() {
+                    break;
+                }
+                rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                attribute.uuid.write_to(&mut rsp[len + 2..]);
+                len += 2 + uuid_len;
+            }
+        }
+        if uuid_len == 0 {
+            self.respond_error(pdu[0], start, att::ATTRIBUTE_NOT_FOUND);
+        } else {
+            self.respond(&rsp[..len]);
+        }
+    }
+
+    fn find_by_type_value(&self, pdu: &[u8]) {
+        if pdu.len() < 7 {
+            return self.respond_error(pdu[0], 0, att::INVALID_PDU);
+        }
+        let (start, end) = match self.handle_range(pdu[0], pdu) {
+            Some(range) => range,
+            None => return,
+        };
+        let uuid = Uuid::Uuid16(u16::from_le_bytes([pdu[5], pdu[6]]));
+        let wanted = &pdu[7..];
+        let mut rsp = [0; MAX_MTU];
+        rsp[0] = att::FIND_BY_TYPE_VALUE_RSP;
+        let mut len = 1;
+        let mut value = [0; MAX_MTU];
+        for handle in start..=end {
+            if let Some(attribute) = self.attribute(handle) {
+                if !attribute.uuid.matches(&uuid) || attribute.len != wanted.len() {
+                    continue;
+                }
+                let value_len = self.read_value(&attribute, 0, &mut value);
+                if &value[..value_len] != wanted {
+                    continue;
+                }
+                if len + 4 > self.mtu.get() {
+                    break;
+                }
+                let group_end = if attribute.is_type(PRIMARY_SERVICE) {
+                    self.group_end(handle)
+                } else {
+                    handle
+                };
+                rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                rsp[len + 2..len + 4].copy_from_slice(&group_end.to_le_bytes());
+                len += 4;
+            }
+        }
+        if len == 1 {
+            self.respond_error(pdu[0], start, att::ATTRIBUTE_NOT_FOUND);
+        } else {
+            self.respond(&rsp[..len]);
+        }
+    }
+
+    /// Answers Read By Type and Read By Group Type requests, which list the
+    /// attributes of a type with their values, and also the end of their
+    /// group for the latter.
+    fn read_by_type(&self, pdu: &[u8], group: bool) {
+        let uuid = match Uuid::from_bytes(pdu.get(5..).unwrap_or(&[])) {
+            Some(uuid) if pdu.len() >= 5 => uuid,
+            _ => return self.respond_error(pdu[0], 0, att::INVALID_PDU),
+        };
+        let (start, end) = match self.handle_range(pdu[0], pdu) {
+            Some(range) => range,
+            None => return,
+        };
+        if group && !uuid.matches(&Uuid::Uuid16(PRIMARY_SERVICE)) {
+            return self.respond_error(pdu[0], start, att::UNSUPPORTED_GROUP_TYPE);
+        }
+        let mtu = self.mtu.get();
+        let header_len = if group { 4 } else { 2 };
+        let mut rsp = [0; MAX_MTU];
+        rsp[0] = if group {
+            att::READ_BY_GROUP_TYPE_RSP
+        } else {
+            att::READ_BY_TYPE_RSP
+        };
+        let mut len = 2;
+        // Every entry of the response has the length of the first one
+        let mut entry_len = 0;
+        for handle in start..=end {
+            let attribute = match self.attribute(handle) {
+                Some(attribute) if attribute.uuid.matches(&uuid) => attribute,
+                _ => continue,
+            };
+            if attribute.permissions & PROPERTY_READ == 0 {
+                if entry_len == 0 {
+                    return self.respond_error(pdu[0], handle, att::READ_NOT_PERMITTED);
+                }
+                break;
+            }
+            let value_len = cmp::min(attribute.len, cmp::min(mtu - 2 - header_len, 255));
+            if entry_len == 0 {
+                entry_len = header_len + value_len;
+                rsp[1] = entry_len as u8;
+            } else if header_len + value_len != entry_len || len + entry_len > mtu {
+                break;
+            }
+            rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+            if group {
+                rsp[len + 2..len + 4].copy_from_slice(&self.group_end(handle).to_le_bytes());
+            }
+            self.read_value(&attribute, 0, &mut rsp[len + header_len..len + entry_len]);
+            len += entry_len;
+        }
+        if entry_len == 0 {
+            self.respond_error(pdu[0], start, att::ATTRIBUTE_NOT_FOUND);
+        } else {
+            self.respond(&rsp[..len]);
+        }
+    }
+
+    fn read(&self, pdu: &[u8], blob: bool) {
+        let expected_len = if blob { 5 } else { 3 };
+        if pdu.len() != expected_len {
+            return self.respond_error(pdu[0], 0, att::INVALID_PDU);
+        }
+        let handle = u16::from_le_bytes([pdu[1], pdu[2]]);
+        let offset = if blob {
+            u16::from_le_bytes([pdu[3], pdu[4]]) as usize
+        } else {
+            0
+        };
+        let attribute = match self.attribute(handle) {
+            Some(attribute) => attribute,
+            None => return self.respond_error(pdu[0], handle, att::INVALID_HANDLE),
+        };
+        if attribute.permissions & PROPERTY_READ == 0 {
+            return self.respond_error(pdu[0], handle, att::READ_NOT_PERMITTED);
+        }
+        if offset > attribute.len {
+            return self.respond_error(pdu[0], handle, att::INVALID_OFFSET);
+        }
+        let mut rsp = [0; MAX_MTU];
+        rsp[0] = if blob {
+            att::READ_BLOB_RSP
+        } else {
+            att::READ_RSP
+        };
+        let len = 1 + self.read_value(&attribute, offset, &mut rsp[1..self.mtu.get()]);
+        self.respond(&rsp[..len]);
+    }
+
+    fn write(&self, pdu: &[u8]) {
+        let command = pdu[0] == att::WRITE_CMD;
+        if pdu.len() < 3 {
+            if !command {
+                self.respond_error(pdu[0], 0, att::INVALID_PDU);
+            }
+            return;
+        }
+        let handle = u16::from_le_bytes([pdu[1], pdu[2]]);
+        let value = &pdu[3..];
+        let permission = if command {
+            PROPERTY_WRITE_WITHOUT_RESPONSE
+        } else {
+            PROPERTY_WRITE
+        };
+        let error = match self.attribute(handle) {
+            None => Some(att::INVALID_HANDLE),
+            Some(attribute) if attribute.permissions & permission == 0 => {
+                Some(att::WRITE_NOT_PERMITTED)
+            }
+            Some(attribute) if value.len() > attribute.capacity => {
+                Some(att::INVALID_ATTRIBUTE_VALUE_LENGTH)
+            }
+            Some(attribute) if attribute.is_type(CLIENT_CHARACTERISTIC_CONFIGURATION) => {
+                if value.len() != 2 {
+                    Some(att::INVALID_ATTRIBUTE_VALUE_LENGTH)
+                } else {
+                    self.store_value(handle, value);
+                    None
+                }
+            }
+            Some(attribute) => {
+                self.store_value(handle, value);
+                self.client
+                    .map(|client| client.write(attribute.owner, handle, value));
+                None
+            }
+        };
+        if command {
+            return;
+        }
+        match error {
+            Some(error) => self.respond_error(pdu[0], handle, error),
+            None => self.respond(&[att::WRITE_RSP]),
+        }
+    }
+
+    fn confirm(&self) {
+        if let Some((owner, handle)) = self.indicating.take() {
+            self.client
+                .map(|client| client.notify_done(owner, handle, ReturnCode::SUCCESS));
+        }
+    }
+
+    fn report(&self, kind: Outgoing, result: ReturnCode) {
+        match kind {
+            Outgoing::Response => (),
+            Outgoing::Notification(owner, handle) => {
+                self.client
+                    .map(|client| client.notify_done(owner, handle, result));
+            }
+            Outgoing::Indication(owner, handle) => {
+                // A sent indication completes when the peer confirms it
+                if result != ReturnCode::SUCCESS {
+                    self.indicating.set(None);
+                    self.client
+                        .map(|client| client.notify_done(owner, handle, result));
+                }
+            }
+        }
+    }
+
+    /// Clears all subscriptions, which only last for a connection.
+    fn reset_configurations(&self) {
+        for handle in 1..=self.num_attributes.get() as u16 {
+            if let Some(attribute) = self.attribute(handle) {
+                if attribute.is_type(CLIENT_CHARACTERISTIC_CONFIGURATION) {
+                    self.store_value(handle, &[0, 0]);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> L2capClient for GattServer<'a> {
+    fn connected(&self) {
+        self.mtu.set(att::DEFAULT_MTU);
+        self.connected.set(true);
+        self.client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.connected.set(false);
+        self.reset_configurations();
+        if let Some(kind) = self.pending.take() {
+            self.report(kind, ReturnCode::FAIL);
+        }
+        if let Some((owner, handle)) = self.indicating.take() {
+            self.client
+                .map(|client| client.notify_done(owner, handle, ReturnCode::FAIL));
+        }
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    fn receive(&self, pdu: &[u8]) {
+        if pdu.is_empty() {
+            return;
+        }
+        match pdu[0] {
+            att::EXCHANGE_MTU_REQ => self.exchange_mtu(pdu),
+            att::FIND_INFORMATION_REQ => self.find_information(pdu),
+            att::FIND_BY_TYPE_VALUE_REQ => self.find_by_type_value(pdu),
+            att::READ_BY_TYPE_REQ => self.read_by_type(pdu, false),
+            att::READ_BY_GROUP_TYPE_REQ => self.read_by_type(pdu, true),
+            att::READ_REQ => self.read(pdu, false),
+            att::READ_BLOB_REQ => self.read(pdu, true),
+            att::WRITE_REQ | att::WRITE_CMD => self.write(pdu),
+            att::HANDLE_VALUE_CFM => self.confirm(),
+            opcode if opcode & att::COMMAND_FLAG != 0 => (),
+            // Responses never arrive at a server
+            opcode if opcode & 1 == 1 => (),
+            opcode => self.respond_error(opcode, 0, att::REQUEST_NOT_SUPPORTED),
+        }
+    }
+
+    fn send_done(&self, result: Option<ReturnCode>) {
+        if let Some(result) = result {
+            if let Some(kind) = self.sending.take() {
+                self.report(kind, result);
+            }
+        }
+        if let Some(kind) = self.pending.get() {
+            let len = self.pending_len.get();
+            let result = self.att_buf.map_or(ReturnCode::FAIL, |buf| {
+                self.l2cap.send(CID_ATT, &buf[..len])
+            });
+            match result {
+                ReturnCode::EBUSY => (),
+                ReturnCode::SUCCESS => {
+                    self.pending.set(None);
+                    self.sending.set(Some(kind));
+                }
+                result => {
+                    self.pending.set(None);
+                    self.report(kind, result);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! L2CAP for Bluetooth Low Energy connections.
+//!
+//! Reassembles the L2CAP PDUs received over a link layer connection from
+//! their fragments and passes those on the attribute protocol channel to its
+//! client, the ATT server. Only the fixed channels of LE are supported and
+//! no channels can be opened: requests on the LE signaling channel are
+//! rejected, and pairing requests on the security manager channel fail with
+//! "pairing not supported" (BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part
+//! A and Part H]).
+//!
+//! PDUs are sent one at a time from a single transmit buffer. A PDU that is
+//! larger than the receive buffer is dropped.
+
+use crate::ble::link_layer::{LinkLayer, LinkLayerClient};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_connection::BleConnectionDriver;
+use kernel::hil::time::Alarm;
+use kernel::ReturnCode;
+
+/// Length of the basic L2CAP header: a payload length and a channel ID.
+pub const L2CAP_HEADER_LEN: usize = 4;
+
+/// Channel ID of the attribute protocol.
+pub const CID_ATT: u16 = 0x0004;
+const CID_LE_SIGNALING: u16 = 0x0005;
+const CID_SMP: u16 = 0x0006;
+
+// LE signaling commands, [Vol 3, Part A], section 4
+const SIGNALING_COMMAND_REJECT: u8 = 0x01;
+const SIGNALING_CONNECTION_PARAMETER_UPDATE_RSP: u8 = 0x13;
+const REJECT_COMMAND_NOT_UNDERSTOOD: u16 = 0x0000;
+
+// Security manager commands, [Vol 3, Part H], section 3.5
+const SMP_PAIRING_REQUEST: u8 = 0x01;
+const SMP_PAIRING_FAILED: u8 = 0x05;
+const SMP_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+/// Sends PDUs on a fixed channel.
+pub trait L2capSender {
+    /// Sends `payload` on channel `cid`. Returns EBUSY if a PDU is being
+    /// sent, ESIZE if `payload` is too large and EOFF if there is no
+    /// connection.
+    fn send(&self, cid: u16, payload: &[u8]) -> ReturnCode;
+
+    /// The largest payload `send` takes.
+    fn max_payload_len(&self) -> usize;
+}
+
+/// The user of a fixed channel.
+pub trait L2capClient {
+    fn connected(&self);
+
+    /// The connection ended for `reason`, one of the link layer error codes.
+    fn disconnected(&self, reason: u8);
+
+    fn receive(&self, payload: &[u8]);
+
+    /// A PDU was sent, or the connection ended before it was, and `send` can
+    /// be called again. `result` is set for PDUs sent on this channel and
+    /// unset for responses L2CAP sent on the other channels.
+    fn send_done(&self, result: Option<ReturnCode>);
+}
+
+pub struct L2cap<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> {
+    link_layer: &'a LinkLayer<'a, R, A>,
+    att_client: OptionalCell<&'a dyn L2capClient>,
+    rx_buf: TakeCell<'static, [u8]>,
+    /// Bytes of the PDU being reassembled received so far
+    rx_len: Cell<usize>,
+    /// Length of the PDU being reassembled, header included, or 0
+    rx_expected: Cell<usize>,
+    /// The PDU being reassembled does not fit in `rx_buf`
+    rx_dropping: Cell<bool>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_buf_len: usize,
+    /// Channel of the PDU being sent
+    tx_cid: OptionalCell<u16>,
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> L2cap<'a, R, A> {
+    pub fn new(
+        link_layer: &'a LinkLayer<'a, R, A>,
+        rx_buf: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+    ) -> L2cap<'a, R, A> {
+        L2cap {
+            link_layer: link_layer,
+            att_client: OptionalCell::empty(),
+            rx_buf: TakeCell::new(rx_buf),
+            rx_len: Cell::new(0),
+            rx_expected: Cell::new(0),
+            rx_dropping: Cell::new(false),
+            tx_buf_len: tx_buf.len(),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_cid: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_att_client(&self, client: &'a dyn L2capClient) {
+        self.att_client.set(client);
+    }
+
+    fn receive_pdu(&self, cid: u16, payload: &[u8]) {
+        match cid {
+            CID_ATT => {
+                self.att_client.map(|client| client.receive(payload));
+            }
+            CID_LE_SIGNALING if payload.len() >= 4 => {
+                let code = payload[0];
+                let identifier = payload[1];
+                if code != SIGNALING_COMMAND_REJECT
+                    && code != SIGNALING_CONNECTION_PARAMETER_UPDATE_RSP
+                {
+                    let reason = REJECT_COMMAND_NOT_UNDERSTOOD.to_le_bytes();
+                    let reject = [
+                        SIGNALING_COMMAND_REJECT,
+                        identifier,
+                        2,
+                        0,
+                        reason[0],
+                        reason[1],
+                    ];
+                    self.send(CID_LE_SIGNALING, &reject);
+                }
+            }
+            CID_SMP if !payload.is_empty() => {
+                if payload[0] == SMP_PAIRING_REQUEST {
+                    self.send(CID_SMP, &[SMP_PAIRING_FAILED, SMP_PAIRING_NOT_SUPPORTED]);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> L2capSender for L2cap<'a, R, A> {
+    fn send(&self, cid: u16, payload: &[u8]) -> ReturnCode {
+        if payload.len() > self.max_payload_len() {
+            return ReturnCode::ESIZE;
+        }
+        self.tx_buf.take().map_or(ReturnCode::EBUSY, |buf| {
+            let len = L2CAP_HEADER_LEN + payload.len();
+            buf[0..2].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+            buf[2..4].copy_from_slice(&cid.to_le_bytes());
+            buf[L2CAP_HEADER_LEN..len].copy_from_slice(payload);
+            match self.link_layer.send(buf, len) {
+                (ReturnCode::SUCCESS, _) => {
+                    self.tx_cid.set(cid);
+                    ReturnCode::SUCCESS
+                }
+                (result, buf) => {
+                    self.tx_buf.put(buf);
+                    result
+                }
+            }
+        })
+    }
+
+    fn max_payload_len(&self) -> usize {
+        self.tx_buf_len - L2CAP_HEADER_LEN
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> LinkLayerClient for L2cap<'a, R, A> {
+    fn connected(&self) {
+        self.rx_expected.set(0);
+        self.att_client.map(|client| client.connected());
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.rx_expected.set(0);
+        self.att_client.map(|client| client.disconnected(reason));
+    }
+
+    fn receive(&self, start: bool, payload: &[u8]) {
+        if start {
+            if payload.len() < 2 {
+                self.rx_expected.set(0);
+                return;
+            }
+            let len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+            self.rx_expected.set(L2CAP_HEADER_LEN + len);
+            self.rx_len.set(0);
+            self.rx_dropping.set(
+                self.rx_buf
+                    .map_or(true, |buf| buf.len() < L2CAP_HEADER_LEN + len),
+            );
+        } else if self.rx_expected.get() == 0 {
+            // A continuation without a start
+            return;
+        }
+
+        let offset = self.rx_len.get();
+        let expected = self.rx_expected.get();
+        let len = core::cmp::min(payload.len(), expected.saturating_sub(offset));
+        if !self.rx_dropping.get() {
+            self.rx_buf.map(|buf| {
+                buf[offset..offset + len].copy_from_slice(&payload[..len]);
+            });
+        }
+        self.rx_len.set(offset + len);
+
+        if offset + len >= expected && expected >= L2CAP_HEADER_LEN {
+            self.rx_expected.set(0);
+            if !self.rx_dropping.get() {
+                self.rx_buf.take().map(|buf| {
+                    let cid = u16::from_le_bytes([buf[2], buf[3]]);
+                    self.receive_pdu(cid, &buf[L2CAP_HEADER_LEN..expected]);
+                    self.rx_buf.replace(buf);
+                });
+            }
+        }
+    }
+
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buf);
+        let result = if self.tx_cid.take() == Some(CID_ATT) {
+            Some(result)
+        } else {
+            None
+        };
+        self.att_client.map(|client| client.send_done(result));
+    }
+}
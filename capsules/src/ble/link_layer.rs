@@ -0,0 +1,1051 @@
+//! Bluetooth Low Energy link layer in the slave role.
+//!
+//! The link layer advertises as a connectable undirected advertiser
+//! (`ADV_IND`) with a static random address, accepts a connection request
+//! (`CONNECT_IND`) addressed to it and then runs the connection as the
+//! slave:
+//!
+//! - Connection events follow the anchor points of the master. The anchor is
+//!   resynchronized from every PDU received, and the receive window is widened
+//!   by the sleep clock accuracy of both sides since the last resynchronization
+//!   (BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.7).
+//! - Data channels are selected with channel selection algorithm #1 (section
+//!   4.5.8.2), and channel map and connection parameter updates take effect at
+//!   their instant.
+//! - PDUs are acknowledged and retransmitted with the SN and NESN bits, so at
+//!   most one PDU is in flight (section 4.5.9). L2CAP PDUs passed to `send()`
+//!   are fragmented into data PDUs of up to 27 bytes.
+//! - The control procedures a slave has to answer are handled: termination,
+//!   feature and version exchange, ping and data length exchange. Encryption
+//!   requests are rejected.
+//! - The connection is lost when no valid PDU arrives for the supervision
+//!   timeout.
+//!
+//! The kernel cannot restart the radio within T_IFS, so a connection event
+//! consists of a single exchange: the master's PDU and the answer to it. A
+//! PDU that was acknowledged is therefore followed by its successor one
+//! connection event later, which limits the throughput to one data PDU every
+//! other connection interval. Slave latency is not used and the data length
+//! extension is not supported.
+//!
+//! The link layer needs the radio to itself, so it cannot be used alongside
+//! `ble_advertising_driver` on the same radio.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ble_link_layer = static_init!(
+//!     capsules::ble::link_layer::LinkLayer<
+//!         'static,
+//!         nrf52::ble_radio::Radio,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!     >,
+//!     capsules::ble::link_layer::LinkLayer::new(
+//!         &nrf52::ble_radio::RADIO,
+//!         ble_alarm,
+//!         BLE_ADDRESS,
+//!         &mut PDU_TX_BUF,
+//!         &mut PDU_RX_BUF,
+//!     )
+//! );
+//! ble_alarm.set_alarm_client(ble_link_layer);
+//! nrf52::ble_radio::RADIO.set_connection_client(ble_link_layer);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection::{self, BleConnectionDriver, ConnectionClient};
+use kernel::hil::time::{Alarm, AlarmClient, Frequency, Ticks};
+use kernel::ReturnCode;
+
+/// Length of the PDU buffers of the link layer, which fit the largest
+/// advertising channel PDU.
+pub const PDU_BUF_LEN: usize = 39;
+/// The largest payload of a data channel PDU.
+pub const MAX_DATA_PAYLOAD_LEN: usize = 27;
+/// The largest advertising data.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+pub const ADDRESS_LEN: usize = 6;
+
+// Error codes, BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D]
+pub const ERROR_CONNECTION_TIMEOUT: u8 = 0x08;
+pub const ERROR_REMOTE_USER_TERMINATED: u8 = 0x13;
+pub const ERROR_LOCAL_HOST_TERMINATED: u8 = 0x16;
+pub const ERROR_UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1a;
+pub const ERROR_INSTANT_PASSED: u8 = 0x28;
+
+// Advertising channel PDUs, section 2.3
+const ADV_IND: u8 = 0x0;
+const CONNECT_IND: u8 = 0x5;
+const ADV_HEADER_TYPE_MASK: u8 = 0x0f;
+const ADV_HEADER_TXADD: u8 = 1 << 6;
+const ADV_HEADER_RXADD: u8 = 1 << 7;
+const CONNECT_IND_LEN: usize = 34;
+
+// Data channel PDU header, section 2.4
+const LLID_MASK: u8 = 0x03;
+const LLID_CONTINUATION: u8 = 0x01;
+const LLID_START: u8 = 0x02;
+const LLID_CONTROL: u8 = 0x03;
+const HEADER_NESN: u8 = 1 << ble_connection::DATA_HEADER_NESN_OFFSET;
+const HEADER_SN: u8 = 1 << ble_connection::DATA_HEADER_SN_OFFSET;
+
+// Control PDU opcodes, section 2.4.2
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0c;
+const LL_REJECT_IND: u8 = 0x0d;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+const LL_LENGTH_REQ: u8 = 0x14;
+const LL_LENGTH_RSP: u8 = 0x15;
+
+const BLUETOOTH_VERSION_4_2: u8 = 0x08;
+const COMPANY_ID_UNASSIGNED: u16 = 0xffff;
+/// Transmission time of the longest data PDU without the data length
+/// extension, in µs.
+const MAX_DATA_PDU_TIME_US: u16 = 328;
+
+const NUM_DATA_CHANNELS: u8 = 37;
+/// Unit of connection intervals, transmit windows and their offsets, in µs.
+const UNIT_US: u32 = 1250;
+/// How long to listen for a connection request after an advertisement, in
+/// µs.
+const ADV_LISTEN_US: u32 = 1500;
+/// Added to every receive window to cover the latency of alarms and radio
+/// interrupts, in µs.
+const RX_MARGIN_US: u32 = 500;
+/// Accuracy of the sleep clock of the slave, in ppm.
+const SLEEP_CLOCK_ACCURACY_PPM: u32 = 50;
+/// The sleep clock accuracy field of a connection request, in ppm (section
+/// 2.3.3.1).
+const MASTER_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+/// A connection that never received a PDU is lost after this many intervals.
+const ESTABLISHMENT_INTERVALS: u32 = 6;
+
+pub trait LinkLayerClient {
+    fn connected(&self);
+
+    /// The connection ended for `reason`, one of the error codes.
+    fn disconnected(&self, reason: u8);
+
+    /// A data PDU was received. `start` tells whether its payload starts an
+    /// L2CAP PDU or continues the previous one.
+    fn receive(&self, start: bool, payload: &[u8]);
+
+    /// The L2CAP PDU passed to `send()` was acknowledged, or the connection
+    /// ended before it was (`ReturnCode::FAIL`).
+    fn send_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Standby,
+    /// Advertising on a channel, or waiting for the next advertising event
+    Advertising(Option<RadioChannel>),
+    /// Waiting for the next connection event, or in one
+    Connected {
+        in_event: bool,
+    },
+}
+
+#[derive(Copy, Clone)]
+struct ConnectionUpdate {
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    timeout: u16,
+    instant: u16,
+}
+
+struct Connection<T: Ticks> {
+    access_address: u32,
+    crc_init: u32,
+    /// Connection interval, in units of 1.25 ms
+    interval: u16,
+    /// Supervision timeout, in units of 10 ms
+    timeout: u16,
+    channel_map: [u8; 5],
+    hop: u8,
+    master_sca_ppm: u32,
+    last_unmapped_channel: u8,
+    event_counter: u16,
+    /// Anchor point of the next connection event
+    anchor: T,
+    /// Extra width of the receive window after the anchor point of the next
+    /// connection event, for transmit windows, in µs
+    window_us: u32,
+    /// The last anchor point at which a PDU was received
+    last_sync: T,
+    /// When the last PDU with a valid CRC was received
+    last_valid_rx: T,
+    established: bool,
+    sn: bool,
+    nesn: bool,
+    update: Option<ConnectionUpdate>,
+    channel_map_update: Option<([u8; 5], u16)>,
+    /// Set once the connection ends after the current connection event
+    end_reason: Option<u8>,
+}
+
+/// A control PDU waiting to be sent.
+#[derive(Copy, Clone)]
+struct ControlPdu {
+    data: [u8; 9],
+    len: usize,
+}
+
+impl ControlPdu {
+    fn new(data: &[u8]) -> ControlPdu {
+        let mut pdu = ControlPdu {
+            data: [0; 9],
+            len: data.len(),
+        };
+        pdu.data[..data.len()].copy_from_slice(data);
+        pdu
+    }
+}
+
+/// The PDU that was sent and is waiting to be acknowledged.
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Empty,
+    Control {
+        terminate: bool,
+    },
+    /// A fragment of the L2CAP PDU being sent, of the given length
+    Data(usize),
+}
+
+/// Returns the channel of data channel index `index`.
+fn data_channel(index: u8) -> RadioChannel {
+    match index {
+        0 => RadioChannel::DataChannel0,
+        1 => RadioChannel::DataChannel1,
+        2 => RadioChannel::DataChannel2,
+        3 => RadioChannel::DataChannel3,
+        4 => RadioChannel::DataChannel4,
+        5 => RadioChannel::DataChannel5,
+        6 => RadioChannel::DataChannel6,
+        7 => RadioChannel::DataChannel7,
+        8 => RadioChannel::DataChannel8,
+        9 => RadioChannel::DataChannel9,
+        10 => RadioChannel::DataChannel10,
+        11 => RadioChannel::DataChannel11,
+        12 => RadioChannel::DataChannel12,
+        13 => RadioChannel::DataChannel13,
+        14 => RadioChannel::DataChannel14,
+        15 => RadioChannel::DataChannel15,
+        16 => RadioChannel::DataChannel16,
+        17 => RadioChannel::DataChannel17,
+        18 => RadioChannel::DataChannel18,
+        19 => RadioChannel::DataChannel19,
+        20 => RadioChannel::DataChannel20,
+        21 => RadioChannel::DataChannel21,
+        22 => RadioChannel::DataChannel22,
+        23 => RadioChannel::DataChannel23,
+        24 => RadioChannel::DataChannel24,
+        25 => RadioChannel::DataChannel25,
+        26 => RadioChannel::DataChannel26,
+        27 => RadioChannel::DataChannel27,
+        28 => RadioChannel::DataChannel28,
+        29 => RadioChannel::DataChannel29,
+        30 => RadioChannel::DataChannel30,
+        31 => RadioChannel::DataChannel31,
+        32 => RadioChannel::DataChannel32,
+        33 => RadioChannel::DataChannel33,
+        34 => RadioChannel::DataChannel34,
+        35 => RadioChannel::DataChannel35,
+        _ => RadioChannel::DataChannel36,
+    }
+}
+
+fn channel_used(channel_map: &[u8; 5], index: u8) -> bool {
+    channel_map[index as usize / 8] & (1 << (index % 8)) != 0
+}
+
+fn num_used_channels(channel_map: &[u8; 5]) -> u8 {
+    (0..NUM_DATA_CHANNELS)
+        .filter(|&index| channel_used(channel_map, index))
+        .count() as u8
+}
+
+/// Air time of a PDU of `len` bytes at 1 Mbit/s, including the preamble,
+/// access address and CRC, in µs.
+fn air_time_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+/// Whether `instant` lies in the past of connection event `event_counter`
+/// (section 5.1.1).
+fn instant_passed(instant: u16, event_counter: u16) -> bool {
+    instant.wrapping_sub(event_counter) >= 32767
+}
+
+pub struct LinkLayer<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn LinkLayerClient>,
+    address: [u8; ADDRESS_LEN],
+    state: Cell<State>,
+    advertising: Cell<bool>,
+    adv_interval_ms: Cell<u32>,
+    adv_data: Cell<[u8; MAX_ADV_DATA_LEN]>,
+    adv_data_len: Cell<usize>,
+    random: Cell<u32>,
+    connection: MapCell<Connection<A::Ticks>>,
+    pdu_tx: TakeCell<'static, [u8]>,
+    pdu_rx: TakeCell<'static, [u8]>,
+    /// Length of the PDU in `pdu_tx`
+    pdu_tx_len: Cell<usize>,
+    control_tx: Cell<Option<ControlPdu>>,
+    in_flight: Cell<Option<InFlight>>,
+    tx_data: TakeCell<'static, [u8]>,
+    tx_data_len: Cell<usize>,
+    tx_data_offset: Cell<usize>,
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> LinkLayer<'a, R, A> {
+    /// `address` is the static random device address, with the least
+    /// significant byte first. Its two most significant bits must be set.
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        address: [u8; ADDRESS_LEN],
+        pdu_tx: &'static mut [u8],
+        pdu_rx: &'static mut [u8],
+    ) -> LinkLayer<'a, R, A> {
+        LinkLayer {
+            radio: radio,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            address: address,
+            state: Cell::new(State::Standby),
+            advertising: Cell::new(false),
+            adv_interval_ms: Cell::new(100),
+            adv_data: Cell::new([0; MAX_ADV_DATA_LEN]),
+            adv_data_len: Cell::new(0),
+            random: Cell::new(0),
+            connection: MapCell::empty(),
+            pdu_tx: TakeCell::new(pdu_tx),
+            pdu_rx: TakeCell::new(pdu_rx),
+            pdu_tx_len: Cell::new(0),
+            control_tx: Cell::new(None),
+            in_flight: Cell::new(None),
+            tx_data: TakeCell::empty(),
+            tx_data_len: Cell::new(0),
+            tx_data_offset: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn LinkLayerClient) {
+        self.client.set(client);
+    }
+
+    pub fn address(&self) -> [u8; ADDRESS_LEN] {
+        self.address
+    }
+
+    /// Sets the data of the advertisements, which is made of AD structures.
+    pub fn set_advertising_data(&self, data: &[u8]) -> ReturnCode {
+        if data.len() > MAX_ADV_DATA_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut adv_data = [0; MAX_ADV_DATA_LEN];
+        adv_data[..data.len()].copy_from_slice(data);
+        self.adv_data.set(adv_data);
+        self.adv_data_len.set(data.len());
+        ReturnCode::SUCCESS
+    }
+
+    /// Starts advertising every `interval_ms` milliseconds (at least 20)
+    /// while not connected.
+    pub fn start_advertising(&self, interval_ms: u32) -> ReturnCode {
+        if interval_ms < 20 || interval_ms > 10240 {
+            return ReturnCode::EINVAL;
+        }
+        self.adv_interval_ms.set(interval_ms);
+        self.advertising.set(true);
+        if self.random.get() == 0 {
+            self.random.set(self.alarm.now().into_u32() | 1);
+        }
+        if self.state.get() == State::Standby {
+            self.state.set(State::Advertising(None));
+            self.schedule_at(self.alarm.now());
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Stops advertising. An advertisement that is being answered with a
+    /// connection request still leads to a connection.
+    pub fn stop_advertising(&self) -> ReturnCode {
+        self.advertising.set(false);
+        match self.state.get() {
+            State::Advertising(None) => {
+                self.alarm.disarm();
+                self.state.set(State::Standby);
+            }
+            State::Advertising(Some(_)) => {
+                if let Some((tx_buf, rx_buf)) = self.radio.abort() {
+                    self.alarm.disarm();
+                    self.pdu_tx.replace(tx_buf);
+                    self.pdu_rx.replace(rx_buf);
+                    self.state.set(State::Standby);
+                }
+            }
+            _ => (),
+        }
+        ReturnCode::SUCCESS
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.state.get() {
+            State::Connected { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Sends the `len` byte L2CAP PDU in `buf`. The client is called back
+    /// once all of it has been acknowledged.
+    pub fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.is_connected() {
+            (ReturnCode::EOFF, Some(buf))
+        } else if self.tx_data.is_some() {
+            (ReturnCode::EBUSY, Some(buf))
+        } else if len > buf.len() || len == 0 {
+            (ReturnCode::ESIZE, Some(buf))
+        } else {
+            self.tx_data.replace(buf);
+            self.tx_data_len.set(len);
+            self.tx_data_offset.set(0);
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    /// Terminates the connection. The client is called back with
+    /// `disconnected` once the master has acknowledged the termination or
+    /// the supervision timeout expires.
+    pub fn disconnect(&self) -> ReturnCode {
+        if !self.is_connected() {
+            return ReturnCode::EOFF;
+        }
+        self.control_tx.set(Some(ControlPdu::new(&[
+            LL_TERMINATE_IND,
+            ERROR_REMOTE_USER_TERMINATED,
+        ])));
+        ReturnCode::SUCCESS
+    }
+
+    // Xorshift, for the pseudo-random advertising delay
+    fn random(&self) -> u32 {
+        let mut x = self.random.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random.set(x);
+        x
+    }
+
+    // Sets the alarm to `time`, or as soon as possible if it has passed
+    fn schedule_at(&self, time: A::Ticks) {
+        let now = self.alarm.now();
+        let dt = time.wrapping_sub(now);
+        let dt = if dt.into_u32() > A::Ticks::max_value().into_u32() / 2 {
+            A::Ticks::from(0)
+        } else {
+            dt
+        };
+        self.alarm.set_alarm(now, dt);
+    }
+
+    fn ticks_to_us(ticks: A::Ticks) -> u32 {
+        (ticks.into_u32() as u64 * 1_000_000 / A::Frequency::frequency() as u64) as u32
+    }
+
+    //
+    // Advertising
+    //
+
+    fn advertise(&self, channel: RadioChannel) {
+        let result = self.pdu_tx.take().map_or(ReturnCode::ENOMEM, |tx_buf| {
+            let adv_data_len = self.adv_data_len.get();
+            tx_buf[0] = ADV_IND | ADV_HEADER_TXADD;
+            tx_buf[1] = (ADDRESS_LEN + adv_data_len) as u8;
+            tx_buf[2..2 + ADDRESS_LEN].copy_from_slice(&self.address);
+            tx_buf[2 + ADDRESS_LEN..2 + ADDRESS_LEN + adv_data_len]
+                .copy_from_slice(&self.adv_data.get()[..adv_data_len]);
+            let tx_len = 2 + ADDRESS_LEN + adv_data_len;
+
+            match self.pdu_rx.take() {
+                None => {
+                    self.pdu_tx.replace(tx_buf);
+                    ReturnCode::ENOMEM
+                }
+                Some(rx_buf) => {
+                    self.radio.set_access_address(
+                        ble_connection::ADVERTISING_ACCESS_ADDRESS,
+                        ble_connection::ADVERTISING_CRC_INIT,
+                    );
+                    let (result, bufs) =
+                        self.radio.transmit_receive(channel, tx_buf, tx_len, rx_buf);
+                    if let Some((tx_buf, rx_buf)) = bufs {
+                        self.pdu_tx.replace(tx_buf);
+                        self.pdu_rx.replace(rx_buf);
+                    }
+                    result
+                }
+            }
+        });
+        if result == ReturnCode::SUCCESS {
+            self.state.set(State::Advertising(Some(channel)));
+            self.schedule_at(
+                self.alarm
+                    .now()
+                    .wrapping_add(A::ticks_from_us(ADV_LISTEN_US)),
+            );
+        } else {
+            // Try again in the next advertising event
+            self.advertising_event_done();
+        }
+    }
+
+    // Moves on to the channel after `channel`, or ends the advertising event
+    fn next_advertising_channel(&self, channel: RadioChannel) {
+        if !self.advertising.get() {
+            self.state.set(State::Standby);
+            return;
+        }
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                self.advertise(RadioChannel::AdvertisingChannel38)
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                self.advertise(RadioChannel::AdvertisingChannel39)
+            }
+            _ => self.advertising_event_done(),
+        }
+    }
+
+    fn advertising_event_done(&self) {
+        if !self.advertising.get() {
+            self.state.set(State::Standby);
+            return;
+        }
+        // advDelay is a pseudo-random delay of 0 to 10 ms (section 4.4.2.2)
+        let delay_us = self.adv_interval_ms.get() * 1000 + self.random() % 10_000;
+        self.state.set(State::Advertising(None));
+        self.schedule_at(self.alarm.now().wrapping_add(A::ticks_from_us(delay_us)));
+    }
+
+    // Returns whether `pdu` is a valid connection request for this device
+    fn is_connect_request(&self, pdu: &[u8]) -> bool {
+        pdu.len() >= 2 + CONNECT_IND_LEN
+            && pdu[0] & ADV_HEADER_TYPE_MASK == CONNECT_IND
+            && pdu[0] & ADV_HEADER_RXADD != 0
+            && pdu[1] as usize == CONNECT_IND_LEN
+            && pdu[8..14] == self.address
+    }
+
+    //
+    // Connections
+    //
+
+    // Sets up the connection requested by `pdu`, which ended just now
+    fn connect(&self, pdu: &[u8]) {
+        let ll_data = &pdu[14..2 + CONNECT_IND_LEN];
+        let access_address = u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]);
+        let crc_init = u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]);
+        let win_size = ll_data[7];
+        let win_offset = u16::from_le_bytes([ll_data[8], ll_data[9]]);
+        let interval = u16::from_le_bytes([ll_data[10], ll_data[11]]);
+        let timeout = u16::from_le_bytes([ll_data[14], ll_data[15]]);
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&ll_data[16..21]);
+        // Channels 37 to 39 are not data channels
+        channel_map[4] &= 0x1f;
+        let hop = ll_data[21] & 0x1f;
+        let sca = ll_data[21] >> 5;
+
+        // Ignore requests with parameters outside the allowed ranges
+        if interval < 6
+            || interval > 3200
+            || timeout < 10
+            || timeout > 3200
+            || win_size < 1
+            || hop < 5
+            || hop > 16
+            || num_used_channels(&channel_map) < 2
+        {
+            self.advertising_event_done();
+            return;
+        }
+
+        // The transmit window starts 1.25 ms plus the window offset after
+        // the end of the connection request (section 4.5.3)
+        let now = self.alarm.now();
+        let anchor = now.wrapping_add(A::ticks_from_us(UNIT_US * (1 + win_offset as u32)));
+        self.connection.put(Connection {
+            access_address: access_address,
+            crc_init: crc_init,
+            interval: interval,
+            timeout: timeout,
+            channel_map: channel_map,
+            hop: hop,
+            master_sca_ppm: MASTER_SCA_PPM[sca as usize],
+            last_unmapped_channel: 0,
+            event_counter: 0,
+            anchor: anchor,
+            window_us: UNIT_US * win_size as u32,
+            last_sync: now,
+            last_valid_rx: now,
+            established: false,
+            sn: false,
+            nesn: false,
+            update: None,
+            channel_map_update: None,
+            end_reason: None,
+        });
+        self.control_tx.set(None);
+        self.in_flight.set(None);
+        self.state.set(State::Connected { in_event: false });
+        self.client.map(|client| client.connected());
+        self.schedule_connection_event();
+    }
+
+    // Sets the alarm to the start of the receive window of the next
+    // connection event
+    fn schedule_connection_event(&self) {
+        let start = self.connection.map(|conn| {
+            let since_sync = conn.anchor.wrapping_sub(conn.last_sync);
+            let widening = self.window_widening_us(conn, since_sync);
+            conn.anchor.wrapping_sub(A::ticks_from_us(widening))
+        });
+        start.map(|start| self.schedule_at(start));
+    }
+
+    // Window widening for an anchor point `since_sync` after the last
+    // resynchronization, in µs (section 4.5.7)
+    fn window_widening_us(&self, conn: &Connection<A::Ticks>, since_sync: A::Ticks) -> u32 {
+        let ppm = conn.master_sca_ppm + SLEEP_CLOCK_ACCURACY_PPM;
+        let drift = (Self::ticks_to_us(since_sync) as u64 * ppm as u64 / 1_000_000) as u32;
+        drift + 16 + RX_MARGIN_US
+    }
+
+    // Selects the data channel of the next connection event with channel
+    // selection algorithm #1 (section 4.5.8.2)
+    fn select_channel(conn: &mut Connection<A::Ticks>) -> RadioChannel {
+        let unmapped = (conn.last_unmapped_channel + conn.hop) % NUM_DATA_CHANNELS;
+        conn.last_unmapped_channel = unmapped;
+        if channel_used(&conn.channel_map, unmapped) {
+            return data_channel(unmapped);
+        }
+        let remapping_index = unmapped % num_used_channels(&conn.channel_map);
+        let index = (0..NUM_DATA_CHANNELS)
+            .filter(|&index| channel_used(&conn.channel_map, index))
+            .nth(remapping_index as usize)
+            .unwrap_or(0);
+        data_channel(index)
+    }
+
+    // Applies the updates whose instant is the next connection event
+    fn apply_instants(conn: &mut Connection<A::Ticks>) {
+        if let Some((channel_map, instant)) = conn.channel_map_update {
+            if instant == conn.event_counter {
+                conn.channel_map = channel_map;
+                conn.channel_map_update = None;
+            }
+        }
+        if let Some(update) = conn.update {
+            if update.instant == conn.event_counter {
+                // The master may send its first PDU anywhere in a transmit
+                // window that starts the window offset after the old anchor
+                // point (section 5.1.1)
+                conn.anchor = conn
+                    .anchor
+                    .wrapping_add(A::ticks_from_us(UNIT_US * update.win_offset as u32));
+                conn.window_us = UNIT_US * update.win_size as u32;
+                conn.interval = update.interval;
+                conn.timeout = update.timeout;
+                conn.update = None;
+            }
+        }
+    }
+
+    // Builds the PDU to send in the next connection event in `pdu_tx`
+    fn prepare_pdu(&self, buf: &mut [u8], sn: bool, nesn: bool) {
+        if self.in_flight.get().is_none() {
+            let (llid, len, in_flight) = if let Some(control) = self.control_tx.take() {
+                buf[2..2 + control.len].copy_from_slice(&control.data[..control.len]);
+                let terminate = control.data[0] == LL_TERMINATE_IND;
+                (
+                    LLID_CONTROL,
+                    control.len,
+                    InFlight::Control {
+                        terminate: terminate,
+                    },
+                )
+            } else if let Some(data) = self.tx_data.take() {
+                let offset = self.tx_data_offset.get();
+                let len = cmp::min(MAX_DATA_PAYLOAD_LEN, self.tx_data_len.get() - offset);
+                buf[2..2 + len].copy_from_slice(&data[offset..offset + len]);
+                self.tx_data.replace(data);
+                let llid = if offset == 0 {
+                    LLID_START
+                } else {
+                    LLID_CONTINUATION
+                };
+                (llid, len, InFlight::Data(len))
+            } else {
+                (LLID_CONTINUATION, 0, InFlight::Empty)
+            };
+            buf[0] = llid;
+            buf[1] = len as u8;
+            self.pdu_tx_len.set(2 + len);
+            self.in_flight.set(Some(in_flight));
+        }
+        // A PDU that was not acknowledged is sent again with the same SN
+        buf[0] &= LLID_MASK;
+        if sn {
+            buf[0] |= HEADER_SN;
+        }
+        if nesn {
+            buf[0] |= HEADER_NESN;
+        }
+    }
+
+    fn start_connection_event(&self) {
+        let event = self.connection.map(|conn| {
+            Self::apply_instants(conn);
+            let channel = Self::select_channel(conn);
+            let since_sync = conn.anchor.wrapping_sub(conn.last_sync);
+            let widening = self.window_widening_us(conn, since_sync);
+            let window_end = conn
+                .anchor
+                .wrapping_add(A::ticks_from_us(widening + conn.window_us));
+            (
+                channel,
+                window_end,
+                conn.access_address,
+                conn.crc_init,
+                conn.sn,
+                conn.nesn,
+            )
+        });
+        let (channel, window_end, access_address, crc_init, sn, nesn) = match event {
+            Some(event) => event,
+            None => return,
+        };
+
+        let started = self.pdu_tx.take().map_or(false, |tx_buf| {
+            self.prepare_pdu(tx_buf, sn, nesn);
+            match self.pdu_rx.take() {
+                None => {
+                    self.pdu_tx.replace(tx_buf);
+                    false
+                }
+                Some(rx_buf) => {
+                    self.radio.set_access_address(access_address, crc_init);
+                    let (result, bufs) =
+                        self.radio
+                            .receive_transmit(channel, rx_buf, tx_buf, self.pdu_tx_len.get());
+                    if let Some((tx_buf, rx_buf)) = bufs {
+                        self.pdu_tx.replace(tx_buf);
+                        self.pdu_rx.replace(rx_buf);
+                    }
+                    result == ReturnCode::SUCCESS
+                }
+            }
+        });
+        if started {
+            self.state.set(State::Connected { in_event: true });
+            self.schedule_at(window_end);
+        } else {
+            self.connection_event_done(false);
+        }
+    }
+
+    // Handles a data channel PDU received in a connection event
+    fn receive_data_pdu(&self, pdu: &[u8]) {
+        let header = pdu[0];
+        let len = cmp::min(pdu[1] as usize, pdu.len() - 2);
+        let payload = &pdu[2..2 + len];
+
+        let (acked, new) = self
+            .connection
+            .map(|conn| {
+                conn.last_valid_rx = self.alarm.now();
+                conn.established = true;
+                let acked = (header & HEADER_NESN != 0) != conn.sn;
+                if acked {
+                    conn.sn = !conn.sn;
+                }
+                let new = (header & HEADER_SN != 0) == conn.nesn;
+                if new {
+                    conn.nesn = !conn.nesn;
+                }
+                (acked, new)
+            })
+            .unwrap_or((false, false));
+
+        if acked {
+            self.pdu_acknowledged();
+        }
+        if new {
+            match header & LLID_MASK {
+                LLID_CONTROL if len > 0 => self.receive_control_pdu(payload),
+                LLID_START => {
+                    self.client.map(|client| client.receive(true, payload));
+                }
+                // Empty PDUs are continuations without payload
+                LLID_CONTINUATION if len > 0 => {
+                    self.client.map(|client| client.receive(false, payload));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn pdu_acknowledged(&self) {
+        match self.in_flight.take() {
+            Some(InFlight::Control { terminate: true }) => {
+                self.connection.map(|conn| {
+                    conn.end_reason = Some(ERROR_LOCAL_HOST_TERMINATED);
+                });
+            }
+            Some(InFlight::Data(len)) => {
+                let offset = self.tx_data_offset.get() + len;
+                self.tx_data_offset.set(offset);
+                if offset >= self.tx_data_len.get() {
+                    self.tx_data.take().map(|buf| {
+                        self.client
+                            .map(move |client| client.send_done(buf, ReturnCode::SUCCESS));
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn receive_control_pdu(&self, payload: &[u8]) {
+        let opcode = payload[0];
+        let data = &payload[1..];
+        let response = match opcode {
+            LL_CONNECTION_UPDATE_IND if data.len() >= 11 => {
+                let update = ConnectionUpdate {
+                    win_size: data[0],
+                    win_offset: u16::from_le_bytes([data[1], data[2]]),
+                    interval: u16::from_le_bytes([data[3], data[4]]),
+                    timeout: u16::from_le_bytes([data[7], data[8]]),
+                    instant: u16::from_le_bytes([data[9], data[10]]),
+                };
+                self.connection.map(|conn| {
+                    if instant_passed(update.instant, conn.event_counter) {
+                        conn.end_reason = Some(ERROR_INSTANT_PASSED);
+                    } else {
+                        conn.update = Some(update);
+                    }
+                });
+                None
+            }
+            LL_CHANNEL_MAP_IND if data.len() >= 7 => {
+                let mut channel_map = [0; 5];
+                channel_map.copy_from_slice(&data[..5]);
+                channel_map[4] &= 0x1f;
+                let instant = u16::from_le_bytes([data[5], data[6]]);
+                self.connection.map(|conn| {
+                    if instant_passed(instant, conn.event_counter) {
+                        conn.end_reason = Some(ERROR_INSTANT_PASSED);
+                    } else if num_used_channels(&channel_map) >= 2 {
+                        conn.channel_map_update = Some((channel_map, instant));
+                    }
+                });
+                None
+            }
+            LL_TERMINATE_IND if !data.is_empty() => {
+                // The termination was acknowledged in this connection event
+                let reason = data[0];
+                self.connection.map(|conn| {
+                    conn.end_reason = Some(reason);
+                });
+                None
+            }
+            LL_ENC_REQ => Some(ControlPdu::new(&[
+                LL_REJECT_IND,
+                ERROR_UNSUPPORTED_REMOTE_FEATURE,
+            ])),
+            LL_FEATURE_REQ => Some(ControlPdu::new(&[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0])),
+            LL_VERSION_IND => {
+                let company = COMPANY_ID_UNASSIGNED.to_le_bytes();
+                Some(ControlPdu::new(&[
+                    LL_VERSION_IND,
+                    BLUETOOTH_VERSION_4_2,
+                    company[0],
+                    company[1],
+                    0,
+                    0,
+                ]))
+            }
+            LL_PING_REQ => Some(ControlPdu::new(&[LL_PING_RSP])),
+            LL_LENGTH_REQ => {
+                let octets = (MAX_DATA_PAYLOAD_LEN as u16).to_le_bytes();
+                let time = MAX_DATA_PDU_TIME_US.to_le_bytes();
+                Some(ControlPdu::new(&[
+                    LL_LENGTH_RSP,
+                    octets[0],
+                    octets[1],
+                    time[0],
+                    time[1],
+                    octets[0],
+                    octets[1],
+                    time[0],
+                    time[1],
+                ]))
+            }
+            // Responses to procedures the slave does not initiate
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_REJECT_IND | LL_PING_RSP | LL_LENGTH_RSP => None,
+            _ => Some(ControlPdu::new(&[LL_UNKNOWN_RSP, opcode])),
+        };
+        if response.is_some() {
+            self.control_tx.set(response);
+        }
+    }
+
+    // Ends the current connection event and schedules the next one. `synced`
+    // tells whether a PDU was received, in which case the anchor point has
+    // been resynchronized.
+    fn connection_event_done(&self, synced: bool) {
+        let now = self.alarm.now();
+        let end_reason = self.connection.map(|conn| {
+            if let Some(reason) = conn.end_reason {
+                return Some(reason);
+            }
+            conn.event_counter = conn.event_counter.wrapping_add(1);
+            conn.anchor = conn
+                .anchor
+                .wrapping_add(A::ticks_from_us(UNIT_US * conn.interval as u32));
+            conn.window_us = 0;
+            if !synced {
+                let timeout_us = if conn.established {
+                    conn.timeout as u32 * 10_000
+                } else {
+                    ESTABLISHMENT_INTERVALS * UNIT_US * conn.interval as u32
+                };
+                let since_rx = now.wrapping_sub(conn.last_valid_rx);
+                if Self::ticks_to_us(since_rx) >= timeout_us {
+                    return Some(ERROR_CONNECTION_TIMEOUT);
+                }
+            }
+            None
+        });
+        match end_reason {
+            Some(Some(reason)) => self.end_connection(reason),
+            Some(None) => {
+                self.state.set(State::Connected { in_event: false });
+                self.schedule_connection_event();
+            }
+            None => self.end_connection(ERROR_CONNECTION_TIMEOUT),
+        }
+    }
+
+    fn end_connection(&self, reason: u8) {
+        self.alarm.disarm();
+        self.connection.take();
+        self.control_tx.set(None);
+        self.in_flight.set(None);
+        self.state.set(State::Standby);
+        self.tx_data.take().map(|buf| {
+            self.client
+                .map(move |client| client.send_done(buf, ReturnCode::FAIL));
+        });
+        self.client.map(|client| client.disconnected(reason));
+        if self.advertising.get() && self.state.get() == State::Standby {
+            self.state.set(State::Advertising(None));
+            self.schedule_at(self.alarm.now());
+        }
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> AlarmClient for LinkLayer<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Standby => (),
+            State::Advertising(None) => self.advertise(RadioChannel::AdvertisingChannel37),
+            // Nothing answered the advertisement
+            State::Advertising(Some(channel)) => {
+                if let Some((tx_buf, rx_buf)) = self.radio.abort() {
+                    self.pdu_tx.replace(tx_buf);
+                    self.pdu_rx.replace(rx_buf);
+                    self.next_advertising_channel(channel);
+                }
+            }
+            State::Connected { in_event: false } => self.start_connection_event(),
+            // The receive window closed without a PDU from the master
+            State::Connected { in_event: true } => {
+                if let Some((tx_buf, rx_buf)) = self.radio.abort() {
+                    self.pdu_tx.replace(tx_buf);
+                    self.pdu_rx.replace(rx_buf);
+                    self.connection_event_done(false);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, R: BleConnectionDriver<'a>, A: Alarm<'a>> ConnectionClient for LinkLayer<'a, R, A> {
+    fn operation_done(
+        &self,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        rx_len: usize,
+        rx_result: ReturnCode,
+    ) {
+        self.alarm.disarm();
+        match self.state.get() {
+            State::Advertising(Some(channel)) => {
+                if rx_result == ReturnCode::SUCCESS && self.is_connect_request(&rx_buf[..rx_len]) {
+                    self.pdu_tx.replace(tx_buf);
+                    self.connect(rx_buf);
+                    self.pdu_rx.replace(rx_buf);
+                } else {
+                    self.pdu_tx.replace(tx_buf);
+                    self.pdu_rx.replace(rx_buf);
+                    self.next_advertising_channel(channel);
+                }
+            }
+            State::Connected { in_event: true } => {
+                // The master's PDU started at the anchor point, and the
+                // answer followed it after T_IFS
+                let tx_len = self.pdu_tx_len.get();
+                let elapsed_us =
+                    air_time_us(rx_len) + ble_connection::T_IFS_US + air_time_us(tx_len);
+                let anchor = self.alarm.now().wrapping_sub(A::ticks_from_us(elapsed_us));
+                self.connection.map(|conn| {
+                    conn.anchor = anchor;
+                    conn.last_sync = anchor;
+                });
+                if rx_result == ReturnCode::SUCCESS && rx_len >= 2 {
+                    self.receive_data_pdu(&rx_buf[..rx_len]);
+                }
+                self.pdu_tx.replace(tx_buf);
+                self.pdu_rx.replace(rx_buf);
+                self.connection_event_done(true);
+            }
+            _ => {
+                self.pdu_tx.replace(tx_buf);
+                self.pdu_rx.replace(rx_buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod att;
+pub mod driver;
+pub mod gatt;
+pub mod l2cap;
+pub mod link_layer;
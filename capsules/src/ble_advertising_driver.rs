@@ -7,6 +7,18 @@
 //! driver but processes can request an advertising or scanning interval.
 //! Processes can also control the TX power used for their advertisements.
 //!
+//! Scannable advertisements (ADV_IND and ADV_SCAN_IND) are answered with the
+//! scan response data of the process, and scanning can be active, in which
+//! case scannable advertisements are answered with a scan request and the
+//! scan response is passed on along with the advertisement. Both need a
+//! radio that implements `BleScanDriver`; other radios scan passively and do
+//! not send scan responses.
+//!
+//! Scanning is shared: every advertisement received while any process scans
+//! is passed to all scanning processes whose filters it passes. A process
+//! can filter by advertiser address, by AD type and by manufacturer ID, and
+//! only advertisements that pass all of its filters wake it up.
+//!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
@@ -14,9 +26,11 @@
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
+//! There are three different buffers:
 //! * 0: Advertising data
-//! * 1: Passive scanning buffer
+//! * 1: Scanning buffer. Received advertisements are written here, followed
+//!      by their scan response in active scanning.
+//! * 2: Scan response data
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -33,7 +47,10 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. It is called
+//!      with the result, the length of the data in the scanning buffer and
+//!      the length of the scan response at its end, which is 0 if there is
+//!      none.
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -48,7 +65,16 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: configure transmitted power
+//! * 5: start scanning, actively if bit 0 of the first argument is set
+//! * 6: only pass on advertisements from the advertiser address whose first
+//!      four bytes (as sent on the air) are the first argument and last two
+//!      the second
+//! * 7: only pass on advertisements with an AD structure of the AD type in
+//!      the first argument
+//! * 8: only pass on advertisements with manufacturer specific data of the
+//!      company ID in the first argument
+//! * 9: remove all filters
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -99,6 +125,10 @@
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+//
+// Scanning events are scheduled the same way, but what one process's scanning event receives is
+// delivered to every scanning process whose filters match, so processes that scan at the same time
+// share the radio time instead of each missing what the others hear.
 
 use core::cell::Cell;
 use core::cmp;
@@ -119,6 +149,7 @@ pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const AD_TYPE_MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -158,12 +189,60 @@ const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
 #[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
 
+/// Which received advertisements are passed to a process. Unset fields
+/// match every advertisement.
+#[derive(Copy, Clone, Default)]
+struct ScanFilter {
+    address: Option<[u8; PACKET_ADDR_LEN]>,
+    ad_type: Option<u8>,
+    company_id: Option<u16>,
+}
+
+impl ScanFilter {
+    // `adv` is the advertisement and `scan_response` the scan response that
+    // answered it, which may be empty
+    fn matches(&self, adv: &[u8], scan_response: &[u8]) -> bool {
+        if let Some(address) = self.address {
+            if adv.get(2..2 + PACKET_ADDR_LEN) != Some(&address[..]) {
+                return false;
+            }
+        }
+        let ad_type_matches = self.ad_type.map_or(true, |ad_type| {
+            Self::has_ad_structure(adv, scan_response, |t, _| t == ad_type)
+        });
+        let company_matches = self.company_id.map_or(true, |company_id| {
+            Self::has_ad_structure(adv, scan_response, |t, data| {
+                t == AD_TYPE_MANUFACTURER_SPECIFIC_DATA
+                    && data.len() >= 2
+                    && u16::from_le_bytes([data[0], data[1]]) == company_id
+            })
+        });
+        ad_type_matches && company_matches
+    }
+
+    // Whether the advertising data of either PDU has an AD structure for
+    // which `f` of its type and data holds
+    fn has_ad_structure<F: Fn(u8, &[u8]) -> bool>(adv: &[u8], scan_response: &[u8], f: F) -> bool {
+        [adv, scan_response].iter().any(|pdu| {
+            let data = pdu.get(2 + PACKET_ADDR_LEN..).unwrap_or(&[]);
+            let mut i = 0;
+            while i + 1 < data.len() && data[i] != 0 {
+                let end = cmp::min(i + 1 + data[i] as usize, data.len());
+                if f(data[i + 1], &data[i + 2..end]) {
+                    return true;
+                }
+                i = end;
+            }
+            false
+        })
+    }
+}
+
 /// Process specific memory
 pub struct App {
     process_status: Option<BLEState>,
@@ -182,9 +261,13 @@ pub struct App {
     /// well.
     random_nonce: u32,
 
+    scan_response_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
+
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    active_scanning: bool,
+    scan_filter: ScanFilter,
 }
 
 impl Default for App {
@@ -192,10 +275,13 @@ impl Default for App {
         App {
             alarm_data: AlarmData::new(),
             adv_data: None,
+            scan_response_data: None,
             scan_buffer: None,
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: None,
+            active_scanning: false,
+            scan_filter: ScanFilter::default(),
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
@@ -236,7 +322,9 @@ impl App {
 
     fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
     where
-        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        B: ble_advertising::BleAdvertisementDriver<'a>
+            + ble_advertising::BleConfig
+            + ble_advertising::BleScanDriver,
         A: kernel::hil::time::Alarm<'a>,
     {
         self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
@@ -270,6 +358,25 @@ impl App {
         })
     }
 
+    // Writes the SCAN_RSP PDU that answers scan requests for the advertisements of the app to `pdu`
+    // and returns its length, or 0 if the advertisements are not scannable or there is no scan
+    // response data.
+    fn scan_response(&self, pdu: &mut [u8; PACKET_LENGTH]) -> usize {
+        match self.pdu_type {
+            ADV_IND | ADV_SCAN_IND => (),
+            _ => return 0,
+        }
+        self.scan_response_data.as_ref().map_or(0, |data| {
+            let data_len = cmp::min(data.len(), PACKET_LENGTH - PACKET_ADDR_LEN - 2);
+            pdu[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+            pdu[1] = (PACKET_ADDR_LEN + data_len) as u8;
+            pdu[2..2 + PACKET_ADDR_LEN].copy_from_slice(&self.address);
+            pdu[2 + PACKET_ADDR_LEN..2 + PACKET_ADDR_LEN + data_len]
+                .copy_from_slice(&data.as_ref()[..data_len]);
+            2 + PACKET_ADDR_LEN + data_len
+        })
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -295,7 +402,9 @@ impl App {
 
 pub struct BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     radio: &'a B,
@@ -309,7 +418,9 @@ where
 
 impl<'a, B, A> BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     pub fn new(
@@ -365,7 +476,9 @@ where
 // Timer alarm
 impl<'a, B, A> kernel::hil::time::AlarmClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     // When an alarm is fired, we find which apps have expired timers. Expired
@@ -408,6 +521,9 @@ where
                                 Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
                             self.sending_app.set(app.appid());
                             self.radio.set_tx_power(app.tx_power);
+                            let mut scan_response = [0; PACKET_LENGTH];
+                            let len = app.scan_response(&mut scan_response);
+                            self.radio.set_scan_response(&scan_response[..len]);
                             app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
                         }
                         Some(BLEState::ScanningIdle) => {
@@ -416,6 +532,12 @@ where
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(app.appid());
                             self.radio.set_tx_power(app.tx_power);
+                            if !app.active_scanning
+                                || self.radio.set_scan_request_address(Some(app.address))
+                                    != ReturnCode::SUCCESS
+                            {
+                                self.radio.set_scan_request_address(None);
+                            }
                             self.radio
                                 .receive_advertisement(RadioChannel::AdvertisingChannel37);
                         }
@@ -435,40 +557,73 @@ where
 // Callback from the radio once a RX event occur
 impl<'a, B, A> ble_advertising::RxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode) {
-        self.receiving_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app, _| {
-                // Validate the received data, because ordinary BLE packets can be bigger than 39
-                // bytes. Thus, we need to check for that!
-                // Moreover, we use the packet header to find size but the radio reads maximum
-                // 39 bytes.
-                // Therefore, we ignore payloads with a header size bigger than 39 because the
-                // channels 37, 38 and 39 should only be used for advertisements!
-                // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
-                // only be sent on the other 37 RadioChannel channels.
-
-                if len <= PACKET_LENGTH as u8 && result == ReturnCode::SUCCESS {
-                    // write to buffer in userland
-                    let success = app
-                        .scan_buffer
-                        .as_mut()
-                        .map(|userland| {
-                            for (dst, src) in userland.iter_mut().zip(buf[0..len as usize].iter()) {
-                                *dst = *src;
-                            }
-                        })
-                        .is_some();
-
-                    if success {
-                        app.scan_callback.map(|mut cb| {
-                            cb.schedule(usize::from(result), len as usize, 0);
-                        });
-                    }
+        // Validate the received data, because ordinary BLE packets can be bigger than 39 bytes.
+        // Thus, we need to check for that! Moreover, we use the packet header to find size but
+        // the radio reads maximum 39 bytes. Therefore, we ignore advertisements with a header size
+        // bigger than 39 because the channels 37, 38 and 39 should only be used for
+        // advertisements! Packets that are bigger than 39 bytes are likely `Channel PDUs` which
+        // should only be sent on the other 37 RadioChannel channels.
+        //
+        // In active scanning, the scan response follows the advertisement.
+        let len = cmp::min(len as usize, buf.len());
+        let adv_len = buf[1] as usize + 2;
+        if adv_len <= PACKET_LENGTH
+            && adv_len <= len
+            && len <= 2 * PACKET_LENGTH
+            && result == ReturnCode::SUCCESS
+        {
+            let adv = &buf[..adv_len];
+            let scan_response = &buf[adv_len..len];
+            self.app.each(|app| {
+                let scanning = match app.process_status {
+                    Some(BLEState::ScanningIdle) | Some(BLEState::Scanning(_)) => true,
+                    _ => false,
+                };
+                if !scanning {
+                    return;
                 }
+                let scan_response = if app.active_scanning {
+                    scan_response
+                } else {
+                    &[]
+                };
+                if !app.scan_filter.matches(adv, scan_response) {
+                    return;
+                }
+                // write to buffer in userland
+                let success = app
+                    .scan_buffer
+                    .as_mut()
+                    .map(|userland| {
+                        for (dst, src) in userland
+                            .iter_mut()
+                            .zip(adv.iter().chain(scan_response.iter()))
+                        {
+                            *dst = *src;
+                        }
+                    })
+                    .is_some();
+
+                if success {
+                    app.scan_callback.map(|mut cb| {
+                        cb.schedule(
+                            usize::from(result),
+                            adv_len + scan_response.len(),
+                            scan_response.len(),
+                        );
+                    });
+                }
+            });
+        }
 
+        self.receiving_app.map(|appid| {
+            let _ = self.app.enter(*appid, |app, _| {
                 match app.process_status {
                     Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
                         app.process_status =
@@ -502,7 +657,9 @@ where
 // Callback from the radio once a TX event occur
 impl<'a, B, A> ble_advertising::TxClient for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     // The ReturnCode indicates valid CRC or not, not used yet but could be used for
@@ -544,7 +701,9 @@ where
 // System Call implementation
 impl<'a, B, A> kernel::Driver for BLE<'a, B, A>
 where
-    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    B: ble_advertising::BleAdvertisementDriver<'a>
+        + ble_advertising::BleConfig
+        + ble_advertising::BleScanDriver,
     A: kernel::hil::time::Alarm<'a>,
 {
     fn command(
@@ -622,11 +781,16 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
+            // Passive or active scanning mode
             5 => self
                 .app
                 .enter(appid, |app, _| {
                     if let Some(BLEState::Initialized) = app.process_status {
+                        app.active_scanning = data & 1 == 1;
+                        if app.active_scanning {
+                            // Scan requests carry the address of the scanner
+                            app.generate_random_address(appid);
+                        }
                         app.process_status = Some(BLEState::ScanningIdle);
                         app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                         self.reset_active_alarm();
@@ -637,6 +801,26 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Scan filters
+            6..=9 => self
+                .app
+                .enter(appid, |app, _| {
+                    let filter = &mut app.scan_filter;
+                    match command_num {
+                        6 => {
+                            let mut address = [0; PACKET_ADDR_LEN];
+                            address[..4].copy_from_slice(&(data as u32).to_le_bytes());
+                            address[4..].copy_from_slice(&(interval as u16).to_le_bytes());
+                            filter.address = Some(address);
+                        }
+                        7 => filter.ad_type = Some(data as u8),
+                        8 => filter.company_id = Some(data as u16),
+                        _ => *filter = ScanFilter::default(),
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -675,6 +859,15 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Scan response buffer
+            2 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.scan_response_data = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
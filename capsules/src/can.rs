@@ -0,0 +1,369 @@
+//! Provides userspace access to a CAN controller.
+//!
+//! Applications share one `hil::can::Can` device, usually a
+//! `virtual_can::CanDevice`. Each application can install one acceptance
+//! filter and receives the frames that match it. Bit timing, mode and
+//! whether the device is on the bus are shared by all applications.
+//!
+//! Frames are sent one at a time from a kernel buffer. Applications that
+//! send while another frame is on the bus are served in turn.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let can = static_init!(
+//!     capsules::can::CanDriver<'static>,
+//!     capsules::can::CanDriver::new(
+//!         can_device,
+//!         &mut capsules::can::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::can::Can::set_transmit_client(can_device, can);
+//! hil::can::Can::set_receive_client(can_device, can);
+//! hil::can::Can::set_state_client(can_device, can);
+//! ```
+//!
+//! Identifiers are passed with bit 31 set for extended (29-bit) identifiers
+//! and clear for standard (11-bit) identifiers.
+
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::can;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Can as usize;
+
+/// Marks an extended identifier in commands and callbacks.
+pub const EXTENDED_ID: usize = 1 << 31;
+
+pub static mut BUFFER: [u8; can::MAX_DATA_LEN] = [0; can::MAX_DATA_LEN];
+
+#[derive(Default)]
+pub struct App {
+    tx_callback: Option<Callback>,
+    rx_callback: Option<Callback>,
+    state_callback: Option<Callback>,
+    tx_buffer: Option<AppSlice<Shared, u8>>,
+    rx_buffer: Option<AppSlice<Shared, u8>>,
+    /// Frame waiting for the controller to become free.
+    pending_tx: Option<(can::Id, usize)>,
+    /// The filter of this app and the device filter it occupies.
+    filter: Option<(can::Filter, usize)>,
+}
+
+pub struct CanDriver<'a> {
+    can: &'a dyn can::Can<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+fn decode_id(id: usize) -> can::Id {
+    if id & EXTENDED_ID != 0 {
+        can::Id::Extended((id & !EXTENDED_ID) as u32)
+    } else {
+        can::Id::Standard(cmp::min(id, u16::MAX as usize) as u16)
+    }
+}
+
+fn encode_id(id: can::Id) -> usize {
+    match id {
+        can::Id::Standard(id) => id as usize,
+        can::Id::Extended(id) => id as usize | EXTENDED_ID,
+    }
+}
+
+impl<'a> CanDriver<'a> {
+    pub fn new(
+        can: &'a dyn can::Can<'a>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> CanDriver<'a> {
+        CanDriver {
+            can,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Copy the frame of `appid` into the kernel buffer and send it.
+    fn send(&self, appid: AppId, app: &mut App, id: can::Id, len: usize) -> ReturnCode {
+        let len = match app.tx_buffer {
+            Some(ref slice) if slice.len() >= len => len,
+            Some(_) => return ReturnCode::ESIZE,
+            None => return ReturnCode::ERESERVE,
+        };
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            if len > buffer.len() {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            app.tx_buffer.as_ref().map(|slice| {
+                buffer[..len].copy_from_slice(&slice.as_ref()[..len]);
+            });
+            match self.can.send(id, buffer, len) {
+                Ok(()) => {
+                    self.current_app.set(appid);
+                    ReturnCode::SUCCESS
+                }
+                Err((rc, buffer)) => {
+                    self.buffer.replace(buffer);
+                    rc
+                }
+            }
+        })
+    }
+
+    /// Send the frame of the next app waiting for the controller.
+    fn send_next(&self) {
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending_tx.take().map_or(false, |(id, len)| {
+                    let rc = self.send(appid, app, id, len);
+                    if rc != ReturnCode::SUCCESS {
+                        app.tx_callback
+                            .map(|mut cb| cb.schedule(usize::from(rc), 0, 0));
+                    }
+                    rc == ReturnCode::SUCCESS
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Find a device filter that no app uses.
+    fn free_filter(&self) -> Option<usize> {
+        (0..self.can.filter_count()).find(|&index| {
+            !self.apps.iter().any(|cntr| {
+                cntr.enter(|app, _| app.filter.map_or(false, |(_, used)| used == index))
+            })
+        })
+    }
+
+    fn set_filter(&self, appid: AppId, filter: Option<can::Filter>) -> ReturnCode {
+        // Look for a free device filter before entering the grant of the app,
+        // which would otherwise be skipped as busy by the search.
+        let free = self.free_filter();
+        self.apps
+            .enter(appid, |app, _| match filter {
+                Some(filter) => {
+                    let index = match app.filter.map(|(_, index)| index).or(free) {
+                        Some(index) => index,
+                        None => return ReturnCode::ENOMEM,
+                    };
+                    let rc = self.can.set_filter(index, Some(filter));
+                    if rc == ReturnCode::SUCCESS {
+                        app.filter = Some((filter, index));
+                    }
+                    rc
+                }
+                None => {
+                    if let Some((_, index)) = app.filter.take() {
+                        self.can.set_filter(index, None);
+                    }
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a> can::TransmitClient for CanDriver<'a> {
+    fn transmit_complete(&self, rc: ReturnCode, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .map(|mut cb| cb.schedule(usize::from(rc), 0, 0));
+            });
+        });
+        self.send_next();
+    }
+}
+
+impl<'a> can::ReceiveClient for CanDriver<'a> {
+    fn message_received(&self, id: can::Id, data: &[u8]) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                if !app.filter.map_or(false, |(filter, _)| filter.matches(id)) {
+                    return;
+                }
+                let len = app.rx_buffer.as_mut().map_or(0, |slice| {
+                    let len = cmp::min(data.len(), slice.len());
+                    slice.as_mut()[..len].copy_from_slice(&data[..len]);
+                    len
+                });
+                app.rx_callback
+                    .map(|mut cb| cb.schedule(encode_id(id), len, 0));
+            });
+        }
+    }
+}
+
+impl<'a> can::StateClient for CanDriver<'a> {
+    fn state_changed(&self, state: can::ErrorState) {
+        let (tec, rec) = self.can.error_counters();
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                app.state_callback
+                    .map(|mut cb| cb.schedule(state as usize, tec as usize, rec as usize));
+            });
+        }
+    }
+}
+
+impl<'a> Driver for CanDriver<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Data of the frame to send.
+    /// - `1`: Buffer for the data of received frames.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.tx_buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.rx_buffer = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Frame sent. Called with the return code of the transmission.
+    /// - `1`: Frame received. Called with the identifier and the number of
+    ///   data bytes copied into the receive buffer.
+    /// - `2`: Error state changed. Called with the new state (0: active,
+    ///   1: passive, 2: bus-off) and the transmit and receive error counters.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.tx_callback = callback,
+                    1 => app.rx_callback = callback,
+                    2 => app.state_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Control the CAN device.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send a frame with identifier `arg1` and the first `arg2` bytes
+    ///   of the transmit buffer.
+    /// - `2`: Set the bitrate to `arg1` bits per second.
+    /// - `3`: Receive frames whose identifier matches `arg1` in the bits set
+    ///   in `arg2`. Replaces the previous filter of the app.
+    /// - `4`: Stop receiving frames.
+    /// - `5`: Join the bus.
+    /// - `6`: Leave the bus.
+    /// - `7`: Set the mode used when joining the bus (0: normal,
+    ///   1: loopback, 2: listen-only).
+    /// - `8`: Get the error state and the transmit and receive error
+    ///   counters, as `state | tec << 8 | rec << 16`.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => {
+                let id = decode_id(arg1);
+                if !id.is_valid() {
+                    return ReturnCode::EINVAL;
+                }
+                if arg2 > can::MAX_DATA_LEN {
+                    return ReturnCode::ESIZE;
+                }
+                let busy = self.current_app.is_some();
+                self.apps
+                    .enter(appid, |app, _| {
+                        if app.pending_tx.is_some()
+                            || self.current_app.map_or(false, |current| *current == appid)
+                        {
+                            ReturnCode::EBUSY
+                        } else if busy {
+                            app.pending_tx = Some((id, arg2));
+                            ReturnCode::SUCCESS
+                        } else {
+                            self.send(appid, app, id, arg2)
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            2 => self.can.set_bitrate(arg1 as u32),
+
+            3 => self.set_filter(
+                appid,
+                Some(can::Filter {
+                    id: decode_id(arg1),
+                    mask: arg2 as u32,
+                }),
+            ),
+
+            4 => self.set_filter(appid, None),
+
+            5 => self.can.enable(),
+
+            6 => self.can.disable(),
+
+            7 => {
+                let mode = match arg1 {
+                    0 => can::Mode::Normal,
+                    1 => can::Mode::Loopback,
+                    2 => can::Mode::ListenOnly,
+                    _ => return ReturnCode::EINVAL,
+                };
+                self.can.set_mode(mode)
+            }
+
+            8 => {
+                let (tec, rec) = self.can.error_counters();
+                ReturnCode::SuccessWithValue {
+                    value: self.can.error_state() as usize
+                        | (tec as usize) << 8
+                        | (rec as usize) << 16,
+                }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
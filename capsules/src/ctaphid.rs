@@ -0,0 +1,927 @@
+//! CTAPHID message framing for FIDO authenticators
+//!
+//! Implements the CTAPHID protocol of the FIDO Client to Authenticator
+//! Protocol on top of a transport that moves fixed-size packets. It
+//! allocates channels, answers INIT, PING and WINK itself, reassembles MSG
+//! (CTAP1/U2F) and CBOR (CTAP2) requests from their initialization and
+//! continuation packets, and splits responses back into packets. While the
+//! client works on a request, it sends KEEPALIVE packets to the host, and it
+//! passes CANCEL on to the client.
+//!
+//! Transports implement `PacketTransport`, which sends and receives packets
+//! of a size the transport chooses, up to `MAX_PACKET_SIZE` bytes. Over USB,
+//! `UsbHidTransport` carries 64-byte packets as HID reports of any
+//! `hil::usb_hid::UsbHid<[u8; 64]>` interface, such as `usb::ctap::CtapHid`.
+//! A BLE GATT characteristic can carry smaller packets, sized to fit the
+//! ATT_MTU of the connection. Shorter packets hold less data, so they also
+//! limit the length of messages, because a message has at most 128
+//! continuation packets.
+//!
+//! One transaction runs at a time: while a request is being received,
+//! processed or answered, requests from other channels get ERR_CHANNEL_BUSY.
+//! A request whose continuation packets stop arriving is abandoned with
+//! ERR_MSG_TIMEOUT.
+//!
+//! ```text
+//!   CtapDriver (complete requests)
+//!        |
+//!   CtapHidFraming
+//!        |
+//!   UsbHidTransport (PacketTransport)
+//!        |
+//!   usb::ctap::CtapHid (64-byte HID reports)
+//! ```
+//!
+//! Based on the FIDO Client to Authenticator Protocol v2.0, section 8.1.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let transport = static_init!(
+//!     capsules::ctaphid::UsbHidTransport<'static, CtapHid<'static, Usbd<'static>>>,
+//!     capsules::ctaphid::UsbHidTransport::new(ctap_hid)
+//! );
+//! ctap_hid.set_client(transport);
+//! let framing = static_init!(
+//!     capsules::ctaphid::CtapHidFraming<
+//!         'static,
+//!         capsules::ctaphid::UsbHidTransport<'static, CtapHid<'static, Usbd<'static>>>,
+//!         VirtualMuxAlarm<'static, Rtc>,
+//!     >,
+//!     capsules::ctaphid::CtapHidFraming::new(
+//!         transport,
+//!         ctap_alarm,
+//!         static_init!([u8; 64], [0; 64]),
+//!         static_init!([u8; 64], [0; 64]),
+//!         &mut capsules::ctaphid::MESSAGE_BUFFER,
+//!     )
+//! );
+//! transport.set_client(framing);
+//! ctap_alarm.set_alarm_client(framing);
+//! framing.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryFrom;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{Alarm, AlarmClient};
+use kernel::hil::usb_hid;
+use kernel::ReturnCode;
+
+/// Largest request or response.
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// Size of USB HID reports, and largest packet of any transport.
+pub const MAX_PACKET_SIZE: usize = 64;
+/// Smallest packet that holds an error or KEEPALIVE packet.
+pub const MIN_PACKET_SIZE: usize = 8;
+
+pub static mut MESSAGE_BUFFER: [u8; MAX_MESSAGE_SIZE] = [0; MAX_MESSAGE_SIZE];
+
+/// Channel that INIT requests for a new channel are sent on.
+const BROADCAST_CHANNEL: u32 = 0xffff_ffff;
+
+/// Header bytes of initialization and continuation packets.
+const INIT_HEADER: usize = 7;
+const CONT_HEADER: usize = 5;
+/// Continuation packets of a message, numbered 0 to 127.
+const MAX_CONT_PACKETS: usize = 128;
+
+/// Time allowed between the packets of a request, in milliseconds.
+const MESSAGE_TIMEOUT: u32 = 500;
+/// Interval between KEEPALIVE packets, in milliseconds.
+const KEEPALIVE_INTERVAL: u32 = 100;
+
+// Commands. LOCK (0x04) is not supported and rejected like unknown commands.
+const PING: u8 = 0x01;
+const MSG: u8 = 0x03;
+const INIT: u8 = 0x06;
+const WINK: u8 = 0x08;
+const CBOR: u8 = 0x10;
+const CANCEL: u8 = 0x11;
+const KEEPALIVE: u8 = 0x3b;
+const ERROR: u8 = 0x3f;
+
+// Errors.
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_MSG_TIMEOUT: u8 = 0x05;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+// Capabilities in the INIT response.
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+
+// Keepalive status codes.
+const STATUS_PROCESSING: u8 = 1;
+const STATUS_UPNEEDED: u8 = 2;
+
+/// Kinds of requests passed to the client.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// A CTAP1/U2F message.
+    Msg = MSG as isize,
+    /// A CTAP2 CBOR message.
+    Cbor = CBOR as isize,
+}
+
+pub trait CtapHidClient {
+    /// A complete request arrived. The client answers it later with
+    /// `respond()`.
+    fn request_received(&self, command: Command, request: &[u8]);
+
+    /// The host cancelled the request being processed. The client should
+    /// still respond, with CTAP2_ERR_KEEPALIVE_CANCEL for a CBOR request.
+    fn request_cancelled(&self);
+
+    /// The response was sent.
+    fn response_sent(&self);
+
+    /// The host asked the authenticator to identify itself to the user.
+    fn wink(&self) {}
+}
+
+/// Message level interface of an authenticator transport.
+pub trait Ctap<'a> {
+    fn set_client(&self, client: &'a dyn CtapHidClient);
+
+    /// Send the response to the request being processed.
+    fn respond(&self, response: &[u8]) -> ReturnCode;
+
+    /// Tell the host, in KEEPALIVE packets, whether the authenticator waits
+    /// for the user to confirm their presence.
+    fn set_user_presence_needed(&self, needed: bool);
+}
+
+/// A transport that moves CTAPHID packets to and from the host.
+pub trait PacketTransport<'a> {
+    fn set_client(&self, client: &'a dyn PacketClient);
+
+    /// Size of the packets, between `MIN_PACKET_SIZE` and `MAX_PACKET_SIZE`
+    /// bytes. It must not change while a message is being sent or received.
+    fn packet_size(&self) -> usize;
+
+    /// Send the first `packet_size()` bytes of `packet`.
+    fn send_packet(&self, packet: &'static mut [u8])
+        -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Receive the next packet into `packet`.
+    fn receive_packet(
+        &self,
+        packet: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait PacketClient {
+    /// A packet of `len` bytes was received.
+    fn packet_received(&self, packet: &'static mut [u8], len: usize);
+
+    /// The packet passed to `send_packet()` was sent.
+    fn packet_sent(&self, packet: &'static mut [u8]);
+}
+
+/// Carries CTAPHID packets as the 64-byte reports of a USB HID interface.
+pub struct UsbHidTransport<'a, H: usb_hid::UsbHid<'a, [u8; 64]>> {
+    hid: &'a H,
+    client: OptionalCell<&'a dyn PacketClient>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>> UsbHidTransport<'a, H> {
+    pub fn new(hid: &'a H) -> UsbHidTransport<'a, H> {
+        UsbHidTransport {
+            hid: hid,
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+/// The report that fills the start of `packet`, or `packet` back if it is too
+/// short for one.
+fn as_report(packet: &'static mut [u8]) -> Result<&'static mut [u8; 64], &'static mut [u8]> {
+    if packet.len() < MAX_PACKET_SIZE {
+        return Err(packet);
+    }
+    Ok(<&mut [u8; 64]>::try_from(&mut packet[..MAX_PACKET_SIZE]).unwrap())
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>> PacketTransport<'a> for UsbHidTransport<'a, H> {
+    fn set_client(&self, client: &'a dyn PacketClient) {
+        self.client.set(client);
+    }
+
+    fn packet_size(&self) -> usize {
+        MAX_PACKET_SIZE
+    }
+
+    fn send_packet(
+        &self,
+        packet: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let report = as_report(packet).map_err(|packet| (ReturnCode::ESIZE, packet))?;
+        self.hid
+            .send_buffer(report)
+            .map(|_| ())
+            .map_err(|(err, report)| (err, &mut report[..]))
+    }
+
+    fn receive_packet(
+        &self,
+        packet: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let report = as_report(packet).map_err(|packet| (ReturnCode::ESIZE, packet))?;
+        self.hid
+            .receive_buffer(report)
+            .map_err(|(err, report)| (err, &mut report[..]))
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::Client<'a, [u8; 64]>
+    for UsbHidTransport<'a, H>
+{
+    fn packet_received(&'a self, _result: ReturnCode, buffer: &'static mut [u8; 64], _: usize) {
+        self.client
+            .map(move |client| client.packet_received(buffer, MAX_PACKET_SIZE));
+    }
+
+    fn packet_transmitted(&'a self, _result: ReturnCode, buffer: &'static mut [u8; 64], _: usize) {
+        self.client.map(move |client| client.packet_sent(buffer));
+    }
+
+    fn can_receive(&'a self) -> bool {
+        true
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reassembling a request from its packets.
+    Receiving,
+    /// The client has the request.
+    Processing,
+    /// Sending the response.
+    Responding,
+}
+
+pub struct CtapHidFraming<'a, T: PacketTransport<'a>, A: Alarm<'a>> {
+    transport: &'a T,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn CtapHidClient>,
+
+    send_packet: TakeCell<'static, [u8]>,
+    recv_packet: TakeCell<'static, [u8]>,
+    /// Length of the shorter packet buffer.
+    packet_buffer_len: usize,
+
+    /// The request or response of the current transaction.
+    message: TakeCell<'static, [u8]>,
+    message_len: Cell<usize>,
+    /// Bytes of the message received or sent so far.
+    offset: Cell<usize>,
+    /// Sequence number of the next continuation packet.
+    seq: Cell<u8>,
+    /// The initialization packet of the response was sent.
+    sent_init: Cell<bool>,
+    channel: Cell<u32>,
+    command: Cell<u8>,
+    state: Cell<State>,
+
+    /// An error to send once the packet being sent is done.
+    pending_error: OptionalCell<(u32, u8)>,
+    keepalive_status: Cell<u8>,
+    /// Last channel allocated by INIT.
+    last_channel: Cell<u32>,
+}
+
+impl<'a, T: PacketTransport<'a>, A: Alarm<'a>> CtapHidFraming<'a, T, A> {
+    /// The packet buffers must hold the packets of `transport`.
+    pub fn new(
+        transport: &'a T,
+        alarm: &'a A,
+        send_packet: &'static mut [u8],
+        recv_packet: &'static mut [u8],
+        message: &'static mut [u8],
+    ) -> CtapHidFraming<'a, T, A> {
+        CtapHidFraming {
+            transport: transport,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            packet_buffer_len: cmp::min(send_packet.len(), recv_packet.len()),
+            send_packet: TakeCell::new(send_packet),
+            recv_packet: TakeCell::new(recv_packet),
+            message: TakeCell::new(message),
+            message_len: Cell::new(0),
+            offset: Cell::new(0),
+            seq: Cell::new(0),
+            sent_init: Cell::new(false),
+            channel: Cell::new(0),
+            command: Cell::new(0),
+            state: Cell::new(State::Idle),
+            pending_error: OptionalCell::empty(),
+            keepalive_status: Cell::new(STATUS_PROCESSING),
+            last_channel: Cell::new(0),
+        }
+    }
+
+    /// Start receiving packets from the transport.
+    pub fn start(&self) -> ReturnCode {
+        self.recv_packet
+            .take()
+            .map_or(ReturnCode::EALREADY, |packet| {
+                match self.transport.receive_packet(packet) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((err, packet)) => {
+                        self.recv_packet.replace(packet);
+                        err
+                    }
+                }
+            })
+    }
+
+    fn packet_size(&self) -> usize {
+        cmp::min(self.transport.packet_size(), self.packet_buffer_len)
+    }
+
+    /// Data bytes in initialization packets.
+    fn init_data(&self) -> usize {
+        self.packet_size() - INIT_HEADER
+    }
+
+    /// Data bytes in continuation packets.
+    fn cont_data(&self) -> usize {
+        self.packet_size() - CONT_HEADER
+    }
+
+    /// Longest message that fits in the message buffer and in the packets of
+    /// the transport.
+    fn max_message_len(&self) -> usize {
+        let packets_len = self.init_data() + MAX_CONT_PACKETS * self.cont_data();
+        self.message
+            .map_or(0, |message| cmp::min(message.len(), packets_len))
+    }
+
+    fn set_alarm(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Abandon the current transaction.
+    fn abort(&self) {
+        if self.state.get() == State::Processing {
+            self.client.map(|client| client.request_cancelled());
+        }
+        self.state.set(State::Idle);
+        let _ = self.alarm.disarm();
+    }
+
+    /// Send an error to `channel` as soon as the transport is free.
+    fn send_error(&self, channel: u32, error: u8) {
+        self.pending_error.set((channel, error));
+        self.send_next();
+    }
+
+    /// Fill in a packet for `channel` with `build` and send it. Returns false
+    /// if a packet is already being sent.
+    fn send(&self, channel: u32, build: impl FnOnce(&mut [u8])) -> bool {
+        let size = self.packet_size();
+        self.send_packet.take().map_or(false, |packet| {
+            for byte in packet.iter_mut() {
+                *byte = 0;
+            }
+            packet[0..4].copy_from_slice(&channel.to_be_bytes());
+            build(&mut packet[..size]);
+            if let Err((_, packet)) = self.transport.send_packet(packet) {
+                self.send_packet.replace(packet);
+            }
+            true
+        })
+    }
+
+    /// Send the next packet: a pending error, or the next packet of the
+    /// response.
+    fn send_next(&self) {
+        if self.send_packet.is_none() {
+            return;
+        }
+        if let Some((channel, error)) = self.pending_error.take() {
+            self.send(channel, |packet| {
+                packet[4] = ERROR | 0x80;
+                packet[6] = 1;
+                packet[7] = error;
+            });
+            return;
+        }
+        if self.state.get() != State::Responding {
+            return;
+        }
+
+        let offset = self.offset.get();
+        let len = self.message_len.get();
+        if self.sent_init.get() && offset == len {
+            self.state.set(State::Idle);
+            self.client.map(|client| client.response_sent());
+            return;
+        }
+        let command = self.command.get();
+        let seq = self.seq.get();
+        let first = !self.sent_init.get();
+        let (header, room) = if first {
+            (INIT_HEADER, self.init_data())
+        } else {
+            (CONT_HEADER, self.cont_data())
+        };
+        let count = cmp::min(room, len - offset);
+        self.message.map(|message| {
+            self.send(self.channel.get(), |packet| {
+                if first {
+                    packet[4] = command | 0x80;
+                    packet[5] = (len >> 8) as u8;
+                    packet[6] = len as u8;
+                } else {
+                    packet[4] = seq;
+                }
+                packet[header..header + count].copy_from_slice(&message[offset..offset + count]);
+            });
+        });
+        self.offset.set(offset + count);
+        if first {
+            self.sent_init.set(true);
+        } else {
+            self.seq.set(seq + 1);
+        }
+    }
+
+    /// Send the first `len` bytes of the message buffer as the response.
+    fn start_response(&self, len: usize) {
+        self.message_len.set(len);
+        self.offset.set(0);
+        self.seq.set(0);
+        self.sent_init.set(false);
+        self.state.set(State::Responding);
+        self.send_next();
+    }
+
+    fn handle_packet(&self, packet: &[u8]) {
+        let channel = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if packet[4] & 0x80 == 0 {
+            self.handle_continuation(channel, packet);
+            return;
+        }
+
+        let command = packet[4] & 0x7f;
+        let len = (packet[5] as usize) << 8 | packet[6] as usize;
+        if channel == 0 {
+            self.send_error(channel, ERR_INVALID_CHANNEL);
+            return;
+        }
+
+        match self.state.get() {
+            State::Idle => {}
+            _ if channel != self.channel.get() => {
+                if command != CANCEL {
+                    self.send_error(channel, ERR_CHANNEL_BUSY);
+                }
+                return;
+            }
+            State::Processing if command == CANCEL => {
+                self.client.map(|client| client.request_cancelled());
+                return;
+            }
+            _ if command == CANCEL => return,
+            _ if command == INIT => {
+                // Resynchronize the channel.
+                self.abort();
+            }
+            State::Receiving => {
+                self.abort();
+                self.send_error(channel, ERR_INVALID_SEQ);
+                return;
+            }
+            State::Processing | State::Responding => {
+                self.send_error(channel, ERR_CHANNEL_BUSY);
+                return;
+            }
+        }
+
+        let allocated = channel != BROADCAST_CHANNEL && channel <= self.last_channel.get();
+        if !allocated && !(command == INIT && channel == BROADCAST_CHANNEL) {
+            self.send_error(channel, ERR_INVALID_CHANNEL);
+            return;
+        }
+        if command == CANCEL {
+            // Nothing to cancel.
+            return;
+        }
+
+        let init_data = self.init_data();
+        let fits = len <= self.max_message_len()
+            && self.message.map_or(false, |message| {
+                let count = cmp::min(len, init_data);
+                message[..count].copy_from_slice(&packet[INIT_HEADER..INIT_HEADER + count]);
+                true
+            });
+        if !fits {
+            self.send_error(channel, ERR_INVALID_LEN);
+            return;
+        }
+
+        self.channel.set(channel);
+        self.command.set(command);
+        self.message_len.set(len);
+        self.offset.set(cmp::min(len, init_data));
+        self.seq.set(0);
+        if len > init_data {
+            self.state.set(State::Receiving);
+            self.set_alarm(MESSAGE_TIMEOUT);
+        } else {
+            self.dispatch();
+        }
+    }
+
+    fn handle_continuation(&self, channel: u32, packet: &[u8]) {
+        if self.state.get() != State::Receiving || channel != self.channel.get() {
+            // Spurious continuation packets are ignored.
+            return;
+        }
+        if packet[4] != self.seq.get() {
+            self.abort();
+            self.send_error(channel, ERR_INVALID_SEQ);
+            return;
+        }
+        let offset = self.offset.get();
+        let count = cmp::min(self.cont_data(), self.message_len.get() - offset);
+        self.message.map(|message| {
+            message[offset..offset + count]
+                .copy_from_slice(&packet[CONT_HEADER..CONT_HEADER + count]);
+        });
+        self.offset.set(offset + count);
+        self.seq.set(packet[4].wrapping_add(1));
+        if self.offset.get() == self.message_len.get() {
+            let _ = self.alarm.disarm();
+            self.dispatch();
+        } else {
+            self.set_alarm(MESSAGE_TIMEOUT);
+        }
+    }
+
+    /// Handle a complete request.
+    fn dispatch(&self) {
+        let channel = self.channel.get();
+        let len = self.message_len.get();
+        match self.command.get() {
+            PING => self.start_response(len),
+            INIT => {
+                if len != 8 {
+                    self.state.set(State::Idle);
+                    self.send_error(channel, ERR_INVALID_LEN);
+                    return;
+                }
+                let new_channel = if channel == BROADCAST_CHANNEL {
+                    let mut next = self.last_channel.get().wrapping_add(1);
+                    if next == BROADCAST_CHANNEL {
+                        next = 1;
+                    }
+                    self.last_channel.set(next);
+                    next
+                } else {
+                    channel
+                };
+                self.message.map(|message| {
+                    // The nonce stays in bytes 0 to 7.
+                    message[8..12].copy_from_slice(&new_channel.to_be_bytes());
+                    message[12] = 2; // CTAPHID protocol version
+                    message[13] = 0; // Major device version
+                    message[14] = 0; // Minor device version
+                    message[15] = 0; // Build device version
+                    message[16] = CAPABILITY_WINK | CAPABILITY_CBOR;
+                });
+                self.start_response(17);
+            }
+            WINK => {
+                self.client.map(|client| client.wink());
+                self.start_response(0);
+            }
+            command @ MSG | command @ CBOR if self.client.is_some() => {
+                let command = if command == MSG {
+                    Command::Msg
+                } else {
+                    Command::Cbor
+                };
+                self.state.set(State::Processing);
+                self.keepalive_status.set(STATUS_PROCESSING);
+                if command == Command::Cbor {
+                    self.set_alarm(KEEPALIVE_INTERVAL);
+                }
+                // The request is lent to the client, which responds once it
+                // is done with it.
+                self.message.take().map(|message| {
+                    self.client
+                        .map(|client| client.request_received(command, &message[..len]));
+                    self.message.replace(message);
+                });
+            }
+            _ => {
+                self.state.set(State::Idle);
+                self.send_error(channel, ERR_INVALID_CMD);
+            }
+        }
+    }
+}
+
+impl<'a, T: PacketTransport<'a>, A: Alarm<'a>> Ctap<'a> for CtapHidFraming<'a, T, A> {
+    fn set_client(&self, client: &'a dyn CtapHidClient) {
+        self.client.set(client);
+    }
+
+    fn respond(&self, response: &[u8]) -> ReturnCode {
+        if self.state.get() != State::Processing {
+            return ReturnCode::EINVAL;
+        }
+        let max_len = self.max_message_len();
+        let copied = response.len() <= max_len
+            && self.message.map_or(false, |message| {
+                message[..response.len()].copy_from_slice(response);
+                true
+            });
+        if !copied {
+            // The response is too long, or the client is still in
+            // `request_received()`.
+            return ReturnCode::ESIZE;
+        }
+        let _ = self.alarm.disarm();
+        self.start_response(response.len());
+        ReturnCode::SUCCESS
+    }
+
+    fn set_user_presence_needed(&self, needed: bool) {
+        self.keepalive_status.set(if needed {
+            STATUS_UPNEEDED
+        } else {
+            STATUS_PROCESSING
+        });
+    }
+}
+
+impl<'a, T: PacketTransport<'a>, A: Alarm<'a>> PacketClient for CtapHidFraming<'a, T, A> {
+    fn packet_received(&self, packet: &'static mut [u8], len: usize) {
+        let size = self.packet_size();
+        // Bytes missing from a short packet read as zero.
+        for byte in packet[cmp::min(len, size)..size].iter_mut() {
+            *byte = 0;
+        }
+        self.handle_packet(&packet[..size]);
+        if let Err((_, packet)) = self.transport.receive_packet(packet) {
+            self.recv_packet.replace(packet);
+        }
+    }
+
+    fn packet_sent(&self, packet: &'static mut [u8]) {
+        self.send_packet.replace(packet);
+        self.send_next();
+    }
+}
+
+impl<'a, T: PacketTransport<'a>, A: Alarm<'a>> AlarmClient for CtapHidFraming<'a, T, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Receiving => {
+                self.state.set(State::Idle);
+                self.send_error(self.channel.get(), ERR_MSG_TIMEOUT);
+            }
+            State::Processing => {
+                // Skip this keepalive if an error is being sent.
+                let status = self.keepalive_status.get();
+                self.send(self.channel.get(), |packet| {
+                    packet[4] = KEEPALIVE | 0x80;
+                    packet[6] = 1;
+                    packet[7] = status;
+                });
+                self.set_alarm(KEEPALIVE_INTERVAL);
+            }
+            State::Responding | State::Idle => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::{
+        Command, Ctap, CtapHidClient, CtapHidFraming, PacketClient, PacketTransport, CBOR, ERROR,
+        ERR_INVALID_LEN, INIT, PING,
+    };
+    use crate::usb::sim::SimAlarm;
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::ReturnCode;
+
+    const BROADCAST: u32 = 0xffff_ffff;
+
+    type Framing<'a> = CtapHidFraming<'a, TestTransport<'a>, SimAlarm<'a>>;
+
+    /// A transport of short packets, like a BLE characteristic.
+    struct TestTransport<'a> {
+        size: usize,
+        client: OptionalCell<&'a dyn PacketClient>,
+        recv_packet: TakeCell<'static, [u8]>,
+        send_packet: TakeCell<'static, [u8]>,
+        sent: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl<'a> TestTransport<'a> {
+        fn new(size: usize) -> TestTransport<'a> {
+            TestTransport {
+                size: size,
+                client: OptionalCell::empty(),
+                recv_packet: TakeCell::empty(),
+                send_packet: TakeCell::empty(),
+                sent: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// Deliver a packet from the host.
+        fn host_send(&self, data: &[u8]) {
+            let packet = self.recv_packet.take().expect("not receiving");
+            packet[..data.len()].copy_from_slice(data);
+            self.client
+                .map(move |client| client.packet_received(packet, data.len()));
+        }
+
+        /// Complete sends until the framing stops sending, and return the
+        /// packets sent.
+        fn host_receive(&self) -> Vec<Vec<u8>> {
+            while let Some(packet) = self.send_packet.take() {
+                self.client.map(move |client| client.packet_sent(packet));
+            }
+            self.sent.replace(Vec::new())
+        }
+    }
+
+    impl<'a> PacketTransport<'a> for TestTransport<'a> {
+        fn set_client(&self, client: &'a dyn PacketClient) {
+            self.client.set(client);
+        }
+
+        fn packet_size(&self) -> usize {
+            self.size
+        }
+
+        fn send_packet(
+            &self,
+            packet: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.sent.borrow_mut().push(packet[..self.size].to_vec());
+            self.send_packet.replace(packet);
+            Ok(())
+        }
+
+        fn receive_packet(
+            &self,
+            packet: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.recv_packet.replace(packet);
+            Ok(())
+        }
+    }
+
+    struct TestClient {
+        request: Cell<Option<(Command, usize)>>,
+    }
+
+    impl CtapHidClient for TestClient {
+        fn request_received(&self, command: Command, request: &[u8]) {
+            self.request.set(Some((command, request.len())));
+        }
+
+        fn request_cancelled(&self) {}
+
+        fn response_sent(&self) {}
+    }
+
+    fn with_framing(packet_size: usize, test: impl FnOnce(&TestTransport, &Framing, &TestClient)) {
+        let transport = TestTransport::new(packet_size);
+        let alarm = SimAlarm::new();
+        let framing = CtapHidFraming::new(
+            &transport,
+            &alarm,
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 1024])),
+        );
+        let client = TestClient {
+            request: Cell::new(None),
+        };
+        transport.set_client(&framing);
+        framing.set_client(&client);
+        assert_eq!(framing.start(), ReturnCode::SUCCESS);
+        test(&transport, &framing, &client);
+    }
+
+    /// Split a message into initialization and continuation packets of
+    /// `size` bytes.
+    fn packets(size: usize, channel: u32, command: u8, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut packet = std::vec![0; size];
+        packet[0..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = command | 0x80;
+        packet[5] = (data.len() >> 8) as u8;
+        packet[6] = data.len() as u8;
+        let mut rest = data;
+        let count = rest.len().min(size - 7);
+        packet[7..7 + count].copy_from_slice(&rest[..count]);
+        rest = &rest[count..];
+        packets.push(packet);
+        for seq in 0.. {
+            if rest.is_empty() {
+                break;
+            }
+            let mut packet = std::vec![0; size];
+            packet[0..4].copy_from_slice(&channel.to_be_bytes());
+            packet[4] = seq;
+            let count = rest.len().min(size - 5);
+            packet[5..5 + count].copy_from_slice(&rest[..count]);
+            rest = &rest[count..];
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// The data of a message sent in `packets`.
+    fn reassemble(size: usize, packets: &[Vec<u8>]) -> Vec<u8> {
+        let len = (packets[0][5] as usize) << 8 | packets[0][6] as usize;
+        let mut data = packets[0][7..].to_vec();
+        for packet in &packets[1..] {
+            data.extend_from_slice(&packet[5..size]);
+        }
+        data.truncate(len);
+        data
+    }
+
+    /// Allocate a channel with INIT.
+    fn init(size: usize, transport: &TestTransport) -> u32 {
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        for packet in packets(size, BROADCAST, INIT, &nonce) {
+            transport.host_send(&packet);
+        }
+        let sent = transport.host_receive();
+        assert_eq!(sent[0][4], INIT | 0x80);
+        let response = reassemble(size, &sent);
+        assert_eq!(response.len(), 17);
+        assert_eq!(response[0..8], nonce);
+        u32::from_be_bytes([response[8], response[9], response[10], response[11]])
+    }
+
+    #[test]
+    fn short_packets() {
+        const SIZE: usize = 20;
+        with_framing(SIZE, |transport, _, _| {
+            let channel = init(SIZE, transport);
+            let data: Vec<u8> = (0..100).collect();
+            for packet in packets(SIZE, channel, PING, &data) {
+                transport.host_send(&packet);
+            }
+            let sent = transport.host_receive();
+            assert_eq!(sent, packets(SIZE, channel, PING, &data));
+            assert!(sent.iter().all(|packet| packet.len() == SIZE));
+        });
+    }
+
+    #[test]
+    fn message_too_long_for_packets() {
+        // 8-byte packets carry at most 1 + 128 * 3 bytes.
+        const SIZE: usize = 8;
+        with_framing(SIZE, |transport, framing, client| {
+            let channel = init(SIZE, transport);
+            transport.host_send(&packets(SIZE, channel, PING, &[0; 386])[0]);
+            let sent = transport.host_receive();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0][4], ERROR | 0x80);
+            assert_eq!(sent[0][7], ERR_INVALID_LEN);
+
+            // The longest message still fits.
+            let data = [0x5a; 385];
+            for packet in packets(SIZE, channel, PING, &data) {
+                transport.host_send(&packet);
+            }
+            assert_eq!(
+                transport.host_receive(),
+                packets(SIZE, channel, PING, &data)
+            );
+
+            // So do responses.
+            transport.host_send(&packets(SIZE, channel, CBOR, &[4])[0]);
+            assert_eq!(client.request.get(), Some((Command::Cbor, 1)));
+            assert_eq!(framing.respond(&[0; 386]), ReturnCode::ESIZE);
+            assert_eq!(framing.respond(&[0; 385]), ReturnCode::SUCCESS);
+            assert_eq!(transport.host_receive().len(), 1 + 128);
+        });
+    }
+}
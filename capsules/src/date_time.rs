@@ -0,0 +1,184 @@
+//! Provides userspace access to the wall-clock date and time.
+//!
+//! Times are exchanged with userspace as seconds since 1970-01-01 00:00:00
+//! UTC, which fit in 32 bits until 2106. Every app can set one alarm; the
+//! driver keeps the earliest of them on the clock, so it must be the only
+//! client of the clock.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let date_time = static_init!(
+//!     capsules::date_time::DateTimeDriver<'static>,
+//!     capsules::date_time::DateTimeDriver::new(&peripherals.rtc, board_kernel.create_grant(&grant_cap))
+//! );
+//! hil::date_time::Clock::set_client(&peripherals.rtc, date_time);
+//! ```
+
+use kernel::hil::date_time::{self, DateTime};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::DateTime as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    alarm: Option<u64>,
+}
+
+pub struct DateTimeDriver<'a> {
+    clock: &'a dyn date_time::Clock<'a>,
+    apps: Grant<App>,
+}
+
+impl<'a> DateTimeDriver<'a> {
+    pub fn new(clock: &'a dyn date_time::Clock<'a>, grant: Grant<App>) -> DateTimeDriver<'a> {
+        DateTimeDriver { clock, apps: grant }
+    }
+
+    /// Notify apps whose alarm has passed and arm the clock for the earliest
+    /// remaining alarm.
+    fn process_alarms(&self) {
+        loop {
+            let now = match self.clock.date_time() {
+                Ok(now) => now.unix_time(),
+                Err(_) => return,
+            };
+            let mut earliest: Option<u64> = None;
+            for cntr in self.apps.iter() {
+                cntr.enter(|app, _| {
+                    if let Some(alarm) = app.alarm {
+                        if alarm <= now {
+                            app.alarm = None;
+                            app.callback.map(|mut cb| cb.schedule(now as usize, 0, 0));
+                        } else if earliest.map_or(true, |earliest| alarm < earliest) {
+                            earliest = Some(alarm);
+                        }
+                    }
+                });
+            }
+            match earliest {
+                None => {
+                    self.clock.disarm_alarm();
+                    return;
+                }
+                Some(alarm) => {
+                    let alarm = DateTime::from_unix_time(alarm);
+                    if self.clock.get_alarm() == Some(alarm)
+                        || self.clock.set_alarm(alarm) != ReturnCode::EINVAL
+                    {
+                        return;
+                    }
+                    // The alarm passed while the apps were checked
+                }
+            }
+        }
+    }
+}
+
+impl<'a> date_time::Client for DateTimeDriver<'a> {
+    fn alarm(&self) {
+        self.process_alarms();
+    }
+}
+
+impl<'a> Driver for DateTimeDriver<'a> {
+    /// Subscribe to alarm notifications.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: The alarm of the app fired. Called with the current time.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Get or set the date and time.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the current time. Fails with EOFF if the clock has not
+    ///   been set.
+    /// - `2`: Set the current time to `data`.
+    /// - `3`: Set the alarm of the app to time `data`, which must be in the
+    ///   future.
+    /// - `4`: Cancel the alarm of the app.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => match self.clock.date_time() {
+                Ok(now) => ReturnCode::SuccessWithValue {
+                    value: now.unix_time() as usize,
+                },
+                Err(rc) => rc,
+            },
+
+            2 => {
+                let rc = self
+                    .clock
+                    .set_date_time(DateTime::from_unix_time(data as u64));
+                if rc == ReturnCode::SUCCESS {
+                    self.process_alarms();
+                }
+                rc
+            }
+
+            3 => {
+                let now = match self.clock.date_time() {
+                    Ok(now) => now.unix_time(),
+                    Err(rc) => return rc,
+                };
+                if data as u64 <= now {
+                    return ReturnCode::EINVAL;
+                }
+                let rc = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.alarm = Some(data as u64);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if rc == ReturnCode::SUCCESS {
+                    self.process_alarms();
+                }
+                rc
+            }
+
+            4 => {
+                let rc = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.alarm = None;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if rc == ReturnCode::SUCCESS {
+                    self.process_alarms();
+                }
+                rc
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
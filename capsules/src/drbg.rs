@@ -0,0 +1,477 @@
+//! HMAC_DRBG random number generator (NIST SP 800-90A) seeded from a
+//! health-tested entropy source.
+//!
+//! `HmacDrbg` turns an `Entropy32` source, such as a TRNG, into an `Rng`
+//! whose output comes from an HMAC-SHA256 deterministic random bit generator
+//! instead of the raw source. It can stand in for `Entropy32ToRandom` under
+//! the userspace RNG driver or the RNG virtualizer.
+//!
+//! Every byte of entropy used is checked with the continuous health tests
+//! of NIST SP 800-90B, the repetition count test and the adaptive proportion
+//! test, treating each byte as a sample. The cutoffs follow from the
+//! min-entropy per byte claimed for the source at construction, with a
+//! false positive probability of 2^-20 per test. A claim below the real
+//! min-entropy only makes the tests less sensitive and the seeds longer.
+//! Before the first seed, 1024 samples are tested and discarded as the
+//! start-up test.
+//!
+//! The generator is seeded with 384 bits of min-entropy (entropy input and
+//! nonce), and reseeded with 256 bits after `RESEED_INTERVAL` requests. If a
+//! health test fails, the pending request fails with FAIL, the generator
+//! state is destroyed, and the next request starts over with the start-up
+//! test.
+//!
+//! Every request waits for an `Entropy32` callback, even when no entropy is
+//! needed, so that randomness is always delivered asynchronously.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let drbg = static_init!(
+//!     capsules::drbg::HmacDrbg<'static>,
+//!     capsules::drbg::HmacDrbg::new(&base_peripherals.trng, 4)
+//! );
+//! let rng = static_init!(
+//!     capsules::rng::RngDriver<'static>,
+//!     capsules::rng::RngDriver::new(drbg, board_kernel.create_grant(&grant_cap))
+//! );
+//! drbg.set_client(rng);
+//! ```
+
+use crate::software_sha256::hmac_sha256;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::hil::entropy::{self, Entropy32};
+use kernel::hil::rng::{self, Rng};
+use kernel::ReturnCode;
+
+/// Requests served between reseeds.
+pub const RESEED_INTERVAL: usize = 1024;
+
+/// Words handed out per request. Clients wanting more return
+/// `Continue::More` and get another request.
+const WORDS_PER_REQUEST: usize = 256;
+
+/// Samples tested and discarded before the first seed.
+const STARTUP_SAMPLES: usize = 1024;
+
+/// Adaptive proportion test window, for non-binary samples.
+const APT_WINDOW: usize = 512;
+
+/// Repetition count and adaptive proportion test cutoffs for each claimed
+/// min-entropy from 1 to 8 bits per byte, for a false positive probability
+/// of 2^-20 (SP 800-90B sections 4.4.1 and 4.4.2).
+const CUTOFFS: [(usize, usize); 8] = [
+    (21, 311),
+    (11, 177),
+    (8, 103),
+    (6, 62),
+    (5, 39),
+    (5, 25),
+    (4, 18),
+    (4, 13),
+];
+
+const SEED_BITS: usize = 384;
+const RESEED_BITS: usize = 256;
+/// Entropy needed for a seed at 1 bit per byte
+const MAX_SEED_LEN: usize = SEED_BITS;
+
+/// SP 800-90B continuous health tests on byte samples.
+struct HealthTests {
+    rct_cutoff: usize,
+    apt_cutoff: usize,
+    last: u8,
+    repetitions: usize,
+    apt_reference: u8,
+    apt_count: usize,
+    apt_samples: usize,
+}
+
+impl HealthTests {
+    fn new(min_entropy: usize) -> HealthTests {
+        let (rct_cutoff, apt_cutoff) = CUTOFFS[min_entropy - 1];
+        HealthTests {
+            rct_cutoff,
+            apt_cutoff,
+            last: 0,
+            repetitions: 0,
+            apt_reference: 0,
+            apt_count: 0,
+            apt_samples: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.repetitions = 0;
+        self.apt_samples = 0;
+    }
+
+    /// Run the tests on the next sample. Returns false if one fails.
+    fn sample(&mut self, sample: u8) -> bool {
+        if self.repetitions > 0 && sample == self.last {
+            self.repetitions += 1;
+            if self.repetitions >= self.rct_cutoff {
+                return false;
+            }
+        } else {
+            self.last = sample;
+            self.repetitions = 1;
+        }
+
+        if self.apt_samples == 0 {
+            self.apt_reference = sample;
+            self.apt_count = 1;
+        } else if sample == self.apt_reference {
+            self.apt_count += 1;
+            if self.apt_count >= self.apt_cutoff {
+                return false;
+            }
+        }
+        self.apt_samples = (self.apt_samples + 1) % APT_WINDOW;
+        true
+    }
+}
+
+/// HMAC_DRBG working state with SHA-256 (SP 800-90A section 10.1.2).
+struct DrbgState {
+    k: [u8; 32],
+    v: [u8; 32],
+    reseed_counter: usize,
+}
+
+impl DrbgState {
+    fn new(seed: &[u8]) -> DrbgState {
+        let mut state = DrbgState {
+            k: [0; 32],
+            v: [1; 32],
+            reseed_counter: 1,
+        };
+        state.update(seed);
+        state
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.k = hmac_sha256(&self.k, &[&self.v, &[0], data]);
+        self.v = hmac_sha256(&self.k, &[&self.v]);
+        if !data.is_empty() {
+            self.k = hmac_sha256(&self.k, &[&self.v, &[1], data]);
+            self.v = hmac_sha256(&self.k, &[&self.v]);
+        }
+    }
+
+    fn reseed(&mut self, seed: &[u8]) {
+        self.update(seed);
+        self.reseed_counter = 1;
+    }
+
+    fn next_block(&mut self) -> [u8; 32] {
+        self.v = hmac_sha256(&self.k, &[&self.v]);
+        self.v
+    }
+
+    /// End a generate request, so that its output cannot be recomputed from
+    /// the new state.
+    fn finish_request(&mut self) {
+        self.update(&[]);
+        self.reseed_counter += 1;
+    }
+}
+
+/// Output of one generate request, computed as it is consumed.
+struct DrbgIter<'a> {
+    state: &'a mut DrbgState,
+    block: [u8; 32],
+    offset: usize,
+    remaining: usize,
+}
+
+impl Iterator for DrbgIter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.offset == self.block.len() {
+            self.block = self.state.next_block();
+            self.offset = 0;
+        }
+        let word = &self.block[self.offset..self.offset + 4];
+        self.offset += 4;
+        self.remaining -= 1;
+        Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+    }
+}
+
+pub struct HmacDrbg<'a> {
+    entropy: &'a dyn Entropy32<'a>,
+    client: OptionalCell<&'a dyn rng::Client>,
+    /// Min-entropy of the source in bits per byte
+    min_entropy: usize,
+    health: MapCell<HealthTests>,
+    /// Empty until seeded, and after a health test failure
+    state: MapCell<DrbgState>,
+    /// Entropy collected for the next seed
+    seed: MapCell<[u8; MAX_SEED_LEN]>,
+    seed_len: Cell<usize>,
+    /// Start-up samples still to test
+    startup_samples: Cell<usize>,
+    requested: Cell<bool>,
+}
+
+impl<'a> HmacDrbg<'a> {
+    /// `min_entropy` is the min-entropy claimed for the source, in bits per
+    /// byte from 1 to 8.
+    pub fn new(entropy: &'a dyn Entropy32<'a>, min_entropy: usize) -> HmacDrbg<'a> {
+        let min_entropy = min_entropy.max(1).min(8);
+        HmacDrbg {
+            entropy,
+            client: OptionalCell::empty(),
+            min_entropy,
+            health: MapCell::new(HealthTests::new(min_entropy)),
+            state: MapCell::empty(),
+            seed: MapCell::new([0; MAX_SEED_LEN]),
+            seed_len: Cell::new(0),
+            startup_samples: Cell::new(STARTUP_SAMPLES),
+            requested: Cell::new(false),
+        }
+    }
+
+    /// Bytes of entropy needed before the next request, or 0 if the state
+    /// can serve it.
+    fn seed_needed(&self) -> usize {
+        let bits = match self.state.map(|state| state.reseed_counter) {
+            None => SEED_BITS,
+            Some(counter) if counter > RESEED_INTERVAL => RESEED_BITS,
+            Some(_) => return 0,
+        };
+        (bits + self.min_entropy - 1) / self.min_entropy
+    }
+
+    /// Test the next word of entropy and keep it for the seed once the
+    /// start-up test has passed. Returns false if a health test fails.
+    fn collect(&self, word: u32, needed: usize) -> bool {
+        for &byte in word.to_le_bytes().iter() {
+            if !self.health.map_or(false, |health| health.sample(byte)) {
+                return false;
+            }
+            if self.startup_samples.get() > 0 {
+                self.startup_samples.set(self.startup_samples.get() - 1);
+            } else if self.seed_len.get() < needed {
+                let index = self.seed_len.get();
+                self.seed.map(|seed| seed[index] = byte);
+                self.seed_len.set(index + 1);
+            }
+        }
+        true
+    }
+
+    /// Seed or reseed the state from the collected entropy.
+    fn seed(&self) {
+        let len = self.seed_len.get();
+        self.seed.map(|seed| {
+            if self.state.is_some() {
+                self.state.map(|state| state.reseed(&seed[..len]));
+            } else {
+                self.state.put(DrbgState::new(&seed[..len]));
+            }
+            for byte in seed.iter_mut() {
+                *byte = 0;
+            }
+        });
+        self.seed_len.set(0);
+    }
+
+    /// Destroy the state after a health test failure and fail the request.
+    fn health_failure(&self) -> entropy::Continue {
+        self.state.take();
+        self.seed.map(|seed| {
+            for byte in seed.iter_mut() {
+                *byte = 0;
+            }
+        });
+        self.seed_len.set(0);
+        self.health.map(|health| health.reset());
+        self.startup_samples.set(STARTUP_SAMPLES);
+        self.fail(ReturnCode::FAIL);
+        entropy::Continue::Done
+    }
+
+    fn fail(&self, error: ReturnCode) {
+        self.requested.set(false);
+        self.client
+            .map(|client| client.randomness_available(&mut core::iter::empty(), error));
+    }
+}
+
+impl<'a> Rng<'a> for HmacDrbg<'a> {
+    fn get(&self) -> ReturnCode {
+        let rc = self.entropy.get();
+        self.requested.set(rc == ReturnCode::SUCCESS);
+        rc
+    }
+
+    fn cancel(&self) -> ReturnCode {
+        // The entropy callback is ignored if it still comes
+        self.requested.set(false);
+        self.entropy.cancel();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.entropy.set_client(self);
+        self.client.set(client);
+    }
+}
+
+impl entropy::Client32 for HmacDrbg<'_> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> entropy::Continue {
+        if !self.requested.get() {
+            return entropy::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.fail(error);
+            return entropy::Continue::Done;
+        }
+
+        let needed = self.seed_needed();
+        if needed > 0 {
+            while self.seed_len.get() < needed {
+                match entropy.next() {
+                    Some(word) => {
+                        if !self.collect(word, needed) {
+                            return self.health_failure();
+                        }
+                    }
+                    None => return entropy::Continue::More,
+                }
+            }
+            self.seed();
+        }
+
+        let result = self.client.map(|client| {
+            self.state.map(|state| {
+                let result = client.randomness_available(
+                    &mut DrbgIter {
+                        state,
+                        block: [0; 32],
+                        offset: 32,
+                        remaining: WORDS_PER_REQUEST,
+                    },
+                    ReturnCode::SUCCESS,
+                );
+                state.finish_request();
+                result
+            })
+        });
+        match result {
+            Some(Some(rng::Continue::More)) => entropy::Continue::More,
+            _ => {
+                self.requested.set(false);
+                entropy::Continue::Done
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generate(state: &mut DrbgState, len: usize) -> [u8; 64] {
+        let mut output = [0; 64];
+        let words = DrbgIter {
+            state,
+            block: [0; 32],
+            offset: 32,
+            remaining: len / 4,
+        };
+        for (chunk, word) in output.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        state.finish_request();
+        output
+    }
+
+    #[test]
+    fn hmac_drbg() {
+        let mut seed = [0; 96];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut state = DrbgState::new(&seed);
+        assert_eq!(
+            generate(&mut state, 32)[..32],
+            [
+                0x71, 0xfb, 0xc0, 0x4a, 0x97, 0x31, 0x08, 0x40, 0x3e, 0x9b, 0x74, 0x9c, 0x12, 0x46,
+                0x08, 0x3e, 0x61, 0x0a, 0xb1, 0x14, 0xc9, 0xed, 0x24, 0x46, 0x49, 0x64, 0xcd, 0xb1,
+                0x98, 0xad, 0x28, 0x1b
+            ]
+        );
+        assert_eq!(
+            generate(&mut state, 40)[..40],
+            [
+                0xe8, 0xf2, 0x2e, 0xdc, 0x6b, 0xef, 0x52, 0x1b, 0xb1, 0x9a, 0xad, 0x4a, 0x32, 0xdd,
+                0xa2, 0xa9, 0x88, 0x25, 0x51, 0x9f, 0xde, 0xa3, 0x71, 0xca, 0x8c, 0x80, 0xec, 0x83,
+                0x51, 0xfe, 0x4c, 0x1b, 0x2a, 0xb4, 0xb8, 0x32, 0xec, 0xdf, 0xa8, 0x03
+            ]
+        );
+        assert_eq!(state.reseed_counter, 3);
+
+        for (i, byte) in seed[..64].iter_mut().enumerate() {
+            *byte = 100 + i as u8;
+        }
+        state.reseed(&seed[..64]);
+        assert_eq!(state.reseed_counter, 1);
+        assert_eq!(
+            generate(&mut state, 32)[..32],
+            [
+                0xf7, 0x45, 0xe7, 0x29, 0xc8, 0x03, 0x48, 0xcb, 0x29, 0x14, 0x4f, 0x81, 0x92, 0x0c,
+                0xe0, 0x54, 0xc2, 0x41, 0x2b, 0x91, 0x10, 0x40, 0x0b, 0x44, 0xbd, 0x54, 0x5d, 0xed,
+                0xc5, 0x06, 0x5a, 0x36
+            ]
+        );
+    }
+
+    #[test]
+    fn repetition_count_test() {
+        let mut health = HealthTests::new(8);
+        // The cutoff for 8 bits per byte is 4 identical samples in a row
+        assert!(health.sample(7));
+        assert!(health.sample(7));
+        assert!(health.sample(7));
+        assert!(!health.sample(7));
+
+        let mut health = HealthTests::new(8);
+        for i in 0..4096 {
+            assert!(health.sample((i * 7) as u8));
+        }
+    }
+
+    #[test]
+    fn adaptive_proportion_test() {
+        // 13 occurrences of the first sample of a window fail at 8 bits per
+        // byte, even without repetitions
+        let mut health = HealthTests::new(8);
+        for i in 0..24 {
+            let sample = if i % 2 == 0 { 0 } else { i as u8 };
+            assert!(health.sample(sample));
+        }
+        assert!(!health.sample(0));
+
+        // The same samples pass at 4 bits per byte
+        let mut health = HealthTests::new(4);
+        for i in 0..25 {
+            let sample = if i % 2 == 0 { 0 } else { i as u8 };
+            assert!(health.sample(sample));
+        }
+    }
+}
@@ -26,17 +26,25 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    UsbHid                = 0x20007,
+    Can                   = 0x20008,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Ping                  = 0x30003,
+    Ethernet              = 0x30004,
+    Dns                   = 0x30005,
+    Coap                  = 0x30006,
+    BleGatt               = 0x30007,
 
     // Cryptography
     Rng                   = 0x40001,
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
     CtapHid               = 0x40004,
+    PublicKeyCrypto       = 0x40005,
 
     // Storage
     AppFlash              = 0x50000,
@@ -73,5 +81,7 @@ pub enum NUM {
     Screen                = 0x90001,
     Touch                 = 0x90002,
     TextScreen            = 0x90003,
+    DateTime              = 0x90004,
+    Pcm                   = 0x90005,
 }
 }
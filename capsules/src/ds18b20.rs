@@ -0,0 +1,565 @@
+//! Driver for the Maxim DS18B20 1-Wire digital thermometer.
+//!
+//! <https://datasheets.maximintegrated.com/en/ds/DS18B20.pdf>
+//!
+//! > The DS18B20 digital thermometer provides 9-bit to 12-bit Celsius
+//! > temperature measurements and has an alarm function with nonvolatile
+//! > user-programmable upper and lower trigger points. The DS18B20
+//! > communicates over a 1-Wire bus that by definition requires only one data
+//! > line (and ground) for communication with a central microprocessor.
+//!
+//! A reading starts a conversion, waits for the 750 ms that a 12-bit
+//! conversion takes at most, and then reads the scratchpad and checks its
+//! CRC. The sensor is addressed by its ROM if one is set with `set_rom()`,
+//! and with Skip ROM otherwise, which only works if it is the only device on
+//! the bus. The driver does not rely on being able to poll for the end of the
+//! conversion, so sensors in parasite power mode work as long as the bus is
+//! strongly pulled up.
+//!
+//! Interrupts can corrupt 1-Wire time slots, so a reset that no device
+//! answers or a scratchpad with a bad CRC is retried, up to `MAX_RETRIES`
+//! times per reading. A reading that still fails, or that fails with a bus
+//! error, completes with `usize::MAX` as the temperature, so that the client
+//! is not left waiting.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let ds18b20_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52840::timer::TimerAlarm>,
+//!     VirtualMuxAlarm::new(timer_mux_alarm));
+//! let ds18b20 = static_init!(
+//!     capsules::ds18b20::Ds18b20<'static, VirtualMuxAlarm<'static, nrf52840::timer::TimerAlarm>>,
+//!     capsules::ds18b20::Ds18b20::new(one_wire, ds18b20_alarm, &mut capsules::ds18b20::BUFFER));
+//! one_wire.set_client(ds18b20);
+//! ds18b20_alarm.set_alarm_client(ds18b20);
+//! ```
+
+use crate::one_wire::{self, OneWire, OneWireClient, ROM_LEN};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::sensors;
+use kernel::hil::time;
+use kernel::ReturnCode;
+
+// Buffer for the commands and the scratchpad: a ROM command, a ROM and a
+// function command.
+pub static mut BUFFER: [u8; 10] = [0; 10];
+
+/// DS18B20 family code, the first byte of its ROM.
+pub const FAMILY_CODE: u8 = 0x28;
+
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+/// The scratchpad: temperature (2 bytes), alarm thresholds (2 bytes),
+/// configuration, 3 reserved bytes and the CRC.
+const SCRATCHPAD_LEN: usize = 9;
+
+/// Conversion time at 12-bit resolution, the power-on default.
+const CONVERSION_MS: u32 = 750;
+
+/// How many times a reading retries a reset without a presence pulse or a
+/// scratchpad read with a bad CRC.
+pub const MAX_RETRIES: u8 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    ConvertReset,
+    ConvertCommand,
+    Converting,
+    ReadReset,
+    ReadCommand,
+    ReadScratchpad,
+}
+
+/// Convert a temperature register value, in sixteenths of degrees, to
+/// hundredths of degrees.
+fn temperature_from_raw(raw: i16) -> i32 {
+    raw as i32 * 100 / 16
+}
+
+pub struct Ds18b20<'a, A: time::Alarm<'a>> {
+    bus: &'a dyn OneWire<'a>,
+    alarm: &'a A,
+    temperature_client: OptionalCell<&'a dyn sensors::TemperatureClient>,
+    rom: OptionalCell<[u8; ROM_LEN]>,
+    state: Cell<State>,
+    /// Retries left for the current reading.
+    retries: Cell<u8>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: time::Alarm<'a>> Ds18b20<'a, A> {
+    pub fn new(
+        bus: &'a dyn OneWire<'a>,
+        alarm: &'a A,
+        buffer: &'static mut [u8],
+    ) -> Ds18b20<'a, A> {
+        Ds18b20 {
+            bus,
+            alarm,
+            temperature_client: OptionalCell::empty(),
+            rom: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            retries: Cell::new(0),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// Address the sensor with `rom`, as found with a bus search, instead of
+    /// with Skip ROM.
+    pub fn set_rom(&self, rom: [u8; ROM_LEN]) {
+        self.rom.set(rom);
+    }
+
+    fn start_reset(&self, state: State) {
+        if self.bus.reset() == ReturnCode::SUCCESS {
+            self.state.set(state);
+        } else {
+            self.fail();
+        }
+    }
+
+    /// Start the reset of the current phase again, or fail the reading if
+    /// it has no retries left.
+    fn retry(&self, state: State) {
+        let retries = self.retries.get();
+        if retries > 0 {
+            self.retries.set(retries - 1);
+            self.start_reset(state);
+        } else {
+            self.fail();
+        }
+    }
+
+    /// End the reading without a temperature.
+    fn fail(&self) {
+        self.state.set(State::Idle);
+        self.temperature_client
+            .map(|client| client.callback(usize::MAX));
+    }
+
+    /// Select the sensor and send `command`.
+    fn send_command(&self, command: u8, state: State) {
+        self.buffer.take().map(|buffer| {
+            let len = match self.rom.map(|rom| *rom) {
+                Some(rom) => {
+                    buffer[0] = one_wire::MATCH_ROM;
+                    buffer[1..1 + ROM_LEN].copy_from_slice(&rom);
+                    buffer[1 + ROM_LEN] = command;
+                    2 + ROM_LEN
+                }
+                None => {
+                    buffer[0] = one_wire::SKIP_ROM;
+                    buffer[1] = command;
+                    2
+                }
+            };
+            match self.bus.write(buffer, len) {
+                Ok(()) => self.state.set(state),
+                Err((_, buffer)) => {
+                    self.buffer.replace(buffer);
+                    self.fail();
+                }
+            }
+        });
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> OneWireClient for Ds18b20<'a, A> {
+    fn reset_done(&self, present: bool) {
+        match (self.state.get(), present) {
+            (State::ConvertReset, true) => self.send_command(CONVERT_T, State::ConvertCommand),
+            (State::ReadReset, true) => self.send_command(READ_SCRATCHPAD, State::ReadCommand),
+            (State::ConvertReset, false) | (State::ReadReset, false) => {
+                self.retry(self.state.get())
+            }
+            _ => {}
+        }
+    }
+
+    fn bit_done(&self, _bit: bool) {}
+
+    fn write_done(&self, rc: ReturnCode, buffer: &'static mut [u8]) {
+        if rc != ReturnCode::SUCCESS {
+            self.buffer.replace(buffer);
+            self.fail();
+            return;
+        }
+        match self.state.get() {
+            State::ConvertCommand => {
+                self.buffer.replace(buffer);
+                self.state.set(State::Converting);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(CONVERSION_MS));
+            }
+            State::ReadCommand => match self.bus.read(buffer, SCRATCHPAD_LEN) {
+                Ok(()) => self.state.set(State::ReadScratchpad),
+                Err((_, buffer)) => {
+                    self.buffer.replace(buffer);
+                    self.fail();
+                }
+            },
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn read_done(&self, rc: ReturnCode, buffer: &'static mut [u8]) {
+        let valid = one_wire::crc8(&buffer[..SCRATCHPAD_LEN]) == 0;
+        let raw = i16::from_le_bytes([buffer[0], buffer[1]]);
+        self.buffer.replace(buffer);
+        if self.state.get() != State::ReadScratchpad {
+            return;
+        }
+
+        if rc != ReturnCode::SUCCESS {
+            self.fail();
+        } else if !valid {
+            // The conversion result stays in the scratchpad, so only the
+            // read is repeated.
+            self.retry(State::ReadReset);
+        } else {
+            self.state.set(State::Idle);
+            let temperature = temperature_from_raw(raw);
+            self.temperature_client
+                .map(|client| client.callback(temperature as usize));
+        }
+    }
+
+    fn search_done(&self, _result: Result<Option<[u8; ROM_LEN]>, ReturnCode>) {}
+}
+
+impl<'a, A: time::Alarm<'a>> time::AlarmClient for Ds18b20<'a, A> {
+    fn alarm(&self) {
+        if self.state.get() == State::Converting {
+            self.start_reset(State::ReadReset);
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> sensors::TemperatureDriver<'a> for Ds18b20<'a, A> {
+    fn set_client(&self, client: &'a dyn sensors::TemperatureClient) {
+        self.temperature_client.set(client);
+    }
+
+    fn read_temperature(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let rc = self.bus.reset();
+        if rc == ReturnCode::SUCCESS {
+            self.retries.set(MAX_RETRIES);
+            self.state.set(State::ConvertReset);
+        }
+        rc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{temperature_from_raw, Ds18b20, CONVERT_T, MAX_RETRIES, READ_SCRATCHPAD};
+    use crate::one_wire::{OneWire, OneWireClient, SKIP_ROM};
+    use core::cell::Cell;
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+    use kernel::hil::time::{Alarm, AlarmClient, Freq1MHz, Ticks32, Time};
+    use kernel::ReturnCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// DS18B20 scratchpad at 85 degrees, the power-on value.
+    const SCRATCHPAD: [u8; 9] = [0x50, 0x05, 0x4b, 0x46, 0x7f, 0xff, 0x0c, 0x10, 0x1c];
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Op {
+        Reset,
+        Write(Vec<u8>),
+        Read(usize),
+    }
+
+    /// A bus that records the operations started on it. Tests complete them
+    /// by calling the driver's `OneWireClient` methods.
+    struct TestBus {
+        ops: TakeCell<'static, Vec<Op>>,
+        reset_result: Cell<ReturnCode>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl TestBus {
+        fn last_op(&self) -> Option<Op> {
+            self.ops.map_or(None, |ops| ops.last().cloned())
+        }
+
+        fn op_count(&self) -> usize {
+            self.ops.map_or(0, |ops| ops.len())
+        }
+    }
+
+    impl<'a> OneWire<'a> for TestBus {
+        fn set_client(&self, _client: &'a dyn OneWireClient) {}
+
+        fn reset(&self) -> ReturnCode {
+            let rc = self.reset_result.get();
+            if rc == ReturnCode::SUCCESS {
+                self.ops.map(|ops| ops.push(Op::Reset));
+            }
+            rc
+        }
+
+        fn write_bit(&self, _bit: bool) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn read_bit(&self) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+
+        fn write(
+            &self,
+            buffer: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.ops
+                .map(|ops| ops.push(Op::Write(buffer[..len].to_vec())));
+            self.buffer.replace(buffer);
+            Ok(())
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            len: usize,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.ops.map(|ops| ops.push(Op::Read(len)));
+            self.buffer.replace(buffer);
+            Ok(())
+        }
+
+        fn search_reset(&self) {}
+
+        fn search_next(&self) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+    }
+
+    /// An alarm that only records whether it is armed. Tests fire it by
+    /// calling the driver's `AlarmClient` method.
+    struct TestAlarm {
+        armed: Cell<bool>,
+    }
+
+    impl Time for TestAlarm {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            0.into()
+        }
+    }
+
+    impl<'a> Alarm<'a> for TestAlarm {
+        fn set_alarm_client(&'a self, _client: &'a dyn AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {
+            self.armed.set(true);
+        }
+
+        fn get_alarm(&self) -> Ticks32 {
+            0.into()
+        }
+
+        fn disarm(&self) -> ReturnCode {
+            self.armed.set(false);
+            ReturnCode::SUCCESS
+        }
+
+        fn is_armed(&self) -> bool {
+            self.armed.get()
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            1.into()
+        }
+    }
+
+    struct TestClient {
+        temperature: OptionalCell<usize>,
+    }
+
+    impl TemperatureClient for TestClient {
+        fn callback(&self, value: usize) {
+            self.temperature.set(value);
+        }
+    }
+
+    type Sensor = Ds18b20<'static, TestAlarm>;
+
+    fn new_sensor() -> (
+        &'static Sensor,
+        &'static TestBus,
+        &'static TestAlarm,
+        &'static TestClient,
+    ) {
+        let bus: &'static TestBus = Box::leak(Box::new(TestBus {
+            ops: TakeCell::new(Box::leak(Box::new(Vec::new()))),
+            reset_result: Cell::new(ReturnCode::SUCCESS),
+            buffer: TakeCell::empty(),
+        }));
+        let alarm: &'static TestAlarm = Box::leak(Box::new(TestAlarm {
+            armed: Cell::new(false),
+        }));
+        let client: &'static TestClient = Box::leak(Box::new(TestClient {
+            temperature: OptionalCell::empty(),
+        }));
+        let sensor: &'static Sensor = Box::leak(Box::new(Ds18b20::new(
+            bus,
+            alarm,
+            Box::leak(Box::new([0; 10])),
+        )));
+        sensor.set_client(client);
+        (sensor, bus, alarm, client)
+    }
+
+    fn complete_write(sensor: &Sensor, bus: &TestBus) {
+        let buffer = bus.buffer.take().unwrap();
+        sensor.write_done(ReturnCode::SUCCESS, buffer);
+    }
+
+    fn complete_read(sensor: &Sensor, bus: &TestBus, scratchpad: &[u8; 9]) {
+        let buffer = bus.buffer.take().unwrap();
+        buffer[..9].copy_from_slice(scratchpad);
+        sensor.read_done(ReturnCode::SUCCESS, buffer);
+    }
+
+    /// Run a reading up to the scratchpad read.
+    fn convert(sensor: &Sensor, bus: &TestBus, alarm: &TestAlarm) {
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+        assert_eq!(bus.last_op(), Some(Op::Reset));
+        sensor.reset_done(true);
+        assert_eq!(
+            bus.last_op(),
+            Some(Op::Write(std::vec![SKIP_ROM, CONVERT_T]))
+        );
+        complete_write(sensor, bus);
+        assert!(alarm.is_armed());
+        alarm.disarm();
+        sensor.alarm();
+        read_command(sensor, bus);
+    }
+
+    /// Answer the reset before a scratchpad read and send the command.
+    fn read_command(sensor: &Sensor, bus: &TestBus) {
+        assert_eq!(bus.last_op(), Some(Op::Reset));
+        sensor.reset_done(true);
+        assert_eq!(
+            bus.last_op(),
+            Some(Op::Write(std::vec![SKIP_ROM, READ_SCRATCHPAD]))
+        );
+        complete_write(sensor, bus);
+        assert_eq!(bus.last_op(), Some(Op::Read(9)));
+    }
+
+    #[test]
+    fn reading() {
+        let (sensor, bus, alarm, client) = new_sensor();
+        convert(sensor, bus, alarm);
+        complete_read(sensor, bus, &SCRATCHPAD);
+        assert_eq!(client.temperature.take(), Some(8500));
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn bad_crc_is_retried() {
+        let (sensor, bus, alarm, client) = new_sensor();
+        let mut corrupted = SCRATCHPAD;
+        corrupted[0] ^= 0x01;
+        convert(sensor, bus, alarm);
+        complete_read(sensor, bus, &corrupted);
+        assert!(client.temperature.is_none());
+        // Only the read is repeated, not the conversion
+        read_command(sensor, bus);
+        complete_read(sensor, bus, &SCRATCHPAD);
+        assert_eq!(client.temperature.take(), Some(8500));
+    }
+
+    #[test]
+    fn failed_reading_completes() {
+        let (sensor, bus, alarm, client) = new_sensor();
+        let mut corrupted = SCRATCHPAD;
+        corrupted[8] ^= 0x80;
+        convert(sensor, bus, alarm);
+        for _ in 0..MAX_RETRIES {
+            complete_read(sensor, bus, &corrupted);
+            assert!(client.temperature.is_none());
+            read_command(sensor, bus);
+        }
+        complete_read(sensor, bus, &corrupted);
+        assert_eq!(client.temperature.take(), Some(usize::MAX));
+
+        // The next reading starts with all its retries
+        convert(sensor, bus, alarm);
+        complete_read(sensor, bus, &corrupted);
+        read_command(sensor, bus);
+        complete_read(sensor, bus, &SCRATCHPAD);
+        assert_eq!(client.temperature.take(), Some(8500));
+    }
+
+    #[test]
+    fn missing_sensor() {
+        let (sensor, bus, _, client) = new_sensor();
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+        for _ in 0..MAX_RETRIES {
+            sensor.reset_done(false);
+            assert_eq!(bus.last_op(), Some(Op::Reset));
+            assert!(client.temperature.is_none());
+        }
+        let ops = bus.op_count();
+        sensor.reset_done(false);
+        assert_eq!(bus.op_count(), ops);
+        assert_eq!(client.temperature.take(), Some(usize::MAX));
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn bus_errors_complete() {
+        // A failed write
+        let (sensor, bus, _, client) = new_sensor();
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+        sensor.reset_done(true);
+        sensor.write_done(ReturnCode::FAIL, bus.buffer.take().unwrap());
+        assert_eq!(client.temperature.take(), Some(usize::MAX));
+
+        // A reset the bus refuses when the conversion ends
+        let (sensor, bus, alarm, client) = new_sensor();
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+        sensor.reset_done(true);
+        complete_write(sensor, bus);
+        bus.reset_result.set(ReturnCode::EBUSY);
+        assert!(alarm.is_armed());
+        sensor.alarm();
+        assert_eq!(client.temperature.take(), Some(usize::MAX));
+        assert_eq!(sensor.read_temperature(), ReturnCode::EBUSY);
+        bus.reset_result.set(ReturnCode::SUCCESS);
+        assert_eq!(sensor.read_temperature(), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn temperature_conversion() {
+        // Examples from the datasheet's temperature/data relationship table.
+        assert_eq!(temperature_from_raw(0x07d0), 12500);
+        assert_eq!(temperature_from_raw(0x0191), 2506);
+        assert_eq!(temperature_from_raw(0x0000), 0);
+        assert_eq!(temperature_from_raw(0xff5e_u16 as i16), -1012);
+        assert_eq!(temperature_from_raw(0xfc90_u16 as i16), -5500);
+    }
+}
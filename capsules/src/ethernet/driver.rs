@@ -0,0 +1,277 @@
+//! Raw Ethernet frame userspace interface, intended for debugging.
+//!
+//! Processes can transmit arbitrary frames, which must include the Ethernet
+//! header, and receive a copy of every frame seen by the adapter. Frames
+//! are copied into a single kernel buffer for transmission, so transmissions
+//! from different processes are queued and sent one at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let eth_driver_user = static_init!(
+//!     capsules::ethernet::virtual_ethernet::EthernetUser<'static>,
+//!     capsules::ethernet::virtual_ethernet::EthernetUser::new(eth_mux)
+//! );
+//! eth_mux.add_user(eth_driver_user);
+//! let eth_driver = static_init!(
+//!     capsules::ethernet::EthernetDriver<'static>,
+//!     capsules::ethernet::EthernetDriver::new(
+//!         eth_driver_user,
+//!         &mut ETH_DRIVER_BUF,
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! eth_driver_user.set_transmit_client(eth_driver);
+//! eth_driver_user.set_receive_client(eth_driver);
+//! ```
+
+use crate::ethernet::virtual_ethernet::{EthernetRxClient, EthernetTxClient, EthernetUser};
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Ethernet as usize;
+
+#[derive(Default)]
+pub struct App {
+    rx_callback: Option<Callback>,
+    tx_callback: Option<Callback>,
+    app_read: Option<AppSlice<Shared, u8>>,
+    app_write: Option<AppSlice<Shared, u8>>,
+    pending_tx: Option<usize>,
+}
+
+pub struct EthernetDriver<'a> {
+    eth: &'a EthernetUser<'a>,
+    kernel_tx: TakeCell<'static, [u8]>,
+    max_frame_len: usize,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+}
+
+impl<'a> EthernetDriver<'a> {
+    pub fn new(
+        eth: &'a EthernetUser<'a>,
+        kernel_tx: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> EthernetDriver<'a> {
+        EthernetDriver {
+            eth: eth,
+            max_frame_len: kernel_tx.len(),
+            kernel_tx: TakeCell::new(kernel_tx),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Returns the first app with a pending transmission, if no
+    /// transmission is in progress.
+    fn get_next_tx_if_idle(&self) -> Option<AppId> {
+        if self.current_app.is_some() {
+            return None;
+        }
+        let mut pending_app = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if app.pending_tx.is_some() {
+                    pending_app = Some(app.appid());
+                }
+            });
+            if pending_app.is_some() {
+                break;
+            }
+        }
+        pending_app
+    }
+
+    /// Copies `appid`'s pending frame into the kernel buffer and passes it
+    /// to the adapter.
+    fn perform_tx_sync(&self, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let len = match app.pending_tx.take() {
+                    Some(len) => len,
+                    None => return ReturnCode::SUCCESS,
+                };
+                let frame = match app.app_write.as_ref() {
+                    Some(frame) if frame.len() >= len => frame,
+                    _ => return ReturnCode::EINVAL,
+                };
+                self.kernel_tx.take().map_or(ReturnCode::EBUSY, |kbuf| {
+                    if len > kbuf.len() {
+                        self.kernel_tx.replace(kbuf);
+                        return ReturnCode::ESIZE;
+                    }
+                    kbuf[..len].copy_from_slice(&frame.as_ref()[..len]);
+                    match self.eth.transmit(kbuf, len) {
+                        Ok(()) => {
+                            self.current_app.set(appid);
+                            ReturnCode::SUCCESS
+                        }
+                        Err((result, kbuf)) => {
+                            self.kernel_tx.replace(kbuf);
+                            result
+                        }
+                    }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn perform_tx_async(&self, appid: AppId) {
+        let result = self.perform_tx_sync(appid);
+        if result != ReturnCode::SUCCESS {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            });
+        }
+    }
+
+    fn do_next_tx_queued(&self) {
+        self.get_next_tx_if_idle()
+            .map(|appid| self.perform_tx_async(appid));
+    }
+
+    /// Starts the next queued transmission. Errors for `new_appid`'s
+    /// transmission are returned directly, others via callbacks.
+    fn do_next_tx_immediate(&self, new_appid: AppId) -> ReturnCode {
+        self.get_next_tx_if_idle()
+            .map_or(ReturnCode::SUCCESS, |appid| {
+                if appid == new_appid {
+                    self.perform_tx_sync(appid)
+                } else {
+                    self.perform_tx_async(appid);
+                    ReturnCode::SUCCESS
+                }
+            })
+    }
+}
+
+impl<'a> Driver for EthernetDriver<'a> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Read buffer. Received frames are copied here, truncated to the
+    ///        length of the buffer.
+    /// - `1`: Write buffer. Holds the frame to transmit, including the
+    ///        Ethernet header.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.app_read = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.app_write = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Frame received. Called with the number of bytes copied into
+    ///        the read buffer and the length of the frame.
+    /// - `1`: Transmission completed. Called with the result.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.rx_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.tx_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Ethernet control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Transmit the first `arg1` bytes of the write buffer. Returns
+    ///        EBUSY if this process already has a frame queued.
+    /// - `2`: Return the maximum length of a frame that can be transmitted.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                let result = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.pending_tx.is_some() {
+                            return ReturnCode::EBUSY;
+                        }
+                        match app.app_write.as_ref() {
+                            Some(frame) if frame.len() >= arg1 && arg1 > 0 => {
+                                app.pending_tx = Some(arg1);
+                                ReturnCode::SUCCESS
+                            }
+                            _ => ReturnCode::EINVAL,
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+                self.do_next_tx_immediate(appid)
+            }
+            2 => ReturnCode::SuccessWithValue {
+                value: self.max_frame_len,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+impl<'a> EthernetTxClient for EthernetDriver<'a> {
+    fn send_done(&self, result: ReturnCode, frame: &'static mut [u8]) {
+        self.kernel_tx.replace(frame);
+        self.current_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            });
+        });
+        self.do_next_tx_queued();
+    }
+}
+
+impl<'a> EthernetRxClient for EthernetDriver<'a> {
+    fn receive(&self, frame: &[u8]) {
+        self.apps.each(|app| {
+            if let Some(mut cb) = app.rx_callback {
+                if let Some(buf) = app.app_read.as_mut() {
+                    let len = cmp::min(frame.len(), buf.len());
+                    buf.as_mut()[..len].copy_from_slice(&frame[..len]);
+                    cb.schedule(len, frame.len(), 0);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,8 @@
+//! Support for Ethernet.
+
+pub mod virtual_ethernet;
+
+mod driver;
+
+pub use self::driver::EthernetDriver;
+pub use self::driver::DRIVER_NUM;
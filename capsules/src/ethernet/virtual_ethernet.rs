@@ -0,0 +1,201 @@
+//! Virtualizes an Ethernet adapter so that multiple users (for example the
+//! IPv4 stack and the raw frame driver) can share it.
+//!
+//! Every received frame is passed to every user, which is responsible for
+//! ignoring frames it is not interested in. The frame is only borrowed for
+//! the duration of the `receive` callback, after which the receive buffer is
+//! returned to the adapter. Each user can have one outstanding transmission
+//! at a time; transmissions from different users are queued and sent in the
+//! order of the user list.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let eth_mux = static_init!(
+//!     capsules::ethernet::virtual_ethernet::MuxEthernet<'static>,
+//!     capsules::ethernet::virtual_ethernet::MuxEthernet::new(ethmac0)
+//! );
+//! ethmac0.set_client(eth_mux);
+//!
+//! let ip_eth_user = static_init!(
+//!     capsules::ethernet::virtual_ethernet::EthernetUser<'static>,
+//!     capsules::ethernet::virtual_ethernet::EthernetUser::new(eth_mux)
+//! );
+//! eth_mux.add_user(ip_eth_user);
+//! ```
+
+use crate::net::ethernet::MIN_FRAME_LEN;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
+use kernel::ReturnCode;
+
+/// Implemented by users of an `EthernetUser` to learn when their frame has
+/// been sent.
+pub trait EthernetTxClient {
+    fn send_done(&self, result: ReturnCode, frame: &'static mut [u8]);
+}
+
+/// Implemented by users of an `EthernetUser` to receive frames.
+pub trait EthernetRxClient {
+    /// Called for every received frame, starting with the Ethernet header.
+    fn receive(&self, frame: &[u8]);
+}
+
+pub struct MuxEthernet<'a> {
+    adapter: &'a dyn EthernetAdapter<'a>,
+    users: List<'a, EthernetUser<'a>>,
+    inflight: OptionalCell<&'a EthernetUser<'a>>,
+}
+
+impl<'a> MuxEthernet<'a> {
+    pub const fn new(adapter: &'a dyn EthernetAdapter<'a>) -> MuxEthernet<'a> {
+        MuxEthernet {
+            adapter: adapter,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    pub fn add_user(&self, user: &'a EthernetUser<'a>) {
+        self.users.push_tail(user);
+    }
+
+    fn get_next_op_if_idle(&self) -> Option<(&'a EthernetUser<'a>, &'static mut [u8], usize)> {
+        if self.inflight.is_some() {
+            return None;
+        }
+        self.users
+            .iter()
+            .find(|user| user.pending.is_some())
+            .and_then(|user| user.pending.take().map(|(frame, len)| (user, frame, len)))
+    }
+
+    /// Passes a frame to the adapter, padding it to the minimum frame size
+    /// if the buffer allows.
+    fn transmit(
+        &self,
+        user: &'a EthernetUser<'a>,
+        frame: &'static mut [u8],
+        mut len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len < MIN_FRAME_LEN && frame.len() >= MIN_FRAME_LEN {
+            for b in frame[len..MIN_FRAME_LEN].iter_mut() {
+                *b = 0;
+            }
+            len = MIN_FRAME_LEN;
+        }
+        let result = self.adapter.transmit(frame, len);
+        if result.is_ok() {
+            self.inflight.set(user);
+        }
+        result
+    }
+
+    fn do_next_op_async(&self) {
+        self.get_next_op_if_idle().map(|(user, frame, len)| {
+            if let Err((result, frame)) = self.transmit(user, frame, len) {
+                user.send_done(result, frame);
+            }
+        });
+    }
+
+    /// Starts the next pending transmission. If it belongs to `new_user`,
+    /// the result is returned instead of being delivered in a callback.
+    fn do_next_op_sync(
+        &self,
+        new_user: &EthernetUser<'a>,
+    ) -> Option<Result<(), (ReturnCode, &'static mut [u8])>> {
+        self.get_next_op_if_idle().and_then(|(user, frame, len)| {
+            if user as *const _ == new_user as *const _ {
+                Some(self.transmit(user, frame, len))
+            } else {
+                if let Err((result, frame)) = self.transmit(user, frame, len) {
+                    user.send_done(result, frame);
+                }
+                None
+            }
+        })
+    }
+}
+
+impl<'a> EthernetAdapterClient for MuxEthernet<'a> {
+    fn tx_done(&self, rc: ReturnCode, packet_buffer: &'static mut [u8]) {
+        self.inflight.take().map(move |user| {
+            user.send_done(rc, packet_buffer);
+        });
+        self.do_next_op_async();
+    }
+
+    fn rx_packet(&self, packet: &'static mut [u8], len: usize) {
+        for user in self.users.iter() {
+            user.receive(&packet[..len]);
+        }
+        self.adapter.return_rx_buffer(packet);
+    }
+}
+
+pub struct EthernetUser<'a> {
+    mux: &'a MuxEthernet<'a>,
+    pending: MapCell<(&'static mut [u8], usize)>,
+    next: ListLink<'a, EthernetUser<'a>>,
+    tx_client: OptionalCell<&'a dyn EthernetTxClient>,
+    rx_client: OptionalCell<&'a dyn EthernetRxClient>,
+}
+
+impl<'a> ListNode<'a, EthernetUser<'a>> for EthernetUser<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, EthernetUser<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> EthernetUser<'a> {
+    pub fn new(mux: &'a MuxEthernet<'a>) -> EthernetUser<'a> {
+        EthernetUser {
+            mux: mux,
+            pending: MapCell::empty(),
+            next: ListLink::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_transmit_client(&self, client: &'a dyn EthernetTxClient) {
+        self.tx_client.set(client);
+    }
+
+    pub fn set_receive_client(&self, client: &'a dyn EthernetRxClient) {
+        self.rx_client.set(client);
+    }
+
+    /// Queues the first `len` bytes of `frame` for transmission. Returns
+    /// EBUSY if this user already has a frame queued or being sent.
+    pub fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len > frame.len() {
+            return Err((ReturnCode::EINVAL, frame));
+        }
+        let inflight = self
+            .mux
+            .inflight
+            .map_or(false, |user| *user as *const _ == self as *const _);
+        if inflight || self.pending.is_some() {
+            return Err((ReturnCode::EBUSY, frame));
+        }
+        self.pending.replace((frame, len));
+        self.mux.do_next_op_sync(self).unwrap_or(Ok(()))
+    }
+
+    fn send_done(&self, result: ReturnCode, frame: &'static mut [u8]) {
+        self.tx_client
+            .map(move |client| client.send_done(result, frame));
+    }
+
+    fn receive(&self, frame: &[u8]) {
+        self.rx_client.map(|client| client.receive(frame));
+    }
+}
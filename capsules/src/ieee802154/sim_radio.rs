@@ -0,0 +1,317 @@
+//! Simulated IEEE 802.15.4 radio.
+//!
+//! `SimRadio` implements `hil::radio::Radio` without any hardware, so that
+//! the 802.15.4 and 6LoWPAN stacks can be run on a host, for example in
+//! `cargo test`. Frames are handed to a `SimMedium`, which decides which
+//! other radios receive them and when; the medium delivers frames with
+//! `deliver()` and completes transmissions with `transmit_done()`.
+//!
+//! The medium also stands in for the passage of time: the radio never makes
+//! a callback from within a call into it. Configuration commits and power
+//! changes are completed when the medium calls `service()` after being
+//! asked to with `SimMedium::service_requested()`.
+//!
+//! Like most radio hardware, the simulated radio filters received frames by
+//! destination address and PAN ID. Frames to the broadcast address or PAN
+//! are always received.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let radio = static_init!(SimRadio<'static>, SimRadio::new(0));
+//! radio.set_medium(medium);
+//! radio.set_transmit_client(awake_mac);
+//! radio.set_receive_client(awake_mac, &mut RADIO_RX_BUF);
+//! radio.start();
+//! ```
+
+use crate::net::ieee802154::{Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::ReturnCode;
+
+const BROADCAST: u16 = 0xffff;
+
+/// The medium that simulated radios transmit on.
+pub trait SimMedium {
+    /// Called when radio `id` starts transmitting the PSDU `frame` (which
+    /// includes the 2 byte MFR). The medium must later complete the
+    /// transmission with `SimRadio::transmit_done()`.
+    fn transmit(&self, id: usize, frame: &[u8]);
+
+    /// Called when radio `id` has callbacks to make. The medium must later
+    /// call `SimRadio::service()`.
+    fn service_requested(&self, id: usize);
+}
+
+pub struct SimRadio<'a> {
+    id: usize,
+    medium: OptionalCell<&'a dyn SimMedium>,
+
+    addr: Cell<u16>,
+    addr_long: Cell<[u8; 8]>,
+    pan: Cell<u16>,
+    tx_power: Cell<i8>,
+    channel: Cell<u8>,
+    on: Cell<bool>,
+    config_pending: Cell<bool>,
+    power_pending: Cell<bool>,
+
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    config_client: OptionalCell<&'static dyn radio::ConfigClient>,
+    power_client: OptionalCell<&'static dyn radio::PowerClient>,
+
+    tx_buf: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+}
+
+impl<'a> SimRadio<'a> {
+    /// Creates a radio that identifies itself to its medium as `id`.
+    pub fn new(id: usize) -> SimRadio<'a> {
+        SimRadio {
+            id: id,
+            medium: OptionalCell::empty(),
+            addr: Cell::new(0),
+            addr_long: Cell::new([0; 8]),
+            pan: Cell::new(0),
+            tx_power: Cell::new(0),
+            channel: Cell::new(26),
+            on: Cell::new(false),
+            config_pending: Cell::new(false),
+            power_pending: Cell::new(false),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            config_client: OptionalCell::empty(),
+            power_client: OptionalCell::empty(),
+            tx_buf: TakeCell::empty(),
+            rx_buf: TakeCell::empty(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn set_medium(&self, medium: &'a dyn SimMedium) {
+        self.medium.set(medium);
+    }
+
+    fn request_service(&self) {
+        let id = self.id;
+        self.medium.map(|medium| medium.service_requested(id));
+    }
+
+    /// Makes the callbacks of configuration commits and power changes since
+    /// the last call.
+    pub fn service(&self) {
+        if self.config_pending.replace(false) {
+            self.config_client
+                .map(|client| client.config_done(ReturnCode::SUCCESS));
+        }
+        if self.power_pending.replace(false) {
+            let on = self.on.get();
+            self.power_client.map(|client| client.changed(on));
+        }
+    }
+
+    /// Completes the outstanding transmission.
+    pub fn transmit_done(&self, acked: bool, result: ReturnCode) {
+        self.tx_buf.take().map(|buf| {
+            self.tx_client
+                .map(move |client| client.send_done(buf, acked, result));
+        });
+    }
+
+    /// Whether the radio would accept a frame with this header.
+    fn address_match(&self, header: &Header) -> bool {
+        let pan_match = header
+            .dst_pan
+            .map_or(true, |pan| pan == BROADCAST || pan == self.pan.get());
+        let addr_match = match header.dst_addr {
+            Some(MacAddress::Short(addr)) => addr == BROADCAST || addr == self.addr.get(),
+            Some(MacAddress::Long(addr)) => addr == self.addr_long.get(),
+            None => false,
+        };
+        pan_match && addr_match
+    }
+
+    /// Receives the PSDU `frame` (including the MFR) from the medium.
+    /// Returns true if the frame was received and should be acknowledged:
+    /// it is addressed to this radio, asks for an acknowledgement and is not
+    /// a broadcast.
+    pub fn deliver(&self, frame: &[u8], crc_valid: bool) -> bool {
+        if !self.on.get() || frame.len() > radio::MAX_FRAME_SIZE {
+            return false;
+        }
+        let (accepted, ack) = match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => (
+                self.address_match(&header),
+                header.ack_requested && header.dst_addr != Some(MacAddress::Short(BROADCAST)),
+            ),
+            None => (false, false),
+        };
+        if !accepted {
+            return false;
+        }
+        // Without a receive buffer the frame is lost, as it is on hardware
+        // when the stack is slow to return the buffer
+        match self.rx_buf.take() {
+            Some(buf) if buf.len() >= radio::PSDU_OFFSET + frame.len() => {
+                buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame.len()].copy_from_slice(frame);
+                match self.rx_client.map(|client| *client) {
+                    Some(client) => {
+                        client.receive(buf, frame.len(), crc_valid, ReturnCode::SUCCESS);
+                        crc_valid && ack
+                    }
+                    None => {
+                        self.rx_buf.replace(buf);
+                        false
+                    }
+                }
+            }
+            Some(buf) => {
+                self.rx_buf.replace(buf);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl radio::Radio for SimRadio<'_> {}
+
+impl radio::RadioConfig for SimRadio<'_> {
+    fn initialize(
+        &self,
+        _spi_buf: &'static mut [u8],
+        _reg_write: &'static mut [u8],
+        _reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn reset(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+
+    fn start(&self) -> ReturnCode {
+        self.on.set(true);
+        self.power_pending.set(true);
+        self.request_service();
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.on.set(false);
+        self.power_pending.set(true);
+        self.request_service();
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.on.get()
+    }
+
+    fn busy(&self) -> bool {
+        self.tx_buf.is_some()
+    }
+
+    fn set_power_client(&self, client: &'static dyn radio::PowerClient) {
+        self.power_client.set(client);
+    }
+
+    fn config_commit(&self) {
+        // Addresses take effect immediately; only the callback is deferred
+        self.config_pending.set(true);
+        self.request_service();
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.config_client.set(client);
+    }
+
+    fn get_address(&self) -> u16 {
+        self.addr.get()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.addr_long.get()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.pan.get()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.tx_power.get()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.channel.get()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.addr.set(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.addr_long.set(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.pan.set(id);
+    }
+
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        self.tx_power.set(power);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        if chan >= 11 && chan <= 26 {
+            self.channel.set(chan);
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::EINVAL
+        }
+    }
+}
+
+impl radio::RadioData for SimRadio<'_> {
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient, buffer: &'static mut [u8]) {
+        self.rx_client.set(client);
+        self.rx_buf.replace(buffer);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.rx_buf.replace(buffer);
+    }
+
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if !self.on.get() {
+            return (ReturnCode::EOFF, Some(buf));
+        }
+        if self.tx_buf.is_some() {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+        if frame_len > radio::MAX_FRAME_SIZE || radio::PSDU_OFFSET + frame_len > buf.len() {
+            return (ReturnCode::ESIZE, Some(buf));
+        }
+        let id = self.id;
+        let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+        self.medium.map(|medium| medium.transmit(id, frame));
+        self.tx_buf.replace(buf);
+        (ReturnCode::SUCCESS, None)
+    }
+}
@@ -15,16 +15,23 @@ pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod ble;
 pub mod ble_advertising_driver;
 pub mod bus;
 pub mod button;
 pub mod buzzer_driver;
+pub mod can;
 pub mod console;
 pub mod crc;
 pub mod ctap;
+pub mod ctaphid;
 pub mod dac;
+pub mod date_time;
 pub mod debug_process_restart;
+pub mod drbg;
 pub mod driver;
+pub mod ds18b20;
+pub mod ethernet;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
@@ -55,10 +62,13 @@ pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
+pub mod one_wire;
 pub mod panic_button;
 pub mod pca9544a;
+pub mod pcm;
 pub mod process_console;
 pub mod proximity;
+pub mod public_key_crypto;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
@@ -67,6 +77,10 @@ pub mod sdcard;
 pub mod segger_rtt;
 pub mod sht3x;
 pub mod si7021;
+pub mod software_aes;
+pub mod software_clock;
+pub mod software_p256;
+pub mod software_sha256;
 pub mod sound_pressure;
 pub mod spi_controller;
 pub mod spi_peripheral;
@@ -80,6 +94,7 @@ pub mod usb;
 pub mod virtual_adc;
 pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
+pub mod virtual_can;
 pub mod virtual_digest;
 pub mod virtual_flash;
 pub mod virtual_hmac;
@@ -58,8 +58,11 @@ use kernel::ReturnCode;
 
 pub static mut TXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize + 4];
 pub static mut RXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize + 4];
+pub static mut QSPI_PAGE: Mx25r6435fQspiPage = Mx25r6435fQspiPage([0; PAGE_SIZE as usize]);
 
 const SPI_SPEED: u32 = 8000000;
+const QSPI_SPEED: u32 = 16000000;
+const FLASH_SIZE: usize = 8 * 1024 * 1024;
 const SECTOR_SIZE: u32 = 4096;
 const PAGE_SIZE: u32 = 256;
 
@@ -104,15 +107,28 @@ impl AsMut<[u8]> for Mx25r6435fSector {
     }
 }
 
+/// Page buffer for `MX25R6435FQspi`, aligned for QSPI controllers that move
+/// data with DMA.
+#[repr(align(4))]
+pub struct Mx25r6435fQspiPage(pub [u8; PAGE_SIZE as usize]);
+
+/// Quad Enable bit of the status register.
+const STATUS_QE: u8 = 0x40;
+/// Write In Progress bit of the status register.
+const STATUS_WIP: u8 = 0x01;
+
 #[allow(dead_code)]
 enum Opcodes {
-    WREN = 0x06, // Write Enable
-    WRDI = 0x04, // Write Disable
-    SE = 0x20,   // Sector Erase
-    READ = 0x03, // Normal Read
-    PP = 0x02,   // Page Program (write)
-    RDID = 0x9f, // Read Identification
-    RDSR = 0x05, // Read Status Register
+    WREN = 0x06,  // Write Enable
+    WRDI = 0x04,  // Write Disable
+    SE = 0x20,    // Sector Erase
+    READ = 0x03,  // Normal Read
+    PP = 0x02,    // Page Program (write)
+    RDID = 0x9f,  // Read Identification
+    RDSR = 0x05,  // Read Status Register
+    WRSR = 0x01,  // Write Status Register
+    READ4 = 0xEB, // Quad I/O Read
+    PP4 = 0x38,   // Quad Page Program
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -591,3 +607,481 @@ impl<
         self.erase_sector(page_number as u32)
     }
 }
+
+/// What `MX25R6435FQspi` waits for the flash to finish.
+#[derive(Clone, Copy, PartialEq)]
+enum QspiWait {
+    QuadEnable,
+    Erase { operation: Operation },
+    Write { sector_index: u32, page_index: u32 },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum QspiState {
+    Idle,
+
+    QuadEnable,
+
+    ReadSector {
+        sector_index: u32,
+        page_index: u32,
+    },
+
+    EraseSector {
+        sector_index: u32,
+        operation: Operation,
+    },
+
+    WritePage {
+        sector_index: u32,
+        page_index: u32,
+    },
+
+    /// The flash started an erase or program operation.
+    Busy {
+        wait: QspiWait,
+    },
+    /// Reading the status register to see if the operation finished.
+    CheckDone {
+        wait: QspiWait,
+    },
+}
+
+/// MX25R6435F connected to a quad-SPI controller.
+///
+/// The driver sets the Quad Enable bit in `init` and then reads with the
+/// quad I/O read and writes with the quad page program command. Data moves
+/// through a page buffer, one page at a time. With `map` the flash can be
+/// read through the address space of the controller.
+///
+/// Usage
+/// -----
+///
+/// ```rust
+/// let mx25r6435f = static_init!(
+///     capsules::mx25r6435f::MX25R6435FQspi<
+///         'static,
+///         nrf52840::qspi::Qspi<'static>,
+///         VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+///     >,
+///     capsules::mx25r6435f::MX25R6435FQspi::new(
+///         &base_peripherals.qspi,
+///         mx25r6435f_virtual_alarm,
+///         &mut capsules::mx25r6435f::QSPI_PAGE.0,
+///     )
+/// );
+/// base_peripherals.qspi.set_client(mx25r6435f);
+/// mx25r6435f_virtual_alarm.set_alarm_client(mx25r6435f);
+/// mx25r6435f.init();
+/// ```
+pub struct MX25R6435FQspi<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>> {
+    qspi: &'a Q,
+    alarm: &'a A,
+    state: Cell<QspiState>,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn hil::flash::Client<MX25R6435FQspi<'a, Q, A>>>,
+    client_sector: TakeCell<'static, Mx25r6435fSector>,
+}
+
+impl<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>> MX25R6435FQspi<'a, Q, A> {
+    pub fn new(qspi: &'a Q, alarm: &'a A, buffer: &'static mut [u8]) -> MX25R6435FQspi<'a, Q, A> {
+        MX25R6435FQspi {
+            qspi,
+            alarm,
+            state: Cell::new(QspiState::Idle),
+            buffer: TakeCell::new(buffer),
+            client: OptionalCell::empty(),
+            client_sector: TakeCell::empty(),
+        }
+    }
+
+    /// Configure the controller and set the Quad Enable bit of the flash.
+    /// Flash operations fail with EBUSY until the flash finished writing the
+    /// status register.
+    pub fn init(&self) -> ReturnCode {
+        if self.state.get() != QspiState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if let Err(rc) = self
+            .qspi
+            .configure(QSPI_SPEED, FLASH_SIZE, hil::qspi::AddressSize::Bits24)
+        {
+            return rc;
+        }
+        self.start(QspiState::QuadEnable)
+    }
+
+    /// Map the flash into the address space of the controller.
+    pub fn map(&self) -> Result<hil::qspi::MappedRegion, ReturnCode> {
+        self.qspi.map(Self::read_command(0))
+    }
+
+    pub fn unmap(&self) -> ReturnCode {
+        self.qspi.unmap()
+    }
+
+    fn read_command(address: u32) -> hil::qspi::Command {
+        hil::qspi::Command {
+            instruction: Opcodes::READ4 as u8,
+            address: Some(address),
+            // Two cycles of mode bits and four dummy cycles
+            dummy_cycles: 6,
+            mode: hil::qspi::Mode::QuadIo,
+        }
+    }
+
+    /// Send write enable, and continue in `state` once it is done.
+    fn start(&self, state: QspiState) -> ReturnCode {
+        let rc = self
+            .qspi
+            .command(hil::qspi::Command::simple(Opcodes::WREN as u8));
+        if rc == ReturnCode::SUCCESS {
+            self.state.set(state);
+        }
+        rc
+    }
+
+    fn start_read(&self, sector_index: u32, page_index: u32) -> ReturnCode {
+        let address = sector_index * SECTOR_SIZE + page_index * PAGE_SIZE;
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            match self
+                .qspi
+                .read(Self::read_command(address), buffer, PAGE_SIZE as usize)
+            {
+                Ok(()) => {
+                    self.state.set(QspiState::ReadSector {
+                        sector_index,
+                        page_index,
+                    });
+                    ReturnCode::SUCCESS
+                }
+                Err((rc, buffer)) => {
+                    self.buffer.replace(buffer);
+                    rc
+                }
+            }
+        })
+    }
+
+    fn read_sector(
+        &self,
+        sector_index: u32,
+        sector: &'static mut Mx25r6435fSector,
+    ) -> Result<(), (ReturnCode, &'static mut Mx25r6435fSector)> {
+        if self.state.get() != QspiState::Idle {
+            return Err((ReturnCode::EBUSY, sector));
+        }
+        match self.start_read(sector_index, 0) {
+            ReturnCode::SUCCESS => {
+                self.client_sector.replace(sector);
+                Ok(())
+            }
+            rc => Err((rc, sector)),
+        }
+    }
+
+    fn write_sector(
+        &self,
+        sector_index: u32,
+        sector: &'static mut Mx25r6435fSector,
+    ) -> Result<(), (ReturnCode, &'static mut Mx25r6435fSector)> {
+        if self.state.get() != QspiState::Idle {
+            return Err((ReturnCode::EBUSY, sector));
+        }
+        match self.start(QspiState::EraseSector {
+            sector_index,
+            operation: Operation::Write { sector_index },
+        }) {
+            ReturnCode::SUCCESS => {
+                self.client_sector.replace(sector);
+                Ok(())
+            }
+            rc => Err((rc, sector)),
+        }
+    }
+
+    fn erase_sector(&self, sector_index: u32) -> ReturnCode {
+        if self.state.get() != QspiState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.start(QspiState::EraseSector {
+            sector_index,
+            operation: Operation::Erase,
+        })
+    }
+
+    fn check_done(&self, wait: QspiWait) {
+        let rc = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            match self
+                .qspi
+                .read(hil::qspi::Command::simple(Opcodes::RDSR as u8), buffer, 1)
+            {
+                Ok(()) => {
+                    self.state.set(QspiState::CheckDone { wait });
+                    ReturnCode::SUCCESS
+                }
+                Err((rc, buffer)) => {
+                    self.buffer.replace(buffer);
+                    rc
+                }
+            }
+        });
+        if rc != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+
+    /// The flash finished what `wait` waited for.
+    fn done(&self, wait: QspiWait) {
+        let rc = match wait {
+            QspiWait::QuadEnable => {
+                self.state.set(QspiState::Idle);
+                ReturnCode::SUCCESS
+            }
+            QspiWait::Erase {
+                operation: Operation::Erase,
+            } => {
+                self.state.set(QspiState::Idle);
+                self.client.map(|client| {
+                    client.erase_complete(hil::flash::Error::CommandComplete);
+                });
+                ReturnCode::SUCCESS
+            }
+            QspiWait::Erase {
+                operation: Operation::Write { sector_index },
+            } => self.start(QspiState::WritePage {
+                sector_index,
+                page_index: 0,
+            }),
+            QspiWait::Write {
+                sector_index,
+                page_index,
+            } => {
+                if page_index * PAGE_SIZE == SECTOR_SIZE {
+                    self.state.set(QspiState::Idle);
+                    self.client_sector.take().map(|sector| {
+                        self.client.map(move |client| {
+                            client.write_complete(sector, hil::flash::Error::CommandComplete);
+                        });
+                    });
+                    ReturnCode::SUCCESS
+                } else {
+                    self.start(QspiState::WritePage {
+                        sector_index,
+                        page_index,
+                    })
+                }
+            }
+        };
+        if rc != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+
+    /// Abort the running operation and report the failure to the client.
+    fn fail(&self) {
+        let erase = QspiWait::Erase {
+            operation: Operation::Erase,
+        };
+        match self.state.replace(QspiState::Idle) {
+            QspiState::Idle
+            | QspiState::QuadEnable
+            | QspiState::Busy {
+                wait: QspiWait::QuadEnable,
+            }
+            | QspiState::CheckDone {
+                wait: QspiWait::QuadEnable,
+            } => {}
+            QspiState::EraseSector {
+                operation: Operation::Erase,
+                ..
+            } => self.client.map_or((), |client| {
+                client.erase_complete(hil::flash::Error::FlashError);
+            }),
+            QspiState::Busy { wait } | QspiState::CheckDone { wait } if wait == erase => {
+                self.client.map_or((), |client| {
+                    client.erase_complete(hil::flash::Error::FlashError);
+                })
+            }
+            QspiState::ReadSector { .. } => self.client_sector.take().map_or((), |sector| {
+                self.client.map(move |client| {
+                    client.read_complete(sector, hil::flash::Error::FlashError);
+                });
+            }),
+            _ => self.client_sector.take().map_or((), |sector| {
+                self.client.map(move |client| {
+                    client.write_complete(sector, hil::flash::Error::FlashError);
+                });
+            }),
+        }
+    }
+}
+
+impl<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>> hil::qspi::Client
+    for MX25R6435FQspi<'a, Q, A>
+{
+    fn command_done(&self, buffer: Option<&'static mut [u8]>, rc: ReturnCode) {
+        if let Some(buffer) = buffer {
+            self.buffer.replace(buffer);
+        }
+        if rc != ReturnCode::SUCCESS {
+            self.fail();
+            return;
+        }
+
+        let rc = match self.state.get() {
+            QspiState::Idle => ReturnCode::SUCCESS,
+            QspiState::QuadEnable => {
+                // Write enable is done, set the Quad Enable bit.
+                self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                    buffer[0] = STATUS_QE;
+                    self.state.set(QspiState::Busy {
+                        wait: QspiWait::QuadEnable,
+                    });
+                    self.qspi
+                        .write(hil::qspi::Command::simple(Opcodes::WRSR as u8), buffer, 1)
+                        .err()
+                        .map_or(ReturnCode::SUCCESS, |(rc, buffer)| {
+                            self.buffer.replace(buffer);
+                            rc
+                        })
+                })
+            }
+            QspiState::ReadSector {
+                sector_index,
+                page_index,
+            } => {
+                let offset = (page_index * PAGE_SIZE) as usize;
+                self.client_sector.map(|sector| {
+                    self.buffer.map(|buffer| {
+                        sector.0[offset..offset + PAGE_SIZE as usize]
+                            .copy_from_slice(&buffer[..PAGE_SIZE as usize]);
+                    });
+                });
+                if (page_index + 1) * PAGE_SIZE == SECTOR_SIZE {
+                    self.state.set(QspiState::Idle);
+                    self.client_sector.take().map(|sector| {
+                        self.client.map(move |client| {
+                            client.read_complete(sector, hil::flash::Error::CommandComplete);
+                        });
+                    });
+                    ReturnCode::SUCCESS
+                } else {
+                    self.start_read(sector_index, page_index + 1)
+                }
+            }
+            QspiState::EraseSector {
+                sector_index,
+                operation,
+            } => {
+                self.state.set(QspiState::Busy {
+                    wait: QspiWait::Erase { operation },
+                });
+                self.qspi.command(hil::qspi::Command {
+                    address: Some(sector_index * SECTOR_SIZE),
+                    ..hil::qspi::Command::simple(Opcodes::SE as u8)
+                })
+            }
+            QspiState::WritePage {
+                sector_index,
+                page_index,
+            } => {
+                let offset = (page_index * PAGE_SIZE) as usize;
+                self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                    self.client_sector.map(|sector| {
+                        buffer[..PAGE_SIZE as usize]
+                            .copy_from_slice(&sector.0[offset..offset + PAGE_SIZE as usize]);
+                    });
+                    self.state.set(QspiState::Busy {
+                        wait: QspiWait::Write {
+                            sector_index,
+                            page_index: page_index + 1,
+                        },
+                    });
+                    let command = hil::qspi::Command {
+                        instruction: Opcodes::PP4 as u8,
+                        address: Some(sector_index * SECTOR_SIZE + page_index * PAGE_SIZE),
+                        dummy_cycles: 0,
+                        mode: hil::qspi::Mode::QuadIo,
+                    };
+                    self.qspi
+                        .write(command, buffer, PAGE_SIZE as usize)
+                        .err()
+                        .map_or(ReturnCode::SUCCESS, |(rc, buffer)| {
+                            self.buffer.replace(buffer);
+                            rc
+                        })
+                })
+            }
+            QspiState::Busy { wait } => {
+                // Datasheet says erase takes 58 ms and writing a page or the
+                // status register 3.2 ms on average. So we wait that long.
+                let delay = match wait {
+                    QspiWait::Erase { .. } => A::ticks_from_ms(58),
+                    QspiWait::QuadEnable | QspiWait::Write { .. } => A::ticks_from_us(3200),
+                };
+                self.alarm.set_alarm(self.alarm.now(), delay);
+                ReturnCode::SUCCESS
+            }
+            QspiState::CheckDone { wait } => {
+                let status = self.buffer.map_or(STATUS_WIP, |buffer| buffer[0]);
+                if status & STATUS_WIP == STATUS_WIP {
+                    // Still in progress.
+                    self.check_done(wait);
+                } else {
+                    self.done(wait);
+                }
+                ReturnCode::SUCCESS
+            }
+        };
+        if rc != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+}
+
+impl<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>> hil::time::AlarmClient
+    for MX25R6435FQspi<'a, Q, A>
+{
+    fn alarm(&self) {
+        // After the timer expires we still have to check that the erase/write
+        // operation has finished.
+        if let QspiState::Busy { wait } = self.state.get() {
+            self.check_done(wait);
+        }
+    }
+}
+
+impl<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>, C: hil::flash::Client<Self>>
+    hil::flash::HasClient<'a, C> for MX25R6435FQspi<'a, Q, A>
+{
+    fn set_client(&self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<'a, Q: hil::qspi::Qspi<'a>, A: hil::time::Alarm<'a>> hil::flash::Flash
+    for MX25R6435FQspi<'a, Q, A>
+{
+    type Page = Mx25r6435fSector;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.read_sector(page_number as u32, buf)
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.write_sector(page_number as u32, buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.erase_sector(page_number as u32)
+    }
+}
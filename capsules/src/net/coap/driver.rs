@@ -0,0 +1,982 @@
+//! CoAP userspace interface.
+//!
+//! Lets processes act as CoAP servers and clients through a
+//! [CoapEndpoint](../endpoint/struct.CoapEndpoint.html), without parsing
+//! CoAP themselves.
+//!
+//! As servers, processes register resource paths. Requests for a registered
+//! path are passed to the process that registered it, which responds with a
+//! code and a payload. On top of this the driver implements:
+//!
+//! - Block-wise transfers (RFC 7959). Representations larger than a block
+//!   are sent in `BLOCK_SZX` sized blocks (or smaller ones if the client
+//!   asks); only the first block involves the process, and later blocks are
+//!   served from its transmit buffer, which must not change until the
+//!   transfer completes. Uploads in Block1 blocks are reassembled in the
+//!   receive buffer of the process, which is notified once the last block
+//!   arrives.
+//! - Observe (RFC 7641). Clients that GET a resource with the Observe
+//!   option are registered as observers, and the process can send the
+//!   current representation to all observers of a resource as
+//!   non-confirmable notifications. Observers that reset a notification are
+//!   removed.
+//!
+//! As clients, processes send one request at a time to an address and
+//! port. Responses sent in blocks are fetched block by block and
+//! reassembled in the receive buffer of the process. A GET can also
+//! register the process as an observer of the resource; notifications are
+//! then delivered to it until it cancels the observation.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let coap_driver = static_init!(
+//!     capsules::net::coap::driver::CoapDriver<'static, VirtualMuxAlarm<'static, Ast>>,
+//!     capsules::net::coap::driver::CoapDriver::new(
+//!         coap_endpoint,
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! coap_endpoint.set_client(coap_driver);
+//! ```
+
+use crate::net::coap::endpoint::{CoapClient, CoapEndpoint, Peer};
+use crate::net::coap::message::{
+    code, encode_uint, option, path_options, Block, CoapOption, Message, MAX_TOKEN_LEN,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::Alarm;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// The number of resources that can be registered across all processes.
+pub const MAX_RESOURCES: usize = 8;
+/// The number of observers across all resources.
+pub const MAX_OBSERVERS: usize = 4;
+/// The longest resource or request path.
+pub const MAX_PATH_LEN: usize = 32;
+/// The largest number of segments in a path.
+pub const MAX_PATH_SEGMENTS: usize = 6;
+/// Size exponent of the blocks the driver sends (64 byte blocks).
+pub const BLOCK_SZX: u8 = 2;
+
+/// Length of the peer buffer: a 16 byte IPv6 address followed by a
+/// big-endian port.
+const PEER_LEN: usize = 18;
+/// Flags of the request command.
+const REQUEST_NON_CONFIRMABLE: usize = 0x100;
+const REQUEST_OBSERVE: usize = 0x200;
+
+#[derive(Default)]
+pub struct App {
+    request_callback: Option<Callback>,
+    response_callback: Option<Callback>,
+    notify_callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    rx: Option<AppSlice<Shared, u8>>,
+    tx: Option<AppSlice<Shared, u8>>,
+    peer: Option<AppSlice<Shared, u8>>,
+}
+
+#[derive(Copy, Clone)]
+struct Resource {
+    appid: AppId,
+    path: [u8; MAX_PATH_LEN],
+    path_len: usize,
+    /// Code and length of the representation in the transmit buffer of the
+    /// process, from which later blocks and notifications are sent
+    representation: Option<(u8, usize)>,
+    observe_seq: u32,
+}
+
+#[derive(Copy, Clone)]
+struct Observer {
+    resource: usize,
+    peer: Peer,
+    token: [u8; MAX_TOKEN_LEN],
+    token_len: usize,
+    /// The message ID of the last notification sent to the observer
+    last_message_id: Option<u16>,
+}
+
+/// A request passed to a process that it has not responded to yet.
+#[derive(Copy, Clone)]
+struct PendingRequest {
+    resource: usize,
+    /// The last block of an upload, which is acknowledged in the response
+    block1: Option<Block>,
+    /// The request registered an observer
+    observe: bool,
+    block2_szx: u8,
+}
+
+/// An upload in Block1 blocks that is in progress.
+#[derive(Copy, Clone)]
+struct Upload {
+    resource: usize,
+    peer: Peer,
+    received: usize,
+}
+
+/// An outstanding client request.
+#[derive(Copy, Clone)]
+struct ClientRequest {
+    appid: AppId,
+    peer: Peer,
+    token: [u8; 4],
+    code: u8,
+    confirmable: bool,
+    observe: bool,
+    path: [u8; MAX_PATH_LEN],
+    path_len: usize,
+    message_id: u16,
+    /// Bytes of the response received so far
+    received: usize,
+}
+
+#[derive(Copy, Clone)]
+struct Observation {
+    appid: AppId,
+    peer: Peer,
+    token: [u8; 4],
+}
+
+/// Notifications of a resource that are being sent.
+#[derive(Copy, Clone)]
+struct Notification {
+    resource: usize,
+    next_observer: usize,
+    notified: usize,
+    /// The notification being transmitted, if any
+    message_id: Option<u16>,
+}
+
+pub struct CoapDriver<'a, A: Alarm<'a>> {
+    endpoint: &'a CoapEndpoint<'a, A>,
+    apps: Grant<App>,
+    resources: Cell<[Option<Resource>; MAX_RESOURCES]>,
+    observers: Cell<[Option<Observer>; MAX_OBSERVERS]>,
+    pending: OptionalCell<PendingRequest>,
+    upload: OptionalCell<Upload>,
+    request: OptionalCell<ClientRequest>,
+    observation: OptionalCell<Observation>,
+    notification: OptionalCell<Notification>,
+}
+
+impl<'a, A: Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(endpoint: &'a CoapEndpoint<'a, A>, grant: Grant<App>) -> CoapDriver<'a, A> {
+        CoapDriver {
+            endpoint: endpoint,
+            apps: grant,
+            resources: Cell::new([None; MAX_RESOURCES]),
+            observers: Cell::new([None; MAX_OBSERVERS]),
+            pending: OptionalCell::empty(),
+            upload: OptionalCell::empty(),
+            request: OptionalCell::empty(),
+            observation: OptionalCell::empty(),
+            notification: OptionalCell::empty(),
+        }
+    }
+
+    fn resource(&self, index: usize) -> Option<Resource> {
+        self.resources
+            .get()
+            .get(index)
+            .and_then(|resource| *resource)
+    }
+
+    fn update_resource<F: FnOnce(&mut Resource)>(&self, index: usize, f: F) {
+        let mut resources = self.resources.get();
+        if let Some(Some(resource)) = resources.get_mut(index) {
+            f(resource);
+        }
+        self.resources.set(resources);
+    }
+
+    /// Returns the resource `index` if it belongs to `appid`.
+    fn app_resource(&self, appid: AppId, index: usize) -> Option<Resource> {
+        self.resource(index)
+            .filter(|resource| resource.appid == appid)
+    }
+
+    fn register(&self, appid: AppId, path_len: usize) -> ReturnCode {
+        let mut path = [0; MAX_PATH_LEN];
+        let copied = self
+            .apps
+            .enter(appid, |app, _| match app.path {
+                Some(ref slice) if path_len <= slice.len() && path_len <= MAX_PATH_LEN => {
+                    path[..path_len].copy_from_slice(&slice.as_ref()[..path_len]);
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::EINVAL,
+            })
+            .unwrap_or_else(|err| err.into());
+        if copied != ReturnCode::SUCCESS {
+            return copied;
+        }
+        let path = &path[..path_len];
+        let mut segments = [CoapOption::new(0, &[]); MAX_PATH_SEGMENTS];
+        match path_options(path, &mut segments) {
+            Some(count) if segments[..count].iter().all(|s| !s.value.is_empty()) => {}
+            _ => return ReturnCode::EINVAL,
+        }
+
+        let mut resources = self.resources.get();
+        if resources
+            .iter()
+            .flatten()
+            .any(|resource| &resource.path[..resource.path_len] == path)
+        {
+            return ReturnCode::EALREADY;
+        }
+        match resources.iter().position(|resource| resource.is_none()) {
+            Some(index) => {
+                let mut resource = Resource {
+                    appid: appid,
+                    path: [0; MAX_PATH_LEN],
+                    path_len: path_len,
+                    representation: None,
+                    observe_seq: 0,
+                };
+                resource.path[..path_len].copy_from_slice(path);
+                resources[index] = Some(resource);
+                self.resources.set(resources);
+                ReturnCode::SuccessWithValue { value: index }
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    fn unregister(&self, index: usize) {
+        let mut resources = self.resources.get();
+        resources[index] = None;
+        self.resources.set(resources);
+        let mut observers = self.observers.get();
+        for observer in observers.iter_mut() {
+            if observer.map_or(false, |observer| observer.resource == index) {
+                *observer = None;
+            }
+        }
+        self.observers.set(observers);
+        if self
+            .pending
+            .map_or(false, |pending| pending.resource == index)
+        {
+            self.pending.clear();
+            self.endpoint.respond(code::NOT_FOUND, &[], &[]);
+        }
+        if self.upload.map_or(false, |upload| upload.resource == index) {
+            self.upload.clear();
+        }
+        if self.notification.map_or(false, |n| n.resource == index) {
+            self.notification.clear();
+        }
+    }
+
+    /// Registers an observer, replacing an earlier registration of the same
+    /// peer and token. Returns false if there is no room for it.
+    fn add_observer(&self, resource: usize, peer: Peer, token: &[u8]) -> bool {
+        self.remove_observer(peer, token);
+        let mut observers = self.observers.get();
+        match observers.iter_mut().find(|observer| observer.is_none()) {
+            Some(slot) => {
+                let mut observer = Observer {
+                    resource: resource,
+                    peer: peer,
+                    token: [0; MAX_TOKEN_LEN],
+                    token_len: token.len(),
+                    last_message_id: None,
+                };
+                observer.token[..token.len()].copy_from_slice(token);
+                *slot = Some(observer);
+                self.observers.set(observers);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_observer(&self, peer: Peer, token: &[u8]) {
+        let mut observers = self.observers.get();
+        for observer in observers.iter_mut() {
+            if observer.map_or(false, |observer| {
+                observer.peer == peer && &observer.token[..observer.token_len] == token
+            }) {
+                *observer = None;
+            }
+        }
+        self.observers.set(observers);
+    }
+
+    /// Calls `f` with the options and payload of block `num` of the
+    /// representation of `len` bytes in the transmit buffer of `appid`.
+    fn with_block<F, R>(
+        &self,
+        appid: AppId,
+        len: usize,
+        num: u32,
+        szx: u8,
+        observe: Option<u32>,
+        block1: Option<Block>,
+        f: F,
+    ) -> Result<R, ReturnCode>
+    where
+        F: FnOnce(&[CoapOption], &[u8]) -> Result<R, ReturnCode>,
+        R: Copy,
+    {
+        self.apps
+            .enter(appid, |app, _| {
+                let tx = match app.tx {
+                    Some(ref tx) if len <= tx.len() => tx,
+                    _ => return Err(ReturnCode::EINVAL),
+                };
+                let size = 16 << szx;
+                let start = num as usize * size;
+                if start > 0 && start >= len {
+                    return Err(ReturnCode::EINVAL);
+                }
+                let end = cmp::min(start + size, len);
+
+                let mut observe_buf = [0; 4];
+                let mut block2_buf = [0; 4];
+                let mut block1_buf = [0; 4];
+                let mut options = [CoapOption::new(0, &[]); 3];
+                let mut count = 0;
+                if let Some(seq) = observe {
+                    // Observe sequence numbers are 24 bits long
+                    let value = encode_uint(seq & 0x00ff_ffff, &mut observe_buf);
+                    options[count] = CoapOption::new(option::OBSERVE, value);
+                    count += 1;
+                }
+                if len > size {
+                    let value = Block::new(num, end < len, szx).encode(&mut block2_buf);
+                    options[count] = CoapOption::new(option::BLOCK2, value);
+                    count += 1;
+                }
+                if let Some(block1) = block1 {
+                    options[count] =
+                        CoapOption::new(option::BLOCK1, block1.encode(&mut block1_buf));
+                    count += 1;
+                }
+                f(&options[..count], &tx.as_ref()[start..end])
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Responds to the pending request with the first block of `len` bytes
+    /// of the transmit buffer of `appid`.
+    fn respond(&self, appid: AppId, response_code: u8, len: usize) -> ReturnCode {
+        let pending = match self.pending.map(|pending| *pending) {
+            Some(pending) if self.app_resource(appid, pending.resource).is_some() => pending,
+            _ => return ReturnCode::EINVAL,
+        };
+        let observe = if pending.observe {
+            self.resource(pending.resource)
+                .map(|resource| resource.observe_seq)
+        } else {
+            None
+        };
+        let result = self.with_block(
+            appid,
+            len,
+            0,
+            pending.block2_szx,
+            observe,
+            pending.block1,
+            |options, payload| match self.endpoint.respond(response_code, options, payload) {
+                ReturnCode::SUCCESS => Ok(()),
+                err => Err(err),
+            },
+        );
+        match result {
+            Ok(()) => {
+                self.pending.clear();
+                self.update_resource(pending.resource, |resource| {
+                    resource.representation = Some((response_code, len));
+                });
+                ReturnCode::SUCCESS
+            }
+            Err(err) => err,
+        }
+    }
+
+    /// Copies `payload` into the receive buffer of `appid` at `offset`.
+    /// Returns the number of bytes copied, or None if the process is gone.
+    fn write_rx(&self, appid: AppId, offset: usize, payload: &[u8]) -> Option<usize> {
+        self.apps
+            .enter(appid, |app, _| match app.rx {
+                Some(ref mut rx) if offset <= rx.len() => {
+                    let len = cmp::min(payload.len(), rx.len() - offset);
+                    rx.as_mut()[offset..offset + len].copy_from_slice(&payload[..len]);
+                    len
+                }
+                _ => 0,
+            })
+            .ok()
+    }
+
+    /// Handles a request for a later block of a representation, or a block
+    /// of an upload, and copies the request body into the receive buffer of
+    /// the process. Returns the length of the body once the whole body has
+    /// been received, or None if the request has already been responded to.
+    fn handle_blocks(
+        &self,
+        index: usize,
+        resource: &Resource,
+        peer: Peer,
+        msg: &Message,
+    ) -> Option<usize> {
+        if let (Some(block2), Some((response_code, len))) = (msg.block2(), resource.representation)
+        {
+            if msg.code == code::GET && block2.num > 0 {
+                let szx = cmp::min(block2.szx, BLOCK_SZX);
+                let num = (block2.offset() / (16 << szx)) as u32;
+                let result = self.with_block(
+                    resource.appid,
+                    len,
+                    num,
+                    szx,
+                    None,
+                    None,
+                    |options, payload| match self.endpoint.respond(response_code, options, payload)
+                    {
+                        ReturnCode::SUCCESS => Ok(()),
+                        err => Err(err),
+                    },
+                );
+                if result.is_err() {
+                    self.endpoint.respond(code::BAD_OPTION, &[], &[]);
+                }
+                return None;
+            }
+        }
+
+        let block1 = match msg.block1() {
+            Some(block1) => block1,
+            None => {
+                return match self.write_rx(resource.appid, 0, msg.payload) {
+                    Some(len) => Some(len),
+                    None => {
+                        self.endpoint.respond(code::INTERNAL_SERVER_ERROR, &[], &[]);
+                        None
+                    }
+                };
+            }
+        };
+        let expected = if block1.num == 0 {
+            Some(0)
+        } else {
+            self.upload
+                .map(|upload| *upload)
+                .filter(|upload| upload.resource == index && upload.peer == peer)
+                .map(|upload| upload.received)
+        };
+        if expected != Some(block1.offset()) {
+            self.upload.clear();
+            self.endpoint
+                .respond(code::REQUEST_ENTITY_INCOMPLETE, &[], &[]);
+            return None;
+        }
+        let received = match self.write_rx(resource.appid, block1.offset(), msg.payload) {
+            Some(len) if len == msg.payload.len() => block1.offset() + len,
+            _ => {
+                self.upload.clear();
+                self.endpoint
+                    .respond(code::REQUEST_ENTITY_TOO_LARGE, &[], &[]);
+                return None;
+            }
+        };
+        if block1.more {
+            self.upload.set(Upload {
+                resource: index,
+                peer: peer,
+                received: received,
+            });
+            let mut buf = [0; 4];
+            let value = block1.encode(&mut buf);
+            self.endpoint.respond(
+                code::CONTINUE,
+                &[CoapOption::new(option::BLOCK1, value)],
+                &[],
+            );
+            return None;
+        }
+        self.upload.clear();
+        Some(received)
+    }
+
+    /// Sends the current representation of the notified resource to its
+    /// next observer, or completes the notification.
+    fn notify_next(&self) {
+        let notification = match self.notification.map(|n| *n) {
+            Some(notification) if notification.message_id.is_none() => notification,
+            _ => return,
+        };
+        let resource = self.resource(notification.resource);
+        let (appid, (response_code, len), seq) = match resource
+            .and_then(|r| r.representation.map(|repr| (r.appid, repr, r.observe_seq)))
+        {
+            Some(values) => values,
+            None => {
+                self.notification.clear();
+                return;
+            }
+        };
+        let observers = self.observers.get();
+        for i in notification.next_observer..MAX_OBSERVERS {
+            let observer = match observers[i] {
+                Some(observer) if observer.resource == notification.resource => observer,
+                _ => continue,
+            };
+            let token = &observer.token[..observer.token_len];
+            let result = self.with_block(
+                appid,
+                len,
+                0,
+                BLOCK_SZX,
+                Some(seq),
+                None,
+                |options, payload| {
+                    self.endpoint
+                        .send(observer.peer, false, response_code, token, options, payload)
+                },
+            );
+            match result {
+                Ok(message_id) => {
+                    let mut observers = self.observers.get();
+                    if let Some(observer) = observers[i].as_mut() {
+                        observer.last_message_id = Some(message_id);
+                    }
+                    self.observers.set(observers);
+                    self.notification.set(Notification {
+                        next_observer: i + 1,
+                        message_id: Some(message_id),
+                        ..notification
+                    });
+                    return;
+                }
+                // Retried when the endpoint has sent its outstanding message
+                Err(ReturnCode::EBUSY) => {
+                    self.notification.set(Notification {
+                        next_observer: i,
+                        ..notification
+                    });
+                    return;
+                }
+                Err(_) => {}
+            }
+        }
+        self.notification.clear();
+        let _ = self.apps.enter(appid, |app, _| {
+            app.notify_callback.map(|mut cb| {
+                cb.schedule(
+                    usize::from(ReturnCode::SUCCESS),
+                    notification.notified,
+                    notification.resource,
+                )
+            });
+        });
+    }
+
+    fn notify(&self, appid: AppId, index: usize, len: usize) -> ReturnCode {
+        if self.app_resource(appid, index).is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if self.notification.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let fits = self
+            .apps
+            .enter(appid, |app, _| {
+                app.tx.as_ref().map_or(false, |tx| len <= tx.len())
+            })
+            .unwrap_or(false);
+        if !fits {
+            return ReturnCode::EINVAL;
+        }
+        self.update_resource(index, |resource| {
+            resource.representation = Some((code::CONTENT, len));
+            resource.observe_seq = resource.observe_seq.wrapping_add(1);
+        });
+        self.notification.set(Notification {
+            resource: index,
+            next_observer: 0,
+            notified: 0,
+            message_id: None,
+        });
+        self.notify_next();
+        ReturnCode::SUCCESS
+    }
+
+    /// Sends `req`, asking for the Block2 block `block2` of the response if
+    /// given.
+    fn send_request(
+        &self,
+        req: &ClientRequest,
+        payload: &[u8],
+        block2: Option<Block>,
+    ) -> Result<u16, ReturnCode> {
+        let mut block_buf = [0; 4];
+        let mut options = [CoapOption::new(0, &[]); MAX_PATH_SEGMENTS + 2];
+        let mut count = 0;
+        if req.observe && block2.is_none() {
+            // Observe 0 registers, and is encoded as an empty value
+            options[count] = CoapOption::new(option::OBSERVE, &[]);
+            count += 1;
+        }
+        count += path_options(
+            &req.path[..req.path_len],
+            &mut options[count..count + MAX_PATH_SEGMENTS],
+        )
+        .ok_or(ReturnCode::EINVAL)?;
+        if let Some(block2) = block2 {
+            options[count] = CoapOption::new(option::BLOCK2, block2.encode(&mut block_buf));
+            count += 1;
+        }
+        self.endpoint.send(
+            req.peer,
+            req.confirmable,
+            req.code,
+            &req.token,
+            &options[..count],
+            payload,
+        )
+    }
+
+    fn start_request(&self, appid: AppId, flags: usize, payload_len: usize) -> ReturnCode {
+        let request_code = (flags & 0xff) as u8;
+        let path_len = flags >> 16;
+        if !code::is_request(request_code) || path_len > MAX_PATH_LEN {
+            return ReturnCode::EINVAL;
+        }
+        if self.request.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let mut req = ClientRequest {
+            appid: appid,
+            peer: Peer::new(IPAddr::new(), 0),
+            token: self.endpoint.new_token(),
+            code: request_code,
+            confirmable: flags & REQUEST_NON_CONFIRMABLE == 0,
+            observe: flags & REQUEST_OBSERVE != 0 && request_code == code::GET,
+            path: [0; MAX_PATH_LEN],
+            path_len: path_len,
+            message_id: 0,
+            received: 0,
+        };
+        self.apps
+            .enter(appid, |app, _| match (&app.peer, &app.path, &app.tx) {
+                (Some(peer), Some(path), tx)
+                    if peer.len() >= PEER_LEN && path_len <= path.len() =>
+                {
+                    let peer = peer.as_ref();
+                    req.peer.addr.0.copy_from_slice(&peer[..16]);
+                    req.peer.port = (peer[16] as u16) << 8 | peer[17] as u16;
+                    req.path[..path_len].copy_from_slice(&path.as_ref()[..path_len]);
+                    let payload = match tx {
+                        Some(tx) if payload_len <= tx.len() => &tx.as_ref()[..payload_len],
+                        None if payload_len == 0 => &[],
+                        _ => return ReturnCode::EINVAL,
+                    };
+                    match self.send_request(&req, payload, None) {
+                        Ok(message_id) => {
+                            req.message_id = message_id;
+                            self.request.set(req);
+                            ReturnCode::SUCCESS
+                        }
+                        Err(err) => err,
+                    }
+                }
+                _ => ReturnCode::EINVAL,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Completes the client request, passing the result to its process.
+    fn finish_request(&self, result: ReturnCode, response_code: u8, len: usize) {
+        self.request.take().map(|req| {
+            let _ = self.apps.enter(req.appid, |app, _| {
+                app.response_callback
+                    .map(|mut cb| cb.schedule(usize::from(result), response_code as usize, len));
+            });
+        });
+    }
+
+    fn cancel(&self, appid: AppId) {
+        if self.request.map_or(false, |req| req.appid == appid) {
+            self.request.clear();
+        }
+        if self.observation.map_or(false, |obs| obs.appid == appid) {
+            self.observation.clear();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapClient for CoapDriver<'a, A> {
+    fn request_received(&self, peer: Peer, msg: &Message) {
+        let found = self
+            .resources
+            .get()
+            .iter()
+            .enumerate()
+            .find_map(|(index, resource)| {
+                resource
+                    .filter(|resource| msg.path_matches(&resource.path[..resource.path_len]))
+                    .map(|resource| (index, resource))
+            });
+        let (index, resource) = match found {
+            Some(found) => found,
+            None => {
+                self.endpoint.respond(code::NOT_FOUND, &[], &[]);
+                return;
+            }
+        };
+
+        let len = match self.handle_blocks(index, &resource, peer, msg) {
+            Some(len) => len,
+            None => return,
+        };
+        let observe = match (msg.code, msg.observe()) {
+            (code::GET, Some(0)) => self.add_observer(index, peer, msg.token),
+            (code::GET, Some(1)) => {
+                self.remove_observer(peer, msg.token);
+                false
+            }
+            _ => false,
+        };
+        self.pending.set(PendingRequest {
+            resource: index,
+            block1: msg.block1(),
+            observe: observe,
+            block2_szx: msg
+                .block2()
+                .map_or(BLOCK_SZX, |block2| cmp::min(block2.szx, BLOCK_SZX)),
+        });
+        let scheduled = self
+            .apps
+            .enter(resource.appid, |app, _| {
+                app.request_callback
+                    .map_or(false, |mut cb| cb.schedule(index, msg.code as usize, len))
+            })
+            .unwrap_or(false);
+        if !scheduled {
+            self.pending.clear();
+            self.endpoint.respond(code::INTERNAL_SERVER_ERROR, &[], &[]);
+        }
+    }
+
+    fn response_received(&self, peer: Peer, msg: &Message) -> bool {
+        // Notifications of an established observation
+        if let Some(obs) = self.observation.map(|obs| *obs) {
+            if obs.peer == peer && msg.token == obs.token {
+                let len = self.write_rx(obs.appid, 0, msg.payload).unwrap_or(0);
+                let _ = self.apps.enter(obs.appid, |app, _| {
+                    app.response_callback.map(|mut cb| {
+                        cb.schedule(usize::from(ReturnCode::SUCCESS), msg.code as usize, len)
+                    });
+                });
+                return true;
+            }
+        }
+
+        let mut req = match self.request.map(|req| *req) {
+            Some(req) if req.peer == peer && msg.token == req.token => req,
+            _ => return false,
+        };
+        let block2 = msg.block2();
+        let offset = block2.map_or(0, |block2| block2.offset());
+        if offset != req.received {
+            self.finish_request(ReturnCode::FAIL, msg.code, req.received);
+            return true;
+        }
+        let written = self.write_rx(req.appid, offset, msg.payload).unwrap_or(0);
+        req.received += written;
+        if written < msg.payload.len() {
+            self.finish_request(ReturnCode::ESIZE, msg.code, req.received);
+            return true;
+        }
+
+        match block2 {
+            Some(block2) if block2.more => {
+                let next = Block::new(block2.num + 1, false, block2.szx);
+                match self.send_request(&req, &[], Some(next)) {
+                    Ok(message_id) => {
+                        req.message_id = message_id;
+                        self.request.set(req);
+                    }
+                    Err(err) => self.finish_request(err, msg.code, req.received),
+                }
+            }
+            _ => {
+                if req.observe && msg.observe().is_some() {
+                    self.observation.set(Observation {
+                        appid: req.appid,
+                        peer: req.peer,
+                        token: req.token,
+                    });
+                }
+                self.request.set(req);
+                self.finish_request(ReturnCode::SUCCESS, msg.code, req.received);
+            }
+        }
+        true
+    }
+
+    fn reset_received(&self, peer: Peer, message_id: u16) {
+        let mut observers = self.observers.get();
+        for observer in observers.iter_mut() {
+            if observer.map_or(false, |observer| {
+                observer.peer == peer && observer.last_message_id == Some(message_id)
+            }) {
+                *observer = None;
+            }
+        }
+        self.observers.set(observers);
+    }
+
+    fn send_done(&self, message_id: u16, result: ReturnCode) {
+        if let Some(notification) = self.notification.map(|n| *n) {
+            if notification.message_id == Some(message_id) {
+                let notified = notification.notified + (result == ReturnCode::SUCCESS) as usize;
+                self.notification.set(Notification {
+                    notified: notified,
+                    message_id: None,
+                    ..notification
+                });
+            }
+        }
+        let failed = self.request.map_or(false, |req| {
+            req.message_id == message_id && result != ReturnCode::SUCCESS
+        });
+        if failed {
+            self.finish_request(result, 0, 0);
+        }
+        // The endpoint can send again
+        self.notify_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CoapDriver<'a, A> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Path buffer. Holds the path of a resource to register or of a
+    ///        request to send, with segments separated by '/'.
+    /// - `1`: Receive buffer. The payloads of requests to resources of the
+    ///        process and of responses to its requests are written here.
+    /// - `2`: Transmit buffer. Holds the payloads of responses,
+    ///        notifications and requests.
+    /// - `3`: Peer buffer. Holds the destination of requests: a 16 byte
+    ///        IPv6 address followed by a 2 byte big-endian port.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0..=3 => self
+                .apps
+                .enter(appid, |app, _| {
+                    match allow_num {
+                        0 => app.path = slice,
+                        1 => app.rx = slice,
+                        2 => app.tx = slice,
+                        _ => app.peer = slice,
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Request callback. Called with the resource ID, the method
+    ///        code and the length of the payload in the receive buffer
+    ///        when a request for a resource of the process arrives.
+    /// - `1`: Response callback. Called with the result (SUCCESS, ENOACK
+    ///        if a confirmable request was not acknowledged, FAIL if it was
+    ///        reset, ESIZE if the response did not fit in the receive
+    ///        buffer), the response code and the length of the payload when
+    ///        a request completes or a notification arrives.
+    /// - `2`: Notification callback. Called with the result, the number of
+    ///        observers notified and the resource ID when notifications have
+    ///        been sent.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0..=2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    match subscribe_num {
+                        0 => app.request_callback = callback,
+                        1 => app.response_callback = callback,
+                        _ => app.notify_callback = callback,
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// CoAP control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the resource whose path is the first `arg1` bytes of
+    ///        the path buffer. Returns the resource ID.
+    /// - `2`: Unregister resource `arg1`.
+    /// - `3`: Respond to the pending request with response code `arg1` and
+    ///        the first `arg2` bytes of the transmit buffer as payload.
+    /// - `4`: Notify the observers of resource `arg1` of the representation
+    ///        in the first `arg2` bytes of the transmit buffer.
+    /// - `5`: Send a request to the peer in the peer buffer. The low byte of
+    ///        `arg1` is the method code, bit 8 makes the request
+    ///        non-confirmable, bit 9 registers the process as an observer
+    ///        of the resource (GET only), and bits 16 and up are the length
+    ///        of the path in the path buffer. `arg2` is the length of the
+    ///        payload in the transmit buffer. Returns EBUSY if a request is
+    ///        outstanding.
+    /// - `6`: Cancel the outstanding request and the observation of the
+    ///        process, if any.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.register(appid, arg1),
+            2 => {
+                if self.app_resource(appid, arg1).is_some() {
+                    self.unregister(arg1);
+                    ReturnCode::SUCCESS
+                } else {
+                    ReturnCode::EINVAL
+                }
+            }
+            3 => self.respond(appid, arg1 as u8, arg2),
+            4 => self.notify(appid, arg1, arg2),
+            5 => self.start_request(appid, arg1, arg2),
+            6 => {
+                self.cancel(appid);
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
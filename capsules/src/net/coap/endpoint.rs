@@ -0,0 +1,711 @@
+//! The CoAP message layer (RFC 7252 section 4) on top of the UDP stack.
+//!
+//! A `CoapEndpoint` sends and receives CoAP messages through a `UDPSender`
+//! and `UDPReceiver` bound to a port with the `UdpPortManager`, and all
+//! traffic is subject to its `NetworkCapability`. It takes care of:
+//!
+//! - Retransmitting confirmable messages with exponential back-off until
+//!   they are acknowledged or reset, or `MAX_RETRANSMIT` retransmissions
+//!   have been sent. One confirmable message is outstanding at a time
+//!   (NSTART = 1).
+//! - Detecting duplicate requests. A duplicate of the request last
+//!   responded to is answered with the same response; other duplicates are
+//!   ignored.
+//! - Responding to requests. The response to a confirmable request is
+//!   piggybacked on its acknowledgement if the client responds within
+//!   `PROCESSING_DELAY_MS`; otherwise the request is acknowledged and the
+//!   response is later sent as a separate confirmable message. One request
+//!   is processed at a time; others are answered with 5.03 (Service
+//!   Unavailable).
+//! - Acknowledging confirmable responses, and resetting messages the client
+//!   does not accept.
+//!
+//! Tokens are matched against requests by the client of the endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let coap = static_init!(
+//!     CoapEndpoint<'static, VirtualMuxAlarm<'static, Ast>>,
+//!     CoapEndpoint::new(
+//!         coap_send,
+//!         coap_alarm,
+//!         LeasableBuffer::new(&mut COAP_TX_BUF),
+//!         &mut COAP_OUT_BUF,
+//!         &mut COAP_RESP_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! coap_send.set_client(coap);
+//! coap_recv.set_client(coap);
+//! coap_alarm.set_alarm_client(coap);
+//! ```
+
+use crate::net::coap::message::{
+    self, code, CoapOption, Message, MessageType, COAP_HEADER_LEN, MAX_TOKEN_LEN,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+/// Size of each of the message buffers of the endpoint.
+pub const COAP_BUF_LEN: usize = 128;
+
+/// Transmission parameters (RFC 7252 section 4.8).
+pub const ACK_TIMEOUT_MS: u32 = 2000;
+pub const MAX_RETRANSMIT: u8 = 4;
+/// Time the endpoint waits for its client to respond to a confirmable
+/// request before acknowledging it and sending a separate response.
+pub const PROCESSING_DELAY_MS: u32 = 1000;
+/// Time after which a request that has not been responded to is dropped.
+pub const RESPONSE_TIMEOUT_MS: u32 = 30000;
+/// Time for which received message IDs are remembered.
+pub const EXCHANGE_LIFETIME_S: u32 = 247;
+
+/// The number of received message IDs remembered for deduplication.
+const DEDUP_SIZE: usize = 8;
+
+/// The address and UDP port of the other end of an exchange.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub addr: IPAddr,
+    pub port: u16,
+}
+
+impl Peer {
+    pub fn new(addr: IPAddr, port: u16) -> Peer {
+        Peer {
+            addr: addr,
+            port: port,
+        }
+    }
+}
+
+/// Clients of the endpoint are given the requests and responses it
+/// receives.
+pub trait CoapClient {
+    /// Called when a new request is received. The client must respond with
+    /// `CoapEndpoint::respond`, either from this call or later.
+    fn request_received(&self, peer: Peer, request: &Message);
+
+    /// Called when a response is received, either piggybacked on an
+    /// acknowledgement or in a separate message. Returns false if the
+    /// response matches no outstanding request, in which case a separate
+    /// response is reset.
+    fn response_received(&self, peer: Peer, response: &Message) -> bool;
+
+    /// Called when a Reset is received for the message `message_id`.
+    fn reset_received(&self, peer: Peer, message_id: u16);
+
+    /// Called when a message sent with `CoapEndpoint::send` completes: when
+    /// a confirmable message is acknowledged (SUCCESS), reset (FAIL) or
+    /// times out (ENOACK), or when a non-confirmable message has been
+    /// transmitted.
+    fn send_done(&self, message_id: u16, result: ReturnCode);
+}
+
+/// A request that has not been responded to yet.
+#[derive(Copy, Clone)]
+struct Exchange<T: Ticks> {
+    peer: Peer,
+    message_id: u16,
+    token: [u8; MAX_TOKEN_LEN],
+    token_len: usize,
+    confirmable: bool,
+    /// The request has been acknowledged, so the response is sent
+    /// separately
+    separate: bool,
+    deadline: T,
+}
+
+#[derive(Copy, Clone)]
+struct DedupEntry<T: Ticks> {
+    peer: Peer,
+    message_id: u16,
+    received: T,
+}
+
+/// The message in the outgoing buffer.
+#[derive(Copy, Clone)]
+struct Outgoing<T: Ticks> {
+    peer: Peer,
+    len: usize,
+    message_id: u16,
+    confirmable: bool,
+    /// The message must be (re)transmitted when the UDP sender is free
+    pending: bool,
+    retransmissions: u8,
+    timeout_ms: u32,
+    deadline: T,
+}
+
+/// The last response sent, kept to answer duplicate requests.
+#[derive(Copy, Clone)]
+struct LastResponse {
+    peer: Peer,
+    len: usize,
+    /// The message ID of the request it answers
+    request_id: u16,
+    pending: bool,
+}
+
+pub struct CoapEndpoint<'a, A: Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    /// Confirmable or non-confirmable message sent with `send`
+    out_buf: TakeCell<'static, [u8]>,
+    outgoing: OptionalCell<Outgoing<A::Ticks>>,
+    /// Response sent with `respond`
+    resp_buf: TakeCell<'static, [u8]>,
+    last_response: OptionalCell<LastResponse>,
+    /// Empty acknowledgement or reset waiting to be sent
+    control: OptionalCell<(Peer, [u8; COAP_HEADER_LEN])>,
+    /// What the UDP sender is transmitting
+    in_flight: OptionalCell<InFlight>,
+    exchange: OptionalCell<Exchange<A::Ticks>>,
+    dedup: Cell<[Option<DedupEntry<A::Ticks>>; DEDUP_SIZE]>,
+    dedup_next: Cell<usize>,
+    next_message_id: Cell<u16>,
+    client: OptionalCell<&'a dyn CoapClient>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Control,
+    Response,
+    Outgoing,
+}
+
+impl<'a, A: Alarm<'a>> CoapEndpoint<'a, A> {
+    /// `sender` must be bound to the local CoAP port, and the three buffers
+    /// should be `COAP_BUF_LEN` bytes long.
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        out_buf: &'static mut [u8],
+        resp_buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> CoapEndpoint<'a, A> {
+        CoapEndpoint {
+            sender: sender,
+            alarm: alarm,
+            net_cap: net_cap,
+            tx_buf: MapCell::new(tx_buf),
+            out_buf: TakeCell::new(out_buf),
+            outgoing: OptionalCell::empty(),
+            resp_buf: TakeCell::new(resp_buf),
+            last_response: OptionalCell::empty(),
+            control: OptionalCell::empty(),
+            in_flight: OptionalCell::empty(),
+            exchange: OptionalCell::empty(),
+            dedup: Cell::new([None; DEDUP_SIZE]),
+            dedup_next: Cell::new(0),
+            next_message_id: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoapClient) {
+        self.client.set(client);
+    }
+
+    /// Returns a message ID that has not been used recently.
+    pub fn new_message_id(&self) -> u16 {
+        let mut id = self.next_message_id.get();
+        if id == 0 {
+            // Start from an unpredictable ID
+            id = self.alarm.now().into_u32() as u16 | 1;
+        }
+        self.next_message_id.set(id.wrapping_add(1));
+        id
+    }
+
+    /// Returns a new token for a request.
+    pub fn new_token(&self) -> [u8; 4] {
+        let id = self.new_message_id() as u32;
+        let mixed = (id << 16 | self.alarm.now().into_u32() & 0xffff).wrapping_mul(0x9e37_79b9);
+        mixed.to_be_bytes()
+    }
+
+    /// Returns true if a message sent with `send` is still outstanding, in
+    /// which case another cannot be sent yet.
+    pub fn is_busy(&self) -> bool {
+        self.outgoing.is_some()
+    }
+
+    /// Sends a confirmable or non-confirmable message to `peer`, returning
+    /// its message ID. `send_done` is called when it completes. Returns
+    /// EBUSY if a previous message is still outstanding and ESIZE if the
+    /// message does not fit in a buffer.
+    pub fn send(
+        &self,
+        peer: Peer,
+        confirmable: bool,
+        code: u8,
+        token: &[u8],
+        options: &[CoapOption],
+        payload: &[u8],
+    ) -> Result<u16, ReturnCode> {
+        if self.outgoing.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        let mtype = if confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        };
+        let message_id = self.new_message_id();
+        let len = self
+            .out_buf
+            .map(|buf| message::encode(buf, mtype, code, message_id, token, options, payload))
+            .and_then(|result| result.done())
+            .map(|(len, _)| len)
+            .ok_or(ReturnCode::ESIZE)?;
+
+        // The first timeout is chosen randomly between ACK_TIMEOUT and
+        // 1.5 times ACK_TIMEOUT
+        let jitter = self.alarm.now().into_u32() % (ACK_TIMEOUT_MS / 2);
+        let timeout_ms = ACK_TIMEOUT_MS + jitter;
+        self.outgoing.set(Outgoing {
+            peer: peer,
+            len: len,
+            message_id: message_id,
+            confirmable: confirmable,
+            pending: true,
+            retransmissions: 0,
+            timeout_ms: timeout_ms,
+            deadline: self.deadline_in(timeout_ms),
+        });
+        self.transmit_next();
+        if confirmable {
+            self.rearm();
+        }
+        Ok(message_id)
+    }
+
+    /// Responds to the request last passed to `request_received`. The
+    /// response is piggybacked on the acknowledgement of a confirmable
+    /// request if possible. Returns EINVAL if there is no request to respond
+    /// to, EBUSY if a separate response cannot be sent yet because another
+    /// confirmable message is outstanding, and ESIZE if the response does
+    /// not fit in a buffer.
+    pub fn respond(&self, code: u8, options: &[CoapOption], payload: &[u8]) -> ReturnCode {
+        let exchange = match self.exchange.map(|exchange| *exchange) {
+            Some(exchange) => exchange,
+            None => return ReturnCode::EINVAL,
+        };
+        let token = &exchange.token[..exchange.token_len];
+        if exchange.separate {
+            return match self.send(exchange.peer, true, code, token, options, payload) {
+                Ok(_) => {
+                    self.exchange.clear();
+                    self.rearm();
+                    ReturnCode::SUCCESS
+                }
+                Err(result) => result,
+            };
+        }
+
+        if self.last_response.map_or(false, |last| last.pending) {
+            return ReturnCode::EBUSY;
+        }
+        let (mtype, message_id) = if exchange.confirmable {
+            (MessageType::Acknowledgement, exchange.message_id)
+        } else {
+            (MessageType::NonConfirmable, self.new_message_id())
+        };
+        let encoded = self
+            .resp_buf
+            .map(|buf| message::encode(buf, mtype, code, message_id, token, options, payload))
+            .and_then(|result| result.done());
+        match encoded {
+            Some((len, _)) => {
+                self.last_response.set(LastResponse {
+                    peer: exchange.peer,
+                    len: len,
+                    request_id: exchange.message_id,
+                    pending: true,
+                });
+                self.exchange.clear();
+                self.transmit_next();
+                self.rearm();
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ESIZE,
+        }
+    }
+
+    fn deadline_in(&self, ms: u32) -> A::Ticks {
+        self.alarm.now().wrapping_add(A::ticks_from_ms(ms))
+    }
+
+    /// Returns true if `deadline` has passed.
+    fn expired(&self, deadline: A::Ticks) -> bool {
+        // Deadlines are never more than half the tick range away, so a
+        // deadline that appears to be far in the future has passed
+        let remaining = deadline.wrapping_sub(self.alarm.now()).into_u32();
+        remaining == 0 || remaining > u32::MAX / 2
+    }
+
+    /// Arms the alarm for the earliest of the retransmission and exchange
+    /// deadlines.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let retransmit = self
+            .outgoing
+            .map(|out| *out)
+            .filter(|out| out.confirmable)
+            .map(|out| out.deadline);
+        let exchange = self.exchange.map(|exchange| exchange.deadline);
+        let deadline = match (retransmit, exchange) {
+            (Some(a), Some(b)) => {
+                if a.wrapping_sub(now).into_u32() <= b.wrapping_sub(now).into_u32() {
+                    Some(a)
+                } else {
+                    Some(b)
+                }
+            }
+            (a, b) => a.or(b),
+        };
+        match deadline {
+            Some(deadline) if !self.expired(deadline) => {
+                self.alarm.set_alarm(now, deadline.wrapping_sub(now))
+            }
+            Some(_) => self.alarm.set_alarm(now, A::Ticks::from(1)),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+
+    /// Queues an empty acknowledgement or reset for `message_id`. If one is
+    /// already waiting, the new one is dropped; the peer retransmits.
+    fn send_empty(&self, peer: Peer, mtype: MessageType, message_id: u16) {
+        if self.control.is_none() {
+            let mut msg = [0; COAP_HEADER_LEN];
+            message::encode_empty(&mut msg, mtype, message_id);
+            self.control.set((peer, msg));
+            self.transmit_next();
+        }
+    }
+
+    /// Transmits the next waiting message if the UDP sender is free:
+    /// control messages first, then responses, then other messages.
+    fn transmit_next(&self) {
+        if self.in_flight.is_some() {
+            return;
+        }
+        let mut buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        buf.reset();
+        let next = if let Some((peer, msg)) = self.control.take() {
+            buf[..COAP_HEADER_LEN].copy_from_slice(&msg);
+            Some((InFlight::Control, peer, COAP_HEADER_LEN))
+        } else if let Some(last) = self.last_response.map(|last| *last).filter(|l| l.pending) {
+            self.resp_buf
+                .map(|resp| buf[..last.len].copy_from_slice(&resp[..last.len]));
+            self.last_response.map(|last| last.pending = false);
+            Some((InFlight::Response, last.peer, last.len))
+        } else if let Some(out) = self.outgoing.map(|out| *out).filter(|out| out.pending) {
+            self.out_buf
+                .map(|msg| buf[..out.len].copy_from_slice(&msg[..out.len]));
+            self.outgoing.map(|out| out.pending = false);
+            Some((InFlight::Outgoing, out.peer, out.len))
+        } else {
+            None
+        };
+
+        match next {
+            Some((kind, peer, len)) => {
+                buf.slice(0..len);
+                match self.sender.send_to(peer.addr, peer.port, buf, self.net_cap) {
+                    Ok(()) => self.in_flight.set(kind),
+                    Err(buf) => {
+                        self.tx_buf.replace(buf);
+                        self.transmitted(kind, ReturnCode::FAIL);
+                    }
+                }
+            }
+            None => {
+                self.tx_buf.replace(buf);
+            }
+        }
+    }
+
+    /// Called when a message has been passed to the network, or failed to
+    /// be.
+    fn transmitted(&self, kind: InFlight, result: ReturnCode) {
+        if kind != InFlight::Outgoing {
+            return;
+        }
+        // Confirmable messages complete when they are acknowledged, and are
+        // retransmitted if they are lost
+        if let Some(out) = self.outgoing.map(|out| *out).filter(|out| !out.confirmable) {
+            self.outgoing.clear();
+            self.client
+                .map(|client| client.send_done(out.message_id, result));
+        }
+    }
+
+    /// Completes the outstanding confirmable message, if it has ID
+    /// `message_id`.
+    fn complete_outgoing(&self, message_id: u16, result: ReturnCode) -> bool {
+        match self.outgoing.map(|out| *out) {
+            Some(out) if out.confirmable && out.message_id == message_id => {
+                self.outgoing.clear();
+                self.rearm();
+                self.client
+                    .map(|client| client.send_done(message_id, result));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records a received message ID, returning true if it is a duplicate.
+    fn is_duplicate(&self, peer: Peer, message_id: u16) -> bool {
+        let mut entries = self.dedup.get();
+        let now = self.alarm.now();
+        let lifetime = A::ticks_from_seconds(EXCHANGE_LIFETIME_S).into_u32();
+        let duplicate = entries.iter().any(|entry| {
+            entry.map_or(false, |entry| {
+                entry.peer == peer
+                    && entry.message_id == message_id
+                    && now.wrapping_sub(entry.received).into_u32() < lifetime
+            })
+        });
+        if !duplicate {
+            let next = self.dedup_next.get();
+            entries[next] = Some(DedupEntry {
+                peer: peer,
+                message_id: message_id,
+                received: now,
+            });
+            self.dedup.set(entries);
+            self.dedup_next.set((next + 1) % DEDUP_SIZE);
+        }
+        duplicate
+    }
+
+    fn receive_request(&self, peer: Peer, msg: &Message) {
+        let confirmable = msg.mtype == MessageType::Confirmable;
+        if self.is_duplicate(peer, msg.message_id) {
+            let last = self.last_response.map(|last| *last);
+            match last {
+                Some(last) if last.peer == peer && last.request_id == msg.message_id => {
+                    self.last_response.map(|last| last.pending = true);
+                    self.transmit_next();
+                }
+                _ => {
+                    let separate = self.exchange.map_or(false, |exchange| {
+                        exchange.peer == peer
+                            && exchange.message_id == msg.message_id
+                            && exchange.separate
+                    });
+                    if separate {
+                        self.send_empty(peer, MessageType::Acknowledgement, msg.message_id);
+                    }
+                }
+            }
+            return;
+        }
+
+        if self.exchange.is_some() {
+            // Only one request is processed at a time
+            let mtype = if confirmable {
+                MessageType::Acknowledgement
+            } else {
+                MessageType::NonConfirmable
+            };
+            let message_id = if confirmable {
+                msg.message_id
+            } else {
+                self.new_message_id()
+            };
+            if !self.last_response.map_or(false, |last| last.pending) {
+                let encoded = self.resp_buf.map(|buf| {
+                    message::encode(
+                        buf,
+                        mtype,
+                        code::SERVICE_UNAVAILABLE,
+                        message_id,
+                        msg.token,
+                        &[],
+                        &[],
+                    )
+                    .done()
+                });
+                if let Some(Some((len, _))) = encoded {
+                    self.last_response.set(LastResponse {
+                        peer: peer,
+                        len: len,
+                        request_id: msg.message_id,
+                        pending: true,
+                    });
+                    self.transmit_next();
+                }
+            }
+            return;
+        }
+
+        let mut token = [0; MAX_TOKEN_LEN];
+        token[..msg.token.len()].copy_from_slice(msg.token);
+        let delay = if confirmable {
+            PROCESSING_DELAY_MS
+        } else {
+            RESPONSE_TIMEOUT_MS
+        };
+        self.exchange.set(Exchange {
+            peer: peer,
+            message_id: msg.message_id,
+            token: token,
+            token_len: msg.token.len(),
+            confirmable: confirmable,
+            separate: false,
+            deadline: self.deadline_in(delay),
+        });
+        self.rearm();
+        self.client.map(|client| client.request_received(peer, msg));
+    }
+
+    fn receive_response(&self, peer: Peer, msg: &Message) {
+        match msg.mtype {
+            MessageType::Acknowledgement => {
+                if !self.complete_outgoing(msg.message_id, ReturnCode::SUCCESS) {
+                    return;
+                }
+                // A piggybacked response
+                self.client
+                    .map(|client| client.response_received(peer, msg));
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable => {
+                let confirmable = msg.mtype == MessageType::Confirmable;
+                if self.is_duplicate(peer, msg.message_id) {
+                    if confirmable {
+                        self.send_empty(peer, MessageType::Acknowledgement, msg.message_id);
+                    }
+                    return;
+                }
+                let accepted = self
+                    .client
+                    .map_or(false, |client| client.response_received(peer, msg));
+                if !accepted {
+                    self.send_empty(peer, MessageType::Reset, msg.message_id);
+                } else if confirmable {
+                    self.send_empty(peer, MessageType::Acknowledgement, msg.message_id);
+                }
+            }
+            MessageType::Reset => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CoapEndpoint<'a, A> {
+    fn alarm(&self) {
+        // Retransmit the outstanding confirmable message
+        if let Some(out) = self.outgoing.map(|out| *out) {
+            if out.confirmable && self.expired(out.deadline) {
+                if out.retransmissions >= MAX_RETRANSMIT {
+                    self.complete_outgoing(out.message_id, ReturnCode::ENOACK);
+                } else {
+                    let timeout_ms = out.timeout_ms * 2;
+                    self.outgoing.set(Outgoing {
+                        pending: true,
+                        retransmissions: out.retransmissions + 1,
+                        timeout_ms: timeout_ms,
+                        deadline: self.deadline_in(timeout_ms),
+                        ..out
+                    });
+                    self.transmit_next();
+                }
+            }
+        }
+
+        // Acknowledge requests the client is slow to respond to, and drop
+        // those it never responds to
+        if let Some(exchange) = self.exchange.map(|exchange| *exchange) {
+            if self.expired(exchange.deadline) {
+                if exchange.confirmable && !exchange.separate {
+                    self.exchange.set(Exchange {
+                        separate: true,
+                        deadline: self.deadline_in(RESPONSE_TIMEOUT_MS),
+                        ..exchange
+                    });
+                    self.send_empty(
+                        exchange.peer,
+                        MessageType::Acknowledgement,
+                        exchange.message_id,
+                    );
+                } else {
+                    self.exchange.clear();
+                }
+            }
+        }
+        self.rearm();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for CoapEndpoint<'a, A> {
+    fn send_done(&self, result: ReturnCode, buf: LeasableBuffer<'static, u8>) {
+        self.tx_buf.replace(buf);
+        if let Some(kind) = self.in_flight.take() {
+            self.transmitted(kind, result);
+        }
+        self.transmit_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for CoapEndpoint<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let msg = match Message::decode(payload) {
+            Some(msg) => msg,
+            None => return,
+        };
+        let peer = Peer::new(src_addr, src_port);
+        if msg.code == code::EMPTY {
+            match msg.mtype {
+                MessageType::Acknowledgement => {
+                    self.complete_outgoing(msg.message_id, ReturnCode::SUCCESS);
+                }
+                MessageType::Reset => {
+                    self.complete_outgoing(msg.message_id, ReturnCode::FAIL);
+                    self.client
+                        .map(|client| client.reset_received(peer, msg.message_id));
+                }
+                // A ping: confirmable empty messages are reset
+                MessageType::Confirmable => {
+                    self.send_empty(peer, MessageType::Reset, msg.message_id)
+                }
+                MessageType::NonConfirmable => {}
+            }
+        } else if code::is_request(msg.code) {
+            match msg.mtype {
+                MessageType::Confirmable | MessageType::NonConfirmable => {
+                    self.receive_request(peer, &msg)
+                }
+                _ => {}
+            }
+        } else if code::is_response(msg.code) {
+            self.receive_response(peer, &msg);
+        }
+    }
+}
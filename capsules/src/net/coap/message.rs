@@ -0,0 +1,584 @@
+//! Encoding and decoding of CoAP messages (RFC 7252), including the Block1
+//! and Block2 options of block-wise transfers (RFC 7959) and the Observe
+//! option (RFC 7641).
+//!
+//! A decoded [Message](struct.Message.html) borrows the buffer it was
+//! decoded from; its options are checked when it is decoded and can then be
+//! iterated over. Messages are encoded in one call from a sorted slice of
+//! options.
+
+use crate::net::stream::{encode_u16, encode_u8, SResult};
+
+pub const COAP_PORT: u16 = 5683;
+pub const COAP_HEADER_LEN: usize = 4;
+pub const MAX_TOKEN_LEN: usize = 8;
+
+const COAP_VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> MessageType {
+        match bits & 0x3 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// Method and response codes, as `class << 5 | detail`.
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const CONTINUE: u8 = 0x5f;
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+    pub fn is_request(code: u8) -> bool {
+        code >= GET && code < 0x20
+    }
+
+    pub fn is_response(code: u8) -> bool {
+        code >= 0x40
+    }
+}
+
+/// Option numbers.
+pub mod option {
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const URI_QUERY: u16 = 15;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const SIZE1: u16 = 60;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CoapOption<'b> {
+    pub number: u16,
+    pub value: &'b [u8],
+}
+
+impl<'b> CoapOption<'b> {
+    pub fn new(number: u16, value: &'b [u8]) -> CoapOption<'b> {
+        CoapOption {
+            number: number,
+            value: value,
+        }
+    }
+}
+
+/// The value of a Block1 or Block2 option.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Block size exponent; the block size is `16 << szx`
+    pub szx: u8,
+}
+
+impl Block {
+    /// The largest size exponent (1024 byte blocks).
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn new(num: u32, more: bool, szx: u8) -> Block {
+        Block {
+            num: num,
+            more: more,
+            szx: szx,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// The offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn decode(value: &[u8]) -> Option<Block> {
+        let value = decode_uint(value)?;
+        let szx = (value & 0x7) as u8;
+        if szx > Block::MAX_SZX {
+            return None;
+        }
+        Some(Block::new(value >> 4, value & 0x8 != 0, szx))
+    }
+
+    /// Encodes the block into `buf`, returning the encoded option value.
+    pub fn encode<'b>(&self, buf: &'b mut [u8; 4]) -> &'b [u8] {
+        let value = self.num << 4 | (self.more as u32) << 3 | self.szx as u32;
+        encode_uint(value, buf)
+    }
+}
+
+/// Decodes an unsigned integer option value of up to 4 bytes.
+pub fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, &b| acc << 8 | b as u32))
+}
+
+/// Encodes `value` as an unsigned integer option value, using as few bytes
+/// as possible.
+pub fn encode_uint(value: u32, buf: &mut [u8; 4]) -> &[u8] {
+    *buf = value.to_be_bytes();
+    let len = 4 - value.leading_zeros() as usize / 8;
+    &buf[4 - len..]
+}
+
+/// Reads the extended form of an option delta or length nibble at `off`,
+/// returning the value and the offset past it.
+fn read_extended(buf: &[u8], off: usize, nibble: u8) -> Option<(u16, usize)> {
+    match nibble {
+        13 => Some((*buf.get(off)? as u16 + 13, off + 1)),
+        14 => {
+            let ext = (*buf.get(off)? as u16) << 8 | *buf.get(off + 1)? as u16;
+            Some((ext.checked_add(269)?, off + 2))
+        }
+        15 => None,
+        n => Some((n as u16, off)),
+    }
+}
+
+/// Reads the option at `off` following the option numbered `prev`. Returns
+/// None at the end of the options (the payload marker or the end of the
+/// buffer); otherwise returns the option and the offset past it, or Err if
+/// the option is malformed.
+fn read_option(buf: &[u8], off: usize, prev: u16) -> Result<Option<(CoapOption, usize)>, ()> {
+    let first = match buf.get(off) {
+        None | Some(&PAYLOAD_MARKER) => return Ok(None),
+        Some(&first) => first,
+    };
+    let (delta, off) = read_extended(buf, off + 1, first >> 4).ok_or(())?;
+    let (len, off) = read_extended(buf, off, first & 0xf).ok_or(())?;
+    let number = prev.checked_add(delta).ok_or(())?;
+    let value = buf.get(off..off + len as usize).ok_or(())?;
+    Ok(Some((CoapOption::new(number, value), off + len as usize)))
+}
+
+/// Iterator over the options of a decoded message.
+pub struct OptionIter<'b> {
+    buf: &'b [u8],
+    off: usize,
+    number: u16,
+}
+
+impl<'b> Iterator for OptionIter<'b> {
+    type Item = CoapOption<'b>;
+
+    fn next(&mut self) -> Option<CoapOption<'b>> {
+        match read_option(self.buf, self.off, self.number) {
+            Ok(Some((option, off))) => {
+                self.off = off;
+                self.number = option.number;
+                Some(option)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A decoded CoAP message.
+#[derive(Copy, Clone, Debug)]
+pub struct Message<'b> {
+    pub mtype: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: &'b [u8],
+    options: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl<'b> Message<'b> {
+    /// Decodes a message, checking its header and options. Returns None if
+    /// the message is malformed.
+    pub fn decode(buf: &'b [u8]) -> Option<Message<'b>> {
+        if buf.len() < COAP_HEADER_LEN || buf[0] >> 6 != COAP_VERSION {
+            return None;
+        }
+        let token_len = (buf[0] & 0xf) as usize;
+        if token_len > MAX_TOKEN_LEN {
+            return None;
+        }
+        let code = buf[1];
+        let message_id = (buf[2] as u16) << 8 | buf[3] as u16;
+        let token = buf.get(COAP_HEADER_LEN..COAP_HEADER_LEN + token_len)?;
+        let rest = &buf[COAP_HEADER_LEN + token_len..];
+
+        let mut off = 0;
+        let mut number = 0;
+        while let Some((option, next)) = read_option(rest, off, number).ok()? {
+            off = next;
+            number = option.number;
+        }
+        let payload = match rest.get(off) {
+            // A payload marker must be followed by a payload
+            Some(&PAYLOAD_MARKER) if off + 1 < rest.len() => &rest[off + 1..],
+            Some(_) => return None,
+            None => &[],
+        };
+        let msg = Message {
+            mtype: MessageType::from_bits(buf[0] >> 4),
+            code: code,
+            message_id: message_id,
+            token: token,
+            options: &rest[..off],
+            payload: payload,
+        };
+        // Empty messages carry nothing but the header
+        if code == code::EMPTY && buf.len() != COAP_HEADER_LEN {
+            return None;
+        }
+        Some(msg)
+    }
+
+    pub fn options(&self) -> OptionIter<'b> {
+        OptionIter {
+            buf: self.options,
+            off: 0,
+            number: 0,
+        }
+    }
+
+    /// Returns the value of the first option numbered `number`.
+    pub fn option(&self, number: u16) -> Option<&'b [u8]> {
+        self.options()
+            .find(|option| option.number == number)
+            .map(|option| option.value)
+    }
+
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).and_then(decode_uint)
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.option(option::BLOCK1).and_then(Block::decode)
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.option(option::BLOCK2).and_then(Block::decode)
+    }
+
+    pub fn observe(&self) -> Option<u32> {
+        self.uint_option(option::OBSERVE)
+    }
+
+    /// Returns true if the Uri-Path options of the message spell `path`,
+    /// with segments separated by '/'. Leading slashes in `path` are ignored.
+    pub fn path_matches(&self, path: &[u8]) -> bool {
+        let path = trim_leading_slashes(path);
+        let mut segments = self
+            .options()
+            .filter(|option| option.number == option::URI_PATH)
+            .map(|option| option.value);
+        if path.is_empty() {
+            return segments.next().is_none();
+        }
+        path.split(|&c| c == b'/')
+            .all(|expected| segments.next() == Some(expected))
+            && segments.next().is_none()
+    }
+}
+
+fn trim_leading_slashes(path: &[u8]) -> &[u8] {
+    let start = path.iter().position(|&c| c != b'/').unwrap_or(path.len());
+    &path[start..]
+}
+
+/// Splits `path` into Uri-Path options, stored in `options`. Returns the
+/// number of options, or None if there are too many segments.
+pub fn path_options<'b>(path: &'b [u8], options: &mut [CoapOption<'b>]) -> Option<usize> {
+    let path = trim_leading_slashes(path);
+    if path.is_empty() {
+        return Some(0);
+    }
+    let mut count = 0;
+    for segment in path.split(|&c| c == b'/') {
+        *options.get_mut(count)? = CoapOption::new(option::URI_PATH, segment);
+        count += 1;
+    }
+    Some(count)
+}
+
+/// Encodes an option delta or length nibble, returning the nibble and the
+/// extended bytes.
+fn option_nibble(value: u16) -> (u8, [u8; 2], usize) {
+    if value < 13 {
+        (value as u8, [0; 2], 0)
+    } else if value < 269 {
+        (13, [(value - 13) as u8, 0], 1)
+    } else {
+        (14, (value - 269).to_be_bytes(), 2)
+    }
+}
+
+/// Encodes a message into `buf`, returning its length. `options` must be
+/// sorted by option number.
+pub fn encode(
+    buf: &mut [u8],
+    mtype: MessageType,
+    code: u8,
+    message_id: u16,
+    token: &[u8],
+    options: &[CoapOption],
+    payload: &[u8],
+) -> SResult<usize> {
+    stream_cond!(token.len() <= MAX_TOKEN_LEN);
+    stream_len_cond!(buf, COAP_HEADER_LEN + token.len());
+    let first = COAP_VERSION << 6 | (mtype as u8) << 4 | token.len() as u8;
+    let mut off = enc_consume!(buf, 0; encode_u8, first);
+    off = enc_consume!(buf, off; encode_u8, code);
+    off = enc_consume!(buf, off; encode_u16, message_id);
+    buf[off..off + token.len()].copy_from_slice(token);
+    off += token.len();
+
+    let mut prev = 0;
+    for option in options {
+        stream_cond!(option.number >= prev && option.value.len() <= 1024);
+        let (delta, delta_ext, delta_ext_len) = option_nibble(option.number - prev);
+        let (len, len_ext, len_ext_len) = option_nibble(option.value.len() as u16);
+        let option_len = 1 + delta_ext_len + len_ext_len + option.value.len();
+        stream_len_cond!(buf, off + option_len);
+        off = enc_consume!(buf, off; encode_u8, delta << 4 | len);
+        buf[off..off + delta_ext_len].copy_from_slice(&delta_ext[..delta_ext_len]);
+        off += delta_ext_len;
+        buf[off..off + len_ext_len].copy_from_slice(&len_ext[..len_ext_len]);
+        off += len_ext_len;
+        buf[off..off + option.value.len()].copy_from_slice(option.value);
+        off += option.value.len();
+        prev = option.number;
+    }
+
+    if !payload.is_empty() {
+        stream_len_cond!(buf, off + 1 + payload.len());
+        off = enc_consume!(buf, off; encode_u8, PAYLOAD_MARKER);
+        buf[off..off + payload.len()].copy_from_slice(payload);
+        off += payload.len();
+    }
+    stream_done!(off, off);
+}
+
+/// Encodes an empty message (an empty ACK or a Reset) into `buf`.
+pub fn encode_empty(buf: &mut [u8; COAP_HEADER_LEN], mtype: MessageType, message_id: u16) {
+    buf[0] = COAP_VERSION << 6 | (mtype as u8) << 4;
+    buf[1] = code::EMPTY;
+    buf[2..4].copy_from_slice(&message_id.to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let long = [0x5a; 300];
+        let mut block = [0; 4];
+        let block2 = Block::new(20, true, 2).encode(&mut block);
+        let options = [
+            CoapOption::new(option::OBSERVE, &[]),
+            CoapOption::new(option::URI_PATH, b"sensors"),
+            CoapOption::new(option::URI_PATH, b"temp"),
+            CoapOption::new(option::CONTENT_FORMAT, &[50]),
+            CoapOption::new(option::BLOCK2, block2),
+            // A one byte extended delta, and two byte extended delta and
+            // length
+            CoapOption::new(option::SIZE1, &[4, 0]),
+            CoapOption::new(2100, &long),
+        ];
+        let mut buf = [0; 512];
+        let len = encode(
+            &mut buf,
+            MessageType::Confirmable,
+            code::CONTENT,
+            0xbeef,
+            &[1, 2, 3],
+            &options,
+            b"21.5",
+        )
+        .done()
+        .unwrap()
+        .1;
+
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(msg.mtype, MessageType::Confirmable);
+        assert_eq!(msg.code, code::CONTENT);
+        assert_eq!(msg.message_id, 0xbeef);
+        assert_eq!(msg.token, &[1, 2, 3]);
+        assert_eq!(msg.payload, b"21.5");
+        assert!(msg.options().eq(options.iter().cloned()));
+        assert!(msg.path_matches(b"/sensors/temp"));
+        assert!(!msg.path_matches(b"sensors"));
+        assert_eq!(msg.observe(), Some(0));
+        assert_eq!(msg.block2(), Some(Block::new(20, true, 2)));
+        assert_eq!(msg.block1(), None);
+        assert_eq!(msg.uint_option(option::SIZE1), Some(1024));
+
+        // Without options or payload
+        let len = encode(
+            &mut buf,
+            MessageType::Acknowledgement,
+            code::CHANGED,
+            7,
+            &[],
+            &[],
+            &[],
+        )
+        .done()
+        .unwrap()
+        .1;
+        assert_eq!(&buf[..len], &[0x60, code::CHANGED, 0, 7]);
+        let msg = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(msg.options().count(), 0);
+        assert!(msg.payload.is_empty());
+        assert!(msg.path_matches(b"/"));
+    }
+
+    #[test]
+    fn known_answer() {
+        // GET /temp with token 0x71, from RFC 7252 appendix A
+        let msg: &[u8] = &[0x41, 0x01, 0x7d, 0x34, 0x71, 0xb4, b't', b'e', b'm', b'p'];
+        let mut buf = [0; 16];
+        let path = [CoapOption::new(option::URI_PATH, b"temp")];
+        let len = encode(
+            &mut buf,
+            MessageType::Confirmable,
+            code::GET,
+            0x7d34,
+            &[0x71],
+            &path,
+            &[],
+        )
+        .done()
+        .unwrap()
+        .1;
+        assert_eq!(&buf[..len], msg);
+
+        let msg = Message::decode(msg).unwrap();
+        assert_eq!(msg.code, code::GET);
+        assert!(msg.path_matches(b"temp"));
+    }
+
+    #[test]
+    fn encode_errors() {
+        let mut buf = [0; 16];
+        // Token too long, options out of order, buffer too small
+        assert!(encode(
+            &mut buf,
+            MessageType::Reset,
+            code::EMPTY,
+            0,
+            &[0; 9],
+            &[],
+            &[]
+        )
+        .done()
+        .is_none());
+        let options = [
+            CoapOption::new(option::URI_QUERY, b"a"),
+            CoapOption::new(option::URI_PATH, b"b"),
+        ];
+        assert!(encode(
+            &mut buf,
+            MessageType::Confirmable,
+            code::GET,
+            0,
+            &[],
+            &options,
+            &[]
+        )
+        .done()
+        .is_none());
+        assert!(encode(
+            &mut buf,
+            MessageType::Confirmable,
+            code::PUT,
+            0,
+            &[],
+            &[],
+            &[0; 12]
+        )
+        .done()
+        .is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        // Too short, wrong version, token longer than 8 or than the message
+        assert!(Message::decode(&[0x40, 0x01, 0x00]).is_none());
+        assert!(Message::decode(&[0x80, 0x01, 0x00, 0x01]).is_none());
+        assert!(Message::decode(&[0x49, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(Message::decode(&[0x42, 0x01, 0x00, 0x01, 0x71]).is_none());
+
+        // Option delta or length nibble 15 (reserved)
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xf1, 0x00]).is_none());
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0x1f, 0x00]).is_none());
+        // Extended delta or length bytes missing
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xd0]).is_none());
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xe0, 0x00]).is_none());
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0x1d]).is_none());
+        // Option numbers past 65535, in one delta or two
+        assert!(
+            Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xe0, 0xfe, 0x00, 0xe0, 0x01, 0x00])
+                .is_none()
+        );
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xe0, 0xff, 0x00]).is_none());
+        // Option value past the end of the message
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xb4, b't', b'e']).is_none());
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xbd, 0x10, b't']).is_none());
+
+        // A payload marker with no payload
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xff]).is_none());
+        assert!(Message::decode(&[0x40, 0x01, 0x00, 0x01, 0xb1, b'a', 0xff]).is_none());
+        // Empty messages with a token or payload
+        assert!(Message::decode(&[0x41, 0x00, 0x00, 0x01, 0x71]).is_none());
+        assert!(Message::decode(&[0x40, 0x00, 0x00, 0x01, 0xff, 0x00]).is_none());
+        assert!(Message::decode(&[0x40, 0x00, 0x00, 0x01]).is_some());
+    }
+
+    #[test]
+    fn uint_and_block() {
+        let mut buf = [0; 4];
+        assert_eq!(encode_uint(0, &mut buf), &[]);
+        assert_eq!(encode_uint(0xff, &mut buf), &[0xff]);
+        assert_eq!(encode_uint(0x1_0000, &mut buf), &[1, 0, 0]);
+        assert_eq!(decode_uint(&[]), Some(0));
+        assert_eq!(decode_uint(&[1, 0, 0]), Some(0x1_0000));
+        assert_eq!(decode_uint(&[1, 0, 0, 0, 0]), None);
+
+        let block = Block::new(3, false, Block::MAX_SZX);
+        assert_eq!(block.size(), 1024);
+        assert_eq!(block.offset(), 3072);
+        assert_eq!(Block::decode(block.encode(&mut buf)), Some(block));
+        // Size exponent 7 is reserved
+        assert_eq!(Block::decode(&[0x17]), None);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod driver;
+pub mod endpoint;
+pub mod message;
@@ -0,0 +1,197 @@
+//! DNS userspace interface.
+//!
+//! Allows processes to resolve host names to addresses through a
+//! [DnsResolver](../resolver/trait.DnsResolver.html). Only one name can be
+//! resolved at a time across all processes; names found in the resolver's
+//! cache are answered immediately.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let dns_driver = static_init!(
+//!     capsules::net::dns::driver::DnsDriver<'static>,
+//!     capsules::net::dns::driver::DnsDriver::new(
+//!         resolver,
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! resolver.set_client(dns_driver);
+//! ```
+
+use crate::net::dns::message::{RecordType, MAX_NAME_LEN};
+use crate::net::dns::resolver::{DnsClient, DnsResolver};
+use crate::net::ipv6::ip_utils::IPAddr;
+use kernel::common::cells::OptionalCell;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Dns as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    name: Option<AppSlice<Shared, u8>>,
+    result: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct DnsDriver<'a> {
+    resolver: &'a dyn DnsResolver<'a>,
+    apps: Grant<App>,
+    outstanding: OptionalCell<(AppId, RecordType)>,
+}
+
+impl<'a> DnsDriver<'a> {
+    pub fn new(resolver: &'a dyn DnsResolver<'a>, grant: Grant<App>) -> DnsDriver<'a> {
+        DnsDriver {
+            resolver: resolver,
+            apps: grant,
+            outstanding: OptionalCell::empty(),
+        }
+    }
+
+    /// Copies `addr` into the result buffer of `app`, in its 4 byte form
+    /// for A records. Returns the number of bytes of the address.
+    fn write_result(app: &mut App, rtype: RecordType, addr: &IPAddr) -> usize {
+        let bytes = match rtype {
+            RecordType::A => &addr.0[12..16],
+            RecordType::AAAA => &addr.0[..],
+        };
+        match app.result {
+            Some(ref mut result) if result.len() >= bytes.len() => {
+                result.as_mut()[..bytes.len()].copy_from_slice(bytes);
+                bytes.len()
+            }
+            _ => 0,
+        }
+    }
+
+    fn resolve(&self, appid: AppId, name_len: usize, rtype: usize) -> ReturnCode {
+        let rtype = match RecordType::from_usize(rtype) {
+            Some(rtype) => rtype,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.outstanding.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                let mut name = [0; MAX_NAME_LEN + 1];
+                match app.name {
+                    Some(ref slice) if name_len <= slice.len() && name_len <= name.len() => {
+                        name[..name_len].copy_from_slice(&slice.as_ref()[..name_len]);
+                    }
+                    _ => return ReturnCode::EINVAL,
+                }
+                match self.resolver.resolve(&name[..name_len], rtype) {
+                    Ok(Some(addr)) => ReturnCode::SuccessWithValue {
+                        value: Self::write_result(app, rtype, &addr),
+                    },
+                    Ok(None) => {
+                        self.outstanding.set((appid, rtype));
+                        ReturnCode::SUCCESS
+                    }
+                    Err(result) => result,
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a> Driver for DnsDriver<'a> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Name buffer. Contains the dotted name to resolve.
+    /// - `1`: Result buffer. The resolved address is written here: 4 bytes
+    ///        for an A record and 16 bytes for an AAAA record.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 | 1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if allow_num == 0 {
+                        app.name = slice;
+                    } else {
+                        app.result = slice;
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Resolution callback. Called with the result (SUCCESS, FAIL if
+    ///        the name does not exist, ENOACK if no name server answered)
+    ///        and the number of bytes written to the result buffer.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// DNS control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Resolve the first `arg1` bytes of the name buffer. `arg2` is
+    ///        the record type: 1 for A or 28 for AAAA. If the answer is
+    ///        cached it is written to the result buffer immediately and its
+    ///        length is returned; otherwise SUCCESS is returned and the
+    ///        callback is called when the query completes. Returns EBUSY if
+    ///        a query is already outstanding and ENODEVICE if no name server
+    ///        is known.
+    /// - `2`: Flush the cache of resolved names.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.resolve(appid, arg1, arg2),
+            2 => {
+                self.resolver.flush_cache();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+impl<'a> DnsClient for DnsDriver<'a> {
+    fn resolved(&self, result: ReturnCode, addr: IPAddr) {
+        self.outstanding.take().map(|(appid, rtype)| {
+            let _ = self.apps.enter(appid, |app, _| {
+                let len = if result == ReturnCode::SUCCESS {
+                    Self::write_result(app, rtype, &addr)
+                } else {
+                    0
+                };
+                app.callback
+                    .map(|mut cb| cb.schedule(result.into(), len, 0));
+            });
+        });
+    }
+}
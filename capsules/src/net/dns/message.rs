@@ -0,0 +1,471 @@
+//! Encoding of DNS queries and decoding of the answers to them (RFC 1035),
+//! as needed by a stub resolver.
+//!
+//! Only single-question queries for A and AAAA records are supported. Names
+//! in responses may be compressed; CNAME and other records in the answer
+//! section are skipped, so an alias is only resolved if the server includes
+//! the address record of its target.
+
+use crate::net::ipv4::ipv4::IPv4Addr;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::{encode_u16, encode_u8, SResult};
+
+pub const DNS_PORT: u16 = 53;
+pub const DNS_HEADER_LEN: usize = 12;
+/// The largest DNS message sent or accepted over UDP.
+pub const MAX_MESSAGE_LEN: usize = 512;
+/// The longest name, in its dotted text form, that can be resolved.
+pub const MAX_NAME_LEN: usize = 64;
+
+const MAX_LABEL_LEN: usize = 63;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+const POINTER_MASK: u8 = 0xc0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordType {
+    A = 1,
+    AAAA = 28,
+}
+
+impl RecordType {
+    pub fn from_usize(rtype: usize) -> Option<RecordType> {
+        match rtype {
+            1 => Some(RecordType::A),
+            28 => Some(RecordType::AAAA),
+            _ => None,
+        }
+    }
+}
+
+/// Why a response did not produce an address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DnsError {
+    /// The response is malformed or does not answer the query.
+    Malformed,
+    /// The server reported that the name does not exist.
+    NameError,
+    /// The server reported another error.
+    ServerFailure,
+    /// The response contains no record of the requested type.
+    NoRecord,
+}
+
+/// An address obtained from a response. A records are returned as
+/// IPv4-mapped IPv6 addresses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Answer {
+    pub addr: IPAddr,
+    /// Time to live of the record, in seconds
+    pub ttl: u32,
+}
+
+/// Returns true if `name` can be encoded: it must be a non-empty dotted
+/// name of at most `MAX_NAME_LEN` bytes with no empty or overlong labels.
+/// A single trailing dot is allowed.
+pub fn is_valid_name(name: &[u8]) -> bool {
+    let name = strip_trailing_dot(name);
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .split(|&c| c == b'.')
+            .all(|label| !label.is_empty() && label.len() <= MAX_LABEL_LEN)
+}
+
+fn strip_trailing_dot(name: &[u8]) -> &[u8] {
+    match name.split_last() {
+        Some((b'.', rest)) => rest,
+        _ => name,
+    }
+}
+
+/// Encodes a recursive query for the `rtype` records of `name` into `buf`,
+/// returning its length. `name` must satisfy `is_valid_name`.
+pub fn encode_query(buf: &mut [u8], id: u16, name: &[u8], rtype: RecordType) -> SResult<usize> {
+    stream_cond!(is_valid_name(name));
+    let name = strip_trailing_dot(name);
+    // Each label gains a length byte, plus the terminating root label
+    stream_len_cond!(buf, DNS_HEADER_LEN + name.len() + 2 + 4);
+
+    let mut off = enc_consume!(buf, 0; encode_u16, id);
+    off = enc_consume!(buf, off; encode_u16, FLAG_RECURSION_DESIRED);
+    off = enc_consume!(buf, off; encode_u16, 1); // QDCOUNT
+    off = enc_consume!(buf, off; encode_u16, 0); // ANCOUNT
+    off = enc_consume!(buf, off; encode_u16, 0); // NSCOUNT
+    off = enc_consume!(buf, off; encode_u16, 0); // ARCOUNT
+    for label in name.split(|&c| c == b'.') {
+        off = enc_consume!(buf, off; encode_u8, label.len() as u8);
+        buf[off..off + label.len()].copy_from_slice(label);
+        off += label.len();
+    }
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u16, rtype as u16);
+    off = enc_consume!(buf, off; encode_u16, CLASS_IN);
+    stream_done!(off, off);
+}
+
+/// Returns the ID of a DNS message, if it is long enough to have one.
+pub fn message_id(msg: &[u8]) -> Option<u16> {
+    if msg.len() < DNS_HEADER_LEN {
+        None
+    } else {
+        Some(read_u16(msg, 0))
+    }
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    (buf[off] as u16) << 8 | buf[off + 1] as u16
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    (read_u16(buf, off) as u32) << 16 | read_u16(buf, off + 2) as u32
+}
+
+/// Returns the offset just past the (possibly compressed) name starting at
+/// `off`.
+fn skip_name(msg: &[u8], mut off: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(off)?;
+        if len & POINTER_MASK == POINTER_MASK {
+            return if off + 2 <= msg.len() {
+                Some(off + 2)
+            } else {
+                None
+            };
+        } else if len & POINTER_MASK != 0 {
+            return None;
+        } else if len == 0 {
+            return Some(off + 1);
+        }
+        off += 1 + len as usize;
+    }
+}
+
+/// Compares the (possibly compressed) name at `off` with the dotted name
+/// `name`, ignoring ASCII case.
+fn name_matches(msg: &[u8], mut off: usize, name: &[u8]) -> bool {
+    let mut labels = strip_trailing_dot(name).split(|&c| c == b'.');
+    // Bound the number of compression pointers followed, so that a
+    // malicious message cannot make this loop forever
+    let mut jumps = 0;
+    loop {
+        let len = match msg.get(off) {
+            Some(&len) => len,
+            None => return false,
+        };
+        if len & POINTER_MASK == POINTER_MASK {
+            if off + 2 > msg.len() || jumps > MAX_NAME_LEN {
+                return false;
+            }
+            jumps += 1;
+            off = (read_u16(msg, off) & 0x3fff) as usize;
+            continue;
+        } else if len & POINTER_MASK != 0 {
+            return false;
+        } else if len == 0 {
+            return labels.next().is_none();
+        }
+        let start = off + 1;
+        let end = start + len as usize;
+        match (labels.next(), msg.get(start..end)) {
+            (Some(label), Some(encoded)) if label.eq_ignore_ascii_case(encoded) => off = end,
+            _ => return false,
+        }
+    }
+}
+
+/// Decodes the response to the query with ID `id` for the `rtype` records
+/// of `name`, returning the first matching address.
+pub fn decode_response(
+    msg: &[u8],
+    id: u16,
+    name: &[u8],
+    rtype: RecordType,
+) -> Result<Answer, DnsError> {
+    if message_id(msg) != Some(id) {
+        return Err(DnsError::Malformed);
+    }
+    let flags = read_u16(msg, 2);
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(DnsError::Malformed);
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Err(DnsError::NameError),
+        _ => return Err(DnsError::ServerFailure),
+    }
+    let qdcount = read_u16(msg, 4);
+    let ancount = read_u16(msg, 6);
+    if qdcount != 1 {
+        return Err(DnsError::Malformed);
+    }
+
+    // Check that the question is the one that was asked
+    let mut off = DNS_HEADER_LEN;
+    if !name_matches(msg, off, name) {
+        return Err(DnsError::Malformed);
+    }
+    off = skip_name(msg, off).ok_or(DnsError::Malformed)?;
+    if off + 4 > msg.len() || read_u16(msg, off) != rtype as u16 {
+        return Err(DnsError::Malformed);
+    }
+    off += 4;
+
+    for _ in 0..ancount {
+        off = skip_name(msg, off).ok_or(DnsError::Malformed)?;
+        if off + 10 > msg.len() {
+            return Err(DnsError::Malformed);
+        }
+        let record_type = read_u16(msg, off);
+        let class = read_u16(msg, off + 2);
+        let ttl = read_u32(msg, off + 4);
+        let rdlength = read_u16(msg, off + 8) as usize;
+        off += 10;
+        let rdata = msg.get(off..off + rdlength).ok_or(DnsError::Malformed)?;
+        off += rdlength;
+
+        if class != CLASS_IN || record_type != rtype as u16 {
+            continue;
+        }
+        // TTLs with the top bit set are treated as zero (RFC 2181)
+        let ttl = if ttl & 0x8000_0000 != 0 { 0 } else { ttl };
+        match (rtype, rdata.len()) {
+            (RecordType::A, 4) => {
+                let addr = IPv4Addr([rdata[0], rdata[1], rdata[2], rdata[3]]);
+                return Ok(Answer {
+                    addr: addr.to_ipv6_mapped(),
+                    ttl: ttl,
+                });
+            }
+            (RecordType::AAAA, 16) => {
+                let mut addr = IPAddr::new();
+                addr.0.copy_from_slice(rdata);
+                return Ok(Answer {
+                    addr: addr,
+                    ttl: ttl,
+                });
+            }
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+    Err(DnsError::NoRecord)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    /// The response to a query for `rtype` records of example.com, with no
+    /// answers yet.
+    fn response(rtype: RecordType, flags: u16) -> Vec<u8> {
+        let mut msg = [0; MAX_MESSAGE_LEN];
+        let len = encode_query(&mut msg, ID, b"example.com", rtype)
+            .done()
+            .unwrap()
+            .1;
+        let mut msg = msg[..len].to_vec();
+        msg[2..4].copy_from_slice(&(FLAG_RESPONSE | flags).to_be_bytes());
+        msg
+    }
+
+    /// Appends a record whose name points to the question and bumps ANCOUNT.
+    fn add_answer(msg: &mut Vec<u8>, rtype: u16, ttl: u32, rdata: &[u8]) {
+        msg.extend_from_slice(&[0xc0, DNS_HEADER_LEN as u8]);
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(rdata);
+        let ancount = read_u16(msg, 6) + 1;
+        msg[6..8].copy_from_slice(&ancount.to_be_bytes());
+    }
+
+    #[test]
+    fn query() {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let len = encode_query(&mut buf, ID, b"example.com.", RecordType::AAAA)
+            .done()
+            .unwrap()
+            .1;
+        let expected: &[u8] = &[
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 7, b'e', b'x',
+            b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x1c, 0x00, 0x01,
+        ];
+        assert_eq!(&buf[..len], expected);
+        assert_eq!(message_id(&buf[..len]), Some(ID));
+
+        assert!(encode_query(&mut buf, ID, b"example..com", RecordType::A)
+            .done()
+            .is_none());
+        assert!(
+            encode_query(&mut buf[..20], ID, b"example.com", RecordType::A)
+                .done()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn a_record() {
+        let mut msg = response(RecordType::A, 0);
+        add_answer(&mut msg, RecordType::A as u16, 300, &[93, 184, 216, 34]);
+        let answer = decode_response(&msg, ID, b"EXAMPLE.com", RecordType::A).unwrap();
+        assert_eq!(answer.addr, IPv4Addr([93, 184, 216, 34]).to_ipv6_mapped());
+        assert_eq!(answer.ttl, 300);
+    }
+
+    #[test]
+    fn aaaa_record_after_cname() {
+        let mut msg = response(RecordType::AAAA, 0);
+        // A CNAME with an uncompressed target, which is skipped
+        add_answer(&mut msg, 5, 60, &[3, b'w', b'w', b'w', 0xc0, 12]);
+        let mut addr = [0; 16];
+        addr[0] = 0x20;
+        addr[1] = 0x01;
+        addr[15] = 1;
+        add_answer(&mut msg, RecordType::AAAA as u16, 0x8000_0000, &addr);
+        let answer = decode_response(&msg, ID, b"example.com", RecordType::AAAA).unwrap();
+        assert_eq!(answer.addr.0, addr);
+        // The top bit of the TTL is set, so it counts as zero
+        assert_eq!(answer.ttl, 0);
+    }
+
+    #[test]
+    fn errors() {
+        let msg = response(RecordType::A, RCODE_NAME_ERROR);
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::NameError)
+        );
+        let msg = response(RecordType::A, 2);
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::ServerFailure)
+        );
+        let msg = response(RecordType::A, 0);
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::NoRecord)
+        );
+    }
+
+    #[test]
+    fn wrong_response() {
+        let mut msg = response(RecordType::A, 0);
+        add_answer(&mut msg, RecordType::A as u16, 300, &[10, 0, 0, 1]);
+        // Another ID, name or type, or a query instead of a response
+        assert_eq!(
+            decode_response(&msg, ID + 1, b"example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+        assert_eq!(
+            decode_response(&msg, ID, b"example.org", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+        assert_eq!(
+            decode_response(&msg, ID, b"www.example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::AAAA),
+            Err(DnsError::Malformed)
+        );
+        msg[2] &= !0x80;
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+        assert_eq!(
+            decode_response(&msg[..8], ID, b"example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+    }
+
+    #[test]
+    fn truncated_record() {
+        let mut msg = response(RecordType::A, 0);
+        add_answer(&mut msg, RecordType::A as u16, 300, &[10, 0, 0, 1]);
+        // Every proper prefix that still holds the question
+        let question_end = DNS_HEADER_LEN + 13 + 4;
+        for len in question_end..msg.len() {
+            assert_eq!(
+                decode_response(&msg[..len], ID, b"example.com", RecordType::A),
+                Err(DnsError::Malformed),
+                "length {}",
+                len
+            );
+        }
+        // An address of the wrong length
+        let mut msg = response(RecordType::A, 0);
+        add_answer(&mut msg, RecordType::A as u16, 300, &[10, 0, 0]);
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+    }
+
+    #[test]
+    fn names() {
+        let msg: &[u8] = &[
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, // 0
+            3, b'w', b'w', b'w', 0xc0, 0, // 13
+            0xc0, 19, // 19: points to itself
+            0xc0, 23, 0xc0, 21, // 21: two pointers to each other
+            0x40, 0, // 25: reserved label type
+        ];
+        assert_eq!(skip_name(msg, 0), Some(13));
+        assert_eq!(skip_name(msg, 13), Some(19));
+        assert_eq!(skip_name(msg, 19), Some(21));
+        assert_eq!(skip_name(msg, 25), None);
+        assert_eq!(skip_name(&msg[..10], 0), None);
+        assert_eq!(skip_name(&msg[..18], 13), None);
+
+        assert!(name_matches(msg, 0, b"example.com"));
+        assert!(name_matches(msg, 0, b"Example.COM."));
+        assert!(!name_matches(msg, 0, b"example"));
+        assert!(!name_matches(msg, 0, b"example.com.au"));
+        assert!(name_matches(msg, 13, b"www.example.com"));
+        assert!(!name_matches(msg, 13, b"example.com"));
+        assert!(!name_matches(&msg[..18], 13, b"www.example.com"));
+        assert!(!name_matches(&msg[..10], 0, b"example.com"));
+        assert!(!name_matches(msg, 25, b"example.com"));
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let msg: &[u8] = &[0xc0, 2, 0xc0, 0];
+        assert!(!name_matches(msg, 0, b"example.com"));
+        assert!(!name_matches(&[0xc0, 0], 0, b"example.com"));
+
+        // A response whose question is a compression loop
+        let mut msg = [0; DNS_HEADER_LEN + 6].to_vec();
+        msg[0..2].copy_from_slice(&ID.to_be_bytes());
+        msg[2] = 0x80;
+        msg[5] = 1;
+        msg[12..14].copy_from_slice(&[0xc0, 12]);
+        msg[15] = 1;
+        assert_eq!(
+            decode_response(&msg, ID, b"example.com", RecordType::A),
+            Err(DnsError::Malformed)
+        );
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(is_valid_name(b"example.com"));
+        assert!(is_valid_name(b"localhost."));
+        assert!(!is_valid_name(b""));
+        assert!(!is_valid_name(b"."));
+        assert!(!is_valid_name(b".example.com"));
+        assert!(!is_valid_name(&[b'a'; MAX_NAME_LEN + 1]));
+        assert!(!is_valid_name(&[b'a'; MAX_LABEL_LEN + 1]));
+    }
+}
@@ -0,0 +1,450 @@
+//! A DNS stub resolver with a small cache of answers.
+//!
+//! The resolver sends recursive queries to the name servers it has been
+//! given, either by the board or by a DHCP client through the
+//! [DnsConfig](trait.DnsConfig.html) trait. Queries are sent over UDP on
+//! IPv4 (through a `UDP4Socket`) to servers with IPv4-mapped addresses, and
+//! over the 6LoWPAN UDP stack (through a `UDPSender`) to all other servers,
+//! so a resolver only needs the socket types its servers are reachable on.
+//!
+//! One query is outstanding at a time. An unanswered query is retransmitted
+//! every `DNS_RETRY_MS`, rotating through the configured servers, and fails
+//! with ENOACK after `DNS_MAX_ATTEMPTS` transmissions. Answers are cached
+//! for their time to live, capped at `MAX_CACHE_TTL` seconds.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let resolver = static_init!(
+//!     StubResolver<'static, VirtualMuxAlarm<'static, LiteXAlarm>>,
+//!     StubResolver::new(dns_alarm, LeasableBuffer::new(&mut DNS_BUF), net_cap)
+//! );
+//! dns_alarm.set_alarm_client(resolver);
+//! resolver.set_udp4_socket(dns_socket);
+//! dns_socket.set_send_client(resolver);
+//! dns_socket.set_receive_client(resolver);
+//! dhcp_client.set_dns_config(resolver);
+//! ```
+
+use crate::net::dns::message::{
+    decode_response, encode_query, is_valid_name, message_id, DnsError, RecordType, DNS_PORT,
+    MAX_NAME_LEN,
+};
+use crate::net::ipv4::ipv4::IPv4Addr;
+use crate::net::ipv4::udp4::{UDP4RecvClient, UDP4SendClient, UDP4Socket};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::ReturnCode;
+
+/// The number of name servers the resolver keeps.
+pub const MAX_DNS_SERVERS: usize = 2;
+/// The smallest transmit buffer that can hold a query for any name.
+pub const DNS_BUF_LEN: usize = MAX_NAME_LEN + 18;
+/// The number of answers kept in the cache.
+pub const DNS_CACHE_SIZE: usize = 4;
+/// The longest time an answer is cached, in seconds.
+pub const MAX_CACHE_TTL: u32 = 3600;
+/// Time to wait for a response before retransmitting a query.
+pub const DNS_RETRY_MS: u32 = 2000;
+/// Number of times a query is sent before giving up.
+pub const DNS_MAX_ATTEMPTS: u8 = 4;
+
+/// Interval at which the cache is aged while it is not empty, in seconds.
+/// This is short enough that the alarm counter cannot wrap in between.
+const CACHE_AGE_INTERVAL: u32 = 60;
+
+/// Implemented by users of a resolver to learn the result of a query.
+pub trait DnsClient {
+    /// Called when a query started by `DnsResolver::resolve` completes.
+    /// `addr` is only meaningful if `result` is SUCCESS, and A records are
+    /// returned as IPv4-mapped addresses. `result` is FAIL if the name or
+    /// record does not exist and ENOACK if no server answered.
+    fn resolved(&self, result: ReturnCode, addr: IPAddr);
+}
+
+pub trait DnsResolver<'a> {
+    fn set_client(&self, client: &'a dyn DnsClient);
+
+    /// Looks up the `rtype` record of `name`, a dotted name such as
+    /// `b"example.com"`. Returns the address immediately if it is cached,
+    /// or `Ok(None)` if a query was started and will complete in
+    /// `DnsClient::resolved`. Returns EINVAL if the name is malformed,
+    /// EBUSY if a query is already outstanding and ENODEVICE if no name
+    /// server is configured.
+    fn resolve(&self, name: &[u8], rtype: RecordType) -> Result<Option<IPAddr>, ReturnCode>;
+
+    /// Removes all entries from the cache.
+    fn flush_cache(&self);
+}
+
+/// Configuration of the name servers a resolver queries.
+pub trait DnsConfig {
+    /// Replaces the name servers with the first `MAX_DNS_SERVERS` entries
+    /// of `servers`. IPv4 servers are given as IPv4-mapped addresses.
+    fn set_servers(&self, servers: &[IPAddr]);
+}
+
+#[derive(Copy, Clone)]
+struct CacheEntry {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    rtype: RecordType,
+    addr: IPAddr,
+    /// Remaining time to live, in seconds
+    ttl: u32,
+}
+
+impl CacheEntry {
+    fn matches(&self, name: &[u8], rtype: RecordType) -> bool {
+        self.rtype == rtype && self.name[..self.name_len].eq_ignore_ascii_case(name)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Query {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    rtype: RecordType,
+    id: u16,
+    server: IPAddr,
+    server_index: usize,
+    attempts: u8,
+}
+
+impl Query {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+pub struct StubResolver<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    udp4: OptionalCell<&'a UDP4Socket<'a>>,
+    udp6: OptionalCell<&'a dyn UDPSender<'a>>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    servers: Cell<[Option<IPAddr>; MAX_DNS_SERVERS]>,
+    query: OptionalCell<Query>,
+    cache: [Cell<Option<CacheEntry>>; DNS_CACHE_SIZE],
+    aged_at: Cell<A::Ticks>,
+    next_id: Cell<u16>,
+    client: OptionalCell<&'a dyn DnsClient>,
+}
+
+impl<'a, A: Alarm<'a>> StubResolver<'a, A> {
+    /// `tx_buf` should hold at least `DNS_BUF_LEN` bytes.
+    pub fn new(
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> StubResolver<'a, A> {
+        StubResolver {
+            alarm: alarm,
+            udp4: OptionalCell::empty(),
+            udp6: OptionalCell::empty(),
+            tx_buf: MapCell::new(tx_buf),
+            net_cap: net_cap,
+            servers: Cell::new([None; MAX_DNS_SERVERS]),
+            query: OptionalCell::empty(),
+            cache: Default::default(),
+            aged_at: Cell::new(A::Ticks::from(0)),
+            next_id: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Sets the socket used to reach name servers over IPv4.
+    pub fn set_udp4_socket(&self, socket: &'a UDP4Socket<'a>) {
+        self.udp4.set(socket);
+    }
+
+    /// Sets the sender used to reach name servers over IPv6.
+    pub fn set_udp6_sender(&self, sender: &'a dyn UDPSender<'a>) {
+        self.udp6.set(sender);
+    }
+
+    /// Subtracts the whole seconds elapsed since the cache was last aged
+    /// from the time to live of every entry, dropping expired ones.
+    fn age_cache(&self) {
+        let elapsed = self.alarm.now().wrapping_sub(self.aged_at.get());
+        let seconds = elapsed.into_u32() / A::Frequency::frequency();
+        if seconds == 0 {
+            return;
+        }
+        self.aged_at.set(
+            self.aged_at
+                .get()
+                .wrapping_add(A::ticks_from_seconds(seconds)),
+        );
+        for entry in self.cache.iter() {
+            entry.set(entry.get().and_then(|mut e| {
+                if e.ttl > seconds {
+                    e.ttl -= seconds;
+                    Some(e)
+                } else {
+                    None
+                }
+            }));
+        }
+    }
+
+    fn cache_lookup(&self, name: &[u8], rtype: RecordType) -> Option<IPAddr> {
+        self.cache
+            .iter()
+            .filter_map(|entry| entry.get())
+            .find(|entry| entry.matches(name, rtype))
+            .map(|entry| entry.addr)
+    }
+
+    fn cache_insert(&self, query: &Query, addr: IPAddr, ttl: u32) {
+        let ttl = core::cmp::min(ttl, MAX_CACHE_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let new_entry = Some(CacheEntry {
+            name: query.name,
+            name_len: query.name_len,
+            rtype: query.rtype,
+            addr: addr,
+            ttl: ttl,
+        });
+        // Reuse the entry for this name, else a free one, else the one
+        // closest to expiring
+        let slot = self
+            .cache
+            .iter()
+            .find(|entry| {
+                entry
+                    .get()
+                    .map_or(false, |e| e.matches(query.name(), query.rtype))
+            })
+            .or_else(|| self.cache.iter().find(|entry| entry.get().is_none()))
+            .or_else(|| {
+                self.cache
+                    .iter()
+                    .min_by_key(|entry| entry.get().map_or(0, |e| e.ttl))
+            });
+        slot.map(|entry| entry.set(new_entry));
+    }
+
+    /// Returns the next configured server at or after `index`, wrapping
+    /// around.
+    fn next_server(&self, index: usize) -> Option<(usize, IPAddr)> {
+        let servers = self.servers.get();
+        (0..MAX_DNS_SERVERS)
+            .map(|i| (index + i) % MAX_DNS_SERVERS)
+            .find_map(|i| servers[i].map(|server| (i, server)))
+    }
+
+    /// Sends the outstanding query to its current server. Transmission
+    /// errors are not reported; the query is retransmitted when the retry
+    /// timer fires.
+    fn send_query(&self) {
+        let query = match self.query.map(|query| *query) {
+            Some(query) => query,
+            None => return,
+        };
+        self.tx_buf.take().map(|mut buf| {
+            let len = match encode_query(&mut buf[..], query.id, query.name(), query.rtype).done() {
+                Some((len, _)) => len,
+                None => {
+                    self.tx_buf.replace(buf);
+                    return;
+                }
+            };
+            buf.slice(0..len);
+
+            let result = match IPv4Addr::from_ipv6_mapped(&query.server) {
+                Some(server) => match self.udp4.map(|socket| *socket) {
+                    Some(socket) => socket.send_to(server, DNS_PORT, buf, self.net_cap),
+                    None => Err(buf),
+                },
+                None => match self.udp6.map(|sender| *sender) {
+                    Some(sender) => sender.send_to(query.server, DNS_PORT, buf, self.net_cap),
+                    None => Err(buf),
+                },
+            };
+            if let Err(mut buf) = result {
+                buf.reset();
+                self.tx_buf.replace(buf);
+            }
+        });
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(DNS_RETRY_MS));
+    }
+
+    /// Arms the alarm to age the cache, if no query is outstanding and the
+    /// cache is not empty.
+    fn arm_cache_timer(&self) {
+        if self.query.is_none() && self.cache.iter().any(|entry| entry.get().is_some()) {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_seconds(CACHE_AGE_INTERVAL));
+        }
+    }
+
+    fn complete(&self, result: ReturnCode, addr: IPAddr) {
+        self.query.clear();
+        self.alarm.disarm();
+        self.arm_cache_timer();
+        self.client.map(|client| client.resolved(result, addr));
+    }
+
+    fn receive_response(&self, src_addr: IPAddr, src_port: u16, payload: &[u8]) {
+        let query = match self.query.map(|query| *query) {
+            Some(query) => query,
+            None => return,
+        };
+        if src_port != DNS_PORT || message_id(payload) != Some(query.id) {
+            return;
+        }
+        // Responses to earlier transmissions of the query to another server
+        // are accepted too
+        let servers = self.servers.get();
+        if !servers.iter().any(|server| *server == Some(src_addr)) {
+            return;
+        }
+        match decode_response(payload, query.id, query.name(), query.rtype) {
+            Ok(answer) => {
+                self.age_cache();
+                self.cache_insert(&query, answer.addr, answer.ttl);
+                self.complete(ReturnCode::SUCCESS, answer.addr);
+            }
+            Err(DnsError::NameError) | Err(DnsError::NoRecord) => {
+                self.complete(ReturnCode::FAIL, IPAddr::new())
+            }
+            // Wait for another server to answer
+            Err(DnsError::ServerFailure) | Err(DnsError::Malformed) => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> DnsResolver<'a> for StubResolver<'a, A> {
+    fn set_client(&self, client: &'a dyn DnsClient) {
+        self.client.set(client);
+    }
+
+    fn resolve(&self, name: &[u8], rtype: RecordType) -> Result<Option<IPAddr>, ReturnCode> {
+        if !is_valid_name(name) {
+            return Err(ReturnCode::EINVAL);
+        }
+        let name = match name.split_last() {
+            Some((b'.', rest)) => rest,
+            _ => name,
+        };
+        self.age_cache();
+        if let Some(addr) = self.cache_lookup(name, rtype) {
+            return Ok(Some(addr));
+        }
+        if self.query.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        let (server_index, server) = self.next_server(0).ok_or(ReturnCode::ENODEVICE)?;
+
+        let id = self
+            .next_id
+            .get()
+            .wrapping_add(self.alarm.now().into_u32() as u16);
+        self.next_id.set(id.wrapping_add(1));
+        let mut query = Query {
+            name: [0; MAX_NAME_LEN],
+            name_len: name.len(),
+            rtype: rtype,
+            id: id,
+            server: server,
+            server_index: server_index,
+            attempts: 1,
+        };
+        query.name[..name.len()].copy_from_slice(name);
+        self.query.set(query);
+        self.send_query();
+        Ok(None)
+    }
+
+    fn flush_cache(&self) {
+        for entry in self.cache.iter() {
+            entry.set(None);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> DnsConfig for StubResolver<'a, A> {
+    fn set_servers(&self, servers: &[IPAddr]) {
+        let mut new_servers = [None; MAX_DNS_SERVERS];
+        for (slot, server) in new_servers.iter_mut().zip(
+            servers
+                .iter()
+                .filter(|server| !server.is_unspecified() && !server.is_multicast()),
+        ) {
+            *slot = Some(*server);
+        }
+        if new_servers != self.servers.get() {
+            // Answers from the previous servers may not be valid on the new
+            // network
+            self.servers.set(new_servers);
+            self.flush_cache();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for StubResolver<'a, A> {
+    fn alarm(&self) {
+        self.age_cache();
+        match self.query.map(|query| *query) {
+            Some(query) if query.attempts >= DNS_MAX_ATTEMPTS => {
+                self.complete(ReturnCode::ENOACK, IPAddr::new());
+            }
+            Some(mut query) => match self.next_server(query.server_index + 1) {
+                Some((server_index, server)) => {
+                    query.server_index = server_index;
+                    query.server = server;
+                    query.attempts += 1;
+                    self.query.set(query);
+                    self.send_query();
+                }
+                None => self.complete(ReturnCode::ENODEVICE, IPAddr::new()),
+            },
+            None => self.arm_cache_timer(),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDP4SendClient for StubResolver<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        // Failed transmissions are retried by the retry timer
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDP4RecvClient for StubResolver<'a, A> {
+    fn receive(&self, src_addr: IPv4Addr, src_port: u16, _dst_port: u16, payload: &[u8]) {
+        self.receive_response(src_addr.to_ipv6_mapped(), src_port, payload);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for StubResolver<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for StubResolver<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        self.receive_response(src_addr, src_port, payload);
+    }
+}
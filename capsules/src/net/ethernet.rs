@@ -0,0 +1,88 @@
+//! Implements Ethernet II header encoding and decoding.
+//!
+//! Only untagged frames are supported. Frames carrying an 802.1Q tag decode
+//! with the TPID as their EtherType and are ignored by the protocols above.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16};
+use crate::net::stream::{encode_bytes, encode_u16};
+
+/// Length of an untagged Ethernet II header.
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Smallest frame (excluding the frame check sequence) that may be put on
+/// the wire. Shorter frames are padded with zeros before transmission.
+pub const MIN_FRAME_LEN: usize = 60;
+
+/// Largest payload carried in a single untagged frame.
+pub const MTU: usize = 1500;
+
+pub mod ethertype {
+    pub const IPV4: u16 = 0x0800;
+    pub const ARP: u16 = 0x0806;
+    pub const IPV6: u16 = 0x86DD;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+
+    pub fn new() -> MacAddr {
+        MacAddr([0; 6])
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddr::BROADCAST
+    }
+
+    /// Group addresses (including broadcast) have the least significant bit
+    /// of the first octet set.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        encode_bytes(buf, &self.0)
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<MacAddr> {
+        let mut addr = MacAddr::new();
+        let off = dec_consume!(buf; decode_bytes, &mut addr.0);
+        stream_done!(off, addr);
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct EthernetHeader {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    pub fn new(dst: MacAddr, src: MacAddr, ethertype: u16) -> EthernetHeader {
+        EthernetHeader {
+            dst: dst,
+            src: src,
+            ethertype: ethertype,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, ETHERNET_HEADER_LEN);
+        let off = enc_consume!(buf; self.dst; encode);
+        let off = enc_consume!(buf, off; self.src; encode);
+        let off = enc_consume!(buf, off; encode_u16, self.ethertype);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<EthernetHeader> {
+        stream_len_cond!(buf, ETHERNET_HEADER_LEN);
+        let (off, dst) = dec_try!(buf; MacAddr::decode);
+        let (off, src) = dec_try!(buf, off; MacAddr::decode);
+        let (off, ethertype) = dec_try!(buf, off; decode_u16);
+        stream_done!(off, EthernetHeader::new(dst, src, ethertype));
+    }
+}
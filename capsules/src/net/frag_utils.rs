@@ -54,9 +54,13 @@ impl Bitmap {
             result
         } else {
             let mut result = (self.map[start_byte_idx] & first) == 0;
-            result = result && ((self.map[end_byte_idx] & second) == 0);
             self.map[start_byte_idx] |= first;
-            self.map[end_byte_idx] |= second;
+            // The end byte is not touched if the range ends on a byte
+            // boundary, in which case it may be past the end of the map.
+            if second != 0 {
+                result = result && ((self.map[end_byte_idx] & second) == 0);
+                self.map[end_byte_idx] |= second;
+            }
             // Set all bytes between start and end bytes.
             for i in start_byte_idx + 1..end_byte_idx {
                 result = result && (self.map[i] == 0);
@@ -71,9 +75,11 @@ impl Bitmap {
         for i in 0..total_length / 8 {
             result = result && (self.map[i] == 0xff);
         }
-        // Check last byte.
-        let mask = 0xff >> (8 - (total_length % 8));
-        result = result && (self.map[total_length / 8] == mask);
+        // Check last byte, if it is partially used.
+        if total_length % 8 != 0 {
+            let mask = 0xff >> (8 - (total_length % 8));
+            result = result && (self.map[total_length / 8] == mask);
+        }
         result
     }
 }
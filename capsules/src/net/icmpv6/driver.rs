@@ -0,0 +1,270 @@
+//! ICMPv6 ping userspace interface.
+//!
+//! Allows processes to send ICMPv6 Echo Requests and be notified of the
+//! matching Echo Reply, together with the round-trip time. Only one echo
+//! request can be outstanding at a time across all processes; a request that
+//! is not answered within its timeout is reported to the process as failed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ping_driver = static_init!(
+//!     capsules::net::icmpv6::driver::PingDriver<'static, VirtualMuxAlarm<'static, Ast>>,
+//!     capsules::net::icmpv6::driver::PingDriver::new(
+//!         icmp_send_struct,
+//!         ping_alarm,
+//!         LeasableBuffer::new(&mut PING_BUF),
+//!         board_kernel.create_grant(&grant_cap),
+//!         net_cap,
+//!     )
+//! );
+//! icmp_send_struct.set_client(ping_driver);
+//! icmp_receiver.set_client(ping_driver);
+//! ping_alarm.set_alarm_client(ping_driver);
+//! ```
+
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use core::mem;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Ping as usize;
+
+/// Identifier placed in all echo requests sent by this driver.
+const PING_ID: u16 = 0x7063;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    dest: Option<AppSlice<Shared, u8>>,
+}
+
+#[derive(Copy, Clone)]
+struct Outstanding<T: Ticks> {
+    appid: AppId,
+    dest: IPAddr,
+    seqno: u16,
+    start: T,
+}
+
+pub struct PingDriver<'a, A: Alarm<'a>> {
+    sender: &'a dyn ICMP6Sender<'a>,
+    alarm: &'a A,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    apps: Grant<App>,
+    outstanding: OptionalCell<Outstanding<A::Ticks>>,
+    next_seqno: Cell<u16>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> PingDriver<'a, A> {
+    pub fn new(
+        sender: &'a dyn ICMP6Sender<'a>,
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        grant: Grant<App>,
+        net_cap: &'static NetworkCapability,
+    ) -> PingDriver<'a, A> {
+        PingDriver {
+            sender: sender,
+            alarm: alarm,
+            tx_buf: MapCell::new(tx_buf),
+            apps: grant,
+            outstanding: OptionalCell::empty(),
+            next_seqno: Cell::new(0),
+            net_cap: net_cap,
+        }
+    }
+
+    fn send_request(&self, appid: AppId, len: usize, timeout_ms: usize) -> ReturnCode {
+        if self.outstanding.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let dest = match self.apps.enter(appid, |app, _| {
+            app.dest.as_ref().and_then(|cfg| {
+                if cfg.len() != mem::size_of::<IPAddr>() {
+                    return None;
+                }
+                let mut dest = IPAddr::new();
+                dest.0.copy_from_slice(cfg.as_ref());
+                Some(dest)
+            })
+        }) {
+            Ok(Some(dest)) => dest,
+            Ok(None) => return ReturnCode::EINVAL,
+            Err(err) => return err.into(),
+        };
+        if dest.is_unspecified() || timeout_ms == 0 {
+            return ReturnCode::EINVAL;
+        }
+
+        self.tx_buf.take().map_or(ReturnCode::EBUSY, |mut buf| {
+            if len > buf.len() {
+                self.tx_buf.replace(buf);
+                return ReturnCode::ESIZE;
+            }
+            // Fill the echo data with an incrementing pattern
+            for i in 0..len {
+                buf[i] = i as u8;
+            }
+            buf.slice(0..len);
+
+            let seqno = self.next_seqno.get();
+            self.next_seqno.set(seqno.wrapping_add(1));
+            let mut header = ICMP6Header::new(ICMP6Type::Type128);
+            header.set_options(ICMP6HeaderOptions::Type128 { id: PING_ID, seqno });
+
+            match self.sender.send(dest, header, buf, self.net_cap) {
+                Ok(()) => {
+                    let now = self.alarm.now();
+                    self.outstanding.set(Outstanding {
+                        appid: appid,
+                        dest: dest,
+                        seqno: seqno,
+                        start: now,
+                    });
+                    self.alarm
+                        .set_alarm(now, A::ticks_from_ms(timeout_ms as u32));
+                    ReturnCode::SUCCESS
+                }
+                Err(mut buf) => {
+                    buf.reset();
+                    self.tx_buf.replace(buf);
+                    ReturnCode::FAIL
+                }
+            }
+        })
+    }
+
+    fn complete(&self, result: ReturnCode, rtt_ms: usize) {
+        self.outstanding.take().map(|outstanding| {
+            let _ = self.apps.enter(outstanding.appid, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(result.into(), outstanding.seqno as usize, rtt_ms));
+            });
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for PingDriver<'a, A> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Destination buffer. Contains the 16 byte IPv6 address that
+    ///        echo requests are sent to.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.dest = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Echo completion callback. Called with the result (SUCCESS,
+    ///        ENOACK if no reply arrived before the timeout, or the error
+    ///        returned while sending), the sequence number of the request
+    ///        and the round-trip time in milliseconds.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Ping control.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Send an echo request to the address in the destination buffer.
+    ///        `arg1` is the number of bytes of echo data and `arg2` the
+    ///        timeout in milliseconds. Returns EBUSY if an echo request is
+    ///        already outstanding, and ESIZE if the echo data does not fit in
+    ///        the kernel buffer.
+    /// - `2`: Return the maximum number of bytes of echo data.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.send_request(appid, arg1, arg2),
+            2 => ReturnCode::SuccessWithValue {
+                value: self.tx_buf.map_or(0, |buf| buf.len()),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for PingDriver<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, _payload: &[u8]) {
+        if let ICMP6HeaderOptions::Type129 { id, seqno } = icmp_header.get_options() {
+            let matches = self.outstanding.map_or(false, |outstanding| {
+                id == PING_ID
+                    && seqno == outstanding.seqno
+                    && (ip_header.get_src_addr() == outstanding.dest
+                        || outstanding.dest.is_multicast())
+            });
+            if matches {
+                self.alarm.disarm();
+                let elapsed = self.outstanding.map_or(0, |outstanding| {
+                    self.alarm.now().wrapping_sub(outstanding.start).into_u32()
+                });
+                let rtt_ms = (elapsed as u64 * 1000 / A::Frequency::frequency() as u64) as usize;
+                self.complete(ReturnCode::SUCCESS, rtt_ms);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6SendClient for PingDriver<'a, A> {
+    fn send_done(&self, result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        buf.reset();
+        self.tx_buf.replace(buf);
+        if result != ReturnCode::SUCCESS {
+            self.alarm.disarm();
+            self.complete(result, 0);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for PingDriver<'a, A> {
+    fn alarm(&self) {
+        self.complete(ReturnCode::ENOACK, 0);
+    }
+}
@@ -0,0 +1,108 @@
+//! ICMPv6 echo responder.
+//!
+//! Replies to ICMPv6 Echo Requests (RFC 4443 section 4.1) addressed to this
+//! node, either by unicast or to the link-local all-nodes multicast address.
+//! The echoed data is copied into a buffer owned by the responder, so
+//! requests that arrive while a previous reply is still being sent, or whose
+//! data does not fit in that buffer, are dropped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let echo_responder = static_init!(
+//!     ICMP6EchoResponder<'static>,
+//!     ICMP6EchoResponder::new(icmp_send_struct, LeasableBuffer::new(&mut ECHO_BUF), net_cap)
+//! );
+//! icmp_send_struct.set_client(echo_responder);
+//! icmp_receiver.set_client(echo_responder);
+//! ```
+
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+pub struct ICMP6EchoResponder<'a> {
+    sender: &'a dyn ICMP6Sender<'a>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    enabled: Cell<bool>,
+    replies_sent: Cell<usize>,
+}
+
+impl<'a> ICMP6EchoResponder<'a> {
+    pub fn new(
+        sender: &'a dyn ICMP6Sender<'a>,
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ICMP6EchoResponder<'a> {
+        ICMP6EchoResponder {
+            sender: sender,
+            tx_buf: MapCell::new(tx_buf),
+            net_cap: net_cap,
+            enabled: Cell::new(true),
+            replies_sent: Cell::new(0),
+        }
+    }
+
+    /// Enables or disables replying to echo requests.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    /// Returns the number of echo replies sent so far.
+    pub fn replies_sent(&self) -> usize {
+        self.replies_sent.get()
+    }
+}
+
+impl<'a> ICMP6RecvClient for ICMP6EchoResponder<'a> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        let (id, seqno) = match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type128 { id, seqno } => (id, seqno),
+            _ => return,
+        };
+        let dst = ip_header.get_dst_addr();
+        if !self.enabled.get() || (dst.is_multicast() && dst != IPAddr::all_nodes()) {
+            return;
+        }
+        let src = ip_header.get_src_addr();
+        if src.is_unspecified() || src.is_multicast() {
+            return;
+        }
+
+        self.tx_buf.take().map(|mut buf| {
+            if payload.len() > buf.len() {
+                // Echo data too long to reply to
+                self.tx_buf.replace(buf);
+                return;
+            }
+            buf[..payload.len()].copy_from_slice(payload);
+            buf.slice(0..payload.len());
+
+            let mut reply = ICMP6Header::new(ICMP6Type::Type129);
+            reply.set_options(ICMP6HeaderOptions::Type129 { id, seqno });
+            if let Err(mut buf) = self.sender.send(src, reply, buf, self.net_cap) {
+                buf.reset();
+                self.tx_buf.replace(buf);
+            }
+        });
+    }
+}
+
+impl<'a> ICMP6SendClient for ICMP6EchoResponder<'a> {
+    fn send_done(&self, result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        if result == ReturnCode::SUCCESS {
+            self.replies_sent.set(self.replies_sent.get() + 1);
+        }
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
@@ -0,0 +1,655 @@
+//! 6LoWPAN Neighbor Discovery host (RFC 6775).
+//!
+//! Implements the host side of Neighbor Discovery as optimized for 6LoWPAN
+//! networks. Rather than relying on multicast address resolution, a host:
+//!
+//! 1. Sends Router Solicitations (RS) to the all-routers multicast address
+//!    until it receives a Router Advertisement (RA).
+//! 2. Learns its default router, and any on-link prefix with the autonomous
+//!    flag set, from the RA. A global address is formed from the prefix and
+//!    the interface identifier of the link-local address, unless an
+//!    address assigned by DHCPv6 has been set with `set_managed_address`.
+//! 3. Registers that address with the router by sending a unicast Neighbor
+//!    Solicitation (NS) carrying an Address Registration Option (ARO), and
+//!    waits for a Neighbor Advertisement (NA) from the router with a successful
+//!    status.
+//! 4. Re-registers before the registration lifetime expires. If the router
+//!    stops responding, the host falls back to soliciting routers.
+//!
+//! The host also answers Neighbor Solicitations for its own addresses, so
+//! neighbors that perform classic (RFC 4861) address resolution can reach it.
+//!
+//! Every `IP6Sender` passed to the constructor is kept up to date: its
+//! gateway is set to the link-layer address of the default router, and its
+//! source address to the address being registered.
+//!
+//! Timers longer than a minute are split into several alarms, so that long
+//! lifetimes do not overflow the tick counter of low-frequency alarms.
+
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6_send::IP6Sender;
+use crate::net::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::util::SecondsTimer;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// Protocol constants from RFC 4861 and RFC 6775, in seconds.
+pub const RTR_SOLICITATION_INTERVAL: u32 = 10;
+pub const MAX_RTR_SOLICITATIONS: u8 = 3;
+pub const MAX_RTR_SOLICITATION_INTERVAL: u32 = 60;
+pub const RETRANS_TIMER: u32 = 1;
+pub const MAX_UNICAST_SOLICIT: u8 = 3;
+
+/// Default address registration lifetime, in units of 60 seconds.
+pub const DEFAULT_REGISTRATION_LIFETIME: u16 = 15;
+
+/// Minimum buffer size needed to build any of the messages sent by the host.
+pub const ND_BUF_LEN: usize = 64;
+
+/// Neighbor Discovery option types.
+pub mod option_type {
+    pub const SOURCE_LL_ADDR: u8 = 1;
+    pub const TARGET_LL_ADDR: u8 = 2;
+    pub const PREFIX_INFO: u8 = 3;
+    pub const ADDR_REGISTRATION: u8 = 33;
+}
+
+/// Flags of a Neighbor Advertisement.
+pub mod na_flags {
+    pub const SOLICITED: u32 = 1 << 30;
+    pub const OVERRIDE: u32 = 1 << 29;
+}
+
+const PIO_AUTONOMOUS: u8 = 0x40;
+const RA_MANAGED: u8 = 0x80;
+const RA_OTHER_CONFIG: u8 = 0x40;
+
+/// Address Registration Option status values (RFC 6775 section 4.1).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegistrationStatus {
+    Success,
+    Duplicate,
+    CacheFull,
+    Other(u8),
+}
+
+impl RegistrationStatus {
+    fn from_u8(status: u8) -> RegistrationStatus {
+        match status {
+            0 => RegistrationStatus::Success,
+            1 => RegistrationStatus::Duplicate,
+            2 => RegistrationStatus::CacheFull,
+            other => RegistrationStatus::Other(other),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NDState {
+    Idle,
+    Soliciting,
+    Registering,
+    Registered,
+    Failed,
+}
+
+/// The default router learned from a Router Advertisement.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DefaultRouter {
+    pub addr: IPAddr,
+    pub mac: MacAddress,
+    /// Router lifetime in seconds
+    pub lifetime: u16,
+    /// Addresses are available from DHCPv6 (the M flag)
+    pub managed: bool,
+    /// Other configuration, such as name servers, is available from DHCPv6
+    /// (the O flag)
+    pub other_config: bool,
+}
+
+/// An on-link prefix learned from a Prefix Information Option.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Prefix {
+    pub prefix: [u8; 16],
+    pub len: u8,
+    /// Valid lifetime in seconds
+    pub valid_lifetime: u32,
+}
+
+/// Clients are notified when the default router or address registration
+/// changes.
+pub trait NeighborDiscoveryClient {
+    /// Called when a default router is learned, or `None` when it expires.
+    fn router_changed(&self, router: Option<DefaultRouter>);
+
+    /// Called when an address registration completes. `result` is SUCCESS
+    /// if the router accepted the registration, EALREADY if the address is a
+    /// duplicate, ENOMEM if the router's neighbor cache is full and FAIL
+    /// otherwise.
+    fn address_registered(&self, addr: IPAddr, result: ReturnCode);
+}
+
+/// Derives the link-layer address of a neighbor from the interface
+/// identifier of its link-local address (the inverse of
+/// `IPAddr::generate_from_mac`).
+pub fn mac_from_iid(addr: &IPAddr) -> MacAddress {
+    if addr.0[8..14] == [0x00, 0x00, 0x00, 0xff, 0xfe, 0x00] {
+        MacAddress::Short((addr.0[14] as u16) << 8 | addr.0[15] as u16)
+    } else {
+        let mut long_addr = [0; 8];
+        long_addr.copy_from_slice(&addr.0[8..16]);
+        long_addr[0] ^= 0b00000010;
+        MacAddress::Long(long_addr)
+    }
+}
+
+/// Encodes a source or target link-layer address option into `buf`,
+/// returning its length.
+fn encode_ll_option(buf: &mut [u8], opt_type: u8, mac: MacAddress) -> usize {
+    let len = match mac {
+        MacAddress::Short(_) => 8,
+        MacAddress::Long(_) => 16,
+    };
+    for b in buf[..len].iter_mut() {
+        *b = 0;
+    }
+    buf[0] = opt_type;
+    buf[1] = (len / 8) as u8;
+    match mac {
+        MacAddress::Short(short_addr) => {
+            buf[2] = (short_addr >> 8) as u8;
+            buf[3] = short_addr as u8;
+        }
+        MacAddress::Long(long_addr) => buf[2..10].copy_from_slice(&long_addr),
+    }
+    len
+}
+
+/// Decodes the link-layer address carried in a link-layer address option.
+fn decode_ll_option(opt: &[u8]) -> Option<MacAddress> {
+    match opt.len() {
+        8 => Some(MacAddress::Short((opt[2] as u16) << 8 | opt[3] as u16)),
+        16 => {
+            let mut long_addr = [0; 8];
+            long_addr.copy_from_slice(&opt[2..10]);
+            Some(MacAddress::Long(long_addr))
+        }
+        _ => None,
+    }
+}
+
+/// Iterates over the options in an ND message body, calling `f` with the
+/// type and contents (including the type and length bytes) of each one.
+/// Returns false if the options are malformed.
+pub fn for_each_option<F: FnMut(u8, &[u8])>(options: &[u8], mut f: F) -> bool {
+    let mut off = 0;
+    while off + 2 <= options.len() {
+        let len = options[off + 1] as usize * 8;
+        if len == 0 || off + len > options.len() {
+            return false;
+        }
+        f(options[off], &options[off..off + len]);
+        off += len;
+    }
+    off == options.len()
+}
+
+pub struct NeighborDiscovery<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    sender: &'a dyn ICMP6Sender<'a>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    ip_senders: &'a [&'a dyn IP6Sender<'a>],
+    mac_addr: MacAddress,
+    eui64: [u8; 8],
+    link_local: IPAddr,
+    state: Cell<NDState>,
+    router: OptionalCell<DefaultRouter>,
+    prefix: OptionalCell<Prefix>,
+    managed_address: OptionalCell<IPAddr>,
+    address: Cell<IPAddr>,
+    registration_lifetime: Cell<u16>,
+    retries: Cell<u8>,
+    rs_interval: Cell<u32>,
+    timer: SecondsTimer,
+    client: OptionalCell<&'a dyn NeighborDiscoveryClient>,
+}
+
+impl<'a, A: Alarm<'a>> NeighborDiscovery<'a, A> {
+    /// # Arguments
+    ///
+    /// `mac_addr` - The link-layer address of this interface
+    /// `eui64` - The EUI-64 of this node, used as the registration owner
+    /// `ip_senders` - IPv6 senders whose gateway and source address follow
+    /// the default router and registered address
+    pub fn new(
+        alarm: &'a A,
+        sender: &'a dyn ICMP6Sender<'a>,
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+        ip_senders: &'a [&'a dyn IP6Sender<'a>],
+        mac_addr: MacAddress,
+        eui64: [u8; 8],
+    ) -> NeighborDiscovery<'a, A> {
+        let link_local = IPAddr::generate_from_mac(mac_addr);
+        NeighborDiscovery {
+            alarm: alarm,
+            sender: sender,
+            tx_buf: MapCell::new(tx_buf),
+            net_cap: net_cap,
+            ip_senders: ip_senders,
+            mac_addr: mac_addr,
+            eui64: eui64,
+            link_local: link_local,
+            state: Cell::new(NDState::Idle),
+            router: OptionalCell::empty(),
+            prefix: OptionalCell::empty(),
+            managed_address: OptionalCell::empty(),
+            address: Cell::new(link_local),
+            registration_lifetime: Cell::new(DEFAULT_REGISTRATION_LIFETIME),
+            retries: Cell::new(0),
+            rs_interval: Cell::new(RTR_SOLICITATION_INTERVAL),
+            timer: SecondsTimer::new(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn NeighborDiscoveryClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the lifetime requested when registering addresses, in units of
+    /// 60 seconds.
+    pub fn set_registration_lifetime(&self, lifetime: u16) {
+        self.registration_lifetime.set(cmp::max(lifetime, 1));
+    }
+
+    /// Starts soliciting routers. Returns EALREADY if already started.
+    pub fn start(&self) -> ReturnCode {
+        if self.state.get() != NDState::Idle && self.state.get() != NDState::Failed {
+            return ReturnCode::EALREADY;
+        }
+        self.solicit_routers();
+        ReturnCode::SUCCESS
+    }
+
+    /// Stops all Neighbor Discovery activity. The learned router and
+    /// address are kept.
+    pub fn stop(&self) {
+        self.alarm.disarm();
+        self.state.set(NDState::Idle);
+    }
+
+    pub fn get_state(&self) -> NDState {
+        self.state.get()
+    }
+
+    pub fn get_default_router(&self) -> Option<DefaultRouter> {
+        self.router.map(|router| *router)
+    }
+
+    pub fn get_prefix(&self) -> Option<Prefix> {
+        self.prefix.map(|prefix| *prefix)
+    }
+
+    /// Returns the address that is registered (or being registered) with
+    /// the default router.
+    pub fn get_address(&self) -> IPAddr {
+        self.address.get()
+    }
+
+    pub fn get_link_local(&self) -> IPAddr {
+        self.link_local
+    }
+
+    /// Sets an address assigned by DHCPv6, which is registered instead of
+    /// the address formed from the on-link prefix. Passing `None` returns
+    /// to address autoconfiguration.
+    pub fn set_managed_address(&self, addr: Option<IPAddr>) {
+        self.managed_address.insert(addr);
+        let state = self.state.get();
+        let new_address = self.preferred_address();
+        if (state == NDState::Registered || state == NDState::Registering)
+            && new_address != self.address.get()
+        {
+            self.set_address(new_address);
+            self.start_registration();
+        }
+    }
+
+    /// Returns true if `addr` is one of the addresses of this interface.
+    pub fn is_local_address(&self, addr: &IPAddr) -> bool {
+        *addr == self.link_local || *addr == self.address.get()
+    }
+
+    /// Returns the address to register: the address assigned by DHCPv6 if
+    /// there is one, else the address formed from the on-link prefix.
+    fn preferred_address(&self) -> IPAddr {
+        self.managed_address.unwrap_or_else(|| {
+            self.prefix.map_or(self.address.get(), |prefix| {
+                let mut addr = self.link_local;
+                addr.set_prefix(&prefix.prefix, prefix.len);
+                addr
+            })
+        })
+    }
+
+    fn solicit_routers(&self) {
+        self.state.set(NDState::Soliciting);
+        self.retries.set(0);
+        self.rs_interval.set(RTR_SOLICITATION_INTERVAL);
+        self.send_rs();
+        self.timer.start(self.alarm, RTR_SOLICITATION_INTERVAL);
+    }
+
+    fn start_registration(&self) {
+        self.state.set(NDState::Registering);
+        self.retries.set(0);
+        self.send_registration();
+        self.timer.start(self.alarm, RETRANS_TIMER);
+    }
+
+    fn send(&self, dest: IPAddr, header: ICMP6Header, build: impl FnOnce(&mut [u8]) -> usize) {
+        self.tx_buf.take().map(|mut buf| {
+            let len = build(&mut buf[..]);
+            buf.slice(0..len);
+            if let Err(mut buf) = self.sender.send(dest, header, buf, self.net_cap) {
+                buf.reset();
+                self.tx_buf.replace(buf);
+            }
+        });
+    }
+
+    fn send_rs(&self) {
+        let mac_addr = self.mac_addr;
+        self.send(
+            IPAddr::all_routers(),
+            ICMP6Header::new(ICMP6Type::Type133),
+            |buf| encode_ll_option(buf, option_type::SOURCE_LL_ADDR, mac_addr),
+        );
+    }
+
+    fn send_registration(&self) {
+        let router = match self.router.map(|router| *router) {
+            Some(router) => router,
+            None => return,
+        };
+        let target = self.address.get();
+        let mac_addr = self.mac_addr;
+        let lifetime = self.registration_lifetime.get();
+        let eui64 = self.eui64;
+        self.send(router.addr, ICMP6Header::new(ICMP6Type::Type135), |buf| {
+            buf[0..16].copy_from_slice(&target.0);
+            let mut off = 16;
+            off += encode_ll_option(&mut buf[off..], option_type::SOURCE_LL_ADDR, mac_addr);
+            let aro = &mut buf[off..off + 16];
+            aro[0] = option_type::ADDR_REGISTRATION;
+            aro[1] = 2;
+            for b in aro[2..6].iter_mut() {
+                *b = 0;
+            }
+            aro[6] = (lifetime >> 8) as u8;
+            aro[7] = lifetime as u8;
+            aro[8..16].copy_from_slice(&eui64);
+            off + 16
+        });
+    }
+
+    fn send_na(&self, dest: IPAddr, target: IPAddr, solicited: bool) {
+        let mut header = ICMP6Header::new(ICMP6Type::Type136);
+        let flags = if solicited {
+            na_flags::SOLICITED | na_flags::OVERRIDE
+        } else {
+            na_flags::OVERRIDE
+        };
+        header.set_options(ICMP6HeaderOptions::Type136 { flags });
+        let mac_addr = self.mac_addr;
+        self.send(dest, header, |buf| {
+            buf[0..16].copy_from_slice(&target.0);
+            16 + encode_ll_option(&mut buf[16..], option_type::TARGET_LL_ADDR, mac_addr)
+        });
+    }
+
+    fn set_router(&self, router: Option<DefaultRouter>) {
+        let changed = self.router.map(|router| *router) != router;
+        match router {
+            Some(router) => {
+                self.router.set(router);
+                for sender in self.ip_senders.iter() {
+                    sender.set_gateway(router.mac);
+                }
+            }
+            None => self.router.clear(),
+        }
+        if changed {
+            self.client.map(|client| client.router_changed(router));
+        }
+    }
+
+    fn set_address(&self, addr: IPAddr) {
+        self.address.set(addr);
+        for sender in self.ip_senders.iter() {
+            sender.set_addr(addr);
+        }
+    }
+
+    fn receive_ra(&self, ip_header: &IP6Header, flags: u8, lifetime: u16, body: &[u8]) {
+        let src = ip_header.get_src_addr();
+        // RFC 4861 section 6.1.2: RAs must come from a link-local address
+        // and must not have been forwarded
+        if !src.is_unicast_link_local() || ip_header.get_hop_limit() != 255 || body.len() < 8 {
+            return;
+        }
+        if self.state.get() == NDState::Idle {
+            return;
+        }
+
+        let mut router_mac = None;
+        let mut prefix = None;
+        let valid = for_each_option(&body[8..], |opt_type, opt| match opt_type {
+            option_type::SOURCE_LL_ADDR => router_mac = decode_ll_option(opt),
+            option_type::PREFIX_INFO if opt.len() == 32 => {
+                let prefix_len = opt[2];
+                if opt[3] & PIO_AUTONOMOUS != 0 && prefix_len == 64 {
+                    let valid_lifetime = (opt[4] as u32) << 24
+                        | (opt[5] as u32) << 16
+                        | (opt[6] as u32) << 8
+                        | opt[7] as u32;
+                    let mut pfx = [0; 16];
+                    pfx.copy_from_slice(&opt[16..32]);
+                    prefix = Some(Prefix {
+                        prefix: pfx,
+                        len: prefix_len,
+                        valid_lifetime: valid_lifetime,
+                    });
+                }
+            }
+            _ => {}
+        });
+        if !valid {
+            return;
+        }
+
+        if lifetime == 0 {
+            // The router is no longer a default router
+            if self.router.map_or(false, |router| router.addr == src) {
+                self.set_router(None);
+                self.solicit_routers();
+            }
+            return;
+        }
+
+        self.set_router(Some(DefaultRouter {
+            addr: src,
+            mac: router_mac.unwrap_or_else(|| mac_from_iid(&src)),
+            lifetime: lifetime,
+            managed: flags & RA_MANAGED != 0,
+            other_config: flags & RA_OTHER_CONFIG != 0,
+        }));
+
+        if let Some(prefix) = prefix {
+            if prefix.valid_lifetime > 0 {
+                self.prefix.set(prefix);
+            }
+        }
+        let new_address = self.preferred_address();
+
+        let state = self.state.get();
+        if state == NDState::Soliciting
+            || state == NDState::Failed
+            || (state == NDState::Registered && new_address != self.address.get())
+        {
+            self.set_address(new_address);
+            self.start_registration();
+        }
+    }
+
+    fn receive_na(&self, ip_header: &IP6Header, body: &[u8]) {
+        if self.state.get() != NDState::Registering
+            || ip_header.get_hop_limit() != 255
+            || body.len() < 16
+        {
+            return;
+        }
+        // Only the router the registration was sent to can answer it
+        let src = ip_header.get_src_addr();
+        if !self.router.map_or(false, |router| router.addr == src) {
+            return;
+        }
+        if body[0..16] != self.address.get().0 {
+            return;
+        }
+        let mut status = None;
+        let mut lifetime = 0;
+        for_each_option(&body[16..], |opt_type, opt| {
+            if opt_type == option_type::ADDR_REGISTRATION && opt.len() == 16 {
+                status = Some(RegistrationStatus::from_u8(opt[2]));
+                lifetime = (opt[6] as u16) << 8 | opt[7] as u16;
+            }
+        });
+
+        let addr = self.address.get();
+        match status {
+            None => {} // Not a response to our registration
+            Some(RegistrationStatus::Success) => {
+                self.state.set(NDState::Registered);
+                // Refresh the registration after three quarters of the
+                // shorter of the registration and router lifetimes
+                let router_lifetime = self.router.map_or(0, |router| router.lifetime as u32);
+                let mut refresh = cmp::max(lifetime, 1) as u32 * 60;
+                if router_lifetime > 0 {
+                    refresh = cmp::min(refresh, router_lifetime);
+                }
+                self.timer.start(self.alarm, cmp::max(refresh * 3 / 4, 1));
+                self.client
+                    .map(|client| client.address_registered(addr, ReturnCode::SUCCESS));
+            }
+            Some(RegistrationStatus::CacheFull) => {
+                // Try again, possibly with another router, later
+                self.state.set(NDState::Soliciting);
+                self.retries.set(MAX_RTR_SOLICITATIONS);
+                self.rs_interval.set(MAX_RTR_SOLICITATION_INTERVAL);
+                self.timer.start(self.alarm, MAX_RTR_SOLICITATION_INTERVAL);
+                self.client
+                    .map(|client| client.address_registered(addr, ReturnCode::ENOMEM));
+            }
+            Some(RegistrationStatus::Duplicate) => {
+                self.alarm.disarm();
+                self.state.set(NDState::Failed);
+                self.client
+                    .map(|client| client.address_registered(addr, ReturnCode::EALREADY));
+            }
+            Some(RegistrationStatus::Other(_)) => {
+                self.alarm.disarm();
+                self.state.set(NDState::Failed);
+                self.client
+                    .map(|client| client.address_registered(addr, ReturnCode::FAIL));
+            }
+        }
+    }
+
+    fn receive_ns(&self, ip_header: &IP6Header, body: &[u8]) {
+        if ip_header.get_hop_limit() != 255 || body.len() < 16 {
+            return;
+        }
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&body[0..16]);
+        if !self.is_local_address(&target) {
+            return;
+        }
+        let src = ip_header.get_src_addr();
+        if src.is_unspecified() {
+            // Duplicate address detection from another node
+            self.send_na(IPAddr::all_nodes(), target, false);
+        } else {
+            self.send_na(src, target, true);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for NeighborDiscovery<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type134 {
+                flags, lifetime, ..
+            } => self.receive_ra(&ip_header, flags, lifetime, payload),
+            ICMP6HeaderOptions::Type135 { .. } => self.receive_ns(&ip_header, payload),
+            ICMP6HeaderOptions::Type136 { .. } => self.receive_na(&ip_header, payload),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6SendClient for NeighborDiscovery<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        // Failed transmissions are retried by the retransmission timers
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for NeighborDiscovery<'a, A> {
+    fn alarm(&self) {
+        if !self.timer.fired(self.alarm) {
+            return;
+        }
+        match self.state.get() {
+            NDState::Soliciting => {
+                // After the initial solicitations, retransmit with binary
+                // exponential backoff (RFC 6775 section 5.3)
+                let retries = self.retries.get().saturating_add(1);
+                self.retries.set(retries);
+                if retries >= MAX_RTR_SOLICITATIONS {
+                    let interval =
+                        cmp::min(self.rs_interval.get() * 2, MAX_RTR_SOLICITATION_INTERVAL);
+                    self.rs_interval.set(interval);
+                }
+                self.send_rs();
+                self.timer.start(self.alarm, self.rs_interval.get());
+            }
+            NDState::Registering => {
+                let retries = self.retries.get() + 1;
+                self.retries.set(retries);
+                if retries >= MAX_UNICAST_SOLICIT {
+                    // The router is unreachable
+                    self.set_router(None);
+                    self.solicit_routers();
+                } else {
+                    self.send_registration();
+                    self.timer.start(self.alarm, RETRANS_TIMER);
+                }
+            }
+            NDState::Registered => self.start_registration(),
+            NDState::Idle | NDState::Failed => {}
+        }
+    }
+}
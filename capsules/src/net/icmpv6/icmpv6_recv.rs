@@ -0,0 +1,85 @@
+//! This file contains the definition and implementation of the ICMPv6
+//! receive path. The [MuxIcmp6Receiver](struct.MuxIcmp6Receiver.html) is set
+//! as the ICMPv6 client of the IPv6 receiver, decodes the ICMPv6 header of
+//! each received packet and passes it to every registered
+//! [ICMP6Receiver](struct.ICMP6Receiver.html). Clients are expected to
+//! ignore message types they are not interested in, in the same way that
+//! `MacUser`s filter frames from the virtual MAC layer.
+
+use crate::net::icmpv6::ICMP6Header;
+use crate::net::ipv6::ip_utils::ip6_nh;
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::{IP6Header, ICMP_HDR_LEN};
+use kernel::common::cells::OptionalCell;
+use kernel::common::{List, ListLink, ListNode};
+
+/// Kernel capsules implement this trait to receive ICMPv6 messages.
+pub trait ICMP6RecvClient {
+    /// Called for every received ICMPv6 message whose checksum is valid.
+    ///
+    /// # Arguments
+    ///
+    /// `ip_header` - The IPv6 header of the received packet
+    /// `icmp_header` - The decoded ICMPv6 header
+    /// `payload` - The ICMPv6 message body following the 8 byte header
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]);
+}
+
+pub struct MuxIcmp6Receiver<'a> {
+    rcvr_list: List<'a, ICMP6Receiver<'a>>,
+}
+
+impl<'a> MuxIcmp6Receiver<'a> {
+    pub fn new() -> MuxIcmp6Receiver<'a> {
+        MuxIcmp6Receiver {
+            rcvr_list: List::new(),
+        }
+    }
+
+    pub fn add_client(&self, rcvr: &'a ICMP6Receiver<'a>) {
+        self.rcvr_list.push_tail(rcvr);
+    }
+}
+
+impl<'a> IP6RecvClient for MuxIcmp6Receiver<'a> {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8]) {
+        if ip_header.get_next_header() != ip6_nh::ICMP || payload.len() < ICMP_HDR_LEN {
+            return;
+        }
+        // Unsupported message types fail to decode and are ignored
+        if let Some((_, mut icmp_header)) = ICMP6Header::decode(payload).done() {
+            icmp_header.set_len(payload.len() as u16);
+            for rcvr in self.rcvr_list.iter() {
+                rcvr.client.map(|client| {
+                    client.receive(ip_header, icmp_header, &payload[ICMP_HDR_LEN..]);
+                });
+            }
+        }
+    }
+}
+
+/// A node in the list of ICMPv6 receivers, which passes received messages to
+/// the `ICMP6RecvClient` set on it.
+pub struct ICMP6Receiver<'a> {
+    client: OptionalCell<&'a dyn ICMP6RecvClient>,
+    next: ListLink<'a, ICMP6Receiver<'a>>,
+}
+
+impl<'a> ListNode<'a, ICMP6Receiver<'a>> for ICMP6Receiver<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, ICMP6Receiver<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> ICMP6Receiver<'a> {
+    pub fn new() -> ICMP6Receiver<'a> {
+        ICMP6Receiver {
+            client: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ICMP6RecvClient) {
+        self.client.set(client);
+    }
+}
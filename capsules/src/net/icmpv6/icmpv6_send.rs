@@ -1,10 +1,16 @@
-//! This file contains the definition and implementation of a simple ICMPv6
-//! sending interface. The [ICMP6Sender](trait.ICMP6Sender.html) trait provides
-//! an interface for an upper layer to send an ICMPv6 packet, and the
+//! This file contains the definition and implementation of a virtualized
+//! ICMPv6 sending interface. The [ICMP6Sender](trait.ICMP6Sender.html) trait
+//! provides an interface for an upper layer to send an ICMPv6 packet, and the
 //! [ICMP6SendClient](trait.ICMP6SendClient.html) trait is implemented by the
 //! upper layer to allow them to receive the `send_done` callback once
 //! transmission has completed.
 //!
+//! Several kernel capsules (e.g. the echo responder, neighbor discovery and
+//! the userspace ping driver) send ICMPv6 messages over the same IPv6 sender.
+//! As with the UDP stack, a [MuxIcmp6Sender](struct.MuxIcmp6Sender.html)
+//! queues packets from each `ICMP6SendStruct` in FIFO order, with each sender
+//! being allowed a single outstanding packet at a time.
+//!
 //! - Author: Conor McAvity <cmcavity@stanford.edu>
 
 use crate::net::icmpv6::ICMP6Header;
@@ -12,15 +18,18 @@ use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
 use crate::net::ipv6::TransportHeader;
 use crate::net::network_capabilities::NetworkCapability;
-use kernel::common::cells::OptionalCell;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::debug;
 use kernel::ReturnCode;
 
 /// A trait for a client of an `ICMP6Sender`.
 pub trait ICMP6SendClient {
     /// A client callback invoked after an ICMP6Sender has completed sending
-    /// a requested packet.
-    fn send_done(&self, result: ReturnCode);
+    /// a requested packet. The buffer passed to `send` is returned.
+    fn send_done(&self, result: ReturnCode, buf: LeasableBuffer<'static, u8>);
 }
 
 /// A trait that defines an interface for sending ICMPv6 packets.
@@ -44,57 +53,163 @@ pub trait ICMP6Sender<'a> {
     ///
     /// # Return Value
     ///
-    /// This function returns a code reporting either success or any
-    /// synchronous errors. Note that any asynchronous errors are returned
-    /// via the callback.
+    /// This function returns `Ok(())` if the packet was sent or queued, and
+    /// returns the buffer if a synchronous error occurred. Note that any
+    /// asynchronous errors are returned via the callback.
     fn send(
-        &self,
+        &'a self,
         dest: IPAddr,
         icmp_header: ICMP6Header,
-        buf: &'static mut [u8],
+        buf: LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
-    ) -> ReturnCode;
+    ) -> Result<(), LeasableBuffer<'static, u8>>;
+}
+
+/// Queues packets from several `ICMP6SendStruct`s onto a single `IP6Sender`.
+pub struct MuxIcmp6Sender<'a> {
+    sender_list: List<'a, ICMP6SendStruct<'a>>,
+    ip_sender: &'a dyn IP6Sender<'a>,
+}
+
+impl<'a> MuxIcmp6Sender<'a> {
+    pub fn new(ip_sender: &'a dyn IP6Sender<'a>) -> MuxIcmp6Sender<'a> {
+        MuxIcmp6Sender {
+            sender_list: List::new(),
+            ip_sender: ip_sender,
+        }
+    }
+
+    fn send_to(&self, caller: &'a ICMP6SendStruct<'a>) -> ReturnCode {
+        // If the list is empty, initiate the send immediately and return the
+        // result. Otherwise the packet stays queued until its turn.
+        let list_empty = self.sender_list.head().is_none();
+        self.sender_list.push_tail(caller);
+        if list_empty {
+            self.send_head()
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn send_head(&self) -> ReturnCode {
+        self.sender_list
+            .head()
+            .map_or(ReturnCode::SUCCESS, |sender| {
+                match (
+                    sender.tx_buffer.take(),
+                    sender.next_th.take(),
+                    sender.net_cap.take(),
+                ) {
+                    (Some(buf), Some(th), Some(net_cap)) => {
+                        let ret = self
+                            .ip_sender
+                            .send_to(sender.next_dest.get(), th, &buf, net_cap);
+                        sender.tx_buffer.replace(buf);
+                        ret
+                    }
+                    (buf, _, _) => {
+                        buf.map(|buf| sender.tx_buffer.replace(buf));
+                        debug!("[ICMP6_SEND] Missing packet state for queued sender");
+                        ReturnCode::FAIL
+                    }
+                }
+            })
+    }
 }
 
-/// A struct that implements the `ICMP6Sender` trait.
-pub struct ICMP6SendStruct<'a, T: IP6Sender<'a>> {
-    ip_send_struct: &'a T,
+impl<'a> IP6SendClient for MuxIcmp6Sender<'a> {
+    fn send_done(&self, result: ReturnCode) {
+        let last_sender = self.sender_list.pop_head();
+        last_sender.map(|sender| {
+            sender.tx_buffer.take().map(|buf| {
+                sender
+                    .client
+                    .map(move |client| client.send_done(result, buf));
+            });
+        });
+
+        // Send any packets that were queued behind the completed one. If the
+        // IP layer rejects a packet synchronously, report it to its sender
+        // and move on to the next one.
+        loop {
+            match self.send_head() {
+                ReturnCode::SUCCESS => break,
+                err => {
+                    let failed = self.sender_list.pop_head();
+                    failed.map(|sender| {
+                        sender.tx_buffer.take().map(|buf| {
+                            sender.client.map(move |client| client.send_done(err, buf));
+                        });
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// A struct that implements the `ICMP6Sender` trait. Each kernel capsule
+/// sending ICMPv6 packets uses its own instance.
+pub struct ICMP6SendStruct<'a> {
+    mux_sender: &'a MuxIcmp6Sender<'a>,
     client: OptionalCell<&'a dyn ICMP6SendClient>,
+    next: ListLink<'a, ICMP6SendStruct<'a>>,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    next_dest: Cell<IPAddr>,
+    next_th: OptionalCell<TransportHeader>,
+    net_cap: OptionalCell<&'static NetworkCapability>,
+}
+
+impl<'a> ListNode<'a, ICMP6SendStruct<'a>> for ICMP6SendStruct<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, ICMP6SendStruct<'a>> {
+        &self.next
+    }
 }
 
-impl<'a, T: IP6Sender<'a>> ICMP6SendStruct<'a, T> {
-    pub fn new(ip_send_struct: &'a T) -> ICMP6SendStruct<'a, T> {
+impl<'a> ICMP6SendStruct<'a> {
+    pub fn new(mux_sender: &'a MuxIcmp6Sender<'a>) -> ICMP6SendStruct<'a> {
         ICMP6SendStruct {
-            ip_send_struct: ip_send_struct,
+            mux_sender: mux_sender,
             client: OptionalCell::empty(),
+            next: ListLink::empty(),
+            tx_buffer: MapCell::empty(),
+            next_dest: Cell::new(IPAddr::new()),
+            next_th: OptionalCell::empty(),
+            net_cap: OptionalCell::empty(),
         }
     }
 }
 
-impl<'a, T: IP6Sender<'a>> ICMP6Sender<'a> for ICMP6SendStruct<'a, T> {
+impl<'a> ICMP6Sender<'a> for ICMP6SendStruct<'a> {
     fn set_client(&self, client: &'a dyn ICMP6SendClient) {
         self.client.set(client);
     }
 
     fn send(
-        &self,
+        &'a self,
         dest: IPAddr,
         mut icmp_header: ICMP6Header,
-        buf: &'static mut [u8],
+        buf: LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
-    ) -> ReturnCode {
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if self.tx_buffer.is_some() {
+            // Only a single outstanding packet per sender
+            return Err(buf);
+        }
         let total_len = buf.len() + icmp_header.get_hdr_size();
         icmp_header.set_len(total_len as u16);
-        let transport_header = TransportHeader::ICMP(icmp_header);
-        self.ip_send_struct
-            .send_to(dest, transport_header, &LeasableBuffer::new(buf), net_cap)
-    }
-}
-
-impl<'a, T: IP6Sender<'a>> IP6SendClient for ICMP6SendStruct<'a, T> {
-    /// Forwards callback received from the `IP6Sender` to the
-    /// `ICMP6SendClient`.
-    fn send_done(&self, result: ReturnCode) {
-        self.client.map(|client| client.send_done(result));
+        self.tx_buffer.replace(buf);
+        self.next_dest.set(dest);
+        self.next_th.set(TransportHeader::ICMP(icmp_header));
+        self.net_cap.set(net_cap);
+        match self.mux_sender.send_to(self) {
+            ReturnCode::SUCCESS => Ok(()),
+            _ => {
+                // The failed packet was at the head of the queue
+                self.mux_sender.sender_list.pop_head();
+                self.next_th.clear();
+                self.net_cap.clear();
+                Err(self.tx_buffer.take().unwrap())
+            }
+        }
     }
 }
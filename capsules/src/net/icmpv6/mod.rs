@@ -1,3 +1,7 @@
+pub mod driver;
+pub mod icmpv6_echo;
+pub mod icmpv6_nd;
+pub mod icmpv6_recv;
 pub mod icmpv6_send;
 
 // Reexport the exports of the [`icmpv6`] module, to avoid redundant
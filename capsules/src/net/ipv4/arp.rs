@@ -0,0 +1,96 @@
+//! Implements encoding and decoding of Address Resolution Protocol (RFC 826)
+//! packets for IPv4 over Ethernet. Resolved neighbors are kept in a
+//! [NeighborCache](../../link/struct.NeighborCache.html).
+
+use crate::net::ethernet::{ethertype, MacAddr};
+use crate::net::ipv4::ipv4::IPv4Addr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// Length of an ARP packet for IPv4 over Ethernet.
+pub const ARP_PACKET_LEN: usize = 28;
+
+const HTYPE_ETHERNET: u16 = 1;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ArpOperation {
+    Request = 1,
+    Reply = 2,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ArpPacket {
+    pub operation: ArpOperation,
+    pub sender_mac: MacAddr,
+    pub sender_ip: IPv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: IPv4Addr,
+}
+
+impl ArpPacket {
+    pub fn request(sender_mac: MacAddr, sender_ip: IPv4Addr, target_ip: IPv4Addr) -> ArpPacket {
+        ArpPacket {
+            operation: ArpOperation::Request,
+            sender_mac: sender_mac,
+            sender_ip: sender_ip,
+            target_mac: MacAddr::new(),
+            target_ip: target_ip,
+        }
+    }
+
+    /// Constructs the reply to this request, sent from `mac`.
+    pub fn reply(&self, mac: MacAddr) -> ArpPacket {
+        ArpPacket {
+            operation: ArpOperation::Reply,
+            sender_mac: mac,
+            sender_ip: self.target_ip,
+            target_mac: self.sender_mac,
+            target_ip: self.sender_ip,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, ARP_PACKET_LEN);
+        let off = enc_consume!(buf; encode_u16, HTYPE_ETHERNET);
+        let off = enc_consume!(buf, off; encode_u16, ethertype::IPV4);
+        let off = enc_consume!(buf, off; encode_u8, 6);
+        let off = enc_consume!(buf, off; encode_u8, 4);
+        let off = enc_consume!(buf, off; encode_u16, self.operation as u16);
+        let off = enc_consume!(buf, off; encode_bytes, &self.sender_mac.0);
+        let off = enc_consume!(buf, off; encode_bytes, &self.sender_ip.0);
+        let off = enc_consume!(buf, off; encode_bytes, &self.target_mac.0);
+        let off = enc_consume!(buf, off; encode_bytes, &self.target_ip.0);
+        stream_done!(off, off);
+    }
+
+    /// Decodes an ARP packet. Packets for other hardware or protocol
+    /// address types are rejected.
+    pub fn decode(buf: &[u8]) -> SResult<ArpPacket> {
+        stream_len_cond!(buf, ARP_PACKET_LEN);
+        let (off, htype) = dec_try!(buf; decode_u16);
+        let (off, ptype) = dec_try!(buf, off; decode_u16);
+        let (off, hlen) = dec_try!(buf, off; decode_u8);
+        let (off, plen) = dec_try!(buf, off; decode_u8);
+        stream_cond!(htype == HTYPE_ETHERNET && ptype == ethertype::IPV4);
+        stream_cond!(hlen == 6 && plen == 4);
+        let (off, oper) = dec_try!(buf, off; decode_u16);
+        let operation = match oper {
+            1 => ArpOperation::Request,
+            2 => ArpOperation::Reply,
+            _ => stream_err!(),
+        };
+        let mut packet = ArpPacket {
+            operation: operation,
+            sender_mac: MacAddr::new(),
+            sender_ip: IPv4Addr::UNSPECIFIED,
+            target_mac: MacAddr::new(),
+            target_ip: IPv4Addr::UNSPECIFIED,
+        };
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.sender_mac.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.sender_ip.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.target_mac.0);
+        let off = dec_consume!(buf, off; decode_bytes, &mut packet.target_ip.0);
+        stream_done!(off, packet);
+    }
+}
@@ -0,0 +1,696 @@
+//! DHCP client (RFC 2131) for an IPv4 interface.
+//!
+//! The client obtains an address, subnet mask, default gateway and name
+//! servers for an [IP4Config](../ipv4_interface/trait.IP4Config.html)
+//! interface, and keeps the lease alive:
+//!
+//! 1. It broadcasts DHCPDISCOVER messages until a server makes an offer,
+//!    then broadcasts a DHCPREQUEST for the offered address.
+//! 2. When the server acknowledges the request, the interface is configured
+//!    and the name servers are passed to the `DnsConfig`, if one is set.
+//! 3. At T1 (half the lease by default) the lease is renewed with the
+//!    server that granted it. If that server does not answer by T2, the
+//!    client broadcasts requests to any server. If the lease expires, the
+//!    interface is deconfigured and the client starts over.
+//!
+//! A DHCPNAK at any point also deconfigures the interface and restarts
+//! discovery. Offered addresses are not probed with ARP before use.
+//!
+//! The client sends and receives through a `UDP4Socket` bound to
+//! `DHCP_CLIENT_PORT`. Since the interface accepts packets to any address
+//! until it is configured, offers and acknowledgements are received before
+//! the client has an address.
+//!
+//! Timers longer than a minute are split into several alarms, so that long
+//! lease times do not overflow the tick counter of the alarm.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let dhcp = static_init!(
+//!     Dhcp4Client<'static, VirtualMuxAlarm<'static, LiteXAlarm>>,
+//!     Dhcp4Client::new(
+//!         dhcp_socket,
+//!         dhcp_alarm,
+//!         ip4,
+//!         LeasableBuffer::new(&mut DHCP_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! dhcp_socket.set_send_client(dhcp);
+//! dhcp_socket.set_receive_client(dhcp);
+//! dhcp_alarm.set_alarm_client(dhcp);
+//! dhcp.set_dns_config(resolver);
+//! dhcp.start();
+//! ```
+
+use crate::net::dns::resolver::{DnsConfig, MAX_DNS_SERVERS};
+use crate::net::ipv4::ipv4::IPv4Addr;
+use crate::net::ipv4::ipv4_interface::IP4Config;
+use crate::net::ipv4::udp4::{UDP4RecvClient, UDP4SendClient, UDP4Socket};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::util::SecondsTimer;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+/// Size of the messages sent by the client, which is also the minimum size
+/// of its transmit buffer (the minimum BOOTP message size).
+pub const DHCP_BUF_LEN: usize = 300;
+
+/// Initial and maximum retransmission intervals while discovering and
+/// requesting, in seconds (RFC 2131 section 4.1).
+pub const INITIAL_RETRANS: u32 = 4;
+pub const MAX_RETRANS: u32 = 64;
+/// Number of DHCPREQUESTs sent for an offer before discovering again.
+pub const MAX_REQUEST_ATTEMPTS: u8 = 4;
+/// Shortest interval between retransmissions while renewing or rebinding,
+/// in seconds.
+pub const MIN_RENEW_RETRANS: u32 = 60;
+
+const BOOTP_FIXED_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = BOOTP_FIXED_LEN + MAGIC_COOKIE.len();
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+mod msg_type {
+    pub const DISCOVER: u8 = 1;
+    pub const OFFER: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const ACK: u8 = 5;
+    pub const NAK: u8 = 6;
+}
+
+mod option {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const DNS_SERVER: u8 = 6;
+    pub const REQUESTED_ADDR: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_LIST: u8 = 55;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const END: u8 = 255;
+}
+
+const PARAMETER_LIST: [u8; 6] = [
+    option::SUBNET_MASK,
+    option::ROUTER,
+    option::DNS_SERVER,
+    option::LEASE_TIME,
+    option::RENEWAL_TIME,
+    option::REBINDING_TIME,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DhcpState {
+    Idle,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+/// The configuration granted by a DHCP server.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dhcp4Lease {
+    pub addr: IPv4Addr,
+    pub netmask: IPv4Addr,
+    pub gateway: IPv4Addr,
+    pub server: IPv4Addr,
+    pub dns_servers: [IPv4Addr; MAX_DNS_SERVERS],
+    /// Lease time, renewal time (T1) and rebinding time (T2) in seconds
+    pub lease_time: u32,
+    pub renewal_time: u32,
+    pub rebinding_time: u32,
+}
+
+/// Clients are notified when a lease is acquired or lost.
+pub trait Dhcp4LeaseClient {
+    /// Called with the new lease when one is acquired or renewed with
+    /// different parameters, and with `None` when the lease is lost.
+    fn lease_changed(&self, lease: Option<Dhcp4Lease>);
+}
+
+/// The fields of a server message the client acts on.
+#[derive(Copy, Clone, Default)]
+struct ServerMessage {
+    msg_type: u8,
+    yiaddr: IPv4Addr,
+    server: Option<IPv4Addr>,
+    netmask: Option<IPv4Addr>,
+    gateway: Option<IPv4Addr>,
+    dns_servers: [IPv4Addr; MAX_DNS_SERVERS],
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+fn read_addr(buf: &[u8]) -> IPv4Addr {
+    IPv4Addr([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Decodes the options of a server message. Returns `None` if they are
+/// malformed.
+fn decode_options(options: &[u8]) -> Option<ServerMessage> {
+    let mut msg = ServerMessage::default();
+    let mut off = 0;
+    while off < options.len() {
+        let code = options[off];
+        if code == option::END {
+            return Some(msg);
+        } else if code == option::PAD {
+            off += 1;
+            continue;
+        }
+        let len = *options.get(off + 1)? as usize;
+        let data = options.get(off + 2..off + 2 + len)?;
+        match (code, len) {
+            (option::MESSAGE_TYPE, 1) => msg.msg_type = data[0],
+            (option::SERVER_ID, 4) => msg.server = Some(read_addr(data)),
+            (option::SUBNET_MASK, 4) => msg.netmask = Some(read_addr(data)),
+            (option::ROUTER, _) if len >= 4 => msg.gateway = Some(read_addr(data)),
+            (option::DNS_SERVER, _) => {
+                for (server, addr) in msg.dns_servers.iter_mut().zip(data.chunks_exact(4)) {
+                    *server = read_addr(addr);
+                }
+            }
+            (option::LEASE_TIME, 4) => msg.lease_time = Some(read_u32(data)),
+            (option::RENEWAL_TIME, 4) => msg.renewal_time = Some(read_u32(data)),
+            (option::REBINDING_TIME, 4) => msg.rebinding_time = Some(read_u32(data)),
+            _ => {}
+        }
+        off += 2 + len;
+    }
+    // The options must end with an END option
+    None
+}
+
+pub struct Dhcp4Client<'a, A: Alarm<'a>> {
+    socket: &'a UDP4Socket<'a>,
+    alarm: &'a A,
+    iface: &'a dyn IP4Config,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    state: Cell<DhcpState>,
+    xid: Cell<u32>,
+    retrans: Cell<u32>,
+    attempts: Cell<u8>,
+    offer: OptionalCell<(IPv4Addr, IPv4Addr)>,
+    lease: OptionalCell<Dhcp4Lease>,
+    // Seconds since the lease was acknowledged
+    lease_elapsed: Cell<u32>,
+    timer: SecondsTimer,
+    dns: OptionalCell<&'a dyn DnsConfig>,
+    client: OptionalCell<&'a dyn Dhcp4LeaseClient>,
+}
+
+impl<'a, A: Alarm<'a>> Dhcp4Client<'a, A> {
+    /// `socket` must already be bound to `DHCP_CLIENT_PORT`, and `tx_buf`
+    /// must hold at least `DHCP_BUF_LEN` bytes.
+    pub fn new(
+        socket: &'a UDP4Socket<'a>,
+        alarm: &'a A,
+        iface: &'a dyn IP4Config,
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Dhcp4Client<'a, A> {
+        Dhcp4Client {
+            socket: socket,
+            alarm: alarm,
+            iface: iface,
+            tx_buf: MapCell::new(tx_buf),
+            net_cap: net_cap,
+            state: Cell::new(DhcpState::Idle),
+            xid: Cell::new(0),
+            retrans: Cell::new(INITIAL_RETRANS),
+            attempts: Cell::new(0),
+            offer: OptionalCell::empty(),
+            lease: OptionalCell::empty(),
+            lease_elapsed: Cell::new(0),
+            timer: SecondsTimer::new(),
+            dns: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Dhcp4LeaseClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the resolver that is given the name servers of each lease.
+    pub fn set_dns_config(&self, dns: &'a dyn DnsConfig) {
+        self.dns.set(dns);
+    }
+
+    /// Starts acquiring a lease. Returns EALREADY if already started.
+    pub fn start(&self) -> ReturnCode {
+        if self.state.get() != DhcpState::Idle {
+            return ReturnCode::EALREADY;
+        }
+        self.discover();
+        ReturnCode::SUCCESS
+    }
+
+    /// Stops all DHCP activity. The interface keeps its configuration, but
+    /// the lease is no longer renewed.
+    pub fn stop(&self) {
+        self.alarm.disarm();
+        self.state.set(DhcpState::Idle);
+    }
+
+    pub fn get_state(&self) -> DhcpState {
+        self.state.get()
+    }
+
+    pub fn get_lease(&self) -> Option<Dhcp4Lease> {
+        self.lease.map(|lease| *lease)
+    }
+
+    /// Picks a new transaction ID, which only needs to be unlikely to
+    /// collide with those of other clients on the link.
+    fn new_xid(&self) {
+        let mac = self.iface.get_mac_address().0;
+        let seed = u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+        self.xid.set(
+            seed ^ self
+                .xid
+                .get()
+                .wrapping_add(self.alarm.now().into_u32())
+                .wrapping_mul(0x9e37_79b9),
+        );
+    }
+
+    fn discover(&self) {
+        self.state.set(DhcpState::Selecting);
+        self.offer.clear();
+        self.new_xid();
+        self.retrans.set(INITIAL_RETRANS);
+        self.send_discover();
+        self.timer.start(self.alarm, INITIAL_RETRANS);
+    }
+
+    /// Returns the next retransmission interval for discovery and requests,
+    /// doubling it each time.
+    fn next_retrans(&self) -> u32 {
+        let retrans = self.retrans.get();
+        self.retrans.set(cmp::min(retrans * 2, MAX_RETRANS));
+        retrans
+    }
+
+    /// While renewing or rebinding, waits half of the time left until
+    /// `deadline` (but at least `MIN_RENEW_RETRANS` seconds) before
+    /// retransmitting, as suggested by RFC 2131 section 4.4.5.
+    fn start_renew_timer(&self, deadline: u32) {
+        let left = deadline.saturating_sub(self.lease_elapsed.get());
+        self.timer.start(
+            self.alarm,
+            cmp::min(cmp::max(left / 2, MIN_RENEW_RETRANS), left),
+        );
+    }
+
+    fn send_discover(&self) {
+        self.send_message(
+            msg_type::DISCOVER,
+            IPv4Addr::UNSPECIFIED,
+            None,
+            None,
+            IPv4Addr::BROADCAST,
+        );
+    }
+
+    fn send_request(&self) {
+        match self.state.get() {
+            DhcpState::Requesting => {
+                self.offer.map(|(addr, server)| {
+                    self.send_message(
+                        msg_type::REQUEST,
+                        IPv4Addr::UNSPECIFIED,
+                        Some(*addr),
+                        Some(*server),
+                        IPv4Addr::BROADCAST,
+                    )
+                });
+            }
+            DhcpState::Renewing => {
+                self.lease.map(|lease| {
+                    self.send_message(msg_type::REQUEST, lease.addr, None, None, lease.server)
+                });
+            }
+            DhcpState::Rebinding => {
+                self.lease.map(|lease| {
+                    self.send_message(
+                        msg_type::REQUEST,
+                        lease.addr,
+                        None,
+                        None,
+                        IPv4Addr::BROADCAST,
+                    )
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Builds a client message and sends it to `dst`. Transmission errors
+    /// are not reported; the message is retransmitted when the timer fires.
+    fn send_message(
+        &self,
+        msg_type: u8,
+        ciaddr: IPv4Addr,
+        requested: Option<IPv4Addr>,
+        server: Option<IPv4Addr>,
+        dst: IPv4Addr,
+    ) {
+        self.tx_buf.take().map(|mut buf| {
+            if buf.len() < DHCP_BUF_LEN {
+                self.tx_buf.replace(buf);
+                return;
+            }
+            for b in buf[..DHCP_BUF_LEN].iter_mut() {
+                *b = 0;
+            }
+            buf[0] = OP_BOOTREQUEST;
+            buf[1] = HTYPE_ETHERNET;
+            buf[2] = 6; // Hardware address length
+            buf[4..8].copy_from_slice(&self.xid.get().to_be_bytes());
+            // Until the client has an address, ask for replies to be
+            // broadcast, since they cannot be unicast to it
+            if ciaddr.is_unspecified() {
+                buf[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+            }
+            buf[12..16].copy_from_slice(&ciaddr.0);
+            buf[28..34].copy_from_slice(&self.iface.get_mac_address().0);
+            buf[BOOTP_FIXED_LEN..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+            let mut off = OPTIONS_OFFSET;
+            buf[off..off + 3].copy_from_slice(&[option::MESSAGE_TYPE, 1, msg_type]);
+            off += 3;
+            if let Some(requested) = requested {
+                buf[off..off + 2].copy_from_slice(&[option::REQUESTED_ADDR, 4]);
+                buf[off + 2..off + 6].copy_from_slice(&requested.0);
+                off += 6;
+            }
+            if let Some(server) = server {
+                buf[off..off + 2].copy_from_slice(&[option::SERVER_ID, 4]);
+                buf[off + 2..off + 6].copy_from_slice(&server.0);
+                off += 6;
+            }
+            buf[off..off + 2]
+                .copy_from_slice(&[option::PARAMETER_LIST, PARAMETER_LIST.len() as u8]);
+            buf[off + 2..off + 2 + PARAMETER_LIST.len()].copy_from_slice(&PARAMETER_LIST);
+            off += 2 + PARAMETER_LIST.len();
+            buf[off] = option::END;
+
+            buf.slice(0..DHCP_BUF_LEN);
+            if let Err(mut buf) = self
+                .socket
+                .send_to(dst, DHCP_SERVER_PORT, buf, self.net_cap)
+            {
+                buf.reset();
+                self.tx_buf.replace(buf);
+            }
+        });
+    }
+
+    /// Deconfigures the interface if it holds a lease, then discovers
+    /// servers again.
+    fn restart(&self) {
+        if self.lease.take().is_some() {
+            self.iface.configure(
+                IPv4Addr::UNSPECIFIED,
+                IPv4Addr::UNSPECIFIED,
+                IPv4Addr::UNSPECIFIED,
+            );
+            self.client.map(|client| client.lease_changed(None));
+        }
+        self.discover();
+    }
+
+    fn receive_offer(&self, msg: &ServerMessage) {
+        let server = match msg.server {
+            Some(server) => server,
+            None => return,
+        };
+        if msg.yiaddr.is_unspecified() || msg.yiaddr.is_broadcast() {
+            return;
+        }
+        // Accept the first offer
+        self.offer.set((msg.yiaddr, server));
+        self.state.set(DhcpState::Requesting);
+        self.attempts.set(1);
+        self.retrans.set(INITIAL_RETRANS);
+        self.send_request();
+        self.timer.start(self.alarm, self.next_retrans());
+    }
+
+    fn receive_ack(&self, msg: &ServerMessage) {
+        let server = match self.state.get() {
+            DhcpState::Requesting => match (msg.server, self.offer.map(|offer| offer.1)) {
+                (Some(server), Some(offered)) if server == offered => server,
+                _ => return,
+            },
+            // Acknowledgements of renewals may omit the server identifier
+            _ => match msg.server.or_else(|| self.lease.map(|lease| lease.server)) {
+                Some(server) => server,
+                None => return,
+            },
+        };
+        if msg.yiaddr.is_unspecified() {
+            return;
+        }
+        let lease_time = msg.lease_time.unwrap_or(0);
+        if lease_time == 0 {
+            return;
+        }
+        let renewal_time = msg.renewal_time.unwrap_or(lease_time / 2);
+        let rebinding_time = msg
+            .rebinding_time
+            .unwrap_or((lease_time as u64 * 7 / 8) as u32);
+        let lease = Dhcp4Lease {
+            addr: msg.yiaddr,
+            // Assume a /24 if the server does not supply a mask
+            netmask: msg.netmask.unwrap_or(IPv4Addr::new(255, 255, 255, 0)),
+            gateway: msg.gateway.unwrap_or(IPv4Addr::UNSPECIFIED),
+            server: server,
+            dns_servers: msg.dns_servers,
+            lease_time: lease_time,
+            renewal_time: cmp::min(renewal_time, lease_time),
+            rebinding_time: cmp::min(cmp::max(rebinding_time, renewal_time), lease_time),
+        };
+
+        let changed = self.lease.map_or(true, |old| {
+            old.addr != lease.addr
+                || old.netmask != lease.netmask
+                || old.gateway != lease.gateway
+                || old.dns_servers != lease.dns_servers
+        });
+        self.lease.set(lease);
+        self.offer.clear();
+        if changed {
+            self.iface
+                .configure(lease.addr, lease.netmask, lease.gateway);
+            self.dns.map(|dns| {
+                let mut servers = [IPAddr::new(); MAX_DNS_SERVERS];
+                for (server, addr) in servers.iter_mut().zip(lease.dns_servers.iter()) {
+                    if !addr.is_unspecified() {
+                        *server = addr.to_ipv6_mapped();
+                    }
+                }
+                dns.set_servers(&servers);
+            });
+            self.client.map(|client| client.lease_changed(Some(lease)));
+        }
+        self.state.set(DhcpState::Bound);
+        self.lease_elapsed.set(0);
+        self.timer.start(self.alarm, lease.renewal_time);
+    }
+
+    fn receive_message(&self, payload: &[u8]) {
+        if payload.len() < OPTIONS_OFFSET
+            || payload[0] != OP_BOOTREPLY
+            || payload[4..8] != self.xid.get().to_be_bytes()
+            || payload[28..34] != self.iface.get_mac_address().0
+            || payload[BOOTP_FIXED_LEN..OPTIONS_OFFSET] != MAGIC_COOKIE
+        {
+            return;
+        }
+        let mut msg = match decode_options(&payload[OPTIONS_OFFSET..]) {
+            Some(msg) => msg,
+            None => return,
+        };
+        msg.yiaddr = read_addr(&payload[16..20]);
+
+        match (self.state.get(), msg.msg_type) {
+            (DhcpState::Selecting, msg_type::OFFER) => self.receive_offer(&msg),
+            (DhcpState::Requesting, msg_type::ACK)
+            | (DhcpState::Renewing, msg_type::ACK)
+            | (DhcpState::Rebinding, msg_type::ACK) => self.receive_ack(&msg),
+            (DhcpState::Requesting, msg_type::NAK)
+            | (DhcpState::Renewing, msg_type::NAK)
+            | (DhcpState::Rebinding, msg_type::NAK) => self.restart(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Dhcp4Client<'a, A> {
+    fn alarm(&self) {
+        if !self.timer.fired(self.alarm) {
+            return;
+        }
+        self.lease_elapsed
+            .set(self.lease_elapsed.get().saturating_add(self.timer.length()));
+        match self.state.get() {
+            DhcpState::Selecting => {
+                self.send_discover();
+                self.timer.start(self.alarm, self.next_retrans());
+            }
+            DhcpState::Requesting => {
+                if self.attempts.get() >= MAX_REQUEST_ATTEMPTS {
+                    self.discover();
+                } else {
+                    self.attempts.set(self.attempts.get() + 1);
+                    self.send_request();
+                    self.timer.start(self.alarm, self.next_retrans());
+                }
+            }
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => {
+                let lease = match self.lease.map(|lease| *lease) {
+                    Some(lease) => lease,
+                    None => return self.restart(),
+                };
+                let elapsed = self.lease_elapsed.get();
+                if elapsed >= lease.lease_time {
+                    self.restart();
+                } else if elapsed >= lease.rebinding_time {
+                    if self.state.get() != DhcpState::Rebinding {
+                        self.state.set(DhcpState::Rebinding);
+                        self.new_xid();
+                    }
+                    self.send_request();
+                    self.start_renew_timer(lease.lease_time);
+                } else {
+                    if self.state.get() == DhcpState::Bound {
+                        self.state.set(DhcpState::Renewing);
+                        self.new_xid();
+                    }
+                    self.send_request();
+                    self.start_renew_timer(lease.rebinding_time);
+                }
+            }
+            DhcpState::Idle => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDP4SendClient for Dhcp4Client<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        // Failed transmissions are retried by the retransmission timers
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDP4RecvClient for Dhcp4Client<'a, A> {
+    fn receive(&self, _src_addr: IPv4Addr, src_port: u16, _dst_port: u16, payload: &[u8]) {
+        if src_port == DHCP_SERVER_PORT {
+            self.receive_message(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_options, msg_type, option};
+    use crate::net::ipv4::ipv4::IPv4Addr;
+
+    #[test]
+    fn ack_options() {
+        #[rustfmt::skip]
+        let options: &[u8] = &[
+            option::MESSAGE_TYPE, 1, msg_type::ACK,
+            option::PAD,
+            option::SERVER_ID, 4, 192, 168, 1, 1,
+            option::SUBNET_MASK, 4, 255, 255, 255, 0,
+            // Only the first router is used
+            option::ROUTER, 8, 192, 168, 1, 254, 192, 168, 1, 253,
+            // Only the first two name servers are used
+            option::DNS_SERVER, 12, 8, 8, 8, 8, 1, 1, 1, 1, 9, 9, 9, 9,
+            option::LEASE_TIME, 4, 0, 0, 0x0e, 0x10,
+            option::RENEWAL_TIME, 4, 0, 0, 0x07, 0x08,
+            option::REBINDING_TIME, 4, 0, 0, 0x0c, 0x4e,
+            // Unknown options are skipped
+            43, 3, 1, 2, 3,
+            option::END,
+            // Anything after END is ignored
+            option::MESSAGE_TYPE, 1, msg_type::NAK,
+        ];
+        let msg = decode_options(options).unwrap();
+        assert_eq!(msg.msg_type, msg_type::ACK);
+        assert_eq!(msg.server, Some(IPv4Addr([192, 168, 1, 1])));
+        assert_eq!(msg.netmask, Some(IPv4Addr([255, 255, 255, 0])));
+        assert_eq!(msg.gateway, Some(IPv4Addr([192, 168, 1, 254])));
+        assert_eq!(
+            msg.dns_servers,
+            [IPv4Addr([8, 8, 8, 8]), IPv4Addr([1, 1, 1, 1])]
+        );
+        assert_eq!(msg.lease_time, Some(3600));
+        assert_eq!(msg.renewal_time, Some(1800));
+        assert_eq!(msg.rebinding_time, Some(3150));
+    }
+
+    #[test]
+    fn options_of_the_wrong_length() {
+        #[rustfmt::skip]
+        let options: &[u8] = &[
+            option::MESSAGE_TYPE, 2, msg_type::OFFER, 0,
+            option::SERVER_ID, 3, 192, 168, 1,
+            option::ROUTER, 2, 192, 168,
+            option::DNS_SERVER, 6, 8, 8, 8, 8, 1, 1,
+            option::LEASE_TIME, 0,
+            option::END,
+        ];
+        let msg = decode_options(options).unwrap();
+        assert_eq!(msg.msg_type, 0);
+        assert_eq!(msg.server, None);
+        assert_eq!(msg.gateway, None);
+        assert_eq!(
+            msg.dns_servers,
+            [IPv4Addr([8, 8, 8, 8]), IPv4Addr::default()]
+        );
+        assert_eq!(msg.lease_time, None);
+    }
+
+    #[test]
+    fn malformed_options() {
+        // No END option
+        assert!(decode_options(&[]).is_none());
+        assert!(decode_options(&[option::MESSAGE_TYPE, 1, msg_type::OFFER]).is_none());
+        assert!(decode_options(&[option::PAD, option::PAD]).is_none());
+        // A code with no length
+        assert!(decode_options(&[option::MESSAGE_TYPE]).is_none());
+        // A length past the end of the options, which swallows the END
+        assert!(decode_options(&[option::MESSAGE_TYPE, 2, msg_type::OFFER, option::END]).is_none());
+        assert!(decode_options(&[option::SERVER_ID, 255, 1, 2, 3, 4, option::END]).is_none());
+    }
+}
@@ -0,0 +1,255 @@
+//! This file contains the IPv4 address type, the `IP4Header` struct with its
+//! encode/decode functions, and the Internet checksum shared by IPv4, ICMP
+//! and UDP over IPv4.
+//!
+//! IPv4 options are accepted on receive and skipped; headers sent by this
+//! stack never carry options and always have the Don't Fragment bit set, as
+//! fragmentation is not supported.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// Length of an IPv4 header without options.
+pub const IP4_HEADER_LEN: usize = 20;
+
+pub mod ip4_proto {
+    pub const ICMP: u8 = 1;
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+}
+
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct IPv4Addr(pub [u8; 4]);
+
+impl IPv4Addr {
+    pub const BROADCAST: IPv4Addr = IPv4Addr([0xff; 4]);
+    pub const UNSPECIFIED: IPv4Addr = IPv4Addr([0; 4]);
+
+    pub fn new(a: u8, b: u8, c: u8, d: u8) -> IPv4Addr {
+        IPv4Addr([a, b, c, d])
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == IPv4Addr::UNSPECIFIED
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == IPv4Addr::BROADCAST
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0xf0 == 0xe0
+    }
+
+    /// Returns true if `other` is in the same subnet as this address.
+    pub fn same_subnet(&self, other: IPv4Addr, netmask: IPv4Addr) -> bool {
+        (0..4).all(|i| self.0[i] & netmask.0[i] == other.0[i] & netmask.0[i])
+    }
+
+    /// Returns the directed broadcast address of the subnet this address
+    /// belongs to.
+    pub fn subnet_broadcast(&self, netmask: IPv4Addr) -> IPv4Addr {
+        let mut addr = *self;
+        for i in 0..4 {
+            addr.0[i] |= !netmask.0[i];
+        }
+        addr
+    }
+
+    /// Returns the IPv4-mapped IPv6 address (`::ffff:a.b.c.d`, RFC 4291)
+    /// for this address. Network capabilities describe remote addresses as
+    /// IPv6 addresses, so IPv4 destinations are checked in this form.
+    pub fn to_ipv6_mapped(&self) -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[10] = 0xff;
+        addr.0[11] = 0xff;
+        addr.0[12..16].copy_from_slice(&self.0);
+        addr
+    }
+
+    /// Returns the IPv4 address embedded in an IPv4-mapped IPv6 address, or
+    /// `None` if `addr` is not IPv4-mapped.
+    pub fn from_ipv6_mapped(addr: &IPAddr) -> Option<IPv4Addr> {
+        if addr.0[0..10] == [0; 10] && addr.0[10..12] == [0xff, 0xff] {
+            Some(IPv4Addr([addr.0[12], addr.0[13], addr.0[14], addr.0[15]]))
+        } else {
+            None
+        }
+    }
+}
+
+/// Sums `buf` as a sequence of big-endian 16 bit words, starting from
+/// `initial`. An odd trailing byte is padded with zero.
+pub fn checksum_add(initial: u32, buf: &[u8]) -> u32 {
+    let mut sum = initial;
+    for chunk in buf.chunks(2) {
+        let hi = (chunk[0] as u32) << 8;
+        let lo = chunk.get(1).map_or(0, |b| *b as u32);
+        sum += hi | lo;
+    }
+    sum
+}
+
+/// Folds a sum produced by `checksum_add` into the one's complement
+/// Internet checksum (RFC 1071).
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Computes the Internet checksum of `buf`.
+pub fn internet_checksum(buf: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, buf))
+}
+
+/// Computes the sum of the pseudo header used by UDP and TCP checksums.
+pub fn pseudo_header_sum(src: IPv4Addr, dst: IPv4Addr, protocol: u8, len: u16) -> u32 {
+    let sum = checksum_add(0, &src.0);
+    let sum = checksum_add(sum, &dst.0);
+    sum + protocol as u32 + len as u32
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct IP4Header {
+    header_len: u8,
+    tos: u8,
+    total_len: u16,
+    id: u16,
+    flags_fragment: u16,
+    ttl: u8,
+    protocol: u8,
+    checksum: u16,
+    src_addr: IPv4Addr,
+    dst_addr: IPv4Addr,
+}
+
+impl IP4Header {
+    pub fn new(src_addr: IPv4Addr, dst_addr: IPv4Addr, protocol: u8) -> IP4Header {
+        IP4Header {
+            header_len: IP4_HEADER_LEN as u8,
+            tos: 0,
+            total_len: IP4_HEADER_LEN as u16,
+            id: 0,
+            flags_fragment: FLAG_DONT_FRAGMENT,
+            ttl: DEFAULT_TTL,
+            protocol: protocol,
+            checksum: 0,
+            src_addr: src_addr,
+            dst_addr: dst_addr,
+        }
+    }
+
+    pub fn get_src_addr(&self) -> IPv4Addr {
+        self.src_addr
+    }
+
+    pub fn get_dst_addr(&self) -> IPv4Addr {
+        self.dst_addr
+    }
+
+    pub fn get_protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn get_ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        self.id = id;
+    }
+
+    /// Length of the header including options, in bytes.
+    pub fn get_header_len(&self) -> usize {
+        self.header_len as usize
+    }
+
+    /// Length of the data following the header, in bytes.
+    pub fn get_payload_len(&self) -> usize {
+        (self.total_len as usize).saturating_sub(self.get_header_len())
+    }
+
+    pub fn set_payload_len(&mut self, len: usize) {
+        self.total_len = (self.get_header_len() + len) as u16;
+    }
+
+    /// Returns true if this header belongs to a fragment of a larger
+    /// datagram.
+    pub fn is_fragment(&self) -> bool {
+        self.flags_fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0
+    }
+
+    /// Serializes the header, computing its checksum. Headers are always
+    /// encoded without options.
+    pub fn encode(&self, buf: &mut [u8]) -> SResult<usize> {
+        stream_len_cond!(buf, IP4_HEADER_LEN);
+        let off = enc_consume!(buf; encode_u8, 0x40 | (IP4_HEADER_LEN / 4) as u8);
+        let off = enc_consume!(buf, off; encode_u8, self.tos);
+        let off = enc_consume!(buf, off; encode_u16, self.total_len);
+        let off = enc_consume!(buf, off; encode_u16, self.id);
+        let off = enc_consume!(buf, off; encode_u16, self.flags_fragment);
+        let off = enc_consume!(buf, off; encode_u8, self.ttl);
+        let off = enc_consume!(buf, off; encode_u8, self.protocol);
+        let cksum_off = off;
+        let off = enc_consume!(buf, off; encode_u16, 0);
+        let off = enc_consume!(buf, off; encode_bytes, &self.src_addr.0);
+        let off = enc_consume!(buf, off; encode_bytes, &self.dst_addr.0);
+        let cksum = internet_checksum(&buf[..off]);
+        enc_consume!(buf, cksum_off; encode_u16, cksum);
+        stream_done!(off, off);
+    }
+
+    /// Deserializes a header. The returned offset points past any options.
+    /// Decoding fails if the version is not 4, if the header checksum does
+    /// not match or if the total length is inconsistent with the header.
+    pub fn decode(buf: &[u8]) -> SResult<IP4Header> {
+        stream_len_cond!(buf, IP4_HEADER_LEN);
+        let (off, version_ihl) = dec_try!(buf; decode_u8);
+        stream_cond!(version_ihl >> 4 == 4);
+        let header_len = (version_ihl & 0x0f) as usize * 4;
+        stream_cond!(header_len >= IP4_HEADER_LEN);
+        stream_len_cond!(buf, header_len);
+        stream_cond!(internet_checksum(&buf[..header_len]) == 0);
+
+        let (off, tos) = dec_try!(buf, off; decode_u8);
+        let (off, total_len) = dec_try!(buf, off; decode_u16);
+        stream_cond!(total_len as usize >= header_len);
+        let (off, id) = dec_try!(buf, off; decode_u16);
+        let (off, flags_fragment) = dec_try!(buf, off; decode_u16);
+        let (off, ttl) = dec_try!(buf, off; decode_u8);
+        let (off, protocol) = dec_try!(buf, off; decode_u8);
+        let (off, checksum) = dec_try!(buf, off; decode_u16);
+        let mut src_addr = IPv4Addr::UNSPECIFIED;
+        let off = dec_consume!(buf, off; decode_bytes, &mut src_addr.0);
+        let mut dst_addr = IPv4Addr::UNSPECIFIED;
+        dec_consume!(buf, off; decode_bytes, &mut dst_addr.0);
+        stream_done!(
+            header_len,
+            IP4Header {
+                header_len: header_len as u8,
+                tos: tos,
+                total_len: total_len,
+                id: id,
+                flags_fragment: flags_fragment,
+                ttl: ttl,
+                protocol: protocol,
+                checksum: checksum,
+                src_addr: src_addr,
+                dst_addr: dst_addr,
+            }
+        );
+    }
+}
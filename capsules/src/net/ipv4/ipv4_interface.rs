@@ -0,0 +1,436 @@
+//! This file contains the IPv4 layer for an Ethernet interface. The
+//! [IP4Interface](struct.IP4Interface.html) resolves next hops with ARP,
+//! answers ARP requests and ICMP echo requests for its own address, and
+//! passes all other received IPv4 packets to its
+//! [IP4RecvClient](trait.IP4RecvClient.html).
+//!
+//! Packets for destinations outside the local subnet are sent to the
+//! configured gateway. The interface holds a single outgoing packet at a
+//! time; a packet whose next hop cannot be resolved after
+//! `ARP_MAX_ATTEMPTS` requests completes with ENOACK. Fragmented packets are
+//! dropped, and outgoing packets are never fragmented.
+//!
+//! Until an address is configured (for example by DHCP), packets sent to
+//! any destination address are accepted.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ip4 = static_init!(
+//!     IP4Interface<'static, VirtualMuxAlarm<'static, LiteXAlarm>>,
+//!     IP4Interface::new(
+//!         ip_eth_user,
+//!         arp_alarm,
+//!         MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+//!         &mut IP4_TX_BUF,
+//!         &mut IP4_CTL_BUF,
+//!         ip_vis,
+//!     )
+//! );
+//! ip_eth_user.set_transmit_client(ip4);
+//! ip_eth_user.set_receive_client(ip4);
+//! arp_alarm.set_alarm_client(ip4);
+//! ip4.configure(
+//!     IPv4Addr::new(192, 168, 1, 50),
+//!     IPv4Addr::new(255, 255, 255, 0),
+//!     IPv4Addr::new(192, 168, 1, 1),
+//! );
+//! ```
+
+use crate::ethernet::virtual_ethernet::{EthernetRxClient, EthernetTxClient, EthernetUser};
+use crate::net::ethernet::{ethertype, EthernetHeader, MacAddr};
+use crate::net::ipv4::arp::{ArpOperation, ArpPacket, ARP_PACKET_LEN};
+use crate::net::ipv4::ipv4::{internet_checksum, ip4_proto, IP4Header, IPv4Addr, IP4_HEADER_LEN};
+use crate::net::link::{DataState, EthernetLink, NeighborCache};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// Time to wait for an ARP reply before retrying.
+pub const ARP_RETRY_MS: u32 = 1000;
+/// Number of ARP requests sent before giving up on a next hop.
+pub const ARP_MAX_ATTEMPTS: u8 = 3;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+
+/// Implemented by the transport layer to learn when a packet passed to
+/// `IP4Sender::send_to` has been sent.
+pub trait IP4SendClient {
+    fn send_done(&self, result: ReturnCode);
+}
+
+/// Implemented by the transport layer to receive IPv4 packets.
+pub trait IP4RecvClient {
+    /// Called for every received packet other than ICMP echo requests, with
+    /// the packet's IPv4 header and the data following it.
+    fn receive(&self, ip_header: IP4Header, payload: &[u8]);
+}
+
+pub trait IP4Sender<'a> {
+    fn set_client(&self, client: &'a dyn IP4SendClient);
+
+    /// Returns the address of the interface, which is unspecified until the
+    /// interface is configured.
+    fn get_addr(&self) -> IPv4Addr;
+
+    /// Returns the largest `header` plus `payload` length accepted by
+    /// `send_to`.
+    fn max_payload_len(&self) -> usize;
+
+    /// Sends an IPv4 packet carrying `header` followed by `payload` to
+    /// `dst`. The data is copied before this function returns; completion
+    /// is signalled through `IP4SendClient::send_done` only if SUCCESS is
+    /// returned.
+    fn send_to(
+        &self,
+        dst: IPv4Addr,
+        protocol: u8,
+        header: &[u8],
+        payload: &[u8],
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode;
+}
+
+/// Address configuration of an IPv4 interface, used by the board or by a
+/// DHCP client.
+pub trait IP4Config {
+    /// Sets the address, subnet mask and default gateway of the interface.
+    /// Passing the unspecified address as `addr` deconfigures it.
+    fn configure(&self, addr: IPv4Addr, netmask: IPv4Addr, gateway: IPv4Addr);
+
+    fn get_netmask(&self) -> IPv4Addr;
+
+    fn get_gateway(&self) -> IPv4Addr;
+
+    fn get_mac_address(&self) -> MacAddr;
+}
+
+pub struct IP4Interface<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    addr: Cell<IPv4Addr>,
+    netmask: Cell<IPv4Addr>,
+    gateway: Cell<IPv4Addr>,
+    arp_cache: NeighborCache<IPv4Addr>,
+    // Sends the outgoing packet of the transport layer, and ARP packets and
+    // echo replies generated by this layer
+    link: EthernetLink<'a, IPv4Addr>,
+    next_id: Cell<u16>,
+    client: OptionalCell<&'a dyn IP4SendClient>,
+    rx_client: OptionalCell<&'a dyn IP4RecvClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a, A: Alarm<'a>> IP4Interface<'a, A> {
+    pub fn new(
+        eth: &'a EthernetUser<'a>,
+        alarm: &'a A,
+        mac: MacAddr,
+        tx_buf: &'static mut [u8],
+        ctl_buf: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP4Interface<'a, A> {
+        IP4Interface {
+            alarm: alarm,
+            addr: Cell::new(IPv4Addr::UNSPECIFIED),
+            netmask: Cell::new(IPv4Addr::UNSPECIFIED),
+            gateway: Cell::new(IPv4Addr::UNSPECIFIED),
+            arp_cache: NeighborCache::new(),
+            link: EthernetLink::new(eth, mac, ethertype::IPV4, tx_buf, ctl_buf),
+            next_id: Cell::new(0),
+            client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    pub fn set_receive_client(&self, client: &'a dyn IP4RecvClient) {
+        self.rx_client.set(client);
+    }
+
+    fn is_local_dst(&self, dst: IPv4Addr) -> bool {
+        let addr = self.addr.get();
+        addr.is_unspecified()
+            || dst == addr
+            || dst.is_broadcast()
+            || dst == addr.subnet_broadcast(self.netmask.get())
+    }
+
+    /// Returns the address whose hardware address the packet must be sent
+    /// to, or `None` if it must be broadcast.
+    fn next_hop(&self, dst: IPv4Addr) -> Result<Option<IPv4Addr>, ReturnCode> {
+        let addr = self.addr.get();
+        let netmask = self.netmask.get();
+        if dst.is_broadcast() || (!addr.is_unspecified() && dst == addr.subnet_broadcast(netmask)) {
+            Ok(None)
+        } else if dst.is_multicast() || dst.is_unspecified() {
+            Err(ReturnCode::EINVAL)
+        } else if addr.same_subnet(dst, netmask) {
+            Ok(Some(dst))
+        } else if !self.gateway.get().is_unspecified() {
+            Ok(Some(self.gateway.get()))
+        } else {
+            Err(ReturnCode::FAIL)
+        }
+    }
+
+    /// Builds and queues an ARP request for `target`.
+    fn send_arp_request(&self, target: IPv4Addr) -> ReturnCode {
+        let request = ArpPacket::request(self.link.get_mac_address(), self.addr.get(), target);
+        self.send_arp(request, MacAddr::BROADCAST)
+    }
+
+    fn send_arp(&self, packet: ArpPacket, dst: MacAddr) -> ReturnCode {
+        let result = self.link.queue_control(dst, ethertype::ARP, |buf| {
+            packet.encode(buf).done().map(|_| ARP_PACKET_LEN)
+        });
+        if result == ReturnCode::SUCCESS {
+            self.transmit_next_async();
+        }
+        result
+    }
+
+    fn send_echo_reply(&self, request: &IP4Header, src_mac: MacAddr, icmp: &[u8]) {
+        let result = self.link.queue_control(src_mac, ethertype::IPV4, |buf| {
+            if buf.len() < IP4_HEADER_LEN + icmp.len() {
+                return None;
+            }
+            let mut ip_header =
+                IP4Header::new(self.addr.get(), request.get_src_addr(), ip4_proto::ICMP);
+            ip_header.set_payload_len(icmp.len());
+            ip_header.set_id(self.next_id.get());
+            self.next_id.set(self.next_id.get().wrapping_add(1));
+            let _ = ip_header.encode(buf);
+
+            let reply = &mut buf[IP4_HEADER_LEN..IP4_HEADER_LEN + icmp.len()];
+            reply.copy_from_slice(icmp);
+            reply[0] = ICMP_ECHO_REPLY;
+            reply[2] = 0;
+            reply[3] = 0;
+            let cksum = internet_checksum(reply);
+            reply[2] = (cksum >> 8) as u8;
+            reply[3] = cksum as u8;
+            Some(IP4_HEADER_LEN + icmp.len())
+        });
+        // A request that arrives while a reply is still queued is dropped;
+        // the sender retransmits it
+        if result == ReturnCode::SUCCESS {
+            self.transmit_next_async();
+        }
+    }
+
+    /// Passes the next frame to the link, and reports failure of the
+    /// outgoing packet to the client.
+    fn transmit_next_async(&self) {
+        let result = self.link.transmit_next();
+        if result != ReturnCode::SUCCESS {
+            self.client.map(|client| client.send_done(result));
+        }
+    }
+
+    fn receive_arp(&self, payload: &[u8]) {
+        let packet = match ArpPacket::decode(payload).done() {
+            Some((_, packet)) => packet,
+            None => return,
+        };
+        let addr = self.addr.get();
+        if addr.is_unspecified() {
+            return;
+        }
+        if packet.target_ip == addr {
+            self.arp_cache.insert(packet.sender_ip, packet.sender_mac);
+        } else {
+            self.arp_cache.refresh(packet.sender_ip, packet.sender_mac);
+        }
+
+        if let DataState::Resolving { next_hop, .. } = self.link.data_state() {
+            if packet.sender_ip == next_hop {
+                self.alarm.disarm();
+                self.link.set_data_dst(packet.sender_mac);
+                self.transmit_next_async();
+            }
+        }
+
+        if packet.operation == ArpOperation::Request && packet.target_ip == addr {
+            // A request that arrives while a reply is still queued is
+            // dropped; the sender retransmits it
+            let _ = self.send_arp(packet.reply(self.link.get_mac_address()), packet.sender_mac);
+        }
+    }
+
+    fn receive_ipv4(&self, src_mac: MacAddr, payload: &[u8]) {
+        let (hdr_len, ip_header) = match IP4Header::decode(payload).done() {
+            Some(result) => result,
+            None => return,
+        };
+        let total_len = hdr_len + ip_header.get_payload_len();
+        if payload.len() < total_len || ip_header.is_fragment() {
+            return;
+        }
+        if !self.is_local_dst(ip_header.get_dst_addr()) {
+            return;
+        }
+        self.arp_cache.refresh(ip_header.get_src_addr(), src_mac);
+        let data = &payload[hdr_len..total_len];
+
+        if ip_header.get_protocol() == ip4_proto::ICMP
+            && data.len() >= ICMP_HEADER_LEN
+            && data[0] == ICMP_ECHO_REQUEST
+        {
+            // Only reply to requests sent to our own address
+            if ip_header.get_dst_addr() == self.addr.get() && internet_checksum(data) == 0 {
+                self.send_echo_reply(&ip_header, src_mac, data);
+            }
+            return;
+        }
+        self.rx_client.map(|client| client.receive(ip_header, data));
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP4Config for IP4Interface<'a, A> {
+    fn configure(&self, addr: IPv4Addr, netmask: IPv4Addr, gateway: IPv4Addr) {
+        self.addr.set(addr);
+        self.netmask.set(netmask);
+        self.gateway.set(gateway);
+        self.arp_cache.clear();
+    }
+
+    fn get_netmask(&self) -> IPv4Addr {
+        self.netmask.get()
+    }
+
+    fn get_gateway(&self) -> IPv4Addr {
+        self.gateway.get()
+    }
+
+    fn get_mac_address(&self) -> MacAddr {
+        self.link.get_mac_address()
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP4Sender<'a> for IP4Interface<'a, A> {
+    fn set_client(&self, client: &'a dyn IP4SendClient) {
+        self.client.set(client);
+    }
+
+    fn get_addr(&self) -> IPv4Addr {
+        self.addr.get()
+    }
+
+    fn max_payload_len(&self) -> usize {
+        self.link.max_data_len() - IP4_HEADER_LEN
+    }
+
+    fn send_to(
+        &self,
+        dst: IPv4Addr,
+        protocol: u8,
+        header: &[u8],
+        payload: &[u8],
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        if !net_cap.remote_addr_valid(dst.to_ipv6_mapped(), self.ip_vis) {
+            return ReturnCode::FAIL;
+        }
+        if self.link.data_state() != DataState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let next_hop = match self.next_hop(dst) {
+            Ok(next_hop) => next_hop,
+            Err(result) => return result,
+        };
+        let data_len = header.len() + payload.len();
+        if data_len > self.max_payload_len() {
+            return ReturnCode::ESIZE;
+        }
+
+        let result = self.link.write_data(|buf| {
+            let mut ip_header = IP4Header::new(self.addr.get(), dst, protocol);
+            ip_header.set_payload_len(data_len);
+            ip_header.set_id(self.next_id.get());
+            let _ = ip_header.encode(buf);
+            let off = IP4_HEADER_LEN;
+            buf[off..off + header.len()].copy_from_slice(header);
+            let off = off + header.len();
+            buf[off..off + payload.len()].copy_from_slice(payload);
+            Ok(off + payload.len())
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.next_id.set(self.next_id.get().wrapping_add(1));
+
+        match next_hop {
+            None => self.link.set_data_dst(MacAddr::BROADCAST),
+            Some(next_hop) => match self.arp_cache.lookup(next_hop) {
+                Some(mac) => self.link.set_data_dst(mac),
+                None => {
+                    let result = self.send_arp_request(next_hop);
+                    if result != ReturnCode::SUCCESS {
+                        return result;
+                    }
+                    self.link.set_data_state(DataState::Resolving {
+                        next_hop: next_hop,
+                        attempts: 1,
+                    });
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(ARP_RETRY_MS));
+                    return ReturnCode::SUCCESS;
+                }
+            },
+        }
+        self.link.transmit_next()
+    }
+}
+
+impl<'a, A: Alarm<'a>> EthernetTxClient for IP4Interface<'a, A> {
+    fn send_done(&self, result: ReturnCode, frame: &'static mut [u8]) {
+        if self.link.send_done(frame) {
+            self.client.map(|client| client.send_done(result));
+        }
+        self.transmit_next_async();
+    }
+}
+
+impl<'a, A: Alarm<'a>> EthernetRxClient for IP4Interface<'a, A> {
+    fn receive(&self, frame: &[u8]) {
+        let (off, header) = match EthernetHeader::decode(frame).done() {
+            Some(result) => result,
+            None => return,
+        };
+        if header.dst != self.link.get_mac_address() && !header.dst.is_broadcast() {
+            return;
+        }
+        match header.ethertype {
+            ethertype::ARP => self.receive_arp(&frame[off..]),
+            ethertype::IPV4 => self.receive_ipv4(header.src, &frame[off..]),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for IP4Interface<'a, A> {
+    fn alarm(&self) {
+        if let DataState::Resolving { next_hop, attempts } = self.link.data_state() {
+            if attempts < ARP_MAX_ATTEMPTS {
+                self.link.set_data_state(DataState::Resolving {
+                    next_hop: next_hop,
+                    attempts: attempts + 1,
+                });
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(ARP_RETRY_MS));
+                // If a reply is still queued, the request is sent on the
+                // next attempt
+                let _ = self.send_arp_request(next_hop);
+            } else {
+                self.link.set_data_state(DataState::Idle);
+                self.client
+                    .map(|client| client.send_done(ReturnCode::ENOACK));
+            }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod arp;
+pub mod dhcp;
+pub mod ipv4;
+pub mod ipv4_interface;
+pub mod udp4;
@@ -0,0 +1,312 @@
+//! This file contains UDP over IPv4. Kernel capsules send and receive
+//! datagrams through a [UDP4Socket](struct.UDP4Socket.html), all of which
+//! share an IPv4 interface through the [MuxUdp4](struct.MuxUdp4.html).
+//!
+//! Ports are reserved with the same `UdpPortManager` used by the IPv6 UDP
+//! stack: a socket must be given the bindings obtained from
+//! `UdpPortManager::bind` before it can send or receive, and sends are
+//! checked against the `NetworkCapability` of the caller. Remote IPv4
+//! addresses are checked in their IPv4-mapped IPv6 form.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let udp4_mux = static_init!(MuxUdp4<'static>, MuxUdp4::new(ip4, udp_vis));
+//! ip4.set_client(udp4_mux);
+//! ip4.set_receive_client(udp4_mux);
+//!
+//! let socket = static_init!(UDP4Socket<'static>, UDP4Socket::new(udp4_mux));
+//! udp4_mux.add_socket(socket);
+//! let (tx_binding, rx_binding) = port_table
+//!     .bind(port_table.create_socket().unwrap(), 5683, net_cap)
+//!     .ok()
+//!     .unwrap();
+//! socket.set_bindings(tx_binding, rx_binding);
+//! socket.set_send_client(app);
+//! socket.set_receive_client(app);
+//! ```
+
+use crate::net::ipv4::ipv4::{checksum_add, checksum_finish, ip4_proto, pseudo_header_sum};
+use crate::net::ipv4::ipv4::{IP4Header, IPv4Addr};
+use crate::net::ipv4::ipv4_interface::{IP4RecvClient, IP4SendClient, IP4Sender};
+use crate::net::network_capabilities::{NetworkCapability, UdpVisibilityCapability};
+use crate::net::udp::udp_port_table::{UdpPortBindingRx, UdpPortBindingTx};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::ReturnCode;
+
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// Implemented by users of a `UDP4Socket` to learn when a datagram has been
+/// sent. The buffer passed to `send_to` is returned.
+pub trait UDP4SendClient {
+    fn send_done(&self, result: ReturnCode, buf: LeasableBuffer<'static, u8>);
+}
+
+/// Implemented by users of a `UDP4Socket` to receive datagrams sent to the
+/// port it is bound to.
+pub trait UDP4RecvClient {
+    fn receive(&self, src_addr: IPv4Addr, src_port: u16, dst_port: u16, payload: &[u8]);
+}
+
+pub struct MuxUdp4<'a> {
+    ip: &'a dyn IP4Sender<'a>,
+    sockets: List<'a, UDP4Socket<'a>>,
+    inflight: OptionalCell<&'a UDP4Socket<'a>>,
+    udp_vis: &'static UdpVisibilityCapability,
+}
+
+impl<'a> MuxUdp4<'a> {
+    pub fn new(
+        ip: &'a dyn IP4Sender<'a>,
+        udp_vis: &'static UdpVisibilityCapability,
+    ) -> MuxUdp4<'a> {
+        MuxUdp4 {
+            ip: ip,
+            sockets: List::new(),
+            inflight: OptionalCell::empty(),
+            udp_vis: udp_vis,
+        }
+    }
+
+    pub fn add_socket(&self, socket: &'a UDP4Socket<'a>) {
+        self.sockets.push_tail(socket);
+    }
+
+    /// Returns the largest payload that can be sent in one datagram.
+    pub fn max_payload_len(&self) -> usize {
+        self.ip.max_payload_len() - UDP_HEADER_LEN
+    }
+
+    fn get_next_if_idle(&self) -> Option<&'a UDP4Socket<'a>> {
+        if self.inflight.is_some() {
+            return None;
+        }
+        self.sockets.iter().find(|socket| socket.pending.get())
+    }
+
+    /// Builds the UDP header for `socket`'s datagram and passes it to the
+    /// IPv4 layer.
+    fn perform_send(&self, socket: &'a UDP4Socket<'a>) -> ReturnCode {
+        socket.pending.set(false);
+        let src_port = match socket.binding_tx.map(|binding| binding.get_port()) {
+            Some(port) => port,
+            None => return ReturnCode::EINVAL,
+        };
+        let net_cap = match socket.net_cap.map(|net_cap| *net_cap) {
+            Some(net_cap) => net_cap,
+            None => return ReturnCode::FAIL,
+        };
+        let dst = socket.dst.get();
+        let dst_port = socket.dst_port.get();
+        let result = socket.tx_buf.map_or(ReturnCode::FAIL, |buf| {
+            let payload = &buf[..];
+            let len = (UDP_HEADER_LEN + payload.len()) as u16;
+            let mut header = [0u8; UDP_HEADER_LEN];
+            header[0..2].copy_from_slice(&src_port.to_be_bytes());
+            header[2..4].copy_from_slice(&dst_port.to_be_bytes());
+            header[4..6].copy_from_slice(&len.to_be_bytes());
+
+            let sum = pseudo_header_sum(self.ip.get_addr(), dst, ip4_proto::UDP, len);
+            let sum = checksum_add(checksum_add(sum, &header), payload);
+            // A computed checksum of zero is transmitted as all ones
+            let cksum = match checksum_finish(sum) {
+                0 => 0xffff,
+                cksum => cksum,
+            };
+            header[6..8].copy_from_slice(&cksum.to_be_bytes());
+            self.ip
+                .send_to(dst, ip4_proto::UDP, &header, payload, net_cap)
+        });
+        if result == ReturnCode::SUCCESS {
+            self.inflight.set(socket);
+        }
+        result
+    }
+
+    fn send_next_async(&self) {
+        self.get_next_if_idle().map(|socket| {
+            let result = self.perform_send(socket);
+            if result != ReturnCode::SUCCESS {
+                socket.send_done(result);
+            }
+        });
+    }
+
+    /// Starts the next queued datagram. If it belongs to `new_socket`, any
+    /// error is returned rather than delivered in a callback.
+    fn send_next_sync(&self, new_socket: &UDP4Socket<'a>) -> ReturnCode {
+        self.get_next_if_idle()
+            .map_or(ReturnCode::SUCCESS, |socket| {
+                if socket as *const _ == new_socket as *const _ {
+                    self.perform_send(socket)
+                } else {
+                    let result = self.perform_send(socket);
+                    if result != ReturnCode::SUCCESS {
+                        socket.send_done(result);
+                    }
+                    ReturnCode::SUCCESS
+                }
+            })
+    }
+}
+
+impl<'a> IP4SendClient for MuxUdp4<'a> {
+    fn send_done(&self, result: ReturnCode) {
+        self.inflight.take().map(|socket| socket.send_done(result));
+        self.send_next_async();
+    }
+}
+
+impl<'a> IP4RecvClient for MuxUdp4<'a> {
+    fn receive(&self, ip_header: IP4Header, payload: &[u8]) {
+        if ip_header.get_protocol() != ip4_proto::UDP || payload.len() < UDP_HEADER_LEN {
+            return;
+        }
+        let src_port = u16::from_be_bytes([payload[0], payload[1]]);
+        let dst_port = u16::from_be_bytes([payload[2], payload[3]]);
+        let len = u16::from_be_bytes([payload[4], payload[5]]);
+        let cksum = u16::from_be_bytes([payload[6], payload[7]]);
+        if (len as usize) < UDP_HEADER_LEN || len as usize > payload.len() {
+            return;
+        }
+        let datagram = &payload[..len as usize];
+        // A checksum of zero means the sender did not compute one
+        if cksum != 0 {
+            let sum = pseudo_header_sum(
+                ip_header.get_src_addr(),
+                ip_header.get_dst_addr(),
+                ip4_proto::UDP,
+                len,
+            );
+            if checksum_finish(checksum_add(sum, datagram)) != 0 {
+                return;
+            }
+        }
+        let socket = self.sockets.iter().find(|socket| {
+            socket
+                .binding_rx
+                .map_or(false, |binding| binding.get_port() == dst_port)
+        });
+        socket.map(|socket| {
+            socket.recv_client.map(|client| {
+                client.receive(
+                    ip_header.get_src_addr(),
+                    src_port,
+                    dst_port,
+                    &datagram[UDP_HEADER_LEN..],
+                )
+            });
+        });
+    }
+}
+
+pub struct UDP4Socket<'a> {
+    mux: &'a MuxUdp4<'a>,
+    next: ListLink<'a, UDP4Socket<'a>>,
+    binding_tx: MapCell<UdpPortBindingTx>,
+    binding_rx: MapCell<UdpPortBindingRx>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    dst: Cell<IPv4Addr>,
+    dst_port: Cell<u16>,
+    net_cap: OptionalCell<&'static NetworkCapability>,
+    pending: Cell<bool>,
+    send_client: OptionalCell<&'a dyn UDP4SendClient>,
+    recv_client: OptionalCell<&'a dyn UDP4RecvClient>,
+}
+
+impl<'a> ListNode<'a, UDP4Socket<'a>> for UDP4Socket<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, UDP4Socket<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> UDP4Socket<'a> {
+    pub fn new(mux: &'a MuxUdp4<'a>) -> UDP4Socket<'a> {
+        UDP4Socket {
+            mux: mux,
+            next: ListLink::empty(),
+            binding_tx: MapCell::empty(),
+            binding_rx: MapCell::empty(),
+            tx_buf: MapCell::empty(),
+            dst: Cell::new(IPv4Addr::UNSPECIFIED),
+            dst_port: Cell::new(0),
+            net_cap: OptionalCell::empty(),
+            pending: Cell::new(false),
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_send_client(&self, client: &'a dyn UDP4SendClient) {
+        self.send_client.set(client);
+    }
+
+    pub fn set_receive_client(&self, client: &'a dyn UDP4RecvClient) {
+        self.recv_client.set(client);
+    }
+
+    /// Gives the socket the bindings returned by `UdpPortManager::bind`.
+    pub fn set_bindings(&self, tx: UdpPortBindingTx, rx: UdpPortBindingRx) {
+        self.binding_tx.replace(tx);
+        self.binding_rx.replace(rx);
+    }
+
+    /// Takes the bindings back from the socket, so that they can be passed
+    /// to `UdpPortManager::unbind`.
+    pub fn take_bindings(&self) -> Option<(UdpPortBindingTx, UdpPortBindingRx)> {
+        match (self.binding_tx.take(), self.binding_rx.take()) {
+            (Some(tx), Some(rx)) => Some((tx, rx)),
+            (tx, rx) => {
+                tx.map(|tx| self.binding_tx.replace(tx));
+                rx.map(|rx| self.binding_rx.replace(rx));
+                None
+            }
+        }
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.binding_tx.is_some() && self.binding_rx.is_some()
+    }
+
+    /// Sends `buf` to `dst_port` on `dst` from the port this socket is bound
+    /// to. On success the buffer is returned in `UDP4SendClient::send_done`.
+    pub fn send_to(
+        &'a self,
+        dst: IPv4Addr,
+        dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        let local_port_valid = self.binding_tx.map_or(false, |binding| {
+            binding.get_port() != 0
+                && net_cap.local_port_valid(binding.get_port(), self.mux.udp_vis)
+        });
+        if !local_port_valid
+            || !net_cap.remote_port_valid(dst_port, self.mux.udp_vis)
+            || self.tx_buf.is_some()
+            || buf.len() > self.mux.max_payload_len()
+        {
+            return Err(buf);
+        }
+        self.tx_buf.replace(buf);
+        self.dst.set(dst);
+        self.dst_port.set(dst_port);
+        self.net_cap.set(net_cap);
+        self.pending.set(true);
+        match self.mux.send_next_sync(self) {
+            ReturnCode::SUCCESS => Ok(()),
+            _ => Err(self.tx_buf.take().unwrap()),
+        }
+    }
+
+    fn send_done(&self, result: ReturnCode) {
+        self.tx_buf.take().map(|buf| {
+            self.send_client
+                .map(move |client| client.send_done(result, buf));
+        });
+    }
+}
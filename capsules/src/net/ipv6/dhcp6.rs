@@ -0,0 +1,899 @@
+//! DHCPv6 client (RFC 8415) for a 6LoWPAN interface.
+//!
+//! The client works alongside [NeighborDiscovery](../../icmpv6/icmpv6_nd/struct.NeighborDiscovery.html),
+//! whose client it must be, and follows the flags of the Router
+//! Advertisements it receives:
+//!
+//! - If the router sets the M flag, the client obtains an address with a
+//!   Solicit/Advertise/Request/Reply exchange and hands it to Neighbor
+//!   Discovery to register in place of the autoconfigured address. The
+//!   address is renewed with the server at T1, rebound with any server at
+//!   T2, and dropped when its valid lifetime ends.
+//! - If only the O flag is set, addresses are autoconfigured from the
+//!   on-link prefix, and once one is registered the client sends an
+//!   Information-Request for name servers, refreshing them periodically.
+//! - Otherwise, addresses are autoconfigured and DHCPv6 is not used.
+//!
+//! Whichever address is registered is written to an entry of the interface
+//! list of the UDP driver, so that processes can bind to it. Name servers
+//! are passed to the `DnsConfig`, if one is set.
+//!
+//! Messages are sent to the All_DHCP_Relay_Agents_and_Servers address
+//! through a `UDPSender` bound to `DHCP6_CLIENT_PORT`, and received through
+//! a `UDPReceiver` bound to the same port. The client is identified by a
+//! DUID-LL formed from its EUI-64.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let dhcp6 = static_init!(
+//!     Dhcp6Client<'static, VirtualMuxAlarm<'static, Ast>>,
+//!     Dhcp6Client::new(
+//!         dhcp6_send,
+//!         dhcp6_alarm,
+//!         neighbor_discovery,
+//!         &local_ip_ifaces[2],
+//!         eui64,
+//!         LeasableBuffer::new(&mut DHCP6_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! dhcp6_send.set_client(dhcp6);
+//! dhcp6_recv.set_client(dhcp6);
+//! dhcp6_alarm.set_alarm_client(dhcp6);
+//! neighbor_discovery.set_client(dhcp6);
+//! ```
+
+use crate::net::dns::resolver::{DnsConfig, MAX_DNS_SERVERS};
+use crate::net::icmpv6::icmpv6_nd::{DefaultRouter, NeighborDiscovery, NeighborDiscoveryClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::net::util::SecondsTimer;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+pub const DHCP6_CLIENT_PORT: u16 = 546;
+pub const DHCP6_SERVER_PORT: u16 = 547;
+
+/// Minimum size of the transmit buffer of the client.
+pub const DHCP6_BUF_LEN: usize = 128;
+
+/// Initial and maximum retransmission intervals of Solicit, Request and
+/// Information-Request messages, in seconds.
+pub const INITIAL_RETRANS: u32 = 1;
+pub const MAX_RETRANS: u32 = 120;
+/// Number of Request messages sent before soliciting again.
+pub const MAX_REQUEST_ATTEMPTS: u8 = 10;
+/// Shortest interval between retransmissions while renewing or rebinding,
+/// in seconds.
+pub const MIN_RENEW_RETRANS: u32 = 60;
+/// Default and minimum intervals at which information obtained with an
+/// Information-Request is refreshed, in seconds (RFC 8415 section 21.23).
+pub const DEFAULT_INFO_REFRESH: u32 = 86400;
+pub const MIN_INFO_REFRESH: u32 = 600;
+
+/// The longest server DUID the client can store.
+const MAX_DUID_LEN: usize = 32;
+const DUID_LEN: usize = 12;
+const DUID_TYPE_LL: u16 = 3;
+const HW_TYPE_EUI64: u16 = 27;
+const HEADER_LEN: usize = 4;
+const IA_NA_LEN: usize = 12;
+const IAADDR_LEN: usize = 24;
+
+/// All_DHCP_Relay_Agents_and_Servers (ff02::1:2).
+const ALL_SERVERS: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0, 0x02]);
+
+mod msg_type {
+    pub const SOLICIT: u8 = 1;
+    pub const ADVERTISE: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const RENEW: u8 = 5;
+    pub const REBIND: u8 = 6;
+    pub const REPLY: u8 = 7;
+    pub const INFORMATION_REQUEST: u8 = 11;
+}
+
+mod option {
+    pub const CLIENTID: u16 = 1;
+    pub const SERVERID: u16 = 2;
+    pub const IA_NA: u16 = 3;
+    pub const IAADDR: u16 = 5;
+    pub const ORO: u16 = 6;
+    pub const ELAPSED_TIME: u16 = 8;
+    pub const STATUS_CODE: u16 = 13;
+    pub const DNS_SERVERS: u16 = 23;
+    pub const INFORMATION_REFRESH_TIME: u16 = 32;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dhcp6State {
+    /// DHCPv6 is not in use
+    Idle,
+    Soliciting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+    /// Waiting for the reply to an Information-Request
+    InfoRequesting,
+    /// Waiting to refresh the information obtained from a server
+    InfoBound,
+}
+
+/// An address assigned by a DHCPv6 server.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Dhcp6Lease {
+    pub addr: IPAddr,
+    /// Renewal time (T1), rebinding time (T2) and valid lifetime in seconds
+    pub renewal_time: u32,
+    pub rebinding_time: u32,
+    pub valid_lifetime: u32,
+}
+
+/// The fields of a server message the client acts on.
+#[derive(Copy, Clone)]
+struct ServerMessage {
+    msg_type: u8,
+    client_id_ok: bool,
+    status_ok: bool,
+    server_id: [u8; MAX_DUID_LEN],
+    server_id_len: usize,
+    lease: Option<Dhcp6Lease>,
+    dns_servers: [IPAddr; MAX_DNS_SERVERS],
+    info_refresh: Option<u32>,
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    (buf[off] as u16) << 8 | buf[off + 1] as u16
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    (read_u16(buf, off) as u32) << 16 | read_u16(buf, off + 2) as u32
+}
+
+/// Calls `f` with the code and data of each option in `options`. Returns
+/// false if the options are malformed.
+fn for_each_option<F: FnMut(u16, &[u8])>(options: &[u8], mut f: F) -> bool {
+    let mut off = 0;
+    while off + 4 <= options.len() {
+        let code = read_u16(options, off);
+        let len = read_u16(options, off + 2) as usize;
+        match options.get(off + 4..off + 4 + len) {
+            Some(data) => f(code, data),
+            None => return false,
+        }
+        off += 4 + len;
+    }
+    off == options.len()
+}
+
+/// Decodes an IA_NA option into a lease, if it is for the IA `iaid` and
+/// carries a usable address.
+fn decode_ia_na(iaid: &[u8], data: &[u8]) -> Option<Dhcp6Lease> {
+    if data.len() < IA_NA_LEN || data[0..4] != *iaid {
+        return None;
+    }
+    let mut renewal_time = read_u32(data, 4);
+    let mut rebinding_time = read_u32(data, 8);
+    let mut lease = None;
+    let mut status_ok = true;
+    let valid = for_each_option(&data[IA_NA_LEN..], |code, opt| match code {
+        option::IAADDR if opt.len() >= IAADDR_LEN => {
+            let mut addr = IPAddr::new();
+            addr.0.copy_from_slice(&opt[0..16]);
+            let preferred = read_u32(opt, 16);
+            let valid_lifetime = read_u32(opt, 20);
+            if valid_lifetime > 0 && lease.is_none() {
+                // Defaults from RFC 8415 section 21.4
+                if renewal_time == 0 {
+                    renewal_time = preferred / 2;
+                }
+                if rebinding_time == 0 {
+                    rebinding_time = (preferred as u64 * 4 / 5) as u32;
+                }
+                lease = Some((addr, valid_lifetime));
+            }
+        }
+        option::STATUS_CODE if opt.len() >= 2 => status_ok = read_u16(opt, 0) == 0,
+        _ => {}
+    });
+    if !valid || !status_ok {
+        return None;
+    }
+    lease.map(|(addr, valid_lifetime)| {
+        let renewal_time = cmp::min(cmp::max(renewal_time, 1), valid_lifetime);
+        Dhcp6Lease {
+            addr: addr,
+            renewal_time: renewal_time,
+            rebinding_time: cmp::min(cmp::max(rebinding_time, renewal_time), valid_lifetime),
+            valid_lifetime: valid_lifetime,
+        }
+    })
+}
+
+/// Decodes a server message in the exchange `xid` with the client `duid`.
+/// Returns `None` if it is malformed, for another client or exchange, or
+/// reports an error.
+fn decode_message(duid: &[u8; DUID_LEN], xid: u32, payload: &[u8]) -> Option<ServerMessage> {
+    if payload.len() < HEADER_LEN || payload[1..4] != xid.to_be_bytes()[1..4] {
+        return None;
+    }
+    let mut msg = ServerMessage {
+        msg_type: payload[0],
+        client_id_ok: false,
+        status_ok: true,
+        server_id: [0; MAX_DUID_LEN],
+        server_id_len: 0,
+        lease: None,
+        dns_servers: [IPAddr::new(); MAX_DNS_SERVERS],
+        info_refresh: None,
+    };
+    let valid = for_each_option(&payload[HEADER_LEN..], |code, data| match code {
+        option::CLIENTID => msg.client_id_ok = data == duid,
+        option::SERVERID if data.len() <= MAX_DUID_LEN => {
+            msg.server_id[..data.len()].copy_from_slice(data);
+            msg.server_id_len = data.len();
+        }
+        option::IA_NA => {
+            if msg.lease.is_none() {
+                msg.lease = decode_ia_na(&duid[8..12], data);
+            }
+        }
+        option::STATUS_CODE if data.len() >= 2 => msg.status_ok = read_u16(data, 0) == 0,
+        option::DNS_SERVERS => {
+            for (server, addr) in msg.dns_servers.iter_mut().zip(data.chunks_exact(16)) {
+                server.0.copy_from_slice(addr);
+            }
+        }
+        option::INFORMATION_REFRESH_TIME if data.len() == 4 => {
+            msg.info_refresh = Some(read_u32(data, 0))
+        }
+        _ => {}
+    });
+    if valid && msg.client_id_ok && msg.status_ok && msg.server_id_len > 0 {
+        Some(msg)
+    } else {
+        None
+    }
+}
+
+/// Writes an option header for an option of `len` bytes, returning the
+/// offset of its data.
+fn put_option_header(buf: &mut [u8], off: usize, code: u16, len: usize) -> usize {
+    buf[off..off + 2].copy_from_slice(&code.to_be_bytes());
+    buf[off + 2..off + 4].copy_from_slice(&(len as u16).to_be_bytes());
+    off + 4
+}
+
+fn put_option(buf: &mut [u8], off: usize, code: u16, data: &[u8]) -> usize {
+    let off = put_option_header(buf, off, code, data.len());
+    buf[off..off + data.len()].copy_from_slice(data);
+    off + data.len()
+}
+
+pub struct Dhcp6Client<'a, A: Alarm<'a>> {
+    sender: &'a dyn UDPSender<'a>,
+    alarm: &'a A,
+    nd: &'a NeighborDiscovery<'a, A>,
+    iface_addr: &'a Cell<IPAddr>,
+    duid: [u8; DUID_LEN],
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    state: Cell<Dhcp6State>,
+    router: OptionalCell<DefaultRouter>,
+    xid: Cell<u32>,
+    retrans: Cell<u32>,
+    attempts: Cell<u8>,
+    server_id: Cell<[u8; MAX_DUID_LEN]>,
+    server_id_len: Cell<usize>,
+    offered: OptionalCell<IPAddr>,
+    lease: OptionalCell<Dhcp6Lease>,
+    // Seconds since the current exchange started
+    exchange_elapsed: Cell<u32>,
+    // Seconds since the lease was obtained
+    lease_elapsed: Cell<u32>,
+    timer: SecondsTimer,
+    dns: OptionalCell<&'a dyn DnsConfig>,
+}
+
+impl<'a, A: Alarm<'a>> Dhcp6Client<'a, A> {
+    /// # Arguments
+    ///
+    /// `sender` - UDP sender bound to `DHCP6_CLIENT_PORT`
+    /// `iface_addr` - The entry of the UDP driver interface list that holds
+    /// the registered address of this interface
+    /// `eui64` - The EUI-64 of this node, used to form the client DUID
+    /// `tx_buf` - Buffer of at least `DHCP6_BUF_LEN` bytes
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        nd: &'a NeighborDiscovery<'a, A>,
+        iface_addr: &'a Cell<IPAddr>,
+        eui64: [u8; 8],
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Dhcp6Client<'a, A> {
+        let mut duid = [0; DUID_LEN];
+        duid[0..2].copy_from_slice(&DUID_TYPE_LL.to_be_bytes());
+        duid[2..4].copy_from_slice(&HW_TYPE_EUI64.to_be_bytes());
+        duid[4..12].copy_from_slice(&eui64);
+        Dhcp6Client {
+            sender: sender,
+            alarm: alarm,
+            nd: nd,
+            iface_addr: iface_addr,
+            duid: duid,
+            tx_buf: MapCell::new(tx_buf),
+            net_cap: net_cap,
+            state: Cell::new(Dhcp6State::Idle),
+            router: OptionalCell::empty(),
+            xid: Cell::new(0),
+            retrans: Cell::new(INITIAL_RETRANS),
+            attempts: Cell::new(0),
+            server_id: Cell::new([0; MAX_DUID_LEN]),
+            server_id_len: Cell::new(0),
+            offered: OptionalCell::empty(),
+            lease: OptionalCell::empty(),
+            exchange_elapsed: Cell::new(0),
+            lease_elapsed: Cell::new(0),
+            timer: SecondsTimer::new(),
+            dns: OptionalCell::empty(),
+        }
+    }
+
+    /// Sets the resolver that is given the name servers learned from
+    /// DHCPv6.
+    pub fn set_dns_config(&self, dns: &'a dyn DnsConfig) {
+        self.dns.set(dns);
+    }
+
+    pub fn get_state(&self) -> Dhcp6State {
+        self.state.get()
+    }
+
+    pub fn get_lease(&self) -> Option<Dhcp6Lease> {
+        self.lease.map(|lease| *lease)
+    }
+
+    /// Picks a new 24 bit transaction ID.
+    fn new_xid(&self) {
+        let seed = u32::from_be_bytes([self.duid[8], self.duid[9], self.duid[10], self.duid[11]]);
+        let xid = seed
+            ^ self
+                .xid
+                .get()
+                .wrapping_add(self.alarm.now().into_u32())
+                .wrapping_mul(0x9e37_79b9);
+        self.xid.set(xid & 0x00ff_ffff);
+    }
+
+    /// Starts a new exchange of `state`, sending its first message.
+    fn start_exchange(&self, state: Dhcp6State) {
+        self.state.set(state);
+        self.new_xid();
+        self.attempts.set(1);
+        self.exchange_elapsed.set(0);
+        self.retrans.set(INITIAL_RETRANS);
+        self.send_message();
+        match state {
+            Dhcp6State::Renewing | Dhcp6State::Rebinding => {
+                self.lease.map(|lease| {
+                    let deadline = if state == Dhcp6State::Renewing {
+                        lease.rebinding_time
+                    } else {
+                        lease.valid_lifetime
+                    };
+                    self.start_renew_timer(deadline);
+                });
+            }
+            _ => self.timer.start(self.alarm, self.next_retrans()),
+        }
+    }
+
+    /// Stops any exchange in progress.
+    fn stop(&self) {
+        self.alarm.disarm();
+        self.state.set(Dhcp6State::Idle);
+    }
+
+    /// Returns the next retransmission interval, doubling it each time.
+    fn next_retrans(&self) -> u32 {
+        let retrans = self.retrans.get();
+        self.retrans.set(cmp::min(retrans * 2, MAX_RETRANS));
+        retrans
+    }
+
+    /// While renewing or rebinding, waits half of the time left until
+    /// `deadline` (but at least `MIN_RENEW_RETRANS` seconds) before
+    /// retransmitting.
+    fn start_renew_timer(&self, deadline: u32) {
+        let left = deadline.saturating_sub(self.lease_elapsed.get());
+        self.timer.start(
+            self.alarm,
+            cmp::min(cmp::max(left / 2, MIN_RENEW_RETRANS), left),
+        );
+    }
+
+    /// Builds the message for the current state and sends it to all
+    /// servers. Transmission errors are not reported; the message is
+    /// retransmitted when the timer fires.
+    fn send_message(&self) {
+        let state = self.state.get();
+        let msg_type = match state {
+            Dhcp6State::Soliciting => msg_type::SOLICIT,
+            Dhcp6State::Requesting => msg_type::REQUEST,
+            Dhcp6State::Renewing => msg_type::RENEW,
+            Dhcp6State::Rebinding => msg_type::REBIND,
+            Dhcp6State::InfoRequesting => msg_type::INFORMATION_REQUEST,
+            _ => return,
+        };
+        self.tx_buf.take().map(|mut buf| {
+            if buf.len() < DHCP6_BUF_LEN {
+                self.tx_buf.replace(buf);
+                return;
+            }
+            buf[0] = msg_type;
+            buf[1..4].copy_from_slice(&self.xid.get().to_be_bytes()[1..4]);
+            let mut off = put_option(&mut buf[..], HEADER_LEN, option::CLIENTID, &self.duid);
+
+            if state == Dhcp6State::Requesting || state == Dhcp6State::Renewing {
+                let server_id = self.server_id.get();
+                off = put_option(
+                    &mut buf[..],
+                    off,
+                    option::SERVERID,
+                    &server_id[..self.server_id_len.get()],
+                );
+            }
+
+            // Elapsed time is in hundredths of a second
+            let elapsed = cmp::min(self.exchange_elapsed.get() * 100, 0xffff) as u16;
+            off = put_option(
+                &mut buf[..],
+                off,
+                option::ELAPSED_TIME,
+                &elapsed.to_be_bytes(),
+            );
+
+            if state != Dhcp6State::InfoRequesting {
+                let addr = match state {
+                    Dhcp6State::Requesting => self.offered.map(|addr| *addr),
+                    Dhcp6State::Soliciting => None,
+                    _ => self.lease.map(|lease| lease.addr),
+                };
+                let ia_len = IA_NA_LEN + addr.map_or(0, |_| 4 + IAADDR_LEN);
+                off = put_option_header(&mut buf[..], off, option::IA_NA, ia_len);
+                // IAID, T1 and T2; the server chooses T1 and T2
+                buf[off..off + 4].copy_from_slice(&self.duid[8..12]);
+                for b in buf[off + 4..off + IA_NA_LEN].iter_mut() {
+                    *b = 0;
+                }
+                off += IA_NA_LEN;
+                if let Some(addr) = addr {
+                    off = put_option_header(&mut buf[..], off, option::IAADDR, IAADDR_LEN);
+                    buf[off..off + 16].copy_from_slice(&addr.0);
+                    for b in buf[off + 16..off + IAADDR_LEN].iter_mut() {
+                        *b = 0;
+                    }
+                    off += IAADDR_LEN;
+                }
+            }
+
+            let mut oro = [0; 4];
+            oro[0..2].copy_from_slice(&option::DNS_SERVERS.to_be_bytes());
+            oro[2..4].copy_from_slice(&option::INFORMATION_REFRESH_TIME.to_be_bytes());
+            let oro_len = if state == Dhcp6State::InfoRequesting {
+                4
+            } else {
+                2
+            };
+            off = put_option(&mut buf[..], off, option::ORO, &oro[..oro_len]);
+
+            buf.slice(0..off);
+            if let Err(mut buf) =
+                self.sender
+                    .send_to(ALL_SERVERS, DHCP6_SERVER_PORT, buf, self.net_cap)
+            {
+                buf.reset();
+                self.tx_buf.replace(buf);
+            }
+        });
+    }
+
+    fn set_dns_servers(&self, servers: &[IPAddr; MAX_DNS_SERVERS]) {
+        if servers.iter().any(|server| !server.is_unspecified()) {
+            self.dns.map(|dns| dns.set_servers(servers));
+        }
+    }
+
+    /// Drops the leased address, returning to address autoconfiguration.
+    fn drop_lease(&self) {
+        if self.lease.take().is_some() {
+            self.nd.set_managed_address(None);
+        }
+    }
+
+    fn receive_advertise(&self, msg: &ServerMessage) {
+        let lease = match msg.lease {
+            Some(lease) => lease,
+            None => return,
+        };
+        // Request the first address advertised
+        self.server_id.set(msg.server_id);
+        self.server_id_len.set(msg.server_id_len);
+        self.offered.set(lease.addr);
+        self.start_exchange(Dhcp6State::Requesting);
+    }
+
+    fn receive_reply(&self, msg: &ServerMessage) {
+        let state = self.state.get();
+        if state == Dhcp6State::InfoRequesting {
+            self.set_dns_servers(&msg.dns_servers);
+            let refresh = msg.info_refresh.unwrap_or(DEFAULT_INFO_REFRESH);
+            self.state.set(Dhcp6State::InfoBound);
+            self.timer
+                .start(self.alarm, cmp::max(refresh, MIN_INFO_REFRESH));
+            return;
+        }
+
+        let lease = match msg.lease {
+            Some(lease) => lease,
+            None => {
+                if state == Dhcp6State::Requesting {
+                    self.start_exchange(Dhcp6State::Soliciting);
+                }
+                return;
+            }
+        };
+        // Later messages go to the server that assigned the address
+        self.server_id.set(msg.server_id);
+        self.server_id_len.set(msg.server_id_len);
+        self.offered.clear();
+        let changed = self.lease.map_or(true, |old| old.addr != lease.addr);
+        self.lease.set(lease);
+        if changed {
+            self.nd.set_managed_address(Some(lease.addr));
+        }
+        self.set_dns_servers(&msg.dns_servers);
+        self.state.set(Dhcp6State::Bound);
+        self.lease_elapsed.set(0);
+        self.timer.start(self.alarm, lease.renewal_time);
+    }
+
+    /// Starts using DHCPv6 as the flags of `router` require.
+    fn follow_router(&self, router: &DefaultRouter) {
+        let state = self.state.get();
+        if router.managed {
+            if state == Dhcp6State::Idle
+                || state == Dhcp6State::InfoRequesting
+                || state == Dhcp6State::InfoBound
+            {
+                self.start_exchange(Dhcp6State::Soliciting);
+            }
+        } else if self.lease.is_none() {
+            // Information-Requests are sent once an address is registered
+            if state != Dhcp6State::InfoRequesting && state != Dhcp6State::InfoBound {
+                self.stop();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> NeighborDiscoveryClient for Dhcp6Client<'a, A> {
+    fn router_changed(&self, router: Option<DefaultRouter>) {
+        match router {
+            Some(router) => {
+                self.router.set(router);
+                self.follow_router(&router);
+            }
+            None => {
+                // Keep any leased address until it expires, in case the
+                // router returns
+                self.router.clear();
+                if self.lease.is_none() {
+                    self.stop();
+                }
+            }
+        }
+    }
+
+    fn address_registered(&self, addr: IPAddr, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            self.iface_addr.set(addr);
+            let other_config = self
+                .router
+                .map_or(false, |router| router.other_config && !router.managed);
+            if other_config && self.state.get() == Dhcp6State::Idle {
+                self.start_exchange(Dhcp6State::InfoRequesting);
+            }
+        } else {
+            self.iface_addr.set(self.nd.get_link_local());
+            if result == ReturnCode::EALREADY && self.lease.is_some() {
+                // The server assigned an address that is already in use
+                self.drop_lease();
+                self.start_exchange(Dhcp6State::Soliciting);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Dhcp6Client<'a, A> {
+    fn alarm(&self) {
+        if !self.timer.fired(self.alarm) {
+            return;
+        }
+        let seconds = self.timer.length();
+        self.exchange_elapsed
+            .set(self.exchange_elapsed.get().saturating_add(seconds));
+        self.lease_elapsed
+            .set(self.lease_elapsed.get().saturating_add(seconds));
+        match self.state.get() {
+            Dhcp6State::Soliciting | Dhcp6State::InfoRequesting => {
+                self.send_message();
+                self.timer.start(self.alarm, self.next_retrans());
+            }
+            Dhcp6State::Requesting => {
+                if self.attempts.get() >= MAX_REQUEST_ATTEMPTS {
+                    self.start_exchange(Dhcp6State::Soliciting);
+                } else {
+                    self.attempts.set(self.attempts.get() + 1);
+                    self.send_message();
+                    self.timer.start(self.alarm, self.next_retrans());
+                }
+            }
+            Dhcp6State::Bound | Dhcp6State::Renewing | Dhcp6State::Rebinding => {
+                let lease = match self.lease.map(|lease| *lease) {
+                    Some(lease) => lease,
+                    None => return self.start_exchange(Dhcp6State::Soliciting),
+                };
+                let elapsed = self.lease_elapsed.get();
+                if elapsed >= lease.valid_lifetime {
+                    self.drop_lease();
+                    if self.router.map_or(false, |router| router.managed) {
+                        self.start_exchange(Dhcp6State::Soliciting);
+                    } else {
+                        self.stop();
+                    }
+                } else if elapsed >= lease.rebinding_time {
+                    if self.state.get() == Dhcp6State::Rebinding {
+                        self.send_message();
+                        self.start_renew_timer(lease.valid_lifetime);
+                    } else {
+                        self.start_exchange(Dhcp6State::Rebinding);
+                    }
+                } else if self.state.get() == Dhcp6State::Renewing {
+                    self.send_message();
+                    self.start_renew_timer(lease.rebinding_time);
+                } else {
+                    self.start_exchange(Dhcp6State::Renewing);
+                }
+            }
+            Dhcp6State::InfoBound => self.start_exchange(Dhcp6State::InfoRequesting),
+            Dhcp6State::Idle => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for Dhcp6Client<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut buf: LeasableBuffer<'static, u8>) {
+        // Failed transmissions are retried by the retransmission timers
+        buf.reset();
+        self.tx_buf.replace(buf);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for Dhcp6Client<'a, A> {
+    fn receive(
+        &self,
+        _src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        if src_port != DHCP6_SERVER_PORT {
+            return;
+        }
+        let msg = match decode_message(&self.duid, self.xid.get(), payload) {
+            Some(msg) => msg,
+            None => return,
+        };
+        match (self.state.get(), msg.msg_type) {
+            (Dhcp6State::Soliciting, msg_type::ADVERTISE) => self.receive_advertise(&msg),
+            (Dhcp6State::Requesting, msg_type::REPLY)
+            | (Dhcp6State::Renewing, msg_type::REPLY)
+            | (Dhcp6State::Rebinding, msg_type::REPLY)
+            | (Dhcp6State::InfoRequesting, msg_type::REPLY) => self.receive_reply(&msg),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const XID: u32 = 0x00ab_cdef;
+    const DUID: [u8; DUID_LEN] = [0, 3, 0, 27, 1, 2, 3, 4, 5, 6, 7, 8];
+    const SERVER_DUID: [u8; 6] = [0, 3, 0, 1, 0xaa, 0xbb];
+
+    fn addr(last: u8) -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = 0x20;
+        addr.0[1] = 0x01;
+        addr.0[15] = last;
+        addr
+    }
+
+    fn push_option(buf: &mut Vec<u8>, code: u16, data: &[u8]) {
+        buf.extend_from_slice(&code.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    fn iaaddr(addr: IPAddr, preferred: u32, valid: u32) -> Vec<u8> {
+        let mut data = addr.0.to_vec();
+        data.extend_from_slice(&preferred.to_be_bytes());
+        data.extend_from_slice(&valid.to_be_bytes());
+        data
+    }
+
+    fn ia_na(iaid: &[u8], t1: u32, t2: u32, options: &[u8]) -> Vec<u8> {
+        let mut data = iaid.to_vec();
+        data.extend_from_slice(&t1.to_be_bytes());
+        data.extend_from_slice(&t2.to_be_bytes());
+        data.extend_from_slice(options);
+        data
+    }
+
+    /// A Reply to this client with the given options after the client and
+    /// server IDs.
+    fn reply(options: &[u8]) -> Vec<u8> {
+        let mut msg = XID.to_be_bytes().to_vec();
+        msg[0] = msg_type::REPLY;
+        push_option(&mut msg, option::CLIENTID, &DUID);
+        push_option(&mut msg, option::SERVERID, &SERVER_DUID);
+        msg.extend_from_slice(options);
+        msg
+    }
+
+    #[test]
+    fn options() {
+        let mut options = Vec::new();
+        push_option(&mut options, option::ELAPSED_TIME, &[0, 0]);
+        push_option(&mut options, option::ORO, &[]);
+        let mut seen = Vec::new();
+        assert!(for_each_option(&options, |code, data| seen.push((code, data.len()))));
+        assert_eq!(seen, [(option::ELAPSED_TIME, 2), (option::ORO, 0)]);
+
+        assert!(for_each_option(&[], |_, _| panic!()));
+        // A length past the end, and trailing bytes too short for a header
+        assert!(!for_each_option(&options[..options.len() - 1], |_, _| {}));
+        assert!(!for_each_option(&[0, 8, 0, 3, 0, 0], |_, _| {}));
+        assert!(!for_each_option(&[0, 8, 0, 0, 1], |_, _| {}));
+    }
+
+    #[test]
+    fn reply_with_lease() {
+        let mut options = Vec::new();
+        let mut iaaddrs = Vec::new();
+        // An address with a zero valid lifetime is not used
+        push_option(&mut iaaddrs, option::IAADDR, &iaaddr(addr(1), 0, 0));
+        push_option(&mut iaaddrs, option::IAADDR, &iaaddr(addr(2), 3000, 6000));
+        push_option(&mut iaaddrs, option::IAADDR, &iaaddr(addr(3), 3000, 6000));
+        push_option(
+            &mut options,
+            option::IA_NA,
+            &ia_na(&DUID[8..12], 1000, 2000, &iaaddrs),
+        );
+        let mut servers = addr(0x53).0.to_vec();
+        servers.extend_from_slice(&addr(0x54).0);
+        servers.extend_from_slice(&addr(0x55).0);
+        push_option(&mut options, option::DNS_SERVERS, &servers);
+        push_option(
+            &mut options,
+            option::INFORMATION_REFRESH_TIME,
+            &[0, 0, 0x0e, 0x10],
+        );
+
+        let msg = decode_message(&DUID, XID, &reply(&options)).unwrap();
+        assert_eq!(msg.msg_type, msg_type::REPLY);
+        assert_eq!(&msg.server_id[..msg.server_id_len], &SERVER_DUID);
+        assert_eq!(
+            msg.lease,
+            Some(Dhcp6Lease {
+                addr: addr(2),
+                renewal_time: 1000,
+                rebinding_time: 2000,
+                valid_lifetime: 6000,
+            })
+        );
+        assert_eq!(msg.dns_servers, [addr(0x53), addr(0x54)]);
+        assert_eq!(msg.info_refresh, Some(3600));
+    }
+
+    #[test]
+    fn lease_times() {
+        let mut iaaddrs = Vec::new();
+        push_option(&mut iaaddrs, option::IAADDR, &iaaddr(addr(1), 1000, 1500));
+        let iaid = &DUID[8..12];
+
+        // T1 and T2 default to 0.5 and 0.8 times the preferred lifetime
+        let lease = decode_ia_na(iaid, &ia_na(iaid, 0, 0, &iaaddrs)).unwrap();
+        assert_eq!((lease.renewal_time, lease.rebinding_time), (500, 800));
+        // T2 is at least T1, and both are at most the valid lifetime
+        let lease = decode_ia_na(iaid, &ia_na(iaid, 700, 600, &iaaddrs)).unwrap();
+        assert_eq!((lease.renewal_time, lease.rebinding_time), (700, 700));
+        let lease = decode_ia_na(iaid, &ia_na(iaid, 2000, 3000, &iaaddrs)).unwrap();
+        assert_eq!((lease.renewal_time, lease.rebinding_time), (1500, 1500));
+    }
+
+    #[test]
+    fn unusable_ia_na() {
+        let iaid = &DUID[8..12];
+        let mut iaaddrs = Vec::new();
+        push_option(&mut iaaddrs, option::IAADDR, &iaaddr(addr(1), 1000, 1500));
+        let mut status = Vec::new();
+        push_option(&mut status, option::STATUS_CODE, &[0, 2]);
+
+        // Another IA, no address, too short, an error status, or malformed
+        assert!(decode_ia_na(iaid, &ia_na(&[9, 9, 9, 9], 0, 0, &iaaddrs)).is_none());
+        assert!(decode_ia_na(iaid, &ia_na(iaid, 0, 0, &[])).is_none());
+        assert!(decode_ia_na(iaid, &iaid[..]).is_none());
+        let mut options = iaaddrs.clone();
+        options.extend_from_slice(&status);
+        assert!(decode_ia_na(iaid, &ia_na(iaid, 0, 0, &options)).is_none());
+        assert!(decode_ia_na(iaid, &ia_na(iaid, 0, 0, &iaaddrs[..10])).is_none());
+
+        // The message is still accepted, without a lease
+        let mut options = Vec::new();
+        push_option(&mut options, option::IA_NA, &ia_na(iaid, 0, 0, &status));
+        let msg = decode_message(&DUID, XID, &reply(&options)).unwrap();
+        assert!(msg.lease.is_none());
+    }
+
+    #[test]
+    fn rejected_messages() {
+        // Another exchange, or too short for a header
+        let msg = reply(&[]);
+        assert!(decode_message(&DUID, XID, &msg).is_some());
+        assert!(decode_message(&DUID, XID + 1, &msg).is_none());
+        assert!(decode_message(&DUID, XID, &msg[..3]).is_none());
+
+        // Another client
+        let mut other = DUID;
+        other[11] ^= 1;
+        assert!(decode_message(&other, XID, &msg).is_none());
+
+        // No client or server ID
+        let mut msg = XID.to_be_bytes().to_vec();
+        msg[0] = msg_type::REPLY;
+        push_option(&mut msg, option::SERVERID, &SERVER_DUID);
+        assert!(decode_message(&DUID, XID, &msg).is_none());
+        let mut msg = XID.to_be_bytes().to_vec();
+        msg[0] = msg_type::REPLY;
+        push_option(&mut msg, option::CLIENTID, &DUID);
+        assert!(decode_message(&DUID, XID, &msg).is_none());
+
+        // An error status
+        let mut options = Vec::new();
+        push_option(&mut options, option::STATUS_CODE, &[0, 1, b'n', b'o']);
+        assert!(decode_message(&DUID, XID, &reply(&options)).is_none());
+
+        // An option running past the end of the message
+        let msg = reply(&[0, 23, 0, 32, 0, 0]);
+        assert!(decode_message(&DUID, XID, &msg).is_none());
+    }
+}
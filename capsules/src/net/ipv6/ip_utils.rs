@@ -111,6 +111,36 @@ impl IPAddr {
     pub fn is_multicast(&self) -> bool {
         self.0[0] == 0xff
     }
+
+    /// Returns the link-local all-nodes multicast address (ff02::1)
+    pub fn all_nodes() -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = 0xff;
+        addr.0[1] = 0x02;
+        addr.0[15] = 0x01;
+        addr
+    }
+
+    /// Returns the link-local all-routers multicast address (ff02::2)
+    pub fn all_routers() -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = 0xff;
+        addr.0[1] = 0x02;
+        addr.0[15] = 0x02;
+        addr
+    }
+
+    /// Returns the solicited-node multicast address (ff02::1:ffXX:XXXX)
+    /// corresponding to this address, as defined in RFC 4291 section 2.7.1
+    pub fn solicited_node(&self) -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = 0xff;
+        addr.0[1] = 0x02;
+        addr.0[11] = 0x01;
+        addr.0[12] = 0xff;
+        addr.0[13..16].copy_from_slice(&self.0[13..16]);
+        addr
+    }
 }
 
 pub fn compute_udp_checksum(
@@ -205,6 +235,20 @@ pub fn compute_icmp_checksum(
             sum += id as u32;
             sum += seqno as u32;
         }
+        ICMP6HeaderOptions::Type133 { reserved }
+        | ICMP6HeaderOptions::Type135 { reserved }
+        | ICMP6HeaderOptions::Type136 { flags: reserved } => {
+            sum += reserved >> 16;
+            sum += reserved & 0xffff;
+        }
+        ICMP6HeaderOptions::Type134 {
+            hop_limit,
+            flags,
+            lifetime,
+        } => {
+            sum += ((hop_limit as u32) << 8) + flags as u32;
+            sum += lifetime as u32;
+        }
     }
 
     // add icmp payload
@@ -215,7 +259,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
@@ -241,7 +285,7 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
         i += 2;
     }
 
-    sum += ip6_header.payload_len as u32;
+    sum += ip6_header.get_payload_len() as u32;
     sum += ip6_header.next_header as u32;
 
     sum
@@ -253,7 +297,12 @@ pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut i: usize = 0;
     while i < (len as usize) {
         let msb = (buf[i] as u32) << 8;
-        let lsb = buf[i + 1] as u32;
+        // An odd-length buffer is padded with a zero byte, as per RFC 1071
+        let lsb = if i + 1 < len as usize {
+            buf[i + 1] as u32
+        } else {
+            0
+        };
         sum += msb + lsb;
         i += 2;
     }
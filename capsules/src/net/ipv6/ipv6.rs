@@ -264,6 +264,9 @@ impl IP6Header {
     pub fn check_transport_checksum(&self, buf: &[u8]) -> ReturnCode {
         match self.next_header {
             ip6_nh::UDP => {
+                if buf.len() < UDP_HDR_LEN {
+                    return ReturnCode::FAIL;
+                }
                 let mut udp_header: [u8; UDP_HDR_LEN] = [0; UDP_HDR_LEN];
                 udp_header.copy_from_slice(&buf[..UDP_HDR_LEN]);
                 let checksum = match UDPHeader::decode(&udp_header).done() {
@@ -281,17 +284,20 @@ impl IP6Header {
                 ReturnCode::SUCCESS
             }
             ip6_nh::ICMP => {
-                // Untested (10/5/18)
-                let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
-                icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
+                if buf.len() < ICMP_HDR_LEN {
+                    return ReturnCode::FAIL;
+                }
+                // Unlike the UDP checksum computation, `compute_icmp_checksum`
+                // does not include the checksum field itself, so the result
+                // is compared against the received checksum.
+                let valid = match ICMP6Header::decode(&buf[..ICMP_HDR_LEN]).done() {
                     Some((_offset, mut hdr)) => {
                         hdr.set_len(buf.len() as u16);
-                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
+                        compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]) == hdr.get_cksum()
                     }
-                    None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
+                    None => false, // Unsupported ICMPv6 message type
                 };
-                if checksum != 0 {
+                if !valid {
                     return ReturnCode::FAIL; //Incorrect cksum
                 }
                 ReturnCode::SUCCESS
@@ -0,0 +1,559 @@
+//! This file contains an IPv6 link layer for an Ethernet interface, such as a
+//! USB CDC-ECM link to a laptop, as an alternative to 6LoWPAN. The
+//! [IP6EthernetInterface](struct.IP6EthernetInterface.html) implements
+//! `IP6Sender`, so the UDP layer and the userspace UDP driver run on top of
+//! it unchanged.
+//!
+//! Packets are carried uncompressed in Ethernet II frames. Next hops are
+//! resolved with Neighbor Solicitations (RFC 4861), and the interface
+//! answers Neighbor Solicitations for its own addresses: the link-local
+//! address derived from its MAC address and the address set with
+//! `set_addr`. Received packets for these addresses or the all-nodes address
+//! are passed to the `IP6RecvStruct`, which dispatches them to the UDP layer
+//! and, through a `MuxIcmp6Receiver`, to ICMPv6 clients. The interface
+//! receives Neighbor Discovery messages as one of these clients.
+//!
+//! The interface also implements `ICMP6Sender`, so that an
+//! `ICMP6EchoResponder` can answer echo requests. Its messages share the
+//! frame that Neighbor Discovery messages are sent from, so `send` fails
+//! while that frame is in use. They can only be sent to multicast
+//! addresses, to known neighbors, and to the sender of the packet being
+//! received.
+//!
+//! All destinations are considered on-link; there is no default router. The
+//! interface holds a single outgoing packet at a time; a packet whose next
+//! hop cannot be resolved after `NS_MAX_ATTEMPTS` solicitations completes
+//! with ENOACK.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ip6 = static_init!(
+//!     IP6EthernetInterface<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     IP6EthernetInterface::new(
+//!         ip_eth_user,
+//!         nd_alarm,
+//!         MacAddr([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]),
+//!         ip6_packet,
+//!         &mut IP6_TX_BUF,
+//!         &mut IP6_CTL_BUF,
+//!         ip_vis,
+//!     )
+//! );
+//! ip_eth_user.set_transmit_client(ip6);
+//! ip_eth_user.set_receive_client(ip6);
+//! nd_alarm.set_alarm_client(ip6);
+//! ip6.set_receiver(ip_receive);
+//! ip6.set_addr(ip6.link_local_addr());
+//!
+//! let icmp_recv_mux = static_init!(MuxIcmp6Receiver<'static>, MuxIcmp6Receiver::new());
+//! ip_receive.set_icmp_client(icmp_recv_mux);
+//! let nd_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+//! nd_recv.set_client(ip6);
+//! icmp_recv_mux.add_client(nd_recv);
+//!
+//! let echo_responder = static_init!(
+//!     ICMP6EchoResponder<'static>,
+//!     ICMP6EchoResponder::new(ip6, LeasableBuffer::new(&mut ECHO_BUF), net_cap)
+//! );
+//! ICMP6Sender::set_client(ip6, echo_responder);
+//! let echo_recv = static_init!(ICMP6Receiver<'static>, ICMP6Receiver::new());
+//! echo_recv.set_client(echo_responder);
+//! icmp_recv_mux.add_client(echo_recv);
+//! ```
+
+use crate::ethernet::virtual_ethernet::{EthernetRxClient, EthernetTxClient, EthernetUser};
+use crate::net::ethernet::{ethertype, EthernetHeader, MacAddr};
+use crate::net::icmpv6::icmpv6_nd::{for_each_option, na_flags, option_type};
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{compute_icmp_checksum, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::IP6RecvStruct;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader, ICMP_HDR_LEN};
+use crate::net::link::{DataState, EthernetLink, NeighborCache};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// Time to wait for a Neighbor Advertisement before retrying.
+pub const NS_RETRY_MS: u32 = 1000;
+/// Number of Neighbor Solicitations sent before giving up on a next hop.
+pub const NS_MAX_ATTEMPTS: u8 = 3;
+
+const IP6_HEADER_LEN: usize = 40;
+
+/// Length of the body of a Neighbor Solicitation or Advertisement sent by
+/// the interface: the target address and a link-layer address option.
+const ND_BODY_LEN: usize = 24;
+
+/// Returns the Ethernet multicast address that IPv6 multicast address `addr`
+/// maps to (RFC 2464 section 7).
+fn multicast_mac(addr: IPAddr) -> MacAddr {
+    MacAddr([0x33, 0x33, addr.0[12], addr.0[13], addr.0[14], addr.0[15]])
+}
+
+/// Builds the body of a Neighbor Discovery message for `target`, carrying
+/// `mac` in an option of type `opt_type`.
+fn nd_body(target: IPAddr, opt_type: u8, mac: MacAddr) -> [u8; ND_BODY_LEN] {
+    let mut body = [0; ND_BODY_LEN];
+    body[0..16].copy_from_slice(&target.0);
+    body[16] = opt_type;
+    body[17] = 1;
+    body[18..24].copy_from_slice(&mac.0);
+    body
+}
+
+/// Returns the link-layer address carried in option `opt_type` of a
+/// Neighbor Discovery message, if any.
+fn ll_addr_option(options: &[u8], opt_type: u8) -> Option<MacAddr> {
+    let mut mac = None;
+    let valid = for_each_option(options, |option, opt| {
+        if option == opt_type && opt.len() >= 8 {
+            let mut addr = MacAddr::new();
+            addr.0.copy_from_slice(&opt[2..8]);
+            mac = Some(addr);
+        }
+    });
+    if valid {
+        mac
+    } else {
+        None
+    }
+}
+
+pub struct IP6EthernetInterface<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    link_local: IPAddr,
+    addr: Cell<IPAddr>,
+    neighbors: NeighborCache<IPAddr>,
+    // Packet the transport layer payload is assembled in
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    // Sends the outgoing packet of the transport layer, and Neighbor
+    // Discovery messages and the messages of the ICMPv6 client
+    link: EthernetLink<'a, IPAddr>,
+    // Sender of the packet being received, which ICMPv6 replies can be
+    // sent to without resolving its address
+    rx_src: OptionalCell<(IPAddr, MacAddr)>,
+    // Buffer of the ICMPv6 client's message while it is being sent
+    icmp_buf: MapCell<LeasableBuffer<'static, u8>>,
+    client: OptionalCell<&'a dyn IP6SendClient>,
+    icmp_client: OptionalCell<&'a dyn ICMP6SendClient>,
+    receiver: OptionalCell<&'a IP6RecvStruct<'a>>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a, A: Alarm<'a>> IP6EthernetInterface<'a, A> {
+    pub fn new(
+        eth: &'a EthernetUser<'a>,
+        alarm: &'a A,
+        mac: MacAddr,
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+        ctl_buf: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP6EthernetInterface<'a, A> {
+        // Modified EUI-64 interface identifier (RFC 4291 appendix A)
+        let mut link_local = IPAddr::new();
+        link_local.set_unicast_link_local();
+        link_local.0[8] = mac.0[0] ^ 0x02;
+        link_local.0[9..11].copy_from_slice(&mac.0[1..3]);
+        link_local.0[11] = 0xff;
+        link_local.0[12] = 0xfe;
+        link_local.0[13..16].copy_from_slice(&mac.0[3..6]);
+
+        IP6EthernetInterface {
+            alarm: alarm,
+            link_local: link_local,
+            addr: Cell::new(link_local),
+            neighbors: NeighborCache::new(),
+            ip6_packet: TakeCell::new(ip6_packet),
+            link: EthernetLink::new(eth, mac, ethertype::IPV6, tx_buf, ctl_buf),
+            rx_src: OptionalCell::empty(),
+            icmp_buf: MapCell::empty(),
+            client: OptionalCell::empty(),
+            icmp_client: OptionalCell::empty(),
+            receiver: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+
+    /// Sets the receiver that packets for this interface are passed to.
+    pub fn set_receiver(&self, receiver: &'a IP6RecvStruct<'a>) {
+        self.receiver.set(receiver);
+    }
+
+    /// Returns the link-local address derived from the MAC address.
+    pub fn link_local_addr(&self) -> IPAddr {
+        self.link_local
+    }
+
+    pub fn get_mac_address(&self) -> MacAddr {
+        self.link.get_mac_address()
+    }
+
+    fn is_own_addr(&self, addr: IPAddr) -> bool {
+        addr == self.link_local || addr == self.addr.get()
+    }
+
+    fn is_local_dst(&self, dst: IPAddr) -> bool {
+        self.is_own_addr(dst)
+            || dst == IPAddr::all_nodes()
+            || dst == self.link_local.solicited_node()
+            || dst == self.addr.get().solicited_node()
+    }
+
+    /// Returns the source address for packets sent to `dst`.
+    fn src_addr_for(&self, dst: IPAddr) -> IPAddr {
+        if dst.is_unicast_link_local() {
+            self.link_local
+        } else {
+            self.addr.get()
+        }
+    }
+
+    /// Returns the link-layer address ICMPv6 messages for `dst` are sent to,
+    /// if it is known without Neighbor Discovery.
+    fn icmp_dst_mac(&self, dst: IPAddr) -> Option<MacAddr> {
+        if dst.is_multicast() {
+            return Some(multicast_mac(dst));
+        }
+        self.neighbors.lookup(dst).or_else(|| {
+            self.rx_src
+                .map(|rx_src| *rx_src)
+                .filter(|(src, _)| *src == dst)
+                .map(|(_, mac)| mac)
+        })
+    }
+
+    /// Queues the ICMPv6 message made of `icmp_header` and `body` in the
+    /// control frame. Returns EBUSY if the control frame is in use.
+    fn queue_icmp(
+        &self,
+        dst: IPAddr,
+        dst_mac: MacAddr,
+        mut icmp_header: ICMP6Header,
+        body: &[u8],
+    ) -> ReturnCode {
+        let icmp_len = ICMP_HDR_LEN + body.len();
+        let mut ip_header = IP6Header::new();
+        ip_header.src_addr = self.src_addr_for(dst);
+        ip_header.dst_addr = dst;
+        ip_header.set_next_header(ip6_nh::ICMP);
+        ip_header.set_payload_len(icmp_len as u16);
+        icmp_header.set_len(icmp_len as u16);
+        icmp_header.set_cksum(compute_icmp_checksum(&ip_header, &icmp_header, body));
+
+        self.link.queue_control(dst_mac, ethertype::IPV6, |buf| {
+            let len = IP6_HEADER_LEN + icmp_len;
+            if buf.len() < len {
+                return None;
+            }
+            let _ = ip_header.encode(buf);
+            let _ = icmp_header.encode(buf, IP6_HEADER_LEN);
+            buf[IP6_HEADER_LEN + ICMP_HDR_LEN..len].copy_from_slice(body);
+            Some(len)
+        })
+    }
+
+    /// Sends a Neighbor Solicitation for `target`.
+    fn send_ns(&self, target: IPAddr) -> ReturnCode {
+        let dst = target.solicited_node();
+        let body = nd_body(target, option_type::SOURCE_LL_ADDR, self.get_mac_address());
+        let header = ICMP6Header::new(ICMP6Type::Type135);
+        let result = self.queue_icmp(dst, multicast_mac(dst), header, &body);
+        if result == ReturnCode::SUCCESS {
+            self.transmit_next_async();
+        }
+        result
+    }
+
+    /// Answers a Neighbor Solicitation for `target` from `src`.
+    fn send_na(&self, src: IPAddr, src_mac: MacAddr, target: IPAddr) -> ReturnCode {
+        // Solicitations from an unspecified address are answered to all nodes
+        let (dst, dst_mac, flags) = if src.is_unspecified() {
+            let dst = IPAddr::all_nodes();
+            (dst, multicast_mac(dst), na_flags::OVERRIDE)
+        } else {
+            (src, src_mac, na_flags::SOLICITED | na_flags::OVERRIDE)
+        };
+        let body = nd_body(target, option_type::TARGET_LL_ADDR, self.get_mac_address());
+        let mut header = ICMP6Header::new(ICMP6Type::Type136);
+        header.set_options(ICMP6HeaderOptions::Type136 { flags });
+        let result = self.queue_icmp(dst, dst_mac, header, &body);
+        if result == ReturnCode::SUCCESS {
+            self.transmit_next_async();
+        }
+        result
+    }
+
+    /// Passes the next frame to the link, and reports failure of the
+    /// outgoing packet to the client.
+    fn transmit_next_async(&self) {
+        let result = self.link.transmit_next();
+        if result != ReturnCode::SUCCESS {
+            self.client.map(|client| client.send_done(result));
+        }
+    }
+
+    /// Sends the outgoing packet if it waits for the link-layer address of
+    /// `ip`.
+    fn resolved(&self, ip: IPAddr, mac: MacAddr) {
+        if let DataState::Resolving { next_hop, .. } = self.link.data_state() {
+            if next_hop == ip {
+                self.alarm.disarm();
+                self.link.set_data_dst(mac);
+                self.transmit_next_async();
+            }
+        }
+    }
+
+    fn receive_ns(&self, src: IPAddr, src_mac: MacAddr, target: IPAddr, options: &[u8]) {
+        if !self.is_own_addr(target) {
+            return;
+        }
+        let src_mac = ll_addr_option(options, option_type::SOURCE_LL_ADDR).unwrap_or(src_mac);
+        if !src.is_unspecified() {
+            self.neighbors.insert(src, src_mac);
+            self.resolved(src, src_mac);
+        }
+        // A solicitation that arrives while the control frame is in use is
+        // not answered; the soliciting node retransmits it
+        let _ = self.send_na(src, src_mac, target);
+    }
+
+    fn receive_na(&self, src_mac: MacAddr, target: IPAddr, options: &[u8]) {
+        let target_mac = ll_addr_option(options, option_type::TARGET_LL_ADDR).unwrap_or(src_mac);
+        let resolving = match self.link.data_state() {
+            DataState::Resolving { next_hop, .. } => next_hop == target,
+            _ => false,
+        };
+        if resolving {
+            self.neighbors.insert(target, target_mac);
+            self.resolved(target, target_mac);
+        } else {
+            self.neighbors.refresh(target, target_mac);
+        }
+    }
+
+    fn receive_ipv6(&self, src_mac: MacAddr, payload: &[u8]) {
+        let ip_header = match IP6Header::decode(payload).done() {
+            Some((_, ip_header)) => ip_header,
+            None => return,
+        };
+        // Frames may be padded beyond the end of the packet
+        let total_len = IP6_HEADER_LEN + ip_header.get_payload_len() as usize;
+        if ip_header.get_version() != 6 || payload.len() < total_len {
+            return;
+        }
+        if !self.is_local_dst(ip_header.get_dst_addr()) {
+            return;
+        }
+        let src = ip_header.get_src_addr();
+        self.neighbors.refresh(src, src_mac);
+        self.rx_src.set((src, src_mac));
+        self.receiver
+            .map(|receiver| receiver.receive_packet(payload, total_len));
+        self.rx_src.clear();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6Sender<'a> for IP6EthernetInterface<'a, A> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.addr.set(src_addr);
+    }
+
+    /// Next hops are resolved with Neighbor Discovery, so the 802.15.4
+    /// gateway address is ignored.
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, ip6_header: IP6Header) {
+        self.ip6_packet
+            .map(|ip6_packet| ip6_packet.header = ip6_header);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return ReturnCode::FAIL;
+        }
+        if self.link.data_state() != DataState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if dst.is_unspecified() {
+            return ReturnCode::EINVAL;
+        }
+
+        let src = self.src_addr_for(dst);
+        let result = self.ip6_packet.map_or(ReturnCode::EBUSY, |ip6_packet| {
+            if payload.len() > ip6_packet.payload.payload.len() {
+                return ReturnCode::ESIZE;
+            }
+            ip6_packet.header = IP6Header::default();
+            ip6_packet.header.src_addr = src;
+            ip6_packet.header.dst_addr = dst;
+            ip6_packet.set_payload(transport_header, payload);
+            ip6_packet.set_transport_checksum();
+
+            self.link.write_data(|buf| {
+                let total_len = ip6_packet.get_total_len() as usize;
+                if total_len > buf.len() {
+                    return Err(ReturnCode::ESIZE);
+                }
+                match ip6_packet.encode(buf).done() {
+                    Some(_) => Ok(total_len),
+                    None => Err(ReturnCode::ESIZE),
+                }
+            })
+        });
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        if dst.is_multicast() {
+            self.link.set_data_dst(multicast_mac(dst));
+        } else {
+            match self.neighbors.lookup(dst) {
+                Some(mac) => self.link.set_data_dst(mac),
+                None => {
+                    let result = self.send_ns(dst);
+                    if result != ReturnCode::SUCCESS {
+                        return result;
+                    }
+                    self.link.set_data_state(DataState::Resolving {
+                        next_hop: dst,
+                        attempts: 1,
+                    });
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(NS_RETRY_MS));
+                    return ReturnCode::SUCCESS;
+                }
+            }
+        }
+        self.link.transmit_next()
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6Sender<'a> for IP6EthernetInterface<'a, A> {
+    fn set_client(&self, client: &'a dyn ICMP6SendClient) {
+        self.icmp_client.set(client);
+    }
+
+    /// Queues the message in the control frame. The buffer is returned if
+    /// the control frame is in use or the link-layer address of `dest` is
+    /// not known.
+    fn send(
+        &'a self,
+        dest: IPAddr,
+        icmp_header: ICMP6Header,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if !net_cap.remote_addr_valid(dest, self.ip_vis) || self.icmp_buf.is_some() {
+            return Err(buf);
+        }
+        let dst_mac = match self.icmp_dst_mac(dest) {
+            Some(mac) => mac,
+            None => return Err(buf),
+        };
+        if self.queue_icmp(dest, dst_mac, icmp_header, &buf[..]) != ReturnCode::SUCCESS {
+            return Err(buf);
+        }
+        // Held until the control frame is sent
+        self.icmp_buf.replace(buf);
+        self.transmit_next_async();
+        Ok(())
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for IP6EthernetInterface<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        let src_mac = match self.rx_src.map(|(_, mac)| *mac) {
+            Some(mac) => mac,
+            // Not received on this interface
+            None => return,
+        };
+        // RFC 4861 section 7.1: Neighbor Discovery messages must not have
+        // been forwarded
+        if ip_header.get_hop_limit() != 255 || icmp_header.get_code() != 0 || payload.len() < 16 {
+            return;
+        }
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&payload[0..16]);
+        match icmp_header.get_type() {
+            ICMP6Type::Type135 => {
+                self.receive_ns(ip_header.get_src_addr(), src_mac, target, &payload[16..])
+            }
+            ICMP6Type::Type136 => self.receive_na(src_mac, target, &payload[16..]),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> EthernetTxClient for IP6EthernetInterface<'a, A> {
+    fn send_done(&self, result: ReturnCode, frame: &'static mut [u8]) {
+        if self.link.send_done(frame) {
+            self.client.map(|client| client.send_done(result));
+        } else {
+            // The control frame held either a Neighbor Discovery message or
+            // the ICMPv6 client's message
+            self.icmp_buf.take().map(|buf| {
+                self.icmp_client
+                    .map(move |client| client.send_done(result, buf));
+            });
+        }
+        self.transmit_next_async();
+    }
+}
+
+impl<'a, A: Alarm<'a>> EthernetRxClient for IP6EthernetInterface<'a, A> {
+    fn receive(&self, frame: &[u8]) {
+        let (off, header) = match EthernetHeader::decode(frame).done() {
+            Some(result) => result,
+            None => return,
+        };
+        if header.dst != self.get_mac_address() && !header.dst.is_multicast() {
+            return;
+        }
+        if header.ethertype == ethertype::IPV6 {
+            self.receive_ipv6(header.src, &frame[off..]);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for IP6EthernetInterface<'a, A> {
+    fn alarm(&self) {
+        if let DataState::Resolving { next_hop, attempts } = self.link.data_state() {
+            if attempts < NS_MAX_ATTEMPTS {
+                self.link.set_data_state(DataState::Resolving {
+                    next_hop: next_hop,
+                    attempts: attempts + 1,
+                });
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(NS_RETRY_MS));
+                // If the control frame is in use, the solicitation is sent
+                // on the next attempt
+                let _ = self.send_ns(next_hop);
+            } else {
+                self.link.set_data_state(DataState::Idle);
+                self.client
+                    .map(|client| client.send_done(ReturnCode::ENOACK));
+            }
+        }
+    }
+}
@@ -1,7 +1,8 @@
+use crate::net::ipv6::ip_utils::ip6_nh;
 use crate::net::ipv6::IP6Header;
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
-use kernel::debug;
 use kernel::ReturnCode;
 
 // To provide some context for the entire rx chain:
@@ -21,8 +22,9 @@ use kernel::ReturnCode;
 - The udp_mac MacUser has a single receive client, which is the `sixlowpan_state` struct
 - `sixlowpan_state` has a single rx_client, which in our case is a single struct that
   implements the `ip_receive ` trait.
-- the `ip_receive` implementing struct (`IP6RecvStruct`) has a single client, which is
-  udp_recv, a `UDPReceive` struct.
+- the `ip_receive` implementing struct (`IP6RecvStruct`) has a default client, which is
+  udp_recv, a `UDPReceive` struct. ICMPv6 packets are instead passed to a separate
+  ICMPv6 client (`MuxIcmp6Receiver`) if one has been set.
 - The UDPReceive struct is a field of the UDPDriver, which ultimately passes the
   packets up to userland.
 */
@@ -38,49 +40,93 @@ pub trait IP6RecvClient {
 /// that are not among the local addresses of this device.
 pub trait IP6Receiver<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient);
+
+    /// Sets the client that receives ICMPv6 packets. If no ICMPv6 client is
+    /// set, ICMPv6 packets are passed to the default client instead.
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient);
 }
 
 pub struct IP6RecvStruct<'a> {
     client: OptionalCell<&'a dyn IP6RecvClient>,
+    icmp_client: OptionalCell<&'a dyn IP6RecvClient>,
+    dropped: Cell<usize>,
 }
 
 impl<'a> IP6Receiver<'a> for IP6RecvStruct<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient) {
         self.client.set(client);
     }
+
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.icmp_client.set(client);
+    }
 }
 
 impl<'a> IP6RecvStruct<'a> {
     pub fn new() -> IP6RecvStruct<'a> {
         IP6RecvStruct {
             client: OptionalCell::empty(),
+            icmp_client: OptionalCell::empty(),
+            dropped: Cell::new(0),
         }
     }
-}
 
-impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
-    fn receive(&self, buf: &[u8], len: usize, result: ReturnCode) {
-        // TODO: Drop here?
-        if len > buf.len() || result != ReturnCode::SUCCESS {
+    /// Returns the number of received packets that were dropped because they
+    /// were malformed or failed checksum verification.
+    pub fn dropped_count(&self) -> usize {
+        self.dropped.get()
+    }
+
+    fn drop_packet(&self) {
+        self.dropped.set(self.dropped.get().wrapping_add(1));
+    }
+
+    /// Passes a complete, decompressed IPv6 packet of `len` bytes up the
+    /// stack. This is used by the 6LoWPAN layer, and can also be used by
+    /// link layers that carry uncompressed IPv6 packets.
+    pub fn receive_packet(&self, buf: &[u8], len: usize) {
+        if len > buf.len() {
+            self.drop_packet();
             return;
         }
-        match IP6Header::decode(buf).done() {
+        match IP6Header::decode(&buf[..len]).done() {
             Some((offset, ip6_header)) => {
-                let checksum_result = ip6_header.check_transport_checksum(&buf[offset..len]);
+                if ip6_header.get_version() != 6
+                    || offset + ip6_header.get_payload_len() as usize > len
+                {
+                    self.drop_packet();
+                    return;
+                }
+                let end = offset + ip6_header.get_payload_len() as usize;
+                let checksum_result = ip6_header.check_transport_checksum(&buf[offset..end]);
                 if checksum_result == ReturnCode::FAIL {
-                    debug!("cksum fail!: {:?}", checksum_result);
-                    return; //Dropped.
+                    self.drop_packet();
+                    return;
                 }
                 // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
                 // are automatically assumed as fine, rather than dropped
 
-                self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                let client =
+                    if ip6_header.get_next_header() == ip6_nh::ICMP && self.icmp_client.is_some() {
+                        &self.icmp_client
+                    } else {
+                        &self.client
+                    };
+                client.map(|client| client.receive(ip6_header, &buf[offset..end]));
             }
             None => {
-                debug!("failed to decode ipv6 header");
-                // TODO: Report the error somewhere...
+                self.drop_packet();
             }
         }
     }
 }
+
+impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
+    fn receive(&self, buf: &[u8], len: usize, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.drop_packet();
+            return;
+        }
+        self.receive_packet(buf, len);
+    }
+}
@@ -101,7 +101,6 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     tx_buf: TakeCell<'static, [u8]>,
     sixlowpan: TxState<'a>,
     radio: &'a dyn MacDevice<'a>,
-    dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
     ip_vis: &'static IpVisibilityCapability,
@@ -135,12 +134,15 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         if !net_cap.remote_addr_valid(dst, self.ip_vis) {
             return ReturnCode::FAIL;
         }
-        self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
+        // Multicast packets are sent to the 802.15.4 broadcast address, all
+        // other packets to the gateway (next hop)
+        let dst_mac_addr = if dst.is_multicast() {
+            MacAddress::Short(0xffff)
+        } else {
+            self.gateway.get()
+        };
+        self.sixlowpan
+            .init(self.src_mac_addr, dst_mac_addr, self.radio.get_pan(), None);
         self.init_packet(dst, transport_header, payload);
         let ret = self.send_next_fragment();
         ret
@@ -166,7 +168,6 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             tx_buf: TakeCell::new(tx_buf),
             sixlowpan: sixlowpan,
             radio: radio,
-            dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
             client: OptionalCell::empty(),
             ip_vis: ip_vis,
@@ -1,4 +1,6 @@
+pub mod dhcp6;
 pub mod ip_utils;
+pub mod ipv6_ethernet;
 pub mod ipv6_recv;
 pub mod ipv6_send;
 
@@ -0,0 +1,311 @@
+//! Parts of an IP layer on an Ethernet link that are shared by the IPv4
+//! ([IP4Interface](../ipv4/ipv4_interface/struct.IP4Interface.html)) and
+//! IPv6 ([IP6EthernetInterface](../ipv6/ipv6_ethernet/struct.IP6EthernetInterface.html))
+//! interfaces.
+//!
+//! [NeighborCache](struct.NeighborCache.html) is a small fixed-size cache
+//! mapping the addresses of neighbors to their link-layer addresses, filled
+//! by ARP or Neighbor Discovery. It evicts the least recently refreshed
+//! entry when it is full. Entries do not expire on their own; they are
+//! refreshed by any packet received from the neighbor, and are replaced when
+//! a neighbor announces a new link-layer address.
+//!
+//! [EthernetLink](struct.EthernetLink.html) holds the two frames an
+//! interface sends from: the outgoing packet of the transport layer, which
+//! may have to wait for its next hop to be resolved, and a control frame for
+//! address resolution messages and echo replies generated by the interface
+//! itself. Control frames take priority when the link becomes free.
+
+use crate::ethernet::virtual_ethernet::EthernetUser;
+use crate::net::ethernet::{EthernetHeader, MacAddr, ETHERNET_HEADER_LEN, MTU};
+use crate::net::ipv4::ipv4::IPv4Addr;
+use crate::net::ipv6::ip_utils::IPAddr;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::ReturnCode;
+
+/// Number of neighbors remembered by a `NeighborCache`.
+pub const NEIGHBOR_CACHE_SIZE: usize = 8;
+
+/// A network layer address that can be resolved to a link-layer address.
+pub trait NeighborAddr: Copy + PartialEq {
+    /// Returns whether this is a unicast address. Only unicast addresses are
+    /// resolved and cached.
+    fn is_unicast(&self) -> bool;
+}
+
+impl NeighborAddr for IPv4Addr {
+    fn is_unicast(&self) -> bool {
+        !self.is_unspecified() && !self.is_broadcast() && !self.is_multicast()
+    }
+}
+
+impl NeighborAddr for IPAddr {
+    fn is_unicast(&self) -> bool {
+        !self.is_unspecified() && !self.is_multicast()
+    }
+}
+
+#[derive(Copy, Clone)]
+struct NeighborEntry<Addr> {
+    ip: Addr,
+    mac: MacAddr,
+    age: u32,
+}
+
+pub struct NeighborCache<Addr: NeighborAddr> {
+    entries: [Cell<Option<NeighborEntry<Addr>>>; NEIGHBOR_CACHE_SIZE],
+    clock: Cell<u32>,
+}
+
+impl<Addr: NeighborAddr> NeighborCache<Addr> {
+    pub fn new() -> NeighborCache<Addr> {
+        NeighborCache {
+            entries: Default::default(),
+            clock: Cell::new(0),
+        }
+    }
+
+    /// Returns the link-layer address of `ip` if it is known.
+    pub fn lookup(&self, ip: Addr) -> Option<MacAddr> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.get())
+            .find(|entry| entry.ip == ip)
+            .map(|entry| entry.mac)
+    }
+
+    /// Adds or refreshes the mapping from `ip` to `mac`.
+    pub fn insert(&self, ip: Addr, mac: MacAddr) {
+        if !ip.is_unicast() || mac.is_multicast() {
+            return;
+        }
+        let age = self.clock.get().wrapping_add(1);
+        self.clock.set(age);
+        let new_entry = Some(NeighborEntry { ip, mac, age });
+
+        // Reuse the entry for this address, else a free one, else the oldest
+        let slot = self
+            .entries
+            .iter()
+            .find(|entry| entry.get().map_or(false, |e| e.ip == ip))
+            .or_else(|| self.entries.iter().find(|entry| entry.get().is_none()))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .max_by_key(|entry| entry.get().map_or(0, |e| age.wrapping_sub(e.age)))
+            });
+        slot.map(|entry| entry.set(new_entry));
+    }
+
+    /// Refreshes the entry for `ip` only if one already exists.
+    pub fn refresh(&self, ip: Addr, mac: MacAddr) {
+        if self.lookup(ip).is_some() {
+            self.insert(ip, mac);
+        }
+    }
+
+    pub fn remove(&self, ip: Addr) {
+        for entry in self.entries.iter() {
+            if entry.get().map_or(false, |e| e.ip == ip) {
+                entry.set(None);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        for entry in self.entries.iter() {
+            entry.set(None);
+        }
+    }
+}
+
+/// State of the outgoing packet of the transport layer.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DataState<Addr> {
+    Idle,
+    Resolving { next_hop: Addr, attempts: u8 },
+    Ready,
+    Sending,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Inflight {
+    Data,
+    Control,
+}
+
+pub struct EthernetLink<'a, Addr: NeighborAddr> {
+    eth: &'a EthernetUser<'a>,
+    mac: MacAddr,
+    ethertype: u16,
+    // Frame holding the outgoing packet of the transport layer
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    data_state: Cell<DataState<Addr>>,
+    // Frame holding messages generated by the interface
+    ctl_buf: TakeCell<'static, [u8]>,
+    ctl_len: Cell<usize>,
+    ctl_pending: Cell<bool>,
+    inflight: OptionalCell<Inflight>,
+}
+
+impl<'a, Addr: NeighborAddr> EthernetLink<'a, Addr> {
+    /// `ethertype` is the EtherType of the packets of the transport layer.
+    pub fn new(
+        eth: &'a EthernetUser<'a>,
+        mac: MacAddr,
+        ethertype: u16,
+        tx_buf: &'static mut [u8],
+        ctl_buf: &'static mut [u8],
+    ) -> EthernetLink<'a, Addr> {
+        EthernetLink {
+            eth,
+            mac,
+            ethertype,
+            tx_buf: TakeCell::new(tx_buf),
+            tx_len: Cell::new(0),
+            data_state: Cell::new(DataState::Idle),
+            ctl_buf: TakeCell::new(ctl_buf),
+            ctl_len: Cell::new(0),
+            ctl_pending: Cell::new(false),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    pub fn get_mac_address(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn data_state(&self) -> DataState<Addr> {
+        self.data_state.get()
+    }
+
+    pub fn set_data_state(&self, state: DataState<Addr>) {
+        self.data_state.set(state);
+    }
+
+    /// Returns the largest packet that fits in the outgoing frame.
+    pub fn max_data_len(&self) -> usize {
+        self.tx_buf
+            .map_or(MTU, |buf| cmp::min(MTU, buf.len() - ETHERNET_HEADER_LEN))
+    }
+
+    /// Writes the outgoing packet with `write`, which is passed the frame
+    /// following the Ethernet header and returns the packet length.
+    pub fn write_data<F>(&self, write: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8]) -> Result<usize, ReturnCode>,
+    {
+        self.tx_buf.map_or(ReturnCode::EBUSY, |buf| {
+            match write(&mut buf[ETHERNET_HEADER_LEN..]) {
+                Ok(len) => {
+                    self.tx_len.set(ETHERNET_HEADER_LEN + len);
+                    ReturnCode::SUCCESS
+                }
+                Err(result) => result,
+            }
+        })
+    }
+
+    /// Fills in the destination of the outgoing packet once its next hop is
+    /// known.
+    pub fn set_data_dst(&self, dst: MacAddr) {
+        self.tx_buf.map(|buf| {
+            let _ = EthernetHeader::new(dst, self.mac, self.ethertype).encode(buf);
+        });
+        self.data_state.set(DataState::Ready);
+    }
+
+    /// Queues a control frame for `dst`. `write` is passed the frame
+    /// following the Ethernet header and returns the message length, or
+    /// `None` if it does not fit. Returns EBUSY if a control frame is
+    /// already queued or being sent. The frame is sent by the next call to
+    /// `transmit_next`, and is returned to `send_done` once it is sent.
+    pub fn queue_control<F>(&self, dst: MacAddr, ethertype: u16, write: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        if self.ctl_pending.get() {
+            return ReturnCode::EBUSY;
+        }
+        let result = self.ctl_buf.map_or(ReturnCode::EBUSY, |buf| {
+            if buf.len() < ETHERNET_HEADER_LEN {
+                return ReturnCode::ESIZE;
+            }
+            let _ = EthernetHeader::new(dst, self.mac, ethertype).encode(buf);
+            match write(&mut buf[ETHERNET_HEADER_LEN..]) {
+                Some(len) => {
+                    self.ctl_len.set(ETHERNET_HEADER_LEN + len);
+                    ReturnCode::SUCCESS
+                }
+                None => ReturnCode::ESIZE,
+            }
+        });
+        if result == ReturnCode::SUCCESS {
+            self.ctl_pending.set(true);
+        }
+        result
+    }
+
+    /// Passes the control frame or the outgoing packet to the link, if it is
+    /// free. Control frames take priority. Returns the result of passing
+    /// the outgoing packet to the link, or SUCCESS if it was not attempted.
+    pub fn transmit_next(&self) -> ReturnCode {
+        if self.inflight.is_some() {
+            return ReturnCode::SUCCESS;
+        }
+        if self.ctl_pending.get() {
+            // A control frame the link rejects stays queued, and is retried
+            // the next time the link is free
+            self.ctl_buf
+                .take()
+                .map(|buf| match self.eth.transmit(buf, self.ctl_len.get()) {
+                    Ok(()) => {
+                        self.ctl_pending.set(false);
+                        self.inflight.set(Inflight::Control);
+                    }
+                    Err((_, buf)) => {
+                        self.ctl_buf.replace(buf);
+                    }
+                });
+            if self.inflight.is_some() {
+                return ReturnCode::SUCCESS;
+            }
+        }
+        if self.data_state.get() != DataState::Ready {
+            return ReturnCode::SUCCESS;
+        }
+        self.tx_buf.take().map_or(ReturnCode::FAIL, |buf| {
+            match self.eth.transmit(buf, self.tx_len.get()) {
+                Ok(()) => {
+                    self.inflight.set(Inflight::Data);
+                    self.data_state.set(DataState::Sending);
+                    ReturnCode::SUCCESS
+                }
+                Err((result, buf)) => {
+                    self.tx_buf.replace(buf);
+                    self.data_state.set(DataState::Idle);
+                    result
+                }
+            }
+        })
+    }
+
+    /// Takes back a frame returned by the link. Returns true if it held the
+    /// outgoing packet, and false if it was the control frame.
+    pub fn send_done(&self, frame: &'static mut [u8]) -> bool {
+        match self.inflight.take() {
+            Some(Inflight::Data) => {
+                self.tx_buf.replace(frame);
+                self.data_state.set(DataState::Idle);
+                true
+            }
+            _ => {
+                self.ctl_buf.replace(frame);
+                false
+            }
+        }
+    }
+}
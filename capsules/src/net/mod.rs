@@ -1,13 +1,18 @@
-//! Modules for IPv6 over 6LoWPAN stack
+//! Modules for the IPv6 over 6LoWPAN and IPv4 over Ethernet stacks
 
 pub mod frag_utils;
 pub mod sixlowpan;
 pub mod util;
 #[macro_use]
 pub mod stream;
+pub mod coap;
+pub mod dns;
+pub mod ethernet;
 pub mod icmpv6;
 pub mod ieee802154;
+pub mod ipv4;
 pub mod ipv6;
+pub mod link;
 pub mod network_capabilities;
 pub mod tcp;
 pub mod thread;
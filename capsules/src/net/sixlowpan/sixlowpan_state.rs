@@ -909,7 +909,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         let rx_state = self
             .rx_states
             .iter()
-            .find(|state| !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency()));
+            .find(|state| !state.is_busy(A::Frequency::frequency(), self.clock.now().into_u32()));
         rx_state.map_or((None, ReturnCode::ENOMEM), |state| {
             state.start_receive(
                 src_mac_addr,
@@ -977,7 +977,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         // Else find a free state
         if rx_state.is_none() {
             rx_state = self.rx_states.iter().find(|state| {
-                !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency())
+                !state.is_busy(A::Frequency::frequency(), self.clock.now().into_u32())
             });
             // Initialize new state
             rx_state.map(|state| {
@@ -3,8 +3,8 @@
 //! Implements a userspace interface for sending and receiving UDP messages.
 //! Processes use this driver to send UDP packets from a common interface
 //! and bind to UDP ports for receiving packets.
-//! Also exposes a list of interface addresses to the application (currently
-//! hard-coded).
+//! Also exposes a list of interface addresses to the application. The
+//! addresses can change at runtime, e.g. when DHCPv6 assigns one.
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::NetworkCapability;
@@ -83,7 +83,7 @@ pub struct UDPDriver<'a> {
     current_app: Cell<Option<AppId>>,
 
     /// List of IP Addresses of the interfaces on the device
-    interface_list: &'static [IPAddr],
+    interface_list: &'static [Cell<IPAddr>],
 
     /// Maximum length payload that an app can transmit via this driver
     max_tx_pyld_len: usize,
@@ -102,7 +102,7 @@ impl<'a> UDPDriver<'a> {
     pub fn new(
         sender: &'a dyn UDPSender<'a>,
         grant: Grant<App>,
-        interface_list: &'static [IPAddr],
+        interface_list: &'static [Cell<IPAddr>],
         max_tx_pyld_len: usize,
         port_table: &'static UdpPortManager,
         kernel_buffer: LeasableBuffer<'static, u8>,
@@ -487,7 +487,7 @@ impl<'a> Driver for UDPDriver<'a> {
                 let iface_size = mem::size_of::<IPAddr>();
                 for i in 0..n_ifaces_to_copy {
                     cfg[i * iface_size..(i + 1) * iface_size]
-                        .copy_from_slice(&self.interface_list[i].0);
+                        .copy_from_slice(&self.interface_list[i].get().0);
                 }
                 // Returns total number of interfaces
                 ReturnCode::SuccessWithValue {
@@ -559,7 +559,7 @@ impl<'a> Driver for UDPDriver<'a> {
                         // Check that requested addr is a local interface
                         let mut requested_is_local = false;
                         for i in 0..self.interface_list.len() {
-                            if requested_addr.addr == self.interface_list[i] {
+                            if requested_addr.addr == self.interface_list[i].get() {
                                 requested_is_local = true;
                             }
                         }
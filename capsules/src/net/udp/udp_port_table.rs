@@ -61,6 +61,16 @@ pub trait PortQuery {
     fn is_bound(&self, port: u16) -> bool;
 }
 
+/// A PortQuery for network stacks without a userspace UDP driver, which
+/// reports that no ports are bound by apps.
+pub struct NoUserPorts;
+
+impl PortQuery for NoUserPorts {
+    fn is_bound(&self, _port: u16) -> bool {
+        false
+    }
+}
+
 /// A UdpSocket provides a handle into the bound port table. When binding to
 /// a port, the socket is consumed and Udp{Sender, Receiver}Binding structs are returned. When
 /// undbinding, the socket is returned and can be used to bind to other ports.
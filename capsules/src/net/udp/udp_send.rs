@@ -69,6 +69,11 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
                     debug!("No buffer available to take.");
                     ReturnCode::FAIL
                 }
+            };
+            // The lower layer does not call `send_done` for a send that
+            // failed synchronously, so the caller must not block the queue.
+            if ret != ReturnCode::SUCCESS {
+                self.sender_list.pop_head();
             }
         } else {
             caller.net_cap.replace(net_cap); //store capability with sender
@@ -1,4 +1,16 @@
-//! Utility functions used in the 6LoWPAN implementation
+//! Utilities shared by the network stack
+//!
+//! Prefix matching and byte order helpers used in the 6LoWPAN
+//! implementation, and `SecondsTimer`, which times the lease and
+//! registration lifetimes of DHCPv4, DHCPv6 and Neighbor Discovery.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::hil::time::Alarm;
+
+/// The longest interval programmed into an alarm at once by `SecondsTimer`,
+/// in seconds.
+const MAX_ALARM_INTERVAL: u32 = 60;
 
 /// Verifies that a prefix given in the form of a byte array slice is valid with
 /// respect to its length in bits (prefix_len):
@@ -64,3 +76,52 @@ pub fn u16_to_network_slice(short: u16, slice: &mut [u8]) {
     slice[0] = (short >> 8) as u8;
     slice[1] = (short & 0xff) as u8;
 }
+
+/// A timer for intervals in seconds, such as protocol lifetimes, that may be
+/// longer than an alarm can count. The interval is split into alarms of at
+/// most `MAX_ALARM_INTERVAL` seconds.
+///
+/// The timer does not own the alarm: the owner passes its alarm in and calls
+/// `fired()` from its `AlarmClient::alarm()` callback.
+pub struct SecondsTimer {
+    length: Cell<u32>,
+    remaining: Cell<u32>,
+}
+
+impl SecondsTimer {
+    pub const fn new() -> SecondsTimer {
+        SecondsTimer {
+            length: Cell::new(0),
+            remaining: Cell::new(0),
+        }
+    }
+
+    /// Starts a timer for `seconds`, replacing any running one.
+    pub fn start<'a, A: Alarm<'a>>(&self, alarm: &A, seconds: u32) {
+        self.length.set(seconds);
+        self.remaining.set(seconds);
+        self.arm_next(alarm);
+    }
+
+    /// Handles an alarm. Returns true once the whole interval has elapsed,
+    /// and otherwise arms the alarm for the next part of it.
+    pub fn fired<'a, A: Alarm<'a>>(&self, alarm: &A) -> bool {
+        if self.remaining.get() > 0 {
+            self.arm_next(alarm);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// The length of the last timer started, in seconds.
+    pub fn length(&self) -> u32 {
+        self.length.get()
+    }
+
+    fn arm_next<'a, A: Alarm<'a>>(&self, alarm: &A) {
+        let interval = cmp::min(self.remaining.get(), MAX_ALARM_INTERVAL);
+        self.remaining.set(self.remaining.get() - interval);
+        alarm.set_alarm(alarm.now(), A::ticks_from_ms(interval * 1000));
+    }
+}
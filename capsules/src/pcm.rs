@@ -0,0 +1,488 @@
+//! Provides userspace access to a PCM audio stream.
+//!
+//! One application at a time can stream samples through a
+//! `hil::i2s::I2s` controller, playing from and capturing into ring
+//! buffers it shares with the kernel. The kernel copies between the rings
+//! and a set of kernel buffers that it keeps queued on the controller, so
+//! the stream keeps running as long as the application keeps up with it.
+//!
+//! Each ring starts with two little-endian 32-bit byte offsets into the
+//! data that follows them: the offset of the next byte to read, then the
+//! offset of the next byte to write. The ring is empty when the offsets are
+//! equal, and holds at most one byte less than the data area. The
+//! application advances the write offset of the playback ring and the read
+//! offset of the capture ring, the kernel the other two.
+//!
+//! When the playback ring runs out of samples the kernel plays silence, and
+//! when the capture ring is full it drops samples. Both are reported to the
+//! application with the amount of data involved.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! let pcm = static_init!(
+//!     capsules::pcm::PcmDriver<'static>,
+//!     capsules::pcm::PcmDriver::new(
+//!         &base_peripherals.i2s,
+//!         &mut capsules::pcm::BUFFERS,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::i2s::I2s::set_client(&base_peripherals.i2s, pcm);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::i2s::{self, Channels, Format, SampleWidth, StopCause};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Pcm as usize;
+
+/// Length of each kernel buffer, a whole number of frames in every format.
+pub const BUFFER_LEN: usize = 512;
+
+/// Bytes before the data of a ring.
+const HEADER_LEN: usize = 8;
+
+/// A kernel buffer, word-aligned for controllers that move it with DMA.
+#[repr(align(4))]
+pub struct Buffer(pub [u8; BUFFER_LEN]);
+
+pub static mut BUFFERS: [Buffer; 4] = [
+    Buffer([0; BUFFER_LEN]),
+    Buffer([0; BUFFER_LEN]),
+    Buffer([0; BUFFER_LEN]),
+    Buffer([0; BUFFER_LEN]),
+];
+
+/// Upcall events.
+const EVENT_PLAYED: usize = 0;
+const EVENT_CAPTURED: usize = 1;
+const EVENT_STOPPED: usize = 2;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    playback: Option<AppSlice<Shared, u8>>,
+    capture: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct PcmDriver<'a> {
+    i2s: &'a dyn i2s::I2s<'a>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+    format: OptionalCell<Format>,
+    /// Kernel buffers not queued on the controller
+    buffers: [TakeCell<'static, [u8]>; 4],
+    /// Bytes of each kernel buffer in use, a whole number of frames
+    period: Cell<usize>,
+}
+
+/// The read and write offsets of a ring with a valid header.
+fn ring_offsets(ring: &[u8]) -> (usize, usize) {
+    let capacity = ring.len() - HEADER_LEN;
+    let offset = |at: usize| {
+        let mut word = [0; 4];
+        word.copy_from_slice(&ring[at..at + 4]);
+        u32::from_le_bytes(word) as usize % capacity
+    };
+    (offset(0), offset(4))
+}
+
+/// Move whole frames from `ring` into `data`, and return how many bytes
+/// were moved.
+fn ring_read(ring: &mut [u8], data: &mut [u8], frame: usize) -> usize {
+    if ring.len() <= HEADER_LEN {
+        return 0;
+    }
+    let capacity = ring.len() - HEADER_LEN;
+    let (read, write) = ring_offsets(ring);
+    let available = (write + capacity - read) % capacity;
+    let mut len = cmp::min(available, data.len());
+    len -= len % frame;
+    for (i, byte) in data[..len].iter_mut().enumerate() {
+        *byte = ring[HEADER_LEN + (read + i) % capacity];
+    }
+    ring[0..4].copy_from_slice(&(((read + len) % capacity) as u32).to_le_bytes());
+    len
+}
+
+/// Move whole frames from `data` into `ring`, and return how many bytes
+/// were moved.
+fn ring_write(ring: &mut [u8], data: &[u8], frame: usize) -> usize {
+    if ring.len() <= HEADER_LEN {
+        return 0;
+    }
+    let capacity = ring.len() - HEADER_LEN;
+    let (read, write) = ring_offsets(ring);
+    let free = capacity - 1 - (write + capacity - read) % capacity;
+    let mut len = cmp::min(free, data.len());
+    len -= len % frame;
+    for (i, byte) in data[..len].iter().enumerate() {
+        ring[HEADER_LEN + (write + i) % capacity] = *byte;
+    }
+    ring[4..8].copy_from_slice(&(((write + len) % capacity) as u32).to_le_bytes());
+    len
+}
+
+impl<'a> PcmDriver<'a> {
+    pub fn new(
+        i2s: &'a dyn i2s::I2s<'a>,
+        buffers: &'static mut [Buffer; 4],
+        grant: Grant<App>,
+    ) -> PcmDriver<'a> {
+        let [b0, b1, b2, b3] = buffers;
+        PcmDriver {
+            i2s,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+            format: OptionalCell::empty(),
+            buffers: [
+                TakeCell::new(&mut b0.0),
+                TakeCell::new(&mut b1.0),
+                TakeCell::new(&mut b2.0),
+                TakeCell::new(&mut b3.0),
+            ],
+            period: Cell::new(BUFFER_LEN),
+        }
+    }
+
+    fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.buffers.iter().find_map(|cell| cell.take())
+    }
+
+    fn put_buffer(&self, buffer: &'static mut [u8]) {
+        if let Some(cell) = self.buffers.iter().find(|cell| cell.is_none()) {
+            cell.replace(buffer);
+        }
+    }
+
+    fn frame_bytes(&self) -> usize {
+        self.format.map_or(1, |format| format.frame_bytes())
+    }
+
+    /// Fill `buffer` from the playback ring of `app`, padding with silence.
+    fn fill(&self, app: &mut App, buffer: &mut [u8]) {
+        let frame = self.frame_bytes();
+        let taken = app
+            .playback
+            .as_mut()
+            .map_or(0, |ring| ring_read(ring.as_mut(), buffer, frame));
+        for byte in buffer[taken..].iter_mut() {
+            *byte = 0;
+        }
+        app.callback
+            .map(|mut cb| cb.schedule(EVENT_PLAYED, taken, buffer.len() - taken));
+    }
+
+    /// Copy `buffer` into the capture ring of `app`, dropping what does
+    /// not fit.
+    fn drain(&self, app: &mut App, buffer: &[u8]) {
+        let frame = self.frame_bytes();
+        let added = app
+            .capture
+            .as_mut()
+            .map_or(0, |ring| ring_write(ring.as_mut(), buffer, frame));
+        app.callback
+            .map(|mut cb| cb.schedule(EVENT_CAPTURED, added, buffer.len() - added));
+    }
+
+    fn configure(&self, sample_rate: usize, encoding: usize) -> ReturnCode {
+        if self.current_app.is_some() || self.i2s.is_running() {
+            return ReturnCode::EBUSY;
+        }
+        let width = match encoding & 0xff {
+            8 => SampleWidth::Bits8,
+            16 => SampleWidth::Bits16,
+            24 => SampleWidth::Bits24,
+            _ => return ReturnCode::EINVAL,
+        };
+        let channels = match (encoding >> 8) & 0xff {
+            0 => Channels::Stereo,
+            1 => Channels::Left,
+            2 => Channels::Right,
+            _ => return ReturnCode::EINVAL,
+        };
+        let format = Format {
+            width,
+            channels,
+            sample_rate: sample_rate as u32,
+        };
+        match self.i2s.configure(format) {
+            Ok(rate) => {
+                let format = Format {
+                    sample_rate: rate,
+                    ..format
+                };
+                self.format.set(format);
+                self.period
+                    .set(BUFFER_LEN - BUFFER_LEN % format.frame_bytes());
+                ReturnCode::SuccessWithValue {
+                    value: rate as usize,
+                }
+            }
+            Err(rc) => rc,
+        }
+    }
+
+    fn start(&self, appid: AppId, playback: bool, capture: bool) -> ReturnCode {
+        if self.current_app.is_some() || self.i2s.is_running() {
+            return ReturnCode::EBUSY;
+        }
+        if self.format.is_none() {
+            return ReturnCode::EOFF;
+        }
+        if !playback && !capture {
+            return ReturnCode::EINVAL;
+        }
+        let period = self.period.get();
+        let rc = self
+            .apps
+            .enter(appid, |app, _| {
+                for (wanted, ring) in [(playback, &app.playback), (capture, &app.capture)].iter() {
+                    match ring {
+                        _ if !wanted => {}
+                        Some(ring) if ring.len() > HEADER_LEN => {}
+                        Some(_) => return ReturnCode::ESIZE,
+                        None => return ReturnCode::ERESERVE,
+                    }
+                }
+
+                let mut rc = ReturnCode::SUCCESS;
+                let mut queued = false;
+                for _ in 0..2 {
+                    if playback {
+                        if let Some(buffer) = self.take_buffer() {
+                            self.fill(app, &mut buffer[..period]);
+                            match self.i2s.queue_transmit(buffer, period) {
+                                Ok(()) => queued = true,
+                                Err((err, buffer)) => {
+                                    self.put_buffer(buffer);
+                                    rc = err;
+                                }
+                            }
+                        }
+                    }
+                    if capture {
+                        if let Some(buffer) = self.take_buffer() {
+                            match self.i2s.queue_receive(buffer, period) {
+                                Ok(()) => queued = true,
+                                Err((err, buffer)) => {
+                                    self.put_buffer(buffer);
+                                    rc = err;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if rc == ReturnCode::SUCCESS {
+                    rc = self.i2s.start();
+                } else if queued && self.i2s.start() == ReturnCode::SUCCESS {
+                    // Get back the buffers the controller accepted.
+                    self.i2s.stop();
+                }
+                rc
+            })
+            .unwrap_or_else(|err| err.into());
+        if rc == ReturnCode::SUCCESS {
+            self.current_app.set(appid);
+        }
+        rc
+    }
+
+    /// Stop the stream if it belongs to an app that is gone.
+    fn app_gone(&self) {
+        if self.i2s.is_running() {
+            self.i2s.stop();
+        }
+    }
+}
+
+impl<'a> i2s::Client for PcmDriver<'a> {
+    fn transmit_done(&self, buffer: &'static mut [u8], rc: ReturnCode) {
+        if rc != ReturnCode::SUCCESS {
+            self.put_buffer(buffer);
+            return;
+        }
+        let period = self.period.get();
+        let filled = self.current_app.map_or(false, |appid| {
+            self.apps
+                .enter(*appid, |app, _| self.fill(app, &mut buffer[..period]))
+                .is_ok()
+        });
+        if !filled {
+            self.put_buffer(buffer);
+            self.app_gone();
+            return;
+        }
+        if let Err((_, buffer)) = self.i2s.queue_transmit(buffer, period) {
+            self.put_buffer(buffer);
+        }
+    }
+
+    fn receive_done(&self, buffer: &'static mut [u8], rc: ReturnCode) {
+        if rc != ReturnCode::SUCCESS {
+            self.put_buffer(buffer);
+            return;
+        }
+        let period = self.period.get();
+        let drained = self.current_app.map_or(false, |appid| {
+            self.apps
+                .enter(*appid, |app, _| self.drain(app, &buffer[..period]))
+                .is_ok()
+        });
+        if !drained {
+            self.put_buffer(buffer);
+            self.app_gone();
+            return;
+        }
+        if let Err((_, buffer)) = self.i2s.queue_receive(buffer, period) {
+            self.put_buffer(buffer);
+        }
+    }
+
+    fn stopped(&self, cause: StopCause) {
+        if let Some(appid) = self.current_app.take() {
+            let _ = self.apps.enter(appid, |app, _| {
+                let cause = match cause {
+                    StopCause::Requested => 0,
+                    StopCause::Underrun => 1,
+                    StopCause::Overrun => 2,
+                };
+                app.callback
+                    .map(|mut cb| cb.schedule(EVENT_STOPPED, cause, 0));
+            });
+        }
+    }
+}
+
+impl<'a> Driver for PcmDriver<'a> {
+    /// Share the rings with the kernel.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Ring of samples to play.
+    /// - `1`: Ring for captured samples.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 | 1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    if allow_num == 0 {
+                        app.playback = slice;
+                    } else {
+                        app.capture = slice;
+                    }
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Subscribe to stream events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Stream events, called with the event and two arguments:
+    ///   - `0`: A kernel buffer was refilled for playback, with the bytes
+    ///     taken from the playback ring and the bytes of silence added
+    ///     because the ring ran empty (underrun).
+    ///   - `1`: A kernel buffer was captured, with the bytes added to the
+    ///     capture ring and the bytes dropped because the ring was full
+    ///     (overrun).
+    ///   - `2`: The stream stopped, with `0` if the app stopped it, `1` if
+    ///     the kernel ran out of samples to play and `2` if it ran out of
+    ///     space to capture into.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Configure and control the stream.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Configure the stream for `data` frames per second, with the
+    ///   sample width in bits (8, 16 or 24) in bits 0-7 of `data2` and the
+    ///   channels (0 stereo, 1 left, 2 right) in bits 8-15. Returns the
+    ///   sample rate the controller picked.
+    /// - `2`: Start streaming, playing if bit 0 of `data` is set and
+    ///   capturing if bit 1 is set.
+    /// - `3`: Stop streaming.
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self.configure(data, data2),
+
+            2 => self.start(appid, data & 1 != 0, data & 2 != 0),
+
+            3 => {
+                if self.current_app.map_or(true, |owner| *owner != appid) {
+                    return ReturnCode::EOFF;
+                }
+                self.i2s.stop()
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_around() {
+        // Eight data bytes, read and write offsets at 6
+        let mut ring = [6, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(ring_write(&mut ring, &[1, 2, 3, 4, 5, 6, 7, 8], 2), 6);
+        assert_eq!(&ring[4..8], &[4, 0, 0, 0]);
+        assert_eq!(&ring[8..], &[3, 4, 5, 6, 0, 0, 1, 2]);
+
+        let mut data = [0; 4];
+        assert_eq!(ring_read(&mut ring, &mut data, 4), 4);
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(&ring[0..4], &[2, 0, 0, 0]);
+
+        // Two bytes are left, less than a frame of four
+        assert_eq!(ring_read(&mut ring, &mut data, 4), 0);
+        assert_eq!(ring_read(&mut ring, &mut data, 2), 2);
+        assert_eq!(&data[..2], &[5, 6]);
+    }
+
+    #[test]
+    fn ring_without_data_is_ignored() {
+        let mut ring = [0; HEADER_LEN];
+        assert_eq!(ring_write(&mut ring, &[1, 2], 1), 0);
+        assert_eq!(ring_read(&mut ring, &mut [0; 2], 1), 0);
+    }
+}
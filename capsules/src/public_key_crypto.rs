@@ -0,0 +1,607 @@
+//! Provides userspace with ECDSA P-256 signatures and ECDH key agreement,
+//! using private keys that stay in the kernel.
+//!
+//! Private keys are held in a fixed number of kernel key slots. An app
+//! creates a key in a free slot, either from the random number generator or
+//! by importing one, and gets the number of the slot as a handle to sign and
+//! agree on secrets with it. The private key can never be read back; only
+//! the public key can. A slot can only be used by the app that created it,
+//! until the app deletes it or exits.
+//!
+//! Only one operation runs at a time. Each app can queue one operation while
+//! another app's is running.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let slots = static_init!(
+//!     [capsules::public_key_crypto::KeySlot; 4],
+//!     [capsules::public_key_crypto::KeySlot::empty(); 4]
+//! );
+//! let pkc = static_init!(
+//!     capsules::public_key_crypto::PublicKeyCryptoDriver<'static, SoftwareP256<'static>>,
+//!     capsules::public_key_crypto::PublicKeyCryptoDriver::new(
+//!         p256,
+//!         rng,
+//!         slots,
+//!         &mut HASH_BUFFER,
+//!         &mut SIGNATURE_BUFFER,
+//!         &mut PUBLIC_KEY_BUFFER,
+//!         &mut SECRET_BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hil::public_key_crypto::EcdsaP256::set_client(p256, pkc);
+//! hil::public_key_crypto::EcdhP256::set_client(p256, pkc);
+//! rng.set_client(pkc);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::public_key_crypto::{
+    EcdhClient, EcdhP256, EcdsaClient, EcdsaP256, HASH_LEN, PRIVATE_KEY_LEN, PUBLIC_KEY_LEN,
+    SHARED_SECRET_LEN, SIGNATURE_LEN,
+};
+use kernel::hil::rng;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::PublicKeyCrypto as usize;
+
+mod cmd {
+    pub const GENERATE: usize = 1;
+    pub const IMPORT: usize = 2;
+    pub const DELETE: usize = 3;
+    pub const PUBLIC_KEY: usize = 4;
+    pub const SIGN: usize = 5;
+    pub const VERIFY: usize = 6;
+    pub const SHARED_SECRET: usize = 7;
+}
+
+/// A kernel-held key pair.
+#[derive(Clone, Copy)]
+pub struct KeySlot {
+    owner: Option<AppId>,
+    /// Whether the public key has been computed
+    ready: bool,
+    private_key: [u8; PRIVATE_KEY_LEN],
+    public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl KeySlot {
+    pub const fn empty() -> KeySlot {
+        KeySlot {
+            owner: None,
+            ready: false,
+            private_key: [0; PRIVATE_KEY_LEN],
+            public_key: [0; PUBLIC_KEY_LEN],
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = KeySlot::empty();
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    /// A command waiting for the running operation, and its argument
+    pending: Option<(usize, usize)>,
+    private_key: Option<AppSlice<Shared, u8>>,
+    hash: Option<AppSlice<Shared, u8>>,
+    public_key: Option<AppSlice<Shared, u8>>,
+    signature: Option<AppSlice<Shared, u8>>,
+    secret: Option<AppSlice<Shared, u8>>,
+}
+
+/// Copy the start of an app buffer, which must be long enough.
+fn copy_from_app(slice: &Option<AppSlice<Shared, u8>>, dest: &mut [u8]) -> Result<(), ReturnCode> {
+    match slice {
+        Some(slice) if slice.len() >= dest.len() => {
+            dest.copy_from_slice(&slice.as_ref()[..dest.len()]);
+            Ok(())
+        }
+        _ => Err(ReturnCode::EINVAL),
+    }
+}
+
+/// Copy to the start of an app buffer, which must be long enough.
+fn copy_to_app(slice: &mut Option<AppSlice<Shared, u8>>, src: &[u8]) -> ReturnCode {
+    match slice {
+        Some(slice) if slice.len() >= src.len() => {
+            slice.as_mut()[..src.len()].copy_from_slice(src);
+            ReturnCode::SUCCESS
+        }
+        _ => ReturnCode::EINVAL,
+    }
+}
+
+pub struct PublicKeyCryptoDriver<'a, C: EcdsaP256<'a> + EcdhP256<'a>> {
+    crypto: &'a C,
+    rng: &'a dyn rng::Rng<'a>,
+    slots: TakeCell<'static, [KeySlot]>,
+    apps: Grant<App>,
+    /// The app, command and slot of the running operation
+    current: OptionalCell<(AppId, usize, usize)>,
+    /// Words of a generated private key received so far
+    random_words: Cell<usize>,
+    hash: TakeCell<'static, [u8; HASH_LEN]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+    secret: TakeCell<'static, [u8; SHARED_SECRET_LEN]>,
+}
+
+impl<'a, C: EcdsaP256<'a> + EcdhP256<'a>> PublicKeyCryptoDriver<'a, C> {
+    pub fn new(
+        crypto: &'a C,
+        rng: &'a dyn rng::Rng<'a>,
+        slots: &'static mut [KeySlot],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+        secret: &'static mut [u8; SHARED_SECRET_LEN],
+        grant: Grant<App>,
+    ) -> PublicKeyCryptoDriver<'a, C> {
+        PublicKeyCryptoDriver {
+            crypto,
+            rng,
+            slots: TakeCell::new(slots),
+            apps: grant,
+            current: OptionalCell::empty(),
+            random_words: Cell::new(0),
+            hash: TakeCell::new(hash),
+            signature: TakeCell::new(signature),
+            public_key: TakeCell::new(public_key),
+            secret: TakeCell::new(secret),
+        }
+    }
+
+    /// Check that `handle` is a complete key of `appid`.
+    fn check_slot(&self, appid: AppId, handle: usize) -> Result<(), ReturnCode> {
+        self.slots
+            .map_or(Err(ReturnCode::FAIL), |slots| match slots.get(handle) {
+                Some(slot) if slot.ready && slot.owner == Some(appid) => Ok(()),
+                _ => Err(ReturnCode::EINVAL),
+            })
+    }
+
+    /// Reserve a slot for a new key of `appid`. Slots of apps that no longer
+    /// exist are reused.
+    fn allocate_slot(&self, appid: AppId) -> Result<usize, ReturnCode> {
+        self.slots.map_or(Err(ReturnCode::FAIL), |slots| {
+            let free = slots.iter().position(|slot| {
+                slot.owner.map_or(true, |owner| {
+                    owner != appid && self.apps.enter(owner, |_, _| ()).is_err()
+                })
+            });
+            free.map_or(Err(ReturnCode::ENOMEM), |index| {
+                slots[index].clear();
+                slots[index].owner = Some(appid);
+                Ok(index)
+            })
+        })
+    }
+
+    fn release_slot(&self, handle: usize) {
+        self.slots.map(|slots| slots[handle].clear());
+    }
+
+    /// Start the public key computation of a new key.
+    fn start_public_key(&self, handle: usize) -> ReturnCode {
+        let public_key = match self.public_key.take() {
+            Some(public_key) => public_key,
+            None => return ReturnCode::EBUSY,
+        };
+        self.slots.map_or(ReturnCode::FAIL, move |slots| {
+            match self
+                .crypto
+                .public_key(&slots[handle].private_key, public_key)
+            {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((rc, public_key)) => {
+                    self.public_key.replace(public_key);
+                    rc
+                }
+            }
+        })
+    }
+
+    /// Start a command of `app`. A callback follows if it returns SUCCESS.
+    fn start(&self, appid: AppId, app: &mut App, command: usize, data: usize) -> ReturnCode {
+        let result = match command {
+            cmd::GENERATE => self.allocate_slot(appid).and_then(|handle| {
+                self.current.set((appid, command, handle));
+                self.random_words.set(0);
+                match self.rng.get() {
+                    ReturnCode::SUCCESS => Ok(()),
+                    rc => {
+                        self.current.clear();
+                        self.release_slot(handle);
+                        Err(rc)
+                    }
+                }
+            }),
+
+            cmd::IMPORT => self.allocate_slot(appid).and_then(|handle| {
+                let rc = self
+                    .slots
+                    .map_or(Err(ReturnCode::FAIL), |slots| {
+                        copy_from_app(&app.private_key, &mut slots[handle].private_key)
+                    })
+                    .and_then(|()| match self.start_public_key(handle) {
+                        ReturnCode::SUCCESS => Ok(()),
+                        rc => Err(rc),
+                    });
+                if rc.is_ok() {
+                    self.current.set((appid, command, handle));
+                } else {
+                    self.release_slot(handle);
+                }
+                rc
+            }),
+
+            cmd::SIGN => self.check_slot(appid, data).and_then(|()| {
+                let (hash, signature) = match (self.hash.take(), self.signature.take()) {
+                    (Some(hash), Some(signature)) => (hash, signature),
+                    (hash, signature) => {
+                        hash.map(|hash| self.hash.replace(hash));
+                        signature.map(|signature| self.signature.replace(signature));
+                        return Err(ReturnCode::EBUSY);
+                    }
+                };
+                if let Err(rc) = copy_from_app(&app.hash, hash) {
+                    self.hash.replace(hash);
+                    self.signature.replace(signature);
+                    return Err(rc);
+                }
+                self.slots.map_or(Err(ReturnCode::FAIL), move |slots| {
+                    match self.crypto.sign(&slots[data].private_key, hash, signature) {
+                        Ok(()) => {
+                            self.current.set((appid, command, data));
+                            Ok(())
+                        }
+                        Err((rc, hash, signature)) => {
+                            self.hash.replace(hash);
+                            self.signature.replace(signature);
+                            Err(rc)
+                        }
+                    }
+                })
+            }),
+
+            cmd::VERIFY => {
+                let (hash, signature) = match (self.hash.take(), self.signature.take()) {
+                    (Some(hash), Some(signature)) => (hash, signature),
+                    (hash, signature) => {
+                        hash.map(|hash| self.hash.replace(hash));
+                        signature.map(|signature| self.signature.replace(signature));
+                        return ReturnCode::EBUSY;
+                    }
+                };
+                let mut public_key = [0; PUBLIC_KEY_LEN];
+                let copied = copy_from_app(&app.hash, hash)
+                    .and_then(|()| copy_from_app(&app.signature, signature))
+                    .and_then(|()| copy_from_app(&app.public_key, &mut public_key));
+                match copied {
+                    Ok(()) => match self.crypto.verify(&public_key, hash, signature) {
+                        Ok(()) => {
+                            self.current.set((appid, command, 0));
+                            Ok(())
+                        }
+                        Err((rc, hash, signature)) => {
+                            self.hash.replace(hash);
+                            self.signature.replace(signature);
+                            Err(rc)
+                        }
+                    },
+                    Err(rc) => {
+                        self.hash.replace(hash);
+                        self.signature.replace(signature);
+                        Err(rc)
+                    }
+                }
+            }
+
+            cmd::SHARED_SECRET => self.check_slot(appid, data).and_then(|()| {
+                let mut peer = [0; PUBLIC_KEY_LEN];
+                copy_from_app(&app.public_key, &mut peer)?;
+                let secret = self.secret.take().ok_or(ReturnCode::EBUSY)?;
+                self.slots.map_or(Err(ReturnCode::FAIL), move |slots| {
+                    match self
+                        .crypto
+                        .shared_secret(&slots[data].private_key, &peer, secret)
+                    {
+                        Ok(()) => {
+                            self.current.set((appid, command, data));
+                            Ok(())
+                        }
+                        Err((rc, secret)) => {
+                            self.secret.replace(secret);
+                            Err(rc)
+                        }
+                    }
+                })
+            }),
+
+            _ => Err(ReturnCode::ENOSUPPORT),
+        };
+        match result {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(rc) => rc,
+        }
+    }
+
+    /// Start the command of the next app that has one waiting.
+    fn check_queue(&self) {
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                let appid = app.appid();
+                app.pending.take().map_or(false, |(command, data)| {
+                    let rc = self.start(appid, app, command, data);
+                    if rc != ReturnCode::SUCCESS {
+                        app.callback
+                            .map(|mut cb| cb.schedule(command, usize::from(rc), 0));
+                    }
+                    rc == ReturnCode::SUCCESS
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Report the end of the running operation to its app and start the next
+    /// one. `complete` can copy results to the app.
+    fn finish<F>(&self, rc: ReturnCode, value: usize, complete: F)
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        if let Some((appid, command, _)) = self.current.take() {
+            let _ = self.apps.enter(appid, |app, _| {
+                let rc = if rc == ReturnCode::SUCCESS {
+                    complete(app)
+                } else {
+                    rc
+                };
+                app.callback
+                    .map(|mut cb| cb.schedule(command, usize::from(rc), value));
+            });
+        }
+        self.check_queue();
+    }
+
+    /// Finish the creation of a key, keeping it if `rc` is SUCCESS.
+    fn finish_key(&self, rc: ReturnCode) {
+        let handle = self.current.map_or(0, |(_, _, handle)| *handle);
+        if rc == ReturnCode::SUCCESS {
+            self.slots.map(|slots| slots[handle].ready = true);
+            self.finish(rc, handle, |_| ReturnCode::SUCCESS);
+        } else {
+            self.release_slot(handle);
+            self.finish(rc, 0, |_| ReturnCode::SUCCESS);
+        }
+    }
+}
+
+impl<'a, C: EcdsaP256<'a> + EcdhP256<'a>> rng::Client for PublicKeyCryptoDriver<'a, C> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        let handle = match self.current.map(|current| *current) {
+            Some((_, cmd::GENERATE, handle)) => handle,
+            _ => return rng::Continue::Done,
+        };
+        if error != ReturnCode::SUCCESS {
+            self.finish_key(error);
+            return rng::Continue::Done;
+        }
+
+        self.slots.map(|slots| {
+            let key = &mut slots[handle].private_key;
+            while self.random_words.get() < PRIVATE_KEY_LEN / 4 {
+                match randomness.next() {
+                    Some(word) => {
+                        let offset = self.random_words.get() * 4;
+                        key[offset..offset + 4].copy_from_slice(&word.to_ne_bytes());
+                        self.random_words.set(self.random_words.get() + 1);
+                    }
+                    None => break,
+                }
+            }
+        });
+        if self.random_words.get() < PRIVATE_KEY_LEN / 4 {
+            return rng::Continue::More;
+        }
+
+        match self.start_public_key(handle) {
+            ReturnCode::SUCCESS => rng::Continue::Done,
+            ReturnCode::EINVAL => {
+                // Out of range for a private key, try another one
+                self.random_words.set(0);
+                rng::Continue::More
+            }
+            rc => {
+                self.finish_key(rc);
+                rng::Continue::Done
+            }
+        }
+    }
+}
+
+impl<'a, C: EcdsaP256<'a> + EcdhP256<'a>> EcdhClient for PublicKeyCryptoDriver<'a, C> {
+    fn public_key_done(&self, rc: ReturnCode, public_key: &'static mut [u8; PUBLIC_KEY_LEN]) {
+        if rc == ReturnCode::SUCCESS {
+            self.current.map(|(_, _, handle)| {
+                self.slots
+                    .map(|slots| slots[*handle].public_key.copy_from_slice(public_key))
+            });
+        }
+        self.public_key.replace(public_key);
+        self.finish_key(rc);
+    }
+
+    fn shared_secret_done(&self, rc: ReturnCode, secret: &'static mut [u8; SHARED_SECRET_LEN]) {
+        self.finish(rc, 0, |app| copy_to_app(&mut app.secret, secret));
+        for byte in secret.iter_mut() {
+            *byte = 0;
+        }
+        self.secret.replace(secret);
+    }
+}
+
+impl<'a, C: EcdsaP256<'a> + EcdhP256<'a>> EcdsaClient for PublicKeyCryptoDriver<'a, C> {
+    fn sign_done(
+        &self,
+        rc: ReturnCode,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        self.finish(rc, 0, |app| copy_to_app(&mut app.signature, signature));
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+    }
+
+    fn verify_done(
+        &self,
+        result: Result<bool, ReturnCode>,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        match result {
+            Ok(valid) => self.finish(ReturnCode::SUCCESS, valid as usize, |_| ReturnCode::SUCCESS),
+            Err(rc) => self.finish(rc, 0, |_| ReturnCode::SUCCESS),
+        }
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+    }
+}
+
+impl<'a, C: EcdsaP256<'a> + EcdhP256<'a>> Driver for PublicKeyCryptoDriver<'a, C> {
+    /// Share buffers with the driver.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The private key to import, 32 bytes.
+    /// - `1`: The hash to sign or verify, 32 bytes.
+    /// - `2`: A public key, 64 bytes: the key read by command 4, or the key
+    ///   used by commands 6 and 7.
+    /// - `3`: A signature, 64 bytes: written by command 5, read by command 6.
+    /// - `4`: The shared secret written by command 7, 32 bytes.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.private_key = slice,
+                    1 => app.hash = slice,
+                    2 => app.public_key = slice,
+                    3 => app.signature = slice,
+                    4 => app.secret = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to the completion of operations.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: An operation completed. Called with the command number, the
+    ///   return code and a value: the handle of a new key, or 1 if a
+    ///   signature is valid.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Manage keys and run operations.
+    ///
+    /// Commands 1, 2, 5, 6 and 7 complete with a callback. If another
+    /// operation is running they are queued, one per app; a second one
+    /// fails with EBUSY.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check. Returns the number of key slots.
+    /// - `1`: Generate a key. The callback gets its handle.
+    /// - `2`: Import the private key of buffer 0. The callback gets its
+    ///   handle.
+    /// - `3`: Delete key `data`.
+    /// - `4`: Copy the public key of key `data` to buffer 2.
+    /// - `5`: Sign the hash of buffer 1 with key `data`, storing the
+    ///   signature in buffer 3.
+    /// - `6`: Verify the signature of buffer 3 over the hash of buffer 1 with
+    ///   the public key of buffer 2.
+    /// - `7`: Compute the secret shared by key `data` and the public key of
+    ///   buffer 2, storing it in buffer 4.
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SuccessWithValue {
+                value: self.slots.map_or(0, |slots| slots.len()),
+            },
+
+            cmd::DELETE => match self.check_slot(appid, data) {
+                Ok(()) => {
+                    self.release_slot(data);
+                    ReturnCode::SUCCESS
+                }
+                Err(rc) => rc,
+            },
+
+            cmd::PUBLIC_KEY => match self.check_slot(appid, data) {
+                Ok(()) => self
+                    .apps
+                    .enter(appid, |app, _| {
+                        self.slots.map_or(ReturnCode::FAIL, |slots| {
+                            copy_to_app(&mut app.public_key, &slots[data].public_key)
+                        })
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                Err(rc) => rc,
+            },
+
+            cmd::GENERATE | cmd::IMPORT | cmd::SIGN | cmd::VERIFY | cmd::SHARED_SECRET => self
+                .apps
+                .enter(appid, |app, _| {
+                    if self.current.is_none() {
+                        self.start(appid, app, command_num, data)
+                    } else if app.pending.is_some() {
+                        ReturnCode::EBUSY
+                    } else {
+                        app.pending = Some((command_num, data));
+                        ReturnCode::SUCCESS
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -19,6 +19,7 @@ use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::gpio;
 use kernel::hil::radio;
 use kernel::hil::spi;
+use kernel::power::PowerConstraint;
 use kernel::ReturnCode;
 
 use crate::rf233_const::CSMA_SEED_1;
@@ -217,6 +218,7 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
+    power_constraint: PowerConstraint<'a>,
 }
 
 fn setting_to_power(setting: u8) -> i8 {
@@ -737,7 +739,7 @@ impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 self.state_transition_read(RF233Register::TRX_STATUS, InternalState::TX_PLL_WAIT);
             }
             InternalState::TX_PLL_WAIT => {
-                self.transmitting.set(true);
+                self.set_transmitting(true);
                 if status == ExternalState::STATE_TRANSITION_IN_PROGRESS as u8 {
                     self.state_transition_read(
                         RF233Register::TRX_STATUS,
@@ -794,7 +796,7 @@ impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                         ReturnCode::SUCCESS
                     };
 
-                    self.transmitting.set(false);
+                    self.set_transmitting(false);
                     let buf = self.tx_buf.take();
                     self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
 
@@ -1063,6 +1065,26 @@ impl<'a, S: spi::SpiMasterDevice> RF233<'a, S> {
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
+            power_constraint: PowerConstraint::new(),
+        }
+    }
+
+    /// The constraint the driver sets while it transmits. Boards register it
+    /// with their power manager.
+    pub fn power_constraint(&'a self) -> &'a PowerConstraint<'a> {
+        &self.power_constraint
+    }
+
+    // A transmission is a chain of SPI transactions started from the SPI
+    // and IRQ interrupts. Waking from deep sleep between them would delay
+    // the frame and the following return to receive mode, so the chip stays
+    // out of deep sleep until the transmission is done.
+    fn set_transmitting(&self, transmitting: bool) {
+        self.transmitting.set(transmitting);
+        if transmitting {
+            self.power_constraint.block_deep_sleep();
+        } else {
+            self.power_constraint.release();
         }
     }
 
@@ -1193,7 +1215,7 @@ impl<S: spi::SpiMasterDevice> radio::RadioConfig for RF233<'_, S> {
         }
         self.reset_pin.set();
         self.sleep_pin.clear();
-        self.transmitting.set(false);
+        self.set_transmitting(false);
         ReturnCode::SUCCESS
     }
 
@@ -1365,7 +1387,7 @@ impl<S: spi::SpiMasterDevice> radio::RadioData for RF233<'_, S> {
         spi_buf[1] = frame_len as u8;
         self.tx_buf.replace(spi_buf);
         self.tx_len.set(frame_len as u8);
-        self.transmitting.set(true);
+        self.set_transmitting(true);
 
         if !self.receiving.get() && state == InternalState::READY {
             self.state_transition_read(
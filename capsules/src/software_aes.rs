@@ -0,0 +1,410 @@
+//! AES-128 in software, in ECB, CBC and CTR mode.
+//!
+//! Implements the `AES128` traits for boards without an AES peripheral, so
+//! that capsules like `virtual_aes_ccm` can run on them. `crypt()` encrypts
+//! or decrypts the whole buffer right away and delivers `crypt_done()` from
+//! a deferred call.
+//!
+//! The S-box is computed as an inversion in GF(2^8) instead of being looked
+//! up in a table, so that neither branches nor memory accesses depend on the
+//! key or the data. This makes the implementation slow, several thousand
+//! cycles per byte.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let aes = static_init!(
+//!     capsules::software_aes::SoftwareAes128<'static>,
+//!     capsules::software_aes::SoftwareAes128::new(dynamic_deferred_caller)
+//! );
+//! aes.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(aes)
+//!         .expect("no deferred call slot available for AES"),
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ReturnCode;
+
+const ROUNDS: usize = 10;
+
+type Block = [u8; AES128_BLOCK_SIZE];
+type RoundKeys = [Block; ROUNDS + 1];
+
+/// Multiply by x in GF(2^8).
+fn xtime(a: u8) -> u8 {
+    (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7))
+}
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(2^8), as a^254. Maps 0 to 0.
+fn gf_inverse(a: u8) -> u8 {
+    let mut result = 1;
+    for bit in (0..8).rev() {
+        result = gf_mul(result, result);
+        if (254 >> bit) & 1 == 1 {
+            result = gf_mul(result, a);
+        }
+    }
+    result
+}
+
+fn sub_byte(a: u8) -> u8 {
+    let b = gf_inverse(a);
+    b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63
+}
+
+fn inv_sub_byte(a: u8) -> u8 {
+    gf_inverse(a.rotate_left(1) ^ a.rotate_left(3) ^ a.rotate_left(6) ^ 0x05)
+}
+
+fn expand_key(key: &[u8]) -> RoundKeys {
+    let mut round_keys = [[0; AES128_BLOCK_SIZE]; ROUNDS + 1];
+    round_keys[0].copy_from_slice(&key[..AES128_KEY_SIZE]);
+    let mut rcon = 1;
+    for round in 1..=ROUNDS {
+        let previous = round_keys[round - 1];
+        let mut word = [
+            sub_byte(previous[13]) ^ rcon,
+            sub_byte(previous[14]),
+            sub_byte(previous[15]),
+            sub_byte(previous[12]),
+        ];
+        for i in 0..AES128_BLOCK_SIZE {
+            word[i % 4] ^= previous[i];
+            round_keys[round][i] = word[i % 4];
+        }
+        rcon = xtime(rcon);
+    }
+    round_keys
+}
+
+fn add_round_key(state: &mut Block, round_key: &Block) {
+    for (byte, key) in state.iter_mut().zip(round_key.iter()) {
+        *byte ^= key;
+    }
+}
+
+/// Row `r` of the state is bytes `r`, `r + 4`, `r + 8` and `r + 12`; rotate
+/// it left by `r` positions, or right when `inverse`.
+fn shift_rows(state: &mut Block, inverse: bool) {
+    let copy = *state;
+    for column in 0..4 {
+        for row in 0..4 {
+            let from = if inverse {
+                (column + 4 - row) % 4
+            } else {
+                (column + row) % 4
+            };
+            state[column * 4 + row] = copy[from * 4 + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut Block) {
+    for column in state.chunks_mut(4) {
+        let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+        let all = a ^ b ^ c ^ d;
+        column[0] ^= all ^ xtime(a ^ b);
+        column[1] ^= all ^ xtime(b ^ c);
+        column[2] ^= all ^ xtime(c ^ d);
+        column[3] ^= all ^ xtime(d ^ a);
+    }
+}
+
+fn inv_mix_columns(state: &mut Block) {
+    for column in state.chunks_mut(4) {
+        let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+        column[0] = gf_mul(a, 14) ^ gf_mul(b, 11) ^ gf_mul(c, 13) ^ gf_mul(d, 9);
+        column[1] = gf_mul(a, 9) ^ gf_mul(b, 14) ^ gf_mul(c, 11) ^ gf_mul(d, 13);
+        column[2] = gf_mul(a, 13) ^ gf_mul(b, 9) ^ gf_mul(c, 14) ^ gf_mul(d, 11);
+        column[3] = gf_mul(a, 11) ^ gf_mul(b, 13) ^ gf_mul(c, 9) ^ gf_mul(d, 14);
+    }
+}
+
+fn encrypt_block(round_keys: &RoundKeys, state: &mut Block) {
+    add_round_key(state, &round_keys[0]);
+    for (round, round_key) in round_keys.iter().enumerate().skip(1) {
+        for byte in state.iter_mut() {
+            *byte = sub_byte(*byte);
+        }
+        shift_rows(state, false);
+        if round != ROUNDS {
+            mix_columns(state);
+        }
+        add_round_key(state, round_key);
+    }
+}
+
+fn decrypt_block(round_keys: &RoundKeys, state: &mut Block) {
+    add_round_key(state, &round_keys[ROUNDS]);
+    for round in (0..ROUNDS).rev() {
+        shift_rows(state, true);
+        for byte in state.iter_mut() {
+            *byte = inv_sub_byte(*byte);
+        }
+        add_round_key(state, &round_keys[round]);
+        if round != 0 {
+            inv_mix_columns(state);
+        }
+    }
+}
+
+/// Increment a big-endian counter block.
+fn increment(counter: &mut Block) {
+    let mut carry = 1u16;
+    for byte in counter.iter_mut().rev() {
+        let sum = *byte as u16 + carry;
+        *byte = sum as u8;
+        carry = sum >> 8;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+/// Encrypt or decrypt `data`, a whole number of blocks, in place. `chain` is
+/// the chaining value or counter, and is updated for the next blocks.
+fn crypt_blocks(
+    round_keys: &RoundKeys,
+    mode: Mode,
+    encrypting: bool,
+    chain: &mut Block,
+    data: &mut [u8],
+) {
+    for chunk in data.chunks_mut(AES128_BLOCK_SIZE) {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(chunk);
+        match (mode, encrypting) {
+            (Mode::Ecb, true) => encrypt_block(round_keys, &mut block),
+            (Mode::Ecb, false) => decrypt_block(round_keys, &mut block),
+            (Mode::Cbc, true) => {
+                add_round_key(&mut block, chain);
+                encrypt_block(round_keys, &mut block);
+                *chain = block;
+            }
+            (Mode::Cbc, false) => {
+                let ciphertext = block;
+                decrypt_block(round_keys, &mut block);
+                add_round_key(&mut block, chain);
+                *chain = ciphertext;
+            }
+            (Mode::Ctr, _) => {
+                let mut keystream = *chain;
+                encrypt_block(round_keys, &mut keystream);
+                add_round_key(&mut block, &keystream);
+                increment(chain);
+            }
+        }
+        chunk.copy_from_slice(&block);
+    }
+}
+
+pub struct SoftwareAes128<'a> {
+    client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    round_keys: Cell<RoundKeys>,
+    iv: Cell<Block>,
+    /// Chaining value or counter of the current message
+    chain: Cell<Block>,
+    mode: Cell<Mode>,
+    encrypting: Cell<bool>,
+    /// Buffers whose callback is pending
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareAes128<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareAes128<'a> {
+        SoftwareAes128 {
+            client: OptionalCell::empty(),
+            round_keys: Cell::new([[0; AES128_BLOCK_SIZE]; ROUNDS + 1]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            chain: Cell::new([0; AES128_BLOCK_SIZE]),
+            mode: Cell::new(Mode::Ecb),
+            encrypting: Cell::new(true),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+}
+
+impl<'a> AES128<'a> for SoftwareAes128<'a> {
+    fn enable(&self) {}
+
+    fn disable(&self) {}
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        self.round_keys.set(expand_key(key));
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(iv);
+        self.iv.set(block);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if self.dest.is_none() {
+            self.chain.set(self.iv.get());
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.dest.is_some() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        if start_index > stop_index
+            || stop_index > dest.len()
+            || (stop_index - start_index) % AES128_BLOCK_SIZE != 0
+            || source
+                .as_ref()
+                .map_or(false, |source| source.len() != stop_index - start_index)
+        {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+
+        let data = &mut dest[start_index..stop_index];
+        if let Some(ref source) = source {
+            data.copy_from_slice(source);
+        }
+        let mut chain = self.chain.get();
+        crypt_blocks(
+            &self.round_keys.get(),
+            self.mode.get(),
+            self.encrypting.get(),
+            &mut chain,
+            data,
+        );
+        self.chain.set(chain);
+
+        if let Some(source) = source {
+            self.source.replace(source);
+        }
+        self.dest.replace(dest);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        None
+    }
+}
+
+impl AES128ECB for SoftwareAes128<'_> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.mode.set(Mode::Ecb);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128CBC for SoftwareAes128<'_> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        self.mode.set(Mode::Cbc);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128Ctr for SoftwareAes128<'_> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        self.mode.set(Mode::Ctr);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareAes128<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(dest) = self.dest.take() {
+            let source = self.source.take();
+            self.client
+                .map(move |client| client.crypt_done(source, dest));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::aes::{CTXT_CBC, CTXT_CTR, CTXT_ECB, IV_CBC, IV_CTR, KEY, PTXT};
+
+    fn check(mode: Mode, iv: &Block, ciphertext: &[u8]) {
+        let round_keys = expand_key(&KEY);
+
+        let mut data = PTXT;
+        let mut chain = *iv;
+        crypt_blocks(&round_keys, mode, true, &mut chain, &mut data);
+        assert_eq!(&data[..], ciphertext);
+
+        let mut chain = *iv;
+        crypt_blocks(&round_keys, mode, false, &mut chain, &mut data);
+        assert_eq!(data, PTXT);
+    }
+
+    #[test]
+    fn s_box() {
+        assert_eq!(sub_byte(0x00), 0x63);
+        assert_eq!(sub_byte(0x53), 0xed);
+        for i in 0..=255 {
+            assert_eq!(inv_sub_byte(sub_byte(i)), i);
+        }
+    }
+
+    #[test]
+    fn ecb() {
+        check(Mode::Ecb, &[0; AES128_BLOCK_SIZE], &CTXT_ECB);
+    }
+
+    #[test]
+    fn cbc() {
+        check(Mode::Cbc, &IV_CBC, &CTXT_CBC);
+    }
+
+    #[test]
+    fn ctr() {
+        check(Mode::Ctr, &IV_CTR, &CTXT_CTR);
+    }
+}
@@ -0,0 +1,191 @@
+//! A calendar clock in software, for chips without a real-time clock.
+//!
+//! `SoftwareClock` counts time with a free-running `Counter` and extends it
+//! to 64 bits by counting overflows, so the counter must keep running in all
+//! sleep states the board uses (an RTC peripheral usually does). Calendar
+//! alarms are scheduled on an `Alarm`, usually a virtual alarm on the same
+//! hardware, in steps of at most half its range.
+//!
+//! The time is lost on reset, so the clock reports EOFF until it is set.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let clock_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let clock = static_init!(
+//!     capsules::software_clock::SoftwareClock<'static, nrf52::rtc::Rtc, VirtualMuxAlarm<'static, nrf52::rtc::Rtc>>,
+//!     capsules::software_clock::SoftwareClock::new(&base_peripherals.rtc, clock_alarm)
+//! );
+//! base_peripherals.rtc.set_overflow_client(clock);
+//! clock_alarm.set_alarm_client(clock);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::date_time::{self, DateTime};
+use kernel::hil::time::{self, Alarm, Counter, Frequency, Ticks};
+use kernel::ReturnCode;
+
+pub struct SoftwareClock<'a, C: Counter<'a>, A: Alarm<'a>> {
+    counter: &'a C,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn date_time::Client>,
+    /// Counter overflows since boot.
+    overflows: Cell<u64>,
+    /// The counter value at the last read, to notice an overflow before its
+    /// interrupt is handled.
+    last_ticks: Cell<u32>,
+    /// An overflow was counted by a read and not yet by the interrupt.
+    counted_early: Cell<bool>,
+    /// Unix time in counter ticks when the extended counter was zero, or
+    /// `None` if the clock has not been set. Wraps if the clock was set to a
+    /// time earlier than the time since boot.
+    epoch: Cell<Option<u64>>,
+    /// Unix time of the pending alarm in counter ticks.
+    alarm_at: Cell<Option<u64>>,
+}
+
+impl<'a, C: Counter<'a>, A: Alarm<'a>> SoftwareClock<'a, C, A> {
+    pub fn new(counter: &'a C, alarm: &'a A) -> SoftwareClock<'a, C, A> {
+        SoftwareClock {
+            counter,
+            alarm,
+            client: OptionalCell::empty(),
+            overflows: Cell::new(0),
+            last_ticks: Cell::new(0),
+            counted_early: Cell::new(false),
+            epoch: Cell::new(None),
+            alarm_at: Cell::new(None),
+        }
+    }
+
+    /// Start the counter if it is not running yet.
+    pub fn start(&self) -> ReturnCode {
+        self.last_ticks.set(self.counter.now().into_u32());
+        if self.counter.is_running() {
+            ReturnCode::SUCCESS
+        } else {
+            self.counter.start()
+        }
+    }
+
+    fn frequency() -> u64 {
+        C::Frequency::frequency() as u64
+    }
+
+    /// The counter extended to 64 bits.
+    fn ticks(&self) -> u64 {
+        let now = self.counter.now().into_u32();
+        if now < self.last_ticks.get() && !self.counted_early.get() {
+            self.overflows.set(self.overflows.get() + 1);
+            self.counted_early.set(true);
+        }
+        self.last_ticks.set(now);
+        let period = C::Ticks::max_value().into_u32() as u64 + 1;
+        self.overflows.get() * period + now as u64
+    }
+
+    /// Unix time in counter ticks.
+    fn now(&self) -> Option<u64> {
+        self.epoch
+            .get()
+            .map(|epoch| epoch.wrapping_add(self.ticks()))
+    }
+
+    fn arm(&self) {
+        let (target, now) = match (self.alarm_at.get(), self.now()) {
+            (Some(target), Some(now)) => (target, now),
+            _ => return,
+        };
+        let remaining = target.saturating_sub(now);
+        // Convert to alarm ticks, rounding up so the alarm never fires early
+        let alarm_frequency = A::Frequency::frequency() as u64;
+        let remaining = (remaining * alarm_frequency + Self::frequency() - 1) / Self::frequency();
+        let max_dt = A::Ticks::max_value().into_u32() as u64 / 2;
+        let dt = core::cmp::min(remaining, max_dt) as u32;
+        let dt = core::cmp::max(dt, self.alarm.minimum_dt().into_u32());
+        self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(dt));
+    }
+}
+
+impl<'a, C: Counter<'a>, A: Alarm<'a>> time::OverflowClient for SoftwareClock<'a, C, A> {
+    fn overflow(&self) {
+        if self.counted_early.get() {
+            self.counted_early.set(false);
+        } else {
+            self.overflows.set(self.overflows.get() + 1);
+        }
+        self.last_ticks.set(self.counter.now().into_u32());
+    }
+}
+
+impl<'a, C: Counter<'a>, A: Alarm<'a>> time::AlarmClient for SoftwareClock<'a, C, A> {
+    fn alarm(&self) {
+        let (target, now) = match (self.alarm_at.get(), self.now()) {
+            (Some(target), Some(now)) => (target, now),
+            _ => return,
+        };
+        if now >= target {
+            self.alarm_at.set(None);
+            self.client.map(|client| client.alarm());
+        } else {
+            self.arm();
+        }
+    }
+}
+
+impl<'a, C: Counter<'a>, A: Alarm<'a>> date_time::Clock<'a> for SoftwareClock<'a, C, A> {
+    fn set_client(&self, client: &'a dyn date_time::Client) {
+        self.client.set(client);
+    }
+
+    fn date_time(&self) -> Result<DateTime, ReturnCode> {
+        let now = self.now().ok_or(ReturnCode::EOFF)?;
+        Ok(DateTime::from_unix_time(now / Self::frequency()))
+    }
+
+    fn set_date_time(&self, date_time: DateTime) -> ReturnCode {
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        let epoch = (date_time.unix_time() * Self::frequency()).wrapping_sub(self.ticks());
+        self.epoch.set(Some(epoch));
+        // Alarms stay at their calendar time
+        self.arm();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_alarm(&self, date_time: DateTime) -> ReturnCode {
+        let now = match self.now() {
+            Some(now) => now,
+            None => return ReturnCode::EOFF,
+        };
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        let target = date_time.unix_time() * Self::frequency();
+        if target <= now {
+            return ReturnCode::EINVAL;
+        }
+        self.alarm_at.set(Some(target));
+        self.arm();
+        ReturnCode::SUCCESS
+    }
+
+    fn get_alarm(&self) -> Option<DateTime> {
+        self.alarm_at
+            .get()
+            .map(|target| DateTime::from_unix_time(target / Self::frequency()))
+    }
+
+    fn disarm_alarm(&self) -> ReturnCode {
+        self.alarm_at.set(None);
+        self.alarm.disarm()
+    }
+}
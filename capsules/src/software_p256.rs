@@ -0,0 +1,914 @@
+//! ECDSA and ECDH on the NIST P-256 curve in software.
+//!
+//! Implements the `EcdsaP256` and `EcdhP256` traits for boards without a
+//! public-key accelerator. A scalar multiplication takes a few million
+//! cycles, so every operation is split into steps of `BITS_PER_STEP` bits of
+//! the scalar, each run from its own deferred call. Interrupts and other
+//! deferred calls are serviced between the steps.
+//!
+//! Field arithmetic is done in Montgomery form with 32-bit limbs. Scalar
+//! multiplications use a Montgomery ladder over the complete addition
+//! formulas of Renes, Costello and Batina, and inversions use Fermat's little
+//! theorem, so neither branches nor memory accesses depend on private keys
+//! or nonces. Signatures use deterministic nonces (RFC 6979), so no random
+//! number generator is needed.
+//!
+//! Only one operation runs at a time, whether ECDSA or ECDH.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let p256 = static_init!(
+//!     capsules::software_p256::SoftwareP256<'static>,
+//!     capsules::software_p256::SoftwareP256::new(dynamic_deferred_caller)
+//! );
+//! p256.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(p256)
+//!         .expect("no deferred call slot available for P-256"),
+//! );
+//! ```
+
+use crate::software_sha256::hmac_sha256;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::public_key_crypto::{
+    EcdhClient, EcdhP256, EcdsaClient, EcdsaP256, HASH_LEN, PRIVATE_KEY_LEN, PUBLIC_KEY_LEN,
+    SHARED_SECRET_LEN, SIGNATURE_LEN,
+};
+use kernel::ReturnCode;
+
+/// Bits of the scalar processed by each deferred call.
+const BITS_PER_STEP: usize = 8;
+
+const LIMBS: usize = 8;
+const BITS: usize = 32 * LIMBS;
+
+/// A 256-bit number, least significant limb first.
+type Limbs = [u32; LIMBS];
+
+/// A modulus for Montgomery arithmetic with R = 2^256.
+struct Modulus {
+    m: Limbs,
+    /// -m^-1 mod 2^32
+    m_inv: u32,
+    /// R^2 mod m
+    r2: Limbs,
+}
+
+/// The field prime p.
+const P: Modulus = Modulus {
+    m: [
+        0xffffffff, 0xffffffff, 0xffffffff, 0x00000000, 0x00000000, 0x00000000, 0x00000001,
+        0xffffffff,
+    ],
+    m_inv: 0x00000001,
+    r2: [
+        0x00000003, 0x00000000, 0xffffffff, 0xfffffffb, 0xfffffffe, 0xffffffff, 0xfffffffd,
+        0x00000004,
+    ],
+};
+
+/// The group order n.
+const N: Modulus = Modulus {
+    m: [
+        0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad, 0xffffffff, 0xffffffff, 0x00000000,
+        0xffffffff,
+    ],
+    m_inv: 0xee00bc4f,
+    r2: [
+        0xbe79eea2, 0x83244c95, 0x49bd6fa6, 0x4699799c, 0x2b6bec59, 0x2845b239, 0xf3d95620,
+        0x66e12d94,
+    ],
+};
+
+const ZERO: Limbs = [0; LIMBS];
+const ONE: Limbs = [1, 0, 0, 0, 0, 0, 0, 0];
+
+/// 1 modulo p in Montgomery form.
+const P_ONE: Limbs = [
+    0x00000001, 0x00000000, 0x00000000, 0xffffffff, 0xffffffff, 0xffffffff, 0xfffffffe, 0x00000000,
+];
+
+/// The curve coefficient b modulo p in Montgomery form. The coefficient a is
+/// -3.
+const B: Limbs = [
+    0x29c4bddf, 0xd89cdf62, 0x78843090, 0xacf005cd, 0xf7212ed6, 0xe5a220ab, 0x04874834, 0xdc30061d,
+];
+
+/// The base point.
+const G: Point = Point {
+    x: [
+        0x18a9143c, 0x79e730d4, 0x5fedb601, 0x75ba95fc, 0x77622510, 0x79fb732b, 0xa53755c6,
+        0x18905f76,
+    ],
+    y: [
+        0xce95560a, 0xddf25357, 0xba19e45c, 0x8b4ab8e4, 0xdd21f325, 0xd2e88688, 0x25885d85,
+        0x8571ff18,
+    ],
+    z: P_ONE,
+};
+
+const IDENTITY: Point = Point {
+    x: ZERO,
+    y: P_ONE,
+    z: ZERO,
+};
+
+/// a + b, and the carry out.
+fn add(a: &Limbs, b: &Limbs) -> (Limbs, u32) {
+    let mut sum = ZERO;
+    let mut carry = 0u64;
+    for ((s, a), b) in sum.iter_mut().zip(a.iter()).zip(b.iter()) {
+        let t = *a as u64 + *b as u64 + carry;
+        *s = t as u32;
+        carry = t >> 32;
+    }
+    (sum, carry as u32)
+}
+
+/// a - b, and the borrow out.
+fn sub(a: &Limbs, b: &Limbs) -> (Limbs, u32) {
+    let mut difference = ZERO;
+    let mut borrow = 0u64;
+    for ((d, a), b) in difference.iter_mut().zip(a.iter()).zip(b.iter()) {
+        let t = (*a as u64).wrapping_sub(*b as u64).wrapping_sub(borrow);
+        *d = t as u32;
+        borrow = t >> 63;
+    }
+    (difference, borrow as u32)
+}
+
+/// `a` if `choice` is 0, `b` if it is 1.
+fn select(a: &Limbs, b: &Limbs, choice: u32) -> Limbs {
+    let mask = 0u32.wrapping_sub(choice);
+    let mut result = ZERO;
+    for ((r, a), b) in result.iter_mut().zip(a.iter()).zip(b.iter()) {
+        *r = a ^ (mask & (a ^ b));
+    }
+    result
+}
+
+fn is_zero(a: &Limbs) -> bool {
+    a.iter().fold(0, |acc, limb| acc | limb) == 0
+}
+
+/// Whether `a` is in the range [1, m - 1].
+fn in_range(a: &Limbs, m: &Modulus) -> bool {
+    !is_zero(a) && sub(a, &m.m).1 == 1
+}
+
+/// Parse 32 big-endian bytes.
+fn from_bytes(bytes: &[u8]) -> Limbs {
+    let mut limbs = ZERO;
+    for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(4).rev()) {
+        *limb = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    limbs
+}
+
+/// Write 32 big-endian bytes.
+fn to_bytes(limbs: &Limbs, bytes: &mut [u8]) {
+    for (chunk, limb) in bytes.chunks_mut(4).rev().zip(limbs.iter()) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+}
+
+impl Modulus {
+    fn add(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let (sum, carry) = add(a, b);
+        let (reduced, borrow) = sub(&sum, &self.m);
+        select(&reduced, &sum, borrow & (carry ^ 1))
+    }
+
+    fn sub(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let (difference, borrow) = sub(a, b);
+        add(&difference, &select(&ZERO, &self.m, borrow)).0
+    }
+
+    /// Montgomery multiplication, a * b / R mod m.
+    fn mul(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for &b in b.iter() {
+            let mut carry = 0u64;
+            for (t, a) in t.iter_mut().zip(a.iter()) {
+                let s = *t as u64 + *a as u64 * b as u64 + carry;
+                *t = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMBS] as u64 + carry;
+            t[LIMBS] = s as u32;
+            t[LIMBS + 1] = (s >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.m_inv);
+            let mut carry = (t[0] as u64 + q as u64 * self.m[0] as u64) >> 32;
+            for j in 1..LIMBS {
+                let s = t[j] as u64 + q as u64 * self.m[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMBS] as u64 + carry;
+            t[LIMBS - 1] = s as u32;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 32) as u32;
+        }
+
+        // The result is below 2m
+        let mut result = ZERO;
+        result.copy_from_slice(&t[..LIMBS]);
+        let (reduced, borrow) = sub(&result, &self.m);
+        select(&reduced, &result, borrow & (t[LIMBS] ^ 1))
+    }
+
+    fn to_montgomery(&self, a: &Limbs) -> Limbs {
+        self.mul(a, &self.r2)
+    }
+
+    fn from_montgomery(&self, a: &Limbs) -> Limbs {
+        self.mul(a, &ONE)
+    }
+
+    /// The inverse of `a` in Montgomery form, computed as a^(m - 2). Zero
+    /// has no inverse and gives zero.
+    fn invert(&self, a: &Limbs) -> Limbs {
+        let exponent = sub(&self.m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+        let mut result = self.to_montgomery(&ONE);
+        for bit in (0..BITS).rev() {
+            result = self.mul(&result, &result);
+            // The exponent is public
+            if (exponent[bit / 32] >> (bit % 32)) & 1 == 1 {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+
+    /// Reduce a 256-bit value, which takes at most one subtraction as
+    /// 2^256 < 2m.
+    fn reduce(&self, a: &Limbs) -> Limbs {
+        let (reduced, borrow) = sub(a, &self.m);
+        select(&reduced, a, borrow)
+    }
+}
+
+/// A point in projective coordinates, (X : Y : Z) standing for (X/Z, Y/Z),
+/// with coordinates modulo p in Montgomery form.
+#[derive(Clone, Copy)]
+struct Point {
+    x: Limbs,
+    y: Limbs,
+    z: Limbs,
+}
+
+impl Point {
+    /// Parse and validate an uncompressed public key.
+    fn from_public_key(bytes: &[u8; PUBLIC_KEY_LEN]) -> Option<Point> {
+        let x = from_bytes(&bytes[..32]);
+        let y = from_bytes(&bytes[32..]);
+        if sub(&x, &P.m).1 == 0 || sub(&y, &P.m).1 == 0 {
+            return None;
+        }
+        let x = P.to_montgomery(&x);
+        let y = P.to_montgomery(&y);
+
+        // y^2 = x^3 - 3x + b
+        let three_x = P.add(&P.add(&x, &x), &x);
+        let rhs = P.add(&P.sub(&P.mul(&P.mul(&x, &x), &x), &three_x), &B);
+        if P.mul(&y, &y) != rhs {
+            return None;
+        }
+        Some(Point { x, y, z: P_ONE })
+    }
+
+    /// The affine coordinates, or None for the point at infinity.
+    fn to_affine(&self) -> Option<(Limbs, Limbs)> {
+        if is_zero(&self.z) {
+            return None;
+        }
+        let z_inv = P.invert(&self.z);
+        Some((
+            P.from_montgomery(&P.mul(&self.x, &z_inv)),
+            P.from_montgomery(&P.mul(&self.y, &z_inv)),
+        ))
+    }
+
+    /// Complete addition for curves with a = -3 (Renes, Costello and Batina,
+    /// algorithm 4). Also correct for doubling and the point at infinity.
+    fn add(&self, other: &Point) -> Point {
+        let (x1, y1, z1) = (&self.x, &self.y, &self.z);
+        let (x2, y2, z2) = (&other.x, &other.y, &other.z);
+
+        let t0 = P.mul(x1, x2);
+        let t1 = P.mul(y1, y2);
+        let t2 = P.mul(z1, z2);
+        let t3 = P.mul(&P.add(x1, y1), &P.add(x2, y2));
+        let t3 = P.sub(&t3, &P.add(&t0, &t1));
+        let t4 = P.mul(&P.add(y1, z1), &P.add(y2, z2));
+        let t4 = P.sub(&t4, &P.add(&t1, &t2));
+        let x3 = P.mul(&P.add(x1, z1), &P.add(x2, z2));
+        let y3 = P.sub(&x3, &P.add(&t0, &t2));
+        let z3 = P.mul(&B, &t2);
+        let x3 = P.sub(&y3, &z3);
+        let z3 = P.add(&x3, &x3);
+        let x3 = P.add(&x3, &z3);
+        let z3 = P.sub(&t1, &x3);
+        let x3 = P.add(&t1, &x3);
+        let y3 = P.mul(&B, &y3);
+        let t1 = P.add(&t2, &t2);
+        let t2 = P.add(&t1, &t2);
+        let y3 = P.sub(&P.sub(&y3, &t2), &t0);
+        let t1 = P.add(&y3, &y3);
+        let y3 = P.add(&t1, &y3);
+        let t1 = P.add(&t0, &t0);
+        let t0 = P.sub(&P.add(&t1, &t0), &t2);
+        let t1 = P.mul(&t4, &y3);
+        let t2 = P.mul(&t0, &y3);
+        let y3 = P.add(&P.mul(&x3, &z3), &t2);
+        let x3 = P.sub(&P.mul(&t3, &x3), &t1);
+        let z3 = P.add(&P.mul(&t4, &z3), &P.mul(&t3, &t0));
+
+        Point {
+            x: x3,
+            y: y3,
+            z: z3,
+        }
+    }
+
+    /// Swap `a` and `b` if `choice` is 1.
+    fn conditional_swap(a: &mut Point, b: &mut Point, choice: u32) {
+        let (ax, ay, az) = (a.x, a.y, a.z);
+        a.x = select(&a.x, &b.x, choice);
+        a.y = select(&a.y, &b.y, choice);
+        a.z = select(&a.z, &b.z, choice);
+        b.x = select(&b.x, &ax, choice);
+        b.y = select(&b.y, &ay, choice);
+        b.z = select(&b.z, &az, choice);
+    }
+}
+
+/// A scalar multiplication in progress, with the invariant r1 = r0 + point.
+#[derive(Clone, Copy)]
+struct Ladder {
+    scalar: Limbs,
+    r0: Point,
+    r1: Point,
+    /// Bits of the scalar still to process
+    bits: usize,
+}
+
+impl Ladder {
+    fn new(scalar: Limbs, point: Point) -> Ladder {
+        Ladder {
+            scalar,
+            r0: IDENTITY,
+            r1: point,
+            bits: BITS,
+        }
+    }
+
+    /// Process the next bits of the scalar. Returns true once `r0` holds the
+    /// product.
+    fn step(&mut self, bits: usize) -> bool {
+        for _ in 0..bits.min(self.bits) {
+            self.bits -= 1;
+            let bit = (self.scalar[self.bits / 32] >> (self.bits % 32)) & 1;
+            Point::conditional_swap(&mut self.r0, &mut self.r1, bit);
+            self.r1 = self.r0.add(&self.r1);
+            self.r0 = self.r0.add(&self.r0);
+            Point::conditional_swap(&mut self.r0, &mut self.r1, bit);
+        }
+        self.bits == 0
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    PublicKey,
+    SharedSecret,
+    Sign {
+        hash: Limbs,
+        private_key: Limbs,
+        nonce: Limbs,
+    },
+    /// Computes u1 * G, then u2 * Q, and compares their sum with r.
+    Verify {
+        r: Limbs,
+        u2: Limbs,
+        public_key: Point,
+        u1_g: Option<Point>,
+    },
+    /// A verification of a signature that is out of range.
+    Reject,
+}
+
+/// Results handed to the clients.
+enum Outcome {
+    PublicKey(Option<(Limbs, Limbs)>),
+    SharedSecret(Option<Limbs>),
+    Signature(Option<(Limbs, Limbs)>),
+    Verified(bool),
+}
+
+struct Job {
+    operation: Operation,
+    ladder: Ladder,
+}
+
+impl Job {
+    fn public_key(private_key: &[u8; PRIVATE_KEY_LEN]) -> Result<Job, ReturnCode> {
+        let private_key = from_bytes(private_key);
+        if !in_range(&private_key, &N) {
+            return Err(ReturnCode::EINVAL);
+        }
+        Ok(Job {
+            operation: Operation::PublicKey,
+            ladder: Ladder::new(private_key, G),
+        })
+    }
+
+    fn shared_secret(
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        peer_public_key: &[u8; PUBLIC_KEY_LEN],
+    ) -> Result<Job, ReturnCode> {
+        let private_key = from_bytes(private_key);
+        if !in_range(&private_key, &N) {
+            return Err(ReturnCode::EINVAL);
+        }
+        let peer = Point::from_public_key(peer_public_key).ok_or(ReturnCode::EINVAL)?;
+        Ok(Job {
+            operation: Operation::SharedSecret,
+            ladder: Ladder::new(private_key, peer),
+        })
+    }
+
+    fn sign(private_key: &[u8; PRIVATE_KEY_LEN], hash: &[u8; HASH_LEN]) -> Result<Job, ReturnCode> {
+        let key = from_bytes(private_key);
+        if !in_range(&key, &N) {
+            return Err(ReturnCode::EINVAL);
+        }
+        let hash = N.reduce(&from_bytes(hash));
+        let nonce = rfc6979_nonce(private_key, &hash);
+        Ok(Job {
+            operation: Operation::Sign {
+                hash,
+                private_key: key,
+                nonce,
+            },
+            ladder: Ladder::new(nonce, G),
+        })
+    }
+
+    fn verify(
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &[u8; HASH_LEN],
+        signature: &[u8; SIGNATURE_LEN],
+    ) -> Result<Job, ReturnCode> {
+        let public_key = Point::from_public_key(public_key).ok_or(ReturnCode::EINVAL)?;
+        let r = from_bytes(&signature[..32]);
+        let s = from_bytes(&signature[32..]);
+        if !in_range(&r, &N) || !in_range(&s, &N) {
+            return Ok(Job {
+                operation: Operation::Reject,
+                ladder: Ladder::new(ZERO, IDENTITY),
+            });
+        }
+
+        // Multiplying plain values by w in Montgomery form gives plain values
+        let w = N.invert(&N.to_montgomery(&s));
+        let u1 = N.mul(&N.reduce(&from_bytes(hash)), &w);
+        let u2 = N.mul(&r, &w);
+        Ok(Job {
+            operation: Operation::Verify {
+                r,
+                u2,
+                public_key,
+                u1_g: None,
+            },
+            ladder: Ladder::new(u1, G),
+        })
+    }
+
+    /// Run the next step. Returns true once the job is complete.
+    fn step(&mut self) -> bool {
+        if let Operation::Reject = self.operation {
+            return true;
+        }
+        if !self.ladder.step(BITS_PER_STEP) {
+            return false;
+        }
+        if let Operation::Verify {
+            u2,
+            public_key,
+            ref mut u1_g,
+            ..
+        } = self.operation
+        {
+            if u1_g.is_none() {
+                *u1_g = Some(self.ladder.r0);
+                self.ladder = Ladder::new(u2, public_key);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn outcome(&self) -> Outcome {
+        let product = self.ladder.r0;
+        match self.operation {
+            Operation::PublicKey => Outcome::PublicKey(product.to_affine()),
+            Operation::SharedSecret => Outcome::SharedSecret(product.to_affine().map(|(x, _)| x)),
+            Operation::Sign {
+                hash,
+                private_key,
+                nonce,
+            } => Outcome::Signature(product.to_affine().and_then(|(x, _)| {
+                let r = N.reduce(&x);
+                // s = (hash + r * private_key) / nonce
+                let r_key = N.mul(&N.to_montgomery(&r), &private_key);
+                let s = N.mul(&N.invert(&N.to_montgomery(&nonce)), &N.add(&hash, &r_key));
+                if is_zero(&r) || is_zero(&s) {
+                    None
+                } else {
+                    Some((r, s))
+                }
+            })),
+            Operation::Verify { r, u1_g, .. } => {
+                let sum = u1_g.map_or(product, |u1_g| u1_g.add(&product));
+                Outcome::Verified(sum.to_affine().map_or(false, |(x, _)| N.reduce(&x) == r))
+            }
+            Operation::Reject => Outcome::Verified(false),
+        }
+    }
+
+    /// Overwrite the secrets of the job.
+    fn clear(&mut self) {
+        self.ladder = Ladder::new(ZERO, IDENTITY);
+        self.operation = Operation::Sign {
+            hash: ZERO,
+            private_key: ZERO,
+            nonce: ZERO,
+        };
+    }
+}
+
+/// The signature nonce for `private_key` and the reduced `hash`, derived
+/// with HMAC-SHA256 as in RFC 6979 section 3.2.
+fn rfc6979_nonce(private_key: &[u8; PRIVATE_KEY_LEN], hash: &Limbs) -> Limbs {
+    let mut hash_bytes = [0; HASH_LEN];
+    to_bytes(hash, &mut hash_bytes);
+
+    let mut k = [0u8; 32];
+    let mut v = [1u8; 32];
+    k = hmac_sha256(&k, &[&v, &[0], private_key, &hash_bytes]);
+    v = hmac_sha256(&k, &[&v]);
+    k = hmac_sha256(&k, &[&v, &[1], private_key, &hash_bytes]);
+    v = hmac_sha256(&k, &[&v]);
+    loop {
+        v = hmac_sha256(&k, &[&v]);
+        let nonce = from_bytes(&v);
+        if in_range(&nonce, &N) {
+            return nonce;
+        }
+        k = hmac_sha256(&k, &[&v, &[0]]);
+        v = hmac_sha256(&k, &[&v]);
+    }
+}
+
+pub struct SoftwareP256<'a> {
+    ecdsa_client: OptionalCell<&'a dyn EcdsaClient>,
+    ecdh_client: OptionalCell<&'a dyn EcdhClient>,
+    job: MapCell<Job>,
+    /// Buffers of the running operation
+    hash: TakeCell<'static, [u8; HASH_LEN]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+    secret: TakeCell<'static, [u8; SHARED_SECRET_LEN]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareP256<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareP256<'a> {
+        SoftwareP256 {
+            ecdsa_client: OptionalCell::empty(),
+            ecdh_client: OptionalCell::empty(),
+            job: MapCell::empty(),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            public_key: TakeCell::empty(),
+            secret: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn start(&self, job: Job) {
+        self.job.put(job);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn deliver(&self, outcome: Outcome) {
+        match outcome {
+            Outcome::PublicKey(point) => {
+                if let Some(public_key) = self.public_key.take() {
+                    let rc = point.map_or(ReturnCode::FAIL, |(x, y)| {
+                        to_bytes(&x, &mut public_key[..32]);
+                        to_bytes(&y, &mut public_key[32..]);
+                        ReturnCode::SUCCESS
+                    });
+                    self.ecdh_client
+                        .map(move |client| client.public_key_done(rc, public_key));
+                }
+            }
+            Outcome::SharedSecret(x) => {
+                if let Some(secret) = self.secret.take() {
+                    let rc = x.map_or(ReturnCode::FAIL, |x| {
+                        to_bytes(&x, secret);
+                        ReturnCode::SUCCESS
+                    });
+                    self.ecdh_client
+                        .map(move |client| client.shared_secret_done(rc, secret));
+                }
+            }
+            Outcome::Signature(rs) => {
+                if let (Some(hash), Some(signature)) = (self.hash.take(), self.signature.take()) {
+                    let rc = rs.map_or(ReturnCode::FAIL, |(r, s)| {
+                        to_bytes(&r, &mut signature[..32]);
+                        to_bytes(&s, &mut signature[32..]);
+                        ReturnCode::SUCCESS
+                    });
+                    self.ecdsa_client
+                        .map(move |client| client.sign_done(rc, hash, signature));
+                }
+            }
+            Outcome::Verified(valid) => {
+                if let (Some(hash), Some(signature)) = (self.hash.take(), self.signature.take()) {
+                    self.ecdsa_client
+                        .map(move |client| client.verify_done(Ok(valid), hash, signature));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> EcdsaP256<'a> for SoftwareP256<'a> {
+    fn set_client(&self, client: &'a dyn EcdsaClient) {
+        self.ecdsa_client.set(client);
+    }
+
+    fn sign(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.job.is_some() {
+            return Err((ReturnCode::EBUSY, hash, signature));
+        }
+        match Job::sign(private_key, hash) {
+            Ok(job) => {
+                self.hash.replace(hash);
+                self.signature.replace(signature);
+                self.start(job);
+                Ok(())
+            }
+            Err(rc) => Err((rc, hash, signature)),
+        }
+    }
+
+    fn verify(
+        &self,
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    > {
+        if self.job.is_some() {
+            return Err((ReturnCode::EBUSY, hash, signature));
+        }
+        match Job::verify(public_key, hash, signature) {
+            Ok(job) => {
+                self.hash.replace(hash);
+                self.signature.replace(signature);
+                self.start(job);
+                Ok(())
+            }
+            Err(rc) => Err((rc, hash, signature)),
+        }
+    }
+}
+
+impl<'a> EcdhP256<'a> for SoftwareP256<'a> {
+    fn set_client(&self, client: &'a dyn EcdhClient) {
+        self.ecdh_client.set(client);
+    }
+
+    fn public_key(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; PUBLIC_KEY_LEN])> {
+        if self.job.is_some() {
+            return Err((ReturnCode::EBUSY, public_key));
+        }
+        match Job::public_key(private_key) {
+            Ok(job) => {
+                self.public_key.replace(public_key);
+                self.start(job);
+                Ok(())
+            }
+            Err(rc) => Err((rc, public_key)),
+        }
+    }
+
+    fn shared_secret(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        peer_public_key: &[u8; PUBLIC_KEY_LEN],
+        secret: &'static mut [u8; SHARED_SECRET_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; SHARED_SECRET_LEN])> {
+        if self.job.is_some() {
+            return Err((ReturnCode::EBUSY, secret));
+        }
+        match Job::shared_secret(private_key, peer_public_key) {
+            Ok(job) => {
+                self.secret.replace(secret);
+                self.start(job);
+                Ok(())
+            }
+            Err(rc) => Err((rc, secret)),
+        }
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareP256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        match self.job.map(|job| job.step()) {
+            Some(false) => {
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+            }
+            Some(true) => {
+                let outcome = self.job.map(|job| {
+                    let outcome = job.outcome();
+                    job.clear();
+                    outcome
+                });
+                // Release the engine before the callback, which may start
+                // the next operation
+                self.job.take();
+                outcome.map(|outcome| self.deliver(outcome));
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(mut job: Job) -> Outcome {
+        while !job.step() {}
+        job.outcome()
+    }
+
+    fn public_key(private_key: &[u8; PRIVATE_KEY_LEN]) -> [u8; PUBLIC_KEY_LEN] {
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        match run(Job::public_key(private_key).unwrap()) {
+            Outcome::PublicKey(Some((x, y))) => {
+                to_bytes(&x, &mut public_key[..32]);
+                to_bytes(&y, &mut public_key[32..]);
+            }
+            _ => panic!("no public key"),
+        }
+        public_key
+    }
+
+    fn verify(
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &[u8; HASH_LEN],
+        signature: &[u8; SIGNATURE_LEN],
+    ) -> bool {
+        match run(Job::verify(public_key, hash, signature).unwrap()) {
+            Outcome::Verified(valid) => valid,
+            _ => panic!("no verification"),
+        }
+    }
+
+    // RFC 6979 appendix A.2.5
+    const RFC6979_PRIVATE_KEY: [u8; PRIVATE_KEY_LEN] = [
+        0xc9, 0xaf, 0xa9, 0xd8, 0x45, 0xba, 0x75, 0x16, 0x6b, 0x5c, 0x21, 0x57, 0x67, 0xb1, 0xd6,
+        0x93, 0x4e, 0x50, 0xc3, 0xdb, 0x36, 0xe8, 0x9b, 0x12, 0x7b, 0x8a, 0x62, 0x2b, 0x12, 0x0f,
+        0x67, 0x21,
+    ];
+    const RFC6979_PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] = [
+        0x60, 0xfe, 0xd4, 0xba, 0x25, 0x5a, 0x9d, 0x31, 0xc9, 0x61, 0xeb, 0x74, 0xc6, 0x35, 0x6d,
+        0x68, 0xc0, 0x49, 0xb8, 0x92, 0x3b, 0x61, 0xfa, 0x6c, 0xe6, 0x69, 0x62, 0x2e, 0x60, 0xf2,
+        0x9f, 0xb6, 0x79, 0x03, 0xfe, 0x10, 0x08, 0xb8, 0xbc, 0x99, 0xa4, 0x1a, 0xe9, 0xe9, 0x56,
+        0x28, 0xbc, 0x64, 0xf2, 0xf1, 0xb2, 0x0c, 0x2d, 0x7e, 0x9f, 0x51, 0x77, 0xa3, 0xc2, 0x94,
+        0xd4, 0x46, 0x22, 0x99,
+    ];
+    // SHA-256 of "sample"
+    const RFC6979_HASH: [u8; HASH_LEN] = [
+        0xaf, 0x2b, 0xdb, 0xe1, 0xaa, 0x9b, 0x6e, 0xc1, 0xe2, 0xad, 0xe1, 0xd6, 0x94, 0xf4, 0x1f,
+        0xc7, 0x1a, 0x83, 0x1d, 0x02, 0x68, 0xe9, 0x89, 0x15, 0x62, 0x11, 0x3d, 0x8a, 0x62, 0xad,
+        0xd1, 0xbf,
+    ];
+    const RFC6979_SIGNATURE: [u8; SIGNATURE_LEN] = [
+        0xef, 0xd4, 0x8b, 0x2a, 0xac, 0xb6, 0xa8, 0xfd, 0x11, 0x40, 0xdd, 0x9c, 0xd4, 0x5e, 0x81,
+        0xd6, 0x9d, 0x2c, 0x87, 0x7b, 0x56, 0xaa, 0xf9, 0x91, 0xc3, 0x4d, 0x0e, 0xa8, 0x4e, 0xaf,
+        0x37, 0x16, 0xf7, 0xcb, 0x1c, 0x94, 0x2d, 0x65, 0x7c, 0x41, 0xd4, 0x36, 0xc7, 0xa1, 0xb6,
+        0xe2, 0x9f, 0x65, 0xf3, 0xe9, 0x00, 0xdb, 0xb9, 0xaf, 0xf4, 0x06, 0x4d, 0xc4, 0xab, 0x2f,
+        0x84, 0x3a, 0xcd, 0xa8,
+    ];
+
+    #[test]
+    fn ecdsa_sign_verify() {
+        assert_eq!(public_key(&RFC6979_PRIVATE_KEY)[..], RFC6979_PUBLIC_KEY[..]);
+
+        let mut signature = [0; SIGNATURE_LEN];
+        match run(Job::sign(&RFC6979_PRIVATE_KEY, &RFC6979_HASH).unwrap()) {
+            Outcome::Signature(Some((r, s))) => {
+                to_bytes(&r, &mut signature[..32]);
+                to_bytes(&s, &mut signature[32..]);
+            }
+            _ => panic!("no signature"),
+        }
+        assert_eq!(signature[..], RFC6979_SIGNATURE[..]);
+
+        assert!(verify(
+            &RFC6979_PUBLIC_KEY,
+            &RFC6979_HASH,
+            &RFC6979_SIGNATURE
+        ));
+        let mut hash = RFC6979_HASH;
+        hash[0] ^= 1;
+        assert!(!verify(&RFC6979_PUBLIC_KEY, &hash, &RFC6979_SIGNATURE));
+        let mut signature = RFC6979_SIGNATURE;
+        signature[63] ^= 1;
+        assert!(!verify(&RFC6979_PUBLIC_KEY, &RFC6979_HASH, &signature));
+        assert!(!verify(
+            &RFC6979_PUBLIC_KEY,
+            &RFC6979_HASH,
+            &[0; SIGNATURE_LEN]
+        ));
+    }
+
+    #[test]
+    fn ecdh_shared_secret() {
+        let private_a = [
+            0x76, 0xfd, 0x4a, 0x64, 0x62, 0x46, 0x30, 0xd9, 0x04, 0x3f, 0xf5, 0xe1, 0xb4, 0xfb,
+            0x89, 0xfb, 0xa6, 0x03, 0x1d, 0x41, 0xba, 0x5f, 0x9c, 0x54, 0xef, 0x43, 0xff, 0x44,
+            0xd9, 0x91, 0x3f, 0x7c,
+        ];
+        let private_b = [
+            0x83, 0xfe, 0x86, 0x01, 0x51, 0x57, 0x22, 0x98, 0xfa, 0xbd, 0x23, 0xd2, 0xf5, 0xbc,
+            0x65, 0xe3, 0x81, 0x83, 0x5f, 0xbc, 0x67, 0x10, 0x8a, 0x42, 0x4e, 0x25, 0x4e, 0x08,
+            0xa8, 0xe5, 0x79, 0x90,
+        ];
+        let shared = [
+            0x9d, 0x0d, 0xdd, 0x28, 0xde, 0xb4, 0x5f, 0x7f, 0xbb, 0x2b, 0xcf, 0x95, 0xf5, 0x27,
+            0x10, 0xed, 0xa3, 0xd7, 0x09, 0xb2, 0xb6, 0xeb, 0xcb, 0xc6, 0xc5, 0xa4, 0xc5, 0xf6,
+            0xb8, 0x48, 0x1b, 0xb6,
+        ];
+
+        let public_a = public_key(&private_a);
+        let public_b = public_key(&private_b);
+        for (private_key, peer) in [(private_a, public_b), (private_b, public_a)].iter() {
+            match run(Job::shared_secret(private_key, peer).unwrap()) {
+                Outcome::SharedSecret(Some(x)) => assert_eq!(x, from_bytes(&shared)),
+                _ => panic!("no shared secret"),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(Job::public_key(&[0; PRIVATE_KEY_LEN]).is_err());
+        assert!(Job::public_key(&[0xff; PRIVATE_KEY_LEN]).is_err());
+        let mut off_curve = RFC6979_PUBLIC_KEY;
+        off_curve[63] ^= 1;
+        assert!(Job::shared_secret(&RFC6979_PRIVATE_KEY, &off_curve).is_err());
+        assert!(Job::verify(&off_curve, &RFC6979_HASH, &RFC6979_SIGNATURE).is_err());
+    }
+}
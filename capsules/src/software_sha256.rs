@@ -0,0 +1,344 @@
+//! SHA-256 and HMAC-SHA256 in software.
+//!
+//! Implements the `Digest` and `HMACSha256` traits for boards without a
+//! hashing peripheral. Data is hashed as soon as it is added, and the
+//! callbacks are delivered from a deferred call. The computation does not
+//! branch on or index memory with the data or the key.
+//!
+//! `set_mode_hmacsha256()` applies to the next digest only; after `run()` the
+//! engine goes back to plain SHA-256.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sha = static_init!(
+//!     capsules::software_sha256::SoftwareSha256<'static>,
+//!     capsules::software_sha256::SoftwareSha256::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for SHA-256"),
+//! );
+//!
+//! let hmac = static_init!(
+//!     capsules::hmac::HmacDriver<'static, capsules::software_sha256::SoftwareSha256<'static>, [u8; 32]>,
+//!     capsules::hmac::HmacDriver::new(
+//!         sha,
+//!         &mut HMAC_DATA_BUFFER,
+//!         &mut HMAC_DEST_BUFFER,
+//!         board_kernel.create_grant(&memory_allocation_cap),
+//!     )
+//! );
+//! digest::Digest::set_client(sha, hmac);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ReturnCode;
+
+const BLOCK_SIZE: usize = 64;
+const DIGEST_SIZE: usize = 32;
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Running SHA-256 computation.
+struct Sha256State {
+    h: [u32; 8],
+    block: [u8; BLOCK_SIZE],
+    /// Bytes in `block`
+    block_len: usize,
+    /// Bytes hashed so far
+    total_len: u64,
+}
+
+impl Sha256State {
+    fn new() -> Sha256State {
+        Sha256State {
+            h: H0,
+            block: [0; BLOCK_SIZE],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.block[self.block_len] = byte;
+            self.block_len += 1;
+            if self.block_len == BLOCK_SIZE {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+        self.total_len += data.len() as u64;
+    }
+
+    fn finish(mut self) -> [u8; DIGEST_SIZE] {
+        let bits = self.total_len * 8;
+        self.update(&[0x80]);
+        while self.block_len != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0; DIGEST_SIZE];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.h.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    #[allow(clippy::many_single_char_names)]
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.h.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
+
+/// Start an HMAC: hash the key XORed with the inner pad.
+fn hmac_start(key: &[u8; DIGEST_SIZE]) -> Sha256State {
+    let mut state = Sha256State::new();
+    state.update(&pad(key, 0x36));
+    state
+}
+
+/// Finish an HMAC from the state started by `hmac_start()`.
+fn hmac_finish(key: &[u8; DIGEST_SIZE], inner: Sha256State) -> [u8; DIGEST_SIZE] {
+    let mut outer = Sha256State::new();
+    outer.update(&pad(key, 0x5c));
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+/// HMAC-SHA256 of the concatenation of `parts`, for other software
+/// primitives built on it.
+pub(crate) fn hmac_sha256(key: &[u8; DIGEST_SIZE], parts: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut inner = hmac_start(key);
+    for part in parts {
+        inner.update(part);
+    }
+    hmac_finish(key, inner)
+}
+
+fn pad(key: &[u8; DIGEST_SIZE], value: u8) -> [u8; BLOCK_SIZE] {
+    let mut block = [value; BLOCK_SIZE];
+    for (byte, k) in block.iter_mut().zip(key.iter()) {
+        *byte ^= k;
+    }
+    block
+}
+
+pub struct SoftwareSha256<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, [u8; DIGEST_SIZE]>>,
+    state: MapCell<Sha256State>,
+    /// HMAC key of the running digest
+    key: Cell<Option<[u8; DIGEST_SIZE]>>,
+    /// Buffers whose callbacks are pending
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; DIGEST_SIZE]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareSha256<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareSha256<'a> {
+        SoftwareSha256 {
+            client: OptionalCell::empty(),
+            state: MapCell::new(Sha256State::new()),
+            key: Cell::new(None),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+
+    fn schedule_callback(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl<'a> digest::Digest<'a, [u8; DIGEST_SIZE]> for SoftwareSha256<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, [u8; DIGEST_SIZE]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ReturnCode, &'static mut [u8])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, data.take()));
+        }
+        let len = data.len();
+        self.state.map(|state| state.update(&data[..len]));
+        self.data.replace(data.take());
+        self.schedule_callback();
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; DIGEST_SIZE],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; DIGEST_SIZE])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, digest));
+        }
+        if let Some(state) = self.state.replace(Sha256State::new()) {
+            *digest = match self.key.take() {
+                Some(key) => hmac_finish(&key, state),
+                None => state.finish(),
+            };
+        }
+        self.digest.replace(digest);
+        self.schedule_callback();
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.state.replace(Sha256State::new());
+        self.key.set(None);
+    }
+}
+
+impl digest::HMACSha256 for SoftwareSha256<'_> {
+    fn set_mode_hmacsha256(&self, key: &[u8; DIGEST_SIZE]) -> Result<(), ReturnCode> {
+        if self.busy() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.state.replace(hmac_start(key));
+        self.key.set(Some(*key));
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareSha256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(data) = self.data.take() {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        }
+        if let Some(digest) = self.digest.take() {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> [u8; DIGEST_SIZE] {
+        let mut state = Sha256State::new();
+        state.update(data);
+        state.finish()
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            [
+                0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+                0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+                0x78, 0x52, 0xb8, 0x55
+            ]
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad
+            ]
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            [
+                0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e,
+                0x60, 0x39, 0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4,
+                0x19, 0xdb, 0x06, 0xc1
+            ]
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_vector() {
+        // RFC 4231 test case 2, the key padded with zeros
+        let mut key = [0; DIGEST_SIZE];
+        key[..4].copy_from_slice(b"Jefe");
+        assert_eq!(
+            hmac_sha256(&key, &[b"what do ya want ", b"for nothing?"]),
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+                0x64, 0xec, 0x38, 0x43
+            ]
+        );
+    }
+}
@@ -91,7 +91,13 @@ impl<'a> TemperatureSensor<'a> {
                 if !self.busy.get() {
                     app.subscribed = true;
                     self.busy.set(true);
-                    self.driver.read_temperature()
+                    let rc = self.driver.read_temperature();
+                    if rc != ReturnCode::SUCCESS {
+                        // No callback will come for a reading that did not start
+                        app.subscribed = false;
+                        self.busy.set(false);
+                    }
+                    rc
                 } else {
                     ReturnCode::EBUSY
                 }
@@ -493,25 +493,25 @@ impl<'a, A: AES128<'a> + AES128ECB> hil::symmetric_encryption::Client<'a> for Te
 }
 
 #[rustfmt::skip]
-const KEY: [u8; AES128_KEY_SIZE] = [
+pub(crate) const KEY: [u8; AES128_KEY_SIZE] = [
     0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6,
     0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c
 ];
 
 #[rustfmt::skip]
-const IV_CTR: [u8; AES128_BLOCK_SIZE] = [
+pub(crate) const IV_CTR: [u8; AES128_BLOCK_SIZE] = [
     0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7,
     0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff
 ];
 
 #[rustfmt::skip]
-const IV_CBC: [u8; AES128_BLOCK_SIZE] = [
+pub(crate) const IV_CBC: [u8; AES128_BLOCK_SIZE] = [
     0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
     0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f
 ];
 
 #[rustfmt::skip]
-const PTXT: [u8; 4 * AES128_BLOCK_SIZE] = [
+pub(crate) const PTXT: [u8; 4 * AES128_BLOCK_SIZE] = [
     0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96,
     0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
     0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c,
@@ -523,7 +523,7 @@ const PTXT: [u8; 4 * AES128_BLOCK_SIZE] = [
 ];
 
 #[rustfmt::skip]
-const CTXT_CTR: [u8; 4 * AES128_BLOCK_SIZE] = [
+pub(crate) const CTXT_CTR: [u8; 4 * AES128_BLOCK_SIZE] = [
     0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26,
     0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d, 0xb6, 0xce,
     0x98, 0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff,
@@ -535,7 +535,7 @@ const CTXT_CTR: [u8; 4 * AES128_BLOCK_SIZE] = [
 ];
 
 #[rustfmt::skip]
-const CTXT_CBC: [u8; 4 * AES128_BLOCK_SIZE] = [
+pub(crate) const CTXT_CBC: [u8; 4 * AES128_BLOCK_SIZE] = [
     0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46,
     0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9, 0x19, 0x7d,
     0x50, 0x86, 0xcb, 0x9b, 0x50, 0x72, 0x19, 0xee,
@@ -547,7 +547,7 @@ const CTXT_CBC: [u8; 4 * AES128_BLOCK_SIZE] = [
 ];
 
 #[rustfmt::skip]
-const CTXT_ECB: [u8; 4 * AES128_BLOCK_SIZE] = [
+pub(crate) const CTXT_ECB: [u8; 4 * AES128_BLOCK_SIZE] = [
     0x3a, 0xd7, 0x7b, 0xb4, 0x0d, 0x7a, 0x36, 0x60,
     0xa8, 0x9e, 0xca, 0xf3, 0x24, 0x66, 0xef, 0x97,
     0xf5, 0xd3, 0xd5, 0x85, 0x03, 0xb9, 0x69, 0x9d,
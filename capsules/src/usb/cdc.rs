@@ -1,25 +1,43 @@
 //! Communications Class Device for USB
 //!
 //! This capsule allows Tock to support a serial port over USB.
+//!
+//! The port is open while the host sets the DTR control line, which
+//! terminals do when they open the device. Output written while the port is
+//! closed waits for `CDC_BUFFER_TIMEOUT_MS` after the port is enabled.
+//! After that, it is stored in the backlog given with `set_backlog()`, if
+//! any, and sent when the port is next opened; what does not fit is handled
+//! according to the port's [`OverflowPolicy`](enum.OverflowPolicy.html).
+//!
+//! Several ports can be functions of one
+//! [composite device](../composite/struct.CompositeDevice.html), for example
+//! to give the debug console, the process console and an application each
+//! their own serial device on the host. `set_interface_name()` names a port
+//! so that the host can tell them apart.
 
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::Function;
 use super::descriptors;
 use super::descriptors::Buffer64;
+use super::descriptors::CdcAcmSetLineCodingData;
 use super::descriptors::CdcInterfaceDescriptor;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
 use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
+use kernel::common::cells::MapCell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::common::cells::VolatileCell;
 use kernel::common::dynamic_deferred_call::{
     DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
 };
+use kernel::common::{Queue, RingBuffer};
 use kernel::hil;
 use kernel::hil::time::{Alarm, AlarmClient};
 use kernel::hil::uart;
@@ -27,11 +45,14 @@ use kernel::hil::usb::TransferType;
 use kernel::ReturnCode;
 
 /// Identifying number for the endpoint when transferring data from us to the
-/// host.
+/// host, unless a composite device assigns another one.
 const ENDPOINT_IN_NUM: usize = 2;
 /// Identifying number for the endpoint when transferring data from the host to
-/// us.
+/// us, unless a composite device assigns another one.
 const ENDPOINT_OUT_NUM: usize = 3;
+/// Identifying number for the notification endpoint, unless a composite
+/// device assigns another one.
+const ENDPOINT_NOTIFY_NUM: usize = 4;
 
 static LANGUAGES: &'static [u16; 1] = &[
     0x0409, // English (United States)
@@ -48,7 +69,14 @@ pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
 /// if a debug output is not connected.
 pub const CDC_BUFFER_TIMEOUT_MS: u32 = 10000;
 
-const N_ENDPOINTS: usize = 3;
+const N_ENDPOINTS: usize = 2;
+
+/// Bits of the value of a SET_CONTROL_LINE_STATE request.
+const CONTROL_LINE_DTR: u16 = 1 << 0;
+const CONTROL_LINE_RTS: u16 = 1 << 1;
+
+const IN_BUFFER: usize = 0;
+const OUT_BUFFER: usize = 1;
 
 /// States of the CDC driver.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -64,14 +92,23 @@ enum State {
     /// The host has enumerated this USB device. Things should be functional at
     /// this point.
     Enumerated,
-    /// We have seen the CDC messages that we expect to signal that a CDC client
-    /// has connected. We stay in the "connecting" state until the USB transfer
-    /// has completed.
-    Connecting,
-    /// A CDC client is connected. We can safely send data.
+    /// A CDC client has opened the port by setting DTR. We can safely send
+    /// data.
     Connected,
 }
 
+/// What a port does with output written while no CDC client has it open,
+/// once the backlog is full or if it has none.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the output that does not fit in the backlog.
+    DropNewest,
+    /// Drop the oldest output in the backlog to make room.
+    DropOldest,
+    /// Hold the write that does not fit until a client opens the port.
+    Block,
+}
+
 /// States of the Control Endpoint related to CDC-ACM.
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum CtrlState {
@@ -79,12 +116,18 @@ enum CtrlState {
     Idle,
     /// Host has sent a SET_LINE_CODING configuration request.
     SetLineCoding,
+    /// Host has asked for the line coding with a GET_LINE_CODING request.
+    GetLineCoding,
+    /// Host has set the control lines to this value with a
+    /// SET_CONTROL_LINE_STATE request.
+    SetControlLineState(u16),
 }
 
 #[derive(PartialEq)]
 enum CDCCntrlMessage {
     NotSupported,
     SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
     SetControlLineState = 0x22,
     SendBreak = 0x23,
 }
@@ -93,6 +136,7 @@ impl From<u8> for CDCCntrlMessage {
     fn from(num: u8) -> Self {
         match num {
             0x20 => CDCCntrlMessage::SetLineCoding,
+            0x21 => CDCCntrlMessage::GetLineCoding,
             0x22 => CDCCntrlMessage::SetControlLineState,
             0x23 => CDCCntrlMessage::SendBreak,
             _ => CDCCntrlMessage::NotSupported,
@@ -100,6 +144,17 @@ impl From<u8> for CDCCntrlMessage {
     }
 }
 
+/// Told when the host changes the settings of a port.
+pub trait LineStateClient {
+    /// The host set the line coding. Terminals do so when they open the
+    /// port and when the user changes the baud rate.
+    fn line_coding_changed(&self, _line_coding: CdcAcmSetLineCodingData) {}
+
+    /// The host changed the DTR or RTS control line. The port is open while
+    /// DTR is set.
+    fn control_lines_changed(&self, _dtr: bool, _rts: bool) {}
+}
+
 /// Implementation of the Abstract Control Model (ACM) for the Communications
 /// Class Device (CDC) over USB.
 pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
@@ -117,6 +172,19 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// request the host is currently sending us.
     ctrl_state: Cell<CtrlState>,
 
+    /// The line coding the host last set.
+    line_coding: Cell<CdcAcmSetLineCodingData>,
+    /// The control lines the host last set.
+    dtr: Cell<bool>,
+    rts: Cell<bool>,
+    /// Client told about changes to the line coding and control lines.
+    line_state_client: OptionalCell<&'a dyn LineStateClient>,
+
+    /// Output written while the port was closed, which is sent before
+    /// anything else once it opens.
+    backlog: MapCell<RingBuffer<'static, u8>>,
+    overflow_policy: Cell<OverflowPolicy>,
+
     /// A holder reference for the TX buffer we are transmitting from.
     tx_buffer: TakeCell<'static, [u8]>,
     /// The number of bytes the client has asked us to send. We track this so we
@@ -150,15 +218,15 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     deferred_caller: &'a DynamicDeferredCall,
     /// Deferred Call Handle
     handle: OptionalCell<DeferredCallHandle>,
-    /// Flag to mark we are waiting on a deferred call for dropping a TX. This
-    /// can happen if an upper layer told us to transmit a buffer, but there is
-    /// no host connected and therefore we cannot actually transmit. However,
-    /// normal UART semantics are that we can always send (perhaps with a
-    /// delay), even if nothing is actually listening. To keep the upper layers
-    /// happy and to allow this CDC layer to just drop messages, we always
-    /// return SUCCESS for TX, and then use a deferred call to signal the
-    /// transmit done callback.
-    deferred_call_pending_droptx: Cell<bool>,
+    /// Flag to mark we are waiting on a deferred call to store or drop a TX.
+    /// This can happen if an upper layer told us to transmit a buffer, but
+    /// there is no host connected and therefore we cannot actually transmit.
+    /// However, normal UART semantics are that we can always send (perhaps
+    /// with a delay), even if nothing is actually listening. To keep the upper
+    /// layers happy and to allow this CDC layer to store or drop messages, we
+    /// always return SUCCESS for TX, and then use a deferred call to move the
+    /// data to the backlog and signal the transmit done callback.
+    deferred_call_pending_storetx: Cell<bool>,
     /// Flag to mark we need a deferred call to signal a callback after an RX
     /// abort occurs.
     deferred_call_pending_abortrx: Cell<bool>,
@@ -171,6 +239,17 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// This was originally added for the bootloader to allow the host to tell
     /// the device to enter bootloader mode.
     host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
+
+    /// Name of the port shown by the host, if it is part of a composite
+    /// device, and its string index.
+    interface_name: Cell<Option<&'static &'static str>>,
+    interface_string: Cell<u8>,
+
+    /// Interface and endpoint numbers, which a composite device can change.
+    interface: Cell<u8>,
+    endpoint_notify: Cell<usize>,
+    endpoint_in: Cell<usize>,
+    endpoint_out: Cell<usize>,
 }
 
 impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
@@ -226,7 +305,10 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
 
         let endpoints: &[&[EndpointDescriptor]] = &[
             &[EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(4, TransferDirection::DeviceToHost),
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NOTIFY_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
                 transfer_type: TransferType::Interrupt,
                 max_packet_size: 8,
                 interval: 16,
@@ -234,7 +316,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             &[
                 EndpointDescriptor {
                     endpoint_address: EndpointAddress::new_const(
-                        2,
+                        ENDPOINT_IN_NUM,
                         TransferDirection::DeviceToHost,
                     ),
                     transfer_type: TransferType::Bulk,
@@ -243,7 +325,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 },
                 EndpointDescriptor {
                     endpoint_address: EndpointAddress::new_const(
-                        3,
+                        ENDPOINT_OUT_NUM,
                         TransferDirection::HostToDevice,
                     ),
                     transfer_type: TransferType::Bulk,
@@ -284,13 +366,15 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 LANGUAGES,
                 strings,
             ),
-            buffers: [
-                Buffer64::default(),
-                Buffer64::default(),
-                Buffer64::default(),
-            ],
+            buffers: [Buffer64::default(), Buffer64::default()],
             state: Cell::new(State::Disabled),
             ctrl_state: Cell::new(CtrlState::Idle),
+            line_coding: Cell::new(CdcAcmSetLineCodingData::default()),
+            dtr: Cell::new(false),
+            rts: Cell::new(false),
+            line_state_client: OptionalCell::empty(),
+            backlog: MapCell::empty(),
+            overflow_policy: Cell::new(OverflowPolicy::DropNewest),
             tx_buffer: TakeCell::empty(),
             tx_len: Cell::new(0),
             tx_offset: Cell::new(0),
@@ -303,9 +387,15 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             boot_period: Cell::new(true),
             deferred_caller,
             handle: OptionalCell::empty(),
-            deferred_call_pending_droptx: Cell::new(false),
+            deferred_call_pending_storetx: Cell::new(false),
             deferred_call_pending_abortrx: Cell::new(false),
             host_initiated_function,
+            interface_name: Cell::new(None),
+            interface_string: Cell::new(0),
+            interface: Cell::new(0),
+            endpoint_notify: Cell::new(ENDPOINT_NOTIFY_NUM),
+            endpoint_in: Cell::new(ENDPOINT_IN_NUM),
+            endpoint_out: Cell::new(ENDPOINT_OUT_NUM),
         }
     }
 
@@ -313,48 +403,59 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         self.handle.replace(handle);
     }
 
-    #[inline]
-    pub fn controller(&self) -> &'a U {
-        self.client_ctrl.controller()
+    pub fn set_line_state_client(&self, client: &'a dyn LineStateClient) {
+        self.line_state_client.set(client);
     }
 
-    #[inline]
-    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
-        &self.buffers[i - 1].buf
-    }
-
-    /// This is a helper function used to indicate successful uart transmission to
-    /// a higher layer client despite not actually being connected to a host. Allows
-    /// blocking debug interfaces to function in the same way they do when an actual UART
-    /// interface is in use. This should only be called in an upcall.
-    fn indicate_tx_success(&self) {
-        self.tx_len.set(0);
-        self.tx_offset.set(0);
-        self.tx_client.map(|client| {
-            self.tx_buffer.take().map(|buf| {
-                client.transmitted_buffer(buf, 0, ReturnCode::FAIL);
-            });
-        });
+    /// Store output written while the port is closed in `backlog`, which
+    /// holds one byte less than its length.
+    pub fn set_backlog(&self, backlog: &'static mut [u8]) {
+        if backlog.len() > 1 {
+            self.backlog.put(RingBuffer::new(backlog));
+        }
     }
-}
 
-impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
-    for CdcAcm<'a, U, A>
-{
-    fn enable(&'a self) {
-        // Set up the default control endpoint
-        self.client_ctrl.enable();
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.overflow_policy.set(policy);
+    }
+
+    /// Name the port. The name must be set before the port is added to a
+    /// composite device, and is not used by a standalone port.
+    pub fn set_interface_name(&self, name: &'static &'static str) {
+        self.interface_name.set(Some(name));
+    }
 
+    /// Whether a CDC client has the port open.
+    pub fn is_open(&self) -> bool {
+        self.state.get() == State::Connected
+    }
+
+    /// The line coding the host last set, or 115200 8N1 if it has not.
+    pub fn line_coding(&self) -> CdcAcmSetLineCodingData {
+        self.line_coding.get()
+    }
+
+    /// The DTR and RTS control lines the host last set.
+    pub fn control_lines(&self) -> (bool, bool) {
+        (self.dtr.get(), self.rts.get())
+    }
+
+    #[inline]
+    pub fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    fn enable_endpoints(&'a self) {
         // Setup buffers for IN and OUT data transfer.
         self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+            .endpoint_set_in_buffer(self.endpoint_in.get(), &self.buffers[IN_BUFFER].buf);
         self.controller()
-            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint_in.get());
 
         self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+            .endpoint_set_out_buffer(self.endpoint_out.get(), &self.buffers[OUT_BUFFER].buf);
         self.controller()
-            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint_out.get());
 
         self.state.set(State::Enabled);
 
@@ -364,6 +465,172 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
         );
     }
 
+    /// Track the CDC requests that tell us when a CDC client connects or
+    /// disconnects.
+    fn handle_class_request(&self, setup_data: SetupData) {
+        let b_request = setup_data.request_code;
+
+        let ctrl_state = match CDCCntrlMessage::from(b_request) {
+            CDCCntrlMessage::SetLineCoding => CtrlState::SetLineCoding,
+            CDCCntrlMessage::GetLineCoding => CtrlState::GetLineCoding,
+            CDCCntrlMessage::SetControlLineState => {
+                // Bit 0 and 1 of the value (setup_data.value) can be set
+                // D0: Indicates to DCE if DTE is present or not.
+                //     - 0 -> Not present
+                //     - 1 -> Present
+                // D1: Carrier control for half duplex modems.
+                //     - 0 -> Deactivate carrier
+                //     - 1 -> Activate carrier
+                // The port is open while DTE is present. The new state
+                // applies once the transfer has completed.
+                CtrlState::SetControlLineState(setup_data.value)
+            }
+            CDCCntrlMessage::SendBreak => {
+                // On Mac, we seem to get the SEND_BREAK to signal that a
+                // client disconnects.
+                self.close();
+                CtrlState::Idle
+            }
+            CDCCntrlMessage::NotSupported => CtrlState::Idle,
+        };
+        self.ctrl_state.set(ctrl_state);
+    }
+
+    /// Handle the data of a Control Out transfer in `buf`.
+    fn handle_ctrl_data(&self, buf: &[VolatileCell<u8>]) {
+        // Check what state our Ctrl endpoint is in.
+        if self.ctrl_state.get() == CtrlState::SetLineCoding {
+            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
+            // We can parse the data we got.
+            CdcAcmSetLineCodingData::get(buf).map(|line_coding| {
+                self.line_coding.set(line_coding);
+
+                // Check if the baud rate we got matches the special flag
+                // value (1200 baud). If so, we run an optional function
+                // provided when the CDC stack was configured.
+                if line_coding.baud_rate == 1200 {
+                    self.host_initiated_function.map(|f| {
+                        f();
+                    });
+                }
+            });
+        }
+    }
+
+    /// Write the line coding to the data stage of a GET_LINE_CODING
+    /// request.
+    fn send_line_coding(&self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.line_coding.get().put(buf) {
+            0 => hil::usb::CtrlInResult::Error,
+            len => hil::usb::CtrlInResult::Packet(len, true),
+        }
+    }
+
+    /// Handle the completion of a Control transfer.
+    fn ctrl_complete(&self) {
+        match self.ctrl_state.replace(CtrlState::Idle) {
+            CtrlState::SetLineCoding => {
+                let line_coding = self.line_coding.get();
+                self.line_state_client
+                    .map(|client| client.line_coding_changed(line_coding));
+            }
+            CtrlState::SetControlLineState(lines) => {
+                // Here we check to see if we just got connected to or
+                // disconnected from a CDC client.
+                let dtr = lines & CONTROL_LINE_DTR != 0;
+                let rts = lines & CONTROL_LINE_RTS != 0;
+                self.dtr.set(dtr);
+                self.rts.set(rts);
+                if dtr {
+                    self.open();
+                } else {
+                    self.close();
+                }
+                self.line_state_client
+                    .map(|client| client.control_lines_changed(dtr, rts));
+            }
+            _ => {}
+        }
+    }
+
+    /// A CDC client opened the port, so we can begin transmitting if needed.
+    fn open(&self) {
+        if self.state.get() == State::Enumerated {
+            self.state.set(State::Connected);
+            if self.tx_buffer.is_some() || self.backlog.map_or(false, |b| b.has_elements()) {
+                self.controller().endpoint_resume_in(self.endpoint_in.get());
+            }
+        }
+    }
+
+    /// The CDC client closed the port. A pending transmission is stored or
+    /// dropped from a deferred call.
+    fn close(&self) {
+        if self.state.get() == State::Connected {
+            self.state.set(State::Enumerated);
+            if self.tx_buffer.is_some() {
+                self.deferred_call_pending_storetx.set(true);
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+            }
+        }
+    }
+
+    /// Move the rest of the pending transmission to the backlog while the
+    /// port is closed. Once it is all stored, or the overflow policy drops
+    /// the rest, signal the callback to the higher layer, which allows
+    /// blocking debug interfaces to function in the same way they do when an
+    /// actual UART interface is in use. During the boot period, what does not
+    /// fit waits for a host whatever the policy. This should only be called
+    /// in an upcall.
+    fn store_tx(&self) {
+        let policy = self.overflow_policy.get();
+        let hold = self.boot_period.get() || policy == OverflowPolicy::Block;
+        let finished = self.tx_buffer.map_or(false, |tx_buf| {
+            let len = self.tx_len.get();
+            let mut offset = self.tx_offset.get();
+            self.backlog.map(|backlog| {
+                while offset < len {
+                    if !backlog.enqueue(tx_buf[offset]) {
+                        if hold || policy == OverflowPolicy::DropNewest {
+                            break;
+                        }
+                        backlog.push(tx_buf[offset]);
+                    }
+                    offset += 1;
+                }
+            });
+            self.tx_offset.set(offset);
+            offset == len || !hold
+        });
+        if finished {
+            // Report the bytes that were sent or stored; the others were
+            // dropped.
+            let stored = self.tx_offset.replace(0);
+            let len = self.tx_len.replace(0);
+            let result = if stored == len {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::FAIL
+            };
+            self.tx_client.map(|client| {
+                self.tx_buffer.take().map(|buf| {
+                    client.transmitted_buffer(buf, stored, result);
+                });
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
+    for CdcAcm<'a, U, A>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.enable_endpoints();
+    }
+
     fn attach(&'a self) {
         self.client_ctrl.attach();
         self.state.set(State::Attached);
@@ -372,6 +639,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     fn bus_reset(&'a self) {
         // We take a bus reset to mean the enumeration has finished.
         self.state.set(State::Enumerated);
+        self.dtr.set(false);
+        self.rts.set(false);
     }
 
     /// Handle a Control Setup transaction.
@@ -379,67 +648,24 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// CDC uses special values here, and we can use these to know when a CDC
     /// client is connected or not.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
-        descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
-            let b_request = setup_data.request_code;
-
-            match CDCCntrlMessage::from(b_request) {
-                CDCCntrlMessage::SetLineCoding => {
-                    self.ctrl_state.set(CtrlState::SetLineCoding);
-                }
-                CDCCntrlMessage::SetControlLineState => {
-                    // Bit 0 and 1 of the value (setup_data.value) can be set
-                    // D0: Indicates to DCE if DTE is present or not.
-                    //     - 0 -> Not present
-                    //     - 1 -> Present
-                    // D1: Carrier control for half duplex modems.
-                    //     - 0 -> Deactivate carrier
-                    //     - 1 -> Activate carrier
-                    // Currently we don't care about the value
-                }
-                CDCCntrlMessage::SendBreak => {
-                    // On Mac, we seem to get the SEND_BREAK to signal that a
-                    // client disconnects.
-                    self.state.set(State::Enumerated)
-                }
-                _ => {}
-            }
-        });
+        descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .map(|setup_data| self.handle_class_request(setup_data));
 
         self.client_ctrl.ctrl_setup(endpoint)
     }
 
     /// Handle a Control In transaction
     fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
-        self.client_ctrl.ctrl_in(endpoint)
+        if self.ctrl_state.get() == CtrlState::GetLineCoding {
+            self.send_line_coding(&self.client_ctrl.ctrl_buffer.buf)
+        } else {
+            self.client_ctrl.ctrl_in(endpoint)
+        }
     }
 
     /// Handle a Control Out transaction
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
-        // Check what state our Ctrl endpoint is in.
-        if self.ctrl_state.get() == CtrlState::SetLineCoding {
-            // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
-            // We can parse the data we got.
-            descriptors::CdcAcmSetLineCodingData::get(&self.client_ctrl.ctrl_buffer.buf).map(
-                |line_coding| {
-                    // Check if we should switch our main state machine to
-                    // connecting meaning that the host is connecting to the virtual
-                    // serial port. We decide this based on if the host is
-                    // configuring the baud rate to what we expect.
-                    if self.state.get() == State::Enumerated && line_coding.baud_rate == 115200 {
-                        self.state.set(State::Connecting);
-                    }
-
-                    // Check if the baud rate we got matches the special flag
-                    // value (1200 baud). If so, we run an optional function
-                    // provided when the CDC stack was configured.
-                    if line_coding.baud_rate == 1200 {
-                        self.host_initiated_function.map(|f| {
-                            f();
-                        });
-                    }
-                },
-            );
-        }
+        self.handle_ctrl_data(&self.client_ctrl.ctrl_buffer.buf);
 
         self.client_ctrl.ctrl_out(endpoint, packet_bytes)
     }
@@ -450,16 +676,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        self.ctrl_state.set(CtrlState::Idle);
-
-        // Here we check to see if we just got connected to a CDC client. If so,
-        // we can begin transmitting if needed.
-        if self.state.get() == State::Connecting {
-            self.state.set(State::Connected);
-            if self.tx_buffer.is_some() {
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
-            }
-        }
+        self.ctrl_complete();
 
         self.client_ctrl.ctrl_status_complete(endpoint)
     }
@@ -472,9 +689,30 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// `hil::usb::InResult::Delay` from this function. That means we can use
     /// this as a callback to mean that the transmission finished by waiting
     /// until this function is called when we don't have anything left to send.
-    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
         match transfer_type {
             TransferType::Bulk => {
+                if self.state.get() != State::Connected {
+                    return hil::usb::InResult::Delay;
+                }
+
+                // Data stored while the port was closed goes first.
+                let packet = &self.buffers[IN_BUFFER].buf;
+                let backlog_bytes = self.backlog.map_or(0, |backlog| {
+                    let mut n = 0;
+                    while n < packet.len() {
+                        match backlog.dequeue() {
+                            Some(byte) => packet[n].set(byte),
+                            None => break,
+                        }
+                        n += 1;
+                    }
+                    n
+                });
+                if backlog_bytes > 0 {
+                    return hil::usb::InResult::Packet(backlog_bytes);
+                }
+
                 self.tx_buffer
                     .take()
                     .map_or(hil::usb::InResult::Delay, |tx_buf| {
@@ -486,7 +724,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
                             // Get packet that we have shared with the underlying
                             // USB stack to copy the tx into.
-                            let packet = self.buffer(endpoint);
+                            let packet = &self.buffers[IN_BUFFER].buf;
 
                             // Calculate how much more we can send.
                             let to_send = cmp::min(packet.len(), remaining);
@@ -534,7 +772,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     fn packet_out(
         &'a self,
         transfer_type: TransferType,
-        endpoint: usize,
+        _endpoint: usize,
         packet_bytes: u32,
     ) -> hil::usb::OutResult {
         match transfer_type {
@@ -549,7 +787,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
                     let copy_length = cmp::min(packet_bytes as usize, available_bytes);
 
                     // Do the copy into the RX buffer.
-                    let packet = self.buffer(endpoint);
+                    let packet = &self.buffers[OUT_BUFFER].buf;
                     for i in 0..copy_length {
                         rx_buf[rx_offset + i] = packet[i].get();
                     }
@@ -588,6 +826,12 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 
     fn packet_transmitted(&'a self, _endpoint: usize) {
+        // Send the rest of the backlog first.
+        if self.backlog.map_or(false, |backlog| backlog.has_elements()) {
+            self.controller().endpoint_resume_in(self.endpoint_in.get());
+            return;
+        }
+
         // Check if more to send.
         self.tx_buffer.take().map(|tx_buf| {
             // Check if we have any bytes to send.
@@ -595,7 +839,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
             if remaining > 0 {
                 // We do, so ask to send again.
                 self.tx_buffer.replace(tx_buf);
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in.get());
             } else {
                 // We don't have anything to send, so that means we are
                 // ok to signal the callback.
@@ -647,18 +891,15 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Transmit<'a>
             if self.state.get() == State::Connected {
                 // Then signal to the lower layer that we are ready to do a TX
                 // by putting data in the IN endpoint.
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
-                (ReturnCode::SUCCESS, None)
-            } else if self.boot_period.get() {
-                // indicate success because we will try to send it once a host connects
-                (ReturnCode::SUCCESS, None)
+                self.controller().endpoint_resume_in(self.endpoint_in.get());
             } else {
-                // indicate success, but we will not actually queue this message -- just schedule
-                // a deferred callback to return the buffer immediately.
-                self.deferred_call_pending_droptx.set(true);
+                // indicate success, and schedule a deferred callback to store
+                // the message in the backlog or drop it, and return the
+                // buffer unless it has to wait for a host.
+                self.deferred_call_pending_storetx.set(true);
                 self.handle.map(|handle| self.deferred_caller.set(*handle));
-                (ReturnCode::SUCCESS, None)
             }
+            (ReturnCode::SUCCESS, None)
         }
     }
 
@@ -719,9 +960,9 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> AlarmClient for CdcA
             // we are already connected, so any queued messages are going to be sent.
             // do nothing.
         } else {
-            // no client has connected, but we do not want to block indefinitely, so go ahead
-            // and deliver a callback.
-            self.indicate_tx_success();
+            // no client has connected, but we do not want to block indefinitely unless the
+            // overflow policy says so, so go ahead and deliver a callback.
+            self.store_tx();
         }
     }
 }
@@ -730,8 +971,8 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> DynamicDeferredCallC
     for CdcAcm<'a, U, A>
 {
     fn call(&self, _handle: DeferredCallHandle) {
-        if self.deferred_call_pending_droptx.replace(false) {
-            self.indicate_tx_success()
+        if self.deferred_call_pending_storetx.replace(false) && !self.is_open() {
+            self.store_tx()
         }
 
         if self.deferred_call_pending_abortrx.replace(false) {
@@ -760,3 +1001,452 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::UartData<'a>
     for CdcAcm<'a, U, A>
 {
 }
+
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> Function<'a> for CdcAcm<'a, U, A> {
+    fn interface_count(&self) -> u8 {
+        2
+    }
+
+    fn endpoint_count(&self) -> usize {
+        2
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        (0x02, 0x02, 0x01)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        // The notification endpoint, then bulk IN and OUT endpoints of the
+        // same number.
+        self.interface.set(first_interface);
+        self.endpoint_notify.set(first_endpoint);
+        self.endpoint_in.set(first_endpoint + 1);
+        self.endpoint_out.set(first_endpoint + 1);
+    }
+
+    fn strings(&self) -> &[&'static str] {
+        self.interface_name
+            .get()
+            .map_or(&[], |name| core::slice::from_ref(name))
+    }
+
+    fn assign_strings(&self, first_string: u8) {
+        if self.interface_name.get().is_some() {
+            self.interface_string.set(first_string);
+        }
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        let communication = self.interface.get();
+        let data = communication + 1;
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: communication,
+                    num_endpoints: 1,
+                    interface_class: 0x02,    // CDC communication
+                    interface_subclass: 0x02, // abstract control model (ACM)
+                    interface_protocol: 0x01, // V.25ter (AT commands)
+                    string_index: self.interface_string.get(),
+                    ..InterfaceDescriptor::default()
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+                    field1: 0x10, // CDC
+                    field2: 0x11, // CDC
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
+                    field1: 0x00, // Capabilities
+                    field2: data,
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
+                    field1: 0x06, // Capabilities
+                    field2: 0x00, // unused
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+                    field1: communication,
+                    field2: data,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_notify.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 8,
+                    interval: 16,
+                },
+                &InterfaceDescriptor {
+                    interface_number: data,
+                    num_endpoints: 2,
+                    interface_class: 0x0a,    // CDC data
+                    interface_subclass: 0x00, // none
+                    interface_protocol: 0x00, // none
+                    ..InterfaceDescriptor::default()
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_in.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_out.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+            ],
+        )
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+        self.state.set(State::Attached);
+    }
+
+    fn bus_reset(&'a self) {
+        hil::usb::Client::bus_reset(self);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        self.handle_class_request(setup);
+        match setup.request_type.transfer_direction() {
+            TransferDirection::HostToDevice => hil::usb::CtrlSetupResult::Ok,
+            TransferDirection::DeviceToHost => {
+                if self.ctrl_state.get() == CtrlState::GetLineCoding {
+                    hil::usb::CtrlSetupResult::Ok
+                } else {
+                    hil::usb::CtrlSetupResult::ErrNonstandardRequest
+                }
+            }
+        }
+    }
+
+    fn ctrl_in(&'a self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        if self.ctrl_state.get() == CtrlState::GetLineCoding {
+            self.send_line_coding(buf)
+        } else {
+            hil::usb::CtrlInResult::Error
+        }
+    }
+
+    fn ctrl_out(&'a self, buf: &[VolatileCell<u8>], _packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.handle_ctrl_data(buf);
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.ctrl_complete();
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+
+    use super::super::sim::{setup, SimAlarm, SimUsbController, TransferError};
+    use super::{CdcAcm, OverflowPolicy, CDC_BUFFER_TIMEOUT_MS};
+    use kernel::common::cells::TakeCell;
+    use kernel::common::dynamic_deferred_call::{
+        DynamicDeferredCall, DynamicDeferredCallClient, DynamicDeferredCallClientState,
+    };
+    use kernel::hil::time::Alarm;
+    use kernel::hil::uart::{self, Transmit};
+    use kernel::hil::usb::{Client, UsbController};
+    use kernel::ReturnCode;
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "Serial", "1"];
+
+    const SET_LINE_CODING: u8 = 0x20;
+    const GET_LINE_CODING: u8 = 0x21;
+    const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+    struct TxClient {
+        sent: Cell<Option<usize>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl TxClient {
+        fn new() -> TxClient {
+            TxClient {
+                sent: Cell::new(None),
+                buffer: TakeCell::empty(),
+            }
+        }
+    }
+
+    impl uart::TransmitClient for TxClient {
+        fn transmitted_buffer(&self, buffer: &'static mut [u8], len: usize, _rval: ReturnCode) {
+            self.sent.set(Some(len));
+            self.buffer.replace(buffer);
+        }
+    }
+
+    fn deferred_caller() -> &'static DynamicDeferredCall {
+        let states = Box::leak(Box::new(<[DynamicDeferredCallClientState; 2]>::default()));
+        Box::leak(Box::new(DynamicDeferredCall::new(states)))
+    }
+
+    fn line_coding(baud_rate: u32) -> [u8; 7] {
+        let baud = baud_rate.to_le_bytes();
+        // 8 data bits, 1 stop bit, no parity
+        [baud[0], baud[1], baud[2], baud[3], 0, 0, 8]
+    }
+
+    #[test]
+    fn descriptors() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let cdc = CdcAcm::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            &alarm,
+            deferred_caller(),
+            None,
+        );
+        usb.set_client(&cdc);
+        cdc.enable();
+        cdc.attach();
+
+        let device = usb.enumerate();
+        // Communications interface, then data interface
+        assert_eq!(&device.configuration[9 + 5..9 + 8], &[0x02, 0x02, 0x01]);
+        assert_eq!(device.strings[1].as_deref(), Some("Serial"));
+    }
+
+    #[test]
+    fn connects_on_dtr() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let cdc = CdcAcm::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            &alarm,
+            deferred_caller(),
+            None,
+        );
+        let tx_client = TxClient::new();
+        usb.set_client(&cdc);
+        cdc.set_transmit_client(&tx_client);
+        cdc.enable();
+        cdc.attach();
+        usb.enumerate();
+
+        // Data written before a terminal connects is held back.
+        let buffer = Box::leak(Box::new(*b"hello"));
+        assert_eq!(cdc.transmit_buffer(buffer, 5).0, ReturnCode::SUCCESS);
+        assert_eq!(usb.transfer_in(2), Err(TransferError::Nak));
+
+        // Terminals set the line coding and DTR when they open the port.
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding(115200)),
+            Ok(())
+        );
+        assert_eq!(usb.transfer_in(2), Err(TransferError::Nak));
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_CONTROL_LINE_STATE, 3, 0, 0), &[]),
+            Ok(())
+        );
+        assert!(cdc.is_open());
+        assert_eq!(cdc.control_lines(), (true, true));
+        assert_eq!(usb.drain_in(2), b"hello".to_vec());
+        assert_eq!(tx_client.sent.get(), Some(5));
+        assert!(tx_client.buffer.is_some());
+    }
+
+    #[test]
+    fn line_coding_does_not_connect() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let cdc = CdcAcm::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            &alarm,
+            deferred_caller(),
+            None,
+        );
+        usb.set_client(&cdc);
+        cdc.enable();
+        cdc.attach();
+        usb.enumerate();
+
+        let buffer = Box::leak(Box::new(*b"hello"));
+        assert_eq!(cdc.transmit_buffer(buffer, 5).0, ReturnCode::SUCCESS);
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding(9600)),
+            Ok(())
+        );
+        assert_eq!(usb.transfer_in(2), Err(TransferError::Nak));
+    }
+
+    #[test]
+    fn touch_1200_runs_host_function() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let touched = Cell::new(false);
+        let function = || touched.set(true);
+        let cdc = CdcAcm::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            &alarm,
+            deferred_caller(),
+            Some(&function),
+        );
+        usb.set_client(&cdc);
+        cdc.enable();
+        cdc.attach();
+        usb.enumerate();
+
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding(115200)),
+            Ok(())
+        );
+        assert!(!touched.get());
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding(1200)),
+            Ok(())
+        );
+        assert!(touched.get());
+    }
+
+    #[test]
+    fn get_line_coding() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let cdc = CdcAcm::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            &alarm,
+            deferred_caller(),
+            None,
+        );
+        usb.set_client(&cdc);
+        cdc.enable();
+        cdc.attach();
+        usb.enumerate();
+
+        assert_eq!(
+            usb.control_in(setup(0xa1, GET_LINE_CODING, 0, 0, 7)),
+            Ok(line_coding(115200).to_vec())
+        );
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_LINE_CODING, 0, 0, 7), &line_coding(9600)),
+            Ok(())
+        );
+        assert_eq!(cdc.line_coding().baud_rate, 9600);
+        assert_eq!(
+            usb.control_in(setup(0xa1, GET_LINE_CODING, 0, 0, 7)),
+            Ok(line_coding(9600).to_vec())
+        );
+    }
+
+    #[test]
+    fn backlog_while_closed() {
+        // The deferred call needs a 'static client.
+        let usb = Box::leak(Box::new(SimUsbController::new()));
+        let alarm = Box::leak(Box::new(SimAlarm::new()));
+        let deferred_caller = deferred_caller();
+        let cdc = Box::leak(Box::new(CdcAcm::new(
+            usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            alarm,
+            deferred_caller,
+            None,
+        )));
+        let handle = deferred_caller.register(cdc).unwrap();
+        cdc.initialize_callback_handle(handle);
+        alarm.set_alarm_client(cdc);
+        let tx_client = Box::leak(Box::new(TxClient::new()));
+        cdc.set_transmit_client(tx_client);
+        cdc.set_backlog(Box::leak(Box::new([0; 9])));
+        cdc.set_overflow_policy(OverflowPolicy::DropOldest);
+        usb.set_client(cdc);
+        cdc.enable();
+        cdc.attach();
+        usb.enumerate();
+        alarm.advance(CDC_BUFFER_TIMEOUT_MS);
+
+        // Once the boot period is over, writes to a closed port complete
+        // at once, and the backlog keeps the latest 8 bytes.
+        let buffer = Box::leak(Box::new(*b"hello, world"));
+        assert_eq!(cdc.transmit_buffer(buffer, 12).0, ReturnCode::SUCCESS);
+        assert!(deferred_caller.has_pending());
+        cdc.call(handle);
+        assert_eq!(tx_client.sent.get(), Some(12));
+
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_CONTROL_LINE_STATE, 1, 0, 0), &[]),
+            Ok(())
+        );
+        assert_eq!(usb.drain_in(2), b"o, world".to_vec());
+
+        // With the Block policy, a write that does not fit waits for the
+        // port to open again.
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_CONTROL_LINE_STATE, 0, 0, 0), &[]),
+            Ok(())
+        );
+        assert!(!cdc.is_open());
+        cdc.set_overflow_policy(OverflowPolicy::Block);
+        tx_client.sent.set(None);
+        let buffer = tx_client.buffer.take().unwrap();
+        assert_eq!(cdc.transmit_buffer(buffer, 12).0, ReturnCode::SUCCESS);
+        cdc.call(handle);
+        assert_eq!(tx_client.sent.get(), None);
+        assert_eq!(
+            usb.control_out(setup(0x21, SET_CONTROL_LINE_STATE, 1, 0, 0), &[]),
+            Ok(())
+        );
+        assert_eq!(usb.drain_in(2), b"hello, world".to_vec());
+        assert_eq!(tx_client.sent.get(), Some(12));
+    }
+}
@@ -0,0 +1,847 @@
+//! Composite USB device
+//!
+//! Lets several USB functions, such as a CDC-ACM serial port and a CTAP HID
+//! key, share one USB controller. The composite device is the client of the
+//! controller: it answers the standard control requests, builds the
+//! configuration descriptor from the descriptors of its functions, grouping
+//! the interfaces of each function with an interface association descriptor,
+//! and passes class and vendor requests and endpoint traffic to the function
+//! they belong to.
+//!
+//! Functions are added in order before the device is enabled. Each one is
+//! assigned the next free interface numbers and endpoint numbers, starting
+//! at interface 0 and endpoint 1. A function may use both the IN and the OUT
+//! endpoint of each endpoint number it gets.
+//!
+//! ```
+//!                CompositeDevice
+//!                /      |      \
+//!           CdcAcm   CtapHid   ...  (Function)
+//!                \      |      /
+//!                 UsbController
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let composite = static_init!(
+//!     capsules::usb::composite::CompositeDevice<'static, nrf52::usbd::Usbd<'static>>,
+//!     capsules::usb::composite::CompositeDevice::new(
+//!         &nrf52::usbd::USBD,
+//!         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503a,
+//!         STRINGS,
+//!         &mut capsules::usb::composite::DESCRIPTOR_BUFFER,
+//!     )
+//! );
+//! composite.add_function(cdc);
+//! composite.add_function(ctap);
+//! nrf52::usbd::USBD.set_client(composite);
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::InterfaceAssociationDescriptor;
+use super::descriptors::LanguagesDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::StringDescriptor;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Maximum number of functions of a composite device.
+pub const MAX_FUNCTIONS: usize = 4;
+
+/// Number of endpoint numbers, including the default control endpoint.
+const N_ENDPOINTS: usize = 8;
+
+/// Storage for the descriptors sent to the host.
+pub static mut DESCRIPTOR_BUFFER: [u8; 256] = [0; 256];
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// A USB function that is part of a composite device.
+///
+/// Functions get the control requests for their interfaces and endpoints
+/// and the traffic on their endpoints. They use the controller directly to
+/// set up and resume their endpoints.
+pub trait Function<'a> {
+    /// Number of interfaces of the function.
+    fn interface_count(&self) -> u8;
+
+    /// Number of endpoint numbers the function needs.
+    fn endpoint_count(&self) -> usize;
+
+    /// Class, subclass and protocol of the function, for its interface
+    /// association descriptor.
+    fn function_class(&self) -> (u8, u8, u8);
+
+    /// Tell the function its first interface number and endpoint number.
+    /// Its other interfaces and endpoints follow consecutively.
+    fn assign(&self, first_interface: u8, first_endpoint: usize);
+
+    /// Strings that the descriptors of the function refer to, such as the
+    /// MAC address of a network function. They get the string indexes after
+    /// the device strings and the strings of the functions added before.
+    fn strings(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Tell the function the index of its first string.
+    fn assign_strings(&self, _first_string: u8) {}
+
+    /// Write the interface, class-specific and endpoint descriptors of the
+    /// function to `buf`. Returns their length, or 0 if they do not fit.
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize;
+
+    /// Write a descriptor that the host requested from an interface of the
+    /// function, such as a HID report descriptor. Returns its length, or 0
+    /// if the function has no such descriptor.
+    fn write_class_descriptor(&self, _descriptor_type: DescriptorType, _buf: &[Cell<u8>]) -> usize {
+        0
+    }
+
+    /// The alternate setting selected for `interface`, one of the
+    /// interfaces of the function.
+    fn alternate_setting(&self, _interface: u8) -> u8 {
+        0
+    }
+
+    /// Select alternate setting `alternate_setting` of `interface`. Returns
+    /// false if the interface has no such setting.
+    fn set_alternate_setting(&'a self, _interface: u8, alternate_setting: u8) -> bool {
+        alternate_setting == 0
+    }
+
+    /// Set up the endpoints of the function.
+    fn enable(&'a self);
+
+    fn bus_reset(&'a self) {}
+
+    /// Handle a class or vendor request addressed to an interface or
+    /// endpoint of the function. If the function accepts it, the data stage
+    /// goes to `ctrl_in` or `ctrl_out`.
+    fn ctrl_setup(&'a self, _setup: SetupData) -> hil::usb::CtrlSetupResult {
+        hil::usb::CtrlSetupResult::ErrNonstandardRequest
+    }
+
+    /// Write the next packet of the data stage of an accepted IN request to
+    /// `buf`.
+    fn ctrl_in(&'a self, _buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        hil::usb::CtrlInResult::Error
+    }
+
+    /// Handle a packet of the data stage of an accepted OUT request.
+    fn ctrl_out(
+        &'a self,
+        _buf: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Halted
+    }
+
+    /// An accepted request completed.
+    fn ctrl_status_complete(&'a self) {}
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult;
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult;
+
+    fn packet_transmitted(&'a self, endpoint: usize);
+}
+
+/// States of the default control endpoint.
+#[derive(Copy, Clone)]
+enum State {
+    Init,
+
+    /// Sending the data in `descriptors` from the first to the second
+    /// offset.
+    CtrlIn(usize, usize),
+
+    /// A function handles the current request.
+    Function(usize),
+
+    SetAddress,
+}
+
+/// A function and the numbers assigned to it.
+struct Slot<'a> {
+    function: OptionalCell<&'a dyn Function<'a>>,
+    first_interface: Cell<u8>,
+    first_endpoint: Cell<usize>,
+    first_string: Cell<u8>,
+}
+
+pub struct CompositeDevice<'a, U: 'a> {
+    controller: &'a U,
+    state: Cell<State>,
+
+    /// A 64-byte buffer for the control endpoint.
+    ctrl_buffer: Buffer64,
+
+    /// Storage for composing descriptors and other responses to control
+    /// requests.
+    descriptors: &'a [Cell<u8>],
+
+    functions: [Slot<'a>; MAX_FUNCTIONS],
+    next_interface: Cell<u8>,
+    next_endpoint: Cell<usize>,
+    next_string: Cell<u8>,
+
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    configuration: Cell<u8>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        descriptor_buffer: &'a mut [u8],
+    ) -> Self {
+        CompositeDevice {
+            controller: controller,
+            state: Cell::new(State::Init),
+            ctrl_buffer: Buffer64::default(),
+            descriptors: Cell::from_mut(descriptor_buffer).as_slice_of_cells(),
+            functions: Default::default(),
+            next_interface: Cell::new(0),
+            next_endpoint: Cell::new(1),
+            next_string: Cell::new(strings.len() as u8 + 1),
+            max_ctrl_packet_size: max_ctrl_packet_size,
+            vendor_id: vendor_id,
+            product_id: product_id,
+            strings: strings,
+            configuration: Cell::new(0),
+        }
+    }
+
+    /// Add a function to the device and assign it interface and endpoint
+    /// numbers. Functions must be added before the device is enabled.
+    ///
+    /// Returns ENOMEM if there is no room for another function, there are
+    /// not enough endpoints left or the configuration descriptor would no
+    /// longer fit in the descriptor buffer.
+    pub fn add_function(&self, function: &'a dyn Function<'a>) -> ReturnCode {
+        let first_endpoint = self.next_endpoint.get();
+        if first_endpoint + function.endpoint_count() > N_ENDPOINTS {
+            return ReturnCode::ENOMEM;
+        }
+        match self.functions.iter().find(|slot| slot.function.is_none()) {
+            Some(slot) => {
+                let first_interface = self.next_interface.get();
+                slot.function.set(function);
+                slot.first_interface.set(first_interface);
+                slot.first_endpoint.set(first_endpoint);
+                self.next_interface
+                    .set(first_interface + function.interface_count());
+                self.next_endpoint
+                    .set(first_endpoint + function.endpoint_count());
+                function.assign(first_interface, first_endpoint);
+                let first_string = self.next_string.get();
+                slot.first_string.set(first_string);
+                self.next_string
+                    .set(first_string + function.strings().len() as u8);
+                function.assign_strings(first_string);
+                if self.write_configuration().is_none() {
+                    // The configuration descriptor would not fit in the
+                    // descriptor buffer with this function.
+                    slot.function.clear();
+                    self.next_interface.set(first_interface);
+                    self.next_endpoint.set(first_endpoint);
+                    self.next_string.set(first_string);
+                    return ReturnCode::ENOMEM;
+                }
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// The index of the function with interface `interface`.
+    fn interface_owner(&self, interface: u8) -> Option<usize> {
+        self.functions.iter().position(|slot| {
+            slot.function.map_or(false, |function| {
+                let first = slot.first_interface.get();
+                interface >= first && interface < first + function.interface_count()
+            })
+        })
+    }
+
+    /// The index of the function with endpoint number `endpoint`.
+    fn endpoint_owner(&self, endpoint: usize) -> Option<usize> {
+        self.functions.iter().position(|slot| {
+            slot.function.map_or(false, |function| {
+                let first = slot.first_endpoint.get();
+                endpoint >= first && endpoint < first + function.endpoint_count()
+            })
+        })
+    }
+
+    /// The string with index `index` of one of the functions.
+    fn function_string(&self, index: u8) -> Option<&'static str> {
+        self.functions.iter().find_map(|slot| {
+            slot.function.and_then(|function| {
+                let strings = function.strings();
+                let first = slot.first_string.get();
+                if index >= first && ((index - first) as usize) < strings.len() {
+                    Some(strings[(index - first) as usize])
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    fn function(&self, index: usize) -> Option<&'a dyn Function<'a>> {
+        self.functions
+            .get(index)
+            .and_then(|slot| slot.function.map(|function| *function))
+    }
+
+    /// Write the configuration descriptor and the descriptors of all
+    /// functions to `descriptors`. Returns their length, or None if they do
+    /// not fit.
+    fn write_configuration(&self) -> Option<usize> {
+        let buf = self.descriptors;
+        let mut config = descriptors::ConfigurationDescriptor {
+            num_interfaces: self.next_interface.get(),
+            ..descriptors::ConfigurationDescriptor::default()
+        };
+        if config.size() > buf.len() {
+            return None;
+        }
+        let mut len = config.size();
+        for slot in self.functions.iter() {
+            let function = match slot.function.map(|function| *function) {
+                Some(function) => function,
+                None => continue,
+            };
+            if function.interface_count() > 1 {
+                let (class, subclass, protocol) = function.function_class();
+                let written = InterfaceAssociationDescriptor {
+                    first_interface: slot.first_interface.get(),
+                    interface_count: function.interface_count(),
+                    function_class: class,
+                    function_subclass: subclass,
+                    function_protocol: protocol,
+                    string_index: 0,
+                }
+                .write_to(&buf[len..]);
+                if written == 0 {
+                    return None;
+                }
+                len += written;
+            }
+            match function.write_descriptors(&buf[len..]) {
+                0 => return None,
+                written => len += written,
+            }
+        }
+        config.related_descriptor_length = len - config.size();
+        config.write_to(buf);
+        Some(len)
+    }
+
+    /// Send the first `len` bytes of `descriptors`, but no more than the
+    /// host asked for.
+    fn send(&self, len: usize, requested_length: u16) -> hil::usb::CtrlSetupResult {
+        self.state
+            .set(State::CtrlIn(0, min(len, requested_length as usize)));
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    fn handle_standard_device_request(
+        &self,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => match descriptor_type {
+                DescriptorType::Device => {
+                    let len = descriptors::DeviceDescriptor {
+                        vendor_id: self.vendor_id,
+                        product_id: self.product_id,
+                        manufacturer_string: 1,
+                        product_string: 2,
+                        serial_number_string: 3,
+                        // Functions are described by interface association
+                        // descriptors.
+                        class: 0xef,
+                        subclass: 0x02,
+                        protocol: 0x01,
+                        max_packet_size_ep0: self.max_ctrl_packet_size,
+                        ..descriptors::DeviceDescriptor::default()
+                    }
+                    .write_to(self.descriptors);
+                    self.send(len, requested_length)
+                }
+                DescriptorType::Configuration => match descriptor_index {
+                    // A descriptor that does not fit in the buffer would
+                    // reach the host truncated, so stall instead.
+                    0 => match self.write_configuration() {
+                        Some(len) => self.send(len, requested_length),
+                        None => hil::usb::CtrlSetupResult::ErrGeneric,
+                    },
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex,
+                },
+                DescriptorType::String => {
+                    let len = match descriptor_index {
+                        0 => LanguagesDescriptor { langs: LANGUAGES }.write_to(self.descriptors),
+                        i if i as usize <= self.strings.len() && lang_id == LANGUAGES[0] => {
+                            StringDescriptor {
+                                string: self.strings[i as usize - 1],
+                            }
+                            .write_to(self.descriptors)
+                        }
+                        i if lang_id == LANGUAGES[0] => {
+                            self.function_string(i).map_or(0, |string| {
+                                StringDescriptor { string }.write_to(self.descriptors)
+                            })
+                        }
+                        _ => 0,
+                    };
+                    if len > 0 {
+                        self.send(len, requested_length)
+                    } else {
+                        hil::usb::CtrlSetupResult::ErrInvalidStringIndex
+                    }
+                }
+                DescriptorType::DeviceQualifier => {
+                    // We are full-speed only, so we must respond with a
+                    // request error
+                    hil::usb::CtrlSetupResult::ErrNoDeviceQualifier
+                }
+                _ => hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            StandardRequest::SetAddress { device_address } => {
+                // The address is enabled in the status stage.
+                self.controller.set_address(device_address);
+                self.state.set(State::SetAddress);
+                hil::usb::CtrlSetupResult::OkSetAddress
+            }
+            StandardRequest::SetConfiguration {
+                configuration_value,
+            } => {
+                self.configuration.set(configuration_value);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::GetConfiguration => {
+                self.descriptors[0].set(self.configuration.get());
+                self.send(1, 1)
+            }
+            StandardRequest::GetStatus { .. } => {
+                // Not self-powered and no remote wakeup.
+                self.descriptors[0].set(0);
+                self.descriptors[1].set(0);
+                self.send(2, 2)
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    fn handle_standard_interface_request(
+        &self,
+        request: StandardRequest,
+        interface: u8,
+    ) -> hil::usb::CtrlSetupResult {
+        let function = match self
+            .interface_owner(interface)
+            .and_then(|i| self.function(i))
+        {
+            Some(function) => function,
+            None => return hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+        };
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                requested_length,
+                ..
+            } => match function.write_class_descriptor(descriptor_type, self.descriptors) {
+                0 => hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+                len => self.send(len, requested_length),
+            },
+            StandardRequest::GetInterface { .. } => {
+                self.descriptors[0].set(function.alternate_setting(interface));
+                self.send(1, 1)
+            }
+            StandardRequest::SetInterface {
+                alternate_setting, ..
+            } => {
+                if function.set_alternate_setting(interface, alternate_setting as u8) {
+                    hil::usb::CtrlSetupResult::Ok
+                } else {
+                    hil::usb::CtrlSetupResult::ErrGeneric
+                }
+            }
+            StandardRequest::GetStatus { .. } => {
+                self.descriptors[0].set(0);
+                self.descriptors[1].set(0);
+                self.send(2, 2)
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    fn handle_standard_endpoint_request(
+        &self,
+        request: StandardRequest,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetStatus { .. } => {
+                // Endpoints are never halted.
+                self.descriptors[0].set(0);
+                self.descriptors[1].set(0);
+                self.send(2, 2)
+            }
+            StandardRequest::ClearFeature { .. } | StandardRequest::SetFeature { .. } => {
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    /// Pass a class or vendor request to the function it is addressed to.
+    fn handle_function_request(&self, setup_data: SetupData) -> hil::usb::CtrlSetupResult {
+        let owner = match setup_data.request_type.recipient() {
+            Recipient::Interface => self.interface_owner(setup_data.index as u8),
+            Recipient::Endpoint => self.endpoint_owner((setup_data.index & 0x0f) as usize),
+            _ => None,
+        };
+        match owner.and_then(|i| self.function(i).map(|function| (i, function))) {
+            Some((i, function)) => {
+                let result = function.ctrl_setup(setup_data);
+                if let hil::usb::CtrlSetupResult::Ok = result {
+                    self.state.set(State::Function(i));
+                }
+                result
+            }
+            None => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.controller
+            .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
+        self.controller
+            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller
+            .endpoint_out_enable(TransferType::Control, 0);
+
+        for slot in self.functions.iter() {
+            slot.function.map(|function| function.enable());
+        }
+    }
+
+    fn attach(&'a self) {
+        self.controller.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.state.set(State::Init);
+        self.configuration.set(0);
+        for slot in self.functions.iter() {
+            slot.function.map(|function| function.bus_reset());
+        }
+    }
+
+    /// Handle a Control Setup transaction
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        if endpoint != 0 {
+            // For now we only support the default Control endpoint
+            return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
+        }
+        self.state.set(State::Init);
+        SetupData::get(&self.ctrl_buffer.buf).map_or(
+            hil::usb::CtrlSetupResult::ErrNoParse,
+            |setup_data| match setup_data.request_type.request_type() {
+                RequestType::Standard => match setup_data.get_standard_request() {
+                    Some(request) => match setup_data.request_type.recipient() {
+                        Recipient::Device => self.handle_standard_device_request(request),
+                        Recipient::Interface => {
+                            self.handle_standard_interface_request(request, setup_data.index as u8)
+                        }
+                        Recipient::Endpoint => self.handle_standard_endpoint_request(request),
+                        _ => hil::usb::CtrlSetupResult::ErrGeneric,
+                    },
+                    None => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+                },
+                RequestType::Class | RequestType::Vendor => {
+                    self.handle_function_request(setup_data)
+                }
+                RequestType::Reserved => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+        )
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, _endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.state.get() {
+            State::CtrlIn(start, end) => {
+                let packet_bytes = min(self.ctrl_buffer.buf.len(), end.saturating_sub(start));
+                let packet = &self.descriptors[start..start + packet_bytes];
+                for (cell, byte) in self.ctrl_buffer.buf.iter().zip(packet.iter()) {
+                    cell.set(byte.get());
+                }
+                self.state.set(State::CtrlIn(start + packet_bytes, end));
+                hil::usb::CtrlInResult::Packet(packet_bytes, start + packet_bytes == end)
+            }
+            State::Function(i) => self.function(i).map_or(hil::usb::CtrlInResult::Error, |f| {
+                f.ctrl_in(&self.ctrl_buffer.buf)
+            }),
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, _endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.state.get() {
+            State::Function(i) => self
+                .function(i)
+                .map_or(hil::usb::CtrlOutResult::Halted, |f| {
+                    f.ctrl_out(&self.ctrl_buffer.buf, packet_bytes)
+                }),
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status(&'a self, _endpoint: usize) {
+        // Entered Status stage
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::SetAddress => self.controller.enable_address(),
+            State::Function(i) => {
+                self.function(i).map(|f| f.ctrl_status_complete());
+            }
+            _ => {}
+        }
+        self.state.set(State::Init);
+    }
+
+    /// Handle a Bulk/Interrupt IN transaction
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.endpoint_owner(endpoint)
+            .and_then(|i| self.function(i))
+            .map_or(hil::usb::InResult::Error, |f| {
+                f.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.endpoint_owner(endpoint)
+            .and_then(|i| self.function(i))
+            .map_or(hil::usb::OutResult::Error, |f| {
+                f.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.endpoint_owner(endpoint)
+            .and_then(|i| self.function(i))
+            .map(|f| f.packet_transmitted(endpoint));
+    }
+}
+
+impl<'a> Default for Slot<'a> {
+    fn default() -> Self {
+        Slot {
+            function: OptionalCell::empty(),
+            first_interface: Cell::new(0),
+            first_endpoint: Cell::new(0),
+            first_string: Cell::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::boxed::Box;
+
+    use super::super::cdc::CdcAcm;
+    use super::super::ctap::CtapHid;
+    use super::super::hid::{BootInterface, Hid, KEYBOARD_HID_DESCRIPTOR, KEYBOARD_REPORT};
+    use super::super::sim::{setup, SimAlarm, SimUsbController};
+    use super::CompositeDevice;
+    use kernel::common::dynamic_deferred_call::{
+        DynamicDeferredCall, DynamicDeferredCallClientState,
+    };
+    use kernel::hil::uart::Transmit;
+    use kernel::hil::usb::{Client, UsbController};
+    use kernel::ReturnCode;
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "Composite", "1"];
+
+    #[test]
+    fn cdc_keyboard_and_ctap() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let states = Box::leak(Box::new(<[DynamicDeferredCallClientState; 1]>::default()));
+        let deferred_caller = DynamicDeferredCall::new(states);
+        let cdc = CdcAcm::new(&usb, 64, 0, 0, STRINGS, &alarm, &deferred_caller, None);
+        let keyboard = Hid::new(
+            &usb,
+            64,
+            0,
+            0,
+            STRINGS,
+            BootInterface::Keyboard,
+            &KEYBOARD_HID_DESCRIPTOR,
+            &KEYBOARD_REPORT,
+        );
+        let ctap = CtapHid::new(&usb, 0, 0, STRINGS);
+        let composite = CompositeDevice::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            Box::leak(Box::new([0; 256])),
+        );
+        assert_eq!(composite.add_function(&cdc), ReturnCode::SUCCESS);
+        assert_eq!(composite.add_function(&keyboard), ReturnCode::SUCCESS);
+        assert_eq!(composite.add_function(&ctap), ReturnCode::SUCCESS);
+        usb.set_client(&composite);
+        composite.enable();
+        composite.attach();
+
+        let device = usb.enumerate();
+        assert_eq!(&device.device[4..7], &[0xef, 0x02, 0x01]);
+        // Two CDC interfaces, the keyboard and the key
+        assert_eq!(device.configuration[4], 4);
+        assert_eq!(device.strings[1].as_deref(), Some("Composite"));
+
+        // Class requests reach the CDC function through the composite
+        // device, and its data goes out on its assigned endpoint.
+        let buffer = Box::leak(Box::new(*b"hi"));
+        assert_eq!(cdc.transmit_buffer(buffer, 2).0, ReturnCode::SUCCESS);
+        let baud = 115200u32.to_le_bytes();
+        assert_eq!(
+            usb.control_out(
+                setup(0x21, 0x20, 0, 0, 7),
+                &[baud[0], baud[1], baud[2], baud[3], 0, 0, 8]
+            ),
+            Ok(())
+        );
+        assert_eq!(usb.control_out(setup(0x21, 0x22, 1, 0, 0), &[]), Ok(()));
+        assert_eq!(usb.drain_in(2), b"hi".to_vec());
+    }
+
+    #[test]
+    fn named_serial_ports() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let states = Box::leak(Box::new(<[DynamicDeferredCallClientState; 2]>::default()));
+        let deferred_caller = DynamicDeferredCall::new(states);
+        let console = CdcAcm::new(&usb, 64, 0, 0, STRINGS, &alarm, &deferred_caller, None);
+        let app = CdcAcm::new(&usb, 64, 0, 0, STRINGS, &alarm, &deferred_caller, None);
+        console.set_interface_name(&"Console");
+        app.set_interface_name(&"App");
+        let composite = CompositeDevice::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            Box::leak(Box::new([0; 256])),
+        );
+        assert_eq!(composite.add_function(&console), ReturnCode::SUCCESS);
+        assert_eq!(composite.add_function(&app), ReturnCode::SUCCESS);
+        usb.set_client(&composite);
+        composite.enable();
+        composite.attach();
+
+        let device = usb.enumerate();
+        assert_eq!(device.configuration[4], 4);
+        // The communication interface of each port names it: the first
+        // one follows its interface association descriptor.
+        assert_eq!(device.configuration[9 + 8 + 8], 4);
+        assert_eq!(
+            usb.get_descriptor(3, 5, 255).unwrap(),
+            [8, 3, b'A', 0, b'p', 0, b'p', 0]
+        );
+
+        // Each port has its own control lines and endpoints.
+        assert_eq!(usb.control_out(setup(0x21, 0x22, 1, 2, 0), &[]), Ok(()));
+        assert!(!console.is_open());
+        assert!(app.is_open());
+        let buffer = Box::leak(Box::new(*b"app"));
+        assert_eq!(app.transmit_buffer(buffer, 3).0, ReturnCode::SUCCESS);
+        assert_eq!(usb.drain_in(4), b"app".to_vec());
+    }
+
+    #[test]
+    fn descriptors_must_fit() {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let states = Box::leak(Box::new(<[DynamicDeferredCallClientState; 1]>::default()));
+        let deferred_caller = DynamicDeferredCall::new(states);
+        let cdc = CdcAcm::new(&usb, 64, 0, 0, STRINGS, &alarm, &deferred_caller, None);
+        let ctap = CtapHid::new(&usb, 0, 0, STRINGS);
+        // Room for the CDC function, but not for the key as well.
+        let composite = CompositeDevice::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            Box::leak(Box::new([0; 96])),
+        );
+        assert_eq!(composite.add_function(&cdc), ReturnCode::SUCCESS);
+        assert_eq!(composite.add_function(&ctap), ReturnCode::ENOMEM);
+        usb.set_client(&composite);
+        composite.enable();
+        composite.attach();
+
+        // The device describes the CDC function alone, and completely.
+        let device = usb.enumerate();
+        assert_eq!(device.configuration[4], 2);
+        let total_length = u16::from_le_bytes([device.configuration[2], device.configuration[3]]);
+        assert_eq!(total_length as usize, device.configuration.len());
+    }
+}
@@ -5,8 +5,10 @@
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::Function;
 use super::descriptors;
 use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
 use super::descriptors::DescriptorType;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
@@ -15,16 +17,19 @@ use super::descriptors::HIDDescriptor;
 use super::descriptors::HIDSubordinateDescriptor;
 use super::descriptors::InterfaceDescriptor;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
 use kernel::hil;
 use kernel::hil::usb::TransferType;
 use kernel::ReturnCode;
 
-/// Use 1 Interrupt transfer IN/OUT endpoint
+/// Use 1 Interrupt transfer IN/OUT endpoint, unless a composite device
+/// assigns another one
 const ENDPOINT_NUM: usize = 1;
 
 const OUT_BUFFER: usize = 0;
@@ -99,6 +104,13 @@ pub struct CtapHid<'a, U: 'a> {
     recv_offset: Cell<usize>,
 
     saved_endpoint: OptionalCell<usize>,
+    /// We answered the last OUT packet with `Delay`, so the endpoint must be
+    /// resumed when the client wants to receive again.
+    out_delayed: Cell<bool>,
+
+    /// Interface and endpoint numbers, which a composite device can change.
+    interface: Cell<u8>,
+    endpoint: Cell<usize>,
 }
 
 impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
@@ -175,6 +187,9 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
             recv_len: Cell::new(0),
             recv_offset: Cell::new(0),
             saved_endpoint: OptionalCell::empty(),
+            out_delayed: Cell::new(false),
+            interface: Cell::new(0),
+            endpoint: Cell::new(ENDPOINT_NUM),
         }
     }
 
@@ -187,6 +202,16 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         self.client.set(client);
     }
 
+    fn enable_endpoints(&'a self) {
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint.get(), &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint.get(), &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, self.endpoint.get());
+    }
+
     fn can_receive(&'a self) -> bool {
         self.client
             .map(move |client| client.can_receive())
@@ -202,7 +227,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
         let len = send.len();
 
         self.send_buffer.replace(send);
-        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        self.controller().endpoint_resume_in(self.endpoint.get());
 
         Ok(len)
     }
@@ -235,9 +260,9 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
                 // Reset the offset
                 self.recv_offset.set(0);
             }
-        } else {
+        } else if self.out_delayed.take() {
             // If we have nothing to process, accept more data
-            self.controller().endpoint_resume_out(ENDPOINT_NUM);
+            self.controller().endpoint_resume_out(self.endpoint.get());
         }
 
         Ok(())
@@ -257,13 +282,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         // Set up the default control endpoint
         self.client_ctrl.enable();
 
-        // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
-        self.controller()
-            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -294,7 +313,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
         if self.send_buffer.is_some() {
-            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+            self.controller().endpoint_resume_in(self.endpoint.get());
         }
 
         self.client_ctrl.ctrl_status_complete(endpoint)
@@ -372,19 +391,26 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
                         // client asked for.
                         if total_received_bytes >= self.recv_len.get() {
                             if self.can_receive() {
+                                // Reset the offset
+                                self.recv_offset.set(0);
                                 self.client.map(move |client| {
                                     client.packet_received(ReturnCode::SUCCESS, buf, endpoint);
                                 });
-                                // Reset the offset
-                                self.recv_offset.set(0);
-                                // Delay the next packet until we have finished
-                                // processing this packet
-                                hil::usb::OutResult::Delay
+                                if self.recv_buffer.is_some() {
+                                    // The client is ready for the next packet
+                                    hil::usb::OutResult::Ok
+                                } else {
+                                    // Delay the next packet until we have
+                                    // finished processing this packet
+                                    self.out_delayed.set(true);
+                                    hil::usb::OutResult::Delay
+                                }
                             } else {
                                 // We can't receive data. Record that we have data to send later
                                 // and apply back pressure to USB
                                 self.saved_endpoint.set(endpoint);
                                 self.recv_buffer.replace(buf);
+                                self.out_delayed.set(true);
                                 hil::usb::OutResult::Delay
                             }
                         } else {
@@ -408,3 +434,351 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         });
     }
 }
+
+impl<'a, U: hil::usb::UsbController<'a>> Function<'a> for CtapHid<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn endpoint_count(&self) -> usize {
+        1
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        (0x03, 0x00, 0x00)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.endpoint.set(first_endpoint);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: self.interface.get(),
+                    num_endpoints: 2,
+                    interface_class: 0x03,    // HID
+                    interface_subclass: 0x00, // No subcall
+                    interface_protocol: 0x00, // No protocol
+                    ..InterfaceDescriptor::default()
+                },
+                &HID_DESCRIPTOR,
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 64,
+                    interval: 5,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 64,
+                    interval: 5,
+                },
+            ],
+        )
+    }
+
+    fn write_class_descriptor(&self, descriptor_type: DescriptorType, buf: &[Cell<u8>]) -> usize {
+        match descriptor_type {
+            DescriptorType::HID => HID_DESCRIPTOR.write_to(buf),
+            DescriptorType::Report => REPORT.write_to(buf),
+            _ => 0,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        // Accept requests without data for the device, such as SET_IDLE.
+        match setup.request_type.transfer_direction() {
+            TransferDirection::HostToDevice => hil::usb::CtrlSetupResult::Ok,
+            TransferDirection::DeviceToHost => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        _buf: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(self.endpoint.get());
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::super::sim::{SimAlarm, SimUsbController, TransferError};
+    use super::CtapHid;
+    use crate::ctaphid::{
+        Command, Ctap, CtapHidClient, CtapHidFraming, PacketTransport, UsbHidTransport,
+    };
+    use kernel::hil::time::Alarm;
+    use kernel::hil::usb::{Client, UsbController};
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "FIDO Key", "1"];
+
+    const BROADCAST: u32 = 0xffff_ffff;
+    const ENDPOINT: usize = 1;
+
+    type Framing<'a> =
+        CtapHidFraming<'a, UsbHidTransport<'a, CtapHid<'a, SimUsbController<'a>>>, SimAlarm<'a>>;
+
+    struct TestClient {
+        request: Cell<Option<(Command, usize)>>,
+        cancelled: Cell<bool>,
+        sent: Cell<bool>,
+    }
+
+    impl CtapHidClient for TestClient {
+        fn request_received(&self, command: Command, request: &[u8]) {
+            self.request.set(Some((command, request.len())));
+        }
+
+        fn request_cancelled(&self) {
+            self.cancelled.set(true);
+        }
+
+        fn response_sent(&self) {
+            self.sent.set(true);
+        }
+    }
+
+    /// Build an authenticator on a simulated controller and enumerate it.
+    fn with_authenticator(test: impl FnOnce(&SimUsbController, &Framing, &SimAlarm, &TestClient)) {
+        let usb = SimUsbController::new();
+        let alarm = SimAlarm::new();
+        let ctap = CtapHid::new(&usb, 0x6667, 0xabcd, STRINGS);
+        let transport = UsbHidTransport::new(&ctap);
+        let framing = CtapHidFraming::new(
+            &transport,
+            &alarm,
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 64])),
+            Box::leak(Box::new([0; 1024])),
+        );
+        let client = TestClient {
+            request: Cell::new(None),
+            cancelled: Cell::new(false),
+            sent: Cell::new(false),
+        };
+        usb.set_client(&ctap);
+        ctap.set_client(&transport);
+        transport.set_client(&framing);
+        alarm.set_alarm_client(&framing);
+        framing.set_client(&client);
+        framing.start();
+        ctap.enable();
+        ctap.attach();
+        usb.enumerate();
+
+        test(&usb, &framing, &alarm, &client);
+    }
+
+    fn init_packet(channel: u32, command: u8, len: usize, data: &[u8]) -> [u8; 64] {
+        let mut packet = [0; 64];
+        packet[0..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = command | 0x80;
+        packet[5] = (len >> 8) as u8;
+        packet[6] = len as u8;
+        packet[7..7 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn continuation_packet(channel: u32, seq: u8, data: &[u8]) -> [u8; 64] {
+        let mut packet = [0; 64];
+        packet[0..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = seq;
+        packet[5..5 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn receive(usb: &SimUsbController) -> Vec<u8> {
+        let packet = usb.transfer_in(ENDPOINT).expect("no packet from device");
+        assert_eq!(packet.len(), 64);
+        packet
+    }
+
+    fn assert_error(usb: &SimUsbController, channel: u32, error: u8) {
+        let packet = receive(usb);
+        assert_eq!(&packet[0..4], &channel.to_be_bytes());
+        assert_eq!(&packet[4..8], &[0xbf, 0, 1, error]);
+    }
+
+    /// Allocate a channel with INIT.
+    fn allocate_channel(usb: &SimUsbController) -> u32 {
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+        usb.transfer_out(ENDPOINT, &init_packet(BROADCAST, 0x06, 8, &nonce))
+            .unwrap();
+        let packet = receive(usb);
+        assert_eq!(&packet[0..4], &BROADCAST.to_be_bytes());
+        assert_eq!(&packet[4..7], &[0x86, 0, 17]);
+        assert_eq!(&packet[7..15], &nonce);
+        // Protocol version 2, WINK and CBOR capabilities
+        assert_eq!(packet[19], 2);
+        assert_eq!(packet[23], 0x05);
+        u32::from_be_bytes([packet[15], packet[16], packet[17], packet[18]])
+    }
+
+    #[test]
+    fn descriptors() {
+        with_authenticator(|usb, _, _, _| {
+            // The HID report descriptor was read during enumeration; the
+            // interface is a HID interface with an interrupt IN and OUT
+            // endpoint.
+            let configuration = usb.get_descriptor(2, 0, 255).unwrap();
+            assert_eq!(configuration[9 + 5], 0x03);
+            assert_eq!(configuration[9 + 4], 2);
+        });
+    }
+
+    #[test]
+    fn init_allocates_channels() {
+        with_authenticator(|usb, _, _, _| {
+            let first = allocate_channel(usb);
+            let second = allocate_channel(usb);
+            assert!(first != 0 && first != BROADCAST);
+            assert!(second != first && second != BROADCAST);
+        });
+    }
+
+    #[test]
+    fn ping_is_reassembled_and_echoed() {
+        with_authenticator(|usb, _, _, _| {
+            let channel = allocate_channel(usb);
+            let data: Vec<u8> = (0..100).collect();
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x01, 100, &data[..57]))
+                .unwrap();
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+            usb.transfer_out(ENDPOINT, &continuation_packet(channel, 0, &data[57..]))
+                .unwrap();
+
+            let first = receive(usb);
+            assert_eq!(&first[0..4], &channel.to_be_bytes());
+            assert_eq!(&first[4..7], &[0x81, 0, 100]);
+            let second = receive(usb);
+            assert_eq!(&second[0..5], &[first[0], first[1], first[2], first[3], 0]);
+            let mut echo = first[7..].to_vec();
+            echo.extend(&second[5..5 + 43]);
+            assert_eq!(echo, data);
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+        });
+    }
+
+    #[test]
+    fn cbor_request_with_keepalives() {
+        with_authenticator(|usb, framing, alarm, client| {
+            let channel = allocate_channel(usb);
+            usb.transfer_out(
+                ENDPOINT,
+                &init_packet(channel, 0x10, 3, &[0x04, 0xa0, 0x00]),
+            )
+            .unwrap();
+            assert_eq!(client.request.get(), Some((Command::Cbor, 3)));
+
+            alarm.advance(100);
+            assert_eq!(&receive(usb)[4..8], &[0xbb, 0, 1, 1]);
+            framing.set_user_presence_needed(true);
+            alarm.advance(100);
+            assert_eq!(&receive(usb)[4..8], &[0xbb, 0, 1, 2]);
+
+            assert_eq!(framing.respond(&[0x00, 0xa1]), kernel::ReturnCode::SUCCESS);
+            assert_eq!(&receive(usb)[4..9], &[0x90, 0, 2, 0x00, 0xa1]);
+            assert!(client.sent.get());
+
+            // No more keepalives once the response is out.
+            alarm.advance(1000);
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+        });
+    }
+
+    #[test]
+    fn cancel_reaches_client() {
+        with_authenticator(|usb, _, _, client| {
+            let channel = allocate_channel(usb);
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x10, 1, &[0x01]))
+                .unwrap();
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x11, 0, &[]))
+                .unwrap();
+            assert!(client.cancelled.get());
+        });
+    }
+
+    #[test]
+    fn errors() {
+        with_authenticator(|usb, _, alarm, _| {
+            let channel = allocate_channel(usb);
+
+            // Channels must be allocated with INIT first.
+            usb.transfer_out(ENDPOINT, &init_packet(channel + 1, 0x01, 0, &[]))
+                .unwrap();
+            assert_error(usb, channel + 1, 0x0b);
+
+            // Unknown commands
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x30, 0, &[]))
+                .unwrap();
+            assert_error(usb, channel, 0x01);
+
+            // Another channel while a request is being received
+            let other = allocate_channel(usb);
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x01, 100, &[0; 57]))
+                .unwrap();
+            usb.transfer_out(ENDPOINT, &init_packet(other, 0x01, 0, &[]))
+                .unwrap();
+            assert_error(usb, other, 0x06);
+
+            // Continuation packets out of sequence
+            usb.transfer_out(ENDPOINT, &continuation_packet(channel, 1, &[0; 43]))
+                .unwrap();
+            assert_error(usb, channel, 0x04);
+
+            // Continuation packets that never arrive
+            usb.transfer_out(ENDPOINT, &init_packet(channel, 0x01, 100, &[0; 57]))
+                .unwrap();
+            alarm.advance(499);
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+            alarm.advance(1);
+            assert_error(usb, channel, 0x05);
+        });
+    }
+}
@@ -97,7 +97,10 @@ impl SetupData {
                 10 => Some(StandardRequest::GetInterface {
                     interface: self.index,
                 }),
-                11 => Some(StandardRequest::SetInterface),
+                11 => Some(StandardRequest::SetInterface {
+                    alternate_setting: self.value,
+                    interface: self.index,
+                }),
                 12 => Some(StandardRequest::SynchFrame),
                 _ => None,
             },
@@ -142,7 +145,10 @@ pub enum StandardRequest {
     GetInterface {
         interface: u16,
     },
-    SetInterface,
+    SetInterface {
+        alternate_setting: u16,
+        interface: u16,
+    },
     SynchFrame,
 }
 
@@ -156,6 +162,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 0x0b,
     HID = 0x21,
     Report = 0x22,
     CdcInterface = 0x24,
@@ -171,6 +178,7 @@ fn get_descriptor_type(byte: u8) -> Option<DescriptorType> {
         6 => Some(DescriptorType::DeviceQualifier),
         7 => Some(DescriptorType::OtherSpeedConfiguration),
         8 => Some(DescriptorType::InterfacePower),
+        0x0b => Some(DescriptorType::InterfaceAssociation),
         0x21 => Some(DescriptorType::HID),
         0x22 => Some(DescriptorType::Report),
         0x24 => Some(DescriptorType::CdcInterface),
@@ -552,6 +560,17 @@ pub fn create_descriptor_buffers(
     (dev_buf, other_buf)
 }
 
+/// Write `descriptors` one after the other to `buf`. Returns their total
+/// length, or 0 if they do not all fit.
+pub fn write_descriptors(buf: &[Cell<u8>], descriptors: &[&dyn Descriptor]) -> usize {
+    if descriptors.iter().map(|d| d.size()).sum::<usize>() > buf.len() {
+        return 0;
+    }
+    descriptors
+        .iter()
+        .fold(0, |len, d| len + d.write_to_unchecked(&buf[len..]))
+}
+
 pub struct ConfigurationDescriptor {
     pub num_interfaces: u8,
     pub configuration_value: u8,
@@ -654,6 +673,34 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+/// Groups the interfaces of a function of a composite device.
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
 pub struct EndpointAddress(u8);
 
 impl EndpointAddress {
@@ -859,8 +906,44 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
-/// The data structure sent in a CDC-ACM Set Line Coding message.
-#[derive(Debug, Copy, Clone)]
+/// The Ethernet networking functional descriptor of a CDC-ECM
+/// communication interface.
+pub struct CdcEthernetNetworkingDescriptor {
+    /// Index of the string holding the MAC address of the host's end of
+    /// the link, as 12 hexadecimal digits.
+    pub mac_address_string: u8,
+    /// Bitmap of the Ethernet statistics the device collects.
+    pub statistics: u32,
+    /// Largest Ethernet frame, without the frame check sequence.
+    pub max_segment_size: u16,
+    /// Number of multicast filters. Bit 15 is set if the filters are
+    /// imperfect.
+    pub multicast_filters: u16,
+    pub power_filters: u8,
+}
+
+impl Descriptor for CdcEthernetNetworkingDescriptor {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(13); // Size of descriptor
+        buf[1].set(DescriptorType::CdcInterface as u8);
+        buf[2].set(CdcInterfaceDescriptorSubType::EthernetNetworking as u8);
+        buf[3].set(self.mac_address_string);
+        put_u16(&buf[4..6], self.statistics as u16);
+        put_u16(&buf[6..8], (self.statistics >> 16) as u16);
+        put_u16(&buf[8..10], self.max_segment_size);
+        put_u16(&buf[10..12], self.multicast_filters);
+        buf[12].set(self.power_filters);
+        13
+    }
+}
+
+/// The data structure sent in CDC-ACM Set Line Coding and Get Line Coding
+/// messages.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct CdcAcmSetLineCodingData {
     pub baud_rate: u32,
     pub stop_bits: u8,
@@ -882,6 +965,73 @@ impl CdcAcmSetLineCodingData {
             data_bits: p[6].get(),
         })
     }
+
+    /// Write the structure to the data stage of a Get Line Coding message.
+    /// Returns its length, or 0 if it does not fit.
+    pub fn put(&self, p: &[VolatileCell<u8>]) -> usize {
+        if p.len() < 7 {
+            return 0;
+        }
+        for (cell, byte) in p.iter().zip(self.baud_rate.to_le_bytes().iter()) {
+            cell.set(*byte);
+        }
+        p[4].set(self.stop_bits);
+        p[5].set(self.parity);
+        p[6].set(self.data_bits);
+        7
+    }
+}
+
+impl Default for CdcAcmSetLineCodingData {
+    /// 115200 baud, 8 data bits, no parity, 1 stop bit.
+    fn default() -> Self {
+        CdcAcmSetLineCodingData {
+            baud_rate: 115200,
+            stop_bits: 0,
+            parity: 0,
+            data_bits: 8,
+        }
+    }
+}
+
+//
+// For DFU
+//
+
+/// The DFU functional descriptor, which follows the DFU interface
+/// descriptors. It shares its descriptor type (0x21) with the HID
+/// descriptor.
+pub struct DfuFunctionalDescriptor {
+    pub can_download: bool,
+    pub can_upload: bool,
+    pub manifestation_tolerant: bool,
+    pub will_detach: bool,
+    /// Time in milliseconds the device waits for a USB reset after a
+    /// DFU_DETACH request.
+    pub detach_timeout: u16,
+    /// Largest block of a DFU_DNLOAD or DFU_UPLOAD request.
+    pub transfer_size: u16,
+}
+
+impl Descriptor for DfuFunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(0x21); // DFU functional
+        buf[2].set(
+            (self.can_download as u8)
+                | (self.can_upload as u8) << 1
+                | (self.manifestation_tolerant as u8) << 2
+                | (self.will_detach as u8) << 3,
+        );
+        put_u16(&buf[3..5], self.detach_timeout);
+        put_u16(&buf[5..7], self.transfer_size);
+        put_u16(&buf[7..9], 0x0110); // DFU 1.1
+        9
+    }
 }
 
 pub struct LanguagesDescriptor<'a> {
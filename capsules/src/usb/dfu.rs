@@ -0,0 +1,1090 @@
+//! USB Device Firmware Upgrade (DFU) class
+//!
+//! Lets a host such as `dfu-util` write kernel images or Tock application
+//! binaries (TBF images) to flash over USB, without an external bootloader.
+//!
+//! The DFU function is part of a
+//! [composite device](../composite/struct.CompositeDevice.html), alone or next
+//! to other functions. It starts in runtime mode, where its interface only
+//! accepts DFU_DETACH. On DFU_DETACH the device disconnects itself and
+//! reconnects in DFU mode, with one alternate setting of the interface for
+//! each download target given by the board. The host selects a target with
+//! SET_INTERFACE and downloads the image to it.
+//!
+//! Images are checked while they are written and once the download is
+//! complete, and the client is only told about an image that passed:
+//!
+//! - a kernel image must start with a Cortex-M vector table, end with the
+//!   CRC-32 of the rest of the image as a little-endian word, and fit in its
+//!   target. The CRC catches truncated and corrupted images. The target
+//!   should be a staging area that a bootloader copies the kernel from;
+//!   writing over the running kernel is not supported. The CRC is the one
+//!   of zlib, so it can be appended with
+//!   `python3 -c "import sys, struct, zlib; d = open(sys.argv[1], 'rb').read(); sys.stdout.buffer.write(d + struct.pack('<I', zlib.crc32(d)))" kernel.bin > kernel.dfu`.
+//! - an application image is one or more TBF binaries, each with a valid
+//!   header, that end exactly at the end of the image. The rest of the target
+//!   is marked empty, so the kernel does not find stale applications after
+//!   the new ones.
+//!
+//! Images are written in place, so the processes running from an application
+//! target are stopped before its first page is overwritten. They stay
+//! stopped; the client decides when to load the new applications, for
+//! example a `DfuReset` that resets the chip once the download ends. If a
+//! download that has started writing fails or is abandoned, the first page of
+//! the target is erased so that neither the kernel nor a bootloader finds a
+//! partial image there, and the client is told that the target is invalid.
+//!
+//! Errors are reported to the host with DFU_GETSTATUS. Uploads are not
+//! supported.
+//!
+//! Based on the USB Device Firmware Upgrade specification revision 1.1.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static TARGETS: &'static [capsules::usb::dfu::Target] = &[
+//!     capsules::usb::dfu::Target {
+//!         kind: capsules::usb::dfu::ImageKind::Kernel,
+//!         start: 0x80000,
+//!         length: 0x40000,
+//!     },
+//!     capsules::usb::dfu::Target {
+//!         kind: capsules::usb::dfu::ImageKind::Apps,
+//!         start: 0x40000,
+//!         length: 0x40000,
+//!     },
+//! ];
+//!
+//! let dfu = static_init!(
+//!     capsules::usb::dfu::Dfu<'static, nrf52::usbd::Usbd<'static>, nrf52::nvmc::Nvmc, VirtualMuxAlarm<'static, Rtc>, Capability>,
+//!     capsules::usb::dfu::Dfu::new(
+//!         &nrf52::usbd::USBD,
+//!         &nrf52::nvmc::NVMC,
+//!         dfu_alarm,
+//!         TARGETS,
+//!         static_init!(nrf52::nvmc::NrfPage, nrf52::nvmc::NrfPage::default()),
+//!         board_kernel,
+//!         Capability,
+//!     )
+//! );
+//! nrf52::nvmc::NVMC.set_client(dfu);
+//! dfu_alarm.set_alarm_client(dfu);
+//! composite.add_function(dfu);
+//!
+//! // Reset the chip after each download to start the new applications.
+//! let dfu_reset = static_init!(
+//!     capsules::usb::dfu::DfuReset<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::usb::dfu::DfuReset::new(reset_alarm, &reset)
+//! );
+//! reset_alarm.set_alarm_client(dfu_reset);
+//! dfu.set_client(dfu_reset);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::Function;
+use super::descriptors;
+use super::descriptors::DfuFunctionalDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::time::{Alarm, AlarmClient};
+use kernel::hil::usb::TransferType;
+
+/// Largest block of a download. It divides the flash page size of the
+/// supported chips, so a block never spans two pages.
+const TRANSFER_SIZE: u16 = 256;
+
+/// Time the host should wait between DFU_GETSTATUS requests while we write
+/// to flash, in milliseconds.
+const POLL_TIMEOUT: u32 = 10;
+
+/// Time between the end of a DFU_DETACH request and disconnecting, and
+/// between disconnecting and connecting in DFU mode, in milliseconds.
+const DETACH_DELAY: u32 = 10;
+const REATTACH_DELAY: u32 = 100;
+
+/// Time between the end of a download and the reset of `DfuReset`, in
+/// milliseconds. It lets the host read the final status first.
+const RESET_DELAY: u32 = 500;
+
+// Class requests.
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// What a download target holds, which decides how images are checked.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageKind {
+    Kernel,
+    Apps,
+}
+
+/// A region of flash that images can be downloaded to. `start` must be at
+/// the start of a flash page.
+pub struct Target {
+    pub kind: ImageKind,
+    pub start: usize,
+    pub length: usize,
+}
+
+pub trait DfuClient {
+    /// A checked image of `length` bytes was written to target `target`. For
+    /// a kernel image, `length` includes the CRC at its end.
+    /// The client decides when to switch to it, for example by resetting
+    /// into a bootloader or restarting the applications.
+    fn image_downloaded(&self, target: usize, length: usize);
+
+    /// A download to target `target` failed after it had started writing,
+    /// and the first page of the target was erased. The target holds no
+    /// image until the next successful download.
+    fn image_invalid(&self, target: usize);
+}
+
+/// A `DfuClient` that resets the chip shortly after a download ends, whether
+/// it succeeded or not. After the reset the kernel loads the new
+/// applications, or starts without the ones a failed download invalidated,
+/// instead of leaving the processes that were stopped for the download
+/// stopped.
+pub struct DfuReset<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    reset: &'a (dyn Fn() + 'a),
+}
+
+impl<'a, A: Alarm<'a>> DfuReset<'a, A> {
+    pub fn new(alarm: &'a A, reset: &'a (dyn Fn() + 'a)) -> DfuReset<'a, A> {
+        DfuReset {
+            alarm: alarm,
+            reset: reset,
+        }
+    }
+
+    fn reset_later(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(RESET_DELAY));
+    }
+}
+
+impl<'a, A: Alarm<'a>> DfuClient for DfuReset<'a, A> {
+    fn image_downloaded(&self, _target: usize, _length: usize) {
+        self.reset_later();
+    }
+
+    fn image_invalid(&self, _target: usize) {
+        self.reset_later();
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for DfuReset<'a, A> {
+    fn alarm(&self) {
+        (self.reset)();
+    }
+}
+
+/// Device states reported to the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    Idle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    Error = 10,
+}
+
+/// Status codes reported to the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPkt = 0x0f,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Runtime,
+    Dfu,
+}
+
+/// Class request in progress on the control endpoint.
+#[derive(Copy, Clone, PartialEq)]
+enum Request {
+    None,
+    Detach,
+    Dnload,
+    GetStatus,
+    GetState,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum AlarmAction {
+    None,
+    Detach,
+    Attach,
+}
+
+/// Checks an image as it is downloaded, one byte at a time.
+#[derive(Copy, Clone)]
+struct Checker {
+    kind: ImageKind,
+    failed: bool,
+    /// Kernel images: the first two words of the vector table, the CRC of
+    /// the image up to the last four bytes seen, and those four bytes.
+    vectors: [u8; 8],
+    crc: u32,
+    tail: [u8; 4],
+    /// Application images: offset of the current TBF header and of the one
+    /// after it, and the parts of the current header seen so far.
+    app_start: usize,
+    next_app: usize,
+    word: u32,
+    header_size: usize,
+    checksum: u32,
+    header_checksum: u32,
+}
+
+impl Checker {
+    fn new(kind: ImageKind) -> Checker {
+        Checker {
+            kind: kind,
+            failed: false,
+            vectors: [0; 8],
+            crc: 0xffffffff,
+            tail: [0; 4],
+            app_start: 0,
+            next_app: 0,
+            word: 0,
+            header_size: 16,
+            checksum: 0,
+            header_checksum: 0,
+        }
+    }
+
+    fn feed(&mut self, offset: usize, byte: u8) {
+        match self.kind {
+            ImageKind::Kernel => {
+                if offset < self.vectors.len() {
+                    self.vectors[offset] = byte;
+                }
+                if offset == self.vectors.len() - 1 {
+                    let sp = u32::from_le_bytes([
+                        self.vectors[0],
+                        self.vectors[1],
+                        self.vectors[2],
+                        self.vectors[3],
+                    ]);
+                    let reset = u32::from_le_bytes([
+                        self.vectors[4],
+                        self.vectors[5],
+                        self.vectors[6],
+                        self.vectors[7],
+                    ]);
+                    // The initial stack pointer is word aligned and the
+                    // reset handler is Thumb code.
+                    if sp == 0 || sp % 4 != 0 || reset & 1 == 0 || reset == 0xffffffff {
+                        self.failed = true;
+                    }
+                }
+                // Until the image ends, any of the last four bytes may be
+                // part of the CRC, so bytes only count once they are older.
+                let slot = offset % self.tail.len();
+                if offset >= self.tail.len() {
+                    self.crc = crc32_update(self.crc, self.tail[slot]);
+                }
+                self.tail[slot] = byte;
+            }
+            ImageKind::Apps => self.feed_tbf(offset, byte),
+        }
+    }
+
+    fn feed_tbf(&mut self, offset: usize, byte: u8) {
+        if offset == self.next_app {
+            self.app_start = offset;
+            self.header_size = 16;
+            self.checksum = 0;
+        }
+        let rel = offset - self.app_start;
+        if rel >= self.header_size {
+            return;
+        }
+
+        self.word = self.word >> 8 | (byte as u32) << 24;
+        if rel % 4 != 3 {
+            return;
+        }
+        let word = self.word;
+        match rel / 4 {
+            // Version and header size.
+            0 => {
+                self.header_size = (word >> 16) as usize;
+                if word & 0xffff != 2 || self.header_size < 16 || self.header_size % 4 != 0 {
+                    self.failed = true;
+                }
+            }
+            // Total size.
+            1 => {
+                let total_size = word as usize;
+                if total_size < self.header_size {
+                    self.failed = true;
+                }
+                self.next_app = self.app_start + total_size;
+            }
+            // The checksum does not cover itself.
+            3 => self.header_checksum = word,
+            _ => {}
+        }
+        if rel / 4 != 3 {
+            self.checksum ^= word;
+        }
+        if rel == self.header_size - 1 && self.checksum != self.header_checksum {
+            self.failed = true;
+        }
+    }
+
+    /// Whether an image of `length` bytes is complete and valid.
+    fn finish(&self, length: usize) -> bool {
+        if self.failed {
+            return false;
+        }
+        match self.kind {
+            ImageKind::Kernel => {
+                let n = self.tail.len();
+                let crc = u32::from_le_bytes([
+                    self.tail[length % n],
+                    self.tail[(length + 1) % n],
+                    self.tail[(length + 2) % n],
+                    self.tail[(length + 3) % n],
+                ]);
+                length >= self.vectors.len() + n && !self.crc == crc
+            }
+            ImageKind::Apps => length > 0 && length == self.next_app,
+        }
+    }
+}
+
+/// Add `byte` to a CRC-32 (IEEE 802.3, as in zlib) in progress.
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc ^ byte as u32;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 {
+            crc >> 1 ^ 0xedb88320
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
+
+pub struct Dfu<
+    'a,
+    U: 'a,
+    F: 'static + hil::flash::Flash,
+    A: 'a + Alarm<'a>,
+    C: ProcessManagementCapability,
+> {
+    controller: &'a U,
+    flash: &'a F,
+    alarm: &'a A,
+    targets: &'static [Target],
+    client: OptionalCell<&'a dyn DfuClient>,
+    kernel: &'static kernel::Kernel,
+    capability: C,
+
+    /// The page being filled with downloaded data, and the number of bytes
+    /// in it.
+    page: TakeCell<'static, F::Page>,
+    page_fill: Cell<usize>,
+    /// Flash page the buffer is written to next.
+    page_number: Cell<usize>,
+    /// A flash write is in progress.
+    busy: Cell<bool>,
+    /// Pages left to mark empty after an application image.
+    clear_pages: Cell<usize>,
+    /// The download has started writing to the target.
+    written: Cell<bool>,
+    /// The target must be invalidated once the write in progress completes.
+    invalidate_pending: Cell<bool>,
+    /// Target whose first page is being erased.
+    invalidating: OptionalCell<usize>,
+
+    mode: Cell<Mode>,
+    state: Cell<DfuState>,
+    status: Cell<Status>,
+    request: Cell<Request>,
+    alarm_action: Cell<AlarmAction>,
+
+    /// Selected alternate setting, which is the download target.
+    alternate: Cell<u8>,
+    /// Bytes downloaded so far.
+    length: Cell<usize>,
+    checker: Cell<Checker>,
+
+    interface: Cell<u8>,
+}
+
+impl<
+        'a,
+        U: hil::usb::UsbController<'a>,
+        F: hil::flash::Flash,
+        A: Alarm<'a>,
+        C: ProcessManagementCapability,
+    > Dfu<'a, U, F, A, C>
+{
+    pub fn new(
+        controller: &'a U,
+        flash: &'a F,
+        alarm: &'a A,
+        targets: &'static [Target],
+        page: &'static mut F::Page,
+        kernel: &'static kernel::Kernel,
+        capability: C,
+    ) -> Dfu<'a, U, F, A, C> {
+        Dfu {
+            controller: controller,
+            flash: flash,
+            alarm: alarm,
+            targets: targets,
+            client: OptionalCell::empty(),
+            kernel: kernel,
+            capability: capability,
+            page: TakeCell::new(page),
+            page_fill: Cell::new(0),
+            page_number: Cell::new(0),
+            busy: Cell::new(false),
+            clear_pages: Cell::new(0),
+            written: Cell::new(false),
+            invalidate_pending: Cell::new(false),
+            invalidating: OptionalCell::empty(),
+            mode: Cell::new(Mode::Runtime),
+            state: Cell::new(DfuState::AppIdle),
+            status: Cell::new(Status::Ok),
+            request: Cell::new(Request::None),
+            alarm_action: Cell::new(AlarmAction::None),
+            alternate: Cell::new(0),
+            length: Cell::new(0),
+            checker: Cell::new(Checker::new(ImageKind::Kernel)),
+            interface: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn DfuClient) {
+        self.client.set(client);
+    }
+
+    fn target(&self) -> &'static Target {
+        &self.targets[self.alternate.get() as usize]
+    }
+
+    fn page_size(&self) -> usize {
+        self.page.map_or(0, |page| page.as_mut().len())
+    }
+
+    fn fail(&self, status: Status) {
+        self.status.set(status);
+        self.state.set(DfuState::Error);
+        self.abandon();
+    }
+
+    /// Give up on the download in progress. If it has written to the target,
+    /// the partial image there is invalidated.
+    fn abandon(&self) {
+        if self.written.get() {
+            if self.busy.get() {
+                self.invalidate_pending.set(true);
+            } else {
+                self.invalidate();
+            }
+        }
+    }
+
+    /// Erase the first page of the target, which holds the kernel's vector
+    /// table or the first TBF header.
+    fn invalidate(&self) {
+        self.written.set(false);
+        self.invalidate_pending.set(false);
+        let target = self.alternate.get() as usize;
+        let page_number = self.target().start / cmp::max(self.page_size(), 1);
+        self.page.take().map(|page| {
+            for byte in page.as_mut().iter_mut() {
+                *byte = 0xff;
+            }
+            match self.flash.write_page(page_number, page) {
+                Ok(()) => {
+                    self.busy.set(true);
+                    self.invalidating.set(target);
+                }
+                Err((_, page)) => {
+                    self.page.replace(page);
+                    self.client.map(|client| client.image_invalid(target));
+                }
+            }
+        });
+    }
+
+    /// Stop the processes running from an application target before their
+    /// code is overwritten.
+    fn stop_processes(&self) {
+        let target = self.target();
+        if target.kind != ImageKind::Apps {
+            return;
+        }
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                let start = process.flash_start() as usize;
+                if start >= target.start && start < target.start + target.length {
+                    process.stop();
+                }
+            });
+    }
+
+    /// Start a download to the selected target.
+    fn start_download(&self) {
+        let target = self.target();
+        self.length.set(0);
+        self.page_fill.set(0);
+        self.page_number
+            .set(target.start / cmp::max(self.page_size(), 1));
+        self.clear_pages.set(0);
+        self.checker.set(Checker::new(target.kind));
+    }
+
+    /// Handle the setup stage of a DFU request.
+    fn handle_request(&self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+        let state = self.state.get();
+        let request = match (setup.request_code, self.mode.get()) {
+            (DFU_DETACH, Mode::Runtime) => Request::Detach,
+            (DFU_GETSTATUS, _) => Request::GetStatus,
+            (DFU_GETSTATE, _) => Request::GetState,
+            (DFU_DNLOAD, Mode::Dfu) => match state {
+                // Still erasing the target of a failed download.
+                DfuState::Idle if self.busy.get() => {
+                    return hil::usb::CtrlSetupResult::ErrGeneric;
+                }
+                DfuState::Idle | DfuState::DnloadIdle if setup.length > TRANSFER_SIZE => {
+                    self.fail(Status::ErrStalledPkt);
+                    return hil::usb::CtrlSetupResult::ErrGeneric;
+                }
+                DfuState::Idle if setup.length > 0 => {
+                    self.start_download();
+                    if !self.begin_block(setup.length as usize) {
+                        return hil::usb::CtrlSetupResult::ErrGeneric;
+                    }
+                    Request::Dnload
+                }
+                DfuState::DnloadIdle if setup.length > 0 => {
+                    if !self.begin_block(setup.length as usize) {
+                        return hil::usb::CtrlSetupResult::ErrGeneric;
+                    }
+                    Request::Dnload
+                }
+                DfuState::DnloadIdle => {
+                    // The end of the image.
+                    self.state.set(DfuState::ManifestSync);
+                    self.flush();
+                    Request::None
+                }
+                _ => {
+                    self.fail(Status::ErrStalledPkt);
+                    return hil::usb::CtrlSetupResult::ErrGeneric;
+                }
+            },
+            (DFU_CLRSTATUS, Mode::Dfu) if state == DfuState::Error => {
+                self.status.set(Status::Ok);
+                self.state.set(DfuState::Idle);
+                Request::None
+            }
+            (DFU_ABORT, Mode::Dfu) => match state {
+                DfuState::Idle | DfuState::DnloadSync | DfuState::DnloadIdle
+                    if !self.busy.get() =>
+                {
+                    self.state.set(DfuState::Idle);
+                    self.abandon();
+                    Request::None
+                }
+                _ => {
+                    self.fail(Status::ErrStalledPkt);
+                    return hil::usb::CtrlSetupResult::ErrGeneric;
+                }
+            },
+            _ => {
+                if self.mode.get() == Mode::Dfu {
+                    self.fail(Status::ErrStalledPkt);
+                }
+                return hil::usb::CtrlSetupResult::ErrGeneric;
+            }
+        };
+        self.request.set(request);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Check that a block of `length` bytes fits in the page buffer and the
+    /// target.
+    fn begin_block(&self, length: usize) -> bool {
+        if self.page_fill.get() + length > self.page_size()
+            || self.length.get() + length > self.target().length
+        {
+            self.fail(Status::ErrAddress);
+            false
+        } else {
+            self.state.set(DfuState::DnloadSync);
+            true
+        }
+    }
+
+    /// Send the data of a DFU_GETSTATUS or DFU_GETSTATE request.
+    fn request_in(&self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.request.get() {
+            Request::GetStatus => {
+                let busy = self.busy.get();
+                match self.state.get() {
+                    DfuState::AppDetach => {}
+                    DfuState::DnloadSync | DfuState::DnBusy => self.state.set(if busy {
+                        DfuState::DnBusy
+                    } else {
+                        DfuState::DnloadIdle
+                    }),
+                    DfuState::ManifestSync | DfuState::Manifest => self.state.set(if busy {
+                        DfuState::Manifest
+                    } else {
+                        DfuState::Idle
+                    }),
+                    _ => {}
+                }
+                let poll_timeout = if busy { POLL_TIMEOUT } else { 0 };
+                buf[0].set(self.status.get() as u8);
+                buf[1].set(poll_timeout as u8);
+                buf[2].set((poll_timeout >> 8) as u8);
+                buf[3].set((poll_timeout >> 16) as u8);
+                buf[4].set(self.state.get() as u8);
+                buf[5].set(0); // No status description
+                hil::usb::CtrlInResult::Packet(6, true)
+            }
+            Request::GetState => {
+                buf[0].set(self.state.get() as u8);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Receive a packet of a DFU_DNLOAD block.
+    fn request_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        if self.request.get() != Request::Dnload {
+            return hil::usb::CtrlOutResult::Halted;
+        }
+        if self.state.get() != DfuState::DnloadSync {
+            // Drop the rest of a block that failed.
+            return hil::usb::CtrlOutResult::Ok;
+        }
+        let mut checker = self.checker.get();
+        self.page.map(|page| {
+            let page = page.as_mut();
+            let fill = self.page_fill.get();
+            let len = cmp::min(packet_bytes as usize, page.len() - fill);
+            for i in 0..len {
+                let byte = buf[i].get();
+                page[fill + i] = byte;
+                checker.feed(self.length.get() + i, byte);
+            }
+            self.page_fill.set(fill + len);
+            self.length.set(self.length.get() + len);
+        });
+        self.checker.set(checker);
+        if checker.failed {
+            self.fail(Status::ErrFile);
+        }
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn request_complete(&self) {
+        match self.request.replace(Request::None) {
+            Request::Detach => {
+                self.state.set(DfuState::AppDetach);
+                self.set_alarm(AlarmAction::Detach, DETACH_DELAY);
+            }
+            Request::Dnload => {
+                if self.state.get() == DfuState::DnloadSync
+                    && self.page_fill.get() == self.page_size()
+                {
+                    self.write_page();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_alarm(&self, action: AlarmAction, ms: u32) {
+        self.alarm_action.set(action);
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Write the page buffer to flash.
+    fn write_page(&self) {
+        if !self.written.get() {
+            self.stop_processes();
+            self.written.set(true);
+        }
+        self.page.take().map(
+            |page| match self.flash.write_page(self.page_number.get(), page) {
+                Ok(()) => {
+                    self.busy.set(true);
+                    self.page_number.set(self.page_number.get() + 1);
+                    self.page_fill.set(0);
+                }
+                Err((_, page)) => {
+                    self.page.replace(page);
+                    self.fail(Status::ErrWrite);
+                }
+            },
+        );
+    }
+
+    /// Write the rest of the image at the end of a download.
+    fn flush(&self) {
+        let target = self.target();
+        if target.kind == ImageKind::Apps
+            && self.page_fill.get() == 0
+            && self.length.get() < target.length
+        {
+            // Mark the page after the last application empty, so the kernel
+            // stops looking for applications there. A partly filled last
+            // page is padded with 0xff instead.
+            self.clear_pages.set(1);
+        }
+        if self.page_fill.get() > 0 {
+            self.page.map(|page| {
+                for byte in page.as_mut()[self.page_fill.get()..].iter_mut() {
+                    *byte = 0xff;
+                }
+            });
+            self.write_page();
+        } else {
+            self.manifest();
+        }
+    }
+
+    /// Write any empty pages left, then check the complete image.
+    fn manifest(&self) {
+        if self.clear_pages.get() > 0 {
+            self.clear_pages.set(self.clear_pages.get() - 1);
+            self.page.map(|page| {
+                for byte in page.as_mut().iter_mut() {
+                    *byte = 0xff;
+                }
+            });
+            self.write_page();
+            return;
+        }
+
+        if self.checker.get().finish(self.length.get()) {
+            self.written.set(false);
+            let target = self.alternate.get() as usize;
+            let length = self.length.get();
+            self.client
+                .map(|client| client.image_downloaded(target, length));
+        } else {
+            self.fail(Status::ErrNotDone);
+        }
+    }
+}
+
+impl<
+        'a,
+        U: hil::usb::UsbController<'a>,
+        F: hil::flash::Flash,
+        A: Alarm<'a>,
+        C: ProcessManagementCapability,
+    > Function<'a> for Dfu<'a, U, F, A, C>
+{
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn endpoint_count(&self) -> usize {
+        0
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        match self.mode.get() {
+            Mode::Runtime => (0xfe, 0x01, 0x01),
+            Mode::Dfu => (0xfe, 0x01, 0x02),
+        }
+    }
+
+    fn assign(&self, first_interface: u8, _first_endpoint: usize) {
+        self.interface.set(first_interface);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        let (class, subclass, protocol) = self.function_class();
+        let settings = match self.mode.get() {
+            Mode::Runtime => 1,
+            Mode::Dfu => self.targets.len(),
+        };
+        let mut len = 0;
+        for setting in 0..settings {
+            let written = descriptors::write_descriptors(
+                &buf[len..],
+                &[&InterfaceDescriptor {
+                    interface_number: self.interface.get(),
+                    alternate_setting: setting as u8,
+                    num_endpoints: 0,
+                    interface_class: class,
+                    interface_subclass: subclass,
+                    interface_protocol: protocol,
+                    ..InterfaceDescriptor::default()
+                }],
+            );
+            if written == 0 {
+                return 0;
+            }
+            len += written;
+        }
+        let written = descriptors::write_descriptors(
+            &buf[len..],
+            &[&DfuFunctionalDescriptor {
+                can_download: true,
+                can_upload: false,
+                manifestation_tolerant: true,
+                will_detach: true,
+                detach_timeout: 1000,
+                transfer_size: TRANSFER_SIZE,
+            }],
+        );
+        if written == 0 {
+            return 0;
+        }
+        len + written
+    }
+
+    fn alternate_setting(&self, _interface: u8) -> u8 {
+        self.alternate.get()
+    }
+
+    fn set_alternate_setting(&'a self, _interface: u8, alternate_setting: u8) -> bool {
+        let valid = match self.mode.get() {
+            Mode::Runtime => alternate_setting == 0,
+            Mode::Dfu => (alternate_setting as usize) < self.targets.len() && !self.busy.get(),
+        };
+        if valid {
+            if self.mode.get() == Mode::Dfu {
+                self.abandon();
+                self.state.set(DfuState::Idle);
+            }
+            self.alternate.set(alternate_setting);
+        }
+        valid
+    }
+
+    fn enable(&'a self) {}
+
+    fn bus_reset(&'a self) {
+        if self.mode.get() == Mode::Dfu {
+            // A reset abandons a download.
+            self.status.set(Status::Ok);
+            self.state.set(DfuState::Idle);
+            self.abandon();
+        }
+        self.request.set(Request::None);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        self.handle_request(setup)
+    }
+
+    fn ctrl_in(&'a self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        self.request_in(buf)
+    }
+
+    fn ctrl_out(&'a self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.request_out(buf, packet_bytes)
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.request_complete();
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<
+        'a,
+        U: hil::usb::UsbController<'a>,
+        F: hil::flash::Flash,
+        A: Alarm<'a>,
+        C: ProcessManagementCapability,
+    > hil::flash::Client<F> for Dfu<'a, U, F, A, C>
+{
+    fn read_complete(&self, page: &'static mut F::Page, _error: hil::flash::Error) {
+        self.page.replace(page);
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: hil::flash::Error) {
+        self.page.replace(page);
+        self.busy.set(false);
+        if let Some(target) = self.invalidating.take() {
+            self.client.map(|client| client.image_invalid(target));
+            return;
+        }
+        if self.invalidate_pending.get() {
+            self.invalidate();
+            return;
+        }
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(Status::ErrWrite);
+            return;
+        }
+        match self.state.get() {
+            DfuState::ManifestSync | DfuState::Manifest => self.manifest(),
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, _error: hil::flash::Error) {}
+}
+
+impl<
+        'a,
+        U: hil::usb::UsbController<'a>,
+        F: hil::flash::Flash,
+        A: Alarm<'a>,
+        C: ProcessManagementCapability,
+    > AlarmClient for Dfu<'a, U, F, A, C>
+{
+    fn alarm(&self) {
+        match self.alarm_action.replace(AlarmAction::None) {
+            AlarmAction::Detach => {
+                // Reconnect as a DFU mode device, so the host reads the new
+                // descriptors.
+                self.controller.detach();
+                self.mode.set(Mode::Dfu);
+                self.alternate.set(0);
+                self.status.set(Status::Ok);
+                self.state.set(DfuState::Idle);
+                self.set_alarm(AlarmAction::Attach, REATTACH_DELAY);
+            }
+            AlarmAction::Attach => self.controller.attach(),
+            AlarmAction::None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::{crc32_update, Checker, ImageKind};
+
+    fn crc32(data: &[u8]) -> u32 {
+        !data
+            .iter()
+            .fold(0xffffffff, |crc, &byte| crc32_update(crc, byte))
+    }
+
+    /// A kernel image: a vector table, some code and the CRC.
+    fn kernel_image() -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(&0x2000_4000u32.to_le_bytes());
+        image.extend_from_slice(&0x0001_0101u32.to_le_bytes());
+        image.extend((0..300).map(|i| i as u8));
+        let crc = crc32(&image);
+        image.extend_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    fn check(image: &[u8]) -> bool {
+        let mut checker = Checker::new(ImageKind::Kernel);
+        for (offset, &byte) in image.iter().enumerate() {
+            checker.feed(offset, byte);
+        }
+        checker.finish(image.len())
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn kernel_image_with_crc() {
+        let image = kernel_image();
+        assert!(check(&image));
+        // Every length modulo 4 puts the CRC at a different place.
+        for code_length in 0..4 {
+            let mut image = kernel_image()[..8 + code_length].to_vec();
+            let crc = crc32(&image);
+            image.extend_from_slice(&crc.to_le_bytes());
+            assert!(check(&image));
+        }
+    }
+
+    #[test]
+    fn truncated_kernel_image() {
+        let image = kernel_image();
+        // Without the CRC, and cut short anywhere else.
+        assert!(!check(&image[..image.len() - 4]));
+        assert!(!check(&image[..image.len() - 1]));
+        assert!(!check(&image[..100]));
+        assert!(!check(&image[..8]));
+        assert!(!check(&[]));
+    }
+
+    #[test]
+    fn corrupt_kernel_image() {
+        let mut image = kernel_image();
+        image[200] ^= 0x10;
+        assert!(!check(&image));
+
+        let mut image = kernel_image();
+        let last = image.len() - 1;
+        image[last] ^= 0x01;
+        assert!(!check(&image));
+    }
+
+    #[test]
+    fn kernel_image_without_vector_table() {
+        let mut image = Vec::new();
+        image.extend_from_slice(&0x2000_4000u32.to_le_bytes());
+        // The reset handler is not Thumb code.
+        image.extend_from_slice(&0x0001_0100u32.to_le_bytes());
+        let crc = crc32(&image);
+        image.extend_from_slice(&crc.to_le_bytes());
+        assert!(!check(&image));
+    }
+}
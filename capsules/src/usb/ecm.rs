@@ -0,0 +1,678 @@
+//! USB CDC Ethernet Control Model (ECM) class
+//!
+//! Presents an Ethernet interface to the host, so that the network stack of
+//! the board can be reached over USB. The function is an
+//! [`EthernetAdapter`](../../../kernel/hil/ethernet/trait.EthernetAdapter.html):
+//! it is usually the adapter of a
+//! [`MuxEthernet`](../../ethernet/virtual_ethernet/struct.MuxEthernet.html)
+//! that the IPv6 or IPv4 stack is a user of.
+//!
+//! The ECM function is part of a
+//! [composite device](../composite/struct.CompositeDevice.html), alone or next
+//! to other functions. It has a communication interface with an interrupt
+//! endpoint for notifications, and a data interface whose alternate setting
+//! 1 has a bulk endpoint in each direction. The link is up while the host has
+//! selected alternate setting 1 of the data interface. Frames are sent as a
+//! series of full packets ended by a short packet, which is a zero-length
+//! packet if the frame is a multiple of the packet size.
+//!
+//! The host reads the MAC address of its end of the link from a string of
+//! 12 hexadecimal digits given to the constructor. It must differ from the
+//! MAC address the board uses for its end. The device passes all frames to
+//! its client, whatever packet filter the host selects, and collects no
+//! statistics.
+//!
+//! Based on the USB Class Definitions for Communications Devices, Ethernet
+//! Control Model Devices revision 1.2.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let ecm = static_init!(
+//!     capsules::usb::ecm::CdcEcm<'static, nrf52::usbd::Usbd<'static>>,
+//!     capsules::usb::ecm::CdcEcm::new(
+//!         &nrf52::usbd::USBD,
+//!         "020000000002",
+//!         &mut capsules::usb::ecm::RX_BUFFER,
+//!     )
+//! );
+//! composite.add_function(ecm);
+//!
+//! let eth_mux = static_init!(
+//!     capsules::ethernet::virtual_ethernet::MuxEthernet<'static>,
+//!     capsules::ethernet::virtual_ethernet::MuxEthernet::new(ecm)
+//! );
+//! ecm.set_client(eth_mux);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::Function;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::CdcEthernetNetworkingDescriptor;
+use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient, MAX_FRAME_LEN};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Storage for the frame being received.
+pub static mut RX_BUFFER: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+
+/// Size of the packets on the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Frames shorter than an Ethernet header are dropped.
+const MIN_FRAME_LEN: usize = 14;
+
+/// Largest frame the host may send, without the frame check sequence.
+const MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// Bit rate reported to the host, in bits per second. This is the
+/// full-speed USB signalling rate.
+const BIT_RATE: u32 = 12_000_000;
+
+const NOTIFY_BUFFER: usize = 0;
+const IN_BUFFER: usize = 1;
+const OUT_BUFFER: usize = 2;
+
+/// Class requests of the communication interface.
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+/// Notification codes.
+const NETWORK_CONNECTION: u8 = 0x00;
+const CONNECTION_SPEED_CHANGE: u8 = 0x2a;
+
+/// The next notification to send to the host.
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Notification {
+    None,
+    Connected,
+    Speed,
+}
+
+pub struct CdcEcm<'a, U: 'a> {
+    controller: &'a U,
+
+    /// 64 byte buffers for the notification endpoint and the bulk
+    /// endpoints.
+    buffers: [Buffer64; 3],
+
+    /// The MAC address of the host, as a string for the iMACAddress field.
+    mac_address: [&'static str; 1],
+
+    /// Whether the host has selected alternate setting 1 of the data
+    /// interface.
+    active: Cell<bool>,
+    notification: Cell<Notification>,
+
+    client: OptionalCell<&'a dyn EthernetAdapterClient>,
+
+    /// The frame being sent.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    /// Whether the short packet that ends the frame has been sent.
+    tx_last: Cell<bool>,
+
+    /// The frame being received, unless the client has it.
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    /// Whether the frame being received does not fit in `rx_buffer`. It is
+    /// dropped once it is complete.
+    rx_overflow: Cell<bool>,
+    /// Whether the OUT endpoint was paused because the client had the
+    /// receive buffer.
+    out_delayed: Cell<bool>,
+
+    /// Interface, endpoint and string numbers, which the composite device
+    /// assigns.
+    interface: Cell<u8>,
+    endpoint_notify: Cell<usize>,
+    endpoint_data: Cell<usize>,
+    mac_address_string: Cell<u8>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CdcEcm<'a, U> {
+    /// `mac_address` is the MAC address of the host's end of the link, as 12
+    /// hexadecimal digits.
+    pub fn new(controller: &'a U, mac_address: &'static str, rx_buffer: &'static mut [u8]) -> Self {
+        CdcEcm {
+            controller: controller,
+            buffers: [
+                Buffer64::default(),
+                Buffer64::default(),
+                Buffer64::default(),
+            ],
+            mac_address: [mac_address],
+            active: Cell::new(false),
+            notification: Cell::new(Notification::None),
+            client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_last: Cell::new(false),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_overflow: Cell::new(false),
+            out_delayed: Cell::new(false),
+            interface: Cell::new(0),
+            endpoint_notify: Cell::new(1),
+            endpoint_data: Cell::new(2),
+            mac_address_string: Cell::new(4),
+        }
+    }
+
+    /// Whether the host has enabled the data interface.
+    pub fn is_active(&self) -> bool {
+        self.active.get()
+    }
+
+    fn activate(&self) {
+        self.active.set(true);
+        self.rx_len.set(0);
+        self.rx_overflow.set(false);
+        self.notification.set(Notification::Connected);
+        self.controller
+            .endpoint_resume_in(self.endpoint_notify.get());
+    }
+
+    /// Take the link down, failing the frame being sent.
+    fn deactivate(&self) {
+        self.active.set(false);
+        self.notification.set(Notification::None);
+        self.rx_len.set(0);
+        self.rx_overflow.set(false);
+        if self.tx_buffer.is_some() {
+            self.finish_transmit(ReturnCode::FAIL);
+        }
+    }
+
+    fn finish_transmit(&self, result: ReturnCode) {
+        self.tx_last.set(false);
+        self.tx_buffer.take().map(|buf| {
+            self.client.map(move |client| client.tx_done(result, buf));
+        });
+    }
+
+    /// Write the next notification to the notification endpoint buffer.
+    fn notify_in(&self) -> hil::usb::InResult {
+        let packet = &self.buffers[NOTIFY_BUFFER].buf;
+        let (code, value, data): (u8, u16, Option<u32>) = match self.notification.get() {
+            Notification::None => return hil::usb::InResult::Delay,
+            Notification::Connected => {
+                self.notification.set(Notification::Speed);
+                (NETWORK_CONNECTION, 1, None)
+            }
+            Notification::Speed => {
+                self.notification.set(Notification::None);
+                (CONNECTION_SPEED_CHANGE, 0, Some(BIT_RATE))
+            }
+        };
+        let interface = self.interface.get() as u16;
+        let length: u16 = if data.is_some() { 8 } else { 0 };
+        let header = [
+            0xa1, // Class request to an interface, device to host
+            code,
+            value as u8,
+            (value >> 8) as u8,
+            interface as u8,
+            (interface >> 8) as u8,
+            length as u8,
+            (length >> 8) as u8,
+        ];
+        for (cell, byte) in packet.iter().zip(header.iter()) {
+            cell.set(*byte);
+        }
+        if let Some(bit_rate) = data {
+            // The downstream and the upstream bit rate.
+            for (i, byte) in bit_rate
+                .to_le_bytes()
+                .iter()
+                .chain(bit_rate.to_le_bytes().iter())
+                .enumerate()
+            {
+                packet[8 + i].set(*byte);
+            }
+        }
+        hil::usb::InResult::Packet(8 + length as usize)
+    }
+
+    /// Write the next packet of the frame being sent to the IN endpoint
+    /// buffer.
+    fn data_in(&self) -> hil::usb::InResult {
+        if self.tx_last.get() {
+            self.finish_transmit(ReturnCode::SUCCESS);
+        }
+        self.tx_buffer.map_or(hil::usb::InResult::Delay, |tx_buf| {
+            let packet = &self.buffers[IN_BUFFER].buf;
+            let offset = self.tx_offset.get();
+            let size = cmp::min(packet.len(), self.tx_len.get() - offset);
+            for (cell, byte) in packet.iter().zip(tx_buf[offset..offset + size].iter()) {
+                cell.set(*byte);
+            }
+            self.tx_offset.set(offset + size);
+            if size < packet.len() {
+                self.tx_last.set(true);
+            }
+            hil::usb::InResult::Packet(size)
+        })
+    }
+
+    /// Add a packet from the OUT endpoint buffer to the frame being
+    /// received, and pass the frame to the client once it is complete.
+    fn data_out(&self, packet_bytes: usize) -> hil::usb::OutResult {
+        let rx_buf = match self.rx_buffer.take() {
+            Some(rx_buf) => rx_buf,
+            None => {
+                // The client still has the buffer, so drop the packet.
+                self.out_delayed.set(true);
+                return hil::usb::OutResult::Delay;
+            }
+        };
+
+        let packet = &self.buffers[OUT_BUFFER].buf;
+        let offset = self.rx_len.get();
+        let size = cmp::min(packet_bytes, rx_buf.len() - offset);
+        if size < packet_bytes {
+            self.rx_overflow.set(true);
+        }
+        for (byte, cell) in rx_buf[offset..offset + size].iter_mut().zip(packet.iter()) {
+            *byte = cell.get();
+        }
+        self.rx_len.set(offset + size);
+
+        if packet_bytes == MAX_PACKET_SIZE {
+            // The frame continues in the next packet.
+            self.rx_buffer.replace(rx_buf);
+            return hil::usb::OutResult::Ok;
+        }
+
+        let len = self.rx_len.replace(0);
+        if self.rx_overflow.replace(false) || len < MIN_FRAME_LEN || !self.active.get() {
+            self.rx_buffer.replace(rx_buf);
+            return hil::usb::OutResult::Ok;
+        }
+        match self.client.map(|client| *client) {
+            Some(client) => {
+                client.rx_packet(rx_buf, len);
+                if self.rx_buffer.is_some() {
+                    hil::usb::OutResult::Ok
+                } else {
+                    // NAK further packets until the client returns the
+                    // buffer.
+                    self.out_delayed.set(true);
+                    hil::usb::OutResult::Delay
+                }
+            }
+            None => {
+                self.rx_buffer.replace(rx_buf);
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> EthernetAdapter<'a> for CdcEcm<'a, U> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient) {
+        self.client.set(client);
+    }
+
+    /// Returns EOFF if the host has not enabled the link.
+    fn transmit(
+        &self,
+        packet: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len > packet.len() {
+            Err((ReturnCode::EINVAL, packet))
+        } else if len > MAX_FRAME_LEN {
+            Err((ReturnCode::ESIZE, packet))
+        } else if self.tx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, packet))
+        } else if !self.active.get() {
+            Err((ReturnCode::EOFF, packet))
+        } else {
+            self.tx_len.set(len);
+            self.tx_offset.set(0);
+            self.tx_last.set(false);
+            self.tx_buffer.replace(packet);
+            self.controller.endpoint_resume_in(self.endpoint_data.get());
+            Ok(())
+        }
+    }
+
+    fn return_rx_buffer(&self, rx_buffer: &'static mut [u8]) {
+        self.rx_buffer.replace(rx_buffer);
+        if self.out_delayed.take() {
+            self.controller
+                .endpoint_resume_out(self.endpoint_data.get());
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Function<'a> for CdcEcm<'a, U> {
+    fn interface_count(&self) -> u8 {
+        2
+    }
+
+    fn endpoint_count(&self) -> usize {
+        2
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        (0x02, 0x06, 0x00)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        // The notification endpoint, then bulk IN and OUT endpoints of the
+        // same number.
+        self.interface.set(first_interface);
+        self.endpoint_notify.set(first_endpoint);
+        self.endpoint_data.set(first_endpoint + 1);
+    }
+
+    fn strings(&self) -> &[&'static str] {
+        &self.mac_address
+    }
+
+    fn assign_strings(&self, first_string: u8) {
+        self.mac_address_string.set(first_string);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        let communication = self.interface.get();
+        let data = communication + 1;
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: communication,
+                    num_endpoints: 1,
+                    interface_class: 0x02,    // CDC communication
+                    interface_subclass: 0x06, // Ethernet control model (ECM)
+                    interface_protocol: 0x00, // none
+                    ..InterfaceDescriptor::default()
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+                    field1: 0x10, // CDC 1.10
+                    field2: 0x01, // CDC 1.10
+                },
+                &CdcInterfaceDescriptor {
+                    subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+                    field1: communication,
+                    field2: data,
+                },
+                &CdcEthernetNetworkingDescriptor {
+                    mac_address_string: self.mac_address_string.get(),
+                    statistics: 0,
+                    max_segment_size: MAX_SEGMENT_SIZE,
+                    multicast_filters: 0,
+                    power_filters: 0,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_notify.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 16,
+                    interval: 32,
+                },
+                // Alternate setting 0 of the data interface has no
+                // endpoints, so the link is down.
+                &InterfaceDescriptor {
+                    interface_number: data,
+                    num_endpoints: 0,
+                    interface_class: 0x0a,    // CDC data
+                    interface_subclass: 0x00, // none
+                    interface_protocol: 0x00, // none
+                    ..InterfaceDescriptor::default()
+                },
+                &InterfaceDescriptor {
+                    interface_number: data,
+                    alternate_setting: 1,
+                    num_endpoints: 2,
+                    interface_class: 0x0a,    // CDC data
+                    interface_subclass: 0x00, // none
+                    interface_protocol: 0x00, // none
+                    ..InterfaceDescriptor::default()
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_data.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: MAX_PACKET_SIZE as u16,
+                    interval: 0,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_data.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: MAX_PACKET_SIZE as u16,
+                    interval: 0,
+                },
+            ],
+        )
+    }
+
+    fn alternate_setting(&self, interface: u8) -> u8 {
+        if interface == self.interface.get() + 1 {
+            self.active.get() as u8
+        } else {
+            0
+        }
+    }
+
+    fn set_alternate_setting(&'a self, interface: u8, alternate_setting: u8) -> bool {
+        if interface != self.interface.get() + 1 {
+            return alternate_setting == 0;
+        }
+        match alternate_setting {
+            0 => {
+                self.deactivate();
+                true
+            }
+            1 => {
+                self.activate();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_notify.get(), &self.buffers[NOTIFY_BUFFER].buf);
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, self.endpoint_notify.get());
+
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_data.get(), &self.buffers[IN_BUFFER].buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint_data.get(), &self.buffers[OUT_BUFFER].buf);
+        self.controller
+            .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_data.get());
+    }
+
+    fn bus_reset(&'a self) {
+        self.deactivate();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        match (setup.request_type.transfer_direction(), setup.request_code) {
+            (TransferDirection::HostToDevice, SET_ETHERNET_PACKET_FILTER) => {
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        _buf: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt if endpoint == self.endpoint_notify.get() => self.notify_in(),
+            TransferType::Bulk => self.data_in(),
+            _ => hil::usb::InResult::Error,
+        }
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => self.data_out(packet_bytes as usize),
+            _ => hil::usb::OutResult::Error,
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if endpoint == self.endpoint_notify.get() {
+            if self.notification.get() != Notification::None {
+                self.controller.endpoint_resume_in(endpoint);
+            }
+        } else if self.tx_last.get() {
+            self.finish_transmit(ReturnCode::SUCCESS);
+        } else if self.tx_buffer.is_some() {
+            self.controller.endpoint_resume_in(endpoint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+
+    use super::super::composite::CompositeDevice;
+    use super::super::sim::{setup, SimUsbController};
+    use super::CdcEcm;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient, MAX_FRAME_LEN};
+    use kernel::hil::usb::{Client, UsbController};
+    use kernel::ReturnCode;
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "Network", "1"];
+
+    struct Link {
+        rx_buffer: TakeCell<'static, [u8]>,
+        rx_len: Cell<usize>,
+        tx_buffer: TakeCell<'static, [u8]>,
+        tx_result: Cell<Option<ReturnCode>>,
+    }
+
+    impl EthernetAdapterClient for Link {
+        fn tx_done(&self, result: ReturnCode, packet: &'static mut [u8]) {
+            self.tx_result.set(Some(result));
+            self.tx_buffer.replace(packet);
+        }
+
+        fn rx_packet(&self, packet: &'static mut [u8], len: usize) {
+            self.rx_len.set(len);
+            self.rx_buffer.replace(packet);
+        }
+    }
+
+    #[test]
+    fn frames_over_usb() {
+        let usb = SimUsbController::new();
+        let ecm = CdcEcm::new(
+            &usb,
+            "020000000002",
+            Box::leak(Box::new([0; MAX_FRAME_LEN])),
+        );
+        let link = Link {
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_result: Cell::new(None),
+        };
+        ecm.set_client(&link);
+        let composite = CompositeDevice::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            Box::leak(Box::new([0; 256])),
+        );
+        assert_eq!(composite.add_function(&ecm), ReturnCode::SUCCESS);
+        usb.set_client(&composite);
+        composite.enable();
+        composite.attach();
+
+        let device = usb.enumerate();
+        assert_eq!(device.configuration[4], 2);
+        // The MAC address string follows the device strings.
+        let mac: std::vec::Vec<u8> = "020000000002"
+            .bytes()
+            .flat_map(|b| std::vec![b, 0])
+            .collect();
+        assert_eq!(usb.get_descriptor(3, 4, 255).unwrap()[2..], mac[..]);
+
+        // The link is down until the host selects alternate setting 1.
+        let frame = Box::leak(Box::new([0x5a; 128]));
+        match ecm.transmit(frame, 128) {
+            Err((ReturnCode::EOFF, frame)) => link.tx_buffer.replace(frame),
+            _ => panic!("transmit while the link is down"),
+        };
+        assert_eq!(usb.control_out(setup(0x01, 11, 1, 1, 0), &[]), Ok(()));
+        assert!(ecm.is_active());
+        // NETWORK_CONNECTION(connected), then CONNECTION_SPEED_CHANGE
+        let notifications = usb.drain_in(1);
+        assert_eq!(notifications.len(), 24);
+        assert_eq!(&notifications[..4], &[0xa1, 0x00, 0x01, 0x00]);
+        assert_eq!(&notifications[8..10], &[0xa1, 0x2a]);
+
+        // A frame that is a multiple of the packet size ends with a
+        // zero-length packet.
+        let frame = link.tx_buffer.take().unwrap();
+        assert!(ecm.transmit(frame, 128).is_ok());
+        assert_eq!(usb.transfer_in(2).unwrap().len(), 64);
+        assert_eq!(usb.transfer_in(2).unwrap().len(), 64);
+        assert_eq!(link.tx_result.get(), None);
+        assert_eq!(usb.transfer_in(2).unwrap().len(), 0);
+        assert_eq!(link.tx_result.get(), Some(ReturnCode::SUCCESS));
+
+        // A received frame is passed to the client, and further packets are
+        // refused until it returns the buffer.
+        assert_eq!(usb.transfer_out(2, &[1; 64]), Ok(()));
+        assert_eq!(usb.transfer_out(2, &[2; 36]), Ok(()));
+        assert_eq!(link.rx_len.get(), 100);
+        let rx_buffer = link.rx_buffer.take().unwrap();
+        assert_eq!(rx_buffer[63..65], [1, 2]);
+        assert!(usb.transfer_out(2, &[3; 20]).is_err());
+        ecm.return_rx_buffer(rx_buffer);
+        assert_eq!(usb.transfer_out(2, &[3; 20]), Ok(()));
+        assert_eq!(link.rx_len.get(), 20);
+    }
+}
@@ -0,0 +1,1025 @@
+//! USB Human Interface Device (HID) class
+//!
+//! A HID function with a report descriptor supplied by the board, for
+//! keyboards, mice and custom devices. Input reports are sent to the host
+//! over an interrupt IN endpoint, and output reports arrive over an interrupt
+//! OUT endpoint or with SET_REPORT requests. The host can also read input and
+//! feature reports with GET_REPORT and write feature reports with SET_REPORT.
+//!
+//! If the report descriptor declares report IDs, the first byte of every
+//! report passed to and from the client is its report ID. Reports are at
+//! most [MAX_REPORT_SIZE](constant.MAX_REPORT_SIZE.html) bytes, so that each
+//! one fits in a single packet.
+//!
+//! Devices that implement a boot interface also answer SET_PROTOCOL and
+//! GET_PROTOCOL, so BIOSes can use them without parsing the report
+//! descriptor. The client is told when the protocol changes and must send
+//! boot reports while in the boot protocol. The descriptors in this module
+//! for keyboards and mice use the boot report formats, so their reports are
+//! the same in both protocols.
+//!
+//! The idle rate set with SET_IDLE is stored and reported back, but reports
+//! are only sent when the client sends them.
+//!
+//! Based on the Device Class Definition for HID 1.11.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let hid = static_init!(
+//!     capsules::usb::hid::Hid<'static, nrf52::usbd::Usbd<'static>>,
+//!     capsules::usb::hid::Hid::new(
+//!         &nrf52::usbd::USBD,
+//!         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x520b,
+//!         STRINGS,
+//!         capsules::usb::hid::BootInterface::Keyboard,
+//!         &capsules::usb::hid::KEYBOARD_HID_DESCRIPTOR,
+//!         &capsules::usb::hid::KEYBOARD_REPORT,
+//!     )
+//! );
+//! hid.set_client(keyboard);
+//! nrf52::usbd::USBD.set_client(hid);
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::Function;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Largest report, the size of a full-speed interrupt packet.
+pub const MAX_REPORT_SIZE: usize = 64;
+
+/// Interrupt IN/OUT endpoint, unless a composite device assigns another one.
+const ENDPOINT_NUM: usize = 1;
+
+/// Polling interval of the interrupt endpoints, in milliseconds.
+const INTERVAL: u8 = 10;
+
+const IN_BUFFER: usize = 0;
+const OUT_BUFFER: usize = 1;
+
+const N_ENDPOINTS: usize = 2;
+
+// Class requests.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Report descriptor of a boot keyboard: a modifier byte, a reserved byte
+/// and six key codes in, and five LEDs out.
+pub static KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LED report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): LED report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array): key arrays
+    0xc0, // End Collection
+];
+
+/// Report descriptor of a boot mouse: three buttons and relative X and Y
+/// movement.
+pub static MOUSE_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): buttons
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant): padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative): X and Y
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+pub static KEYBOARD_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: KEYBOARD_REPORT_DESCRIPTOR,
+};
+
+pub static KEYBOARD_HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: KEYBOARD_REPORT_DESCRIPTOR.len() as u16,
+    }],
+};
+
+pub static MOUSE_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: MOUSE_REPORT_DESCRIPTOR,
+};
+
+pub static MOUSE_HID_DESCRIPTOR: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: MOUSE_REPORT_DESCRIPTOR.len() as u16,
+    }],
+};
+
+/// The boot interface a HID device implements, if any.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BootInterface {
+    None,
+    Keyboard,
+    Mouse,
+}
+
+impl BootInterface {
+    /// Interface subclass and protocol codes.
+    fn codes(&self) -> (u8, u8) {
+        match *self {
+            BootInterface::None => (0x00, 0x00),
+            BootInterface::Keyboard => (0x01, 0x01),
+            BootInterface::Mouse => (0x01, 0x02),
+        }
+    }
+}
+
+/// Report format selected by the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+impl ReportType {
+    fn from_u8(report_type: u8) -> Option<ReportType> {
+        match report_type {
+            1 => Some(ReportType::Input),
+            2 => Some(ReportType::Output),
+            3 => Some(ReportType::Feature),
+            _ => None,
+        }
+    }
+}
+
+pub trait HidClient {
+    /// A report passed to `send_report()` was sent to the host.
+    fn report_sent(&self, report: &'static mut [u8], result: ReturnCode);
+
+    /// The host sent an output report, or a feature report with
+    /// SET_REPORT.
+    fn report_received(&self, report_type: ReportType, report: &[u8]);
+
+    /// The host asked for a report with GET_REPORT. Write it to `buf` and
+    /// return its length, or return `None` to refuse the request.
+    fn get_report(&self, report_type: ReportType, report_id: u8, buf: &mut [u8]) -> Option<usize>;
+
+    /// The host switched between the boot and report protocols.
+    fn protocol_changed(&self, _protocol: Protocol) {}
+}
+
+/// Class request in progress on the control endpoint.
+#[derive(Copy, Clone, PartialEq)]
+enum CtrlState {
+    Idle,
+    GetReport(ReportType, u8, usize),
+    SetReport(ReportType),
+    GetIdle,
+    GetProtocol,
+}
+
+pub struct Hid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for the IN and OUT endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    hid_descriptor: &'static HIDDescriptor<'static>,
+    report_descriptor: &'static ReportDescriptor<'static>,
+    boot: BootInterface,
+
+    client: OptionalCell<&'a dyn HidClient>,
+
+    /// The report being sent, and its length.
+    send_buffer: TakeCell<'static, [u8]>,
+    send_len: Cell<usize>,
+
+    ctrl_state: Cell<CtrlState>,
+    protocol: Cell<Protocol>,
+    /// Idle rate set by the host, in units of 4 ms.
+    idle: Cell<u8>,
+
+    /// Interface and endpoint numbers, which a composite device can change.
+    interface: Cell<u8>,
+    endpoint: Cell<usize>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Hid<'a, U> {
+    /// Create a standalone HID device. In the standalone device, the report
+    /// descriptor must be at most 128 bytes long.
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        boot: BootInterface,
+        hid_descriptor: &'static HIDDescriptor<'static>,
+        report_descriptor: &'static ReportDescriptor<'static>,
+    ) -> Self {
+        let (subclass, protocol) = boot.codes();
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            num_endpoints: 2,
+            interface_class: 0x03, // HID
+            interface_subclass: subclass,
+            interface_protocol: protocol,
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: MAX_REPORT_SIZE as u16,
+                interval: INTERVAL,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: MAX_REPORT_SIZE as u16,
+                interval: INTERVAL,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(hid_descriptor),
+                None, // No CDC descriptor array
+            );
+
+        Hid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                Some(hid_descriptor),
+                Some(report_descriptor),
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            hid_descriptor: hid_descriptor,
+            report_descriptor: report_descriptor,
+            boot: boot,
+            client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            send_len: Cell::new(0),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            protocol: Cell::new(Protocol::Report),
+            idle: Cell::new(0),
+            interface: Cell::new(0),
+            endpoint: Cell::new(ENDPOINT_NUM),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    pub fn set_client(&self, client: &'a dyn HidClient) {
+        self.client.set(client);
+    }
+
+    /// The protocol selected by the host.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.get()
+    }
+
+    /// Send the first `len` bytes of `report` to the host as an input
+    /// report. The buffer is returned with `report_sent()`.
+    pub fn send_report(
+        &self,
+        report: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.send_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, report));
+        }
+        if len > MAX_REPORT_SIZE || len > report.len() {
+            return Err((ReturnCode::ESIZE, report));
+        }
+        self.send_len.set(len);
+        self.send_buffer.replace(report);
+        self.controller().endpoint_resume_in(self.endpoint.get());
+        Ok(())
+    }
+
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint.get(), &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint.get(), &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, self.endpoint.get());
+    }
+
+    fn reset(&self) {
+        // The host must select the boot protocol again after a reset.
+        self.ctrl_state.set(CtrlState::Idle);
+        self.idle.set(0);
+        if self.protocol.replace(Protocol::Report) != Protocol::Report {
+            self.client
+                .map(|client| client.protocol_changed(Protocol::Report));
+        }
+    }
+
+    /// Handle the setup stage of a class request.
+    fn handle_class_request(&self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        // A new setup stage ends the last request, even one that stalled.
+        self.ctrl_state.set(CtrlState::Idle);
+        match setup.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+        let report_type = ReportType::from_u8((setup.value >> 8) as u8);
+        let state = match (setup.request_code, report_type) {
+            (GET_REPORT, Some(report_type)) if report_type != ReportType::Output => {
+                CtrlState::GetReport(report_type, setup.value as u8, setup.length as usize)
+            }
+            (SET_REPORT, Some(report_type)) if report_type != ReportType::Input => {
+                CtrlState::SetReport(report_type)
+            }
+            (GET_IDLE, _) => CtrlState::GetIdle,
+            (SET_IDLE, _) => {
+                self.idle.set((setup.value >> 8) as u8);
+                CtrlState::Idle
+            }
+            (GET_PROTOCOL, _) if self.boot != BootInterface::None => CtrlState::GetProtocol,
+            (SET_PROTOCOL, _) if self.boot != BootInterface::None => {
+                let protocol = match setup.value {
+                    0 => Protocol::Boot,
+                    _ => Protocol::Report,
+                };
+                if self.protocol.replace(protocol) != protocol {
+                    self.client.map(|client| client.protocol_changed(protocol));
+                }
+                CtrlState::Idle
+            }
+            _ => return hil::usb::CtrlSetupResult::ErrGeneric,
+        };
+        self.ctrl_state.set(state);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Send the data of a class request to the host.
+    fn class_in(&self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetReport(report_type, report_id, requested_length) => {
+                let mut report = [0; MAX_REPORT_SIZE];
+                self.client
+                    .map(|client| client.get_report(report_type, report_id, &mut report))
+                    .flatten()
+                    .map_or(hil::usb::CtrlInResult::Error, |len| {
+                        let len = cmp::min(cmp::min(len, requested_length), buf.len());
+                        for i in 0..len {
+                            buf[i].set(report[i]);
+                        }
+                        hil::usb::CtrlInResult::Packet(len, true)
+                    })
+            }
+            CtrlState::GetIdle => {
+                buf[0].set(self.idle.get());
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::GetProtocol => {
+                buf[0].set(self.protocol.get() as u8);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Idle | CtrlState::SetReport(_) => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Receive the data of a SET_REPORT request from the host.
+    fn class_out(&self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::SetReport(report_type) => {
+                self.receive_report(report_type, buf, packet_bytes as usize);
+                hil::usb::CtrlOutResult::Ok
+            }
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn class_complete(&self) {
+        self.ctrl_state.set(CtrlState::Idle);
+
+        // A report may have been queued while the control endpoint was busy.
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(self.endpoint.get());
+        }
+    }
+
+    fn receive_report(&self, report_type: ReportType, packet: &[VolatileCell<u8>], len: usize) {
+        let mut report = [0; MAX_REPORT_SIZE];
+        let len = cmp::min(cmp::min(len, packet.len()), MAX_REPORT_SIZE);
+        for i in 0..len {
+            report[i] = packet[i].get();
+        }
+        self.client
+            .map(|client| client.report_received(report_type, &report[..len]));
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Hid<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request =
+            descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).filter(|setup| {
+                match setup.request_type.request_type() {
+                    RequestType::Class => true,
+                    _ => false,
+                }
+            });
+        match class_request {
+            Some(setup) => self.handle_class_request(setup),
+            None => {
+                self.ctrl_state.set(CtrlState::Idle);
+                self.client_ctrl.ctrl_setup(endpoint)
+            }
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::Idle => self.client_ctrl.ctrl_in(endpoint),
+            _ => self.class_in(&self.client_ctrl.ctrl_buffer.buf),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::Idle => self.client_ctrl.ctrl_out(endpoint, packet_bytes),
+            _ => self.class_out(&self.client_ctrl.ctrl_buffer.buf, packet_bytes),
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.class_complete();
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle an Interrupt IN transaction: send the pending input report.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                self.send_buffer
+                    .take()
+                    .map_or(hil::usb::InResult::Delay, |report| {
+                        let packet = &self.buffers[IN_BUFFER].buf;
+                        let len = self.send_len.get();
+                        for i in 0..len {
+                            packet[i].set(report[i]);
+                        }
+
+                        // Keep the report until it has been transmitted.
+                        self.send_buffer.replace(report);
+                        hil::usb::InResult::Packet(len)
+                    })
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    /// Handle an Interrupt OUT transaction: pass the output report to the
+    /// client.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                self.receive_report(
+                    ReportType::Output,
+                    &self.buffers[OUT_BUFFER].buf,
+                    packet_bytes as usize,
+                );
+                hil::usb::OutResult::Ok
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        self.send_buffer.take().map(|report| {
+            self.client
+                .map(move |client| client.report_sent(report, ReturnCode::SUCCESS));
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Function<'a> for Hid<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn endpoint_count(&self) -> usize {
+        1
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        let (subclass, protocol) = self.boot.codes();
+        (0x03, subclass, protocol)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        self.interface.set(first_interface);
+        self.endpoint.set(first_endpoint);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        let (subclass, protocol) = self.boot.codes();
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: self.interface.get(),
+                    num_endpoints: 2,
+                    interface_class: 0x03, // HID
+                    interface_subclass: subclass,
+                    interface_protocol: protocol,
+                    ..InterfaceDescriptor::default()
+                },
+                self.hid_descriptor,
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: MAX_REPORT_SIZE as u16,
+                    interval: INTERVAL,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: MAX_REPORT_SIZE as u16,
+                    interval: INTERVAL,
+                },
+            ],
+        )
+    }
+
+    fn write_class_descriptor(&self, descriptor_type: DescriptorType, buf: &[Cell<u8>]) -> usize {
+        match descriptor_type {
+            DescriptorType::HID => self.hid_descriptor.write_to(buf),
+            DescriptorType::Report => self.report_descriptor.write_to(buf),
+            _ => 0,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        self.handle_class_request(setup)
+    }
+
+    fn ctrl_in(&'a self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        self.class_in(buf)
+    }
+
+    fn ctrl_out(&'a self, buf: &[VolatileCell<u8>], packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.class_out(buf, packet_bytes)
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.class_complete();
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::super::sim::{setup, SimUsbController, TransferError};
+    use super::{
+        BootInterface, Hid, HidClient, Protocol, ReportType, KEYBOARD_HID_DESCRIPTOR,
+        KEYBOARD_REPORT, KEYBOARD_REPORT_DESCRIPTOR, MOUSE_HID_DESCRIPTOR, MOUSE_REPORT,
+    };
+    use kernel::hil::usb::{Client, UsbController};
+    use kernel::ReturnCode;
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "Keyboard", "1"];
+
+    const ENDPOINT: usize = 1;
+
+    const GET_REPORT: u8 = 0x01;
+    const GET_IDLE: u8 = 0x02;
+    const GET_PROTOCOL: u8 = 0x03;
+    const SET_REPORT: u8 = 0x09;
+    const SET_IDLE: u8 = 0x0a;
+    const SET_PROTOCOL: u8 = 0x0b;
+
+    /// The input report GET_REPORT is answered with: the A key pressed.
+    const INPUT_REPORT: [u8; 8] = [0, 0, 0x04, 0, 0, 0, 0, 0];
+    /// The only feature report, with ID 5.
+    const FEATURE_REPORT: [u8; 3] = [5, 0xaa, 0xbb];
+
+    struct TestClient {
+        received: RefCell<Vec<(ReportType, Vec<u8>)>>,
+        protocols: RefCell<Vec<Protocol>>,
+        sent: Cell<Option<ReturnCode>>,
+    }
+
+    impl HidClient for TestClient {
+        fn report_sent(&self, _report: &'static mut [u8], result: ReturnCode) {
+            self.sent.set(Some(result));
+        }
+
+        fn report_received(&self, report_type: ReportType, report: &[u8]) {
+            self.received
+                .borrow_mut()
+                .push((report_type, report.to_vec()));
+        }
+
+        fn get_report(
+            &self,
+            report_type: ReportType,
+            report_id: u8,
+            buf: &mut [u8],
+        ) -> Option<usize> {
+            let report: &[u8] = match (report_type, report_id) {
+                (ReportType::Input, 0) => &INPUT_REPORT,
+                (ReportType::Feature, 5) => &FEATURE_REPORT,
+                _ => return None,
+            };
+            buf[..report.len()].copy_from_slice(report);
+            Some(report.len())
+        }
+
+        fn protocol_changed(&self, protocol: Protocol) {
+            self.protocols.borrow_mut().push(protocol);
+        }
+    }
+
+    type TestHid<'a> = Hid<'a, SimUsbController<'a>>;
+
+    /// Build a HID device on a simulated controller and enumerate it.
+    fn with_hid(boot: BootInterface, test: impl FnOnce(&SimUsbController, &TestHid, &TestClient)) {
+        let usb = SimUsbController::new();
+        let (hid_descriptor, report_descriptor) = match boot {
+            BootInterface::Mouse => (&MOUSE_HID_DESCRIPTOR, &MOUSE_REPORT),
+            _ => (&KEYBOARD_HID_DESCRIPTOR, &KEYBOARD_REPORT),
+        };
+        let hid = Hid::new(
+            &usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            boot,
+            hid_descriptor,
+            report_descriptor,
+        );
+        let client = TestClient {
+            received: RefCell::new(Vec::new()),
+            protocols: RefCell::new(Vec::new()),
+            sent: Cell::new(None),
+        };
+        usb.set_client(&hid);
+        hid.set_client(&client);
+        hid.enable();
+        hid.attach();
+        usb.enumerate();
+
+        test(&usb, &hid, &client);
+    }
+
+    #[test]
+    fn descriptors() {
+        with_hid(BootInterface::Keyboard, |usb, _, _| {
+            let configuration = usb.get_descriptor(2, 0, 255).unwrap();
+            // A boot keyboard interface with an interrupt IN and OUT endpoint
+            assert_eq!(&configuration[9..18], &[9, 4, 0, 0, 2, 3, 1, 1, 0]);
+            assert_eq!(configuration[18 + 1], 0x21);
+            assert_eq!(&configuration[27..31], &[7, 5, 0x81, 3]);
+            assert_eq!(&configuration[34..38], &[7, 5, 0x01, 3]);
+
+            let length = KEYBOARD_REPORT_DESCRIPTOR.len() as u16;
+            let report = usb.control_in(setup(0x81, 6, 0x2200, 0, length));
+            assert_eq!(report, Ok(KEYBOARD_REPORT_DESCRIPTOR.to_vec()));
+        });
+        with_hid(BootInterface::Mouse, |usb, _, _| {
+            let configuration = usb.get_descriptor(2, 0, 255).unwrap();
+            assert_eq!(&configuration[9..18], &[9, 4, 0, 0, 2, 3, 1, 2, 0]);
+        });
+    }
+
+    #[test]
+    fn get_report() {
+        with_hid(BootInterface::Keyboard, |usb, _, _| {
+            let input = usb.control_in(setup(0xa1, GET_REPORT, 0x0100, 0, 64));
+            assert_eq!(input, Ok(INPUT_REPORT.to_vec()));
+            // Truncated to the length the host asked for
+            let input = usb.control_in(setup(0xa1, GET_REPORT, 0x0100, 0, 3));
+            assert_eq!(input, Ok(INPUT_REPORT[..3].to_vec()));
+
+            let feature = usb.control_in(setup(0xa1, GET_REPORT, 0x0305, 0, 64));
+            assert_eq!(feature, Ok(FEATURE_REPORT.to_vec()));
+
+            // A report the client refuses, an output report, and an unknown
+            // report type
+            let unknown_id = usb.control_in(setup(0xa1, GET_REPORT, 0x0306, 0, 64));
+            assert_eq!(unknown_id, Err(TransferError::Stall));
+            let output = usb.control_in(setup(0xa1, GET_REPORT, 0x0200, 0, 64));
+            assert_eq!(output, Err(TransferError::Stall));
+            let reserved = usb.control_in(setup(0xa1, GET_REPORT, 0x0400, 0, 64));
+            assert_eq!(reserved, Err(TransferError::Stall));
+
+            // The control endpoint still answers standard requests
+            assert!(usb.get_descriptor(1, 0, 18).is_ok());
+        });
+    }
+
+    #[test]
+    fn set_report() {
+        with_hid(BootInterface::Keyboard, |usb, _, client| {
+            // Caps Lock on, by SET_REPORT and over the OUT endpoint
+            usb.control_out(setup(0x21, SET_REPORT, 0x0200, 0, 1), &[0x02])
+                .unwrap();
+            usb.transfer_out(ENDPOINT, &[0x00]).unwrap();
+            usb.control_out(setup(0x21, SET_REPORT, 0x0305, 0, 3), &[5, 1, 2])
+                .unwrap();
+            assert_eq!(
+                *client.received.borrow(),
+                [
+                    (ReportType::Output, std::vec![0x02]),
+                    (ReportType::Output, std::vec![0x00]),
+                    (ReportType::Feature, std::vec![5, 1, 2]),
+                ]
+            );
+
+            // Input reports cannot be set
+            let input = usb.control_out(setup(0x21, SET_REPORT, 0x0100, 0, 1), &[0]);
+            assert_eq!(input, Err(TransferError::Stall));
+            assert_eq!(client.received.borrow().len(), 3);
+        });
+    }
+
+    #[test]
+    fn idle_rate() {
+        with_hid(BootInterface::Keyboard, |usb, _, _| {
+            assert_eq!(
+                usb.control_in(setup(0xa1, GET_IDLE, 0, 0, 1)),
+                Ok(std::vec![0])
+            );
+            // 500 ms, for all reports
+            usb.control_out(setup(0x21, SET_IDLE, 0x7d00, 0, 0), &[])
+                .unwrap();
+            assert_eq!(
+                usb.control_in(setup(0xa1, GET_IDLE, 0, 0, 1)),
+                Ok(std::vec![0x7d])
+            );
+
+            // A bus reset restores the default
+            usb.bus_reset();
+            assert_eq!(
+                usb.control_in(setup(0xa1, GET_IDLE, 0, 0, 1)),
+                Ok(std::vec![0])
+            );
+        });
+    }
+
+    #[test]
+    fn boot_protocol() {
+        with_hid(BootInterface::Keyboard, |usb, hid, client| {
+            let get_protocol = setup(0xa1, GET_PROTOCOL, 0, 0, 1);
+            assert_eq!(usb.control_in(get_protocol), Ok(std::vec![1]));
+            assert_eq!(hid.protocol(), Protocol::Report);
+
+            usb.control_out(setup(0x21, SET_PROTOCOL, 0, 0, 0), &[])
+                .unwrap();
+            assert_eq!(usb.control_in(get_protocol), Ok(std::vec![0]));
+            assert_eq!(hid.protocol(), Protocol::Boot);
+
+            // Selecting the same protocol again is not a change
+            usb.control_out(setup(0x21, SET_PROTOCOL, 0, 0, 0), &[])
+                .unwrap();
+            assert_eq!(*client.protocols.borrow(), [Protocol::Boot]);
+
+            usb.control_out(setup(0x21, SET_PROTOCOL, 1, 0, 0), &[])
+                .unwrap();
+            assert_eq!(hid.protocol(), Protocol::Report);
+
+            // A bus reset returns to the report protocol, and tells the
+            // client
+            usb.control_out(setup(0x21, SET_PROTOCOL, 0, 0, 0), &[])
+                .unwrap();
+            usb.bus_reset();
+            assert_eq!(hid.protocol(), Protocol::Report);
+            assert_eq!(
+                *client.protocols.borrow(),
+                [
+                    Protocol::Boot,
+                    Protocol::Report,
+                    Protocol::Boot,
+                    Protocol::Report
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn no_boot_interface() {
+        with_hid(BootInterface::None, |usb, hid, client| {
+            let get_protocol = usb.control_in(setup(0xa1, GET_PROTOCOL, 0, 0, 1));
+            assert_eq!(get_protocol, Err(TransferError::Stall));
+            let set_protocol = usb.control_out(setup(0x21, SET_PROTOCOL, 0, 0, 0), &[]);
+            assert_eq!(set_protocol, Err(TransferError::Stall));
+            assert_eq!(hid.protocol(), Protocol::Report);
+            assert!(client.protocols.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn send_report() {
+        with_hid(BootInterface::Keyboard, |usb, hid, client| {
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+
+            let report: &'static mut [u8] = Box::leak(Box::new(INPUT_REPORT));
+            assert!(hid.send_report(report, 8).is_ok());
+            let other: &'static mut [u8] = Box::leak(Box::new([0; 65]));
+            let other = match hid.send_report(other, 8) {
+                Err((ReturnCode::EBUSY, other)) => other,
+                _ => panic!("second report accepted"),
+            };
+            assert_eq!(client.sent.get(), None);
+
+            assert_eq!(usb.transfer_in(ENDPOINT), Ok(INPUT_REPORT.to_vec()));
+            assert_eq!(client.sent.get(), Some(ReturnCode::SUCCESS));
+            assert_eq!(usb.transfer_in(ENDPOINT), Err(TransferError::Nak));
+
+            // Reports longer than a packet
+            match hid.send_report(other, 65) {
+                Err((ReturnCode::ESIZE, _)) => {}
+                _ => panic!("oversized report accepted"),
+            }
+        });
+    }
+}
@@ -0,0 +1,288 @@
+//! System call interface to a USB HID device
+//!
+//! Lets an application act as a keyboard, a mouse or a custom HID device
+//! whose report descriptor is given by the board. The first application to
+//! use the driver owns the device until it exits.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! let hid_driver = static_init!(
+//!     capsules::usb::hid_driver::HidDriver<'static, nrf52::usbd::Usbd<'static>>,
+//!     capsules::usb::hid_driver::HidDriver::new(
+//!         hid,
+//!         &mut capsules::usb::hid_driver::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! hid.set_client(hid_driver);
+//! ```
+//!
+//! ## Userspace interface
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer for output and feature reports from the host.
+//! - `1`: Input report to send. It also answers GET_REPORT requests for
+//!   input reports with the last report sent.
+//! - `2`: Feature report, answering GET_REPORT requests for feature reports.
+//!
+//! If the report descriptor declares report IDs, each report starts with its
+//! ID, and GET_REPORT requests are only answered with a report of the ID the
+//! host asked for.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Events, `fn(event, arg1, arg2)`:
+//!   - `fn(0, report_type, length)`: a report of `length` bytes was copied to
+//!     the receive buffer. `report_type` is 2 for output and 3 for feature
+//!     reports.
+//!   - `fn(1, result, 0)`: the input report was sent.
+//!   - `fn(2, protocol, 0)`: the host selected the boot (0) or report (1)
+//!     protocol.
+//!
+//! ### Command
+//!
+//! - `0`: Check that the driver exists.
+//! - `1`: Send the first `data1` bytes of the input report buffer.
+//! - `2`: Get the protocol selected by the host.
+
+use core::cmp;
+
+use super::hid::{Hid, HidClient, Protocol, ReportType, MAX_REPORT_SIZE};
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbHid as usize;
+
+pub static mut BUFFER: [u8; MAX_REPORT_SIZE] = [0; MAX_REPORT_SIZE];
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    recv_buf: Option<AppSlice<Shared, u8>>,
+    send_buf: Option<AppSlice<Shared, u8>>,
+    feature_buf: Option<AppSlice<Shared, u8>>,
+    /// Length of the last input report sent.
+    sent_len: usize,
+}
+
+pub struct HidDriver<'a, U: 'a + hil::usb::UsbController<'a>> {
+    hid: &'a Hid<'a, U>,
+    apps: Grant<App>,
+    appid: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> HidDriver<'a, U> {
+    pub fn new(
+        hid: &'a Hid<'a, U>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> HidDriver<'a, U> {
+        HidDriver {
+            hid: hid,
+            apps: grant,
+            appid: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    fn send_report(&self, app: &mut App, len: usize) -> ReturnCode {
+        let data = match app.send_buf {
+            Some(ref slice) => slice,
+            None => return ReturnCode::ERESERVE,
+        };
+        if len > data.len() {
+            return ReturnCode::ESIZE;
+        }
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EBUSY,
+        };
+        if len > buffer.len() {
+            self.buffer.replace(buffer);
+            return ReturnCode::ESIZE;
+        }
+        buffer[..len].copy_from_slice(&data.as_ref()[..len]);
+        match self.hid.send_report(buffer, len) {
+            Ok(()) => {
+                app.sent_len = len;
+                ReturnCode::SUCCESS
+            }
+            Err((err, buffer)) => {
+                self.buffer.replace(buffer);
+                err
+            }
+        }
+    }
+}
+
+/// Copies `report` to `buf` to answer a GET_REPORT request for `report_id`,
+/// returning its length. Returns None if there is no report, or if the host
+/// asked for a report ID and `report` starts with another one.
+fn copy_report(report: &[u8], report_id: u8, buf: &mut [u8]) -> Option<usize> {
+    if report.is_empty() || (report_id != 0 && report[0] != report_id) {
+        return None;
+    }
+    let len = cmp::min(report.len(), buf.len());
+    buf[..len].copy_from_slice(&report[..len]);
+    Some(len)
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> HidClient for HidDriver<'a, U> {
+    fn report_sent(&self, report: &'static mut [u8], result: ReturnCode) {
+        self.buffer.replace(report);
+        self.appid.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(1, usize::from(result), 0));
+            });
+        });
+    }
+
+    fn report_received(&self, report_type: ReportType, report: &[u8]) {
+        self.appid.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                let len = app.recv_buf.as_mut().map_or(0, |dest| {
+                    let len = cmp::min(dest.len(), report.len());
+                    dest.as_mut()[..len].copy_from_slice(&report[..len]);
+                    len
+                });
+                app.callback
+                    .map(|mut cb| cb.schedule(0, report_type as usize, len));
+            });
+        });
+    }
+
+    fn get_report(&self, report_type: ReportType, report_id: u8, buf: &mut [u8]) -> Option<usize> {
+        self.appid.and_then(|appid| {
+            self.apps
+                .enter(appid, |app, _| {
+                    let (slice, len) = match report_type {
+                        ReportType::Input => (app.send_buf.as_ref(), app.sent_len),
+                        ReportType::Feature => (
+                            app.feature_buf.as_ref(),
+                            app.feature_buf.as_ref().map_or(0, |slice| slice.len()),
+                        ),
+                        ReportType::Output => (None, 0),
+                    };
+                    slice.and_then(|slice| {
+                        copy_report(
+                            &slice.as_ref()[..cmp::min(len, slice.len())],
+                            report_id,
+                            buf,
+                        )
+                    })
+                })
+                .unwrap_or(None)
+        })
+    }
+
+    fn protocol_changed(&self, protocol: Protocol) {
+        self.appid.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(2, protocol as usize, 0));
+            });
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Driver for HidDriver<'a, U> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.recv_buf = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.send_buf = slice;
+                    app.sent_len = 0;
+                    ReturnCode::SUCCESS
+                }
+                2 => {
+                    app.feature_buf = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+
+        // The first application to use the device owns it, as long as it is
+        // alive.
+        let owned_by_other = self.appid.map_or(false, |owner| {
+            *owner != appid && self.apps.enter(*owner, |_, _| ()).is_ok()
+        });
+        if owned_by_other {
+            return ReturnCode::EBUSY;
+        }
+        self.appid.set(appid);
+
+        match command_num {
+            1 => self
+                .apps
+                .enter(appid, |app, _| self.send_report(app, data1))
+                .unwrap_or_else(|err| err.into()),
+            2 => ReturnCode::SuccessWithValue {
+                value: self.hid.protocol() as usize,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::copy_report;
+
+    #[test]
+    fn report_ids() {
+        let mut buf = [0; 4];
+        // Without report IDs the whole report is returned, up to the
+        // buffer size
+        assert_eq!(copy_report(&[1, 2, 3], 0, &mut buf), Some(3));
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(copy_report(&[9; 6], 0, &mut buf), Some(4));
+        assert_eq!(copy_report(&[], 0, &mut buf), None);
+
+        // With report IDs the first byte must match
+        assert_eq!(copy_report(&[5, 0xaa], 5, &mut buf), Some(2));
+        assert_eq!(&buf[..2], &[5, 0xaa]);
+        assert_eq!(copy_report(&[5, 0xaa], 6, &mut buf), None);
+        assert_eq!(copy_report(&[], 5, &mut buf), None);
+    }
+}
@@ -1,6 +1,14 @@
 pub mod cdc;
+pub mod composite;
 pub mod ctap;
 pub mod descriptors;
+pub mod dfu;
+pub mod ecm;
+pub mod hid;
+pub mod hid_driver;
+pub mod msc;
+#[cfg(test)]
+pub(crate) mod sim;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
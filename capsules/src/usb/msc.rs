@@ -0,0 +1,1596 @@
+//! USB Mass Storage Class device (Bulk-Only Transport, SCSI transparent
+//! command set).
+//!
+//! Exposes a block device to the host as a removable USB drive with a
+//! single logical unit. Storage is accessed one 512-byte block at a time
+//! through the [BlockStorage](trait.BlockStorage.html) trait, which is
+//! implemented here for a region of a
+//! [NonvolatileStorage](../../../kernel/hil/nonvolatile_storage/trait.NonvolatileStorage.html)
+//! and for an [SD card](../../sdcard/struct.SDCard.html).
+//!
+//! The USB HIL cannot stall bulk endpoints, so when a command fails or the
+//! host asks for more data than the command produces, the device pads the
+//! data-in phase with zeros or discards the data-out phase, and reports the
+//! failure and the residue in the command status. Responses shorter than the
+//! host asked for end with a short packet.
+//!
+//! Based on the USB Mass Storage Class Bulk-Only Transport specification
+//! revision 1.0 and the SCSI Primary and Block Commands.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let blocks = static_init!(
+//!     capsules::usb::msc::NonvolatileBlocks<'static>,
+//!     capsules::usb::msc::NonvolatileBlocks::new(
+//!         nv_to_page,
+//!         0x80000,
+//!         0x40000,
+//!         &mut capsules::usb::msc::STORAGE_BUFFER
+//!     )
+//! );
+//! nv_to_page.set_client(blocks);
+//! let msc = static_init!(
+//!     capsules::usb::msc::MassStorage<'static, nrf52::usbd::Usbd<'static>>,
+//!     capsules::usb::msc::MassStorage::new(
+//!         &nrf52::usbd::USBD,
+//!         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521f,
+//!         STRINGS,
+//!         blocks,
+//!         &mut capsules::usb::msc::BLOCK_BUFFER
+//!     )
+//! );
+//! blocks.set_client(msc);
+//! nrf52::usbd::USBD.set_client(msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::Function;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+use crate::sdcard::{SDCard, SDCardClient};
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Size of the blocks exposed to the host.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Buffer for the block being transferred to or from the host.
+pub static mut BLOCK_BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// Buffer for the block storage adapters to pass to the underlying storage.
+pub static mut STORAGE_BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// Storage exposed by the mass storage device, accessed in blocks of
+/// `BLOCK_SIZE` bytes.
+pub trait BlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient);
+
+    /// Number of blocks of the storage, or 0 if there is no medium.
+    fn block_count(&self) -> u32;
+
+    /// Read block `block` into `buffer`. On error the buffer is returned.
+    fn read_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Write `buffer` to block `block`. On error the buffer is returned.
+    fn write_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+/// Client interface for block storage.
+pub trait BlockStorageClient {
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+/// Bulk IN endpoint, unless a composite device assigns another one.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Bulk OUT endpoint, unless a composite device assigns another one.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+const IN_BUFFER: usize = 0;
+const OUT_BUFFER: usize = 1;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Bulk-Only Mass Storage Reset class request.
+const REQUEST_RESET: u8 = 0xff;
+/// Get Max LUN class request.
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LEN: usize = 13;
+
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+
+// SCSI operation codes
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+
+/// SCSI sense key and additional sense code.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Sense(u8, u8);
+
+const NO_SENSE: Sense = Sense(0x00, 0x00);
+const LOGICAL_UNIT_NOT_READY: Sense = Sense(0x02, 0x04);
+const MEDIUM_NOT_PRESENT: Sense = Sense(0x02, 0x3a);
+const WRITE_ERROR: Sense = Sense(0x03, 0x0c);
+const UNRECOVERED_READ_ERROR: Sense = Sense(0x03, 0x11);
+const INVALID_COMMAND: Sense = Sense(0x05, 0x20);
+const LBA_OUT_OF_RANGE: Sense = Sense(0x05, 0x21);
+const INVALID_FIELD_IN_CDB: Sense = Sense(0x05, 0x24);
+const MEDIUM_CHANGED: Sense = Sense(0x06, 0x28);
+
+/// Longest response to a command other than READ(10).
+const RESPONSE_LEN: usize = 36;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Sending the response in `response`.
+    ResponseIn,
+    /// Sending blocks read from storage.
+    ReadIn,
+    /// Receiving blocks to write to storage.
+    WriteOut,
+    /// Padding the data-in phase of a command that has no (more) data.
+    PadIn,
+    /// Discarding the data-out phase of a command that takes no (more) data.
+    DiscardOut,
+    /// Sending the command status wrapper.
+    Status,
+}
+
+pub struct MassStorage<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; 2],
+
+    storage: &'a dyn BlockStorage<'a>,
+
+    /// The block being transferred. Empty while storage is reading or
+    /// writing it.
+    block: TakeCell<'static, [u8]>,
+    /// Whether `block` holds the data of the block being read.
+    block_ready: Cell<bool>,
+    /// Offset of the next byte to transfer in `block`.
+    block_offset: Cell<usize>,
+    /// Block being read or written.
+    lba: Cell<u32>,
+    /// Blocks left to read or write, including `lba`.
+    blocks_left: Cell<u32>,
+    /// A storage operation was abandoned by a reset, so its completion must
+    /// be ignored.
+    abandoned: Cell<bool>,
+
+    state: Cell<State>,
+    /// Tag of the current command, echoed in its status.
+    tag: Cell<u32>,
+    /// Whether the data phase of the current command is to the host.
+    data_in: Cell<bool>,
+    /// Bytes left in the data phase of the current command.
+    remaining: Cell<u32>,
+    /// Bytes of the data phase the command did not process.
+    residue: Cell<u32>,
+    failed: Cell<bool>,
+    sense: Cell<Sense>,
+    /// Whether there was a medium at the last command that needed one, to
+    /// tell the host when it changes.
+    medium_present: Cell<bool>,
+
+    response: Cell<[u8; RESPONSE_LEN]>,
+    /// The current control transfer is a Get Max LUN request.
+    get_max_lun: Cell<bool>,
+
+    /// Vendor and product identification for INQUIRY.
+    vendor: &'static str,
+    product: &'static str,
+
+    /// Interface and endpoint numbers, which a composite device can change.
+    interface: Cell<u8>,
+    endpoint_in: Cell<usize>,
+    endpoint_out: Cell<usize>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MassStorage<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a dyn BlockStorage<'a>,
+        block: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            num_endpoints: 2,
+            interface_class: 0x08,    // Mass Storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-Only Transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x00, // Class defined by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+            );
+
+        MassStorage {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            storage: storage,
+            block: TakeCell::new(block),
+            block_ready: Cell::new(false),
+            block_offset: Cell::new(0),
+            lba: Cell::new(0),
+            blocks_left: Cell::new(0),
+            abandoned: Cell::new(false),
+            state: Cell::new(State::Command),
+            tag: Cell::new(0),
+            data_in: Cell::new(false),
+            remaining: Cell::new(0),
+            residue: Cell::new(0),
+            failed: Cell::new(false),
+            sense: Cell::new(NO_SENSE),
+            medium_present: Cell::new(false),
+            response: Cell::new([0; RESPONSE_LEN]),
+            get_max_lun: Cell::new(false),
+            vendor: strings[0],
+            product: strings[1],
+            interface: Cell::new(0),
+            endpoint_in: Cell::new(ENDPOINT_IN_NUM),
+            endpoint_out: Cell::new(ENDPOINT_OUT_NUM),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Abandon the current command and wait for the next one.
+    fn reset(&self) {
+        if self.block.is_none() {
+            self.abandoned.set(true);
+        }
+        self.state.set(State::Command);
+        self.get_max_lun.set(false);
+    }
+
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint_in.get(), &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint_in.get());
+
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint_out.get(), &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint_out.get());
+    }
+
+    /// Parse the command block wrapper in the OUT buffer and start the
+    /// command. Returns false if it is not a valid command block wrapper.
+    fn receive_command(&self, len: usize) -> bool {
+        let packet = &self.buffers[OUT_BUFFER].buf;
+        let word = |i: usize| {
+            u32::from_le_bytes([
+                packet[i].get(),
+                packet[i + 1].get(),
+                packet[i + 2].get(),
+                packet[i + 3].get(),
+            ])
+        };
+        if len != CBW_LEN || word(0) != CBW_SIGNATURE {
+            return false;
+        }
+
+        let mut cb = [0; 16];
+        for (i, byte) in cb.iter_mut().enumerate() {
+            *byte = packet[15 + i].get();
+        }
+
+        self.tag.set(word(4));
+        self.remaining.set(word(8));
+        self.residue.set(0);
+        self.data_in.set(packet[12].get() & 0x80 != 0);
+        self.failed.set(false);
+        if packet[13].get() != 0 {
+            // There is only one logical unit.
+            self.fail(INVALID_FIELD_IN_CDB);
+        } else {
+            self.execute(&cb);
+        }
+
+        match self.state.get() {
+            State::ResponseIn | State::ReadIn | State::PadIn | State::Status => {
+                self.controller().endpoint_resume_in(self.endpoint_in.get());
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn execute(&self, cb: &[u8; 16]) {
+        match cb[0] {
+            TEST_UNIT_READY => {
+                if self.check_medium().is_some() {
+                    self.succeed();
+                }
+            }
+            REQUEST_SENSE => {
+                let Sense(key, asc) = self.sense.replace(NO_SENSE);
+                let mut sense = [0; 18];
+                sense[0] = 0x70; // Current error, fixed format
+                sense[2] = key;
+                sense[7] = 10; // Additional sense length
+                sense[12] = asc;
+                self.respond(&sense);
+            }
+            INQUIRY => {
+                if cb[1] & 0x01 != 0 {
+                    // No vital product data pages
+                    self.fail(INVALID_FIELD_IN_CDB);
+                    return;
+                }
+                let mut inquiry = [b' '; RESPONSE_LEN];
+                inquiry[0] = 0x00; // Direct access block device
+                inquiry[1] = 0x80; // Removable medium
+                inquiry[2] = 0x04; // SPC-2
+                inquiry[3] = 0x02; // Response data format
+                inquiry[4] = (RESPONSE_LEN - 5) as u8;
+                inquiry[5] = 0;
+                inquiry[6] = 0;
+                inquiry[7] = 0;
+                copy_text(&mut inquiry[8..16], self.vendor);
+                copy_text(&mut inquiry[16..32], self.product);
+                copy_text(&mut inquiry[32..36], "1.0");
+                self.respond(&inquiry);
+            }
+            MODE_SENSE_6 => {
+                // Mode data length, medium type, device-specific parameter
+                // (not write-protected) and no block descriptors.
+                self.respond(&[3, 0, 0, 0]);
+            }
+            MODE_SENSE_10 => {
+                self.respond(&[0, 6, 0, 0, 0, 0, 0, 0]);
+            }
+            READ_FORMAT_CAPACITIES => {
+                if let Some(blocks) = self.check_medium() {
+                    let mut capacities = [0; 12];
+                    capacities[3] = 8; // Capacity list length
+                    capacities[4..8].copy_from_slice(&blocks.to_be_bytes());
+                    capacities[8] = 0x02; // Formatted media
+                    capacities[9..12].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                    self.respond(&capacities);
+                }
+            }
+            READ_CAPACITY_10 => {
+                if let Some(blocks) = self.check_medium() {
+                    let mut capacity = [0; 8];
+                    capacity[0..4].copy_from_slice(&(blocks - 1).to_be_bytes());
+                    capacity[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                    self.respond(&capacity);
+                }
+            }
+            READ_10 | WRITE_10 => {
+                let lba = u32::from_be_bytes([cb[2], cb[3], cb[4], cb[5]]);
+                let count = u16::from_be_bytes([cb[7], cb[8]]) as u32;
+                self.start_transfer(cb[0] == READ_10, lba, count);
+            }
+            VERIFY_10 | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL | SYNCHRONIZE_CACHE_10 => {
+                // Writes reach the storage before their status is sent, and
+                // the medium cannot be ejected or locked.
+                self.succeed();
+            }
+            _ => self.fail(INVALID_COMMAND),
+        }
+    }
+
+    /// The number of blocks of the medium, or None after failing the
+    /// command if there is no medium or it changed since the last command
+    /// that needed it.
+    fn check_medium(&self) -> Option<u32> {
+        let blocks = self.storage.block_count();
+        let was_present = self.medium_present.replace(blocks > 0);
+        if blocks == 0 {
+            self.fail(MEDIUM_NOT_PRESENT);
+            None
+        } else if !was_present {
+            self.fail(MEDIUM_CHANGED);
+            None
+        } else {
+            Some(blocks)
+        }
+    }
+
+    /// Start READ(10) or WRITE(10) of `count` blocks from `lba`.
+    fn start_transfer(&self, read: bool, lba: u32, count: u32) {
+        let blocks = match self.check_medium() {
+            Some(blocks) => blocks,
+            None => return,
+        };
+        if lba as u64 + count as u64 > blocks as u64 {
+            self.fail(LBA_OUT_OF_RANGE);
+            return;
+        }
+        if self.remaining.get() as u64 != count as u64 * BLOCK_SIZE as u64
+            || (count > 0 && self.data_in.get() != read)
+        {
+            self.fail(INVALID_FIELD_IN_CDB);
+            return;
+        }
+        if count == 0 {
+            self.succeed();
+            return;
+        }
+        if self.block.is_none() {
+            // An abandoned operation still holds the buffer.
+            self.fail(LOGICAL_UNIT_NOT_READY);
+            return;
+        }
+
+        self.lba.set(lba);
+        self.blocks_left.set(count);
+        self.block_offset.set(0);
+        if read {
+            self.state.set(State::ReadIn);
+            self.read_block();
+        } else {
+            self.state.set(State::WriteOut);
+        }
+    }
+
+    fn read_block(&self) {
+        self.block_ready.set(false);
+        self.block.take().map(|block| {
+            if let Err((_, block)) = self.storage.read_block(block, self.lba.get()) {
+                self.block.replace(block);
+                self.fail(UNRECOVERED_READ_ERROR);
+            }
+        });
+    }
+
+    fn write_block(&self) -> hil::usb::OutResult {
+        self.block
+            .take()
+            .map_or(hil::usb::OutResult::Error, |block| {
+                match self.storage.write_block(block, self.lba.get()) {
+                    // Hold off the host until the block is written.
+                    Ok(()) => hil::usb::OutResult::Delay,
+                    Err((_, block)) => {
+                        self.block.replace(block);
+                        self.fail(WRITE_ERROR);
+                        self.resume_in_if_status();
+                        hil::usb::OutResult::Ok
+                    }
+                }
+            })
+    }
+
+    /// Send `data` as the response to the current command.
+    fn respond(&self, data: &[u8]) {
+        if !self.data_in.get() && self.remaining.get() > 0 {
+            self.fail(INVALID_FIELD_IN_CDB);
+            return;
+        }
+        let len = cmp::min(data.len(), self.remaining.get() as usize);
+        let mut response = [0; RESPONSE_LEN];
+        response[..len].copy_from_slice(&data[..len]);
+        self.response.set(response);
+        self.residue.set(self.remaining.get() - len as u32);
+        self.remaining.set(len as u32);
+        self.state.set(if len == 0 {
+            State::Status
+        } else {
+            State::ResponseIn
+        });
+    }
+
+    /// Complete the current command, which has no data.
+    fn succeed(&self) {
+        self.skip_data();
+    }
+
+    /// Fail the current command and skip the rest of its data phase.
+    fn fail(&self, sense: Sense) {
+        self.sense.set(sense);
+        self.failed.set(true);
+        self.skip_data();
+    }
+
+    fn skip_data(&self) {
+        let remaining = self.remaining.get();
+        self.residue.set(remaining);
+        self.state.set(if remaining == 0 {
+            State::Status
+        } else if self.data_in.get() {
+            State::PadIn
+        } else {
+            State::DiscardOut
+        });
+    }
+
+    fn resume_in_if_status(&self) {
+        if self.state.get() == State::Status {
+            self.controller().endpoint_resume_in(self.endpoint_in.get());
+        }
+    }
+
+    fn send_status(&self) -> hil::usb::InResult {
+        let packet = &self.buffers[IN_BUFFER].buf;
+        let mut csw = [0; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.get().to_le_bytes());
+        csw[8..12].copy_from_slice(&self.residue.get().to_le_bytes());
+        csw[12] = if self.failed.get() {
+            CSW_STATUS_FAILED
+        } else {
+            CSW_STATUS_PASSED
+        };
+        for (cell, byte) in packet.iter().zip(csw.iter()) {
+            cell.set(*byte);
+        }
+        self.state.set(State::Command);
+        hil::usb::InResult::Packet(CSW_LEN)
+    }
+
+    fn send_block_data(&self) -> hil::usb::InResult {
+        if !self.block_ready.get() {
+            return hil::usb::InResult::Delay;
+        }
+        let packet = &self.buffers[IN_BUFFER].buf;
+        let len = self.block.map_or(0, |block| {
+            let offset = self.block_offset.get();
+            let len = cmp::min(packet.len(), BLOCK_SIZE - offset);
+            for (cell, byte) in packet.iter().zip(block[offset..offset + len].iter()) {
+                cell.set(*byte);
+            }
+            self.block_offset.set(offset + len);
+            len
+        });
+        if len == 0 {
+            return hil::usb::InResult::Delay;
+        }
+        self.remaining.set(self.remaining.get() - len as u32);
+
+        if self.block_offset.get() == BLOCK_SIZE {
+            self.block_offset.set(0);
+            self.blocks_left.set(self.blocks_left.get() - 1);
+            if self.blocks_left.get() == 0 {
+                self.state.set(State::Status);
+            } else {
+                self.lba.set(self.lba.get() + 1);
+                self.read_block();
+            }
+        }
+        hil::usb::InResult::Packet(len)
+    }
+
+    fn receive_block_data(&self, len: usize) -> hil::usb::OutResult {
+        let packet = &self.buffers[OUT_BUFFER].buf;
+        let full = self.block.map_or(false, |block| {
+            let offset = self.block_offset.get();
+            let len = cmp::min(len, BLOCK_SIZE - offset);
+            for (byte, cell) in block[offset..offset + len].iter_mut().zip(packet.iter()) {
+                *byte = cell.get();
+            }
+            self.block_offset.set(offset + len);
+            self.remaining.set(self.remaining.get() - len as u32);
+            offset + len == BLOCK_SIZE
+        });
+        if full {
+            self.write_block()
+        } else {
+            hil::usb::OutResult::Ok
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for MassStorage<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request = descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .and_then(|setup_data| match setup_data.request_type.request_type() {
+                RequestType::Class => Some(setup_data.request_code),
+                _ => None,
+            });
+        match class_request {
+            Some(REQUEST_GET_MAX_LUN) => {
+                // Answered in `ctrl_in`, as the control client only knows
+                // standard requests.
+                self.get_max_lun.set(true);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            Some(REQUEST_RESET) => {
+                self.reset();
+                self.client_ctrl.ctrl_setup(endpoint)
+            }
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        if self.get_max_lun.take() {
+            // Only logical unit 0.
+            self.client_ctrl.ctrl_buffer.buf[0].set(0);
+            return hil::usb::CtrlInResult::Packet(1, true);
+        }
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction: send the data or the status of the
+    /// current command.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => match self.state.get() {
+                State::ResponseIn => {
+                    let packet = &self.buffers[IN_BUFFER].buf;
+                    let len = self.remaining.get() as usize;
+                    for (cell, byte) in packet.iter().zip(self.response.get()[..len].iter()) {
+                        cell.set(*byte);
+                    }
+                    self.remaining.set(0);
+                    self.state.set(State::Status);
+                    hil::usb::InResult::Packet(len)
+                }
+                State::ReadIn => self.send_block_data(),
+                State::PadIn => {
+                    let packet = &self.buffers[IN_BUFFER].buf;
+                    let len = cmp::min(packet.len(), self.remaining.get() as usize);
+                    for cell in packet[..len].iter() {
+                        cell.set(0);
+                    }
+                    self.remaining.set(self.remaining.get() - len as u32);
+                    if self.remaining.get() == 0 {
+                        self.state.set(State::Status);
+                    }
+                    hil::usb::InResult::Packet(len)
+                }
+                State::Status => self.send_status(),
+                State::Command | State::WriteOut | State::DiscardOut => hil::usb::InResult::Delay,
+            },
+            TransferType::Control | TransferType::Interrupt | TransferType::Isochronous => {
+                panic!("Transfer protocol not supported by USB MSC");
+            }
+        }
+    }
+
+    /// Handle a Bulk OUT transaction: a command block wrapper or data for
+    /// the current command.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => match self.state.get() {
+                State::Command => {
+                    // Packets that are not commands are dropped; a host that
+                    // gets no status resets the device.
+                    self.receive_command(packet_bytes as usize);
+                    hil::usb::OutResult::Ok
+                }
+                State::WriteOut => self.receive_block_data(packet_bytes as usize),
+                State::DiscardOut => {
+                    let len = cmp::min(packet_bytes, self.remaining.get());
+                    self.remaining.set(self.remaining.get() - len);
+                    if self.remaining.get() == 0 {
+                        self.state.set(State::Status);
+                        self.controller().endpoint_resume_in(self.endpoint_in.get());
+                    }
+                    hil::usb::OutResult::Ok
+                }
+                State::ResponseIn | State::ReadIn | State::PadIn | State::Status => {
+                    hil::usb::OutResult::Ok
+                }
+            },
+            TransferType::Control | TransferType::Interrupt | TransferType::Isochronous => {
+                panic!("Transfer protocol not supported by USB MSC");
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> BlockStorageClient for MassStorage<'a, U> {
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.block.replace(buffer);
+        if self.abandoned.replace(false) || self.state.get() != State::ReadIn {
+            return;
+        }
+        if result == ReturnCode::SUCCESS {
+            self.block_ready.set(true);
+        } else {
+            self.fail(UNRECOVERED_READ_ERROR);
+        }
+        self.controller().endpoint_resume_in(self.endpoint_in.get());
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.block.replace(buffer);
+        if self.abandoned.replace(false) || self.state.get() != State::WriteOut {
+            return;
+        }
+        self.block_offset.set(0);
+        if result == ReturnCode::SUCCESS {
+            self.lba.set(self.lba.get() + 1);
+            self.blocks_left.set(self.blocks_left.get() - 1);
+            if self.blocks_left.get() == 0 {
+                self.state.set(State::Status);
+            }
+        } else {
+            self.fail(WRITE_ERROR);
+        }
+        self.resume_in_if_status();
+        self.controller()
+            .endpoint_resume_out(self.endpoint_out.get());
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Function<'a> for MassStorage<'a, U> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn endpoint_count(&self) -> usize {
+        1
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        (0x08, 0x06, 0x50)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        // Use the IN and OUT endpoints of the same number
+        self.interface.set(first_interface);
+        self.endpoint_in.set(first_endpoint);
+        self.endpoint_out.set(first_endpoint);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: self.interface.get(),
+                    num_endpoints: 2,
+                    interface_class: 0x08,    // Mass Storage
+                    interface_subclass: 0x06, // SCSI transparent command set
+                    interface_protocol: 0x50, // Bulk-Only Transport
+                    ..InterfaceDescriptor::default()
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_in.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_out.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 64,
+                    interval: 0,
+                },
+            ],
+        )
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        match (setup.request_type.request_type(), setup.request_code) {
+            (RequestType::Class, REQUEST_GET_MAX_LUN) => {
+                self.get_max_lun.set(true);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            (RequestType::Class, REQUEST_RESET) => {
+                self.reset();
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_in(&'a self, buf: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        if self.get_max_lun.take() {
+            // Only logical unit 0.
+            buf[0].set(0);
+            hil::usb::CtrlInResult::Packet(1, true)
+        } else {
+            hil::usb::CtrlInResult::Error
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
+/// Copy `text` to the start of `field`, which is padded with spaces.
+fn copy_text(field: &mut [u8], text: &str) {
+    for (byte, c) in field.iter_mut().zip(text.bytes()) {
+        *byte = c;
+    }
+}
+
+/// Exposes a region of nonvolatile storage as blocks.
+///
+/// Blocks are copied through a buffer of the adapter, so the buffer of the
+/// client is always returned even if the storage keeps its buffer after an
+/// error. The adapter then fails all later operations.
+pub struct NonvolatileBlocks<'a> {
+    storage: &'a dyn NonvolatileStorage<'a>,
+    /// Address of the first block.
+    start: usize,
+    blocks: u32,
+    buffer: TakeCell<'a, [u8]>,
+    client_buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn BlockStorageClient>,
+}
+
+impl<'a> NonvolatileBlocks<'a> {
+    /// Expose the `length` bytes of `storage` from `start` as blocks, using
+    /// `buffer` of at least `BLOCK_SIZE` bytes to access it.
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'a>,
+        start: usize,
+        length: usize,
+        buffer: &'a mut [u8],
+    ) -> NonvolatileBlocks<'a> {
+        NonvolatileBlocks {
+            storage: storage,
+            start: start,
+            blocks: (length / BLOCK_SIZE) as u32,
+            buffer: TakeCell::new(buffer),
+            client_buffer: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    fn start(
+        &self,
+        client_buffer: &'static mut [u8],
+        block: u32,
+        write: bool,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if block >= self.blocks || client_buffer.len() < BLOCK_SIZE {
+            return Err((ReturnCode::EINVAL, client_buffer));
+        }
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Err((ReturnCode::EBUSY, client_buffer)),
+        };
+        let address = self.start + block as usize * BLOCK_SIZE;
+        let result = if write {
+            buffer[..BLOCK_SIZE].copy_from_slice(&client_buffer[..BLOCK_SIZE]);
+            self.storage.write(buffer, address, BLOCK_SIZE)
+        } else {
+            self.storage.read(buffer, address, BLOCK_SIZE)
+        };
+        if result == ReturnCode::SUCCESS {
+            self.client_buffer.replace(client_buffer);
+            Ok(())
+        } else {
+            Err((result, client_buffer))
+        }
+    }
+}
+
+impl<'a> BlockStorage<'a> for NonvolatileBlocks<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn read_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(buffer, block, false)
+    }
+
+    fn write_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(buffer, block, true)
+    }
+}
+
+impl<'a> NonvolatileStorageClient<'a> for NonvolatileBlocks<'a> {
+    fn read_done(&self, buffer: &'a mut [u8], length: usize) {
+        self.client_buffer.take().map(|client_buffer| {
+            client_buffer[..BLOCK_SIZE].copy_from_slice(&buffer[..BLOCK_SIZE]);
+            let result = if length == BLOCK_SIZE {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::FAIL
+            };
+            self.client
+                .map(move |client| client.read_done(client_buffer, result));
+        });
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'a mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.client_buffer.take().map(|client_buffer| {
+            let result = if length == BLOCK_SIZE {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::FAIL
+            };
+            self.client
+                .map(move |client| client.write_done(client_buffer, result));
+        });
+    }
+}
+
+/// Exposes an SD card as blocks.
+///
+/// The card is initialized when it is inserted, and has no blocks until
+/// then. As for [NonvolatileBlocks](struct.NonvolatileBlocks.html), blocks
+/// are copied through a buffer of the adapter.
+pub struct SdCardBlocks<'a, A: hil::time::Alarm<'a>> {
+    sdcard: &'a SDCard<'a, A>,
+    blocks: Cell<u32>,
+    /// Whether the current operation is a read.
+    reading: Cell<bool>,
+    buffer: TakeCell<'static, [u8]>,
+    client_buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn BlockStorageClient>,
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SdCardBlocks<'a, A> {
+    pub fn new(sdcard: &'a SDCard<'a, A>, buffer: &'static mut [u8]) -> SdCardBlocks<'a, A> {
+        SdCardBlocks {
+            sdcard: sdcard,
+            blocks: Cell::new(0),
+            reading: Cell::new(false),
+            buffer: TakeCell::new(buffer),
+            client_buffer: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Watch for card changes and initialize the card if there is one.
+    /// The adapter must be the client of the card.
+    pub fn initialize(&self) -> ReturnCode {
+        self.sdcard.detect_changes();
+        self.sdcard.initialize()
+    }
+
+    fn start(
+        &self,
+        client_buffer: &'static mut [u8],
+        block: u32,
+        write: bool,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if block >= self.blocks.get() || client_buffer.len() < BLOCK_SIZE {
+            return Err((ReturnCode::EINVAL, client_buffer));
+        }
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Err((ReturnCode::EBUSY, client_buffer)),
+        };
+        let result = if write {
+            buffer[..BLOCK_SIZE].copy_from_slice(&client_buffer[..BLOCK_SIZE]);
+            self.sdcard.write_blocks(buffer, block, 1)
+        } else {
+            self.sdcard.read_blocks(buffer, block, 1)
+        };
+        if result == ReturnCode::SUCCESS {
+            self.reading.set(!write);
+            self.client_buffer.replace(client_buffer);
+            Ok(())
+        } else {
+            Err((result, client_buffer))
+        }
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> BlockStorage<'a> for SdCardBlocks<'a, A> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks.get()
+    }
+
+    fn read_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(buffer, block, false)
+    }
+
+    fn write_block(
+        &self,
+        buffer: &'static mut [u8],
+        block: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start(buffer, block, true)
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SdCardBlocks<'a, A> {
+    fn card_detection_changed(&self, installed: bool) {
+        self.blocks.set(0);
+        if installed {
+            self.sdcard.initialize();
+        }
+    }
+
+    fn init_done(&self, block_size: u32, total_size: u64) {
+        if block_size as usize == BLOCK_SIZE {
+            self.blocks
+                .set(cmp::min(total_size / BLOCK_SIZE as u64, u32::MAX as u64) as u32);
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], len: usize) {
+        self.client_buffer.take().map(|client_buffer| {
+            client_buffer[..BLOCK_SIZE].copy_from_slice(&data[..BLOCK_SIZE]);
+            let result = if len == BLOCK_SIZE {
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::FAIL
+            };
+            self.client
+                .map(move |client| client.read_done(client_buffer, result));
+        });
+        self.buffer.replace(data);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.client_buffer.take().map(|client_buffer| {
+            self.client
+                .map(move |client| client.write_done(client_buffer, ReturnCode::SUCCESS));
+        });
+    }
+
+    fn error(&self, _error: u32) {
+        // The card keeps the buffer of the failed operation.
+        self.client_buffer.take().map(|client_buffer| {
+            self.client.map(move |client| {
+                if self.reading.get() {
+                    client.read_done(client_buffer, ReturnCode::FAIL)
+                } else {
+                    client.write_done(client_buffer, ReturnCode::FAIL)
+                }
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::super::sim::{setup, SimUsbController, TransferError};
+    use super::{BlockStorage, BlockStorageClient, MassStorage, BLOCK_SIZE};
+    use kernel::common::cells::{OptionalCell, TakeCell};
+    use kernel::hil::usb::{Client, UsbController};
+    use kernel::ReturnCode;
+
+    static STRINGS: &'static [&'static str; 3] = &["XYZ Corp.", "Flash Drive", "1"];
+
+    const ENDPOINT_IN: usize = 1;
+    const ENDPOINT_OUT: usize = 2;
+    const BLOCKS: u32 = 8;
+
+    const CSW_PASSED: u8 = 0;
+    const CSW_FAILED: u8 = 1;
+
+    /// Blocks in memory. Reads and writes complete when the test calls
+    /// `complete()`.
+    struct RamBlocks {
+        data: RefCell<Vec<u8>>,
+        pending: TakeCell<'static, [u8]>,
+        /// Whether the pending operation is a read, and its block
+        operation: Cell<(bool, u32)>,
+        client: OptionalCell<&'static dyn BlockStorageClient>,
+    }
+
+    impl RamBlocks {
+        fn new() -> RamBlocks {
+            RamBlocks {
+                data: RefCell::new(std::vec![0; BLOCKS as usize * BLOCK_SIZE]),
+                pending: TakeCell::empty(),
+                operation: Cell::new((false, 0)),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        fn complete(&self) {
+            let buffer = self.pending.take().expect("no storage operation");
+            let (read, block) = self.operation.get();
+            let range = block as usize * BLOCK_SIZE..(block as usize + 1) * BLOCK_SIZE;
+            self.client.map(move |client| {
+                if read {
+                    buffer.copy_from_slice(&self.data.borrow()[range]);
+                    client.read_done(buffer, ReturnCode::SUCCESS);
+                } else {
+                    self.data.borrow_mut()[range].copy_from_slice(buffer);
+                    client.write_done(buffer, ReturnCode::SUCCESS);
+                }
+            });
+        }
+
+        fn start(
+            &self,
+            read: bool,
+            buffer: &'static mut [u8],
+            block: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            assert!(self.pending.is_none(), "overlapping storage operations");
+            assert!(block < BLOCKS, "block out of range");
+            self.operation.set((read, block));
+            self.pending.replace(buffer);
+            Ok(())
+        }
+    }
+
+    impl BlockStorage<'static> for RamBlocks {
+        fn set_client(&self, client: &'static dyn BlockStorageClient) {
+            self.client.set(client);
+        }
+
+        fn block_count(&self) -> u32 {
+            BLOCKS
+        }
+
+        fn read_block(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.start(true, buffer, block)
+        }
+
+        fn write_block(
+            &self,
+            buffer: &'static mut [u8],
+            block: u32,
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.start(false, buffer, block)
+        }
+    }
+
+    type Msc = MassStorage<'static, SimUsbController<'static>>;
+
+    /// Build a mass storage device on a simulated controller, enumerate it
+    /// and clear the unit attention of the newly inserted medium.
+    fn with_drive(test: impl FnOnce(&SimUsbController<'static>, &RamBlocks)) {
+        let usb: &'static SimUsbController = Box::leak(Box::new(SimUsbController::new()));
+        let storage: &'static RamBlocks = Box::leak(Box::new(RamBlocks::new()));
+        let msc: &'static Msc = Box::leak(Box::new(MassStorage::new(
+            usb,
+            64,
+            0x6667,
+            0xabcd,
+            STRINGS,
+            storage,
+            Box::leak(Box::new([0; BLOCK_SIZE])),
+        )));
+        usb.set_client(msc);
+        storage.set_client(msc);
+        msc.enable();
+        msc.attach();
+        usb.enumerate();
+
+        let test_unit_ready = [0x00];
+        let (_, status) = command(usb, 1, 0, false, &test_unit_ready);
+        assert_eq!(status, (0, CSW_FAILED));
+        assert_eq!(sense(usb), (0x06, 0x28));
+        let (_, status) = command(usb, 2, 0, false, &test_unit_ready);
+        assert_eq!(status, (0, CSW_PASSED));
+
+        test(usb, storage);
+    }
+
+    fn cbw(tag: u32, length: u32, data_in: bool, cb: &[u8]) -> [u8; 31] {
+        let mut cbw = [0; 31];
+        cbw[0..4].copy_from_slice(b"USBC");
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&length.to_le_bytes());
+        cbw[12] = if data_in { 0x80 } else { 0x00 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        cbw
+    }
+
+    /// Split what the device sent into the data-in phase and the status,
+    /// checking the status tag. Returns the data and the residue and status
+    /// of the command.
+    fn split_status(tag: u32, mut data: Vec<u8>) -> (Vec<u8>, (u32, u8)) {
+        assert!(data.len() >= 13, "no command status");
+        let csw = data.split_off(data.len() - 13);
+        assert_eq!(&csw[0..4], b"USBS");
+        assert_eq!(&csw[4..8], &tag.to_le_bytes());
+        let residue = u32::from_le_bytes([csw[8], csw[9], csw[10], csw[11]]);
+        (data, (residue, csw[12]))
+    }
+
+    /// Run a command with no data or a data-in phase of `length` bytes.
+    fn command(
+        usb: &SimUsbController,
+        tag: u32,
+        length: u32,
+        data_in: bool,
+        cb: &[u8],
+    ) -> (Vec<u8>, (u32, u8)) {
+        usb.transfer_out(ENDPOINT_OUT, &cbw(tag, length, data_in, cb))
+            .unwrap();
+        split_status(tag, usb.drain_in(ENDPOINT_IN))
+    }
+
+    /// Run REQUEST SENSE, returning the sense key and additional sense code.
+    fn sense(usb: &SimUsbController) -> (u8, u8) {
+        let (data, status) = command(usb, 99, 18, true, &[0x03, 0, 0, 0, 18, 0]);
+        assert_eq!(status, (0, CSW_PASSED));
+        assert_eq!(data.len(), 18);
+        assert_eq!(data[0], 0x70);
+        (data[2], data[12])
+    }
+
+    fn rw10(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+        let lba = lba.to_be_bytes();
+        let count = count.to_be_bytes();
+        [
+            opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ]
+    }
+
+    /// Send the data-out phase of a command in 64 byte packets, completing
+    /// each block write the device starts.
+    fn send_data(usb: &SimUsbController, storage: &RamBlocks, data: &[u8]) {
+        for packet in data.chunks(64) {
+            usb.transfer_out(ENDPOINT_OUT, packet).unwrap();
+            if storage.pending.is_some() {
+                storage.complete();
+            }
+        }
+    }
+
+    /// Receive the data-in phase of a read and its status, completing each
+    /// block read the device starts.
+    fn receive_data(usb: &SimUsbController, storage: &RamBlocks, tag: u32) -> (Vec<u8>, (u32, u8)) {
+        let mut data = Vec::new();
+        loop {
+            data.extend(usb.drain_in(ENDPOINT_IN));
+            if storage.pending.is_none() {
+                break;
+            }
+            storage.complete();
+        }
+        split_status(tag, data)
+    }
+
+    #[test]
+    fn inquiry() {
+        with_drive(|usb, _| {
+            let (data, status) = command(usb, 3, 36, true, &[0x12, 0, 0, 0, 36, 0]);
+            assert_eq!(status, (0, CSW_PASSED));
+            assert_eq!(data.len(), 36);
+            // Removable direct access block device
+            assert_eq!(&data[0..5], &[0x00, 0x80, 0x04, 0x02, 31]);
+            assert_eq!(&data[8..16], b"XYZ Corp");
+            assert_eq!(&data[16..32], b"Flash Drive     ");
+            assert_eq!(&data[32..36], b"1.0 ");
+
+            // A host that asks for less gets less; one that asks for more
+            // gets the residue
+            let (data, status) = command(usb, 4, 5, true, &[0x12, 0, 0, 0, 5, 0]);
+            assert_eq!((data.len(), status), (5, (0, CSW_PASSED)));
+            let (data, status) = command(usb, 5, 96, true, &[0x12, 0, 0, 0, 96, 0]);
+            assert_eq!((data.len(), status), (36, (60, CSW_PASSED)));
+
+            // No vital product data pages: the data-in phase is padded
+            let (data, status) = command(usb, 6, 36, true, &[0x12, 1, 0x80, 0, 36, 0]);
+            assert_eq!(data, [0; 36]);
+            assert_eq!(status, (36, CSW_FAILED));
+            assert_eq!(sense(usb), (0x05, 0x24));
+        });
+    }
+
+    #[test]
+    fn read_capacity() {
+        with_drive(|usb, _| {
+            let (data, status) = command(usb, 3, 8, true, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(status, (0, CSW_PASSED));
+            let last_lba = (BLOCKS - 1).to_be_bytes();
+            let block_size = (BLOCK_SIZE as u32).to_be_bytes();
+            assert_eq!(data[0..4], last_lba);
+            assert_eq!(data[4..8], block_size);
+        });
+    }
+
+    #[test]
+    fn read_write() {
+        with_drive(|usb, storage| {
+            let data: Vec<u8> = (0..2 * BLOCK_SIZE)
+                .map(|i| (i * 7 + i / 512) as u8)
+                .collect();
+            usb.transfer_out(ENDPOINT_OUT, &cbw(3, 1024, false, &rw10(0x2a, 5, 2)))
+                .unwrap();
+            send_data(usb, storage, &data);
+            let (_, status) = split_status(3, usb.drain_in(ENDPOINT_IN));
+            assert_eq!(status, (0, CSW_PASSED));
+            assert_eq!(
+                &storage.data.borrow()[5 * BLOCK_SIZE..7 * BLOCK_SIZE],
+                &data[..]
+            );
+
+            usb.transfer_out(ENDPOINT_OUT, &cbw(4, 1536, true, &rw10(0x28, 4, 3)))
+                .unwrap();
+            let (read, status) = receive_data(usb, storage, 4);
+            assert_eq!(status, (0, CSW_PASSED));
+            assert_eq!(&read[..BLOCK_SIZE], &[0; BLOCK_SIZE][..]);
+            assert_eq!(&read[BLOCK_SIZE..], &data[..]);
+
+            // A transfer of no blocks
+            let (data, status) = command(usb, 5, 0, true, &rw10(0x28, 0, 0));
+            assert!(data.is_empty());
+            assert_eq!(status, (0, CSW_PASSED));
+        });
+    }
+
+    #[test]
+    fn out_of_range() {
+        with_drive(|usb, storage| {
+            // The data-in phase is padded and the data-out phase discarded
+            let (data, status) = command(usb, 3, 1024, true, &rw10(0x28, BLOCKS - 1, 2));
+            assert_eq!(data, [0; 1024].to_vec());
+            assert_eq!(status, (1024, CSW_FAILED));
+            assert_eq!(sense(usb), (0x05, 0x21));
+
+            usb.transfer_out(ENDPOINT_OUT, &cbw(4, 512, false, &rw10(0x2a, BLOCKS, 1)))
+                .unwrap();
+            send_data(usb, storage, &[0xaa; 512]);
+            let (_, status) = split_status(4, usb.drain_in(ENDPOINT_IN));
+            assert_eq!(status, (512, CSW_FAILED));
+            assert_eq!(sense(usb), (0x05, 0x21));
+            assert!(storage.data.borrow().iter().all(|&byte| byte == 0));
+        });
+    }
+
+    #[test]
+    fn mismatched_commands() {
+        with_drive(|usb, storage| {
+            // READ(10) with a data-out phase, or one shorter than the blocks
+            usb.transfer_out(ENDPOINT_OUT, &cbw(3, 512, false, &rw10(0x28, 0, 1)))
+                .unwrap();
+            send_data(usb, storage, &[0xaa; 512]);
+            let (_, status) = split_status(3, usb.drain_in(ENDPOINT_IN));
+            assert_eq!(status, (512, CSW_FAILED));
+            assert_eq!(sense(usb), (0x05, 0x24));
+
+            let (data, status) = command(usb, 4, 256, true, &rw10(0x28, 0, 1));
+            assert_eq!(data, [0; 256].to_vec());
+            assert_eq!(status, (256, CSW_FAILED));
+
+            // WRITE(10) with a data-in phase
+            let (data, status) = command(usb, 5, 512, true, &rw10(0x2a, 0, 1));
+            assert_eq!(data.len(), 512);
+            assert_eq!(status, (512, CSW_FAILED));
+            assert!(storage.pending.is_none());
+
+            // INQUIRY with a data-out phase
+            usb.transfer_out(ENDPOINT_OUT, &cbw(6, 36, false, &[0x12, 0, 0, 0, 36, 0]))
+                .unwrap();
+            send_data(usb, storage, &[0; 36]);
+            let (_, status) = split_status(6, usb.drain_in(ENDPOINT_IN));
+            assert_eq!(status, (36, CSW_FAILED));
+
+            // Unknown commands, and logical units other than 0
+            let (_, status) = command(usb, 7, 0, false, &[0xc0]);
+            assert_eq!(status, (0, CSW_FAILED));
+            assert_eq!(sense(usb), (0x05, 0x20));
+            let mut other_lun = cbw(8, 0, false, &[0x00]);
+            other_lun[13] = 1;
+            usb.transfer_out(ENDPOINT_OUT, &other_lun).unwrap();
+            let (_, status) = split_status(8, usb.drain_in(ENDPOINT_IN));
+            assert_eq!(status, (0, CSW_FAILED));
+        });
+    }
+
+    #[test]
+    fn invalid_cbw() {
+        with_drive(|usb, _| {
+            // A packet of the wrong length or signature gets no status
+            let packet = cbw(3, 0, false, &[0x00]);
+            usb.transfer_out(ENDPOINT_OUT, &packet[..30]).unwrap();
+            assert_eq!(usb.transfer_in(ENDPOINT_IN), Err(TransferError::Nak));
+            let mut bad_signature = packet;
+            bad_signature[0] = b'X';
+            usb.transfer_out(ENDPOINT_OUT, &bad_signature).unwrap();
+            assert_eq!(usb.transfer_in(ENDPOINT_IN), Err(TransferError::Nak));
+
+            // The device still takes the next valid command
+            let (_, status) = command(usb, 4, 0, false, &[0x00]);
+            assert_eq!(status, (0, CSW_PASSED));
+        });
+    }
+
+    #[test]
+    fn class_requests() {
+        with_drive(|usb, storage| {
+            // Get Max LUN
+            assert_eq!(usb.control_in(setup(0xa1, 0xfe, 0, 0, 1)), Ok(std::vec![0]));
+
+            // A reset during a write abandons it, and the device takes the
+            // next command
+            usb.transfer_out(ENDPOINT_OUT, &cbw(3, 512, false, &rw10(0x2a, 0, 1)))
+                .unwrap();
+            usb.transfer_out(ENDPOINT_OUT, &[0x55; 64]).unwrap();
+            usb.control_out(setup(0x21, 0xff, 0, 0, 0), &[]).unwrap();
+            let (_, status) = command(usb, 4, 0, false, &[0x00]);
+            assert_eq!(status, (0, CSW_PASSED));
+            assert!(storage.pending.is_none());
+        });
+    }
+}
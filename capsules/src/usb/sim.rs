@@ -0,0 +1,695 @@
+//! Simulated USB controller for testing USB classes without hardware
+//!
+//! `SimUsbController` implements `hil::usb::UsbController` in memory and
+//! plays the host's side of control, bulk and interrupt transfers against
+//! the client attached to it, so that `cargo test` can exercise the USB
+//! stack. It follows the nRF52 driver: an OUT packet the client answers with
+//! `OutResult::Delay` is consumed, and further OUT packets on that endpoint
+//! are refused until the client calls `endpoint_resume_out()`. IN packets
+//! are only requested from the client after `endpoint_resume_in()`, and
+//! until it answers `InResult::Delay`.
+//!
+//! `enumerate()` runs a host's enumeration script and checks the descriptors
+//! it reads against the rules of chapter 9 of the USB 2.0 specification.
+//!
+//! `SimAlarm` is an alarm whose time only moves when a test calls
+//! `advance()`.
+
+extern crate std;
+
+use core::cell::Cell;
+use std::vec::Vec;
+
+use kernel::common::cells::{OptionalCell, VolatileCell};
+use kernel::hil;
+use kernel::hil::time::{Alarm, AlarmClient, Freq1KHz, Ticks, Ticks32, Time};
+use kernel::hil::usb::{DeviceSpeed, TransferType};
+use kernel::ReturnCode;
+
+const N_ENDPOINTS: usize = 16;
+
+/// Size of the control endpoint packets the simulated host uses.
+const MAX_CTRL_PACKET_SIZE: usize = 64;
+
+/// The language ID the simulated host asks for strings in.
+const LANGUAGE_ID: u16 = 0x0409;
+
+/// How the device answered a transfer.
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    /// The device sent a STALL handshake.
+    Stall,
+    /// The device sent a NAK handshake; the host may retry later.
+    Nak,
+}
+
+struct Endpoint<'a> {
+    in_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    out_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    in_type: OptionalCell<TransferType>,
+    out_type: OptionalCell<TransferType>,
+    /// The client has data to send.
+    in_ready: Cell<bool>,
+    /// The client answered the last OUT packet with `Delay`.
+    out_paused: Cell<bool>,
+}
+
+impl Default for Endpoint<'_> {
+    fn default() -> Self {
+        Endpoint {
+            in_buffer: OptionalCell::empty(),
+            out_buffer: OptionalCell::empty(),
+            in_type: OptionalCell::empty(),
+            out_type: OptionalCell::empty(),
+            in_ready: Cell::new(false),
+            out_paused: Cell::new(false),
+        }
+    }
+}
+
+pub struct SimUsbController<'a> {
+    client: OptionalCell<&'a dyn hil::usb::Client<'a>>,
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    endpoints: [Endpoint<'a>; N_ENDPOINTS],
+    speed: OptionalCell<DeviceSpeed>,
+    attached: Cell<bool>,
+    pending_address: Cell<u16>,
+    address: Cell<u16>,
+}
+
+/// Build a SETUP packet.
+pub fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let value = value.to_le_bytes();
+    let index = index.to_le_bytes();
+    let length = length.to_le_bytes();
+    [
+        request_type,
+        request,
+        value[0],
+        value[1],
+        index[0],
+        index[1],
+        length[0],
+        length[1],
+    ]
+}
+
+/// What a host learnt about a device by enumerating it.
+pub struct Enumeration {
+    pub device: Vec<u8>,
+    pub configuration: Vec<u8>,
+    /// String descriptors 1 to 3 decoded, if the device has them.
+    pub strings: Vec<Option<std::string::String>>,
+}
+
+impl<'a> SimUsbController<'a> {
+    pub fn new() -> SimUsbController<'a> {
+        SimUsbController {
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            endpoints: Default::default(),
+            speed: OptionalCell::empty(),
+            attached: Cell::new(false),
+            pending_address: Cell::new(0),
+            address: Cell::new(0),
+        }
+    }
+
+    fn client(&self) -> &'a dyn hil::usb::Client<'a> {
+        self.client.map(|client| *client).expect("no USB client")
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    /// The address the device answers on.
+    pub fn address(&self) -> u16 {
+        self.address.get()
+    }
+
+    pub fn bus_reset(&self) {
+        self.address.set(0);
+        self.client().bus_reset();
+    }
+
+    fn setup_stage(&self, setup: &[u8; 8]) -> Result<(), TransferError> {
+        self.ctrl_buffer.map(|buf| {
+            for (cell, byte) in buf.iter().zip(setup.iter()) {
+                cell.set(*byte);
+            }
+        });
+        match self.client().ctrl_setup(0) {
+            hil::usb::CtrlSetupResult::Ok | hil::usb::CtrlSetupResult::OkSetAddress => Ok(()),
+            _ => Err(TransferError::Stall),
+        }
+    }
+
+    fn status_stage(&self) {
+        self.client().ctrl_status(0);
+        self.client().ctrl_status_complete(0);
+    }
+
+    /// Run a control read and return the data stage.
+    pub fn control_in(&self, setup: [u8; 8]) -> Result<Vec<u8>, TransferError> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        self.setup_stage(&setup)?;
+
+        let mut data = Vec::new();
+        while data.len() < length {
+            match self.client().ctrl_in(0) {
+                hil::usb::CtrlInResult::Packet(size, last) => {
+                    assert!(size <= MAX_CTRL_PACKET_SIZE, "control packet too long");
+                    self.ctrl_buffer.map(|buf| {
+                        data.extend(buf[..size].iter().map(|cell| cell.get()));
+                    });
+                    if last || size < MAX_CTRL_PACKET_SIZE {
+                        break;
+                    }
+                }
+                hil::usb::CtrlInResult::Delay => return Err(TransferError::Nak),
+                hil::usb::CtrlInResult::Error => return Err(TransferError::Stall),
+            }
+        }
+        assert!(data.len() <= length, "device sent more than wLength");
+
+        self.status_stage();
+        Ok(data)
+    }
+
+    /// Run a control write with `data` as the data stage.
+    pub fn control_out(&self, setup: [u8; 8], data: &[u8]) -> Result<(), TransferError> {
+        self.setup_stage(&setup)?;
+
+        for packet in data.chunks(MAX_CTRL_PACKET_SIZE) {
+            self.ctrl_buffer.map(|buf| {
+                for (cell, byte) in buf.iter().zip(packet.iter()) {
+                    cell.set(*byte);
+                }
+            });
+            match self.client().ctrl_out(0, packet.len() as u32) {
+                hil::usb::CtrlOutResult::Ok => {}
+                hil::usb::CtrlOutResult::Delay => return Err(TransferError::Nak),
+                hil::usb::CtrlOutResult::Halted => return Err(TransferError::Stall),
+            }
+        }
+
+        self.status_stage();
+        Ok(())
+    }
+
+    /// Read a descriptor with a standard GET_DESCRIPTOR request to the
+    /// device.
+    pub fn get_descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        length: u16,
+    ) -> Result<Vec<u8>, TransferError> {
+        let language = if descriptor_type == 3 && index != 0 {
+            LANGUAGE_ID
+        } else {
+            0
+        };
+        self.control_in(setup(
+            0x80,
+            6,
+            (descriptor_type as u16) << 8 | index as u16,
+            language,
+            length,
+        ))
+    }
+
+    /// Poll an IN endpoint once.
+    pub fn transfer_in(&self, endpoint: usize) -> Result<Vec<u8>, TransferError> {
+        let ep = &self.endpoints[endpoint];
+        let transfer_type = ep
+            .in_type
+            .map(|transfer_type| *transfer_type)
+            .expect("IN endpoint not enabled");
+        if !ep.in_ready.get() {
+            return Err(TransferError::Nak);
+        }
+        match self.client().packet_in(transfer_type, endpoint) {
+            hil::usb::InResult::Packet(size) => {
+                let data = ep
+                    .in_buffer
+                    .map(|buf| buf[..size].iter().map(|cell| cell.get()).collect())
+                    .expect("IN endpoint has no buffer");
+                self.client().packet_transmitted(endpoint);
+                Ok(data)
+            }
+            hil::usb::InResult::Delay => {
+                ep.in_ready.set(false);
+                Err(TransferError::Nak)
+            }
+            hil::usb::InResult::Error => Err(TransferError::Stall),
+        }
+    }
+
+    /// Poll an IN endpoint until the device has nothing more to send, and
+    /// return everything it sent.
+    pub fn drain_in(&self, endpoint: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Ok(packet) = self.transfer_in(endpoint) {
+            data.extend(packet);
+        }
+        data
+    }
+
+    /// Send one packet to an OUT endpoint.
+    pub fn transfer_out(&self, endpoint: usize, data: &[u8]) -> Result<(), TransferError> {
+        let ep = &self.endpoints[endpoint];
+        let transfer_type = ep
+            .out_type
+            .map(|transfer_type| *transfer_type)
+            .expect("OUT endpoint not enabled");
+        if ep.out_paused.get() {
+            return Err(TransferError::Nak);
+        }
+        ep.out_buffer.map(|buf| {
+            assert!(
+                data.len() <= buf.len(),
+                "packet larger than endpoint buffer"
+            );
+            for (cell, byte) in buf.iter().zip(data.iter()) {
+                cell.set(*byte);
+            }
+        });
+        match self
+            .client()
+            .packet_out(transfer_type, endpoint, data.len() as u32)
+        {
+            hil::usb::OutResult::Ok => Ok(()),
+            hil::usb::OutResult::Delay => {
+                ep.out_paused.set(true);
+                Ok(())
+            }
+            hil::usb::OutResult::Error => Err(TransferError::Stall),
+        }
+    }
+
+    /// Enumerate the device the way a host does after it is attached, and
+    /// check its descriptors.
+    pub fn enumerate(&self) -> Enumeration {
+        assert!(self.is_attached(), "device not attached");
+        assert!(self.speed.is_some(), "device not enabled");
+
+        self.bus_reset();
+        let device = self
+            .get_descriptor(1, 0, 64)
+            .expect("GET_DESCRIPTOR(device)");
+        self.control_out(setup(0x00, 5, 12, 0, 0), &[])
+            .expect("SET_ADDRESS");
+        assert_eq!(self.address(), 12, "address not enabled after SET_ADDRESS");
+
+        let device_full = self
+            .get_descriptor(1, 0, 18)
+            .expect("GET_DESCRIPTOR(device)");
+        assert_eq!(device, device_full, "device descriptor changed");
+        check_device_descriptor(&device);
+
+        let header = self
+            .get_descriptor(2, 0, 9)
+            .expect("GET_DESCRIPTOR(configuration)");
+        assert_eq!(header.len(), 9, "short configuration descriptor");
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        let configuration = self
+            .get_descriptor(2, 0, total_length)
+            .expect("GET_DESCRIPTOR(configuration)");
+        assert_eq!(&configuration[..9], &header[..], "configuration changed");
+        check_configuration_descriptor(&device, &configuration);
+
+        let languages = self
+            .get_descriptor(3, 0, 255)
+            .expect("GET_DESCRIPTOR(string 0)");
+        check_string_descriptor(&languages);
+        assert!(languages.len() >= 4, "no language IDs");
+        assert_eq!(
+            u16::from_le_bytes([languages[2], languages[3]]),
+            LANGUAGE_ID
+        );
+
+        let strings = device[14..17]
+            .iter()
+            .map(|&index| {
+                if index == 0 {
+                    return None;
+                }
+                let string = self
+                    .get_descriptor(3, index, 255)
+                    .expect("GET_DESCRIPTOR(string)");
+                check_string_descriptor(&string);
+                let units: Vec<u16> = string[2..]
+                    .chunks(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect();
+                Some(std::string::String::from_utf16(&units).expect("invalid UTF-16 string"))
+            })
+            .collect();
+
+        // HID report descriptors are read from their interface.
+        for (interface, length) in hid_report_lengths(&configuration) {
+            let report = self
+                .control_in(setup(0x81, 6, 0x2200, interface as u16, length))
+                .expect("GET_DESCRIPTOR(report)");
+            assert_eq!(report.len(), length as usize, "report descriptor length");
+        }
+
+        self.control_out(setup(0x00, 9, configuration[5] as u16, 0, 0), &[])
+            .expect("SET_CONFIGURATION");
+
+        Enumeration {
+            device,
+            configuration,
+            strings,
+        }
+    }
+}
+
+impl<'a> hil::usb::UsbController<'a> for SimUsbController<'a> {
+    fn set_client(&self, client: &'a dyn hil::usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        assert!(buf.len() >= 8, "control buffer too small");
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].in_buffer.set(buf);
+    }
+
+    fn endpoint_set_out_buffer(&self, endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.endpoints[endpoint].out_buffer.set(buf);
+    }
+
+    fn enable_as_device(&self, speed: DeviceSpeed) {
+        assert!(self.ctrl_buffer.is_some(), "enabled without control buffer");
+        self.speed.set(speed);
+    }
+
+    fn attach(&self) {
+        assert!(self.speed.is_some(), "attached before enable_as_device()");
+        self.attached.set(true);
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.pending_address.set(addr);
+    }
+
+    fn enable_address(&self) {
+        self.address.set(self.pending_address.get());
+    }
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].in_type.set(transfer_type);
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoints[endpoint].out_type.set(transfer_type);
+    }
+
+    fn endpoint_in_out_enable(&self, transfer_type: TransferType, endpoint: usize) {
+        self.endpoint_in_enable(transfer_type, endpoint);
+        self.endpoint_out_enable(transfer_type, endpoint);
+    }
+
+    fn endpoint_resume_in(&self, endpoint: usize) {
+        assert!(
+            self.endpoints[endpoint].in_type.is_some(),
+            "resumed disabled IN endpoint"
+        );
+        self.endpoints[endpoint].in_ready.set(true);
+    }
+
+    fn endpoint_resume_out(&self, endpoint: usize) {
+        assert!(
+            self.endpoints[endpoint].out_type.is_some(),
+            "resumed disabled OUT endpoint"
+        );
+        self.endpoints[endpoint].out_paused.set(false);
+    }
+}
+
+/// Check a device descriptor against the USB 2.0 spec, section 9.6.1.
+pub fn check_device_descriptor(desc: &[u8]) {
+    assert_eq!(desc.len(), 18, "device descriptor length");
+    assert_eq!(desc[0], 18, "device bLength");
+    assert_eq!(desc[1], 1, "device bDescriptorType");
+    let bcd_usb = u16::from_le_bytes([desc[2], desc[3]]);
+    assert!(
+        bcd_usb == 0x0110 || bcd_usb == 0x0200,
+        "bcdUSB {:#x}",
+        bcd_usb
+    );
+    assert!(
+        [8, 16, 32, 64].contains(&desc[7]),
+        "bMaxPacketSize0 {}",
+        desc[7]
+    );
+    if desc[4] == 0xef {
+        // Interface association descriptors are announced with the
+        // Miscellaneous class, Common subclass and IAD protocol.
+        assert_eq!((desc[5], desc[6]), (0x02, 0x01), "IAD device class");
+    }
+    assert!(desc[17] >= 1, "bNumConfigurations");
+}
+
+/// Check a configuration descriptor and everything that follows it against
+/// the USB 2.0 spec, sections 9.6.3 to 9.6.6, and the interface association
+/// ECN.
+pub fn check_configuration_descriptor(device: &[u8], desc: &[u8]) {
+    assert!(desc.len() >= 9, "configuration descriptor length");
+    assert_eq!(desc[0], 9, "configuration bLength");
+    assert_eq!(desc[1], 2, "configuration bDescriptorType");
+    assert_eq!(
+        u16::from_le_bytes([desc[2], desc[3]]) as usize,
+        desc.len(),
+        "wTotalLength"
+    );
+    let num_interfaces = desc[4];
+    assert!(desc[5] != 0, "bConfigurationValue must not be 0");
+    assert_eq!(desc[7] & 0x9f, 0x80, "bmAttributes reserved bits");
+
+    // Interface number, alternate setting, class, bNumEndpoints and the
+    // number of endpoint descriptors seen.
+    let mut interfaces: Vec<(u8, u8, u8, u8, u8)> = Vec::new();
+    // Addresses of the endpoints of each alternate setting 0.
+    let mut addresses: Vec<u8> = Vec::new();
+    // First interface and count of each interface association.
+    let mut associations: Vec<(u8, u8)> = Vec::new();
+    let mut pending_association: Option<u8> = None;
+
+    let mut offset = 9;
+    while offset < desc.len() {
+        let length = desc[offset] as usize;
+        assert!(length >= 2, "descriptor at {} too short", offset);
+        assert!(
+            offset + length <= desc.len(),
+            "descriptor at {} overruns wTotalLength",
+            offset
+        );
+        let d = &desc[offset..offset + length];
+        match d[1] {
+            // Interface
+            4 => {
+                assert_eq!(length, 9, "interface bLength");
+                if let Some(first) = pending_association.take() {
+                    assert_eq!(d[2], first, "IAD must precede its first interface");
+                }
+                interfaces.push((d[2], d[3], d[5], d[4], 0));
+            }
+            // Endpoint
+            5 => {
+                assert_eq!(length, 7, "endpoint bLength");
+                let interface = interfaces
+                    .last_mut()
+                    .expect("endpoint outside an interface");
+                interface.4 += 1;
+                let address = d[2];
+                let number = address & 0x0f;
+                assert!(number != 0, "endpoint 0 must not be described");
+                assert_eq!(address & 0x70, 0, "endpoint address reserved bits");
+                if interface.1 == 0 {
+                    assert!(
+                        !addresses.contains(&address),
+                        "endpoint address {:#x} used twice",
+                        address
+                    );
+                    addresses.push(address);
+                }
+                let max_packet_size = u16::from_le_bytes([d[4], d[5]]) & 0x7ff;
+                let interval = d[6];
+                match d[3] & 0x03 {
+                    0 => panic!("control endpoints must not be described"),
+                    1 => {
+                        assert!(max_packet_size <= 1023, "isochronous wMaxPacketSize");
+                        assert!((1..=16).contains(&interval), "isochronous bInterval");
+                    }
+                    2 => assert!(
+                        [8, 16, 32, 64].contains(&max_packet_size),
+                        "bulk wMaxPacketSize {}",
+                        max_packet_size
+                    ),
+                    _ => {
+                        assert!(max_packet_size <= 64, "interrupt wMaxPacketSize");
+                        assert!(interval >= 1, "interrupt bInterval");
+                    }
+                }
+            }
+            // Interface association
+            0x0b => {
+                assert_eq!(length, 8, "IAD bLength");
+                assert!(d[3] >= 1, "IAD bInterfaceCount");
+                associations.push((d[2], d[3]));
+                pending_association = Some(d[2]);
+            }
+            // HID, in a HID interface
+            0x21 if interfaces.last().map_or(false, |i| i.2 == 0x03) => {
+                assert!(length >= 9, "HID bLength");
+                assert_eq!(length, 6 + 3 * d[5] as usize, "HID bNumDescriptors");
+            }
+            // Other class-specific descriptors must be in an interface.
+            _ => assert!(
+                !interfaces.is_empty(),
+                "class descriptor before the first interface"
+            ),
+        }
+        offset += length;
+    }
+    assert!(pending_association.is_none(), "IAD without interfaces");
+
+    let mut numbers: Vec<u8> = interfaces
+        .iter()
+        .filter(|interface| interface.1 == 0)
+        .map(|interface| interface.0)
+        .collect();
+    numbers.sort_unstable();
+    let expected: Vec<u8> = (0..num_interfaces).collect();
+    assert_eq!(
+        numbers, expected,
+        "interface numbers must be 0..bNumInterfaces"
+    );
+    for interface in interfaces.iter() {
+        assert_eq!(
+            interface.3, interface.4,
+            "bNumEndpoints of interface {}",
+            interface.0
+        );
+    }
+    for (first, count) in associations.iter() {
+        assert!(
+            first + count <= num_interfaces,
+            "IAD covers missing interfaces"
+        );
+    }
+    if !associations.is_empty() {
+        assert_eq!(device[4], 0xef, "IADs need the Miscellaneous device class");
+    }
+}
+
+/// Check a string descriptor against the USB 2.0 spec, section 9.6.7.
+pub fn check_string_descriptor(desc: &[u8]) {
+    assert!(desc.len() >= 2, "string descriptor length");
+    assert_eq!(desc[0] as usize, desc.len(), "string bLength");
+    assert_eq!(desc[1], 3, "string bDescriptorType");
+    assert_eq!(desc.len() % 2, 0, "string length must be even");
+}
+
+/// Interfaces with a HID descriptor, and the length of their report
+/// descriptor.
+fn hid_report_lengths(desc: &[u8]) -> Vec<(u8, u16)> {
+    let mut reports = Vec::new();
+    let mut interface = None;
+    let mut offset = 9;
+    while offset < desc.len() {
+        let d = &desc[offset..offset + desc[offset] as usize];
+        match d[1] {
+            4 => interface = Some((d[2], d[5])),
+            0x21 => {
+                if let Some((number, 0x03)) = interface {
+                    if d[6] == 0x22 {
+                        reports.push((number, u16::from_le_bytes([d[7], d[8]])));
+                    }
+                }
+            }
+            _ => {}
+        }
+        offset += d.len();
+    }
+    reports
+}
+
+/// An alarm for tests, counting milliseconds that pass when the test calls
+/// `advance()`.
+pub struct SimAlarm<'a> {
+    now: Cell<u32>,
+    alarm: Cell<u32>,
+    armed: Cell<bool>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+}
+
+impl<'a> SimAlarm<'a> {
+    pub fn new() -> SimAlarm<'a> {
+        SimAlarm {
+            now: Cell::new(0),
+            alarm: Cell::new(0),
+            armed: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Let `ms` milliseconds pass, firing the alarm as often as it is due.
+    pub fn advance(&self, ms: u32) {
+        let end = self.now.get() + ms;
+        while self.armed.get() && self.alarm.get() <= end {
+            self.now.set(self.alarm.get());
+            self.armed.set(false);
+            self.client.map(|client| client.alarm());
+        }
+        self.now.set(end);
+    }
+}
+
+impl Time for SimAlarm<'_> {
+    type Frequency = Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        self.now.get().into()
+    }
+}
+
+impl<'a> Alarm<'a> for SimAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.alarm.set(reference.wrapping_add(dt).into_u32());
+        self.armed.set(true);
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        self.alarm.get().into()
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.armed.set(false);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        1.into()
+    }
+}
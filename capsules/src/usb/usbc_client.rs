@@ -2,8 +2,10 @@
 //!
 //! It responds to standard device requests and can be enumerated.
 
+use super::composite::Function;
 use super::descriptors::{
-    self, Buffer8, DeviceDescriptor, EndpointAddress, EndpointDescriptor, TransferDirection,
+    self, Buffer8, DeviceDescriptor, EndpointAddress, EndpointDescriptor, InterfaceDescriptor,
+    SetupData, TransferDirection,
 };
 use super::usbc_client_ctrl::ClientCtrl;
 use core::cell::Cell;
@@ -34,6 +36,9 @@ pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
 
 const N_ENDPOINTS: usize = 2;
 
+const IN_BUFFER: usize = 0;
+const OUT_BUFFER: usize = 1;
+
 pub struct Client<'a, C: 'a> {
     client_ctrl: ClientCtrl<'a, 'static, C>,
 
@@ -45,6 +50,11 @@ pub struct Client<'a, C: 'a> {
     echo_buf: [Cell<u8>; 8], // Must be no larger than endpoint packet buffer
     echo_len: Cell<usize>,
     delayed_out: Cell<bool>,
+
+    // Interface and endpoint numbers, which a composite device can change
+    interface: Cell<u8>,
+    endpoint_in: Cell<usize>,
+    endpoint_out: Cell<usize>,
 }
 
 impl<'a, C: hil::usb::UsbController<'a>> Client<'a, C> {
@@ -107,30 +117,43 @@ impl<'a, C: hil::usb::UsbController<'a>> Client<'a, C> {
             echo_buf: Default::default(),
             echo_len: Cell::new(0),
             delayed_out: Cell::new(false),
+            interface: Cell::new(0),
+            endpoint_in: Cell::new(1),
+            endpoint_out: Cell::new(2),
         }
     }
 
     fn alert_full(&'a self) {
-        // Alert the controller that we now have data to send on the Bulk IN endpoint 1
-        self.controller().endpoint_resume_in(1);
+        // Alert the controller that we now have data to send on the Bulk IN endpoint
+        self.controller().endpoint_resume_in(self.endpoint_in.get());
     }
 
     fn alert_empty(&'a self) {
         // In case we reported Delay before, alert the controller
-        // that we can now receive data on the Bulk OUT endpoint 2
+        // that we can now receive data on the Bulk OUT endpoint
         if self.delayed_out.take() {
-            self.controller().endpoint_resume_out(2);
+            self.controller()
+                .endpoint_resume_out(self.endpoint_out.get());
         }
     }
 
-    #[inline]
-    fn controller(&'a self) -> &'a C {
-        self.client_ctrl.controller()
+    fn enable_endpoints(&'a self) {
+        // Set up a bulk-in endpoint for debugging
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint_in.get(), &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint_in.get());
+
+        // Set up a bulk-out endpoint for debugging
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint_out.get(), &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint_out.get());
     }
 
     #[inline]
-    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 8] {
-        &self.buffers[i - 1].buf
+    fn controller(&'a self) -> &'a C {
+        self.client_ctrl.controller()
     }
 }
 
@@ -139,13 +162,7 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
         // Set up the default control endpoint
         self.client_ctrl.enable();
 
-        // Set up a bulk-in endpoint for debugging
-        self.controller().endpoint_set_in_buffer(1, self.buffer(1));
-        self.controller().endpoint_in_enable(TransferType::Bulk, 1);
-
-        // Set up a bulk-out endpoint for debugging
-        self.controller().endpoint_set_out_buffer(2, self.buffer(2));
-        self.controller().endpoint_out_enable(TransferType::Bulk, 2);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -156,8 +173,6 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
         // Should the client initiate reconfiguration here?
         // For now, the hardware layer does it.
 
-        debug!("Bus reset");
-
         // Reset the state for our pair of debugging endpoints
         self.echo_len.set(0);
         self.delayed_out.set(false);
@@ -199,7 +214,7 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
                 let packet_bytes = self.echo_len.get();
                 if packet_bytes > 0 {
                     // Copy the entire echo buffer into the packet
-                    let packet = self.buffer(endpoint);
+                    let packet = &self.buffers[IN_BUFFER].buf;
                     for i in 0..packet_bytes {
                         packet[i].set(self.echo_buf[i].get());
                     }
@@ -243,7 +258,7 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
                     hil::usb::OutResult::Delay
                 } else if new_len > 0 {
                     // Copy the packet into our echo buffer
-                    let packet = self.buffer(endpoint);
+                    let packet = &self.buffers[OUT_BUFFER].buf;
                     for i in 0..new_len {
                         self.echo_buf[current_len + i].set(packet[i].get());
                     }
@@ -265,3 +280,96 @@ impl<'a, C: hil::usb::UsbController<'a>> hil::usb::Client<'a> for Client<'a, C>
         // Nothing to do.
     }
 }
+
+impl<'a, C: hil::usb::UsbController<'a>> Function<'a> for Client<'a, C> {
+    fn interface_count(&self) -> u8 {
+        1
+    }
+
+    fn endpoint_count(&self) -> usize {
+        1
+    }
+
+    fn function_class(&self) -> (u8, u8, u8) {
+        (0xff, 0xab, 0)
+    }
+
+    fn assign(&self, first_interface: u8, first_endpoint: usize) {
+        // Use the IN and OUT endpoints of the same number
+        self.interface.set(first_interface);
+        self.endpoint_in.set(first_endpoint);
+        self.endpoint_out.set(first_endpoint);
+    }
+
+    fn write_descriptors(&self, buf: &[Cell<u8>]) -> usize {
+        descriptors::write_descriptors(
+            buf,
+            &[
+                &InterfaceDescriptor {
+                    interface_number: self.interface.get(),
+                    num_endpoints: 2,
+                    ..InterfaceDescriptor::default()
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_in.get(),
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 8,
+                    interval: 0,
+                },
+                &EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new(
+                        self.endpoint_out.get(),
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Bulk,
+                    max_packet_size: 8,
+                    interval: 0,
+                },
+            ],
+        )
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        hil::usb::Client::bus_reset(self);
+    }
+
+    fn ctrl_setup(&'a self, setup: SetupData) -> hil::usb::CtrlSetupResult {
+        // Promiscuously accept vendor data, as the standalone client does
+        match setup.request_type.transfer_direction() {
+            TransferDirection::HostToDevice => hil::usb::CtrlSetupResult::Ok,
+            TransferDirection::DeviceToHost => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        _buf: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
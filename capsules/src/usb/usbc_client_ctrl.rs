@@ -425,3 +425,94 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         self.state[endpoint].set(State::Init);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::sim::{setup, SimUsbController, TransferError};
+    use super::super::usbc_client::Client;
+    use kernel::hil::usb::{Client as _, UsbController};
+
+    #[test]
+    fn enumeration() {
+        let usb = SimUsbController::new();
+        let client = Client::new(&usb, 64);
+        usb.set_client(&client);
+        client.enable();
+        client.attach();
+
+        let device = usb.enumerate();
+        // Vendor and product ID
+        assert_eq!(&device.device[8..12], &[0x67, 0x66, 0xcd, 0xab]);
+        assert_eq!(device.strings[0].as_deref(), Some("XYZ Corp."));
+        assert_eq!(device.strings[1].as_deref(), Some("The Zorpinator"));
+        assert_eq!(device.strings[2].as_deref(), Some("Serial No. 5"));
+    }
+
+    #[test]
+    fn address_is_enabled_after_status_stage() {
+        let usb = SimUsbController::new();
+        let client = Client::new(&usb, 64);
+        usb.set_client(&client);
+        client.enable();
+        client.attach();
+
+        assert_eq!(usb.address(), 0);
+        assert_eq!(usb.control_out(setup(0x00, 5, 42, 0, 0), &[]), Ok(()));
+        assert_eq!(usb.address(), 42);
+    }
+
+    #[test]
+    fn short_and_long_reads() {
+        let usb = SimUsbController::new();
+        let client = Client::new(&usb, 64);
+        usb.set_client(&client);
+        client.enable();
+        client.attach();
+
+        // The host gets at most what it asks for ...
+        assert_eq!(usb.get_descriptor(1, 0, 8).unwrap().len(), 8);
+        assert_eq!(usb.get_descriptor(2, 0, 4).unwrap().len(), 4);
+        // ... and no more than the descriptor.
+        assert_eq!(usb.get_descriptor(1, 0, 255).unwrap().len(), 18);
+        let configuration = usb.get_descriptor(2, 0, 255).unwrap();
+        assert_eq!(
+            u16::from_le_bytes([configuration[2], configuration[3]]) as usize,
+            configuration.len()
+        );
+    }
+
+    #[test]
+    fn invalid_requests_stall() {
+        let usb = SimUsbController::new();
+        let client = Client::new(&usb, 64);
+        usb.set_client(&client);
+        client.enable();
+        client.attach();
+
+        // Device qualifier: the device is full-speed only.
+        assert_eq!(usb.get_descriptor(6, 0, 10), Err(TransferError::Stall));
+        assert_eq!(usb.get_descriptor(1, 1, 18), Err(TransferError::Stall));
+        assert_eq!(usb.get_descriptor(2, 1, 9), Err(TransferError::Stall));
+        assert_eq!(usb.get_descriptor(3, 4, 255), Err(TransferError::Stall));
+        // Strings in an unsupported language
+        assert_eq!(
+            usb.control_in(setup(0x80, 6, 0x0301, 0x0407, 255)),
+            Err(TransferError::Stall)
+        );
+    }
+
+    #[test]
+    fn bulk_echo() {
+        let usb = SimUsbController::new();
+        let client = Client::new(&usb, 64);
+        usb.set_client(&client);
+        client.enable();
+        client.attach();
+        usb.enumerate();
+
+        assert_eq!(usb.transfer_in(1), Err(TransferError::Nak));
+        assert_eq!(usb.transfer_out(2, b"hello"), Ok(()));
+        assert_eq!(usb.transfer_in(1), Ok(b"hello".to_vec()));
+        assert_eq!(usb.transfer_in(1), Err(TransferError::Nak));
+    }
+}
@@ -0,0 +1,331 @@
+//! Virtualize a CAN controller.
+//!
+//! `MuxCan` provides shared access to a single CAN controller for multiple
+//! users. `CanDevice` provides access for a single client and implements
+//! `hil::can::Can` itself, so a capsule cannot tell whether it owns the
+//! controller or shares it.
+//!
+//! Bit timing and operating mode are properties of the bus and shared by all
+//! devices. The controller joins the bus when the first device is enabled and
+//! leaves it when the last device is disabled.
+//!
+//! Every device has `DEVICE_FILTERS` acceptance filters. Each filter a device
+//! installs occupies one hardware filter, so `set_filter` fails with ENOMEM
+//! once the controller has none left. A received frame is passed to every
+//! enabled device with a matching filter, at most once per device.
+//!
+//! Frames are sent one at a time. A device that sends while another frame is
+//! on the bus is queued and sent when the controller becomes free.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//! # use capsules::virtual_can::{CanDevice, MuxCan};
+//!
+//! let can_mux = static_init!(MuxCan<'static>, MuxCan::new(&base_peripherals.can1));
+//! hil::can::Can::set_transmit_client(&base_peripherals.can1, can_mux);
+//! hil::can::Can::set_receive_client(&base_peripherals.can1, can_mux);
+//! hil::can::Can::set_state_client(&base_peripherals.can1, can_mux);
+//!
+//! let can_device = static_init!(CanDevice<'static>, CanDevice::new(can_mux));
+//! can_device.setup(); // This is important!
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::can;
+use kernel::ReturnCode;
+
+/// The number of acceptance filters each device provides.
+pub const DEVICE_FILTERS: usize = 4;
+
+pub struct MuxCan<'a> {
+    can: &'a dyn can::Can<'a>,
+    devices: List<'a, CanDevice<'a>>,
+    busy: Cell<bool>,
+    enabled_devices: Cell<usize>,
+}
+
+impl<'a> MuxCan<'a> {
+    pub fn new(can: &'a dyn can::Can<'a>) -> MuxCan<'a> {
+        MuxCan {
+            can,
+            devices: List::new(),
+            busy: Cell::new(false),
+            enabled_devices: Cell::new(0),
+        }
+    }
+
+    /// Find a hardware filter no device uses.
+    fn free_filter(&self) -> Option<usize> {
+        (0..self.can.filter_count()).find(|&index| {
+            !self.devices.iter().any(|device| {
+                device
+                    .filters
+                    .iter()
+                    .any(|slot| slot.get().map_or(false, |(_, hw)| hw == index))
+            })
+        })
+    }
+
+    fn do_next_op(&self) {
+        while !self.busy.get() {
+            let device = match self
+                .devices
+                .iter()
+                .find(|device| device.tx_buffer.is_some())
+            {
+                Some(device) => device,
+                None => break,
+            };
+            let buffer = match device.tx_buffer.take() {
+                Some(buffer) => buffer,
+                None => break,
+            };
+            if !device.enabled.get() {
+                device.transmit_complete(ReturnCode::ECANCEL, buffer);
+                continue;
+            }
+            match self
+                .can
+                .send(device.tx_id.get(), buffer, device.tx_len.get())
+            {
+                Ok(()) => self.busy.set(true),
+                Err((rc, buffer)) => device.transmit_complete(rc, buffer),
+            }
+        }
+    }
+}
+
+impl<'a> can::TransmitClient for MuxCan<'a> {
+    fn transmit_complete(&self, rc: ReturnCode, buffer: &'static mut [u8]) {
+        self.busy.set(false);
+        // The device on the bus is the one sending without a queued buffer
+        if let Some(device) = self
+            .devices
+            .iter()
+            .find(|device| device.sending.get() && device.tx_buffer.is_none())
+        {
+            device.transmit_complete(rc, buffer);
+        }
+        self.do_next_op();
+    }
+}
+
+impl<'a> can::ReceiveClient for MuxCan<'a> {
+    fn message_received(&self, id: can::Id, data: &[u8]) {
+        self.devices.iter().for_each(|device| {
+            if device.enabled.get() && device.accepts(id) {
+                device
+                    .rx_client
+                    .map(|client| client.message_received(id, data));
+            }
+        });
+    }
+}
+
+impl<'a> can::StateClient for MuxCan<'a> {
+    fn state_changed(&self, state: can::ErrorState) {
+        self.devices.iter().for_each(|device| {
+            if device.enabled.get() {
+                device
+                    .state_client
+                    .map(|client| client.state_changed(state));
+            }
+        });
+    }
+}
+
+pub struct CanDevice<'a> {
+    mux: &'a MuxCan<'a>,
+    next: ListLink<'a, CanDevice<'a>>,
+    /// Installed filters and the hardware filter each one occupies.
+    filters: [Cell<Option<(can::Filter, usize)>>; DEVICE_FILTERS],
+    enabled: Cell<bool>,
+    /// A frame is queued or on the bus.
+    sending: Cell<bool>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_id: Cell<can::Id>,
+    tx_len: Cell<usize>,
+    tx_client: OptionalCell<&'a dyn can::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn can::ReceiveClient>,
+    state_client: OptionalCell<&'a dyn can::StateClient>,
+}
+
+impl<'a> CanDevice<'a> {
+    pub fn new(mux: &'a MuxCan<'a>) -> CanDevice<'a> {
+        CanDevice {
+            mux,
+            next: ListLink::empty(),
+            filters: Default::default(),
+            enabled: Cell::new(false),
+            sending: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            tx_id: Cell::new(can::Id::Standard(0)),
+            tx_len: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Must be called right after `static_init!()`.
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+    }
+
+    fn accepts(&self, id: can::Id) -> bool {
+        self.filters
+            .iter()
+            .any(|slot| slot.get().map_or(false, |(filter, _)| filter.matches(id)))
+    }
+
+    fn transmit_complete(&self, rc: ReturnCode, buffer: &'static mut [u8]) {
+        self.sending.set(false);
+        self.tx_client
+            .map(move |client| client.transmit_complete(rc, buffer));
+    }
+}
+
+impl<'a> ListNode<'a, CanDevice<'a>> for CanDevice<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, CanDevice<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> can::Can<'a> for CanDevice<'a> {
+    fn set_transmit_client(&self, client: &'a dyn can::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn can::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_state_client(&self, client: &'a dyn can::StateClient) {
+        self.state_client.set(client);
+    }
+
+    fn set_bit_timing(&self, timing: can::BitTiming) -> ReturnCode {
+        self.mux.can.set_bit_timing(timing)
+    }
+
+    fn set_bitrate(&self, bitrate: u32) -> ReturnCode {
+        self.mux.can.set_bitrate(bitrate)
+    }
+
+    fn set_mode(&self, mode: can::Mode) -> ReturnCode {
+        self.mux.can.set_mode(mode)
+    }
+
+    fn filter_count(&self) -> usize {
+        DEVICE_FILTERS
+    }
+
+    /// In addition to the errors of `hil::can::Can::set_filter`, fails with
+    /// ENOMEM if all hardware filters are in use by other devices.
+    fn set_filter(&self, index: usize, filter: Option<can::Filter>) -> ReturnCode {
+        let slot = match self.filters.get(index) {
+            Some(slot) => slot,
+            None => return ReturnCode::EINVAL,
+        };
+        match filter {
+            Some(filter) => {
+                let hw = match slot.get().map(|(_, hw)| hw) {
+                    Some(hw) => hw,
+                    None => match self.mux.free_filter() {
+                        Some(hw) => hw,
+                        None => return ReturnCode::ENOMEM,
+                    },
+                };
+                let rc = self.mux.can.set_filter(hw, Some(filter));
+                if rc == ReturnCode::SUCCESS {
+                    slot.set(Some((filter, hw)));
+                }
+                rc
+            }
+            None => {
+                if let Some((_, hw)) = slot.take() {
+                    self.mux.can.set_filter(hw, None);
+                }
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn enable(&self) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        if self.mux.enabled_devices.get() == 0 {
+            let rc = self.mux.can.enable();
+            if rc != ReturnCode::SUCCESS && rc != ReturnCode::EALREADY {
+                return rc;
+            }
+        }
+        self.mux
+            .enabled_devices
+            .set(self.mux.enabled_devices.get() + 1);
+        self.enabled.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    /// A queued frame is returned with ECANCEL once the controller is free.
+    /// A frame already on the bus is only aborted if this was the last
+    /// enabled device.
+    fn disable(&self) -> ReturnCode {
+        if !self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.enabled.set(false);
+        let remaining = self.mux.enabled_devices.get() - 1;
+        self.mux.enabled_devices.set(remaining);
+        if remaining == 0 {
+            self.mux.can.disable()
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn send(
+        &self,
+        id: can::Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.sending.get() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if !id.is_valid() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if len > can::MAX_DATA_LEN || len > buffer.len() {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        if !self.mux.busy.get() {
+            self.mux.can.send(id, buffer, len)?;
+            self.mux.busy.set(true);
+        } else {
+            self.tx_id.set(id);
+            self.tx_len.set(len);
+            self.tx_buffer.replace(buffer);
+        }
+        self.sending.set(true);
+        Ok(())
+    }
+
+    fn error_state(&self) -> can::ErrorState {
+        self.mux.can.error_state()
+    }
+
+    fn error_counters(&self) -> (u8, u8) {
+        self.mux.can.error_counters()
+    }
+}
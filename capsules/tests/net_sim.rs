@@ -0,0 +1,262 @@
+//! End-to-end tests of the 802.15.4, 6LoWPAN, IPv6 and UDP stack on
+//! simulated radios.
+
+mod sim;
+
+use kernel::hil::radio;
+use kernel::ReturnCode;
+use sim::medium::MediumConfig;
+use sim::node::MAX_PAYLOAD_LEN;
+use sim::World;
+
+const PORT_A: u16 = 16123;
+const PORT_B: u16 = 16124;
+const TIMEOUT_MS: u32 = 10_000;
+/// The 6LoWPAN reassembly timeout.
+const REASSEMBLY_TIMEOUT_MS: u32 = 60_000;
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+#[test]
+fn single_frame_datagram() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    let data = payload(20);
+    sock_a.send_to(b, PORT_B, &data).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+
+    let received = sock_b.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].payload, data);
+    assert_eq!(received[0].src_addr, a.ip_addr);
+    assert_eq!(received[0].src_port, PORT_A);
+    assert_eq!(received[0].dst_port, PORT_B);
+
+    let frames = world.medium.frames();
+    assert_eq!(frames.len(), 1);
+    assert!(frames[0].acked);
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    assert_eq!(sock_a.send_results(), vec![ReturnCode::SUCCESS]);
+}
+
+#[test]
+fn link_local_headers_are_compressed() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    let data = payload(10);
+    sock_a.send_to(b, PORT_B, &data).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+
+    // Addresses derived from the MAC addresses are elided entirely, so the
+    // frame is much smaller than the uncompressed IPv6 and UDP headers
+    let frames = world.medium.frames();
+    assert_eq!(frames.len(), 1);
+    assert!(frames[0].psdu.len() < 40 + 8 + data.len());
+}
+
+#[test]
+fn fragmented_datagram_is_reassembled() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    for &len in &[200, 600, MAX_PAYLOAD_LEN] {
+        let data = payload(len);
+        sock_a.send_to(b, PORT_B, &data).unwrap();
+        assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+        let received = sock_b.received();
+        assert_eq!(received.last().map(|dgram| &dgram.payload), Some(&data));
+    }
+    assert_eq!(sock_b.received().len(), 3);
+    assert_eq!(sock_a.send_results(), vec![ReturnCode::SUCCESS; 3]);
+
+    let frames = world.medium.frames();
+    assert!(frames.len() > 3);
+    assert!(frames
+        .iter()
+        .all(|frame| frame.psdu.len() <= radio::MAX_FRAME_SIZE));
+}
+
+#[test]
+fn lost_fragment_prevents_delivery() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    world.medium.drop_frame(1);
+    sock_a.send_to(b, PORT_B, &payload(400)).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    world.run_for(1000);
+    assert!(sock_b.received().is_empty());
+    assert!(!world.medium.frames()[1].acked);
+
+    // The incomplete datagram holds the only reassembly buffer of B until
+    // the reassembly timeout, after which datagrams get through again
+    sock_a.send_to(b, PORT_B, &payload(30)).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    world.run_for(1000);
+    assert!(sock_b.received().is_empty());
+
+    world.run_for(REASSEMBLY_TIMEOUT_MS);
+    let data = payload(30);
+    sock_a.send_to(b, PORT_B, &data).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+    assert_eq!(sock_b.received()[0].payload, data);
+}
+
+#[test]
+fn total_loss_delivers_nothing() {
+    let mut world = World::new(MediumConfig {
+        loss: 1.0,
+        ..MediumConfig::default()
+    });
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    sock_a.send_to(b, PORT_B, &payload(300)).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    world.run_for(1000);
+    assert!(sock_b.received().is_empty());
+    let frames = world.medium.frames();
+    assert!(!frames.is_empty());
+    assert!(frames
+        .iter()
+        .all(|frame| frame.reached.is_empty() && !frame.acked));
+}
+
+#[test]
+fn lossy_medium_is_deterministic() {
+    fn run(seed: u64) -> (usize, Vec<Vec<usize>>) {
+        let mut world = World::new(MediumConfig {
+            loss: 0.3,
+            seed: seed,
+            ..MediumConfig::default()
+        });
+        let a = world.add_node();
+        let b = world.add_node();
+        let sock_a = a.bind(PORT_A);
+        let sock_b = b.bind(PORT_B);
+        for _ in 0..10 {
+            sock_a.send_to(b, PORT_B, &payload(50)).unwrap();
+            assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+        }
+        world.run_for(1000);
+        let reached = world
+            .medium
+            .frames()
+            .into_iter()
+            .map(|frame| frame.reached)
+            .collect();
+        (sock_b.received().len(), reached)
+    }
+
+    let (received, reached) = run(7);
+    assert!(received > 0 && received < 10);
+    assert_eq!(run(7), (received, reached));
+}
+
+#[test]
+fn latency_delays_delivery() {
+    let latency_ms = 50;
+    let mut world = World::new(MediumConfig {
+        latency_ms: latency_ms,
+        ..MediumConfig::default()
+    });
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    let start = world.now();
+    sock_a.send_to(b, PORT_B, &payload(20)).unwrap();
+    assert!(!world.run_until(latency_ms - 1, || !sock_b.received().is_empty()));
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+    assert_eq!(sock_b.received()[0].time, start + latency_ms);
+}
+
+#[test]
+fn frames_above_mtu_are_lost() {
+    let mut world = World::new(MediumConfig {
+        mtu: 80,
+        ..MediumConfig::default()
+    });
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    // A small datagram fits in one short frame
+    let small = payload(10);
+    sock_a.send_to(b, PORT_B, &small).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+
+    // Fragments of a large one are full-sized frames, which do not fit
+    sock_a.send_to(b, PORT_B, &payload(500)).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    world.run_for(1000);
+    assert_eq!(sock_b.received().len(), 1);
+    assert_eq!(sock_b.received()[0].payload, small);
+}
+
+#[test]
+fn frames_are_filtered_by_address() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let c = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+    let sock_c = c.bind(PORT_B);
+
+    let data = payload(300);
+    sock_a.send_to(c, PORT_B, &data).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.is_sending()));
+    world.run_for(1000);
+
+    assert!(sock_b.received().is_empty());
+    assert_eq!(sock_c.received().len(), 1);
+    assert_eq!(sock_c.received()[0].payload, data);
+    // Every frame reached both radios, but only C accepted them
+    assert!(world
+        .medium
+        .frames()
+        .iter()
+        .all(|frame| frame.reached == vec![b.id, c.id] && frame.acked));
+}
+
+#[test]
+fn nodes_exchange_datagrams_both_ways() {
+    let mut world = World::new(MediumConfig::default());
+    let a = world.add_node();
+    let b = world.add_node();
+    let sock_a = a.bind(PORT_A);
+    let sock_b = b.bind(PORT_B);
+
+    let request = payload(150);
+    let response = payload(250);
+    sock_a.send_to(b, PORT_B, &request).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_b.received().is_empty()));
+    sock_b.send_to(a, PORT_A, &response).unwrap();
+    assert!(world.run_until(TIMEOUT_MS, || !sock_a.received().is_empty()));
+
+    assert_eq!(sock_b.received()[0].payload, request);
+    assert_eq!(sock_a.received()[0].payload, response);
+    assert_eq!(sock_a.received()[0].src_addr, b.ip_addr);
+}
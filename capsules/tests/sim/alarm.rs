@@ -0,0 +1,125 @@
+//! Simulated time.
+//!
+//! A `Clock` holds the current time of a simulation in milliseconds and
+//! the `SimAlarm`s that run off it. Time only advances when the simulation
+//! moves it to the next deadline.
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{Alarm, AlarmClient, Freq1KHz, Ticks, Ticks32, Time};
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+
+pub struct Clock {
+    now: Cell<u32>,
+    alarms: RefCell<Vec<&'static SimAlarm>>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            now: Cell::new(0),
+            alarms: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    /// Creates an alarm that runs off this clock.
+    pub fn new_alarm(&'static self) -> &'static SimAlarm {
+        let alarm = Box::leak(Box::new(SimAlarm {
+            clock: self,
+            armed: Cell::new(None),
+            client: OptionalCell::empty(),
+        }));
+        self.alarms.borrow_mut().push(alarm);
+        alarm
+    }
+
+    /// The time of the earliest armed alarm.
+    pub fn next_deadline(&self) -> Option<u32> {
+        let now = self.now();
+        self.alarms
+            .borrow()
+            .iter()
+            .filter_map(|alarm| alarm.remaining(now))
+            .min()
+            .map(|remaining| now + remaining)
+    }
+
+    pub fn advance_to(&self, time: u32) {
+        if time > self.now() {
+            self.now.set(time);
+        }
+    }
+
+    /// Fires the alarms that are due. Returns true if any alarm fired.
+    pub fn fire_due(&self) -> bool {
+        let now = self.now();
+        // Clients may arm alarms again, so iterate over a copy
+        let alarms = self.alarms.borrow().clone();
+        let mut fired = false;
+        for alarm in alarms {
+            if alarm.remaining(now) == Some(0) {
+                alarm.armed.set(None);
+                alarm.client.map(|client| client.alarm());
+                fired = true;
+            }
+        }
+        fired
+    }
+}
+
+pub struct SimAlarm {
+    clock: &'static Clock,
+    /// Reference and interval of the armed alarm
+    armed: Cell<Option<(u32, u32)>>,
+    client: OptionalCell<&'static dyn AlarmClient>,
+}
+
+impl SimAlarm {
+    /// The time until the alarm fires, if it is armed.
+    fn remaining(&self, now: u32) -> Option<u32> {
+        self.armed
+            .get()
+            .map(|(reference, dt)| dt.saturating_sub(now.wrapping_sub(reference)))
+    }
+}
+
+impl Time for SimAlarm {
+    type Frequency = Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.clock.now())
+    }
+}
+
+impl Alarm<'static> for SimAlarm {
+    fn set_alarm_client(&'static self, client: &'static dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.armed.set(Some((reference.into_u32(), dt.into_u32())));
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        let (reference, dt) = self.armed.get().unwrap_or((0, 0));
+        Ticks32::from(reference.wrapping_add(dt))
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.armed.set(None);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        Ticks32::from(1)
+    }
+}
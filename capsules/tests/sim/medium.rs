@@ -0,0 +1,212 @@
+//! An in-memory 802.15.4 medium connecting simulated radios.
+//!
+//! Every frame a radio transmits reaches all other radios after the
+//! configured latency, unless it is lost. Each receiver loses a frame
+//! independently with the configured probability, and frames with a PSDU
+//! longer than the configured MTU reach no one. A transmission is
+//! acknowledged if a receiver accepted the frame and the acknowledgement
+//! was not lost either.
+//!
+//! Losses are drawn from a seeded generator, so a simulation always runs
+//! the same way. Individual frames can also be dropped on purpose with
+//! `drop_frame()`.
+
+use super::alarm::Clock;
+use capsules::ieee802154::sim_radio::{SimMedium, SimRadio};
+use kernel::hil::radio;
+use kernel::ReturnCode;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+
+#[derive(Copy, Clone, Debug)]
+pub struct MediumConfig {
+    /// Probability that a receiver loses a frame or an acknowledgement.
+    pub loss: f64,
+    /// Time from the start of a transmission until it is received, in ms.
+    pub latency_ms: u32,
+    /// The longest PSDU that can be transmitted.
+    pub mtu: usize,
+    /// Seed of the generator that decides which frames are lost.
+    pub seed: u64,
+}
+
+impl Default for MediumConfig {
+    fn default() -> MediumConfig {
+        MediumConfig {
+            loss: 0.0,
+            latency_ms: 2,
+            mtu: radio::MAX_FRAME_SIZE,
+            seed: 1,
+        }
+    }
+}
+
+/// A frame that was transmitted on the medium.
+#[derive(Clone, Debug)]
+pub struct FrameRecord {
+    pub time: u32,
+    pub from: usize,
+    /// The PSDU, including the MFR.
+    pub psdu: Vec<u8>,
+    /// The radios the frame reached, whether or not they accepted it.
+    pub reached: Vec<usize>,
+    pub acked: bool,
+}
+
+enum EventKind {
+    /// Frame `index` of the frame log reaches the other radios.
+    Arrival(usize),
+    Service(usize),
+}
+
+struct Event {
+    time: u32,
+    kind: EventKind,
+}
+
+pub struct Medium {
+    clock: &'static Clock,
+    config: MediumConfig,
+    rng: Cell<u64>,
+    radios: RefCell<Vec<&'static SimRadio<'static>>>,
+    events: RefCell<Vec<Event>>,
+    frames: RefCell<Vec<FrameRecord>>,
+    drop: RefCell<BTreeSet<usize>>,
+}
+
+impl Medium {
+    pub fn new(clock: &'static Clock, config: MediumConfig) -> Medium {
+        Medium {
+            clock: clock,
+            config: config,
+            // xorshift needs a nonzero state
+            rng: Cell::new(config.seed | 1),
+            radios: RefCell::new(Vec::new()),
+            events: RefCell::new(Vec::new()),
+            frames: RefCell::new(Vec::new()),
+            drop: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    /// Connects `radio` to the medium. Radios must be added in the order of
+    /// their IDs.
+    pub fn add_radio(&'static self, radio: &'static SimRadio<'static>) {
+        assert_eq!(radio.id(), self.radios.borrow().len());
+        radio.set_medium(self);
+        self.radios.borrow_mut().push(radio);
+    }
+
+    /// Makes frame `index` (counting from 0 in the order of transmission)
+    /// reach no one.
+    pub fn drop_frame(&self, index: usize) {
+        self.drop.borrow_mut().insert(index);
+    }
+
+    /// All frames transmitted so far.
+    pub fn frames(&self) -> Vec<FrameRecord> {
+        self.frames.borrow().clone()
+    }
+
+    fn lost(&self) -> bool {
+        if self.config.loss <= 0.0 {
+            return false;
+        }
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64 <= self.config.loss
+    }
+
+    fn schedule(&self, time: u32, kind: EventKind) {
+        self.events.borrow_mut().push(Event {
+            time: time,
+            kind: kind,
+        });
+    }
+
+    /// The time of the earliest pending event.
+    pub fn next_event(&self) -> Option<u32> {
+        self.events.borrow().iter().map(|event| event.time).min()
+    }
+
+    /// Processes the earliest event if it is due. Returns true if an event
+    /// was processed.
+    pub fn process_next(&self) -> bool {
+        let now = self.clock.now();
+        let event = {
+            let mut events = self.events.borrow_mut();
+            // Events due at the same time run in the order they were
+            // scheduled
+            match events
+                .iter()
+                .enumerate()
+                .filter(|(_, event)| event.time <= now)
+                .min_by_key(|(_, event)| event.time)
+                .map(|(i, _)| i)
+            {
+                Some(i) => events.remove(i),
+                None => return false,
+            }
+        };
+        match event.kind {
+            EventKind::Arrival(index) => self.arrive(index),
+            EventKind::Service(id) => {
+                let radio = self.radios.borrow()[id];
+                radio.service();
+            }
+        }
+        true
+    }
+
+    fn arrive(&self, index: usize) {
+        let (from, psdu) = {
+            let frames = self.frames.borrow();
+            (frames[index].from, frames[index].psdu.clone())
+        };
+        let radios = self.radios.borrow().clone();
+        let dropped = self.drop.borrow().contains(&index) || psdu.len() > self.config.mtu;
+        let mut reached = Vec::new();
+        let mut acked = false;
+        if !dropped {
+            for radio in radios.iter().filter(|radio| radio.id() != from) {
+                if self.lost() {
+                    continue;
+                }
+                if radio.deliver(&psdu, true) && !self.lost() {
+                    acked = true;
+                }
+                reached.push(radio.id());
+            }
+        }
+        {
+            let mut frames = self.frames.borrow_mut();
+            frames[index].reached = reached;
+            frames[index].acked = acked;
+        }
+        radios[from].transmit_done(acked, ReturnCode::SUCCESS);
+    }
+}
+
+impl SimMedium for Medium {
+    fn transmit(&self, id: usize, frame: &[u8]) {
+        let now = self.clock.now();
+        let index = {
+            let mut frames = self.frames.borrow_mut();
+            frames.push(FrameRecord {
+                time: now,
+                from: id,
+                psdu: frame.to_vec(),
+                reached: Vec::new(),
+                acked: false,
+            });
+            frames.len() - 1
+        };
+        self.schedule(now + self.config.latency_ms, EventKind::Arrival(index));
+    }
+
+    fn service_requested(&self, id: usize) {
+        self.schedule(self.clock.now(), EventKind::Service(id));
+    }
+}
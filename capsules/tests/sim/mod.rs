@@ -0,0 +1,195 @@
+//! Host simulation of 802.15.4 networks running the kernel network stack.
+//!
+//! A `World` holds a simulated clock, a `Medium` and the nodes on it. It
+//! runs the simulation as a discrete event loop: time jumps to the next
+//! alarm or frame arrival, so simulations run as fast as the host allows
+//! and always run the same way.
+//!
+//! The kernel objects of a simulation are leaked, as they need `'static`
+//! lifetimes, and `debug!` output goes to a process-wide debug writer. So
+//! that simulations do not share state, a `World` holds a lock for its
+//! whole lifetime and simulations run one at a time.
+
+pub mod alarm;
+pub mod medium;
+pub mod node;
+
+use self::alarm::Clock;
+use self::medium::{Medium, MediumConfig};
+use self::node::Node;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::ring_buffer::RingBuffer;
+use kernel::debug::{self, DebugWriter, DebugWriterWrapper};
+use kernel::hil::uart::{Transmit, TransmitClient};
+use kernel::ReturnCode;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Once;
+use std::thread;
+
+/// Set while a `World` exists.
+static SIMULATION: AtomicBool = AtomicBool::new(false);
+static DEBUG_INIT: Once = Once::new();
+static DEBUG_UART: AtomicPtr<DebugUart> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The UART under the debug writer. Transmissions are printed and
+/// completed by the simulation loop, as a UART may not call back from
+/// within `transmit_buffer`.
+struct DebugUart {
+    client: OptionalCell<&'static dyn TransmitClient>,
+    buf: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+}
+
+impl DebugUart {
+    fn flush(&self) {
+        self.buf.take().map(|buf| {
+            let len = self.len.get();
+            print!("{}", String::from_utf8_lossy(&buf[..len]));
+            self.client
+                .map(move |client| client.transmitted_buffer(buf, len, ReturnCode::SUCCESS));
+        });
+    }
+}
+
+impl Transmit<'static> for DebugUart {
+    fn set_transmit_client(&self, client: &'static dyn TransmitClient) {
+        self.client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.buf.is_some() {
+            return (ReturnCode::EBUSY, Some(tx_buffer));
+        }
+        self.len.set(tx_len);
+        self.buf.replace(tx_buffer);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn transmit_word(&self, _word: u32) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+
+    fn transmit_abort(&self) -> ReturnCode {
+        ReturnCode::SUCCESS
+    }
+}
+
+fn debug_uart() -> &'static DebugUart {
+    DEBUG_INIT.call_once(|| {
+        let uart: &'static DebugUart = Box::leak(Box::new(DebugUart {
+            client: OptionalCell::empty(),
+            buf: TakeCell::empty(),
+            len: Cell::new(0),
+        }));
+        let ring = Box::leak(Box::new(RingBuffer::new(Box::leak(
+            vec![0; 4096].into_boxed_slice(),
+        ))));
+        let writer = Box::leak(Box::new(DebugWriter::new(
+            uart,
+            Box::leak(vec![0; 1024].into_boxed_slice()),
+            ring,
+        )));
+        uart.set_transmit_client(writer);
+        let wrapper = Box::leak(Box::new(DebugWriterWrapper::new(writer)));
+        // Safe as simulations, which are the only users of the debug
+        // writer, run one at a time
+        unsafe { debug::set_debug_writer_wrapper(wrapper) };
+        DEBUG_UART.store(uart as *const DebugUart as *mut DebugUart, Ordering::SeqCst);
+    });
+    // The UART is leaked, and only used while holding the simulation lock
+    unsafe { &*DEBUG_UART.load(Ordering::SeqCst) }
+}
+
+pub struct World {
+    pub clock: &'static Clock,
+    pub medium: &'static Medium,
+    nodes: Vec<&'static Node>,
+}
+
+impl World {
+    pub fn new(config: MediumConfig) -> World {
+        while SIMULATION
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+        debug_uart();
+        let clock: &'static Clock = Box::leak(Box::new(Clock::new()));
+        let medium = Box::leak(Box::new(Medium::new(clock, config)));
+        World {
+            clock: clock,
+            medium: medium,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Adds a node to the medium.
+    pub fn add_node(&mut self) -> &'static Node {
+        let node = Box::leak(Box::new(Node::new(
+            self.nodes.len(),
+            self.clock,
+            self.medium,
+        )));
+        self.nodes.push(node);
+        node
+    }
+
+    pub fn now(&self) -> u32 {
+        self.clock.now()
+    }
+
+    /// The time of the next event.
+    fn next_event(&self) -> Option<u32> {
+        match (self.medium.next_event(), self.clock.next_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Runs the next event. Returns false if there is nothing left to do.
+    pub fn step(&self) -> bool {
+        let next = match self.next_event() {
+            Some(next) => next,
+            None => return false,
+        };
+        self.clock.advance_to(next);
+        if !self.medium.process_next() {
+            self.clock.fire_due();
+        }
+        debug_uart().flush();
+        true
+    }
+
+    /// Runs until `done` returns true or `timeout_ms` of simulated time
+    /// have passed. Returns the result of `done`.
+    pub fn run_until<F: Fn() -> bool>(&self, timeout_ms: u32, done: F) -> bool {
+        let deadline = self.now() + timeout_ms;
+        while !done() {
+            if self.next_event().map_or(true, |next| next > deadline) || !self.step() {
+                self.clock.advance_to(deadline);
+                return done();
+            }
+        }
+        true
+    }
+
+    /// Runs until nothing is left to do or `timeout_ms` of simulated time
+    /// have passed.
+    pub fn run_for(&self, timeout_ms: u32) {
+        self.run_until(timeout_ms, || false);
+    }
+}
+
+impl Drop for World {
+    fn drop(&mut self) {
+        // Also runs when a simulation panics, which leaves nothing behind
+        // that later simulations use
+        SIMULATION.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,307 @@
+//! A simulated node running the full 802.15.4 and UDP/IPv6 stack.
+//!
+//! Each node is wired the same way as the UDP stack of a board (see the
+//! `udp_mux` component): a `SimRadio` under `AwakeMac`, `Framer`, `MuxMac`,
+//! 6LoWPAN, IPv6 and the UDP muxes. Nodes use the link-local address
+//! derived from their extended MAC address.
+
+use super::alarm::{Clock, SimAlarm};
+use super::medium::Medium;
+use capsules::ieee802154::device::MacDevice;
+use capsules::ieee802154::framer::Framer;
+use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::ieee802154::sim_radio::SimRadio;
+use capsules::ieee802154::virtual_mac::{MacUser, MuxMac};
+use capsules::net::ieee802154::MacAddress;
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::network_capabilities::{
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::sixlowpan::sixlowpan_compression::Context;
+use capsules::net::sixlowpan::sixlowpan_state::{RxState, Sixlowpan, SixlowpanState, TxState};
+use capsules::net::udp::udp_port_table::{
+    NoUserPorts, SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS,
+};
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver, UDPRecvClient};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendClient, UDPSendStruct, UDPSender};
+use capsules::net::udp::UDPHeader;
+use kernel::capabilities;
+use kernel::common::cells::TakeCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::create_capability;
+use kernel::hil::radio::{self, RadioConfig, RadioData};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM};
+use kernel::hil::time::Alarm;
+use kernel::ReturnCode;
+use std::cell::RefCell;
+
+pub const PAN_ID: u16 = 0xabcd;
+/// The largest IPv6 packet a node can reassemble.
+pub const MAX_PACKET_LEN: usize = 1280;
+/// The largest UDP payload a node can send.
+pub const MAX_PAYLOAD_LEN: usize = MAX_PACKET_LEN - 40 - 8;
+
+pub type NodeIpSender = IP6SendStruct<'static, SimAlarm>;
+
+fn leak<T>(value: T) -> &'static T {
+    Box::leak(Box::new(value))
+}
+
+fn leak_buf(len: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; len].into_boxed_slice())
+}
+
+/// Link-layer security is not simulated; secured frames fail to be sent.
+struct NoCcm;
+
+impl AES128CCM<'static> for NoCcm {
+    fn set_client(&'static self, _client: &'static dyn CCMClient) {}
+
+    fn set_key(&self, _key: &[u8]) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn set_nonce(&self, _nonce: &[u8]) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        _a_off: usize,
+        _m_off: usize,
+        _m_len: usize,
+        _mic_len: usize,
+        _confidential: bool,
+        _encrypting: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        (ReturnCode::ENOSUPPORT, Some(buf))
+    }
+}
+
+pub struct Node {
+    pub id: usize,
+    pub radio: &'static SimRadio<'static>,
+    pub mac_addr: MacAddress,
+    pub ip_addr: IPAddr,
+    pub ip_send: &'static NodeIpSender,
+    pub ip_recv: &'static IP6RecvStruct<'static>,
+    udp_send_mux: &'static MuxUdpSender<'static, NodeIpSender>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    udp_vis: &'static UdpVisibilityCapability,
+    net_cap: &'static NetworkCapability,
+    clock: &'static Clock,
+}
+
+impl Node {
+    pub fn new(id: usize, clock: &'static Clock, medium: &'static Medium) -> Node {
+        let mac_long = [0x02, 0, 0, 0, 0, 0, 0x10, id as u8];
+        let mac_addr = MacAddress::Long(mac_long);
+        let ip_addr = IPAddr::generate_from_mac(mac_addr);
+
+        let radio = leak(SimRadio::new(id));
+        medium.add_radio(radio);
+        radio.set_address(0x1000 + id as u16);
+        radio.set_address_long(mac_long);
+        radio.set_pan(PAN_ID);
+        radio.start();
+
+        let awake_mac = leak(AwakeMac::new(radio));
+        radio.set_transmit_client(awake_mac);
+        radio.set_receive_client(awake_mac, leak_buf(radio::MAX_BUF_SIZE));
+
+        let framer = leak(Framer::new(awake_mac, leak(NoCcm)));
+        awake_mac.set_transmit_client(framer);
+        awake_mac.set_receive_client(framer);
+        awake_mac.set_config_client(framer);
+
+        let mux_mac = leak(MuxMac::new(framer));
+        framer.set_transmit_client(mux_mac);
+        framer.set_receive_client(mux_mac);
+        let mac_user = leak(MacUser::new(mux_mac));
+        mux_mac.add_user(mac_user);
+
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = leak(UdpVisibilityCapability::new(&create_cap));
+        let ip_vis = leak(IpVisibilityCapability::new(&create_cap));
+        let net_cap = leak(NetworkCapability::new(
+            AddrRange::Any,
+            PortRange::Any,
+            PortRange::Any,
+            &create_cap,
+        ));
+
+        let ip_alarm = clock.new_alarm();
+        let sixlowpan = leak(Sixlowpan::new(
+            Context {
+                prefix: [0; 16],
+                prefix_len: 0,
+                id: 0,
+                compress: false,
+            },
+            clock.new_alarm(),
+        ));
+        let sixlowpan_state = sixlowpan as &dyn SixlowpanState;
+        sixlowpan_state.add_rx_state(leak(RxState::new(leak_buf(MAX_PACKET_LEN))));
+        mac_user.set_receive_client(sixlowpan);
+
+        let ip6_packet = Box::leak(Box::new(IP6Packet::new(IPPayload::new(
+            TransportHeader::UDP(UDPHeader::new()),
+            leak_buf(MAX_PACKET_LEN - 40),
+        ))));
+        let ip_send = leak(IP6SendStruct::new(
+            ip6_packet,
+            ip_alarm,
+            leak_buf(radio::MAX_BUF_SIZE),
+            TxState::new(sixlowpan_state),
+            mac_user,
+            mac_addr,
+            mac_addr,
+            ip_vis,
+        ));
+        ip_alarm.set_alarm_client(ip_send);
+        ip_send.set_addr(ip_addr);
+        mac_user.set_transmit_client(ip_send);
+
+        let ip_recv = leak(IP6RecvStruct::new());
+        sixlowpan_state.set_rx_client(ip_recv);
+        let udp_recv_mux = leak(MuxUdpReceiver::new());
+        ip_recv.set_client(udp_recv_mux);
+        let udp_send_mux = leak(MuxUdpSender::new(ip_send));
+        ip_send.set_client(udp_send_mux);
+
+        let table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let driver_cap = create_capability!(capabilities::UdpDriverCapability);
+        let used_ports: &'static mut [Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS] =
+            Box::leak(Box::new([None; MAX_NUM_BOUND_PORTS]));
+        let port_table = leak(UdpPortManager::new(&table_cap, used_ports, udp_vis));
+        port_table.set_user_ports(leak(NoUserPorts), &driver_cap);
+
+        Node {
+            id: id,
+            radio: radio,
+            mac_addr: mac_addr,
+            ip_addr: ip_addr,
+            ip_send: ip_send,
+            ip_recv: ip_recv,
+            udp_send_mux: udp_send_mux,
+            udp_recv_mux: udp_recv_mux,
+            port_table: port_table,
+            udp_vis: udp_vis,
+            net_cap: net_cap,
+            clock: clock,
+        }
+    }
+
+    /// Binds a UDP socket to `port`.
+    pub fn bind(&'static self, port: u16) -> &'static Socket {
+        let udp_send = leak(UDPSendStruct::new(self.udp_send_mux, self.udp_vis));
+        let udp_recv = leak(UDPReceiver::new());
+        let (tx, rx) = self
+            .port_table
+            .bind(
+                self.port_table.create_socket().expect("no free socket"),
+                port,
+                self.net_cap,
+            )
+            .expect("port is already bound");
+        udp_send.set_binding(tx);
+        udp_recv.set_binding(rx);
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let socket = leak(Socket {
+            node: self,
+            udp_send: udp_send,
+            buf: TakeCell::new(leak_buf(MAX_PAYLOAD_LEN)),
+            received: RefCell::new(Vec::new()),
+            send_results: RefCell::new(Vec::new()),
+        });
+        udp_send.set_client(socket);
+        udp_recv.set_client(socket);
+        socket
+    }
+}
+
+/// A datagram received by a socket.
+#[derive(Clone, Debug)]
+pub struct Datagram {
+    pub time: u32,
+    pub src_addr: IPAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: Vec<u8>,
+}
+
+/// A kernel UDP socket that records what it receives and the results of
+/// its sends.
+pub struct Socket {
+    node: &'static Node,
+    udp_send: &'static UDPSendStruct<'static, NodeIpSender>,
+    buf: TakeCell<'static, [u8]>,
+    received: RefCell<Vec<Datagram>>,
+    send_results: RefCell<Vec<ReturnCode>>,
+}
+
+impl Socket {
+    /// Sends `payload` to `port` of node `dst`, which is also the next hop.
+    pub fn send_to(&self, dst: &Node, port: u16, payload: &[u8]) -> Result<(), ReturnCode> {
+        let buf = self.buf.take().ok_or(ReturnCode::EBUSY)?;
+        if payload.len() > buf.len() {
+            self.buf.replace(buf);
+            return Err(ReturnCode::ESIZE);
+        }
+        buf[..payload.len()].copy_from_slice(payload);
+        let mut lease = LeasableBuffer::new(buf);
+        lease.slice(0..payload.len());
+        self.node.ip_send.set_gateway(dst.mac_addr);
+        self.udp_send
+            .send_to(dst.ip_addr, port, lease, self.node.net_cap)
+            .map_err(|lease| {
+                self.buf.replace(lease.take());
+                ReturnCode::FAIL
+            })
+    }
+
+    pub fn received(&self) -> Vec<Datagram> {
+        self.received.borrow().clone()
+    }
+
+    /// The results of completed sends, in order.
+    pub fn send_results(&self) -> Vec<ReturnCode> {
+        self.send_results.borrow().clone()
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.buf.is_none()
+    }
+}
+
+impl UDPSendClient for Socket {
+    fn send_done(&self, result: ReturnCode, dgram: LeasableBuffer<'static, u8>) {
+        self.buf.replace(dgram.take());
+        self.send_results.borrow_mut().push(result);
+    }
+}
+
+impl UDPRecvClient for Socket {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+    ) {
+        self.received.borrow_mut().push(Datagram {
+            time: self.node.clock.now(),
+            src_addr: src_addr,
+            src_port: src_port,
+            dst_port: dst_port,
+            payload: payload.to_vec(),
+        });
+    }
+}
@@ -475,6 +475,16 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Ble<'a> {
     }
 }
 
+impl ble_advertising::BleScanDriver for Ble<'_> {
+    fn set_scan_response(&self, _pdu: &[u8]) -> kernel::ReturnCode {
+        kernel::ReturnCode::ENOSUPPORT
+    }
+
+    fn set_scan_request_address(&self, _address: Option<[u8; 6]>) -> kernel::ReturnCode {
+        kernel::ReturnCode::ENOSUPPORT
+    }
+}
+
 impl ble_advertising::BleConfig for Ble<'_> {
     fn set_tx_power(&self, _tx_power: u8) -> kernel::ReturnCode {
         kernel::ReturnCode::SUCCESS
@@ -11,6 +11,7 @@ use core::slice;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::StaticRef;
 use kernel::debug;
+use kernel::hil::ethernet::{EthernetAdapter, EthernetAdapterClient};
 use kernel::ReturnCode;
 
 // Both events have the same index since they are located on different
@@ -80,11 +81,6 @@ impl<R: LiteXSoCRegisterConfiguration> LiteEthMacRegisters<R> {
     }
 }
 
-pub trait LiteEthClient {
-    fn tx_done(&self, rc: ReturnCode, packet_buffer: &'static mut [u8]);
-    fn rx_packet(&self, packet: &'static mut [u8], len: usize);
-}
-
 pub struct LiteEth<'a, R: LiteXSoCRegisterConfiguration> {
     mac_regs: StaticRef<LiteEthMacRegisters<R>>,
     mac_memory_base: usize,
@@ -92,7 +88,7 @@ pub struct LiteEth<'a, R: LiteXSoCRegisterConfiguration> {
     slot_size: usize,
     rx_slots: usize,
     tx_slots: usize,
-    client: OptionalCell<&'a dyn LiteEthClient>,
+    client: OptionalCell<&'a dyn EthernetAdapterClient>,
     tx_packet: TakeCell<'static, [u8]>,
     rx_buffer: TakeCell<'static, [u8]>,
     initialized: Cell<bool>,
@@ -122,10 +118,6 @@ impl<'a, R: LiteXSoCRegisterConfiguration> LiteEth<'a, R> {
         }
     }
 
-    pub fn set_client(&self, client: &'a dyn LiteEthClient) {
-        self.client.set(client);
-    }
-
     pub fn initialize(&self) {
         // Sanity check the memory parameters
         //
@@ -173,21 +165,6 @@ impl<'a, R: LiteXSoCRegisterConfiguration> LiteEth<'a, R> {
         ))
     }
 
-    pub fn return_rx_buffer(&self, rx_buffer: &'static mut [u8]) {
-        // Assert that we won't overwrite a buffer
-        assert!(
-            self.rx_buffer.is_none(),
-            "LiteEth: return RX buffer while one is registered"
-        );
-
-        // Put the buffer back
-        self.rx_buffer.replace(rx_buffer);
-
-        // In case we received a packet RX interrupt but couldn't
-        // handle it due to the missing buffer, reenable RX interrupts
-        self.mac_regs.rx_ev().enable_event(LITEETH_RX_EVENT);
-    }
-
     fn rx_interrupt(&self) {
         // Check whether we have a buffer to read the packet into. If
         // not, we must disable, but not clear the event and enable it
@@ -232,12 +209,49 @@ impl<'a, R: LiteXSoCRegisterConfiguration> LiteEth<'a, R> {
         }
     }
 
+    fn tx_interrupt(&self) {
+        // Deassert the interrupt, but can be left enabled
+        self.mac_regs.tx_ev().clear_event(LITEETH_TX_EVENT);
+
+        if self.tx_packet.is_none() {
+            debug!("LiteEth: tx interrupt called without tx_packet set");
+        }
+
+        // We use only one slot, so this event is unambiguous
+        let packet = self
+            .tx_packet
+            .take()
+            .expect("LiteEth: TakeCell empty in tx callback");
+        self.client
+            .map(move |client| client.tx_done(ReturnCode::SUCCESS, packet));
+    }
+
+    pub fn service_interrupt(&self) {
+        // The interrupt could've been generated by both a packet
+        // being received or finished transmitting. Check and handle
+        // both cases
+
+        if self.mac_regs.rx_ev().event_asserted(LITEETH_RX_EVENT) {
+            self.rx_interrupt();
+        }
+
+        if self.mac_regs.tx_ev().event_asserted(LITEETH_TX_EVENT) {
+            self.tx_interrupt();
+        }
+    }
+}
+
+impl<'a, R: LiteXSoCRegisterConfiguration> EthernetAdapter<'a> for LiteEth<'a, R> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient) {
+        self.client.set(client);
+    }
+
     /// Transmit an ethernet packet over the interface
     ///
     /// For now this will only use a single slot on the interface and
     /// is therefore blocking. A client must wait until a callback to
     /// `tx_done` prior to sending a new packet.
-    pub fn transmit(
+    fn transmit(
         &self,
         packet: &'static mut [u8],
         len: usize,
@@ -278,34 +292,18 @@ impl<'a, R: LiteXSoCRegisterConfiguration> LiteEth<'a, R> {
         Ok(())
     }
 
-    fn tx_interrupt(&self) {
-        // Deassert the interrupt, but can be left enabled
-        self.mac_regs.tx_ev().clear_event(LITEETH_TX_EVENT);
-
-        if self.tx_packet.is_none() {
-            debug!("LiteEth: tx interrupt called without tx_packet set");
-        }
-
-        // We use only one slot, so this event is unambiguous
-        let packet = self
-            .tx_packet
-            .take()
-            .expect("LiteEth: TakeCell empty in tx callback");
-        self.client
-            .map(move |client| client.tx_done(ReturnCode::SUCCESS, packet));
-    }
-
-    pub fn service_interrupt(&self) {
-        // The interrupt could've been generated by both a packet
-        // being received or finished transmitting. Check and handle
-        // both cases
+    fn return_rx_buffer(&self, rx_buffer: &'static mut [u8]) {
+        // Assert that we won't overwrite a buffer
+        assert!(
+            self.rx_buffer.is_none(),
+            "LiteEth: return RX buffer while one is registered"
+        );
 
-        if self.mac_regs.rx_ev().event_asserted(LITEETH_RX_EVENT) {
-            self.rx_interrupt();
-        }
+        // Put the buffer back
+        self.rx_buffer.replace(rx_buffer);
 
-        if self.mac_regs.tx_ev().event_asserted(LITEETH_TX_EVENT) {
-            self.tx_interrupt();
-        }
+        // In case we received a packet RX interrupt but couldn't
+        // handle it due to the missing buffer, reenable RX interrupts
+        self.mac_regs.rx_ev().enable_event(LITEETH_RX_EVENT);
     }
 }
@@ -20,6 +20,7 @@ pub struct Msp432DefaultPeripherals<'a> {
     pub cs: crate::cs::ClockSystem,
     pub dma_channels: crate::dma::DmaChannels<'a>,
     pub adc_ref: crate::ref_module::Ref,
+    pub rtc: crate::rtc::Rtc<'a>,
     pub timer_a0: crate::timer::TimerA<'a>,
     pub timer_a1: crate::timer::TimerA<'a>,
     pub timer_a2: crate::timer::TimerA<'a>,
@@ -35,6 +36,7 @@ impl<'a> Msp432DefaultPeripherals<'a> {
             cs: crate::cs::ClockSystem::new(),
             dma_channels: crate::dma::DmaChannels::new(),
             adc_ref: crate::ref_module::Ref::new(),
+            rtc: crate::rtc::Rtc::new(),
             timer_a0: crate::timer::TimerA::new(crate::timer::TIMER_A0_BASE),
             timer_a1: crate::timer::TimerA::new(crate::timer::TIMER_A1_BASE),
             timer_a2: crate::timer::TimerA::new(crate::timer::TIMER_A2_BASE),
@@ -81,6 +83,7 @@ impl<'a> kernel::InterruptService<()> for Msp432DefaultPeripherals<'a> {
             nvic::TIMER_A1_0 | nvic::TIMER_A1_1 => self.timer_a1.handle_interrupt(),
             nvic::TIMER_A2_0 | nvic::TIMER_A2_1 => self.timer_a2.handle_interrupt(),
             nvic::TIMER_A3_0 | nvic::TIMER_A3_1 => self.timer_a3.handle_interrupt(),
+            nvic::RTC => self.rtc.handle_interrupt(),
 
             _ => return false,
         }
@@ -6,6 +6,8 @@ use kernel::common::registers::{
     register_bitfields, register_structs, InMemoryRegister, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::common::StaticRef;
+use kernel::hil::dma;
+use kernel::ReturnCode;
 
 const DMA_BASE: StaticRef<DmaRegisters> =
     unsafe { StaticRef::new(0x4000_E000 as *const DmaRegisters) };
@@ -43,6 +45,12 @@ pub const AVAILABLE_DMA_CHANNELS: usize = 8;
 /// The DMA can perform up to 1024 transfers before it needs to rearbitrate the bus
 const MAX_TRANSFERS_LEN: usize = 1024;
 
+/// Number of segments a channel can chain in peripheral scatter-gather mode. Each segment needs
+/// a task in the channel's task list.
+pub const MAX_LIST_SEGMENTS: usize = 8;
+
+const TASK_DEFAULT: DmaChannelControl = DmaChannelControl::const_default();
+
 register_structs! {
     /// DMA
     DmaRegisters {
@@ -545,6 +553,26 @@ pub struct DmaConfig {
     pub dst_incr: DmaPtrIncrement,
 }
 
+/// A peripheral trigger of the DMA controller, used to allocate channels
+/// through `hil::dma`.
+///
+/// `channel` and `source` select the trigger as listed in the device
+/// datasheet, e.g. channel 0 with source 1 is the eUSCI_A0 transmitter.
+/// `register` is the peripheral register the channel writes to or reads from.
+#[derive(Copy, Clone)]
+pub enum DmaRequest {
+    PeripheralToMemory {
+        channel: usize,
+        source: u8,
+        register: *const (),
+    },
+    MemoryToPeripheral {
+        channel: usize,
+        source: u8,
+        register: *const (),
+    },
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum DmaTransferType {
     PeripheralToMemory,
@@ -626,6 +654,15 @@ pub struct DmaChannel<'a> {
     bytes_to_transmit_alt: Cell<usize>,
     remaining_words: Cell<usize>,
     client: OptionalCell<&'a dyn DmaClient>,
+    // State used when the channel is driven through `hil::dma`
+    dma_client: OptionalCell<&'a dyn dma::DmaClient>,
+    request: Cell<Option<DmaRequest>>,
+    queued: TakeCell<'static, [u8]>,
+    queued_len: Cell<usize>,
+    // Task list for peripheral scatter-gather transfers. The primary data-structure copies the
+    // tasks one by one into the alternate data-structure, which then runs them.
+    tasks: [DmaChannelControl; MAX_LIST_SEGMENTS],
+    list: TakeCell<'static, [dma::Segment]>,
 }
 
 impl DmaChannelControl {
@@ -669,6 +706,12 @@ impl<'a> DmaChannel<'a> {
             bytes_to_transmit_alt: Cell::new(0),
             remaining_words: Cell::new(0),
             client: OptionalCell::empty(),
+            dma_client: OptionalCell::empty(),
+            request: Cell::new(None),
+            queued: TakeCell::empty(),
+            queued_len: Cell::new(0),
+            tasks: [TASK_DEFAULT; MAX_LIST_SEGMENTS],
+            list: TakeCell::empty(),
         }
     }
 
@@ -693,7 +736,9 @@ impl<'a> DmaChannel<'a> {
             panic!("DMA: Memory scatter-gather mode currently not supported!");
         }
         if conf.mode == DmaMode::PeripheralScatterGather {
-            panic!("DMA: Peripheral scatter-gather mode currently not supported!");
+            panic!(
+                "DMA: Peripheral scatter-gather mode is only available through `transfer_list`!"
+            );
         }
 
         // The memory acces protection fields 'dst_prot_ctrl' and 'src_prot_ctrl' are not necessary
@@ -712,6 +757,10 @@ impl<'a> DmaChannel<'a> {
         self.registers.ch_srccfg[self.chan_nr].set((conf.src_chan % (MAX_SRC_NR + 1)) as u32);
     }
 
+    fn trigger_dma_channel(&self) {
+        self.registers.sw_chtrig.set((1 << self.chan_nr) as u32);
+    }
+
     fn enable_dma_channel(&self) {
         self.registers
             .enaset
@@ -844,11 +893,20 @@ impl<'a> DmaChannel<'a> {
     fn handle_interrupt(&self) {
         if self.remaining_words.get() > 0 {
             self.update_buffer_ptr();
+            if self.transfer_type.get() == DmaTransferType::MemoryToMemory {
+                // Memory transfers have no hardware trigger, request the next cycle
+                self.enable_dma_channel();
+                self.trigger_dma_channel();
+            }
         } else {
             if self.transfer_type.get() != DmaTransferType::PeripheralToMemoryPingPong {
                 // Disable the DMA channel since the data transfer has finished
                 self.registers.enaclr.set((1 << self.chan_nr) as u32);
             }
+            if self.dma_client.is_some() {
+                self.dma_transfer_done();
+                return;
+            }
             // Fire the callback and return the buffer-references
             match self.transfer_type.get() {
                 DmaTransferType::PeripheralToMemory => {
@@ -925,9 +983,6 @@ impl<'a> DmaChannel<'a> {
         dst_buf: &'static mut [u8],
         len: usize,
     ) {
-        // Note: This function is currently entirely untested, since there is currently no driver
-        // available where this function might be used.
-
         let width = self.config.get().width as u32;
 
         // Divide the byte-length by the width to get the number of necessary transfers
@@ -938,8 +993,9 @@ impl<'a> DmaChannel<'a> {
         let src_end_ptr = (&src_buf[0] as *const u8 as u32) + ((len as u32) - 1);
         let dst_end_ptr = (&dst_buf[0] as *const u8 as u32) + ((len as u32) - 1);
 
-        // Setup the DMA configuration
-        self.set_dma_mode(DmaMode::Basic);
+        // Setup the DMA configuration. Memory transfers have no peripheral that
+        // requests every item, so a single software trigger runs the whole cycle.
+        self.set_dma_mode(DmaMode::AutoRequest);
         self.set_primary_buffer(src_end_ptr, dst_end_ptr);
         self.setup_transfer_primary_buffer(len);
 
@@ -967,8 +1023,9 @@ impl<'a> DmaChannel<'a> {
         // Store transfer-type
         self.transfer_type.set(DmaTransferType::MemoryToMemory);
 
-        // Enable the DMA channel
+        // Enable the DMA channel and start the transfer
         self.enable_dma_channel();
+        self.trigger_dma_channel();
     }
 
     /// Start a DMA transfer where the contents of any register will be copied into a provided buffer
@@ -1120,3 +1177,396 @@ impl<'a> DmaChannel<'a> {
         )
     }
 }
+
+impl<'a> DmaChannel<'a> {
+    fn is_allocated(&self) -> bool {
+        self.in_use.get() || self.client.is_some()
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.tx_buf_prim.is_some() || self.rx_buf_prim.is_some() || self.list.is_some()
+    }
+
+    fn start_transfer(&self, buf: &'static mut [u8], len: usize) {
+        match self.request.get() {
+            Some(DmaRequest::PeripheralToMemory { register, .. }) => {
+                self.transfer_periph_to_mem(register, buf, len)
+            }
+            Some(DmaRequest::MemoryToPeripheral { register, .. }) => {
+                self.transfer_mem_to_periph(register, buf, len)
+            }
+            None => {}
+        }
+    }
+
+    fn check_len(&self, buf: &[u8], len: usize) -> ReturnCode {
+        let width = self.config.get().width as usize;
+        if len == 0 || len > buf.len() || len % (1 << width) != 0 {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Start a `transfer_list` transfer in peripheral scatter-gather mode. Every segment gets a
+    /// task that runs in the alternate data-structure; all but the last one hand back to the
+    /// primary data-structure, which copies in the next task on the following request.
+    fn start_list(&self, list: &'static mut [dma::Segment]) {
+        let conf = self.config.get();
+        let (register, to_memory) = match self.request.get() {
+            Some(DmaRequest::PeripheralToMemory { register, .. }) => (register as u32, true),
+            Some(DmaRequest::MemoryToPeripheral { register, .. }) => (register as u32, false),
+            None => return,
+        };
+        let last = list.len() - 1;
+
+        for (i, (task, segment)) in self.tasks.iter().zip(list.iter()).enumerate() {
+            let transfers = segment.len >> (conf.width as usize);
+            // The pointers must point to the last item of the buffer, for detailed calculation
+            // see datasheet p. 646, section 11.2.4.4.
+            let buf_end_ptr = segment
+                .buf
+                .as_ref()
+                .map_or(0, |buf| &buf[0] as *const u8 as u32)
+                + (((transfers - 1) << (conf.width as usize)) as u32);
+            if to_memory {
+                task.src_ptr.set(register);
+                task.dst_ptr.set(buf_end_ptr);
+            } else {
+                task.src_ptr.set(buf_end_ptr);
+                task.dst_ptr.set(register);
+            }
+            let mode = if i == last {
+                DMA_CTRL::CYCLE_CTRL::Basic
+            } else {
+                DMA_CTRL::CYCLE_CTRL::PeripheralScatterGatherAlternate
+            };
+            task.ctrl.write(
+                mode + DMA_CTRL::N_MINUS_1.val((transfers - 1) as u32)
+                    + DMA_CTRL::R_POWER.val(0)
+                    + DMA_CTRL::SRC_SIZE.val(conf.width as u32)
+                    + DMA_CTRL::DST_SIZE.val(conf.width as u32)
+                    + DMA_CTRL::SRC_INC.val(conf.src_incr as u32)
+                    + DMA_CTRL::DST_INC.val(conf.dst_incr as u32),
+            );
+        }
+
+        // Each task is 4 words, which the primary data-structure copies in one go into the
+        // alternate data-structure of this channel
+        let primary = &DMA_CONFIG.0[self.chan_nr];
+        let alternate = &DMA_CONFIG.0[self.chan_nr + AVAILABLE_DMA_CHANNELS];
+        primary
+            .src_ptr
+            .set(&self.tasks[last]._unused as *const InMemoryRegister<u32> as u32);
+        primary
+            .dst_ptr
+            .set(&alternate._unused as *const InMemoryRegister<u32> as u32);
+        primary.ctrl.write(
+            DMA_CTRL::CYCLE_CTRL::PeripheralScatterGatherPrimary
+                + DMA_CTRL::N_MINUS_1.val((4 * list.len() - 1) as u32)
+                + DMA_CTRL::R_POWER.val(2)
+                + DMA_CTRL::SRC_SIZE::Word
+                + DMA_CTRL::DST_SIZE::Word
+                + DMA_CTRL::SRC_INC::Word
+                + DMA_CTRL::DST_INC::Word,
+        );
+        self.registers.altclr.set((1 << self.chan_nr) as u32);
+
+        self.list.replace(list);
+        self.remaining_words.set(0);
+        self.transfer_type.set(if to_memory {
+            DmaTransferType::PeripheralToMemory
+        } else {
+            DmaTransferType::MemoryToPeripheral
+        });
+
+        self.enable_dma_channel();
+    }
+
+    fn dma_transfer_done(&self) {
+        if let Some(list) = self.list.take() {
+            // The primary data-structure was used for the task list, restore the configuration
+            // of the channel for the next transfer
+            self.apply_config();
+            // The controller only interrupts once the last task has completed
+            self.dma_client.map(move |client| {
+                for (index, segment) in list.iter().enumerate() {
+                    client.segment_done(index, segment.len);
+                }
+                client.list_done(list, ReturnCode::SUCCESS);
+            });
+            return;
+        }
+
+        let len = self.bytes_to_transmit_prim.get();
+        if self.transfer_type.get() == DmaTransferType::MemoryToMemory {
+            let src = self.tx_buf_prim.take();
+            let dst = self.rx_buf_prim.take();
+            self.dma_client.map(|client| {
+                if let (Some(src), Some(dst)) = (src, dst) {
+                    client.copy_done(src, dst, len, ReturnCode::SUCCESS);
+                }
+            });
+            return;
+        }
+
+        let buf = self.tx_buf_prim.take().or(self.rx_buf_prim.take());
+        // Keep the peripheral busy before handing back the finished buffer
+        self.queued
+            .take()
+            .map(|next| self.start_transfer(next, self.queued_len.get()));
+        self.dma_client.map(|client| {
+            buf.map(|buf| client.transfer_done(buf, len, ReturnCode::SUCCESS));
+        });
+    }
+}
+
+impl<'a> dma::DmaChannel<'a> for DmaChannel<'a> {
+    fn set_client(&self, client: &'a dyn dma::DmaClient) {
+        self.dma_client.set(client);
+    }
+
+    fn set_width(&self, width: dma::Width) -> ReturnCode {
+        if self.is_transferring() {
+            return ReturnCode::EBUSY;
+        }
+        let (width, incr) = match width {
+            dma::Width::Bits8 => (DmaDataWidth::Width8Bit, DmaPtrIncrement::Incr8Bit),
+            dma::Width::Bits16 => (DmaDataWidth::Width16Bit, DmaPtrIncrement::Incr16Bit),
+            dma::Width::Bits32 => (DmaDataWidth::Width32Bit, DmaPtrIncrement::Incr32Bit),
+        };
+        let mut conf = self.config.get();
+        conf.width = width;
+        // Only the memory side of a transfer moves along the buffer
+        match self.request.get() {
+            Some(DmaRequest::PeripheralToMemory { .. }) => conf.dst_incr = incr,
+            Some(DmaRequest::MemoryToPeripheral { .. }) => conf.src_incr = incr,
+            None => {
+                conf.src_incr = incr;
+                conf.dst_incr = incr;
+            }
+        }
+        self.config.set(conf);
+        self.apply_config();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_half_complete(&self, enable: bool) -> ReturnCode {
+        // The controller only interrupts at the end of a cycle
+        if enable {
+            ReturnCode::ENOSUPPORT
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn transfer(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.in_use.get() {
+            return Err((ReturnCode::EOFF, buf));
+        }
+        if self.request.get().is_none() {
+            return Err((ReturnCode::ENOSUPPORT, buf));
+        }
+        if self.is_transferring() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        let rc = self.check_len(buf, len);
+        if rc != ReturnCode::SUCCESS {
+            return Err((rc, buf));
+        }
+        self.start_transfer(buf, len);
+        Ok(())
+    }
+
+    fn queue(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.is_transferring() {
+            return dma::DmaChannel::transfer(self, buf, len);
+        }
+        if self.queued.is_some() || self.list.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        let rc = self.check_len(buf, len);
+        if rc != ReturnCode::SUCCESS {
+            return Err((rc, buf));
+        }
+        // The channel is restarted with the queued buffer from the
+        // completion interrupt.
+        self.queued_len.set(len);
+        self.queued.replace(buf);
+        Ok(())
+    }
+
+    fn transfer_list(
+        &self,
+        list: &'static mut [dma::Segment],
+    ) -> Result<(), (ReturnCode, &'static mut [dma::Segment])> {
+        if !self.in_use.get() {
+            return Err((ReturnCode::EOFF, list));
+        }
+        if self.request.get().is_none() {
+            return Err((ReturnCode::ENOSUPPORT, list));
+        }
+        if self.is_transferring() {
+            return Err((ReturnCode::EBUSY, list));
+        }
+        // A task runs at most 1024 transfers, it cannot be continued from an interrupt like the
+        // cycles of `transfer`
+        let width = self.config.get().width as usize;
+        let valid = !list.is_empty()
+            && list.len() <= MAX_LIST_SEGMENTS
+            && list.iter().all(|segment| {
+                segment.buf.as_ref().map_or(false, |buf| {
+                    self.check_len(buf, segment.len) == ReturnCode::SUCCESS
+                        && segment.len >> width <= MAX_TRANSFERS_LEN
+                })
+            });
+        if !valid {
+            return Err((ReturnCode::EINVAL, list));
+        }
+        self.start_list(list);
+        Ok(())
+    }
+
+    fn copy(
+        &self,
+        src: &'static mut [u8],
+        dst: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if !self.in_use.get() {
+            return Err((ReturnCode::EOFF, src, dst));
+        }
+        if self.request.get().is_some() {
+            return Err((ReturnCode::ENOSUPPORT, src, dst));
+        }
+        if self.is_transferring() {
+            return Err((ReturnCode::EBUSY, src, dst));
+        }
+        if self.check_len(src, len) != ReturnCode::SUCCESS || len > dst.len() {
+            return Err((ReturnCode::EINVAL, src, dst));
+        }
+        self.transfer_mem_to_mem(src, dst, len);
+        Ok(())
+    }
+
+    fn abort(&self) -> (usize, Option<&'static mut [u8]>, Option<&'static mut [u8]>) {
+        if !self.is_transferring() || self.list.is_some() {
+            return (0, None, self.queued.take());
+        }
+        let (len, tx, rx, _, _) = self.stop();
+        if self.request.get().is_none() {
+            (len, tx, rx)
+        } else {
+            (len, tx.or(rx), self.queued.take())
+        }
+    }
+
+    fn abort_list(&self) -> Option<(usize, &'static mut [dma::Segment])> {
+        let list = self.list.take()?;
+        self.registers.enaclr.set((1 << self.chan_nr) as u32);
+
+        let primary = &DMA_CONFIG.0[self.chan_nr];
+        let alternate = &DMA_CONFIG.0[self.chan_nr + AVAILABLE_DMA_CHANNELS];
+        // The primary data-structure counts down 4 words for every task it has copied, and the
+        // alternate one stops once the copied task has completed
+        let copied = if primary.ctrl.matches_all(DMA_CTRL::CYCLE_CTRL::Stop) {
+            list.len()
+        } else {
+            list.len() - (primary.ctrl.read(DMA_CTRL::N_MINUS_1) as usize + 1) / 4
+        };
+        let completed = if copied > 0 && alternate.ctrl.matches_all(DMA_CTRL::CYCLE_CTRL::Stop) {
+            copied
+        } else {
+            copied.saturating_sub(1)
+        };
+
+        primary.ctrl.modify(DMA_CTRL::CYCLE_CTRL::Stop);
+        alternate.ctrl.modify(DMA_CTRL::CYCLE_CTRL::Stop);
+        self.apply_config();
+        Some((completed, list))
+    }
+
+    fn is_busy(&self) -> bool {
+        self.is_transferring()
+    }
+}
+
+impl<'a> dma::DmaController<'a> for DmaChannels<'a> {
+    type Request = DmaRequest;
+    type Channel = DmaChannel<'a>;
+
+    fn allocate(&'a self, request: DmaRequest) -> Result<&'a DmaChannel<'a>, ReturnCode> {
+        let (chan_nr, source, src_incr, dst_incr) = match request {
+            DmaRequest::PeripheralToMemory {
+                channel, source, ..
+            } => (
+                channel,
+                source,
+                DmaPtrIncrement::NoIncr,
+                DmaPtrIncrement::Incr8Bit,
+            ),
+            DmaRequest::MemoryToPeripheral {
+                channel, source, ..
+            } => (
+                channel,
+                source,
+                DmaPtrIncrement::Incr8Bit,
+                DmaPtrIncrement::NoIncr,
+            ),
+        };
+        if chan_nr >= AVAILABLE_DMA_CHANNELS || source == 0 || source > MAX_SRC_NR {
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+        let channel = &self.channels[chan_nr];
+        if channel.is_allocated() {
+            return Err(ReturnCode::EBUSY);
+        }
+        channel.request.set(Some(request));
+        channel.initialize(&DmaConfig {
+            src_chan: source,
+            mode: DmaMode::Basic,
+            width: DmaDataWidth::Width8Bit,
+            src_incr,
+            dst_incr,
+        });
+        Ok(channel)
+    }
+
+    fn allocate_memory(&'a self) -> Result<&'a DmaChannel<'a>, ReturnCode> {
+        let channel = self
+            .channels
+            .iter()
+            .find(|channel| !channel.is_allocated())
+            .ok_or(ReturnCode::EBUSY)?;
+        // Source 0 is reserved on every channel and used for memory transfers
+        channel.initialize(&DmaConfig {
+            src_chan: 0,
+            mode: DmaMode::AutoRequest,
+            width: DmaDataWidth::Width8Bit,
+            src_incr: DmaPtrIncrement::Incr8Bit,
+            dst_incr: DmaPtrIncrement::Incr8Bit,
+        });
+        Ok(channel)
+    }
+
+    fn release(&self, channel: &DmaChannel<'a>) -> ReturnCode {
+        if !channel.in_use.get() || channel.client.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        if channel.is_transferring() {
+            return ReturnCode::EBUSY;
+        }
+        channel.in_use.set(false);
+        channel.request.set(None);
+        channel.dma_client.clear();
+        ReturnCode::SUCCESS
+    }
+}
@@ -16,6 +16,7 @@ pub mod gpio;
 pub mod nvic;
 pub mod pcm;
 pub mod ref_module;
+pub mod rtc;
 pub mod sysctl;
 pub mod timer;
 pub mod uart;
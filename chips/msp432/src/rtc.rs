@@ -0,0 +1,322 @@
+//! Real-Time Clock (RTC_C)
+//!
+//! The RTC runs in calendar mode from BCLK, which is the 32.768kHz LFXT after
+//! reset, and keeps counting in LPM3 and through resets other than a power
+//! cycle. After a power cycle it is held until the time is set, which is how
+//! the driver tells whether the time is valid.
+//!
+//! The hardware alarm only compares day, hour and minute. The driver checks
+//! the month and year in the alarm interrupt and uses the once-per-second
+//! ready interrupt for the last minute before the alarm.
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::{
+    register_bitfields, register_structs, FieldValue, ReadOnly, ReadWrite,
+};
+use kernel::common::StaticRef;
+use kernel::hil::date_time::{self, DateTime};
+use kernel::ReturnCode;
+
+const RTC_BASE: StaticRef<RtcRegisters> =
+    unsafe { StaticRef::new(0x4000_4400u32 as *const RtcRegisters) };
+
+// Writes to RTCCTL0 and RTCCTL13 need this key in the upper byte of RTCCTL0
+const KEY: u16 = 0xA5;
+
+register_structs! {
+    /// RTC_C
+    RtcRegisters {
+        /// RTCCTL0 Register
+        (0x00 => ctl0: ReadWrite<u16, RTCCTL0::Register>),
+        /// RTCCTL13 Register
+        (0x02 => ctl13: ReadWrite<u16, RTCCTL13::Register>),
+        /// RTCOCAL Register
+        (0x04 => ocal: ReadWrite<u16>),
+        /// RTCTCMP Register
+        (0x06 => tcmp: ReadWrite<u16>),
+        /// Real-Time Clock Prescale Timer 0 Control Register
+        (0x08 => ps0ctl: ReadWrite<u16>),
+        /// Real-Time Clock Prescale Timer 1 Control Register
+        (0x0A => ps1ctl: ReadWrite<u16>),
+        /// Real-Time Clock Prescale Timer Counter Register
+        (0x0C => ps: ReadWrite<u16>),
+        /// Real-Time Clock Interrupt Vector Register
+        (0x0E => iv: ReadOnly<u16>),
+        /// RTCTIM0 Register - Hexadecimal Format
+        (0x10 => tim0: ReadWrite<u16, RTCTIM0::Register>),
+        /// Real-Time Clock Hour, Day of Week
+        (0x12 => tim1: ReadWrite<u16, RTCTIM1::Register>),
+        /// RTCDATE - Hexadecimal Format
+        (0x14 => date: ReadWrite<u16, RTCDATE::Register>),
+        /// RTCYEAR Register - Hexadecimal Format
+        (0x16 => year: ReadWrite<u16>),
+        /// RTCMINHR - Hexadecimal Format
+        (0x18 => aminhr: ReadWrite<u16, RTCAMINHR::Register>),
+        /// RTCADOWDAY - Hexadecimal Format
+        (0x1A => adowday: ReadWrite<u16, RTCADOWDAY::Register>),
+        /// Binary-to-BCD Conversion Register
+        (0x1C => bin2bcd: ReadWrite<u16>),
+        /// BCD-to-Binary Conversion Register
+        (0x1E => bcd2bin: ReadWrite<u16>),
+        (0x20 => @END),
+    }
+}
+
+register_bitfields![u16,
+    RTCCTL0 [
+        /// Real-time clock ready interrupt flag
+        RTCRDYIFG OFFSET(0) NUMBITS(1),
+        /// Real-time clock alarm interrupt flag
+        RTCAIFG OFFSET(1) NUMBITS(1),
+        /// Real-time clock time event interrupt flag
+        RTCTEVIFG OFFSET(2) NUMBITS(1),
+        /// 32-kHz crystal oscillator fault interrupt flag
+        RTCOFIFG OFFSET(3) NUMBITS(1),
+        /// Real-time clock ready interrupt enable
+        RTCRDYIE OFFSET(4) NUMBITS(1),
+        /// Real-time clock alarm interrupt enable
+        RTCAIE OFFSET(5) NUMBITS(1),
+        /// Real-time clock time event interrupt enable
+        RTCTEVIE OFFSET(6) NUMBITS(1),
+        /// 32-kHz crystal oscillator fault interrupt enable
+        RTCOFIE OFFSET(7) NUMBITS(1),
+        /// Real-time clock key
+        RTCKEY OFFSET(8) NUMBITS(8)
+    ],
+    RTCCTL13 [
+        /// Real-time clock time event
+        RTCTEV OFFSET(0) NUMBITS(2),
+        /// Real-time clock source select
+        RTCSSEL OFFSET(2) NUMBITS(2),
+        /// Real-time clock ready
+        RTCRDY OFFSET(4) NUMBITS(1),
+        /// Calendar mode, always reads 1
+        RTCMODE OFFSET(5) NUMBITS(1),
+        /// Real-time clock hold
+        RTCHOLD OFFSET(6) NUMBITS(1),
+        /// Real-time clock BCD select
+        RTCBCD OFFSET(7) NUMBITS(1),
+        /// Real-time clock calibration frequency
+        RTCCALF OFFSET(8) NUMBITS(2)
+    ],
+    RTCTIM0 [
+        /// Seconds (0 to 59)
+        SECONDS OFFSET(0) NUMBITS(6),
+        /// Minutes (0 to 59)
+        MINUTES OFFSET(8) NUMBITS(6)
+    ],
+    RTCTIM1 [
+        /// Hours (0 to 23)
+        HOURS OFFSET(0) NUMBITS(5),
+        /// Day of week (0 to 6)
+        DOW OFFSET(8) NUMBITS(3)
+    ],
+    RTCDATE [
+        /// Day of month (1 to 28, 29, 30, 31)
+        DAY OFFSET(0) NUMBITS(5),
+        /// Month (1 to 12)
+        MONTH OFFSET(8) NUMBITS(4)
+    ],
+    RTCAMINHR [
+        /// Minutes (0 to 59)
+        MINUTES OFFSET(0) NUMBITS(6),
+        /// Alarm enable
+        MINAE OFFSET(7) NUMBITS(1),
+        /// Hours (0 to 23)
+        HOURS OFFSET(8) NUMBITS(5),
+        /// Alarm enable
+        HOURAE OFFSET(15) NUMBITS(1)
+    ],
+    RTCADOWDAY [
+        /// Day of week (0 to 6)
+        DOW OFFSET(0) NUMBITS(3),
+        /// Alarm enable
+        DOWAE OFFSET(7) NUMBITS(1),
+        /// Day of month (1 to 28, 29, 30, 31)
+        DAY OFFSET(8) NUMBITS(5),
+        /// Alarm enable
+        DAYAE OFFSET(15) NUMBITS(1)
+    ]
+];
+
+pub struct Rtc<'a> {
+    registers: StaticRef<RtcRegisters>,
+    client: OptionalCell<&'a dyn date_time::Client>,
+    alarm: Cell<Option<DateTime>>,
+}
+
+impl<'a> Rtc<'a> {
+    pub const fn new() -> Rtc<'a> {
+        Rtc {
+            registers: RTC_BASE,
+            client: OptionalCell::empty(),
+            alarm: Cell::new(None),
+        }
+    }
+
+    fn modify_ctl0(&self, value: FieldValue<u16, RTCCTL0::Register>) {
+        // RTCCTL0 only takes writes that carry the key
+        self.registers.ctl0.modify(value + RTCCTL0::RTCKEY.val(KEY));
+        // Any other key locks the registers again
+        self.registers.ctl0.modify(RTCCTL0::RTCKEY.val(0));
+    }
+
+    fn modify_ctl13(&self, value: FieldValue<u16, RTCCTL13::Register>) {
+        self.registers.ctl0.modify(RTCCTL0::RTCKEY.val(KEY));
+        self.registers.ctl13.modify(value);
+        self.registers.ctl0.modify(RTCCTL0::RTCKEY.val(0));
+    }
+
+    fn read(&self) -> DateTime {
+        let regs = &*self.registers;
+        // The counters may roll over between the reads, so read until two
+        // consecutive reads agree.
+        loop {
+            let tim0 = regs.tim0.extract();
+            let tim1 = regs.tim1.extract();
+            let date = regs.date.extract();
+            let year = regs.year.get();
+            if tim0.get() == regs.tim0.get()
+                && tim1.get() == regs.tim1.get()
+                && date.get() == regs.date.get()
+                && year == regs.year.get()
+            {
+                return DateTime {
+                    year,
+                    month: date.read(RTCDATE::MONTH) as u8,
+                    day: date.read(RTCDATE::DAY) as u8,
+                    hour: tim1.read(RTCTIM1::HOURS) as u8,
+                    minute: tim0.read(RTCTIM0::MINUTES) as u8,
+                    second: tim0.read(RTCTIM0::SECONDS) as u8,
+                };
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.registers.ctl13.is_set(RTCCTL13::RTCHOLD)
+    }
+
+    /// Compare the hardware alarm against the day, hour and minute of
+    /// `alarm`. If that minute has already started, the ready interrupt
+    /// checks the alarm every second instead.
+    fn arm(&self, alarm: DateTime) {
+        let regs = &*self.registers;
+        self.modify_ctl0(
+            RTCCTL0::RTCAIE::CLEAR + RTCCTL0::RTCAIFG::CLEAR + RTCCTL0::RTCRDYIE::CLEAR,
+        );
+        regs.aminhr.write(
+            RTCAMINHR::MINUTES.val(alarm.minute as u16)
+                + RTCAMINHR::MINAE::SET
+                + RTCAMINHR::HOURS.val(alarm.hour as u16)
+                + RTCAMINHR::HOURAE::SET,
+        );
+        regs.adowday
+            .write(RTCADOWDAY::DAY.val(alarm.day as u16) + RTCADOWDAY::DAYAE::SET);
+        self.modify_ctl0(RTCCTL0::RTCAIE::SET);
+
+        let now = self.read();
+        if now.unix_time() / 60 >= alarm.unix_time() / 60 {
+            self.modify_ctl0(RTCCTL0::RTCRDYIE::SET);
+        }
+    }
+
+    fn disable_alarm(&self) {
+        self.modify_ctl0(
+            RTCCTL0::RTCAIE::CLEAR
+                + RTCCTL0::RTCAIFG::CLEAR
+                + RTCCTL0::RTCRDYIE::CLEAR
+                + RTCCTL0::RTCRDYIFG::CLEAR,
+        );
+        self.registers.aminhr.set(0);
+        self.registers.adowday.set(0);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let ctl0 = self.registers.ctl0.extract();
+        self.modify_ctl0(RTCCTL0::RTCAIFG::CLEAR + RTCCTL0::RTCRDYIFG::CLEAR);
+
+        let alarm = match self.alarm.get() {
+            Some(alarm) => alarm,
+            None => return,
+        };
+        if !ctl0.is_set(RTCCTL0::RTCAIFG) && !ctl0.is_set(RTCCTL0::RTCRDYIFG) {
+            return;
+        }
+
+        let now = self.read();
+        if now >= alarm {
+            self.alarm.set(None);
+            self.disable_alarm();
+            self.client.map(|client| client.alarm());
+        } else if now.unix_time() / 60 == alarm.unix_time() / 60 {
+            // Count down the seconds with the ready interrupt
+            self.modify_ctl0(RTCCTL0::RTCRDYIE::SET);
+        }
+        // Otherwise the day matched in an earlier month and the hardware
+        // alarm fires again next month.
+    }
+}
+
+impl<'a> date_time::Clock<'a> for Rtc<'a> {
+    fn set_client(&self, client: &'a dyn date_time::Client) {
+        self.client.set(client);
+    }
+
+    fn date_time(&self) -> Result<DateTime, ReturnCode> {
+        if self.is_running() {
+            Ok(self.read())
+        } else {
+            Err(ReturnCode::EOFF)
+        }
+    }
+
+    fn set_date_time(&self, date_time: DateTime) -> ReturnCode {
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        let regs = &*self.registers;
+        self.modify_ctl13(RTCCTL13::RTCHOLD::SET + RTCCTL13::RTCBCD::CLEAR);
+        regs.year.set(date_time.year);
+        regs.date.write(
+            RTCDATE::DAY.val(date_time.day as u16) + RTCDATE::MONTH.val(date_time.month as u16),
+        );
+        regs.tim1.write(
+            RTCTIM1::HOURS.val(date_time.hour as u16)
+                + RTCTIM1::DOW.val(date_time.day_of_week() as u16),
+        );
+        regs.tim0.write(
+            RTCTIM0::SECONDS.val(date_time.second as u16)
+                + RTCTIM0::MINUTES.val(date_time.minute as u16),
+        );
+        self.modify_ctl13(RTCCTL13::RTCHOLD::CLEAR);
+
+        // An alarm that is now in the past fires with the next ready
+        // interrupt
+        self.alarm.get().map(|alarm| self.arm(alarm));
+        ReturnCode::SUCCESS
+    }
+
+    fn set_alarm(&self, date_time: DateTime) -> ReturnCode {
+        if !self.is_running() {
+            return ReturnCode::EOFF;
+        }
+        if !date_time.is_valid() || date_time <= self.read() {
+            return ReturnCode::EINVAL;
+        }
+        self.alarm.set(Some(date_time));
+        self.arm(date_time);
+        ReturnCode::SUCCESS
+    }
+
+    fn get_alarm(&self) -> Option<DateTime> {
+        self.alarm.get()
+    }
+
+    fn disarm_alarm(&self) -> ReturnCode {
+        self.alarm.set(None);
+        self.disable_alarm();
+        ReturnCode::SUCCESS
+    }
+}
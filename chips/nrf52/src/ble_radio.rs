@@ -32,15 +32,37 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Connections
+//!
+//! The `BleConnectionDriver` operations chain a transmission and a reception
+//! with the `END_DISABLE` and `DISABLED_RXEN`/`DISABLED_TXEN` shortcuts, and
+//! the radio enforces T_IFS between the two. The packet pointer is double
+//! buffered and read when a transmission or reception starts, so the pointer
+//! of the second half is set as soon as the address of the first half has
+//! been sent or received.
+//!
+//! ### Scan requests and responses
+//!
+//! The `BleScanDriver` answers use the same shortcuts, but whether to answer
+//! is only known once the PDU has been received. The `END` interrupt then
+//! either lets the radio ramp up for the answer or disables it before the
+//! answer starts. How long to listen for a SCAN_REQ or SCAN_RSP after the
+//! first PDU is timed with TIMER1. TIMER0 cannot be shared, because the
+//! 802.15.4 radio uses its fixed PPI connections to the radio for CSMA
+//! backoff.
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
-use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection;
+use kernel::hil::time::{Alarm, AlarmClient, Time};
 use kernel::ReturnCode;
 use nrf5x::constants::TxPower;
 
@@ -529,12 +551,99 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+/// The transmitted PDU of connection operations, which is prepared while
+/// `PAYLOAD` is used for the reception.
+static mut TX_PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
+    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+
+/// The SCAN_RSP set with `set_scan_response`.
+static mut SCAN_RSP_PAYLOAD: [u8; ADV_PDU_MAX_LEN] = [0x00; ADV_PDU_MAX_LEN];
+
+/// The SCAN_REQ of active scanning, whose AdvA is filled in once an
+/// advertisement has been received.
+static mut SCAN_REQ_PAYLOAD: [u8; SCAN_REQ_LEN] = [0x00; SCAN_REQ_LEN];
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3
+const ADV_PDU_MAX_LEN: usize = 39;
+const ADV_PAYLOAD_MAX_LEN: u32 = 37;
+const ADV_ADDRESS_LEN: usize = 6;
+const ADV_HEADER_TYPE_MASK: u8 = 0x0f;
+const ADV_HEADER_TXADD: u8 = 1 << 6;
+const ADV_HEADER_RXADD: u8 = 1 << 7;
+const ADV_IND: u8 = 0b0000;
+const SCAN_REQ: u8 = 0b0011;
+const SCAN_RSP: u8 = 0b0100;
+const ADV_SCAN_IND: u8 = 0b0110;
+const SCAN_REQ_LEN: usize = 2 + 2 * ADV_ADDRESS_LEN;
+
+/// How long to listen for an answer after a PDU, in TIMER1 ticks of 1 µs.
+/// An answer starts T_IFS after the PDU and its address has been received
+/// 40 µs later. The rest covers radio ramp-up and interrupt latency.
+const ANSWER_WINDOW_TICKS: u32 = ble_connection::T_IFS_US + 40 + 60;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Operation {
+    Idle,
+    /// A transmission or reception of `BleAdvertisementDriver`
+    Advertising,
+    /// The transmission of `transmit_receive`
+    TransmitReceiveTx,
+    /// The reception of `transmit_receive`
+    TransmitReceiveRx,
+    /// The reception of `receive_transmit`
+    ReceiveTransmitRx,
+    /// The answer of `receive_transmit`
+    ReceiveTransmitTx,
+    /// The transmission of a scannable advertisement
+    ScannableTx,
+    /// Listening for a SCAN_REQ after a scannable advertisement
+    ScanRequestRx,
+    /// The SCAN_RSP answering a SCAN_REQ
+    ScanResponseTx,
+    /// The reception of active scanning
+    ActiveScanRx,
+    /// The SCAN_REQ answering a received advertisement
+    ScanRequestTx,
+    /// Listening for the SCAN_RSP answering the SCAN_REQ
+    ScanResponseRx,
+}
+
+impl Operation {
+    fn is_scan_exchange(&self) -> bool {
+        match *self {
+            Operation::ScannableTx
+            | Operation::ScanRequestRx
+            | Operation::ScanResponseTx
+            | Operation::ActiveScanRx
+            | Operation::ScanRequestTx
+            | Operation::ScanResponseRx => true,
+            _ => false,
+        }
+    }
+}
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Operation>,
+    connection_client: OptionalCell<&'a dyn ble_connection::ConnectionClient>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    conn_tx_buffer: TakeCell<'static, [u8]>,
+    conn_rx_buffer: TakeCell<'static, [u8]>,
+    // Whether the reception of a connection operation has started
+    receiving: Cell<bool>,
+    rx_len: Cell<usize>,
+    rx_result: Cell<ReturnCode>,
+    timer: OptionalCell<&'a crate::timer::TimerAlarm<'a>>,
+    /// Length of the PDU in `SCAN_RSP_PAYLOAD`, or 0
+    scan_response_len: Cell<usize>,
+    scan_request_address: Cell<Option<[u8; ADV_ADDRESS_LEN]>>,
+    /// AdvA of the scannable advertisement being sent
+    advertiser_address: Cell<[u8; ADV_ADDRESS_LEN]>,
 }
 
 impl<'a> Radio<'a> {
@@ -545,9 +654,28 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            connection_client: OptionalCell::empty(),
+            access_address: Cell::new(ble_connection::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(ble_connection::ADVERTISING_CRC_INIT),
+            conn_tx_buffer: TakeCell::empty(),
+            conn_rx_buffer: TakeCell::empty(),
+            receiving: Cell::new(false),
+            rx_len: Cell::new(0),
+            rx_result: Cell::new(ReturnCode::SUCCESS),
+            timer: OptionalCell::empty(),
+            scan_response_len: Cell::new(0),
+            scan_request_address: Cell::new(None),
+            advertiser_address: Cell::new([0; ADV_ADDRESS_LEN]),
         }
     }
 
+    /// Sets the timer that bounds how long to listen for scan requests and
+    /// responses.
+    pub fn set_timer_ref(&self, timer: &'a crate::timer::TimerAlarm<'a>) {
+        self.timer.set(timer);
+    }
+
     pub fn is_enabled(&self) -> bool {
         self.registers.mode.matches_all(Mode::MODE::BLE_1MBIT)
     }
@@ -594,10 +722,25 @@ impl<'a> Radio<'a> {
         }
     }
 
+    fn set_tx_dma_ptr(&self) {
+        unsafe {
+            self.registers.packetptr.set(TX_PAYLOAD.as_ptr() as u32);
+        }
+    }
+
     #[inline(never)]
     pub fn handle_interrupt(&self) {
         self.disable_all_interrupts();
 
+        if self.operation.get().is_scan_exchange() {
+            self.handle_scan_interrupt();
+            return;
+        }
+        if self.operation.get() != Operation::Advertising {
+            self.handle_connection_interrupt();
+            return;
+        }
+
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.event_end.write(Event::READY::CLEAR);
@@ -627,6 +770,7 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_TXDISABLE
                 | nrf5x::constants::RADIO_STATE_TX => {
                     self.radio_off();
+                    self.operation.set(Operation::Idle);
                     self.tx_client
                         .map(|client| client.transmit_event(self.buffer.take().unwrap(), result));
                 }
@@ -635,6 +779,7 @@ impl<'a> Radio<'a> {
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
                     self.radio_off();
+                    self.operation.set(Operation::Idle);
                     unsafe {
                         self.rx_client.map(|client| {
                             // Length is: S0 (1 Byte) + Length (1 Byte) + S1 (0 Bytes) + Payload
@@ -651,6 +796,361 @@ impl<'a> Radio<'a> {
         self.enable_interrupts();
     }
 
+    fn handle_connection_interrupt(&self) {
+        if self.registers.event_ready.is_set(Event::READY) {
+            self.registers.event_ready.write(Event::READY::CLEAR);
+        }
+
+        if self.registers.event_address.is_set(Event::READY) {
+            self.registers.event_address.write(Event::READY::CLEAR);
+            match self.operation.get() {
+                Operation::TransmitReceiveTx => self.set_dma_ptr(),
+                Operation::ReceiveTransmitRx => {
+                    self.receiving.set(true);
+                    self.set_tx_dma_ptr();
+                }
+                // The second half has started, so it must not be started
+                // again when it ends
+                Operation::TransmitReceiveRx => {
+                    self.receiving.set(true);
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                }
+                Operation::ReceiveTransmitTx => {
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                }
+                _ => (),
+            }
+        }
+
+        if self.registers.event_payload.is_set(Event::READY) {
+            self.registers.event_payload.write(Event::READY::CLEAR);
+        }
+
+        if self.registers.event_end.is_set(Event::READY) {
+            self.registers.event_end.write(Event::READY::CLEAR);
+            match self.operation.get() {
+                Operation::TransmitReceiveTx => {
+                    self.operation.set(Operation::TransmitReceiveRx);
+                }
+                Operation::TransmitReceiveRx => {
+                    self.record_reception();
+                    self.complete_connection_operation();
+                }
+                Operation::ReceiveTransmitRx => {
+                    self.record_reception();
+                    if self.rx_result.get() == ReturnCode::SUCCESS {
+                        unsafe {
+                            let sn = (PAYLOAD[0] >> ble_connection::DATA_HEADER_SN_OFFSET) & 1;
+                            let nesn =
+                                (TX_PAYLOAD[0] >> ble_connection::DATA_HEADER_NESN_OFFSET) & 1;
+                            if sn == nesn {
+                                TX_PAYLOAD[0] ^= 1 << ble_connection::DATA_HEADER_NESN_OFFSET;
+                            }
+                        }
+                    }
+                    self.operation.set(Operation::ReceiveTransmitTx);
+                }
+                Operation::ReceiveTransmitTx => self.complete_connection_operation(),
+                _ => (),
+            }
+        }
+
+        if self.operation.get() != Operation::Idle {
+            self.enable_interrupts();
+        }
+    }
+
+    fn handle_scan_interrupt(&self) {
+        if self.registers.event_ready.is_set(Event::READY) {
+            self.registers.event_ready.write(Event::READY::CLEAR);
+        }
+        if self.registers.event_payload.is_set(Event::READY) {
+            self.registers.event_payload.write(Event::READY::CLEAR);
+        }
+
+        if self.registers.event_address.is_set(Event::READY) {
+            self.registers.event_address.write(Event::READY::CLEAR);
+            unsafe {
+                match self.operation.get() {
+                    Operation::ScanRequestRx => {
+                        self.receiving.set(true);
+                        self.registers
+                            .packetptr
+                            .set(SCAN_RSP_PAYLOAD.as_ptr() as u32);
+                    }
+                    Operation::ActiveScanRx => {
+                        self.receiving.set(true);
+                        self.registers
+                            .packetptr
+                            .set(SCAN_REQ_PAYLOAD.as_ptr() as u32);
+                    }
+                    Operation::ScanRequestTx => {
+                        // The SCAN_RSP is received right after the
+                        // advertisement
+                        let offset = self.rx_len.get();
+                        self.registers
+                            .packetptr
+                            .set(PAYLOAD[offset..].as_ptr() as u32);
+                    }
+                    Operation::ScanResponseRx => self.receiving.set(true),
+                    _ => (),
+                }
+            }
+            // Nothing follows the PDU that has started
+            match self.operation.get() {
+                Operation::ScanResponseTx | Operation::ScanResponseRx => {
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                }
+                _ => (),
+            }
+        }
+
+        if self.registers.event_end.is_set(Event::READY) {
+            self.registers.event_end.write(Event::READY::CLEAR);
+            match self.operation.get() {
+                Operation::ScannableTx => {
+                    self.operation.set(Operation::ScanRequestRx);
+                    self.start_answer_window();
+                }
+                Operation::ScanRequestRx => {
+                    if self.is_scan_request_for_us() {
+                        self.operation.set(Operation::ScanResponseTx);
+                        self.answer(Shortcut::DISABLED_TXEN::SET);
+                    } else {
+                        self.complete_scannable_advertisement();
+                    }
+                }
+                Operation::ScanResponseTx => self.complete_scannable_advertisement(),
+                Operation::ActiveScanRx => {
+                    self.record_reception();
+                    if self.prepare_scan_request() {
+                        self.operation.set(Operation::ScanRequestTx);
+                        self.answer(Shortcut::DISABLED_RXEN::SET);
+                    } else {
+                        self.complete_active_scan(self.rx_len.get());
+                    }
+                }
+                Operation::ScanRequestTx => {
+                    self.operation.set(Operation::ScanResponseRx);
+                    self.start_answer_window();
+                }
+                Operation::ScanResponseRx => {
+                    let adv_len = self.rx_len.get();
+                    let len = adv_len + self.scan_response_len_at(adv_len);
+                    self.complete_active_scan(len);
+                }
+                _ => (),
+            }
+        }
+
+        if self.operation.get() != Operation::Idle {
+            self.enable_interrupts();
+        }
+    }
+
+    // Lets the radio answer the PDU that has just been received, and sets up
+    // what follows the answer with `then`.
+    fn answer(&self, then: FieldValue<u32, Shortcut::Register>) {
+        self.registers
+            .shorts
+            .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + then);
+        // The shortcut to the answer has normally been taken already, unless
+        // the radio is still disabling
+        if self.registers.state.get() == nrf5x::constants::RADIO_STATE_DISABLE {
+            self.registers.task_txen.write(Task::ENABLE::SET);
+        }
+    }
+
+    fn start_answer_window(&self) {
+        self.receiving.set(false);
+        self.timer.map(|timer| {
+            timer.set_alarm(timer.now(), ANSWER_WINDOW_TICKS.into());
+        });
+    }
+
+    fn is_scan_request_for_us(&self) -> bool {
+        let crc_ok = self.registers.crcstatus.is_set(Event::READY);
+        unsafe {
+            crc_ok
+                && PAYLOAD[0] & ADV_HEADER_TYPE_MASK == SCAN_REQ
+                && PAYLOAD[1] as usize == SCAN_REQ_LEN - 2
+                && PAYLOAD[8..14] == self.advertiser_address.get()
+        }
+    }
+
+    // Fills in the SCAN_REQ for the advertisement that has been received,
+    // if it is scannable and valid
+    fn prepare_scan_request(&self) -> bool {
+        let adv_len = self.rx_len.get();
+        unsafe {
+            let adv_type = PAYLOAD[0] & ADV_HEADER_TYPE_MASK;
+            if self.rx_result.get() != ReturnCode::SUCCESS
+                || (adv_type != ADV_IND && adv_type != ADV_SCAN_IND)
+                || adv_len < 2 + ADV_ADDRESS_LEN
+                || adv_len > ADV_PDU_MAX_LEN
+            {
+                return false;
+            }
+            let rx_add = if PAYLOAD[0] & ADV_HEADER_TXADD != 0 {
+                ADV_HEADER_RXADD
+            } else {
+                0
+            };
+            SCAN_REQ_PAYLOAD[0] = SCAN_REQ | ADV_HEADER_TXADD | rx_add;
+            SCAN_REQ_PAYLOAD[1] = (SCAN_REQ_LEN - 2) as u8;
+            SCAN_REQ_PAYLOAD[2..8]
+                .copy_from_slice(&self.scan_request_address.get().unwrap_or([0; 6]));
+            SCAN_REQ_PAYLOAD[8..14].copy_from_slice(&PAYLOAD[2..8]);
+        }
+        true
+    }
+
+    // The length of the valid SCAN_RSP from the advertiser that follows the
+    // `adv_len` byte advertisement in `PAYLOAD`, or 0
+    fn scan_response_len_at(&self, adv_len: usize) -> usize {
+        let crc_ok = self.registers.crcstatus.is_set(Event::READY);
+        unsafe {
+            let rsp = &PAYLOAD[adv_len..];
+            let len = rsp[1] as usize + 2;
+            if crc_ok
+                && rsp[0] & ADV_HEADER_TYPE_MASK == SCAN_RSP
+                && len >= 2 + ADV_ADDRESS_LEN
+                && rsp[2..8] == PAYLOAD[2..8]
+            {
+                len
+            } else {
+                0
+            }
+        }
+    }
+
+    fn stop_scan_exchange(&self) {
+        self.timer.map(|timer| timer.disarm());
+        self.disable_all_interrupts();
+        self.registers.shorts.set(0);
+        self.registers.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+        self.operation.set(Operation::Idle);
+        self.receiving.set(false);
+    }
+
+    fn complete_scannable_advertisement(&self) {
+        self.stop_scan_exchange();
+        self.buffer.take().map(|buf| {
+            self.tx_client
+                .map(move |client| client.transmit_event(buf, ReturnCode::SUCCESS));
+        });
+    }
+
+    fn complete_active_scan(&self, len: usize) {
+        self.stop_scan_exchange();
+        let result = self.rx_result.get();
+        unsafe {
+            self.rx_client
+                .map(|client| client.receive_event(&mut PAYLOAD, len as u8, result));
+        }
+    }
+
+    // Uses the longest payload of advertising channel PDUs, so that the
+    // SCAN_RSP received after an advertisement fits in `PAYLOAD`
+    fn limit_to_advertising_pdus(&self) {
+        self.registers
+            .pcnf1
+            .modify(PacketConfiguration1::MAXLEN.val(ADV_PAYLOAD_MAX_LEN));
+        self.registers
+            .tifs
+            .write(InterFrameSpacing::TIFS.val(ble_connection::T_IFS_US));
+    }
+
+    fn record_reception(&self) {
+        let result = if self.registers.crcstatus.is_set(Event::READY) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        };
+        self.rx_result.set(result);
+        // The length field does not include the two header bytes
+        self.rx_len.set(unsafe { PAYLOAD[1] } as usize + 2);
+    }
+
+    fn complete_connection_operation(&self) {
+        self.registers.shorts.set(0);
+        self.radio_off();
+        self.operation.set(Operation::Idle);
+        self.receiving.set(false);
+        let rx_len = self.rx_len.get();
+        self.conn_tx_buffer.take().map(|tx_buf| {
+            self.conn_rx_buffer.take().map(|rx_buf| {
+                let rx_len = cmp::min(rx_len, rx_buf.len());
+                unsafe {
+                    rx_buf[..rx_len].copy_from_slice(&PAYLOAD[..rx_len]);
+                }
+                let result = self.rx_result.get();
+                self.connection_client
+                    .map(move |client| client.operation_done(tx_buf, rx_buf, rx_len, result));
+            });
+        });
+    }
+
+    // Prepares a connection operation whose first half is `first`
+    fn start_connection_operation(
+        &self,
+        first: Operation,
+        channel: RadioChannel,
+        tx_buf: &'static mut [u8],
+        tx_len: usize,
+        rx_buf: &'static mut [u8],
+    ) -> (ReturnCode, Option<(&'static mut [u8], &'static mut [u8])>) {
+        if self.operation.get() != Operation::Idle {
+            return (ReturnCode::EBUSY, Some((tx_buf, rx_buf)));
+        }
+        if tx_len > tx_buf.len() || tx_len > nrf5x::constants::RADIO_PAYLOAD_LENGTH {
+            return (ReturnCode::ESIZE, Some((tx_buf, rx_buf)));
+        }
+        unsafe {
+            TX_PAYLOAD[..tx_len].copy_from_slice(&tx_buf[..tx_len]);
+        }
+        self.conn_tx_buffer.replace(tx_buf);
+        self.conn_rx_buffer.replace(rx_buf);
+        self.operation.set(first);
+        self.receiving.set(false);
+
+        self.ble_initialize(channel);
+        self.ble_set_access_address(self.access_address.get());
+        self.registers.crcinit.set(self.crc_init.get());
+        self.registers
+            .tifs
+            .write(InterFrameSpacing::TIFS.val(ble_connection::T_IFS_US));
+
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+        if first == Operation::TransmitReceiveTx {
+            self.set_tx_dma_ptr();
+            self.registers.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_RXEN::SET,
+            );
+            self.registers.task_txen.write(Task::ENABLE::SET);
+        } else {
+            self.set_dma_ptr();
+            self.registers.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_TXEN::SET,
+            );
+            self.registers.task_rxen.write(Task::ENABLE::SET);
+        }
+        self.enable_interrupts();
+        (ReturnCode::SUCCESS, None)
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
@@ -720,8 +1220,14 @@ impl<'a> Radio<'a> {
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
     // Set access address to 0x8E89BED6
     fn ble_set_advertising_access_address(&self) {
-        self.registers.prefix0.set(0x0000008e);
-        self.registers.base0.set(0x89bed600);
+        self.ble_set_access_address(ble_connection::ADVERTISING_ACCESS_ADDRESS);
+    }
+
+    // The most significant byte of the access address is the prefix and the
+    // other three are the base address
+    fn ble_set_access_address(&self, access_address: u32) {
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
     }
 
     // Packet configuration
@@ -787,7 +1293,31 @@ impl<'a> Radio<'a> {
 }
 
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
-    fn transmit_advertisement(&self, buf: &'static mut [u8], _len: usize, channel: RadioChannel) {
+    fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel) {
+        let adv_type = buf[0] & ADV_HEADER_TYPE_MASK;
+        if self.scan_response_len.get() > 0
+            && (adv_type == ADV_IND || adv_type == ADV_SCAN_IND)
+            && len >= 2 + ADV_ADDRESS_LEN
+        {
+            let mut address = [0; ADV_ADDRESS_LEN];
+            address.copy_from_slice(&buf[2..8]);
+            self.advertiser_address.set(address);
+            self.operation.set(Operation::ScannableTx);
+            let res = self.replace_radio_buffer(buf);
+            self.buffer.replace(res);
+            self.ble_initialize(channel);
+            self.limit_to_advertising_pdus();
+            self.registers.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_RXEN::SET,
+            );
+            self.tx();
+            self.enable_interrupts();
+            return;
+        }
+
+        self.operation.set(Operation::Advertising);
         let res = self.replace_radio_buffer(buf);
         self.buffer.replace(res);
         self.ble_initialize(channel);
@@ -796,6 +1326,22 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 
     fn receive_advertisement(&self, channel: RadioChannel) {
+        if self.scan_request_address.get().is_some() {
+            self.operation.set(Operation::ActiveScanRx);
+            self.receiving.set(false);
+            self.ble_initialize(channel);
+            self.limit_to_advertising_pdus();
+            self.registers.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_TXEN::SET,
+            );
+            self.rx();
+            self.enable_interrupts();
+            return;
+        }
+
+        self.operation.set(Operation::Advertising);
         self.ble_initialize(channel);
         self.rx();
         self.enable_interrupts();
@@ -810,6 +1356,116 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_connection::BleConnectionDriver<'a> for Radio<'a> {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn transmit_receive(
+        &self,
+        channel: RadioChannel,
+        tx_buf: &'static mut [u8],
+        tx_len: usize,
+        rx_buf: &'static mut [u8],
+    ) -> (ReturnCode, Option<(&'static mut [u8], &'static mut [u8])>) {
+        self.start_connection_operation(
+            Operation::TransmitReceiveTx,
+            channel,
+            tx_buf,
+            tx_len,
+            rx_buf,
+        )
+    }
+
+    fn receive_transmit(
+        &self,
+        channel: RadioChannel,
+        rx_buf: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+        tx_len: usize,
+    ) -> (ReturnCode, Option<(&'static mut [u8], &'static mut [u8])>) {
+        self.start_connection_operation(
+            Operation::ReceiveTransmitRx,
+            channel,
+            tx_buf,
+            tx_len,
+            rx_buf,
+        )
+    }
+
+    fn abort(&self) -> Option<(&'static mut [u8], &'static mut [u8])> {
+        let receiving = match self.operation.get() {
+            Operation::TransmitReceiveTx => false,
+            Operation::TransmitReceiveRx | Operation::ReceiveTransmitRx => {
+                // The address event may not have been handled yet
+                self.receiving.get() || self.registers.event_address.is_set(Event::READY)
+            }
+            _ => true,
+        };
+        if receiving {
+            return None;
+        }
+        self.disable_all_interrupts();
+        self.registers.shorts.set(0);
+        self.registers.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+        self.operation.set(Operation::Idle);
+        self.conn_tx_buffer
+            .take()
+            .and_then(|tx_buf| self.conn_rx_buffer.take().map(|rx_buf| (tx_buf, rx_buf)))
+    }
+
+    fn set_connection_client(&self, client: &'a dyn ble_connection::ConnectionClient) {
+        self.connection_client.set(client);
+    }
+}
+
+impl ble_advertising::BleScanDriver for Radio<'_> {
+    fn set_scan_response(&self, pdu: &[u8]) -> ReturnCode {
+        if pdu.len() > ADV_PDU_MAX_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if self.timer.is_none() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        unsafe {
+            SCAN_RSP_PAYLOAD[..pdu.len()].copy_from_slice(pdu);
+        }
+        self.scan_response_len.set(pdu.len());
+        ReturnCode::SUCCESS
+    }
+
+    fn set_scan_request_address(&self, address: Option<[u8; 6]>) -> ReturnCode {
+        if address.is_some() && self.timer.is_none() {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.scan_request_address.set(address);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl AlarmClient for Radio<'_> {
+    // The answer window ended. If no answer has started, the exchange ends
+    // without one.
+    fn alarm(&self) {
+        let listening = match self.operation.get() {
+            Operation::ScanRequestRx | Operation::ScanResponseRx => {
+                !self.receiving.get() && !self.registers.event_address.is_set(Event::READY)
+            }
+            _ => false,
+        };
+        if !listening {
+            return;
+        }
+        if self.operation.get() == Operation::ScanRequestRx {
+            self.complete_scannable_advertisement();
+        } else {
+            self.complete_active_scan(self.rx_len.get());
+        }
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
@@ -1,7 +1,9 @@
 use crate::deferred_call_tasks::DeferredCallTask;
+use crate::power;
 use core::fmt::Write;
 use cortexm4::{self, nvic};
 use kernel::common::deferred_call;
+use kernel::power::SleepState;
 use kernel::InterruptService;
 
 pub struct NRF52<'a, I: InterruptService<DeferredCallTask> + 'a> {
@@ -40,6 +42,7 @@ pub struct Nrf52DefaultPeripherals<'a> {
     pub timer0: crate::timer::TimerAlarm<'a>,
     pub timer1: crate::timer::TimerAlarm<'a>,
     pub timer2: crate::timer::Timer,
+    pub timer3: crate::timer::TimerAlarm<'a>,
     pub uarte0: crate::uart::Uarte<'a>,
     pub spim0: crate::spi::SPIM,
     pub twim0: crate::i2c::TWIM,
@@ -50,6 +53,8 @@ pub struct Nrf52DefaultPeripherals<'a> {
     pub nvmc: crate::nvmc::Nvmc,
     pub clock: crate::clock::Clock,
     pub pwm0: crate::pwm::Pwm,
+    pub i2s: crate::i2s::I2s<'a>,
+    pub pdm: crate::pdm::Pdm<'a>,
 }
 
 impl<'a> Nrf52DefaultPeripherals<'a> {
@@ -66,6 +71,7 @@ impl<'a> Nrf52DefaultPeripherals<'a> {
             timer0: crate::timer::TimerAlarm::new(0),
             timer1: crate::timer::TimerAlarm::new(1),
             timer2: crate::timer::Timer::new(2),
+            timer3: crate::timer::TimerAlarm::new(3),
             uarte0: crate::uart::Uarte::new(),
             spim0: crate::spi::SPIM::new(0),
             twim0: crate::i2c::TWIM::new_twim0(),
@@ -76,11 +82,17 @@ impl<'a> Nrf52DefaultPeripherals<'a> {
             nvmc: crate::nvmc::Nvmc::new(),
             clock: crate::clock::Clock::new(),
             pwm0: crate::pwm::Pwm::new(),
+            i2s: crate::i2s::I2s::new(),
+            pdm: crate::pdm::Pdm::new(),
         }
     }
     // Necessary for setting up circular dependencies
     pub fn init(&'a self) {
+        // TIMER0 has fixed PPI connections to the radio that the 802.15.4
+        // driver uses for CSMA backoff, so BLE gets its own timer.
         self.ieee802154_radio.set_timer_ref(&self.timer0);
+        self.ble_radio.set_timer_ref(&self.timer1);
+        kernel::hil::time::Alarm::set_alarm_client(&self.timer1, &self.ble_radio);
     }
 }
 impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<'a> {
@@ -108,6 +120,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
             crate::peripheral_interrupts::TIMER0 => self.timer0.handle_interrupt(),
             crate::peripheral_interrupts::TIMER1 => self.timer1.handle_interrupt(),
             crate::peripheral_interrupts::TIMER2 => self.timer2.handle_interrupt(),
+            crate::peripheral_interrupts::TIMER3 => self.timer3.handle_interrupt(),
             crate::peripheral_interrupts::UART0 => self.uarte0.handle_interrupt(),
             crate::peripheral_interrupts::SPI0_TWI0 => {
                 // SPI0 and TWI0 share interrupts.
@@ -139,6 +152,8 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52DefaultPeripherals<
             }
             crate::peripheral_interrupts::SPIM2_SPIS2_SPI2 => self.spim2.handle_interrupt(),
             crate::peripheral_interrupts::ADC => self.adc.handle_interrupt(),
+            crate::peripheral_interrupts::I2S => self.i2s.handle_interrupt(),
+            crate::peripheral_interrupts::PDM => self.pdm.handle_interrupt(),
             _ => return false,
         }
         true
@@ -199,9 +214,24 @@ impl<'a, I: InterruptService<DeferredCallTask> + 'a> kernel::Chip for NRF52<'a,
     }
 
     fn sleep(&self) {
+        self.sleep_in(power::SLEEP_LOW_POWER);
+    }
+
+    fn sleep_states(&self) -> &'static [SleepState] {
+        &power::SLEEP_STATES
+    }
+
+    fn sleep_in(&self, state: usize) -> usize {
+        let state = if state >= power::SLEEP_LOW_POWER {
+            power::SLEEP_LOW_POWER
+        } else {
+            power::SLEEP_CONSTANT_LATENCY
+        };
+        power::set_sleep_state(state);
         unsafe {
             cortexm4::support::wfi();
         }
+        state
     }
 
     unsafe fn atomic<F, R>(&self, f: F) -> R
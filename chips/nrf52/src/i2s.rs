@@ -0,0 +1,520 @@
+//! I2S driver for nRF52, in master mode, using EasyDMA.
+//!
+//! The controller moves samples in 32-bit words and uses one length for the
+//! transmit and receive buffers, so every buffer queued while another one is
+//! queued or playing must have the same length. The controller latches the
+//! pointer of the next buffer as soon as it starts the current one, which
+//! is reported by the `TXPTRUPD` and `RXPTRUPD` events. A buffer is done when
+//! the pointer of the buffer after it is latched.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! base_peripherals.i2s.set_pins(
+//!     nrf52840::pinmux::Pinmux::new(I2S_SCK as u32),
+//!     nrf52840::pinmux::Pinmux::new(I2S_LRCK as u32),
+//!     None,
+//!     Some(nrf52840::pinmux::Pinmux::new(I2S_SDOUT as u32)),
+//!     None,
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::i2s::{self, Channels, Format, SampleWidth, StopCause};
+use kernel::ReturnCode;
+use nrf5x::pinmux::Pinmux;
+
+const I2S_BASE: StaticRef<I2sRegisters> =
+    unsafe { StaticRef::new(0x40025000 as *const I2sRegisters) };
+
+/// Largest buffer the controller can move, in 32-bit words.
+const MAX_WORDS: usize = (1 << 14) - 1;
+
+#[repr(C)]
+struct I2sRegisters {
+    /// Starts continuous I2S transfer
+    tasks_start: WriteOnly<u32, TASK::Register>,
+    /// Stops I2S transfer
+    tasks_stop: WriteOnly<u32, TASK::Register>,
+    _reserved0: [u8; 252],
+    /// The RXD.PTR register has been copied to internal double-buffers
+    events_rxptrupd: ReadWrite<u32, EVENT::Register>,
+    /// I2S transfer stopped
+    events_stopped: ReadWrite<u32, EVENT::Register>,
+    _reserved1: [u8; 8],
+    /// The TXD.PTR register has been copied to internal double-buffers
+    events_txptrupd: ReadWrite<u32, EVENT::Register>,
+    _reserved2: [u8; 488],
+    /// Enable or disable interrupt
+    inten: ReadWrite<u32, INTE::Register>,
+    /// Enable interrupt
+    intenset: ReadWrite<u32, INTE::Register>,
+    /// Disable interrupt
+    intenclr: ReadWrite<u32, INTE::Register>,
+    _reserved3: [u8; 500],
+    /// Enable I2S module
+    enable: ReadWrite<u32, ENABLE::Register>,
+    /// I2S mode
+    config_mode: ReadWrite<u32, MODE::Register>,
+    /// Reception enable
+    config_rxen: ReadWrite<u32, ENABLE::Register>,
+    /// Transmission enable
+    config_txen: ReadWrite<u32, ENABLE::Register>,
+    /// Master clock generator enable
+    config_mcken: ReadWrite<u32, ENABLE::Register>,
+    /// Master clock generator frequency
+    config_mckfreq: ReadWrite<u32>,
+    /// MCK / LRCK ratio
+    config_ratio: ReadWrite<u32, RATIO::Register>,
+    /// Sample width
+    config_swidth: ReadWrite<u32, SWIDTH::Register>,
+    /// Alignment of sample within a frame
+    config_align: ReadWrite<u32, ALIGN::Register>,
+    /// Frame format
+    config_format: ReadWrite<u32, FORMAT::Register>,
+    /// Enable channels
+    config_channels: ReadWrite<u32, CHANNELS::Register>,
+    _reserved4: [u8; 12],
+    /// Receive buffer RAM start address
+    rxd_ptr: ReadWrite<u32>,
+    _reserved5: [u8; 4],
+    /// Transmit buffer RAM start address
+    txd_ptr: ReadWrite<u32>,
+    _reserved6: [u8; 12],
+    /// Size of the receive and transmit buffers, in 32-bit words
+    rxtxd_maxcnt: ReadWrite<u32>,
+    _reserved7: [u8; 12],
+    /// Pin select for MCK signal
+    psel_mck: ReadWrite<u32, PSEL::Register>,
+    /// Pin select for SCK signal
+    psel_sck: ReadWrite<u32, PSEL::Register>,
+    /// Pin select for LRCK signal
+    psel_lrck: ReadWrite<u32, PSEL::Register>,
+    /// Pin select for SDIN signal
+    psel_sdin: ReadWrite<u32, PSEL::Register>,
+    /// Pin select for SDOUT signal
+    psel_sdout: ReadWrite<u32, PSEL::Register>,
+}
+
+register_bitfields![u32,
+    TASK [
+        TASK 0
+    ],
+    EVENT [
+        EVENT 0
+    ],
+    INTE [
+        RXPTRUPD 1,
+        STOPPED 2,
+        TXPTRUPD 5
+    ],
+    ENABLE [
+        ENABLE 0
+    ],
+    MODE [
+        MODE OFFSET(0) NUMBITS(1) [
+            Master = 0,
+            Slave = 1
+        ]
+    ],
+    RATIO [
+        RATIO OFFSET(0) NUMBITS(4) []
+    ],
+    SWIDTH [
+        SWIDTH OFFSET(0) NUMBITS(2) [
+            Bits8 = 0,
+            Bits16 = 1,
+            Bits24 = 2
+        ]
+    ],
+    ALIGN [
+        ALIGN OFFSET(0) NUMBITS(1) [
+            Left = 0,
+            Right = 1
+        ]
+    ],
+    FORMAT [
+        FORMAT OFFSET(0) NUMBITS(1) [
+            I2S = 0,
+            Aligned = 1
+        ]
+    ],
+    CHANNELS [
+        CHANNELS OFFSET(0) NUMBITS(2) [
+            Stereo = 0,
+            Left = 1,
+            Right = 2
+        ]
+    ],
+    PSEL [
+        PIN OFFSET(0) NUMBITS(6) [],
+        CONNECT OFFSET(31) NUMBITS(1) [
+            Connected = 0,
+            Disconnected = 1
+        ]
+    ]
+];
+
+/// Master clock dividers of the 32 MHz clock and their `MCKFREQ` values.
+const MCK_DIVIDERS: [(u32, u32); 18] = [
+    (2, 0x80000000),
+    (3, 0x50000000),
+    (4, 0x40000000),
+    (5, 0x30000000),
+    (6, 0x28000000),
+    (8, 0x20000000),
+    (10, 0x18000000),
+    (11, 0x16000000),
+    (15, 0x11000000),
+    (16, 0x10000000),
+    (21, 0x0C000000),
+    (23, 0x0B000000),
+    (30, 0x08800000),
+    (31, 0x08400000),
+    (32, 0x08000000),
+    (42, 0x06000000),
+    (63, 0x04100000),
+    (125, 0x020C0000),
+];
+
+/// MCK / LRCK ratios, indexed by their `RATIO` value.
+const RATIOS: [u32; 9] = [32, 48, 64, 96, 128, 192, 256, 384, 512];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Running,
+    Stopping,
+}
+
+/// The buffers of one direction.
+struct Queue {
+    /// Buffer the controller is working on, or will start with
+    current: TakeCell<'static, [u8]>,
+    /// Buffer the controller continues with
+    next: TakeCell<'static, [u8]>,
+    /// Whether the controller latched the pointer of `current`
+    latched: Cell<bool>,
+}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue {
+            current: TakeCell::empty(),
+            next: TakeCell::empty(),
+            latched: Cell::new(false),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.current.is_none() && self.next.is_none()
+    }
+}
+
+pub struct I2s<'a> {
+    registers: StaticRef<I2sRegisters>,
+    client: OptionalCell<&'a dyn i2s::Client>,
+    format: Cell<Format>,
+    /// `MCKFREQ` and `RATIO` values for the configured sample rate
+    clock: Cell<(u32, u32)>,
+    /// Length of the queued buffers, in bytes
+    len: Cell<usize>,
+    tx: Queue,
+    rx: Queue,
+    state: Cell<State>,
+    stop_cause: Cell<StopCause>,
+}
+
+impl<'a> I2s<'a> {
+    pub const fn new() -> I2s<'a> {
+        I2s {
+            registers: I2S_BASE,
+            client: OptionalCell::empty(),
+            // 16 kHz, from a 32 MHz / 63 master clock at 32X
+            format: Cell::new(Format {
+                width: SampleWidth::Bits16,
+                channels: Channels::Stereo,
+                sample_rate: 15873,
+            }),
+            clock: Cell::new((0x04100000, 0)),
+            len: Cell::new(0),
+            tx: Queue::new(),
+            rx: Queue::new(),
+            state: Cell::new(State::Idle),
+            stop_cause: Cell::new(StopCause::Requested),
+        }
+    }
+
+    /// Select the pins of the interface. `mck` only needs to be connected
+    /// for codecs that do not generate their own master clock, and `sdout`
+    /// and `sdin` only for the directions in use.
+    pub fn set_pins(
+        &self,
+        sck: Pinmux,
+        lrck: Pinmux,
+        mck: Option<Pinmux>,
+        sdout: Option<Pinmux>,
+        sdin: Option<Pinmux>,
+    ) {
+        let regs = &*self.registers;
+        let psel = |reg: &ReadWrite<u32, PSEL::Register>, pin: Option<Pinmux>| match pin {
+            Some(pin) => reg.set(pin.into()),
+            None => reg.write(PSEL::CONNECT::Disconnected),
+        };
+        psel(&regs.psel_sck, Some(sck));
+        psel(&regs.psel_lrck, Some(lrck));
+        psel(&regs.psel_mck, mck);
+        psel(&regs.psel_sdout, sdout);
+        psel(&regs.psel_sdin, sdin);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        if regs.events_txptrupd.is_set(EVENT::EVENT) {
+            regs.events_txptrupd.write(EVENT::EVENT::CLEAR);
+            if let Some(buffer) = self.pointer_latched(&self.tx, &regs.txd_ptr, StopCause::Underrun)
+            {
+                self.client
+                    .map(move |client| client.transmit_done(buffer, ReturnCode::SUCCESS));
+            }
+        }
+        if regs.events_rxptrupd.is_set(EVENT::EVENT) {
+            regs.events_rxptrupd.write(EVENT::EVENT::CLEAR);
+            if let Some(buffer) = self.pointer_latched(&self.rx, &regs.rxd_ptr, StopCause::Overrun)
+            {
+                self.client
+                    .map(move |client| client.receive_done(buffer, ReturnCode::SUCCESS));
+            }
+        }
+        if regs.events_stopped.is_set(EVENT::EVENT) {
+            regs.events_stopped.write(EVENT::EVENT::CLEAR);
+            self.stopped();
+        }
+    }
+
+    /// The controller copied the pointer register of `queue`. Returns the
+    /// buffer it finished, if any.
+    fn pointer_latched(
+        &self,
+        queue: &Queue,
+        ptr: &ReadWrite<u32>,
+        cause: StopCause,
+    ) -> Option<&'static mut [u8]> {
+        if self.state.get() != State::Running {
+            return None;
+        }
+        if !queue.latched.get() {
+            // The stream just started, so `current` was latched and the
+            // pointer register is free for the next buffer.
+            queue.latched.set(true);
+            queue.next.map(|buffer| ptr.set(buffer.as_ptr() as u32));
+            return None;
+        }
+        if queue.next.is_none() {
+            // The controller fell back to `current` again, keep it until
+            // the stream stopped.
+            self.halt(cause);
+            return None;
+        }
+        let done = queue.current.take();
+        queue.current.put(queue.next.take());
+        done
+    }
+
+    fn halt(&self, cause: StopCause) {
+        self.stop_cause.set(cause);
+        self.state.set(State::Stopping);
+        self.registers
+            .intenclr
+            .write(INTE::TXPTRUPD::SET + INTE::RXPTRUPD::SET);
+        self.registers.tasks_stop.write(TASK::TASK::SET);
+    }
+
+    fn stopped(&self) {
+        let regs = &*self.registers;
+        regs.intenclr.write(INTE::STOPPED::SET);
+        regs.enable.write(ENABLE::ENABLE::CLEAR);
+        self.state.set(State::Idle);
+
+        let tx = (self.tx.current.take(), self.tx.next.take());
+        let rx = (self.rx.current.take(), self.rx.next.take());
+        self.client.map(move |client| {
+            for buffer in tx.0.into_iter().chain(tx.1) {
+                client.transmit_done(buffer, ReturnCode::ECANCEL);
+            }
+            for buffer in rx.0.into_iter().chain(rx.1) {
+                client.receive_done(buffer, ReturnCode::ECANCEL);
+            }
+            client.stopped(self.stop_cause.get());
+        });
+    }
+
+    fn queue(
+        &self,
+        queue: &Queue,
+        ptr: &ReadWrite<u32>,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let state = self.state.get();
+        if state == State::Stopping || (state == State::Running && queue.current.is_none()) {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        // EasyDMA can only reach data RAM, in whole words.
+        let addr = buffer.as_ptr() as usize;
+        if len == 0
+            || len > buffer.len()
+            || len % 4 != 0
+            || len % self.format.get().frame_bytes() != 0
+            || len / 4 > MAX_WORDS
+            || addr % 4 != 0
+            || addr & 0xE0000000 != 0x20000000
+        {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if self.tx.is_empty() && self.rx.is_empty() {
+            self.len.set(len);
+        } else if len != self.len.get() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+
+        if queue.current.is_none() {
+            queue.current.replace(buffer);
+        } else if queue.next.is_none() {
+            if state == State::Running && queue.latched.get() {
+                ptr.set(buffer.as_ptr() as u32);
+            }
+            queue.next.replace(buffer);
+        } else {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> i2s::I2s<'a> for I2s<'a> {
+    fn set_client(&self, client: &'a dyn i2s::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, format: Format) -> Result<u32, ReturnCode> {
+        if self.state.get() != State::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        let min_ratio = match format.width {
+            SampleWidth::Bits8 | SampleWidth::Bits16 => 32,
+            SampleWidth::Bits24 => 48,
+        };
+
+        let error = |rate: u32| (rate as i64 - format.sample_rate as i64).abs();
+        let mut best: Option<(u32, u32, u32)> = None;
+        for &(divider, mckfreq) in MCK_DIVIDERS.iter() {
+            for (ratio_val, &ratio) in RATIOS.iter().enumerate() {
+                if ratio < min_ratio {
+                    continue;
+                }
+                let rate = 32_000_000 / divider / ratio;
+                if best.map_or(true, |(best_rate, _, _)| error(rate) < error(best_rate)) {
+                    best = Some((rate, mckfreq, ratio_val as u32));
+                }
+            }
+        }
+        // The tables are not empty, so there is always a candidate.
+        let (rate, mckfreq, ratio) = best.unwrap_or((15873, 0x04100000, 0));
+
+        self.clock.set((mckfreq, ratio));
+        self.format.set(Format {
+            sample_rate: rate,
+            ..format
+        });
+        Ok(rate)
+    }
+
+    fn queue_transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.queue(&self.tx, &self.registers.txd_ptr, buffer, len)
+    }
+
+    fn queue_receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.queue(&self.rx, &self.registers.rxd_ptr, buffer, len)
+    }
+
+    fn start(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EALREADY;
+        }
+        let transmit = self.tx.current.is_some();
+        let receive = self.rx.current.is_some();
+        if !transmit && !receive {
+            return ReturnCode::EOFF;
+        }
+
+        let regs = &*self.registers;
+        let format = self.format.get();
+        let (mckfreq, ratio) = self.clock.get();
+        regs.enable.write(ENABLE::ENABLE::SET);
+        regs.config_mode.write(MODE::MODE::Master);
+        // The master clock generator drives SCK and LRCK in master mode.
+        regs.config_mcken.write(ENABLE::ENABLE::SET);
+        regs.config_txen.write(ENABLE::ENABLE.val(transmit as u32));
+        regs.config_rxen.write(ENABLE::ENABLE.val(receive as u32));
+        regs.config_mckfreq.set(mckfreq);
+        regs.config_ratio.write(RATIO::RATIO.val(ratio));
+        regs.config_swidth.write(match format.width {
+            SampleWidth::Bits8 => SWIDTH::SWIDTH::Bits8,
+            SampleWidth::Bits16 => SWIDTH::SWIDTH::Bits16,
+            SampleWidth::Bits24 => SWIDTH::SWIDTH::Bits24,
+        });
+        regs.config_align.write(ALIGN::ALIGN::Left);
+        regs.config_format.write(FORMAT::FORMAT::I2S);
+        regs.config_channels.write(match format.channels {
+            Channels::Stereo => CHANNELS::CHANNELS::Stereo,
+            Channels::Left => CHANNELS::CHANNELS::Left,
+            Channels::Right => CHANNELS::CHANNELS::Right,
+        });
+        regs.rxtxd_maxcnt.set((self.len.get() / 4) as u32);
+        self.tx
+            .current
+            .map(|buffer| regs.txd_ptr.set(buffer.as_ptr() as u32));
+        self.rx
+            .current
+            .map(|buffer| regs.rxd_ptr.set(buffer.as_ptr() as u32));
+        self.tx.latched.set(false);
+        self.rx.latched.set(false);
+
+        regs.events_txptrupd.write(EVENT::EVENT::CLEAR);
+        regs.events_rxptrupd.write(EVENT::EVENT::CLEAR);
+        regs.events_stopped.write(EVENT::EVENT::CLEAR);
+        regs.intenset.write(
+            INTE::TXPTRUPD.val(transmit as u32)
+                + INTE::RXPTRUPD.val(receive as u32)
+                + INTE::STOPPED::SET,
+        );
+        self.state.set(State::Running);
+        regs.tasks_start.write(TASK::TASK::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        if self.state.get() != State::Running {
+            return ReturnCode::EALREADY;
+        }
+        self.halt(StopCause::Requested);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_running(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+}
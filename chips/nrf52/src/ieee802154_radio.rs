@@ -8,6 +8,7 @@ use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOn
 use kernel::common::StaticRef;
 use kernel::hil::radio::{self, PowerClient};
 use kernel::hil::time::Alarm;
+use kernel::power::PowerConstraint;
 use kernel::ReturnCode;
 
 use crate::ppi;
@@ -675,6 +676,7 @@ pub struct Radio<'p> {
     transmitting: Cell<bool>,
     timer0: OptionalCell<&'p crate::timer::TimerAlarm<'p>>,
     ppi: &'p crate::ppi::Ppi,
+    power_constraint: PowerConstraint<'p>,
 }
 
 impl<'p> Radio<'p> {
@@ -696,9 +698,17 @@ impl<'p> Radio<'p> {
             transmitting: Cell::new(false),
             timer0: OptionalCell::empty(),
             ppi,
+            power_constraint: PowerConstraint::new(),
         }
     }
 
+    /// The constraint the radio sets while the CPU steps it through the clear
+    /// channel assessment before a transmission. Boards register it with
+    /// their power manager.
+    pub fn power_constraint(&'p self) -> &'p PowerConstraint<'p> {
+        &self.power_constraint
+    }
+
     pub fn set_timer_ref(&self, timer: &'p crate::timer::TimerAlarm<'p>) {
         self.timer0.set(timer);
     }
@@ -779,6 +789,11 @@ impl<'p> Radio<'p> {
                 if self.cca_count.get() > 0 {
                     self.ppi.disable(ppi::Channel::CH21::SET);
                 }
+                // The CPU starts the transmission once the channel is clear,
+                // and every microsecond of wakeup latency delays the frame
+                // after the assessment. Stay in constant latency mode until
+                // TXEN is triggered or the radio backs off.
+                self.power_constraint.block_deep_sleep();
                 self.registers.task_ccastart.write(Task::ENABLE::SET);
             } else {
                 self.registers.task_start.write(Task::ENABLE::SET);
@@ -793,11 +808,14 @@ impl<'p> Radio<'p> {
         // THEN start the transmit part of the radio
         if self.registers.event_ccaidle.is_set(Event::READY) {
             self.registers.event_ccaidle.write(Event::READY::CLEAR);
-            self.registers.task_txen.write(Task::ENABLE::SET)
+            self.registers.task_txen.write(Task::ENABLE::SET);
+            self.power_constraint.release();
         }
 
         if self.registers.event_ccabusy.is_set(Event::READY) {
             self.registers.event_ccabusy.write(Event::READY::CLEAR);
+            // The backoff ends in hardware, through PPI from TIMER0
+            self.power_constraint.release();
             //need to back off for a period of time outlined
             //in the IEEE 802.15.4 standard (see Figure 69 in
             //section 7.5.1.4 The CSMA-CA algorithm of the
@@ -1041,6 +1059,7 @@ impl<'p> kernel::hil::radio::RadioConfig for Radio<'p> {
     }
     fn stop(&self) -> ReturnCode {
         self.radio_off();
+        self.power_constraint.release();
         ReturnCode::SUCCESS
     }
     fn is_on(&self) -> bool {
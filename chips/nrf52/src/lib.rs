@@ -12,8 +12,10 @@ pub mod crt1;
 pub mod deferred_call_tasks;
 pub mod ficr;
 pub mod i2c;
+pub mod i2s;
 pub mod ieee802154_radio;
 pub mod nvmc;
+pub mod pdm;
 pub mod power;
 pub mod ppi;
 pub mod pwm;
@@ -0,0 +1,347 @@
+//! PDM microphone interface driver for nRF52, using EasyDMA.
+//!
+//! The controller decimates the bit stream of one or two PDM microphones to
+//! 16-bit PCM samples. It only receives, and the sample rate is the PDM
+//! clock divided by 64. The controller reports the start of each buffer with
+//! `STARTED`, after which the pointer of the next buffer can be written, and
+//! the end with `END`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! base_peripherals.pdm.set_pins(
+//!     nrf52840::pinmux::Pinmux::new(MIC_CLK as u32),
+//!     nrf52840::pinmux::Pinmux::new(MIC_DATA as u32),
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::i2s::{self, Channels, Format, SampleWidth, StopCause};
+use kernel::ReturnCode;
+use nrf5x::pinmux::Pinmux;
+
+const PDM_BASE: StaticRef<PdmRegisters> =
+    unsafe { StaticRef::new(0x4001D000 as *const PdmRegisters) };
+
+/// Largest buffer the controller can fill, in 16-bit samples.
+const MAX_SAMPLES: usize = (1 << 15) - 1;
+
+/// PCM samples per PDM clock cycle.
+const DECIMATION: u32 = 64;
+
+#[repr(C)]
+struct PdmRegisters {
+    /// Starts continuous PDM transfer
+    tasks_start: WriteOnly<u32, TASK::Register>,
+    /// Stops PDM transfer
+    tasks_stop: WriteOnly<u32, TASK::Register>,
+    _reserved0: [u8; 248],
+    /// PDM transfer has started
+    events_started: ReadWrite<u32, EVENT::Register>,
+    /// PDM transfer has finished
+    events_stopped: ReadWrite<u32, EVENT::Register>,
+    /// The PDM has written the last sample specified by SAMPLE.MAXCNT
+    events_end: ReadWrite<u32, EVENT::Register>,
+    _reserved1: [u8; 500],
+    /// Enable or disable interrupt
+    inten: ReadWrite<u32, INTE::Register>,
+    /// Enable interrupt
+    intenset: ReadWrite<u32, INTE::Register>,
+    /// Disable interrupt
+    intenclr: ReadWrite<u32, INTE::Register>,
+    _reserved2: [u8; 500],
+    /// PDM module enable register
+    enable: ReadWrite<u32, ENABLE::Register>,
+    /// PDM clock generator control
+    pdmclkctrl: ReadWrite<u32>,
+    /// Defines the routing of the connected PDM microphones' signals
+    mode: ReadWrite<u32, MODE::Register>,
+    _reserved3: [u8; 12],
+    /// Left output gain adjustment
+    gainl: ReadWrite<u32>,
+    /// Right output gain adjustment
+    gainr: ReadWrite<u32>,
+    _reserved4: [u8; 32],
+    /// Pin select for CLK signal
+    psel_clk: VolatileCell<Pinmux>,
+    /// Pin select for DIN signal
+    psel_din: VolatileCell<Pinmux>,
+    _reserved5: [u8; 24],
+    /// RAM address pointer to write samples to with EasyDMA
+    sample_ptr: ReadWrite<u32>,
+    /// Number of samples to allocate memory for in EasyDMA mode
+    sample_maxcnt: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    TASK [
+        TASK 0
+    ],
+    EVENT [
+        EVENT 0
+    ],
+    INTE [
+        STARTED 0,
+        STOPPED 1,
+        END 2
+    ],
+    ENABLE [
+        ENABLE 0
+    ],
+    MODE [
+        OPERATION OFFSET(0) NUMBITS(1) [
+            Stereo = 0,
+            Mono = 1
+        ],
+        EDGE OFFSET(1) NUMBITS(1) [
+            LeftFalling = 0,
+            LeftRising = 1
+        ]
+    ]
+];
+
+/// PDM clock frequencies and their `PDMCLKCTRL` values.
+const PDM_CLOCKS: [(u32, u32); 3] = [
+    (1_000_000, 0x08000000),
+    (1_032_000, 0x08400000),
+    (1_067_000, 0x08800000),
+];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Running,
+    Stopping,
+}
+
+pub struct Pdm<'a> {
+    registers: StaticRef<PdmRegisters>,
+    client: OptionalCell<&'a dyn i2s::Client>,
+    format: Cell<Format>,
+    /// `PDMCLKCTRL` value for the configured sample rate
+    clock: Cell<u32>,
+    /// Length of the queued buffers, in bytes
+    len: Cell<usize>,
+    /// Buffer the controller is filling, or will start with
+    current: TakeCell<'static, [u8]>,
+    /// Buffer the controller continues with
+    next: TakeCell<'static, [u8]>,
+    /// Whether the controller latched the pointer of `current`
+    latched: Cell<bool>,
+    state: Cell<State>,
+    stop_cause: Cell<StopCause>,
+}
+
+impl<'a> Pdm<'a> {
+    pub const fn new() -> Pdm<'a> {
+        Pdm {
+            registers: PDM_BASE,
+            client: OptionalCell::empty(),
+            format: Cell::new(Format {
+                width: SampleWidth::Bits16,
+                channels: Channels::Left,
+                sample_rate: 1_032_000 / DECIMATION,
+            }),
+            clock: Cell::new(0x08400000),
+            len: Cell::new(0),
+            current: TakeCell::empty(),
+            next: TakeCell::empty(),
+            latched: Cell::new(false),
+            state: Cell::new(State::Idle),
+            stop_cause: Cell::new(StopCause::Requested),
+        }
+    }
+
+    pub fn set_pins(&self, clk: Pinmux, din: Pinmux) {
+        self.registers.psel_clk.set(clk);
+        self.registers.psel_din.set(din);
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        // A buffer ends right before the next one starts.
+        if regs.events_end.is_set(EVENT::EVENT) {
+            regs.events_end.write(EVENT::EVENT::CLEAR);
+            self.buffer_ended();
+        }
+        if regs.events_started.is_set(EVENT::EVENT) {
+            regs.events_started.write(EVENT::EVENT::CLEAR);
+            if self.state.get() == State::Running {
+                self.latched.set(true);
+                self.next
+                    .map(|buffer| regs.sample_ptr.set(buffer.as_ptr() as u32));
+            }
+        }
+        if regs.events_stopped.is_set(EVENT::EVENT) {
+            regs.events_stopped.write(EVENT::EVENT::CLEAR);
+            self.stopped();
+        }
+    }
+
+    fn buffer_ended(&self) {
+        if self.state.get() != State::Running {
+            return;
+        }
+        if self.next.is_none() {
+            // The controller continues with `current` again, keep it until
+            // the stream stopped.
+            self.halt(StopCause::Overrun);
+            return;
+        }
+        self.latched.set(false);
+        let done = self.current.take();
+        self.current.put(self.next.take());
+        if let Some(buffer) = done {
+            self.client
+                .map(move |client| client.receive_done(buffer, ReturnCode::SUCCESS));
+        }
+    }
+
+    fn halt(&self, cause: StopCause) {
+        self.stop_cause.set(cause);
+        self.state.set(State::Stopping);
+        self.registers
+            .intenclr
+            .write(INTE::STARTED::SET + INTE::END::SET);
+        self.registers.tasks_stop.write(TASK::TASK::SET);
+    }
+
+    fn stopped(&self) {
+        let regs = &*self.registers;
+        regs.intenclr.write(INTE::STOPPED::SET);
+        regs.enable.write(ENABLE::ENABLE::CLEAR);
+        self.state.set(State::Idle);
+
+        let buffers = (self.current.take(), self.next.take());
+        self.client.map(move |client| {
+            for buffer in buffers.0.into_iter().chain(buffers.1) {
+                client.receive_done(buffer, ReturnCode::ECANCEL);
+            }
+            client.stopped(self.stop_cause.get());
+        });
+    }
+}
+
+impl<'a> i2s::I2s<'a> for Pdm<'a> {
+    fn set_client(&self, client: &'a dyn i2s::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, format: Format) -> Result<u32, ReturnCode> {
+        if self.state.get() != State::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        if format.width != SampleWidth::Bits16 || format.channels == Channels::Right {
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+
+        let error = |rate: u32| (rate as i64 - format.sample_rate as i64).abs();
+        let (frequency, clock) = PDM_CLOCKS
+            .iter()
+            .min_by_key(|&&(frequency, _)| error(frequency / DECIMATION))
+            .map_or(PDM_CLOCKS[1], |&clock| clock);
+        let rate = frequency / DECIMATION;
+
+        self.clock.set(clock);
+        self.format.set(Format {
+            sample_rate: rate,
+            ..format
+        });
+        Ok(rate)
+    }
+
+    fn queue_transmit(
+        &self,
+        buffer: &'static mut [u8],
+        _len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        Err((ReturnCode::ENOSUPPORT, buffer))
+    }
+
+    fn queue_receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let state = self.state.get();
+        if state == State::Stopping {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        // EasyDMA can only reach data RAM, in whole samples.
+        let addr = buffer.as_ptr() as usize;
+        if len == 0
+            || len > buffer.len()
+            || len % self.format.get().frame_bytes() != 0
+            || len / 2 > MAX_SAMPLES
+            || addr % 2 != 0
+            || addr & 0xE0000000 != 0x20000000
+        {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if self.current.is_none() && self.next.is_none() {
+            self.len.set(len);
+        } else if len != self.len.get() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+
+        if self.current.is_none() {
+            self.current.replace(buffer);
+        } else if self.next.is_none() {
+            if state == State::Running && self.latched.get() {
+                self.registers.sample_ptr.set(buffer.as_ptr() as u32);
+            }
+            self.next.replace(buffer);
+        } else {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EALREADY;
+        }
+        if self.current.is_none() {
+            return ReturnCode::EOFF;
+        }
+
+        let regs = &*self.registers;
+        regs.enable.write(ENABLE::ENABLE::SET);
+        regs.pdmclkctrl.set(self.clock.get());
+        regs.mode.write(
+            match self.format.get().channels {
+                Channels::Stereo => MODE::OPERATION::Stereo,
+                Channels::Left | Channels::Right => MODE::OPERATION::Mono,
+            } + MODE::EDGE::LeftFalling,
+        );
+        regs.sample_maxcnt.set((self.len.get() / 2) as u32);
+        self.current
+            .map(|buffer| regs.sample_ptr.set(buffer.as_ptr() as u32));
+        self.latched.set(false);
+
+        regs.events_started.write(EVENT::EVENT::CLEAR);
+        regs.events_end.write(EVENT::EVENT::CLEAR);
+        regs.events_stopped.write(EVENT::EVENT::CLEAR);
+        regs.intenset
+            .write(INTE::STARTED::SET + INTE::END::SET + INTE::STOPPED::SET);
+        self.state.set(State::Running);
+        regs.tasks_start.write(TASK::TASK::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        if self.state.get() != State::Running {
+            return ReturnCode::EALREADY;
+        }
+        self.halt(StopCause::Requested);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_running(&self) -> bool {
+        self.state.get() != State::Idle
+    }
+}
@@ -5,6 +5,7 @@ use kernel::common::registers::{
     register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::common::StaticRef;
+use kernel::power::SleepState;
 
 const POWER_BASE: StaticRef<PowerRegisters> =
     unsafe { StaticRef::new(0x40000000 as *const PowerRegisters) };
@@ -230,6 +231,35 @@ register_bitfields! [u32,
     ]
 ];
 
+/// Index of the System ON state in constant latency mode, which keeps the
+/// CPU wakeup time short at the cost of a higher idle current.
+pub const SLEEP_CONSTANT_LATENCY: usize = 0;
+/// Index of the System ON state in low-power mode, the reset default.
+pub const SLEEP_LOW_POWER: usize = 1;
+
+/// The sleep states of the nRF52, ordered as `Chip::sleep_states` expects.
+pub static SLEEP_STATES: [SleepState; 2] = [
+    SleepState {
+        name: "constant latency",
+        wakeup_latency_us: 0,
+    },
+    // In low-power mode the internal oscillator and regulators that feed the
+    // CPU have to start again on wakeup.
+    SleepState {
+        name: "low power",
+        wakeup_latency_us: 5,
+    },
+];
+
+/// Select the sub power mode the chip uses while it waits for an interrupt.
+pub(crate) fn set_sleep_state(state: usize) {
+    if state == SLEEP_CONSTANT_LATENCY {
+        POWER_BASE.task_constlat.write(Task::ENABLE::SET);
+    } else {
+        POWER_BASE.task_lowpwr.write(Task::ENABLE::SET);
+    }
+}
+
 /// The USB state machine needs to be notified of power events (USB detected, USB
 /// removed, USB power ready) in order to be initialized and shut down properly.
 /// These events come from the power management registers of this module; that's
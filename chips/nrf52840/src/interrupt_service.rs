@@ -9,6 +9,7 @@ use nrf52::chip::Nrf52DefaultPeripherals;
 pub struct Nrf52840DefaultPeripherals<'a> {
     pub nrf52: Nrf52DefaultPeripherals<'a>,
     pub usbd: crate::usbd::Usbd<'a>,
+    pub qspi: crate::qspi::Qspi<'a>,
     pub gpio_port: crate::gpio::Port<'a, { crate::gpio::NUM_PINS }>,
 }
 
@@ -17,6 +18,7 @@ impl<'a> Nrf52840DefaultPeripherals<'a> {
         Self {
             nrf52: Nrf52DefaultPeripherals::new(ppi),
             usbd: crate::usbd::Usbd::new(),
+            qspi: crate::qspi::Qspi::new(),
             gpio_port: crate::gpio::nrf52840_gpio_create(),
         }
     }
@@ -31,6 +33,7 @@ impl<'a> kernel::InterruptService<DeferredCallTask> for Nrf52840DefaultPeriphera
     unsafe fn service_interrupt(&self, interrupt: u32) -> bool {
         match interrupt {
             crate::peripheral_interrupts::USBD => self.usbd.handle_interrupt(),
+            crate::peripheral_interrupts::QSPI => self.qspi.handle_interrupt(),
             nrf52::peripheral_interrupts::GPIOTE => self.gpio_port.handle_interrupt(),
             _ => return self.nrf52.service_interrupt(interrupt),
         }
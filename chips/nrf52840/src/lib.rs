@@ -8,3 +8,4 @@ pub mod gpio;
 pub mod interrupt_service;
 
 pub mod peripheral_interrupts;
+pub mod qspi;
@@ -1,7 +1,6 @@
 pub const USBD: u32 = 39;
 #[allow(dead_code)]
 pub const UART1: u32 = 40;
-#[allow(dead_code)]
 pub const QSPI: u32 = 41;
 #[allow(dead_code)]
 pub const CRYPTOCELL: u32 = 42;
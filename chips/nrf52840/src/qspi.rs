@@ -0,0 +1,492 @@
+//! QSPI flash controller driver for nRF52840, using EasyDMA.
+//!
+//! The controller only knows the standard read and page program commands of
+//! serial flash chips, selected by the `READOC` and `WRITEOC` fields, and
+//! moves their data with EasyDMA. It runs them with the `READSTART` and
+//! `WRITESTART` tasks, which need word-aligned addresses, lengths and
+//! buffers in RAM. Any other command is sent as a custom instruction, on a
+//! single line and with at most 8 bytes of address and data.
+//!
+//! Once activated, the controller maps the flash at `0x12000000` and reads
+//! it with the `READOC` command. The mapping can not be turned off, so
+//! `unmap` only stops the driver from keeping `READOC` set for it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! nrf52840_peripherals.qspi.set_pins(
+//!     nrf52840::pinmux::Pinmux::new(QSPI_SCK as u32),
+//!     nrf52840::pinmux::Pinmux::new(QSPI_CSN as u32),
+//!     [
+//!         nrf52840::pinmux::Pinmux::new(QSPI_IO0 as u32),
+//!         nrf52840::pinmux::Pinmux::new(QSPI_IO1 as u32),
+//!         nrf52840::pinmux::Pinmux::new(QSPI_IO2 as u32),
+//!         nrf52840::pinmux::Pinmux::new(QSPI_IO3 as u32),
+//!     ],
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::qspi::{self, AddressSize, Command, MappedRegion, Mode};
+use kernel::ReturnCode;
+use nrf52::pinmux::Pinmux;
+
+const QSPI_BASE: StaticRef<QspiRegisters> =
+    unsafe { StaticRef::new(0x40029000 as *const QspiRegisters) };
+
+/// Where the controller maps the flash.
+const XIP_BASE: usize = 0x12000000;
+
+/// Largest flash the mapped region covers.
+const XIP_LEN: usize = 0x08000000;
+
+/// Largest transfer of the `READSTART` and `WRITESTART` tasks, in bytes.
+const MAX_TRANSFER: usize = 0x3FFFC;
+
+/// Frequency `SCKFREQ` divides.
+const BASE_CLOCK: u32 = 32_000_000;
+
+#[repr(C)]
+struct QspiRegisters {
+    /// Activate QSPI interface
+    tasks_activate: WriteOnly<u32, TASK::Register>,
+    /// Start transfer from external flash memory to internal RAM
+    tasks_readstart: WriteOnly<u32, TASK::Register>,
+    /// Start transfer from internal RAM to external flash memory
+    tasks_writestart: WriteOnly<u32, TASK::Register>,
+    /// Start external flash memory erase operation
+    tasks_erasestart: WriteOnly<u32, TASK::Register>,
+    /// Deactivate QSPI interface
+    tasks_deactivate: WriteOnly<u32, TASK::Register>,
+    _reserved0: [u8; 236],
+    /// QSPI peripheral is ready
+    events_ready: ReadWrite<u32, EVENT::Register>,
+    _reserved1: [u8; 508],
+    /// Enable or disable interrupt
+    inten: ReadWrite<u32, INTE::Register>,
+    /// Enable interrupt
+    intenset: ReadWrite<u32, INTE::Register>,
+    /// Disable interrupt
+    intenclr: ReadWrite<u32, INTE::Register>,
+    _reserved2: [u8; 500],
+    /// Enable QSPI peripheral and acquire the pins selected in PSELn registers
+    enable: ReadWrite<u32, ENABLE::Register>,
+    /// Flash memory source address
+    read_src: ReadWrite<u32>,
+    /// RAM destination address
+    read_dst: ReadWrite<u32>,
+    /// Read transfer length
+    read_cnt: ReadWrite<u32>,
+    /// Flash destination address
+    write_dst: ReadWrite<u32>,
+    /// RAM source address
+    write_src: ReadWrite<u32>,
+    /// Write transfer length
+    write_cnt: ReadWrite<u32>,
+    /// Start address of flash block to be erased
+    erase_ptr: ReadWrite<u32>,
+    /// Size of block to be erased
+    erase_len: ReadWrite<u32>,
+    /// Pin select for serial clock SCK
+    psel_sck: VolatileCell<Pinmux>,
+    /// Pin select for chip select signal CSN
+    psel_csn: VolatileCell<Pinmux>,
+    _reserved3: [u8; 4],
+    /// Pin select for serial data IO0 to IO3
+    psel_io: [VolatileCell<Pinmux>; 4],
+    /// Address offset into the external memory for Execute in Place operation
+    xipoffset: ReadWrite<u32>,
+    /// Interface configuration
+    ifconfig0: ReadWrite<u32, IFCONFIG0::Register>,
+    _reserved4: [u8; 184],
+    /// Interface configuration
+    ifconfig1: ReadWrite<u32, IFCONFIG1::Register>,
+    /// Status register
+    status: ReadOnly<u32>,
+    _reserved5: [u8; 44],
+    /// Custom instruction configuration register
+    cinstrconf: ReadWrite<u32, CINSTRCONF::Register>,
+    /// Custom instruction data register 0
+    cinstrdat0: ReadWrite<u32>,
+    /// Custom instruction data register 1
+    cinstrdat1: ReadWrite<u32>,
+}
+
+register_bitfields![u32,
+    TASK [
+        TASK 0
+    ],
+    EVENT [
+        EVENT 0
+    ],
+    INTE [
+        READY 0
+    ],
+    ENABLE [
+        ENABLE 0
+    ],
+    IFCONFIG0 [
+        READOC OFFSET(0) NUMBITS(3) [
+            FastRead = 0,
+            Read2O = 1,
+            Read2IO = 2,
+            Read4O = 3,
+            Read4IO = 4
+        ],
+        WRITEOC OFFSET(3) NUMBITS(3) [
+            PP = 0,
+            PP2O = 1,
+            PP4O = 2,
+            PP4IO = 3
+        ],
+        ADDRMODE OFFSET(6) NUMBITS(1) [
+            Bit24 = 0,
+            Bit32 = 1
+        ]
+    ],
+    IFCONFIG1 [
+        SCKDELAY OFFSET(0) NUMBITS(8) [],
+        SPIMODE OFFSET(25) NUMBITS(1) [
+            Mode0 = 0,
+            Mode3 = 1
+        ],
+        SCKFREQ OFFSET(28) NUMBITS(4) []
+    ],
+    CINSTRCONF [
+        OPCODE OFFSET(0) NUMBITS(8) [],
+        LENGTH OFFSET(8) NUMBITS(4) [],
+        LIO2 OFFSET(12) NUMBITS(1) [],
+        LIO3 OFFSET(13) NUMBITS(1) [],
+        WIPWAIT OFFSET(14) NUMBITS(1) [],
+        WREN OFFSET(15) NUMBITS(1) []
+    ]
+];
+
+/// Read commands the controller knows, and their `READOC` values.
+const READ_COMMANDS: [(u8, Mode, u32); 5] = [
+    (0x0B, Mode::Single, 0),
+    (0x3B, Mode::DualOutput, 1),
+    (0xBB, Mode::DualIo, 2),
+    (0x6B, Mode::QuadOutput, 3),
+    (0xEB, Mode::QuadIo, 4),
+];
+
+/// Program commands the controller knows, and their `WRITEOC` values.
+const WRITE_COMMANDS: [(u8, Mode, u32); 4] = [
+    (0x02, Mode::Single, 0),
+    (0xA2, Mode::DualOutput, 1),
+    (0x32, Mode::QuadOutput, 2),
+    (0x38, Mode::QuadIo, 3),
+];
+
+/// Bytes a custom instruction carries after the opcode.
+const CINSTR_BYTES: usize = 8;
+
+fn opcode(commands: &[(u8, Mode, u32)], command: &Command) -> Option<u32> {
+    commands
+        .iter()
+        .find(|&&(instruction, mode, _)| instruction == command.instruction && mode == command.mode)
+        .map(|&(_, _, value)| value)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    /// A custom instruction, reading `len` bytes after the first `skip`
+    Custom {
+        skip: usize,
+        len: usize,
+    },
+    Transfer,
+}
+
+pub struct Qspi<'a> {
+    registers: StaticRef<QspiRegisters>,
+    client: OptionalCell<&'a dyn qspi::Client>,
+    buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Operation>,
+    address_size: Cell<AddressSize>,
+    size: Cell<usize>,
+    /// `READOC` value of the mapped region
+    mapped: Cell<Option<u32>>,
+}
+
+impl<'a> Qspi<'a> {
+    pub fn new() -> Qspi<'a> {
+        Qspi {
+            registers: QSPI_BASE,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            address_size: Cell::new(AddressSize::Bits24),
+            size: Cell::new(0),
+            mapped: Cell::new(None),
+        }
+    }
+
+    pub fn set_pins(&self, sck: Pinmux, csn: Pinmux, io: [Pinmux; 4]) {
+        let regs = &*self.registers;
+        regs.psel_sck.set(sck);
+        regs.psel_csn.set(csn);
+        for (psel, pin) in regs.psel_io.iter().zip(io.iter()) {
+            psel.set(*pin);
+        }
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        if !regs.events_ready.is_set(EVENT::EVENT) {
+            return;
+        }
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.intenclr.write(INTE::READY::SET);
+
+        let operation = self.operation.replace(Operation::Idle);
+        if let Operation::Custom { skip, len } = operation {
+            let data = (regs.cinstrdat0.get() as u64) | (regs.cinstrdat1.get() as u64) << 32;
+            self.buffer.map(|buffer| {
+                for (i, byte) in buffer[..len].iter_mut().enumerate() {
+                    *byte = (data >> ((skip + i) * 8)) as u8;
+                }
+            });
+        }
+        // Put back the command of the mapped region.
+        if let Some(readoc) = self.mapped.get() {
+            regs.ifconfig0.modify(IFCONFIG0::READOC.val(readoc));
+        }
+
+        if operation != Operation::Idle {
+            let buffer = self.buffer.take();
+            self.client
+                .map(move |client| client.command_done(buffer, ReturnCode::SUCCESS));
+        }
+    }
+
+    fn activate(&self) {
+        let regs = &*self.registers;
+        if regs.enable.is_set(ENABLE::ENABLE) {
+            return;
+        }
+        regs.enable.write(ENABLE::ENABLE::SET);
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.tasks_activate.write(TASK::TASK::SET);
+        // Activation only takes a few clock cycles.
+        while !regs.events_ready.is_set(EVENT::EVENT) {}
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+    }
+
+    fn address_bytes(&self, command: &Command) -> usize {
+        command
+            .address
+            .map_or(0, |_| self.address_size.get().bytes())
+    }
+
+    /// Start `command` as a custom instruction that sends `data` after the
+    /// address.
+    fn custom(&self, command: &Command, data: &[u8], operation: Operation) -> ReturnCode {
+        let address_bytes = self.address_bytes(command);
+        let len = match operation {
+            Operation::Custom { len, .. } => len.max(data.len()),
+            _ => data.len(),
+        };
+        if command.mode != Mode::Single
+            || command.dummy_cycles != 0
+            || address_bytes + len > CINSTR_BYTES
+        {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        let mut bytes = [0u8; CINSTR_BYTES];
+        if let Some(address) = command.address {
+            for i in 0..address_bytes {
+                bytes[i] = (address >> ((address_bytes - 1 - i) * 8)) as u8;
+            }
+        }
+        bytes[address_bytes..address_bytes + data.len()].copy_from_slice(data);
+        let data = u64::from_le_bytes(bytes);
+
+        let regs = &*self.registers;
+        regs.cinstrdat0.set(data as u32);
+        regs.cinstrdat1.set((data >> 32) as u32);
+        self.start(operation);
+        regs.cinstrconf.write(
+            CINSTRCONF::OPCODE.val(command.instruction as u32)
+                + CINSTRCONF::LENGTH.val((1 + address_bytes + len) as u32)
+                + CINSTRCONF::LIO2::SET
+                + CINSTRCONF::LIO3::SET,
+        );
+        ReturnCode::SUCCESS
+    }
+
+    /// Check that the `READSTART` or `WRITESTART` task can move `len` bytes
+    /// of `buffer` at the address of `command`.
+    fn transferable(&self, command: &Command, buffer: &[u8], len: usize) -> bool {
+        let addr = buffer.as_ptr() as usize;
+        command.address.map_or(false, |address| address % 4 == 0)
+            && len % 4 == 0
+            && len <= MAX_TRANSFER
+            && addr % 4 == 0
+            && addr & 0xE0000000 == 0x20000000
+    }
+
+    fn start(&self, operation: Operation) {
+        let regs = &*self.registers;
+        self.operation.set(operation);
+        regs.events_ready.write(EVENT::EVENT::CLEAR);
+        regs.intenset.write(INTE::READY::SET);
+    }
+}
+
+impl<'a> qspi::Qspi<'a> for Qspi<'a> {
+    fn set_client(&self, client: &'a dyn qspi::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(
+        &self,
+        rate: u32,
+        size: usize,
+        address_size: AddressSize,
+    ) -> Result<u32, ReturnCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        if !size.is_power_of_two() || size > XIP_LEN {
+            return Err(ReturnCode::EINVAL);
+        }
+
+        let divider = ((BASE_CLOCK + rate.max(1) - 1) / rate.max(1))
+            .max(1)
+            .min(16);
+        let regs = &*self.registers;
+        regs.ifconfig0.modify(match address_size {
+            AddressSize::Bits24 => IFCONFIG0::ADDRMODE::Bit24,
+            AddressSize::Bits32 => IFCONFIG0::ADDRMODE::Bit32,
+        });
+        regs.ifconfig1
+            .modify(IFCONFIG1::SCKFREQ.val(divider - 1) + IFCONFIG1::SPIMODE::Mode0);
+        regs.xipoffset.set(0);
+        self.address_size.set(address_size);
+        self.size.set(size);
+        self.activate();
+        Ok(BASE_CLOCK / divider)
+    }
+
+    fn command(&self, command: Command) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.custom(&command, &[], Operation::Custom { skip: 0, len: 0 })
+    }
+
+    fn read(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if len == 0 || len > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+
+        let regs = &*self.registers;
+        if let Some(readoc) = opcode(&READ_COMMANDS, &command) {
+            if !self.transferable(&command, buffer, len) {
+                return Err((ReturnCode::EINVAL, buffer));
+            }
+            regs.ifconfig0.modify(IFCONFIG0::READOC.val(readoc));
+            regs.read_src.set(command.address.unwrap_or(0));
+            regs.read_dst.set(buffer.as_ptr() as u32);
+            regs.read_cnt.set(len as u32);
+            self.buffer.replace(buffer);
+            self.start(Operation::Transfer);
+            regs.tasks_readstart.write(TASK::TASK::SET);
+            return Ok(());
+        }
+
+        let skip = self.address_bytes(&command);
+        match self.custom(&command, &[], Operation::Custom { skip, len }) {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            rc => Err((rc, buffer)),
+        }
+    }
+
+    fn write(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.operation.get() != Operation::Idle {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if len == 0 || len > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+
+        let regs = &*self.registers;
+        if let Some(writeoc) = opcode(&WRITE_COMMANDS, &command) {
+            if !self.transferable(&command, buffer, len) {
+                return Err((ReturnCode::EINVAL, buffer));
+            }
+            regs.ifconfig0.modify(IFCONFIG0::WRITEOC.val(writeoc));
+            regs.write_dst.set(command.address.unwrap_or(0));
+            regs.write_src.set(buffer.as_ptr() as u32);
+            regs.write_cnt.set(len as u32);
+            self.buffer.replace(buffer);
+            self.start(Operation::Transfer);
+            regs.tasks_writestart.write(TASK::TASK::SET);
+            return Ok(());
+        }
+
+        let rc = if len > CINSTR_BYTES {
+            ReturnCode::ENOSUPPORT
+        } else {
+            self.custom(
+                &command,
+                &buffer[..len],
+                Operation::Custom { skip: 0, len: 0 },
+            )
+        };
+        match rc {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            rc => Err((rc, buffer)),
+        }
+    }
+
+    fn map(&self, read: Command) -> Result<MappedRegion, ReturnCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        let readoc = opcode(&READ_COMMANDS, &read).ok_or(ReturnCode::ENOSUPPORT)?;
+        self.registers
+            .ifconfig0
+            .modify(IFCONFIG0::READOC.val(readoc));
+        self.mapped.set(Some(readoc));
+        Ok(MappedRegion {
+            address: XIP_BASE,
+            len: self.size.get(),
+        })
+    }
+
+    fn unmap(&self) -> ReturnCode {
+        if self.mapped.get().is_none() {
+            return ReturnCode::EALREADY;
+        }
+        self.mapped.set(None);
+        ReturnCode::SUCCESS
+    }
+}
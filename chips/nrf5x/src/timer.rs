@@ -22,6 +22,7 @@
 //! * Philip Levis <pal@cs.stanford.edu>
 //! * Date: August 18, 2016
 
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
@@ -29,6 +30,7 @@ use kernel::hil;
 use kernel::hil::time::{Alarm, Ticks, Time};
 use kernel::ReturnCode;
 
+#[cfg(not(feature = "nrf52"))]
 const INSTANCES: [StaticRef<TimerRegisters>; 3] = unsafe {
     [
         StaticRef::new(0x40008000 as *const TimerRegisters),
@@ -37,6 +39,17 @@ const INSTANCES: [StaticRef<TimerRegisters>; 3] = unsafe {
     ]
 };
 
+#[cfg(feature = "nrf52")]
+const INSTANCES: [StaticRef<TimerRegisters>; 5] = unsafe {
+    [
+        StaticRef::new(0x40008000 as *const TimerRegisters),
+        StaticRef::new(0x40009000 as *const TimerRegisters),
+        StaticRef::new(0x4000A000 as *const TimerRegisters),
+        StaticRef::new(0x4001A000 as *const TimerRegisters),
+        StaticRef::new(0x4001B000 as *const TimerRegisters),
+    ]
+};
+
 #[repr(C)]
 struct TimerRegisters {
     /// Start Timer
@@ -259,6 +272,7 @@ impl Timer {
 pub struct TimerAlarm<'a> {
     registers: StaticRef<TimerRegisters>,
     client: OptionalCell<&'a dyn hil::time::AlarmClient>,
+    free_running: Cell<bool>,
 }
 
 // CC0 is used for capture
@@ -271,13 +285,26 @@ impl<'a> TimerAlarm<'a> {
         TimerAlarm {
             registers: INSTANCES[instance],
             client: OptionalCell::empty(),
+            free_running: Cell::new(false),
         }
     }
 
+    /// Keep the counter running while no alarm is set, so that `now()`
+    /// advances and the timer can be shared through a virtual alarm mux.
+    /// By default the counter is stopped and reset when an alarm fires,
+    /// which lets the high frequency clock stop while the timer is idle.
+    pub fn start(&self) {
+        self.free_running.set(true);
+        self.registers.bitmode.write(Bitmode::BITMODE::Bit32);
+        self.registers.tasks_start.write(Task::ENABLE::SET);
+    }
+
     fn clear_alarm(&self) {
         self.registers.events_compare[CC_COMPARE].write(Event::READY::CLEAR);
-        self.registers.tasks_stop.write(Task::ENABLE::SET);
-        self.registers.tasks_clear.write(Task::ENABLE::SET);
+        if !self.free_running.get() {
+            self.registers.tasks_stop.write(Task::ENABLE::SET);
+            self.registers.tasks_clear.write(Task::ENABLE::SET);
+        }
         self.disable_interrupts();
     }
 
@@ -307,7 +334,9 @@ impl<'a> TimerAlarm<'a> {
 }
 
 impl Time for TimerAlarm<'_> {
-    type Frequency = hil::time::Freq16KHz;
+    // PRESCALER is left at its reset value of 4, so the counter runs at
+    // 16 MHz / 2^4.
+    type Frequency = hil::time::Freq1MHz;
     // Note: we always use BITMODE::32.
     type Ticks = hil::time::Ticks32;
 
@@ -6,6 +6,7 @@ use crate::pm;
 use core::fmt::Write;
 use cortexm4;
 use kernel::common::deferred_call;
+use kernel::power::SleepState;
 use kernel::{Chip, InterruptService};
 
 pub struct Sam4l<I: InterruptService<Task> + 'static> {
@@ -285,19 +286,32 @@ impl<I: InterruptService<Task> + 'static> Chip for Sam4l<I> {
     }
 
     fn sleep(&self) {
-        if pm::deep_sleep_ready() {
+        self.sleep_in(pm::DEEP_SLEEP);
+    }
+
+    fn sleep_states(&self) -> &'static [SleepState] {
+        &pm::SLEEP_STATES
+    }
+
+    fn sleep_in(&self, state: usize) -> usize {
+        // Peripherals whose clocks are still enabled keep the chip out of
+        // deep sleep regardless of the requested state.
+        let state = if state >= pm::DEEP_SLEEP && pm::deep_sleep_ready() {
             unsafe {
                 cortexm4::scb::set_sleepdeep();
             }
+            pm::DEEP_SLEEP
         } else {
             unsafe {
                 cortexm4::scb::unset_sleepdeep();
             }
-        }
+            pm::SLEEP
+        };
 
         unsafe {
             cortexm4::support::wfi();
         }
+        state
     }
 
     unsafe fn atomic<F, R>(&self, f: F) -> R
@@ -9,6 +9,7 @@ use core::cell::Cell;
 use core::sync::atomic::Ordering;
 use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::power::SleepState;
 use kernel::ClockInterface;
 
 /// §10.7 PM::UserInterface from SAM4L Datasheet.
@@ -1033,6 +1034,25 @@ macro_rules! get_clock {
     }};
 }
 
+/// Index of the sleep state that only stops the CPU clock.
+pub const SLEEP: usize = 0;
+/// Index of the deep sleep (WAIT) state, which also stops the main clocks.
+pub const DEEP_SLEEP: usize = 1;
+
+/// The sleep states of the SAM4L, ordered as `Chip::sleep_states` expects.
+pub static SLEEP_STATES: [SleepState; 2] = [
+    SleepState {
+        name: "sleep",
+        wakeup_latency_us: 0,
+    },
+    // Waking from deep sleep restarts the main clock source and lets it
+    // settle before the CPU runs again.
+    SleepState {
+        name: "deep sleep",
+        wakeup_latency_us: 500,
+    },
+];
+
 /// Determines if the chip can safely go into deep sleep without preventing
 /// currently active peripherals from operating.
 ///
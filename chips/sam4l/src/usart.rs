@@ -11,6 +11,7 @@ use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::hil::spi;
 use kernel::hil::uart;
+use kernel::power::PowerConstraint;
 use kernel::ReturnCode;
 
 use crate::dma;
@@ -394,6 +395,8 @@ pub struct USART<'a> {
 
     spi_chip_select: OptionalCell<&'a dyn hil::gpio::Pin>,
     pm: &'a pm::PowerManager,
+
+    power_constraint: PowerConstraint<'a>,
 }
 
 impl<'a> USART<'a> {
@@ -427,6 +430,8 @@ impl<'a> USART<'a> {
             // This is only used if the USART is in SPI mode.
             spi_chip_select: OptionalCell::empty(),
             pm,
+
+            power_constraint: PowerConstraint::new(),
         }
     }
 
@@ -470,6 +475,12 @@ impl<'a> USART<'a> {
         )
     }
 
+    /// The constraint the USART sets while a transfer is in progress. Boards
+    /// register it with their power manager.
+    pub fn power_constraint(&'a self) -> &'a PowerConstraint<'a> {
+        &self.power_constraint
+    }
+
     pub fn set_dma(&self, rx_dma: &'a dma::DMAChannel, tx_dma: &'a dma::DMAChannel) {
         self.rx_dma.set(Some(rx_dma));
         self.tx_dma.set(Some(tx_dma));
@@ -606,6 +617,20 @@ impl<'a> USART<'a> {
         self.disable_tx_interrupts(usart);
     }
 
+    // The USART clock is stopped in deep sleep, so the chip has to stay in
+    // sleep while a transfer is in progress. `sleep_in` already falls back
+    // to sleep while the clock is enabled; the constraint lets the power
+    // manager select and account for the state the chip actually enters.
+    fn update_power_constraint(&self) {
+        if self.usart_tx_state.get() == USARTStateTX::Idle
+            && self.usart_rx_state.get() == USARTStateRX::Idle
+        {
+            self.power_constraint.release();
+        } else {
+            self.power_constraint.block_deep_sleep();
+        }
+    }
+
     fn reset(&self, usart: &USARTRegManager) {
         usart
             .registers
@@ -700,6 +725,7 @@ impl<'a> USART<'a> {
 
         // Reset status registers.
         usart.registers.cr.write(Control::RSTSTA::SET);
+        self.update_power_constraint();
     }
 
     fn set_baud_rate(&self, usart: &USARTRegManager, baud_rate: u32) {
@@ -842,6 +868,7 @@ impl dma::DMAClient for USART<'_> {
 
             _ => {}
         }
+        self.update_power_constraint();
     }
 }
 
@@ -869,6 +896,7 @@ impl<'a> uart::Receive<'a> for USART<'a> {
         self.enable_rx(usart);
         self.enable_rx_error_interrupts(usart);
         self.usart_rx_state.set(USARTStateRX::DMA_Receiving);
+        self.update_power_constraint();
         // set up dma transfer and start reception
         if let Some(dma) = self.rx_dma.get() {
             dma.enable();
@@ -884,6 +912,7 @@ impl<'a> uart::Receive<'a> for USART<'a> {
         let usart = &USARTRegManager::new(&self);
         self.disable_rx_timeout(usart);
         self.abort_rx(usart, ReturnCode::ECANCEL, uart::Error::Aborted);
+        self.update_power_constraint();
         ReturnCode::EBUSY
     }
 
@@ -908,6 +937,7 @@ impl<'a> uart::Transmit<'a> for USART<'a> {
             // enable TX
             self.enable_tx(usart);
             self.usart_tx_state.set(USARTStateTX::DMA_Transmitting);
+            self.update_power_constraint();
 
             // set up dma transfer and start transmission
             if self.tx_dma.get().is_some() {
@@ -927,6 +957,7 @@ impl<'a> uart::Transmit<'a> for USART<'a> {
         if self.usart_tx_state.get() != USARTStateTX::Idle {
             let usart = &USARTRegManager::new(&self);
             self.abort_tx(usart, ReturnCode::ECANCEL);
+            self.update_power_constraint();
             ReturnCode::EBUSY
         } else {
             ReturnCode::SUCCESS
@@ -1003,6 +1034,7 @@ impl<'a> uart::ReceiveAdvanced<'a> for USART<'a> {
             self.enable_rx(usart);
             self.enable_rx_error_interrupts(usart);
             self.usart_rx_state.set(USARTStateRX::DMA_Receiving);
+            self.update_power_constraint();
 
             // enable receive timeout
             self.enable_rx_timeout(usart, interbyte_timeout);
@@ -1114,6 +1146,7 @@ impl spi::SpiMaster for USART<'_> {
                 dma.do_transfer(self.tx_dma_peripheral, write_buffer, count);
             });
         }
+        self.update_power_constraint();
 
         ReturnCode::SUCCESS
     }
@@ -0,0 +1,596 @@
+//! bxCAN controller (CAN1)
+//!
+//! The driver sends from transmit mailbox 0 and receives through FIFO 0.
+//! Every `hil::can` filter maps to one 32-bit mask filter bank. CAN1 owns
+//! the first 14 of the 28 filter banks, the rest belong to CAN2.
+//!
+//! The controller recovers from bus-off automatically after 128 occurrences
+//! of 11 recessive bits, the new state is reported with the next interrupt.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{register_bitfields, register_structs, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::can;
+use kernel::ClockInterface;
+use kernel::ReturnCode;
+
+use crate::rcc;
+
+register_structs! {
+    /// Transmit mailbox
+    TxMailbox {
+        /// identifier register
+        (0x00 => tir: ReadWrite<u32, TIR::Register>),
+        /// data length control and time stamp register
+        (0x04 => tdtr: ReadWrite<u32, TDTR::Register>),
+        /// data low register
+        (0x08 => tdlr: ReadWrite<u32>),
+        /// data high register
+        (0x0C => tdhr: ReadWrite<u32>),
+        (0x10 => @END),
+    },
+    /// Receive FIFO mailbox
+    RxMailbox {
+        /// identifier register
+        (0x00 => rir: ReadWrite<u32, RIR::Register>),
+        /// data length control and time stamp register
+        (0x04 => rdtr: ReadWrite<u32, RDTR::Register>),
+        /// data low register
+        (0x08 => rdlr: ReadWrite<u32>),
+        /// data high register
+        (0x0C => rdhr: ReadWrite<u32>),
+        (0x10 => @END),
+    },
+    /// Filter bank
+    FilterBank {
+        (0x00 => fr1: ReadWrite<u32>),
+        (0x04 => fr2: ReadWrite<u32>),
+        (0x08 => @END),
+    },
+    CanRegisters {
+        /// master control register
+        (0x000 => mcr: ReadWrite<u32, MCR::Register>),
+        /// master status register
+        (0x004 => msr: ReadWrite<u32, MSR::Register>),
+        /// transmit status register
+        (0x008 => tsr: ReadWrite<u32, TSR::Register>),
+        /// receive FIFO 0 register
+        (0x00C => rf0r: ReadWrite<u32, RFR::Register>),
+        /// receive FIFO 1 register
+        (0x010 => rf1r: ReadWrite<u32, RFR::Register>),
+        /// interrupt enable register
+        (0x014 => ier: ReadWrite<u32, IER::Register>),
+        /// error status register
+        (0x018 => esr: ReadWrite<u32, ESR::Register>),
+        /// bit timing register
+        (0x01C => btr: ReadWrite<u32, BTR::Register>),
+        (0x020 => _reserved0),
+        (0x180 => tx: [TxMailbox; 3]),
+        (0x1B0 => rx: [RxMailbox; 2]),
+        (0x1D0 => _reserved1),
+        /// filter master register
+        (0x200 => fmr: ReadWrite<u32, FMR::Register>),
+        /// filter mode register
+        (0x204 => fm1r: ReadWrite<u32>),
+        (0x208 => _reserved2),
+        /// filter scale register
+        (0x20C => fs1r: ReadWrite<u32>),
+        (0x210 => _reserved3),
+        /// filter FIFO assignment register
+        (0x214 => ffa1r: ReadWrite<u32>),
+        (0x218 => _reserved4),
+        /// filter activation register
+        (0x21C => fa1r: ReadWrite<u32>),
+        (0x220 => _reserved5),
+        (0x240 => filters: [FilterBank; 28]),
+        (0x320 => @END),
+    }
+}
+
+register_bitfields![u32,
+    MCR [
+        /// Debug freeze
+        DBF OFFSET(16) NUMBITS(1) [],
+        /// bxCAN software master reset
+        RESET OFFSET(15) NUMBITS(1) [],
+        /// Time triggered communication mode
+        TTCM OFFSET(7) NUMBITS(1) [],
+        /// Automatic bus-off management
+        ABOM OFFSET(6) NUMBITS(1) [],
+        /// Automatic wakeup mode
+        AWUM OFFSET(5) NUMBITS(1) [],
+        /// No automatic retransmission
+        NART OFFSET(4) NUMBITS(1) [],
+        /// Receive FIFO locked mode
+        RFLM OFFSET(3) NUMBITS(1) [],
+        /// Transmit FIFO priority
+        TXFP OFFSET(2) NUMBITS(1) [],
+        /// Sleep mode request
+        SLEEP OFFSET(1) NUMBITS(1) [],
+        /// Initialization request
+        INRQ OFFSET(0) NUMBITS(1) []
+    ],
+    MSR [
+        /// Receive signal
+        RX OFFSET(11) NUMBITS(1) [],
+        /// Last sample point
+        SAMP OFFSET(10) NUMBITS(1) [],
+        /// Receive mode
+        RXM OFFSET(9) NUMBITS(1) [],
+        /// Transmit mode
+        TXM OFFSET(8) NUMBITS(1) [],
+        /// Sleep acknowledge interrupt
+        SLAKI OFFSET(4) NUMBITS(1) [],
+        /// Wakeup interrupt
+        WKUI OFFSET(3) NUMBITS(1) [],
+        /// Error interrupt
+        ERRI OFFSET(2) NUMBITS(1) [],
+        /// Sleep acknowledge
+        SLAK OFFSET(1) NUMBITS(1) [],
+        /// Initialization acknowledge
+        INAK OFFSET(0) NUMBITS(1) []
+    ],
+    TSR [
+        /// Transmit mailbox 2 empty
+        TME2 OFFSET(28) NUMBITS(1) [],
+        /// Transmit mailbox 1 empty
+        TME1 OFFSET(27) NUMBITS(1) [],
+        /// Transmit mailbox 0 empty
+        TME0 OFFSET(26) NUMBITS(1) [],
+        /// Abort request for mailbox 0
+        ABRQ0 OFFSET(7) NUMBITS(1) [],
+        /// Transmission error of mailbox 0
+        TERR0 OFFSET(3) NUMBITS(1) [],
+        /// Arbitration lost for mailbox 0
+        ALST0 OFFSET(2) NUMBITS(1) [],
+        /// Transmission OK of mailbox 0
+        TXOK0 OFFSET(1) NUMBITS(1) [],
+        /// Request completed mailbox 0
+        RQCP0 OFFSET(0) NUMBITS(1) []
+    ],
+    RFR [
+        /// Release FIFO output mailbox
+        RFOM OFFSET(5) NUMBITS(1) [],
+        /// FIFO overrun
+        FOVR OFFSET(4) NUMBITS(1) [],
+        /// FIFO full
+        FULL OFFSET(3) NUMBITS(1) [],
+        /// FIFO message pending
+        FMP OFFSET(0) NUMBITS(2) []
+    ],
+    IER [
+        /// Error interrupt enable
+        ERRIE OFFSET(15) NUMBITS(1) [],
+        /// Last error code interrupt enable
+        LECIE OFFSET(11) NUMBITS(1) [],
+        /// Bus-off interrupt enable
+        BOFIE OFFSET(10) NUMBITS(1) [],
+        /// Error passive interrupt enable
+        EPVIE OFFSET(9) NUMBITS(1) [],
+        /// Error warning interrupt enable
+        EWGIE OFFSET(8) NUMBITS(1) [],
+        /// FIFO 0 overrun interrupt enable
+        FOVIE0 OFFSET(3) NUMBITS(1) [],
+        /// FIFO 0 message pending interrupt enable
+        FMPIE0 OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox empty interrupt enable
+        TMEIE OFFSET(0) NUMBITS(1) []
+    ],
+    ESR [
+        /// Receive error counter
+        REC OFFSET(24) NUMBITS(8) [],
+        /// Transmit error counter
+        TEC OFFSET(16) NUMBITS(8) [],
+        /// Last error code
+        LEC OFFSET(4) NUMBITS(3) [],
+        /// Bus-off flag
+        BOFF OFFSET(2) NUMBITS(1) [],
+        /// Error passive flag
+        EPVF OFFSET(1) NUMBITS(1) [],
+        /// Error warning flag
+        EWGF OFFSET(0) NUMBITS(1) []
+    ],
+    BTR [
+        /// Silent mode
+        SILM OFFSET(31) NUMBITS(1) [],
+        /// Loop back mode
+        LBKM OFFSET(30) NUMBITS(1) [],
+        /// Resynchronization jump width, minus one
+        SJW OFFSET(24) NUMBITS(2) [],
+        /// Time segment 2, minus one
+        TS2 OFFSET(20) NUMBITS(3) [],
+        /// Time segment 1, minus one
+        TS1 OFFSET(16) NUMBITS(4) [],
+        /// Baud rate prescaler, minus one
+        BRP OFFSET(0) NUMBITS(10) []
+    ],
+    TIR [
+        /// Standard identifier or extended identifier
+        STID OFFSET(21) NUMBITS(11) [],
+        /// Extended identifier
+        EXID OFFSET(3) NUMBITS(18) [],
+        /// Identifier extension
+        IDE OFFSET(2) NUMBITS(1) [],
+        /// Remote transmission request
+        RTR OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox request
+        TXRQ OFFSET(0) NUMBITS(1) []
+    ],
+    TDTR [
+        /// Data length code
+        DLC OFFSET(0) NUMBITS(4) []
+    ],
+    RIR [
+        /// Standard identifier or extended identifier
+        STID OFFSET(21) NUMBITS(11) [],
+        /// Extended identifier
+        EXID OFFSET(3) NUMBITS(18) [],
+        /// Identifier extension
+        IDE OFFSET(2) NUMBITS(1) [],
+        /// Remote transmission request
+        RTR OFFSET(1) NUMBITS(1) []
+    ],
+    RDTR [
+        /// Filter match index
+        FMI OFFSET(8) NUMBITS(8) [],
+        /// Data length code
+        DLC OFFSET(0) NUMBITS(4) []
+    ],
+    FMR [
+        /// CAN2 start bank
+        CAN2SB OFFSET(8) NUMBITS(6) [],
+        /// Filter initialization mode
+        FINIT OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+const CAN1_BASE: StaticRef<CanRegisters> =
+    unsafe { StaticRef::new(0x4000_6400 as *const CanRegisters) };
+
+/// Filter banks assigned to CAN1 with the reset value of CAN2SB.
+const FILTER_BANKS: usize = 14;
+
+// PCLK1 runs at 16MHz from the HSI.
+const PCLK1_HZ: u32 = 16_000_000;
+
+// Bounds the wait for the controller to acknowledge a mode change. Entering
+// or leaving initialization takes at most 11 recessive bits on the bus.
+const MODE_CHANGE_TIMEOUT: usize = 100_000;
+
+/// Position of the identifier in the identifier and filter registers.
+fn id_register(id: can::Id) -> u32 {
+    match id {
+        can::Id::Standard(id) => (id as u32) << 21,
+        // IDE
+        can::Id::Extended(id) => (id << 3) | (1 << 2),
+    }
+}
+
+pub struct Can<'a> {
+    registers: StaticRef<CanRegisters>,
+    clock: CanClock<'a>,
+    tx_client: OptionalCell<&'a dyn can::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn can::ReceiveClient>,
+    state_client: OptionalCell<&'a dyn can::StateClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    timing: Cell<can::BitTiming>,
+    mode: Cell<can::Mode>,
+    enabled: Cell<bool>,
+    state: Cell<can::ErrorState>,
+}
+
+impl<'a> Can<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Can<'a> {
+        Can {
+            registers: CAN1_BASE,
+            clock: CanClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::APB1(rcc::PCLK1::CAN1),
+                rcc,
+            )),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            // 500 kbit/s from the 16MHz PCLK1
+            timing: Cell::new(can::BitTiming {
+                prescaler: 2,
+                segment1: 13,
+                segment2: 2,
+                sync_jump_width: 1,
+            }),
+            mode: Cell::new(can::Mode::Normal),
+            enabled: Cell::new(false),
+            state: Cell::new(can::ErrorState::Active),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    fn request_init(&self, init: bool) -> bool {
+        let value = if init { 1 } else { 0 };
+        self.registers.mcr.modify(MCR::INRQ.val(value));
+        for _ in 0..MODE_CHANGE_TIMEOUT {
+            if self.registers.msr.read(MSR::INAK) == value {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn handle_transmit_interrupt(&self) {
+        if self.registers.tsr.is_set(TSR::RQCP0) {
+            let rc = if self.registers.tsr.is_set(TSR::TXOK0) {
+                ReturnCode::SUCCESS
+            } else if self.enabled.get() {
+                ReturnCode::FAIL
+            } else {
+                ReturnCode::ECANCEL
+            };
+            // Writing RQCP0 also clears TXOK0, ALST0 and TERR0
+            self.registers.tsr.write(TSR::RQCP0::SET);
+            self.tx_buffer.take().map(|buffer| {
+                self.tx_client
+                    .map(move |client| client.transmit_complete(rc, buffer));
+            });
+        }
+        self.update_state();
+    }
+
+    pub fn handle_fifo0_interrupt(&self) {
+        if self.registers.rf0r.is_set(RFR::FOVR) {
+            self.registers.rf0r.write(RFR::FOVR::SET);
+        }
+        while self.registers.rf0r.read(RFR::FMP) > 0 {
+            let mailbox = &self.registers.rx[0];
+            let rir = mailbox.rir.extract();
+            let len = (mailbox.rdtr.read(RDTR::DLC) as usize).min(can::MAX_DATA_LEN);
+            let mut data = [0; can::MAX_DATA_LEN];
+            data[..4].copy_from_slice(&mailbox.rdlr.get().to_le_bytes());
+            data[4..].copy_from_slice(&mailbox.rdhr.get().to_le_bytes());
+            self.registers.rf0r.write(RFR::RFOM::SET);
+
+            // Filters do not look at the RTR bit, skip remote frames here
+            if rir.is_set(RIR::RTR) {
+                continue;
+            }
+            let id = if rir.is_set(RIR::IDE) {
+                can::Id::Extended((rir.read(RIR::STID) << 18) | rir.read(RIR::EXID))
+            } else {
+                can::Id::Standard(rir.read(RIR::STID) as u16)
+            };
+            self.rx_client
+                .map(|client| client.message_received(id, &data[..len]));
+        }
+        self.update_state();
+    }
+
+    pub fn handle_status_interrupt(&self) {
+        self.registers.msr.write(MSR::ERRI::SET);
+        self.update_state();
+    }
+
+    fn update_state(&self) {
+        let state = can::Can::error_state(self);
+        if state != self.state.get() {
+            self.state.set(state);
+            self.state_client.map(|client| client.state_changed(state));
+        }
+    }
+}
+
+impl<'a> can::Can<'a> for Can<'a> {
+    fn set_transmit_client(&self, client: &'a dyn can::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn can::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_state_client(&self, client: &'a dyn can::StateClient) {
+        self.state_client.set(client);
+    }
+
+    fn set_bit_timing(&self, timing: can::BitTiming) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EBUSY;
+        }
+        if timing.prescaler < 1
+            || timing.prescaler > 1024
+            || timing.segment1 < 1
+            || timing.segment1 > 16
+            || timing.segment2 < 1
+            || timing.segment2 > 8
+            || timing.sync_jump_width < 1
+            || timing.sync_jump_width > 4
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.timing.set(timing);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_bitrate(&self, bitrate: u32) -> ReturnCode {
+        match can::BitTiming::for_bitrate(PCLK1_HZ, bitrate) {
+            Some(timing) => self.set_bit_timing(timing),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn set_mode(&self, mode: can::Mode) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.mode.set(mode);
+        ReturnCode::SUCCESS
+    }
+
+    fn filter_count(&self) -> usize {
+        FILTER_BANKS
+    }
+
+    fn set_filter(&self, index: usize, filter: Option<can::Filter>) -> ReturnCode {
+        if index >= FILTER_BANKS || filter.map_or(false, |f| !f.id.is_valid()) {
+            return ReturnCode::EINVAL;
+        }
+        if !self.clock.is_enabled() {
+            self.clock.enable();
+        }
+        let bit = 1 << index;
+        let regs = &*self.registers;
+        regs.fmr.modify(FMR::FINIT::SET);
+        regs.fa1r.set(regs.fa1r.get() & !bit);
+        if let Some(filter) = filter {
+            // 32-bit identifier/mask bank feeding FIFO 0. The IDE bit is
+            // always compared so standard and extended filters stay apart.
+            regs.fs1r.set(regs.fs1r.get() | bit);
+            regs.fm1r.set(regs.fm1r.get() & !bit);
+            regs.ffa1r.set(regs.ffa1r.get() & !bit);
+            let mask = match filter.id {
+                can::Id::Standard(_) => can::Id::Standard((filter.mask & 0x7ff) as u16),
+                can::Id::Extended(_) => can::Id::Extended(filter.mask & 0x1fff_ffff),
+            };
+            regs.filters[index].fr1.set(id_register(filter.id));
+            regs.filters[index].fr2.set(id_register(mask) | (1 << 2));
+            regs.fa1r.set(regs.fa1r.get() | bit);
+        }
+        regs.fmr.modify(FMR::FINIT::CLEAR);
+        ReturnCode::SUCCESS
+    }
+
+    fn enable(&self) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.clock.enable();
+        self.registers.mcr.modify(MCR::SLEEP::CLEAR);
+        if !self.request_init(true) {
+            return ReturnCode::FAIL;
+        }
+
+        let timing = self.timing.get();
+        let (silent, loopback) = match self.mode.get() {
+            can::Mode::Normal => (0, 0),
+            can::Mode::Loopback => (0, 1),
+            can::Mode::ListenOnly => (1, 0),
+        };
+        self.registers.btr.write(
+            BTR::BRP.val(timing.prescaler as u32 - 1)
+                + BTR::TS1.val(timing.segment1 as u32 - 1)
+                + BTR::TS2.val(timing.segment2 as u32 - 1)
+                + BTR::SJW.val(timing.sync_jump_width as u32 - 1)
+                + BTR::SILM.val(silent)
+                + BTR::LBKM.val(loopback),
+        );
+        self.registers
+            .mcr
+            .modify(MCR::ABOM::SET + MCR::NART::CLEAR + MCR::TTCM::CLEAR);
+        self.registers.ier.write(
+            IER::TMEIE::SET
+                + IER::FMPIE0::SET
+                + IER::FOVIE0::SET
+                + IER::ERRIE::SET
+                + IER::EPVIE::SET
+                + IER::BOFIE::SET,
+        );
+
+        if !self.request_init(false) {
+            return ReturnCode::FAIL;
+        }
+        self.enabled.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    fn disable(&self) -> ReturnCode {
+        if !self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.enabled.set(false);
+        if self.tx_buffer.is_some() {
+            // The buffer is returned from the transmit interrupt
+            self.registers.tsr.write(TSR::ABRQ0::SET);
+        }
+        if self.request_init(true) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        }
+    }
+
+    fn send(
+        &self,
+        id: can::Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.tx_buffer.is_some() || !self.registers.tsr.is_set(TSR::TME0) {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if !id.is_valid() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if len > can::MAX_DATA_LEN || len > buffer.len() {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+
+        let mut data = [0; can::MAX_DATA_LEN];
+        data[..len].copy_from_slice(&buffer[..len]);
+        let mailbox = &self.registers.tx[0];
+        mailbox.tdtr.write(TDTR::DLC.val(len as u32));
+        mailbox
+            .tdlr
+            .set(u32::from_le_bytes([data[0], data[1], data[2], data[3]]));
+        mailbox
+            .tdhr
+            .set(u32::from_le_bytes([data[4], data[5], data[6], data[7]]));
+        mailbox.tir.set(id_register(id) | 1);
+        self.tx_buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn error_state(&self) -> can::ErrorState {
+        let esr = self.registers.esr.extract();
+        if esr.is_set(ESR::BOFF) {
+            can::ErrorState::BusOff
+        } else if esr.is_set(ESR::EPVF) {
+            can::ErrorState::Passive
+        } else {
+            can::ErrorState::Active
+        }
+    }
+
+    fn error_counters(&self) -> (u8, u8) {
+        let esr = self.registers.esr.extract();
+        (esr.read(ESR::TEC) as u8, esr.read(ESR::REC) as u8)
+    }
+}
+
+struct CanClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for CanClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
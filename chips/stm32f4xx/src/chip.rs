@@ -20,9 +20,11 @@ pub struct Stm32f4xx<'a, I: InterruptService<DeferredCallTask> + 'a> {
 
 pub struct Stm32f4xxDefaultPeripherals<'a> {
     pub adc1: crate::adc::Adc<'a>,
+    pub can1: crate::can::Can<'a>,
     pub dma_streams: [crate::dma1::Stream<'a>; 8],
     pub exti: &'a crate::exti::Exti<'a>,
     pub i2c1: crate::i2c::I2C<'a>,
+    pub qspi: crate::qspi::Qspi<'a>,
     pub spi3: crate::spi::Spi<'a>,
     pub tim2: crate::tim2::Tim2<'a>,
     pub usart2: crate::usart::Usart<'a>,
@@ -39,9 +41,11 @@ impl<'a> Stm32f4xxDefaultPeripherals<'a> {
     ) -> Self {
         Self {
             adc1: crate::adc::Adc::new(rcc),
+            can1: crate::can::Can::new(rcc),
             dma_streams: crate::dma1::new_dma1_stream(dma),
             exti,
             i2c1: crate::i2c::I2C::new(rcc),
+            qspi: crate::qspi::Qspi::new(rcc),
             spi3: crate::spi::Spi::new(
                 crate::spi::SPI3_BASE,
                 crate::spi::SpiClock(crate::rcc::PeripheralClock::new(
@@ -99,9 +103,15 @@ impl<'a> InterruptService<DeferredCallTask> for Stm32f4xxDefaultPeripherals<'a>
 
             nvic::ADC => self.adc1.handle_interrupt(),
 
+            nvic::CAN1_TX => self.can1.handle_transmit_interrupt(),
+            nvic::CAN1_RX0 => self.can1.handle_fifo0_interrupt(),
+            nvic::CAN1_SCE => self.can1.handle_status_interrupt(),
+
             nvic::I2C1_EV => self.i2c1.handle_event(),
             nvic::I2C1_ER => self.i2c1.handle_error(),
 
+            nvic::QUADSPI => self.qspi.handle_interrupt(),
+
             nvic::SPI3 => self.spi3.handle_interrupt(),
 
             nvic::EXTI0 => self.exti.handle_interrupt(),
@@ -1,7 +1,10 @@
+use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
+use kernel::hil::dma;
 use kernel::ClockInterface;
+use kernel::ReturnCode;
 
 use crate::nvic;
 use crate::rcc;
@@ -749,6 +752,7 @@ enum Direction {
 
 /// DMA data size. Section 9.5.5
 #[allow(dead_code)]
+#[derive(Copy, Clone)]
 #[repr(u32)]
 enum Size {
     Byte = 0b00,
@@ -825,6 +829,15 @@ pub struct Stream<'a> {
     buffer: TakeCell<'static, [u8]>,
     peripheral: OptionalCell<Dma1Peripheral>,
     dma1: &'a Dma1<'a>,
+    // State used when the stream is driven through `hil::dma`
+    dma_client: OptionalCell<&'a dyn dma::DmaClient>,
+    width: Cell<dma::Width>,
+    len: Cell<usize>,
+    queued: TakeCell<'static, [u8]>,
+    queued_len: Cell<usize>,
+    half_complete: Cell<bool>,
+    list: TakeCell<'static, [dma::Segment]>,
+    list_index: Cell<usize>,
 }
 
 pub fn new_dma1_stream<'a>(dma: &'a Dma1) -> [Stream<'a>; 8] {
@@ -840,6 +853,13 @@ pub fn new_dma1_stream<'a>(dma: &'a Dma1) -> [Stream<'a>; 8] {
     ]
 }
 
+fn segment_address(list: &[dma::Segment], index: usize) -> u32 {
+    list[index]
+        .buf
+        .as_ref()
+        .map_or(0, |buf| buf.as_ptr() as u32)
+}
+
 pub trait StreamClient {
     fn transfer_done(&self, pid: Dma1Peripheral);
 }
@@ -852,6 +872,14 @@ impl<'a> Stream<'a> {
             client: OptionalCell::empty(),
             peripheral: OptionalCell::empty(),
             dma1,
+            dma_client: OptionalCell::empty(),
+            width: Cell::new(dma::Width::Bits8),
+            len: Cell::new(0),
+            queued: TakeCell::empty(),
+            queued_len: Cell::new(0),
+            half_complete: Cell::new(false),
+            list: TakeCell::empty(),
+            list_index: Cell::new(0),
         }
     }
 
@@ -860,8 +888,35 @@ impl<'a> Stream<'a> {
     }
 
     pub fn handle_interrupt(&self) {
+        if self.list.is_some() {
+            self.handle_list_interrupt();
+            return;
+        }
+
+        if self.half_complete.get() && self.half_transfer_flag() {
+            self.clear_half_transfer_flag();
+            self.dma_client.map(|client| client.half_complete());
+            if !self.transfer_complete_flag() {
+                return;
+            }
+        }
+
         self.clear_transfer_complete_flag();
 
+        if self.dma_client.is_some() {
+            let buf = self.buffer.take();
+            let len = self.len.get();
+            // Keep the peripheral busy before handing back the finished buffer
+            self.queued.take().map(|next| {
+                self.len.set(self.queued_len.get());
+                self.do_transfer(next, self.queued_len.get());
+            });
+            self.dma_client.map(|client| {
+                buf.map(|buf| client.transfer_done(buf, len, ReturnCode::SUCCESS));
+            });
+            return;
+        }
+
         self.client.map(|client| {
             self.peripheral.map(|pid| {
                 client.transfer_done(*pid);
@@ -901,13 +956,14 @@ impl<'a> Stream<'a> {
         // 3
         self.set_memory_address(&buf[0] as *const u8 as u32);
         // 4
-        self.set_data_items(len as u32);
+        self.set_data_items((len / self.width.get().bytes()) as u32);
         // 5
         self.set_channel();
         // 9
         self.set_direction();
         self.set_peripheral_address_increment();
         self.set_memory_address_increment();
+        self.set_half_transfer_interrupt(self.half_complete.get());
         self.interrupt_enable();
         // 10
         self.enable();
@@ -928,6 +984,116 @@ impl<'a> Stream<'a> {
         self.buffer.take()
     }
 
+    fn is_transferring(&self) -> bool {
+        self.buffer.is_some() || self.list.is_some()
+    }
+
+    /// Start a `transfer_list` transfer. The stream runs in double-buffer
+    /// mode with the first two segments in M0AR and M1AR. Whenever one of
+    /// them completes, the stream switches to the other target on its own
+    /// and the freed address register is loaded with the next segment.
+    fn start_list(&self, list: &'static mut [dma::Segment]) {
+        self.disable_interrupt();
+        self.disable();
+        self.clear_transfer_complete_flag();
+        self.set_peripheral_address();
+        self.set_memory_address(segment_address(list, 0));
+        if list.len() > 1 {
+            self.set_memory1_address(segment_address(list, 1));
+        }
+        self.set_double_buffer(list.len() > 1);
+        // Double-buffer mode reloads NDTR for each target, which is why
+        // every segment has the same length
+        self.set_data_items((list[0].len / self.width.get().bytes()) as u32);
+        self.set_channel();
+        self.set_direction();
+        self.set_peripheral_address_increment();
+        self.set_memory_address_increment();
+        self.set_half_transfer_interrupt(false);
+        self.interrupt_enable();
+        self.list_index.set(0);
+        self.list.replace(list);
+        self.enable();
+    }
+
+    fn handle_list_interrupt(&self) {
+        // Lists run with the half transfer interrupt disabled
+        self.clear_transfer_complete_flag();
+
+        let index = self.list_index.get();
+        let (last, len) = self
+            .list
+            .map_or((0, 0), |list| (list.len() - 1, list[0].len));
+        if index == last {
+            self.disable_interrupt();
+            self.disable();
+            while self.is_enabled() {}
+            self.set_double_buffer(false);
+            self.list.take().map(|list| {
+                self.dma_client.map(move |client| {
+                    client.segment_done(index, len);
+                    client.list_done(list, ReturnCode::SUCCESS);
+                });
+            });
+            return;
+        }
+
+        // The stream has already moved on to segment `index + 1`
+        let next = index + 1;
+        self.list_index.set(next);
+        if next < last {
+            let addr = self.list.map_or(0, |list| segment_address(list, next + 1));
+            if self.current_target() == 0 {
+                self.set_memory1_address(addr);
+            } else {
+                self.set_memory_address(addr);
+            }
+        } else {
+            self.finish_double_buffer(next);
+        }
+        self.dma_client
+            .map(|client| client.segment_done(index, len));
+    }
+
+    /// Leave double-buffer mode while the last segment is running, so the
+    /// stream stops at its end instead of going on with the other target.
+    ///
+    /// DBM can only be cleared with the stream disabled, so the rest of the
+    /// last segment is restarted from where the stream stopped.
+    fn finish_double_buffer(&self, last: usize) {
+        let target = self.current_target();
+        self.disable();
+        while self.is_enabled() {}
+        if self.current_target() != target {
+            // The last segment completed before the stream stopped. Leave
+            // the TC flag that disabling set, so the interrupt fires again
+            // and ends the list.
+            return;
+        }
+        let remaining = self.get_data_items();
+        let moved = self.list.map_or(0, |list| list[last].len)
+            - remaining as usize * self.width.get().bytes();
+        self.clear_transfer_complete_flag();
+        self.set_double_buffer(false);
+        self.set_memory_address(
+            self.list.map_or(0, |list| segment_address(list, last)) + moved as u32,
+        );
+        self.set_data_items(remaining);
+        self.enable();
+    }
+
+    /// Whether the first `len` bytes of `buf` can be transferred with the
+    /// current width. The stream counts items in a 16 bit register, and
+    /// accesses memory at addresses aligned to the width.
+    fn valid_length(&self, buf: &[u8], len: usize) -> bool {
+        let bytes = self.width.get().bytes();
+        len != 0
+            && len <= buf.len()
+            && len % bytes == 0
+            && len / bytes <= u16::MAX as usize
+            && buf.as_ptr() as usize % bytes == 0
+    }
+
     fn set_channel(&self) {
         self.peripheral.map(|pid| {
             match pid {
@@ -1210,6 +1376,82 @@ impl<'a> Stream<'a> {
         }
     }
 
+    fn set_memory1_address(&self, buf_addr: u32) {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.s0m1ar.set(buf_addr),
+            StreamId::Stream1 => self.dma1.registers.s1m1ar.set(buf_addr),
+            StreamId::Stream2 => self.dma1.registers.s2m1ar.set(buf_addr),
+            StreamId::Stream3 => self.dma1.registers.s3m1ar.set(buf_addr),
+            StreamId::Stream4 => self.dma1.registers.s4m1ar.set(buf_addr),
+            StreamId::Stream5 => self.dma1.registers.s5m1ar.set(buf_addr),
+            StreamId::Stream6 => self.dma1.registers.s6m1ar.set(buf_addr),
+            StreamId::Stream7 => self.dma1.registers.s7m1ar.set(buf_addr),
+        }
+    }
+
+    // DBM and CT can only be written while the stream is disabled. Starting
+    // with CT cleared makes M0AR the first target.
+    fn set_double_buffer(&self, enable: bool) {
+        let dbm = if enable { 1 } else { 0 };
+        match self.streamid {
+            StreamId::Stream0 => self
+                .dma1
+                .registers
+                .s0cr
+                .modify(S0CR::DBM.val(dbm) + S0CR::CT::CLEAR),
+            StreamId::Stream1 => self
+                .dma1
+                .registers
+                .s1cr
+                .modify(S1CR::DBM.val(dbm) + S1CR::CT::CLEAR),
+            StreamId::Stream2 => self
+                .dma1
+                .registers
+                .s2cr
+                .modify(S2CR::DBM.val(dbm) + S2CR::CT::CLEAR),
+            StreamId::Stream3 => self
+                .dma1
+                .registers
+                .s3cr
+                .modify(S3CR::DBM.val(dbm) + S3CR::CT::CLEAR),
+            StreamId::Stream4 => self
+                .dma1
+                .registers
+                .s4cr
+                .modify(S4CR::DBM.val(dbm) + S4CR::CT::CLEAR),
+            StreamId::Stream5 => self
+                .dma1
+                .registers
+                .s5cr
+                .modify(S5CR::DBM.val(dbm) + S5CR::CT::CLEAR),
+            StreamId::Stream6 => self
+                .dma1
+                .registers
+                .s6cr
+                .modify(S6CR::DBM.val(dbm) + S6CR::CT::CLEAR),
+            StreamId::Stream7 => self
+                .dma1
+                .registers
+                .s7cr
+                .modify(S7CR::DBM.val(dbm) + S7CR::CT::CLEAR),
+        }
+    }
+
+    // The memory target the stream is working on in double-buffer mode: 0
+    // for M0AR, 1 for M1AR
+    fn current_target(&self) -> u32 {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.s0cr.read(S0CR::CT),
+            StreamId::Stream1 => self.dma1.registers.s1cr.read(S1CR::CT),
+            StreamId::Stream2 => self.dma1.registers.s2cr.read(S2CR::CT),
+            StreamId::Stream3 => self.dma1.registers.s3cr.read(S3CR::CT),
+            StreamId::Stream4 => self.dma1.registers.s4cr.read(S4CR::CT),
+            StreamId::Stream5 => self.dma1.registers.s5cr.read(S5CR::CT),
+            StreamId::Stream6 => self.dma1.registers.s6cr.read(S6CR::CT),
+            StreamId::Stream7 => self.dma1.registers.s7cr.read(S7CR::CT),
+        }
+    }
+
     fn set_data_width_for_peripheral(&self) {
         self.peripheral.map(|pid| match pid {
             Dma1Peripheral::SPI3_TX => {
@@ -1418,6 +1660,59 @@ impl<'a> Stream<'a> {
         }
     }
 
+    fn set_half_transfer_interrupt(&self, enable: bool) {
+        let htie = if enable { 1 } else { 0 };
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.s0cr.modify(S0CR::HTIE.val(htie)),
+            StreamId::Stream1 => self.dma1.registers.s1cr.modify(S1CR::HTIE.val(htie)),
+            StreamId::Stream2 => self.dma1.registers.s2cr.modify(S2CR::HTIE.val(htie)),
+            StreamId::Stream3 => self.dma1.registers.s3cr.modify(S3CR::HTIE.val(htie)),
+            StreamId::Stream4 => self.dma1.registers.s4cr.modify(S4CR::HTIE.val(htie)),
+            StreamId::Stream5 => self.dma1.registers.s5cr.modify(S5CR::HTIE.val(htie)),
+            StreamId::Stream6 => self.dma1.registers.s6cr.modify(S6CR::HTIE.val(htie)),
+            StreamId::Stream7 => self.dma1.registers.s7cr.modify(S7CR::HTIE.val(htie)),
+        }
+    }
+
+    fn half_transfer_flag(&self) -> bool {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.lisr.is_set(LISR::HTIF0),
+            StreamId::Stream1 => self.dma1.registers.lisr.is_set(LISR::HTIF1),
+            StreamId::Stream2 => self.dma1.registers.lisr.is_set(LISR::HTIF2),
+            StreamId::Stream3 => self.dma1.registers.lisr.is_set(LISR::HTIF3),
+            StreamId::Stream4 => self.dma1.registers.hisr.is_set(HISR::HTIF4),
+            StreamId::Stream5 => self.dma1.registers.hisr.is_set(HISR::HTIF5),
+            StreamId::Stream6 => self.dma1.registers.hisr.is_set(HISR::HTIF6),
+            StreamId::Stream7 => self.dma1.registers.hisr.is_set(HISR::HTIF7),
+        }
+    }
+
+    fn transfer_complete_flag(&self) -> bool {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.lisr.is_set(LISR::TCIF0),
+            StreamId::Stream1 => self.dma1.registers.lisr.is_set(LISR::TCIF1),
+            StreamId::Stream2 => self.dma1.registers.lisr.is_set(LISR::TCIF2),
+            StreamId::Stream3 => self.dma1.registers.lisr.is_set(LISR::TCIF3),
+            StreamId::Stream4 => self.dma1.registers.hisr.is_set(HISR::TCIF4),
+            StreamId::Stream5 => self.dma1.registers.hisr.is_set(HISR::TCIF5),
+            StreamId::Stream6 => self.dma1.registers.hisr.is_set(HISR::TCIF6),
+            StreamId::Stream7 => self.dma1.registers.hisr.is_set(HISR::TCIF7),
+        }
+    }
+
+    fn clear_half_transfer_flag(&self) {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.lifcr.write(LIFCR::CHTIF0::SET),
+            StreamId::Stream1 => self.dma1.registers.lifcr.write(LIFCR::CHTIF1::SET),
+            StreamId::Stream2 => self.dma1.registers.lifcr.write(LIFCR::CHTIF2::SET),
+            StreamId::Stream3 => self.dma1.registers.lifcr.write(LIFCR::CHTIF3::SET),
+            StreamId::Stream4 => self.dma1.registers.hifcr.write(HIFCR::CHTIF4::SET),
+            StreamId::Stream5 => self.dma1.registers.hifcr.write(HIFCR::CHTIF5::SET),
+            StreamId::Stream6 => self.dma1.registers.hifcr.write(HIFCR::CHTIF6::SET),
+            StreamId::Stream7 => self.dma1.registers.hifcr.write(HIFCR::CHTIF7::SET),
+        }
+    }
+
     fn enable(&self) {
         match self.streamid {
             StreamId::Stream0 => self.dma1.registers.s0cr.modify(S0CR::EN::SET),
@@ -1444,6 +1739,21 @@ impl<'a> Stream<'a> {
         }
     }
 
+    // EN stays set after it is cleared until the current item has been
+    // transferred
+    fn is_enabled(&self) -> bool {
+        match self.streamid {
+            StreamId::Stream0 => self.dma1.registers.s0cr.is_set(S0CR::EN),
+            StreamId::Stream1 => self.dma1.registers.s1cr.is_set(S1CR::EN),
+            StreamId::Stream2 => self.dma1.registers.s2cr.is_set(S2CR::EN),
+            StreamId::Stream3 => self.dma1.registers.s3cr.is_set(S3CR::EN),
+            StreamId::Stream4 => self.dma1.registers.s4cr.is_set(S4CR::EN),
+            StreamId::Stream5 => self.dma1.registers.s5cr.is_set(S5CR::EN),
+            StreamId::Stream6 => self.dma1.registers.s6cr.is_set(S6CR::EN),
+            StreamId::Stream7 => self.dma1.registers.s7cr.is_set(S7CR::EN),
+        }
+    }
+
     fn clear_transfer_complete_flag(&self) {
         match self.streamid {
             StreamId::Stream0 => {
@@ -1502,6 +1812,190 @@ impl<'a> Stream<'a> {
     }
 }
 
+impl<'a> dma::DmaChannel<'a> for Stream<'a> {
+    fn set_client(&self, client: &'a dyn dma::DmaClient) {
+        self.dma_client.set(client);
+    }
+
+    fn set_width(&self, width: dma::Width) -> ReturnCode {
+        if self.is_transferring() {
+            return ReturnCode::EBUSY;
+        }
+        // The data registers of the USART and SPI peripherals are 16 bits
+        // wide
+        let size = match width {
+            dma::Width::Bits8 => Size::Byte,
+            dma::Width::Bits16 => Size::HalfWord,
+            dma::Width::Bits32 => return ReturnCode::ENOSUPPORT,
+        };
+        if width != self.width.get() {
+            // The memory and peripheral sides use the same width, so the
+            // FIFO does no packing
+            self.stream_set_data_width(Msize(size), Psize(size));
+            self.width.set(width);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn set_half_complete(&self, enable: bool) -> ReturnCode {
+        if self.is_transferring() {
+            return ReturnCode::EBUSY;
+        }
+        self.half_complete.set(enable);
+        ReturnCode::SUCCESS
+    }
+
+    fn transfer(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.peripheral.is_none() {
+            return Err((ReturnCode::EOFF, buf));
+        }
+        if self.is_transferring() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        if !self.valid_length(buf, len) {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        self.len.set(len);
+        self.do_transfer(buf, len);
+        Ok(())
+    }
+
+    fn queue(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.buffer.is_none() {
+            return self.transfer(buf, len);
+        }
+        if self.queued.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        if !self.valid_length(buf, len) {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        // DMA1 streams are restarted with the queued buffer from the
+        // transfer complete interrupt.
+        self.queued_len.set(len);
+        self.queued.replace(buf);
+        Ok(())
+    }
+
+    fn transfer_list(
+        &self,
+        list: &'static mut [dma::Segment],
+    ) -> Result<(), (ReturnCode, &'static mut [dma::Segment])> {
+        if self.peripheral.is_none() {
+            return Err((ReturnCode::EOFF, list));
+        }
+        if self.is_transferring() {
+            return Err((ReturnCode::EBUSY, list));
+        }
+        let valid = !list.is_empty()
+            && list.iter().all(|segment| {
+                segment.len == list[0].len
+                    && segment
+                        .buf
+                        .as_ref()
+                        .map_or(false, |buf| self.valid_length(buf, segment.len))
+            });
+        if !valid {
+            return Err((ReturnCode::EINVAL, list));
+        }
+        self.start_list(list);
+        Ok(())
+    }
+
+    fn copy(
+        &self,
+        src: &'static mut [u8],
+        dst: &'static mut [u8],
+        _len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        // Only DMA2 can transfer from memory to memory, so no DMA1 stream is
+        // ever allocated for copies
+        Err((ReturnCode::ENOSUPPORT, src, dst))
+    }
+
+    fn abort(&self) -> (usize, Option<&'static mut [u8]>, Option<&'static mut [u8]>) {
+        let queued = self.queued.take();
+        if self.buffer.is_none() {
+            return (0, None, queued);
+        }
+        self.set_half_transfer_interrupt(false);
+        let (buf, remaining) = self.abort_transfer();
+        let remaining = remaining as usize * self.width.get().bytes();
+        (self.len.get() - remaining, buf, queued)
+    }
+
+    fn abort_list(&self) -> Option<(usize, &'static mut [dma::Segment])> {
+        let list = self.list.take()?;
+        self.disable_interrupt();
+        self.disable();
+        while self.is_enabled() {}
+        self.set_double_buffer(false);
+        Some((self.list_index.get(), list))
+    }
+
+    fn is_busy(&self) -> bool {
+        self.is_transferring()
+    }
+}
+
+/// Allocates DMA1 streams through `hil::dma`.
+///
+/// Each `Dma1Peripheral` is served by a fixed stream, so a request can only
+/// be allocated if no driver has already called `setup` on that stream. The
+/// board still has to enable the DMA1 clock and the stream interrupts.
+/// Streams transfer 8 or 16 bit items. Lists are chained in double-buffer
+/// mode, so all segments of a list must be the same length. Memory-to-memory
+/// copies are not available, as only DMA2 can perform them.
+pub struct StreamAllocator<'a> {
+    streams: &'a [Stream<'a>; 8],
+}
+
+impl<'a> StreamAllocator<'a> {
+    pub const fn new(streams: &'a [Stream<'a>; 8]) -> Self {
+        Self { streams }
+    }
+}
+
+impl<'a> dma::DmaController<'a> for StreamAllocator<'a> {
+    type Request = Dma1Peripheral;
+    type Channel = Stream<'a>;
+
+    fn allocate(&'a self, request: Dma1Peripheral) -> Result<&'a Stream<'a>, ReturnCode> {
+        let stream = &self.streams[request.get_stream_idx()];
+        if stream.peripheral.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        stream.setup(request);
+        Ok(stream)
+    }
+
+    fn allocate_memory(&'a self) -> Result<&'a Stream<'a>, ReturnCode> {
+        Err(ReturnCode::ENOSUPPORT)
+    }
+
+    fn release(&self, stream: &Stream<'a>) -> ReturnCode {
+        if stream.peripheral.is_none() {
+            return ReturnCode::EALREADY;
+        }
+        if stream.is_transferring() {
+            return ReturnCode::EBUSY;
+        }
+        dma::DmaChannel::set_width(stream, dma::Width::Bits8);
+        stream.peripheral.clear();
+        stream.dma_client.clear();
+        stream.half_complete.set(false);
+        ReturnCode::SUCCESS
+    }
+}
+
 pub struct Dma1<'a> {
     registers: StaticRef<Dma1Registers>,
     clock: Dma1Clock<'a>,
@@ -13,6 +13,7 @@ pub mod nvic;
 
 // Peripherals
 pub mod adc;
+pub mod can;
 pub mod dbg;
 pub mod deferred_calls;
 pub mod dma1;
@@ -20,6 +21,7 @@ pub mod exti;
 pub mod fsmc;
 pub mod gpio;
 pub mod i2c;
+pub mod qspi;
 pub mod rcc;
 pub mod spi;
 pub mod syscfg;
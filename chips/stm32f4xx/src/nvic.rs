@@ -82,3 +82,4 @@ pub const DCMI: u32 = 78;
 pub const FPU: u32 = 81;
 pub const SPI4: u32 = 84;
 pub const SAI1: u32 = 87;
+pub const QUADSPI: u32 = 92;
@@ -0,0 +1,454 @@
+//! QUADSPI controller
+//!
+//! Commands run in indirect mode, with the data moved through the 32-byte
+//! FIFO from the interrupt handler one byte at a time. In memory-mapped mode
+//! the flash appears at `0x90000000`. The controller can not run indirect
+//! commands while mapped, so it aborts memory-mapped mode for each command
+//! and enters it again afterwards.
+//!
+//! The pins have to be set to the QUADSPI alternate function by the board.
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{register_bitfields, register_structs, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::qspi::{self, AddressSize, Command, MappedRegion, Mode};
+use kernel::ClockInterface;
+use kernel::ReturnCode;
+
+use crate::rcc;
+
+register_structs! {
+    QspiRegisters {
+        /// control register
+        (0x00 => cr: ReadWrite<u32, CR::Register>),
+        /// device configuration register
+        (0x04 => dcr: ReadWrite<u32, DCR::Register>),
+        /// status register
+        (0x08 => sr: ReadWrite<u32, SR::Register>),
+        /// flag clear register
+        (0x0C => fcr: WriteOnly<u32, FCR::Register>),
+        /// data length register
+        (0x10 => dlr: ReadWrite<u32>),
+        /// communication configuration register
+        (0x14 => ccr: ReadWrite<u32, CCR::Register>),
+        /// address register
+        (0x18 => ar: ReadWrite<u32>),
+        /// alternate bytes register
+        (0x1C => abr: ReadWrite<u32>),
+        /// data register, accessed one byte at a time
+        (0x20 => dr: ReadWrite<u8>),
+        (0x21 => _reserved0),
+        /// polling status mask register
+        (0x24 => psmkr: ReadWrite<u32>),
+        /// polling status match register
+        (0x28 => psmar: ReadWrite<u32>),
+        /// polling interval register
+        (0x2C => pir: ReadWrite<u32>),
+        /// low-power timeout register
+        (0x30 => lptr: ReadWrite<u32>),
+        (0x34 => @END),
+    }
+}
+
+register_bitfields![u32,
+    CR [
+        /// Clock prescaler
+        PRESCALER OFFSET(24) NUMBITS(8) [],
+        /// FIFO threshold interrupt enable
+        FTIE OFFSET(18) NUMBITS(1) [],
+        /// Transfer complete interrupt enable
+        TCIE OFFSET(17) NUMBITS(1) [],
+        /// Transfer error interrupt enable
+        TEIE OFFSET(16) NUMBITS(1) [],
+        /// FIFO threshold level
+        FTHRES OFFSET(8) NUMBITS(5) [],
+        /// Sample shift
+        SSHIFT OFFSET(4) NUMBITS(1) [],
+        /// Abort request
+        ABORT OFFSET(1) NUMBITS(1) [],
+        /// Enable
+        EN OFFSET(0) NUMBITS(1) []
+    ],
+    DCR [
+        /// Flash memory size
+        FSIZE OFFSET(16) NUMBITS(5) [],
+        /// Chip select high time
+        CSHT OFFSET(8) NUMBITS(3) [],
+        /// Mode 0 / mode 3
+        CKMODE OFFSET(0) NUMBITS(1) []
+    ],
+    SR [
+        /// FIFO level
+        FLEVEL OFFSET(8) NUMBITS(6) [],
+        /// Busy
+        BUSY OFFSET(5) NUMBITS(1) [],
+        /// FIFO threshold flag
+        FTF OFFSET(2) NUMBITS(1) [],
+        /// Transfer complete flag
+        TCF OFFSET(1) NUMBITS(1) [],
+        /// Transfer error flag
+        TEF OFFSET(0) NUMBITS(1) []
+    ],
+    FCR [
+        /// Clear timeout flag
+        CTOF OFFSET(4) NUMBITS(1) [],
+        /// Clear status match flag
+        CSMF OFFSET(3) NUMBITS(1) [],
+        /// Clear transfer complete flag
+        CTCF OFFSET(1) NUMBITS(1) [],
+        /// Clear transfer error flag
+        CTEF OFFSET(0) NUMBITS(1) []
+    ],
+    CCR [
+        /// Functional mode
+        FMODE OFFSET(26) NUMBITS(2) [
+            IndirectWrite = 0b00,
+            IndirectRead = 0b01,
+            AutoPolling = 0b10,
+            MemoryMapped = 0b11
+        ],
+        /// Data mode
+        DMODE OFFSET(24) NUMBITS(2) [],
+        /// Number of dummy cycles
+        DCYC OFFSET(18) NUMBITS(5) [],
+        /// Address size
+        ADSIZE OFFSET(12) NUMBITS(2) [
+            Bits8 = 0b00,
+            Bits16 = 0b01,
+            Bits24 = 0b10,
+            Bits32 = 0b11
+        ],
+        /// Address mode
+        ADMODE OFFSET(10) NUMBITS(2) [],
+        /// Instruction mode
+        IMODE OFFSET(8) NUMBITS(2) [],
+        /// Instruction
+        INSTRUCTION OFFSET(0) NUMBITS(8) []
+    ]
+];
+
+const QSPI_BASE: StaticRef<QspiRegisters> =
+    unsafe { StaticRef::new(0xA0001000 as *const QspiRegisters) };
+
+/// Where memory-mapped mode maps the flash.
+const MAPPED_BASE: usize = 0x90000000;
+
+/// Bytes the FIFO holds.
+const FIFO_LEN: u32 = 32;
+
+// HCLK runs at 16MHz from the HSI.
+const HCLK_HZ: u32 = 16_000_000;
+
+/// `IMODE`, `ADMODE` and `DMODE` values for `mode`, 1, 2 or 3 for one, two
+/// or four lines.
+fn lines(mode: Mode) -> (u32, u32, u32) {
+    match mode {
+        Mode::Single => (1, 1, 1),
+        Mode::DualOutput => (1, 1, 2),
+        Mode::DualIo => (1, 2, 2),
+        Mode::QuadOutput => (1, 1, 3),
+        Mode::QuadIo => (1, 3, 3),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Operation {
+    Idle,
+    Command,
+    Read,
+    Write,
+}
+
+pub struct Qspi<'a> {
+    registers: StaticRef<QspiRegisters>,
+    clock: QspiClock<'a>,
+    client: OptionalCell<&'a dyn qspi::Client>,
+    buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Operation>,
+    index: Cell<usize>,
+    len: Cell<usize>,
+    address_size: Cell<AddressSize>,
+    size: Cell<usize>,
+    /// Read command of the mapped region
+    mapped: Cell<Option<Command>>,
+}
+
+impl<'a> Qspi<'a> {
+    pub const fn new(rcc: &'a rcc::Rcc) -> Qspi<'a> {
+        Qspi {
+            registers: QSPI_BASE,
+            clock: QspiClock(rcc::PeripheralClock::new(
+                rcc::PeripheralClockType::AHB3(rcc::HCLK3::QSPI),
+                rcc,
+            )),
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            operation: Cell::new(Operation::Idle),
+            index: Cell::new(0),
+            len: Cell::new(0),
+            address_size: Cell::new(AddressSize::Bits24),
+            size: Cell::new(0),
+            mapped: Cell::new(None),
+        }
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+        self.transfer();
+
+        let rc = if regs.sr.is_set(SR::TEF) {
+            ReturnCode::FAIL
+        } else if regs.sr.is_set(SR::TCF) {
+            // The last bytes may still be in the FIFO.
+            self.transfer();
+            ReturnCode::SUCCESS
+        } else {
+            return;
+        };
+        regs.fcr.write(FCR::CTEF::SET + FCR::CTCF::SET);
+        regs.cr
+            .modify(CR::TEIE::CLEAR + CR::TCIE::CLEAR + CR::FTIE::CLEAR);
+
+        let operation = self.operation.replace(Operation::Idle);
+        if let Some(read) = self.mapped.get() {
+            self.enter_mapped(&read);
+        }
+        if operation != Operation::Idle {
+            let buffer = self.buffer.take();
+            self.client
+                .map(move |client| client.command_done(buffer, rc));
+        }
+    }
+
+    /// Move data between the buffer and the FIFO.
+    fn transfer(&self) {
+        let regs = &*self.registers;
+        let len = self.len.get();
+        let mut index = self.index.get();
+        match self.operation.get() {
+            Operation::Read => self.buffer.map(|buffer| {
+                while index < len && regs.sr.read(SR::FLEVEL) > 0 {
+                    buffer[index] = regs.dr.get();
+                    index += 1;
+                }
+            }),
+            Operation::Write => self.buffer.map(|buffer| {
+                while index < len && regs.sr.read(SR::FLEVEL) < FIFO_LEN {
+                    regs.dr.set(buffer[index]);
+                    index += 1;
+                }
+                if index == len {
+                    regs.cr.modify(CR::FTIE::CLEAR);
+                }
+            }),
+            Operation::Idle | Operation::Command => None,
+        };
+        self.index.set(index);
+    }
+
+    /// The `CCR` value to run `command` with, moving `len` bytes of data.
+    fn ccr(&self, command: &Command, len: usize) -> Result<u32, ReturnCode> {
+        if command.dummy_cycles > 31 {
+            return Err(ReturnCode::ENOSUPPORT);
+        }
+        let (imode, admode, dmode) = lines(command.mode);
+        let adsize = match self.address_size.get() {
+            AddressSize::Bits24 => CCR::ADSIZE::Bits24,
+            AddressSize::Bits32 => CCR::ADSIZE::Bits32,
+        };
+        let value = CCR::INSTRUCTION.val(command.instruction as u32)
+            + CCR::IMODE.val(imode)
+            + CCR::ADMODE.val(if command.address.is_some() { admode } else { 0 })
+            + adsize
+            + CCR::DCYC.val(command.dummy_cycles as u32)
+            + CCR::DMODE.val(if len > 0 { dmode } else { 0 });
+        Ok(value.value)
+    }
+
+    /// Leave memory-mapped mode so that indirect commands can run.
+    fn abort(&self) {
+        let regs = &*self.registers;
+        regs.cr.modify(CR::ABORT::SET);
+        while regs.cr.is_set(CR::ABORT) {}
+        regs.fcr.write(FCR::CTEF::SET + FCR::CTCF::SET);
+    }
+
+    fn enter_mapped(&self, read: &Command) {
+        let regs = &*self.registers;
+        if let Ok(ccr) = self.ccr(read, 1) {
+            regs.ccr.set(ccr | CCR::FMODE::MemoryMapped.value);
+        }
+    }
+
+    /// Run `command`, transferring `len` bytes of data in `operation`.
+    fn start(&self, command: &Command, operation: Operation, len: usize) -> ReturnCode {
+        if self.operation.get() != Operation::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let ccr = match self.ccr(command, len) {
+            Ok(ccr) => ccr,
+            Err(rc) => return rc,
+        };
+        if self.mapped.get().is_some() {
+            self.abort();
+        }
+
+        let regs = &*self.registers;
+        self.operation.set(operation);
+        self.index.set(0);
+        self.len.set(len);
+        if len > 0 {
+            regs.dlr.set(len as u32 - 1);
+        }
+        regs.cr.modify(CR::TEIE::SET + CR::TCIE::SET);
+        // The command starts with the last of these writes it needs.
+        let fmode = match operation {
+            Operation::Read => CCR::FMODE::IndirectRead,
+            _ => CCR::FMODE::IndirectWrite,
+        };
+        regs.ccr.set(ccr | fmode.value);
+        if let Some(address) = command.address {
+            regs.ar.set(address);
+        }
+        if len > 0 {
+            regs.cr.modify(CR::FTIE::SET);
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<'a> qspi::Qspi<'a> for Qspi<'a> {
+    fn set_client(&self, client: &'a dyn qspi::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(
+        &self,
+        rate: u32,
+        size: usize,
+        address_size: AddressSize,
+    ) -> Result<u32, ReturnCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        if !size.is_power_of_two() || size < 2 || size.trailing_zeros() > 32 {
+            return Err(ReturnCode::EINVAL);
+        }
+
+        let divider = ((HCLK_HZ + rate.max(1) - 1) / rate.max(1)).max(1).min(256);
+        self.enable_clock();
+        if self.mapped.get().is_some() {
+            self.abort();
+        }
+        let regs = &*self.registers;
+        regs.cr.modify(CR::EN::CLEAR);
+        regs.dcr.write(
+            DCR::FSIZE.val(size.trailing_zeros() - 1) + DCR::CSHT.val(1) + DCR::CKMODE::CLEAR,
+        );
+        regs.cr.write(
+            CR::PRESCALER.val(divider - 1) + CR::FTHRES.val(0) + CR::SSHIFT::SET + CR::EN::SET,
+        );
+        self.address_size.set(address_size);
+        self.size.set(size);
+        if let Some(read) = self.mapped.get() {
+            self.enter_mapped(&read);
+        }
+        Ok(HCLK_HZ / divider)
+    }
+
+    fn command(&self, command: Command) -> ReturnCode {
+        self.start(&command, Operation::Command, 0)
+    }
+
+    fn read(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len == 0 || len > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        match self.start(&command, Operation::Read, len) {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            rc => Err((rc, buffer)),
+        }
+    }
+
+    fn write(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len == 0 || len > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        match self.start(&command, Operation::Write, len) {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            rc => Err((rc, buffer)),
+        }
+    }
+
+    fn map(&self, read: Command) -> Result<MappedRegion, ReturnCode> {
+        if self.operation.get() != Operation::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.ccr(&read, 1)?;
+        if self.mapped.get().is_some() {
+            self.abort();
+        }
+        self.mapped.set(Some(read));
+        self.enter_mapped(&read);
+        Ok(MappedRegion {
+            address: MAPPED_BASE,
+            len: self.size.get(),
+        })
+    }
+
+    fn unmap(&self) -> ReturnCode {
+        if self.mapped.get().is_none() {
+            return ReturnCode::EALREADY;
+        }
+        if self.operation.get() == Operation::Idle {
+            self.abort();
+        }
+        self.mapped.set(None);
+        ReturnCode::SUCCESS
+    }
+}
+
+struct QspiClock<'a>(rcc::PeripheralClock<'a>);
+
+impl ClockInterface for QspiClock<'_> {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
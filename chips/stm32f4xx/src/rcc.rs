@@ -768,6 +768,20 @@ impl Rcc {
         self.registers.apb1enr.modify(APB1ENR::SPI3EN::CLEAR)
     }
 
+    // CAN1 clock
+
+    fn is_enabled_can1_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::CAN1EN)
+    }
+
+    fn enable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::SET)
+    }
+
+    fn disable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::CLEAR)
+    }
+
     // TIM2 clock
 
     fn is_enabled_tim2_clock(&self) -> bool {
@@ -936,6 +950,20 @@ impl Rcc {
         self.registers.ahb3enr.modify(AHB3ENR::FMCEN::CLEAR)
     }
 
+    // QSPI
+
+    fn is_enabled_qspi_clock(&self) -> bool {
+        self.registers.ahb3enr.is_set(AHB3ENR::QSPIEN)
+    }
+
+    fn enable_qspi_clock(&self) {
+        self.registers.ahb3enr.modify(AHB3ENR::QSPIEN::SET)
+    }
+
+    fn disable_qspi_clock(&self) {
+        self.registers.ahb3enr.modify(AHB3ENR::QSPIEN::CLEAR)
+    }
+
     // USART2 clock
 
     fn is_enabled_usart2_clock(&self) -> bool {
@@ -1045,6 +1073,7 @@ pub enum HCLK1 {
 /// Peripherals clocked by HCLK3
 pub enum HCLK3 {
     FMC,
+    QSPI,
 }
 
 /// Peripherals clocked by HCLK2
@@ -1060,6 +1089,7 @@ pub enum PCLK1 {
     USART3,
     SPI3,
     I2C1,
+    CAN1,
 }
 
 /// Peripherals clocked by PCLK2
@@ -1098,6 +1128,7 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.is_enabled_fmc_clock(),
+                HCLK3::QSPI => self.rcc.is_enabled_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => self.rcc.is_enabled_tim2_clock(),
@@ -1105,6 +1136,7 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::USART3 => self.rcc.is_enabled_usart3_clock(),
                 PCLK1::I2C1 => self.rcc.is_enabled_i2c1_clock(),
                 PCLK1::SPI3 => self.rcc.is_enabled_spi3_clock(),
+                PCLK1::CAN1 => self.rcc.is_enabled_can1_clock(),
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => self.rcc.is_enabled_adc1_clock(),
@@ -1154,6 +1186,7 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.enable_fmc_clock(),
+                HCLK3::QSPI => self.rcc.enable_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => {
@@ -1171,6 +1204,9 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::SPI3 => {
                     self.rcc.enable_spi3_clock();
                 }
+                PCLK1::CAN1 => {
+                    self.rcc.enable_can1_clock();
+                }
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => {
@@ -1224,6 +1260,7 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
             },
             PeripheralClockType::AHB3(ref v) => match v {
                 HCLK3::FMC => self.rcc.disable_fmc_clock(),
+                HCLK3::QSPI => self.rcc.disable_qspi_clock(),
             },
             PeripheralClockType::APB1(ref v) => match v {
                 PCLK1::TIM2 => {
@@ -1241,6 +1278,9 @@ impl<'a> ClockInterface for PeripheralClock<'a> {
                 PCLK1::SPI3 => {
                     self.rcc.disable_spi3_clock();
                 }
+                PCLK1::CAN1 => {
+                    self.rcc.disable_can1_clock();
+                }
             },
             PeripheralClockType::APB2(ref v) => match v {
                 PCLK2::ADC1 => {
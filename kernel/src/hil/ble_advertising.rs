@@ -56,6 +56,33 @@ pub trait BleAdvertisementDriver<'a> {
     fn set_transmit_client(&self, client: &'a dyn TxClient);
 }
 
+/// Answers to advertisements. These follow the advertisement after the
+/// inter frame space (T_IFS, 150 µs), which is too short for the kernel, so
+/// the radio sends them itself.
+///
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.4.3
+pub trait BleScanDriver {
+    /// Sets the SCAN_RSP PDU, header included, with which the radio answers
+    /// SCAN_REQs for the advertiser after each following
+    /// `transmit_advertisement` of an ADV_IND or ADV_SCAN_IND PDU. The radio
+    /// listens for a SCAN_REQ for a short while after the advertisement and
+    /// calls `transmit_event` once it answered one or the time ran out. An
+    /// empty `pdu` stops the answers.
+    ///
+    /// Returns ESIZE if the PDU does not fit in the radio and ENOSUPPORT if
+    /// the radio cannot answer in time.
+    fn set_scan_response(&self, pdu: &[u8]) -> ReturnCode;
+
+    /// Sets the scanner address with which the radio sends a SCAN_REQ for
+    /// each ADV_IND or ADV_SCAN_IND PDU the following `receive_advertisement`s
+    /// receive. The SCAN_RSP that answers the request, if any, is placed in
+    /// the buffer of `receive_event` right after the advertisement, and the
+    /// length passed covers both. `None` makes scanning passive.
+    ///
+    /// Returns ENOSUPPORT if the radio cannot answer in time.
+    fn set_scan_request_address(&self, address: Option<[u8; 6]>) -> ReturnCode;
+}
+
 pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
 }
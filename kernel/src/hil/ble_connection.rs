@@ -0,0 +1,105 @@
+//! Interface for the radio operations of a Bluetooth Low Energy link layer.
+//!
+//! Advertising (see `ble_advertising`) only needs single transmissions and
+//! receptions. Accepting and maintaining a connection also needs operations
+//! where a reception follows a transmission, or the other way around, after
+//! exactly the inter frame space (T_IFS, 150 µs). That is too tight for the
+//! kernel to meet in software, so the radio performs both halves of such an
+//! exchange as one operation.
+//!
+//! The PDUs passed through this interface are complete link layer PDUs: a
+//! two byte header, whose second byte is the payload length, followed by
+//! the payload. The preamble, access address and CRC are handled by the
+//! radio.
+//!
+//! Timing of the exchanges (advertising intervals, anchor points and receive
+//! windows) is left to the user of the interface, which aborts an operation
+//! with `abort()` when nothing was received in time. An operation that is
+//! not aborted completes once a PDU has been received, and answered in the
+//! case of `receive_transmit`.
+
+use crate::hil::ble_advertising::RadioChannel;
+use crate::returncode::ReturnCode;
+
+/// The access address of advertising channel PDUs.
+///
+/// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8e89bed6;
+
+/// The CRC initial value of advertising channel PDUs.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// The inter frame space, in microseconds.
+pub const T_IFS_US: u32 = 150;
+
+/// Offset of the NESN bit in the first header byte of a data channel PDU.
+pub const DATA_HEADER_NESN_OFFSET: u8 = 2;
+
+/// Offset of the SN bit in the first header byte of a data channel PDU.
+pub const DATA_HEADER_SN_OFFSET: u8 = 3;
+
+pub trait BleConnectionDriver<'a> {
+    /// Sets the access address and CRC initial value of the following
+    /// operations. These are `ADVERTISING_ACCESS_ADDRESS` and
+    /// `ADVERTISING_CRC_INIT` on the advertising channels, and the values
+    /// chosen by the master on the data channels of a connection.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+
+    /// Transmits the `tx_len` byte PDU in `tx_buf` on `channel`, then
+    /// listens for a PDU on the same channel until one is received or the
+    /// operation is aborted. Used to advertise and accept a request to the
+    /// advertisement.
+    ///
+    /// Returns EBUSY if an operation is in progress and ESIZE if the PDU
+    /// does not fit in the radio, along with `(tx_buf, rx_buf)`.
+    fn transmit_receive(
+        &self,
+        channel: RadioChannel,
+        tx_buf: &'static mut [u8],
+        tx_len: usize,
+        rx_buf: &'static mut [u8],
+    ) -> (ReturnCode, Option<(&'static mut [u8], &'static mut [u8])>);
+
+    /// Listens for a PDU on `channel` until one is received or the operation
+    /// is aborted, and answers a received PDU with the `tx_len` byte PDU in
+    /// `tx_buf` after T_IFS. Used by the slave of a connection in each
+    /// connection event.
+    ///
+    /// If the received PDU has a valid CRC and its SN bit equals the NESN bit
+    /// of `tx_buf`, the radio flips the NESN bit of `tx_buf` before sending
+    /// it, which acknowledges the received PDU. The answer is sent even if
+    /// the CRC of the received PDU is invalid, as the specification
+    /// requires.
+    ///
+    /// Returns EBUSY if an operation is in progress and ESIZE if the PDU
+    /// does not fit in the radio, along with `(tx_buf, rx_buf)`.
+    fn receive_transmit(
+        &self,
+        channel: RadioChannel,
+        rx_buf: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+        tx_len: usize,
+    ) -> (ReturnCode, Option<(&'static mut [u8], &'static mut [u8])>);
+
+    /// Aborts the operation in progress unless a PDU is being received, in
+    /// which case the operation completes as usual. Returns `tx_buf` and
+    /// `rx_buf` of the operation if it was aborted; the client is not called
+    /// back then.
+    fn abort(&self) -> Option<(&'static mut [u8], &'static mut [u8])>;
+
+    fn set_connection_client(&self, client: &'a dyn ConnectionClient);
+}
+
+pub trait ConnectionClient {
+    /// Called when an operation completes.
+    ///
+    /// `rx_len` is the length of the received PDU, header included, and
+    /// `rx_result` is SUCCESS if its CRC was valid and FAIL otherwise.
+    fn operation_done(
+        &self,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        rx_len: usize,
+        rx_result: ReturnCode,
+    );
+}
@@ -0,0 +1,272 @@
+//! Interface for CAN (Controller Area Network) controllers.
+//!
+//! A CAN frame carries an 11-bit standard or 29-bit extended identifier and
+//! up to eight bytes of data. A controller starts out disabled: the client
+//! configures the bit timing and the acceptance filters, then calls `enable`
+//! to join the bus. Only frames that match at least one active filter are
+//! passed to the receive client.
+//!
+//! Controllers count transmit and receive errors and leave the bus when too
+//! many errors occur. Changes of this error state are reported to the state
+//! client, so that a client can notice a bus-off condition instead of
+//! waiting forever for a transmission to complete.
+
+use crate::returncode::ReturnCode;
+
+/// The maximum number of data bytes in a classic CAN frame.
+pub const MAX_DATA_LEN: usize = 8;
+
+/// A frame identifier.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Id {
+    /// 11-bit identifier
+    Standard(u16),
+    /// 29-bit identifier
+    Extended(u32),
+}
+
+impl Id {
+    /// Whether the identifier fits in its format.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Id::Standard(id) => id < (1 << 11),
+            Id::Extended(id) => id < (1 << 29),
+        }
+    }
+
+    /// The identifier without its format.
+    pub fn raw(&self) -> u32 {
+        match *self {
+            Id::Standard(id) => id as u32,
+            Id::Extended(id) => id,
+        }
+    }
+}
+
+/// An acceptance filter. A frame matches if it uses the same identifier
+/// format as `id` and its identifier equals `id` in all bits set in `mask`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub id: Id,
+    pub mask: u32,
+}
+
+impl Filter {
+    /// A filter that matches exactly one identifier.
+    pub fn exact(id: Id) -> Filter {
+        Filter { id, mask: !0 }
+    }
+
+    /// Whether a frame with identifier `id` passes this filter.
+    pub fn matches(&self, id: Id) -> bool {
+        match (self.id, id) {
+            (Id::Standard(_), Id::Standard(_)) | (Id::Extended(_), Id::Extended(_)) => {
+                (self.id.raw() ^ id.raw()) & self.mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The timing of a single bit, in time quanta of `prescaler` controller
+/// clock cycles. Every bit starts with one quantum of synchronization,
+/// followed by `segment1` quanta before and `segment2` quanta after the
+/// sample point.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BitTiming {
+    pub prescaler: u16,
+    /// Propagation and phase 1 segments
+    pub segment1: u8,
+    /// Phase 2 segment
+    pub segment2: u8,
+    /// Synchronization jump width
+    pub sync_jump_width: u8,
+}
+
+impl BitTiming {
+    /// Compute a timing for `bitrate` with a controller clocked at
+    /// `clock_hz`, sampling at about 87.5% of the bit as CANopen and
+    /// DeviceNet recommend.
+    ///
+    /// The result fits the ranges every ISO 11898 controller supports:
+    /// prescaler 1 to 1024, `segment1` 1 to 16 and `segment2` 1 to 8. Returns
+    /// `None` if `bitrate` cannot be derived exactly from `clock_hz`.
+    pub fn for_bitrate(clock_hz: u32, bitrate: u32) -> Option<BitTiming> {
+        if bitrate == 0 {
+            return None;
+        }
+        // Prefer more quanta per bit for a finer sample point.
+        (8..=25u32).rev().find_map(|quanta| {
+            let cycles = bitrate.checked_mul(quanta)?;
+            if clock_hz % cycles != 0 {
+                return None;
+            }
+            let prescaler = clock_hz / cycles;
+            if prescaler == 0 || prescaler > 1024 {
+                return None;
+            }
+            let segment2 = (quanta + 4) / 8;
+            let segment1 = quanta - 1 - segment2;
+            if segment1 > 16 || segment2 > 8 {
+                return None;
+            }
+            Some(BitTiming {
+                prescaler: prescaler as u16,
+                segment1: segment1 as u8,
+                segment2: segment2 as u8,
+                sync_jump_width: 1,
+            })
+        })
+    }
+}
+
+/// Operating modes of a controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Send and receive on the bus.
+    Normal,
+    /// Receive the frames the controller sends, without driving the bus.
+    Loopback,
+    /// Receive without acknowledging frames or sending anything.
+    ListenOnly,
+}
+
+/// The fault confinement state of a controller.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorState {
+    /// Normal operation.
+    Active,
+    /// An error counter exceeded 127. The controller still takes part in
+    /// the bus but no longer signals errors it detects.
+    Passive,
+    /// The transmit error counter exceeded 255 and the controller left the
+    /// bus.
+    BusOff,
+}
+
+pub trait Can<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+    fn set_state_client(&self, client: &'a dyn StateClient);
+
+    /// Set the bit timing.
+    ///
+    /// - EBUSY: the controller is enabled.
+    /// - EINVAL: the timing is out of the range of the controller.
+    fn set_bit_timing(&self, timing: BitTiming) -> ReturnCode;
+
+    /// Set the bit timing for `bitrate` bits per second from the controller
+    /// clock.
+    ///
+    /// - EBUSY: the controller is enabled.
+    /// - EINVAL: the bitrate cannot be derived from the controller clock.
+    fn set_bitrate(&self, bitrate: u32) -> ReturnCode;
+
+    /// Set the operating mode used the next time the controller is enabled.
+    ///
+    /// - EBUSY: the controller is enabled.
+    fn set_mode(&self, mode: Mode) -> ReturnCode;
+
+    /// The number of acceptance filters the controller provides.
+    fn filter_count(&self) -> usize;
+
+    /// Install `filter` at `index`, or disable the filter at `index` if
+    /// `filter` is `None`. Filters can be changed while the controller is
+    /// enabled.
+    ///
+    /// - EINVAL: `index` is out of range or the filter identifier does not
+    ///   fit its format.
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> ReturnCode;
+
+    /// Join the bus with the configured timing, mode and filters.
+    ///
+    /// - EALREADY: the controller is already enabled.
+    /// - FAIL: the controller did not leave its configuration mode.
+    fn enable(&self) -> ReturnCode;
+
+    /// Leave the bus. A pending transmission is aborted and its buffer
+    /// returned with ECANCEL.
+    fn disable(&self) -> ReturnCode;
+
+    /// Send a data frame with identifier `id` and the first `len` bytes of
+    /// `buffer`. On success the buffer is returned in `transmit_complete`.
+    /// On failure it is returned immediately along with the reason:
+    ///
+    /// - EOFF: the controller is not enabled.
+    /// - EBUSY: a frame is already being sent.
+    /// - EINVAL: `id` does not fit its format.
+    /// - ESIZE: `len` is larger than `MAX_DATA_LEN` or the buffer.
+    fn send(
+        &self,
+        id: Id,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// The current fault confinement state.
+    fn error_state(&self) -> ErrorState;
+
+    /// The transmit and receive error counters.
+    fn error_counters(&self) -> (u8, u8);
+}
+
+pub trait TransmitClient {
+    /// Called when a frame passed to `send` was acknowledged on the bus
+    /// (SUCCESS), failed (FAIL) or was aborted (ECANCEL).
+    fn transmit_complete(&self, rc: ReturnCode, buffer: &'static mut [u8]);
+}
+
+pub trait ReceiveClient {
+    /// Called when a data frame that matches an active filter was received.
+    fn message_received(&self, id: Id, data: &[u8]);
+}
+
+pub trait StateClient {
+    /// Called when the fault confinement state of the controller changed.
+    fn state_changed(&self, state: ErrorState);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_match_format_and_masked_bits() {
+        let filter = Filter {
+            id: Id::Standard(0x120),
+            mask: 0x7f0,
+        };
+        assert!(filter.matches(Id::Standard(0x12f)));
+        assert!(!filter.matches(Id::Standard(0x130)));
+        assert!(!filter.matches(Id::Extended(0x120)));
+        assert!(Filter::exact(Id::Extended(0x1abc_def0)).matches(Id::Extended(0x1abc_def0)));
+        assert!(!Id::Standard(0x800).is_valid());
+        assert!(!Id::Extended(1 << 29).is_valid());
+    }
+
+    #[test]
+    fn bit_timing_for_common_bitrates() {
+        // 16 MHz clock at 500 kbit/s: 16 quanta of 2 cycles, sampling at 87.5%
+        assert_eq!(
+            BitTiming::for_bitrate(16_000_000, 500_000),
+            Some(BitTiming {
+                prescaler: 2,
+                segment1: 13,
+                segment2: 2,
+                sync_jump_width: 1,
+            })
+        );
+        // 21 quanta would need a segment 1 of 17
+        assert_eq!(
+            BitTiming::for_bitrate(42_000_000, 1_000_000),
+            Some(BitTiming {
+                prescaler: 3,
+                segment1: 11,
+                segment2: 2,
+                sync_jump_width: 1,
+            })
+        );
+        assert_eq!(BitTiming::for_bitrate(16_000_000, 3_000_000), None);
+        assert_eq!(BitTiming::for_bitrate(16_000_000, 0), None);
+    }
+}
@@ -0,0 +1,234 @@
+//! Interface for real-time clocks that keep the calendar date and time.
+//!
+//! Unlike the counters in `hil::time`, a `Clock` counts wall-clock time in
+//! seconds and keeps counting in every sleep state of the chip. Clocks that
+//! are backed by a battery or a retained power domain also keep the time
+//! across resets, so after boot a clock may already have a valid time or may
+//! report that it has not been set yet.
+//!
+//! Dates use the proleptic Gregorian calendar and times are UTC; time zones
+//! and daylight saving are left to userspace.
+//!
+//! A clock provides a single calendar alarm with a resolution of one second.
+//! The alarm fires once, when the clock reaches the alarm time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let now = clock.date_time()?;
+//! clock.set_alarm(DateTime::from_unix_time(now.unix_time() + 3600));
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// The earliest year a `DateTime` can hold.
+pub const MIN_YEAR: u16 = 1970;
+/// The latest year a `DateTime` can hold. Clocks may support fewer years.
+pub const MAX_YEAR: u16 = 4095;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DayOfWeek {
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+/// A calendar date and time of day.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to the number of days in the month
+    pub day: u8,
+    /// 0 to 23
+    pub hour: u8,
+    /// 0 to 59
+    pub minute: u8,
+    /// 0 to 59, leap seconds are not supported
+    pub second: u8,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Whether all fields are in range.
+    pub fn is_valid(&self) -> bool {
+        self.year >= MIN_YEAR
+            && self.year <= MAX_YEAR
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// The date and time `secs` seconds after 1970-01-01 00:00:00.
+    pub fn from_unix_time(secs: u64) -> DateTime {
+        let days = secs / SECONDS_PER_DAY;
+        let time = secs % SECONDS_PER_DAY;
+
+        // Days since 0000-03-01, which puts leap days at the end of each
+        // 400-year era and of each year (Howard Hinnant's civil_from_days).
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// The number of seconds since 1970-01-01 00:00:00.
+    pub fn unix_time(&self) -> u64 {
+        let year = self.year as u64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month = self.month as u64;
+        let day_of_year =
+            (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn day_of_week(&self) -> DayOfWeek {
+        // 1970-01-01 was a Thursday
+        match (self.unix_time() / SECONDS_PER_DAY + 4) % 7 {
+            0 => DayOfWeek::Sunday,
+            1 => DayOfWeek::Monday,
+            2 => DayOfWeek::Tuesday,
+            3 => DayOfWeek::Wednesday,
+            4 => DayOfWeek::Thursday,
+            5 => DayOfWeek::Friday,
+            _ => DayOfWeek::Saturday,
+        }
+    }
+}
+
+pub trait Clock<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// The current date and time.
+    ///
+    /// - EOFF: the clock has not been set since it lost power.
+    fn date_time(&self) -> Result<DateTime, ReturnCode>;
+
+    /// Set the current date and time. A pending alarm stays at its calendar
+    /// time.
+    ///
+    /// - EINVAL: `date_time` is invalid or out of the range of the clock.
+    fn set_date_time(&self, date_time: DateTime) -> ReturnCode;
+
+    /// Fire the alarm when the clock reaches `date_time`, replacing the
+    /// pending alarm.
+    ///
+    /// - EOFF: the clock has not been set.
+    /// - EINVAL: `date_time` is invalid or not in the future.
+    fn set_alarm(&self, date_time: DateTime) -> ReturnCode;
+
+    /// The time of the pending alarm.
+    fn get_alarm(&self) -> Option<DateTime>;
+
+    /// Cancel the pending alarm.
+    fn disarm_alarm(&self) -> ReturnCode;
+}
+
+pub trait Client {
+    /// Called when the clock reached the time of the alarm.
+    fn alarm(&self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_time_round_trip() {
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 58,
+        };
+        assert_eq!(leap_day.unix_time(), 1_709_251_198);
+        assert_eq!(DateTime::from_unix_time(1_709_251_198), leap_day);
+        assert_eq!(leap_day.day_of_week(), DayOfWeek::Thursday);
+
+        let epoch = DateTime::from_unix_time(0);
+        assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+        for secs in (0..4_102_444_800u64).step_by(86_399 * 37) {
+            let date_time = DateTime::from_unix_time(secs);
+            assert!(date_time.is_valid());
+            assert_eq!(date_time.unix_time(), secs);
+        }
+    }
+
+    #[test]
+    fn validates_fields() {
+        let date_time = DateTime {
+            year: 2100,
+            month: 2,
+            day: 29,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        assert!(!date_time.is_valid());
+        assert!(DateTime {
+            day: 28,
+            ..date_time
+        }
+        .is_valid());
+        assert!(!DateTime {
+            year: 1969,
+            day: 28,
+            ..date_time
+        }
+        .is_valid());
+        assert!(!DateTime {
+            day: 28,
+            hour: 24,
+            ..date_time
+        }
+        .is_valid());
+    }
+}
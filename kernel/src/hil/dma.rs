@@ -0,0 +1,240 @@
+//! Interface for direct memory access (DMA) controllers.
+//!
+//! A `DmaController` hands out channels. Each channel is allocated either for
+//! one peripheral request line, such as "USART2 TX", or for memory-to-memory
+//! copies. Request lines are chip-specific, so the controller names them with
+//! its own `Request` type and the board picks the one a driver needs. Not
+//! every controller can copy memory; those that cannot return ENOSUPPORT
+//! from `allocate_memory`. Which item widths are available also depends on
+//! the chip and the peripheral.
+//!
+//! Once allocated, a channel moves data between `&'static mut` buffers and
+//! its peripheral (or another buffer). The channel owns the buffers while a
+//! transfer is running and hands them back in the completion callback, or
+//! from `abort`.
+//!
+//! To keep a peripheral fed, a client can queue one more buffer while a
+//! transfer is running, and the channel starts on it when the running one
+//! completes. For longer chains, `transfer_list` takes a list of segments,
+//! one buffer each, and moves them back to back. Chips link the segments in
+//! hardware where they can (e.g. with double-buffer mode or scatter-gather
+//! descriptors), so there is no gap between them. The client hears about
+//! each segment in `segment_done` and gets the whole list back in
+//! `list_done`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let channel = dma.allocate(Dma1Peripheral::USART2_TX).unwrap();
+//! channel.set_client(driver);
+//! if let Err((rc, buf)) = channel.transfer(buf, len) {
+//!     // The buffer is returned immediately along with the reason.
+//! }
+//!
+//! let list = static_init!(
+//!     [Segment; 2],
+//!     [
+//!         Segment::new(header, header_len),
+//!         Segment::new(payload, payload_len),
+//!     ]
+//! );
+//! if let Err((rc, list)) = channel.transfer_list(list) {
+//!     // The list, with its buffers, is returned immediately.
+//! }
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// Size of each item a channel reads or writes in one bus access.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Width {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl Width {
+    /// Number of bytes in one item.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Width::Bits8 => 1,
+            Width::Bits16 => 2,
+            Width::Bits32 => 4,
+        }
+    }
+}
+
+/// One buffer of a `transfer_list` transfer. The channel moves the first
+/// `len` bytes of `buf`.
+pub struct Segment {
+    pub buf: Option<&'static mut [u8]>,
+    pub len: usize,
+}
+
+impl Segment {
+    pub fn empty() -> Segment {
+        Segment { buf: None, len: 0 }
+    }
+
+    pub fn new(buf: &'static mut [u8], len: usize) -> Segment {
+        Segment {
+            buf: Some(buf),
+            len: len,
+        }
+    }
+}
+
+pub trait DmaController<'a> {
+    /// Chip-specific identifier of a peripheral request line.
+    type Request: Copy;
+    /// The channels this controller hands out.
+    type Channel: DmaChannel<'a> + 'a;
+
+    /// Allocate the channel that serves `request`.
+    ///
+    /// - EBUSY: the channel is already allocated.
+    /// - ENOSUPPORT: no channel of this controller can serve `request`.
+    fn allocate(&'a self, request: Self::Request) -> Result<&'a Self::Channel, ReturnCode>;
+
+    /// Allocate a free channel for memory-to-memory copies.
+    ///
+    /// - EBUSY: all channels are allocated.
+    /// - ENOSUPPORT: the controller cannot copy memory.
+    fn allocate_memory(&'a self) -> Result<&'a Self::Channel, ReturnCode>;
+
+    /// Return `channel` to the controller so it can be allocated again.
+    ///
+    /// - EBUSY: a transfer is still running; abort it first.
+    /// - EALREADY: the channel was not allocated.
+    fn release(&self, channel: &Self::Channel) -> ReturnCode;
+}
+
+pub trait DmaChannel<'a> {
+    /// Set the client to be used for callbacks.
+    fn set_client(&self, client: &'a dyn DmaClient);
+
+    /// Set the item width used for the following transfers. Lengths must be
+    /// a multiple of the width, and buffers aligned to it. Channels start
+    /// with `Width::Bits8`.
+    ///
+    /// - EBUSY: a transfer is running.
+    /// - ENOSUPPORT: the channel or its peripheral cannot use this width.
+    fn set_width(&self, width: Width) -> ReturnCode;
+
+    /// Enable or disable the `half_complete` callback for the following
+    /// transfers.
+    ///
+    /// - ENOSUPPORT: the hardware cannot report half-completed transfers.
+    fn set_half_complete(&self, enable: bool) -> ReturnCode;
+
+    /// Move the first `len` bytes of `buf` to or from the peripheral of this
+    /// channel, in the direction given by its request line.
+    ///
+    /// On success the buffer is returned in `transfer_done`. On failure it
+    /// is returned immediately along with the reason:
+    ///
+    /// - EOFF: the channel is not allocated.
+    /// - EBUSY: a transfer is already running.
+    /// - EINVAL: `len` is zero, larger than the buffer or the channel can
+    ///   transfer at once, or not a multiple of the width, or the buffer is
+    ///   not aligned to the width.
+    /// - ENOSUPPORT: the channel was allocated for memory-to-memory copies.
+    fn transfer(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Queue the first `len` bytes of `buf` to be transferred as soon as the
+    /// running transfer completes. If no transfer is running this is the
+    /// same as `transfer`. Each buffer is returned in its own
+    /// `transfer_done` callback.
+    ///
+    /// - EBUSY: a buffer is already queued.
+    /// - EOFF, EINVAL, ENOSUPPORT: as for `transfer`.
+    fn queue(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Move the segments of `list` to or from the peripheral of this
+    /// channel, one after the other and in order. The buffers stay in the
+    /// list, which is returned in `list_done`.
+    ///
+    /// - EOFF, EBUSY, ENOSUPPORT: as for `transfer`.
+    /// - EINVAL: the list is empty, a segment has no buffer or a length
+    ///   `transfer` would reject, or the segments break a restriction of the
+    ///   chip, such as having to be the same length.
+    fn transfer_list(
+        &self,
+        list: &'static mut [Segment],
+    ) -> Result<(), (ReturnCode, &'static mut [Segment])>;
+
+    /// Copy the first `len` bytes of `src` into `dst`. Only channels
+    /// returned by `allocate_memory` can copy.
+    ///
+    /// On success both buffers are returned in `copy_done`. On failure they
+    /// are returned immediately along with the reason:
+    ///
+    /// - EOFF: the channel is not allocated.
+    /// - EBUSY: a transfer is already running.
+    /// - EINVAL: `len` is zero, larger than either buffer or not a multiple
+    ///   of the width.
+    /// - ENOSUPPORT: the channel was allocated for a peripheral.
+    fn copy(
+        &self,
+        src: &'static mut [u8],
+        dst: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])>;
+
+    /// Stop the running transfer without a callback.
+    ///
+    /// Returns the number of bytes moved before the transfer stopped and the
+    /// buffers the channel held: the running buffer and the queued buffer
+    /// for `transfer`, or the source and destination for `copy`. A list
+    /// transfer is stopped with `abort_list` instead.
+    fn abort(&self) -> (usize, Option<&'static mut [u8]>, Option<&'static mut [u8]>);
+
+    /// Stop the running list transfer without a callback.
+    ///
+    /// Returns the number of segments that were completely transferred and
+    /// the list, or `None` if no list transfer was running.
+    fn abort_list(&self) -> Option<(usize, &'static mut [Segment])>;
+
+    /// Whether a transfer is running.
+    fn is_busy(&self) -> bool;
+}
+
+pub trait DmaClient {
+    /// Called when a buffer passed to `transfer` or `queue` has been
+    /// transferred. `len` is the number of bytes moved. If another buffer
+    /// was queued, the channel is already working on it.
+    fn transfer_done(&self, buf: &'static mut [u8], len: usize, rc: ReturnCode);
+
+    /// Called when a memory-to-memory copy has completed.
+    fn copy_done(
+        &self,
+        _src: &'static mut [u8],
+        _dst: &'static mut [u8],
+        _len: usize,
+        _rc: ReturnCode,
+    ) {
+    }
+
+    /// Called when segment `index` of a `transfer_list` transfer has been
+    /// transferred. `len` is the number of bytes moved. Chips that can only
+    /// interrupt at the end of the list make all of these calls right
+    /// before `list_done`.
+    fn segment_done(&self, _index: usize, _len: usize) {}
+
+    /// Called when all segments of a `transfer_list` transfer have been
+    /// transferred.
+    fn list_done(&self, _list: &'static mut [Segment], _rc: ReturnCode) {}
+
+    /// Called when the first half of the running buffer has been
+    /// transferred, if enabled with `set_half_complete`.
+    fn half_complete(&self) {}
+}
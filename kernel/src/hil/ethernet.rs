@@ -0,0 +1,47 @@
+//! Interface for Ethernet MAC peripherals.
+//!
+//! An `EthernetAdapter` sends and receives complete Ethernet frames,
+//! starting with the destination MAC address and ending with the last byte
+//! of payload. The preamble, start frame delimiter and frame check sequence
+//! are handled by the hardware.
+//!
+//! Received frames are passed to the client in a buffer owned by the
+//! adapter. The client must hand this buffer back with `return_rx_buffer`
+//! before the adapter can deliver the next frame.
+
+use crate::returncode::ReturnCode;
+
+/// Maximum length of an Ethernet frame without the frame check sequence,
+/// including a single 802.1Q tag.
+pub const MAX_FRAME_LEN: usize = 1518;
+
+pub trait EthernetAdapter<'a> {
+    /// Set the client to be used for callbacks.
+    fn set_client(&self, client: &'a dyn EthernetAdapterClient);
+
+    /// Transmit the first `len` bytes of `packet` as a single frame.
+    ///
+    /// On success the buffer is returned in the `tx_done` callback. On
+    /// failure it is returned immediately along with the reason:
+    ///
+    /// - EBUSY: a previous frame is still being transmitted.
+    /// - EINVAL: `len` is larger than the buffer.
+    /// - ESIZE: the frame does not fit in the hardware transmit buffer.
+    fn transmit(
+        &self,
+        packet: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Return the buffer passed to `rx_packet`, enabling reception of the
+    /// next frame.
+    fn return_rx_buffer(&self, rx_buffer: &'static mut [u8]);
+}
+
+pub trait EthernetAdapterClient {
+    /// Called when a frame passed to `transmit` has been sent.
+    fn tx_done(&self, rc: ReturnCode, packet_buffer: &'static mut [u8]);
+
+    /// Called when a frame of `len` bytes has been received into `packet`.
+    fn rx_packet(&self, packet: &'static mut [u8], len: usize);
+}
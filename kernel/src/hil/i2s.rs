@@ -0,0 +1,149 @@
+//! Interface for digital audio interfaces that stream PCM samples, such as
+//! I2S or PDM microphone controllers.
+//!
+//! A stream runs continuously once started. To avoid gaps, the client keeps
+//! two buffers queued per direction: while the controller plays or fills one
+//! buffer, the next one is already waiting. Each buffer is handed back when
+//! the controller is done with it, and the client refills it and queues it
+//! again.
+//!
+//! If the controller finishes a buffer and no other buffer is queued, the
+//! stream stops and the client is told whether transmission ran out of
+//! samples (underrun) or reception ran out of space (overrun). Controllers
+//! that keep using the last buffer until they stopped return it with
+//! ECANCEL.
+//!
+//! Samples are stored little-endian, one sample per `SampleWidth::bytes`
+//! bytes, with the left sample first for stereo streams. Controllers that
+//! move buffers with DMA may require buffers to be 4-byte aligned.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! i2s.configure(Format { width: SampleWidth::Bits16, channels: Channels::Stereo, sample_rate: 16000 })?;
+//! i2s.queue_transmit(buffer_a, buffer_a.len());
+//! i2s.queue_transmit(buffer_b, buffer_b.len());
+//! i2s.start();
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// Size of each sample in memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleWidth {
+    Bits8,
+    Bits16,
+    /// 24-bit samples, stored in the low bytes of 32-bit words
+    Bits24,
+}
+
+impl SampleWidth {
+    /// Bytes one sample occupies in a buffer.
+    pub fn bytes(&self) -> usize {
+        match self {
+            SampleWidth::Bits8 => 1,
+            SampleWidth::Bits16 => 2,
+            SampleWidth::Bits24 => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channels {
+    Stereo,
+    /// Mono, on the left channel
+    Left,
+    /// Mono, on the right channel
+    Right,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Format {
+    pub width: SampleWidth,
+    pub channels: Channels,
+    /// Frames per second
+    pub sample_rate: u32,
+}
+
+impl Format {
+    /// Bytes one frame (a sample for every channel) occupies in a buffer.
+    pub fn frame_bytes(&self) -> usize {
+        match self.channels {
+            Channels::Stereo => 2 * self.width.bytes(),
+            Channels::Left | Channels::Right => self.width.bytes(),
+        }
+    }
+}
+
+/// Why a stream stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopCause {
+    /// The client called `stop`.
+    Requested,
+    /// No transmit buffer was queued in time.
+    Underrun,
+    /// No receive buffer was queued in time.
+    Overrun,
+}
+
+pub trait I2s<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Configure the sample format. The controller picks the sample rate
+    /// closest to `format.sample_rate` it can generate and returns it.
+    ///
+    /// - EBUSY: the stream is running.
+    /// - ENOSUPPORT: the controller does not support the width or channels.
+    fn configure(&self, format: Format) -> Result<u32, ReturnCode>;
+
+    /// Queue `len` bytes of `buffer` to be played. Up to two buffers can be
+    /// queued before the stream starts, and one while it runs, next to the
+    /// buffer being played.
+    ///
+    /// - EBUSY: no more buffers can be queued.
+    /// - EINVAL: `len` is zero, not a multiple of the frame size, larger
+    ///   than the buffer or than the controller supports, or the buffer does
+    ///   not meet the alignment or length requirements of the controller.
+    /// - ENOSUPPORT: the controller cannot transmit.
+    fn queue_transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Queue `len` bytes of `buffer` to be filled with received samples,
+    /// with the same rules as `queue_transmit`.
+    fn queue_receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Start streaming in every direction with a queued buffer.
+    ///
+    /// - EALREADY: the stream is running.
+    /// - EOFF: no buffers are queued.
+    fn start(&self) -> ReturnCode;
+
+    /// Stop streaming. All queued buffers are returned with ECANCEL, then
+    /// `stopped` is called with `StopCause::Requested`.
+    ///
+    /// - EALREADY: the stream is not running.
+    fn stop(&self) -> ReturnCode;
+
+    fn is_running(&self) -> bool;
+}
+
+pub trait Client {
+    /// A transmit buffer was played (SUCCESS) or the stream stopped before
+    /// it was played completely (ECANCEL).
+    fn transmit_done(&self, buffer: &'static mut [u8], rc: ReturnCode);
+
+    /// A receive buffer was filled (SUCCESS) or the stream stopped before it
+    /// was filled completely (ECANCEL).
+    fn receive_done(&self, buffer: &'static mut [u8], rc: ReturnCode);
+
+    /// The stream stopped, after all buffers were returned.
+    fn stopped(&self, cause: StopCause);
+}
@@ -3,21 +3,29 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod ble_connection;
 pub mod bus8080;
+pub mod can;
 pub mod crc;
 pub mod dac;
+pub mod date_time;
 pub mod digest;
+pub mod dma;
 pub mod eic;
 pub mod entropy;
+pub mod ethernet;
 pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod i2s;
 pub mod kv_system;
 pub mod led;
 pub mod log;
 pub mod nonvolatile_storage;
+pub mod public_key_crypto;
 pub mod pwm;
+pub mod qspi;
 pub mod radio;
 pub mod rng;
 pub mod screen;
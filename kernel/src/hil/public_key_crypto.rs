@@ -0,0 +1,136 @@
+//! Interfaces for public-key cryptography on the NIST P-256 curve: ECDSA
+//! signatures and elliptic-curve Diffie-Hellman key agreement.
+//!
+//! All values are big-endian byte strings. A private key is a 32-byte
+//! scalar between 1 and the group order minus 1. A public key is the
+//! uncompressed point without the leading 0x04 byte, that is the 32-byte X
+//! coordinate followed by the 32-byte Y coordinate. A signature is `r`
+//! followed by `s`, 32 bytes each.
+//!
+//! Signing and verification take the hash of the message, not the message
+//! itself, so they can be used with any 256-bit hash such as SHA-256.
+//!
+//! Private keys are passed by reference and copied by the implementation for
+//! the duration of the operation; implementations clear their copy when the
+//! operation completes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! // Sign the SHA-256 digest of a message
+//! ecdsa.set_client(client);
+//! ecdsa.sign(&private_key, digest, signature)?;
+//!
+//! // Later, in `EcdsaClient::sign_done()`, `signature` holds `r || s`
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// Length of a private key.
+pub const PRIVATE_KEY_LEN: usize = 32;
+/// Length of a public key, X followed by Y.
+pub const PUBLIC_KEY_LEN: usize = 64;
+/// Length of the hash that is signed.
+pub const HASH_LEN: usize = 32;
+/// Length of a signature, `r` followed by `s`.
+pub const SIGNATURE_LEN: usize = 64;
+/// Length of a shared secret, the X coordinate of the shared point.
+pub const SHARED_SECRET_LEN: usize = 32;
+
+/// ECDSA signatures with P-256 keys.
+pub trait EcdsaP256<'a> {
+    fn set_client(&self, client: &'a dyn EcdsaClient);
+
+    /// Sign `hash` with `private_key`, storing the signature in `signature`.
+    ///
+    /// - EBUSY: an operation is running.
+    /// - EINVAL: the private key is out of range.
+    fn sign(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    >;
+
+    /// Check that `signature` is a signature of `hash` by the private key of
+    /// `public_key`.
+    ///
+    /// - EBUSY: an operation is running.
+    /// - EINVAL: the public key is not a point of the curve.
+    fn verify(
+        &self,
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8; HASH_LEN],
+            &'static mut [u8; SIGNATURE_LEN],
+        ),
+    >;
+}
+
+pub trait EcdsaClient {
+    /// A signature completed. On error `signature` holds no valid data.
+    fn sign_done(
+        &self,
+        rc: ReturnCode,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    );
+
+    /// A verification completed, with `Ok(true)` if the signature is valid.
+    fn verify_done(
+        &self,
+        result: Result<bool, ReturnCode>,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    );
+}
+
+/// Elliptic-curve Diffie-Hellman with P-256 keys.
+pub trait EcdhP256<'a> {
+    fn set_client(&self, client: &'a dyn EcdhClient);
+
+    /// Compute the public key of `private_key`.
+    ///
+    /// - EBUSY: an operation is running.
+    /// - EINVAL: the private key is out of range.
+    fn public_key(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; PUBLIC_KEY_LEN])>;
+
+    /// Compute the secret shared between `private_key` and the owner of
+    /// `peer_public_key`.
+    ///
+    /// - EBUSY: an operation is running.
+    /// - EINVAL: the private key is out of range, or the public key is not a
+    ///   point of the curve.
+    fn shared_secret(
+        &self,
+        private_key: &[u8; PRIVATE_KEY_LEN],
+        peer_public_key: &[u8; PUBLIC_KEY_LEN],
+        secret: &'static mut [u8; SHARED_SECRET_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; SHARED_SECRET_LEN])>;
+}
+
+pub trait EcdhClient {
+    /// A public key computation completed.
+    fn public_key_done(&self, rc: ReturnCode, public_key: &'static mut [u8; PUBLIC_KEY_LEN]);
+
+    /// A shared secret computation completed. On error `secret` holds no
+    /// valid data.
+    fn shared_secret_done(&self, rc: ReturnCode, secret: &'static mut [u8; SHARED_SECRET_LEN]);
+}
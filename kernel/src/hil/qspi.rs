@@ -0,0 +1,158 @@
+//! Interface for quad-SPI controllers, which talk to serial flash chips over
+//! one, two or four data lines.
+//!
+//! A command is a one-byte instruction, optionally followed by an address,
+//! dummy cycles and a data phase. `Mode` selects how many lines each phase
+//! uses, named like in flash datasheets: `QuadIo` (1-4-4) sends the
+//! instruction on one line and the address and data on four.
+//!
+//! Controllers can also map the flash into the address space, so that reads
+//! of the region are turned into read commands by the hardware
+//! (memory-mapped or execute-in-place mode). Commands can still be run while
+//! the region is mapped, but reads of the region stall or return wrong data
+//! while the flash is busy programming or erasing.
+//!
+//! Controllers with fixed command sets, or that move data with DMA, may
+//! reject commands, lengths or buffers they cannot handle with ENOSUPPORT or
+//! EINVAL. Keeping buffers 4-byte aligned, and addresses and lengths of
+//! multi-line transfers multiples of 4, works with all of them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! // Read 256 bytes at 0x1000 with the quad I/O read command of the flash
+//! qspi.configure(32_000_000, 8 * 1024 * 1024, AddressSize::Bits24)?;
+//! qspi.read(
+//!     Command {
+//!         instruction: 0xEB,
+//!         address: Some(0x1000),
+//!         dummy_cycles: 6,
+//!         mode: Mode::QuadIo,
+//!     },
+//!     buffer,
+//!     256,
+//! );
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// Lines used by the instruction, address and data phases of a command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// 1-1-1
+    Single,
+    /// 1-1-2
+    DualOutput,
+    /// 1-2-2
+    DualIo,
+    /// 1-1-4
+    QuadOutput,
+    /// 1-4-4
+    QuadIo,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressSize {
+    Bits24,
+    Bits32,
+}
+
+impl AddressSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            AddressSize::Bits24 => 3,
+            AddressSize::Bits32 => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Command {
+    pub instruction: u8,
+    pub address: Option<u32>,
+    /// Clock cycles between the address and the data, including mode bits
+    pub dummy_cycles: u8,
+    pub mode: Mode,
+}
+
+impl Command {
+    /// A single-line command without address or dummy cycles.
+    pub const fn simple(instruction: u8) -> Command {
+        Command {
+            instruction,
+            address: None,
+            dummy_cycles: 0,
+            mode: Mode::Single,
+        }
+    }
+}
+
+/// Where the flash is mapped into the address space.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MappedRegion {
+    pub address: usize,
+    pub len: usize,
+}
+
+pub trait Qspi<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Configure the clock and the flash. The controller picks the fastest
+    /// clock not above `rate` it can generate and returns it. `size` is the
+    /// size of the flash in bytes, a power of two.
+    ///
+    /// - EBUSY: a command is running.
+    /// - EINVAL: the size is not supported.
+    fn configure(
+        &self,
+        rate: u32,
+        size: usize,
+        address_size: AddressSize,
+    ) -> Result<u32, ReturnCode>;
+
+    /// Run a command without data phase, like write enable or erase.
+    ///
+    /// - EBUSY: a command is running.
+    /// - ENOSUPPORT: the controller cannot run the command.
+    fn command(&self, command: Command) -> ReturnCode;
+
+    /// Run a command that reads `len` bytes into `buffer`.
+    ///
+    /// - EBUSY: a command is running.
+    /// - EINVAL: `len` is zero or larger than the buffer, or the controller
+    ///   cannot transfer it.
+    /// - ENOSUPPORT: the controller cannot run the command.
+    fn read(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Run a command that writes `len` bytes from `buffer`, with the same
+    /// errors as `read`.
+    fn write(
+        &self,
+        command: Command,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Map the flash into the address space, reading it with `read`. The
+    /// address of the command is ignored.
+    ///
+    /// - EBUSY: a command is running.
+    /// - ENOSUPPORT: the controller cannot read with the command.
+    fn map(&self, read: Command) -> Result<MappedRegion, ReturnCode>;
+
+    /// Remove the flash from the address space.
+    ///
+    /// - EALREADY: the flash is not mapped.
+    fn unmap(&self) -> ReturnCode;
+}
+
+pub trait Client {
+    /// A command completed. `buffer` is the buffer of a `read` or `write`.
+    fn command_done(&self, buffer: Option<&'static mut [u8]>, rc: ReturnCode);
+}
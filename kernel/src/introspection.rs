@@ -15,6 +15,7 @@ use core::cell::Cell;
 use crate::callback::AppId;
 use crate::capabilities::ProcessManagementCapability;
 use crate::common::cells::NumericCellExt;
+use crate::power::StateResidency;
 use crate::process;
 use crate::sched::Kernel;
 
@@ -159,4 +160,15 @@ impl KernelInfo {
         });
         count.get()
     }
+
+    /// Returns how often and for how long the chip was in sleep state
+    /// `state`, as an index into `Chip::sleep_states`. Returns `None` if no
+    /// power manager is set or it does not track this state.
+    pub fn sleep_residency(
+        &self,
+        state: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Option<StateResidency> {
+        self.kernel.sleep_residency(state)
+    }
 }
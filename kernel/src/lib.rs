@@ -96,6 +96,7 @@ pub mod debug;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod power;
 pub mod syscall;
 
 mod callback;
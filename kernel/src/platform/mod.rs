@@ -119,6 +119,21 @@ pub trait Chip {
     /// chip and resumes the scheduler.
     fn sleep(&self);
 
+    /// The sleep states this chip supports, ordered from the shallowest to
+    /// the deepest. Chips that do not describe their states only ever use
+    /// `sleep`.
+    fn sleep_states(&self) -> &'static [crate::power::SleepState] {
+        &[]
+    }
+
+    /// Enter the sleep state at index `state` of `sleep_states`, or a
+    /// shallower one if the hardware does not allow `state` right now.
+    /// Returns the index of the state the chip was in.
+    fn sleep_in(&self, _state: usize) -> usize {
+        self.sleep();
+        0
+    }
+
     /// Run a function in an atomic state, which means that interrupts are
     /// disabled so that an interrupt will not fire during the passed in
     /// function's execution.
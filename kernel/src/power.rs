@@ -0,0 +1,260 @@
+//! Power management: choosing how deeply the chip sleeps.
+//!
+//! Chips describe the sleep states they support with `Chip::sleep_states`,
+//! ordered from the shallowest to the deepest state. Deeper states save more
+//! power but take longer to wake up from, and usually stop clocks that some
+//! peripherals need.
+//!
+//! Drivers and capsules that cannot tolerate a long wakeup latency register a
+//! `PowerConstraint` with the board's `PowerManager` and set the largest
+//! latency they can accept while they are active, e.g. while a transfer that
+//! must be serviced quickly is in progress. When the kernel has no work, it
+//! asks the power manager for the deepest state that satisfies every
+//! constraint and passes it to `Chip::sleep_in`. The chip may still enter a
+//! shallower state if its hardware requires it, for example because a
+//! peripheral clock is still running.
+//!
+//! The power manager also counts how often and for how long the chip was in
+//! each state. This is reported through `introspection::KernelInfo`. Sleeps
+//! longer than the wraparound period of the timer used for the measurement
+//! are undercounted.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let pm = static_init!(
+//!     PowerManager<'static, nrf52::rtc::Rtc>,
+//!     PowerManager::new(&base_peripherals.rtc)
+//! );
+//! pm.add_constraint(&radio_constraint);
+//! board_kernel.set_power_manager(pm);
+//! ```
+
+use core::cell::Cell;
+
+use crate::common::cells::OptionalCell;
+use crate::common::{List, ListLink, ListNode};
+use crate::hil::time::{Frequency, Ticks, Time};
+
+/// The maximum number of sleep states the power manager keeps statistics
+/// for. Deeper states of a chip are never selected.
+pub const MAX_SLEEP_STATES: usize = 4;
+
+/// A sleep state a chip can enter when the kernel has no work.
+pub struct SleepState {
+    /// Human-readable name of the state, used for introspection.
+    pub name: &'static str,
+    /// The worst-case time in microseconds from the wakeup event until the
+    /// chip executes instructions again.
+    pub wakeup_latency_us: u32,
+}
+
+/// How often and for how long the chip was in one sleep state.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StateResidency {
+    pub entries: u32,
+    pub time_us: u64,
+}
+
+/// A limit on the wakeup latency a driver or capsule can tolerate.
+pub struct PowerConstraint<'a> {
+    max_latency_us: Cell<u32>,
+    next: ListLink<'a, PowerConstraint<'a>>,
+}
+
+impl<'a> PowerConstraint<'a> {
+    pub const fn new() -> PowerConstraint<'a> {
+        PowerConstraint {
+            max_latency_us: Cell::new(u32::MAX),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Only allow sleep states that wake up within `us` microseconds.
+    pub fn limit_latency(&self, us: u32) {
+        self.max_latency_us.set(us);
+    }
+
+    /// Only allow the shallowest sleep state.
+    pub fn block_deep_sleep(&self) {
+        self.max_latency_us.set(0);
+    }
+
+    /// Allow every sleep state again.
+    pub fn release(&self) {
+        self.max_latency_us.set(u32::MAX);
+    }
+
+    pub fn max_latency_us(&self) -> u32 {
+        self.max_latency_us.get()
+    }
+}
+
+impl<'a> ListNode<'a, PowerConstraint<'a>> for PowerConstraint<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, PowerConstraint<'a>> {
+        &self.next
+    }
+}
+
+/// The interface the kernel loop uses to select sleep states and account
+/// for the time spent in them.
+pub trait PowerManagement {
+    /// Return the index of the deepest state in `states` that satisfies all
+    /// registered constraints.
+    fn select_sleep_state(&self, states: &[SleepState]) -> usize;
+
+    /// Called right before the chip goes to sleep.
+    fn sleep_started(&self);
+
+    /// Called right after the chip woke up from sleep state `state`.
+    fn sleep_ended(&self, state: usize);
+
+    /// How often and for how long the chip was in sleep state `state`.
+    fn residency(&self, state: usize) -> Option<StateResidency>;
+}
+
+/// Selects sleep states from registered constraints and measures the time
+/// spent in each state with a `Time` source that keeps running in all
+/// sleep states, such as an RTC.
+pub struct PowerManager<'a, T: Time> {
+    time: &'a T,
+    constraints: List<'a, PowerConstraint<'a>>,
+    sleep_start: OptionalCell<T::Ticks>,
+    residency: [Cell<StateResidency>; MAX_SLEEP_STATES],
+}
+
+impl<'a, T: Time> PowerManager<'a, T> {
+    pub fn new(time: &'a T) -> PowerManager<'a, T> {
+        PowerManager {
+            time,
+            constraints: List::new(),
+            sleep_start: OptionalCell::empty(),
+            residency: Default::default(),
+        }
+    }
+
+    pub fn add_constraint(&self, constraint: &'a PowerConstraint<'a>) {
+        self.constraints.push_head(constraint);
+    }
+
+    /// The smallest wakeup latency any registered constraint accepts.
+    pub fn max_latency_us(&self) -> u32 {
+        self.constraints
+            .iter()
+            .map(|constraint| constraint.max_latency_us())
+            .min()
+            .unwrap_or(u32::MAX)
+    }
+}
+
+impl<'a, T: Time> PowerManagement for PowerManager<'a, T> {
+    fn select_sleep_state(&self, states: &[SleepState]) -> usize {
+        let max_latency = self.max_latency_us();
+        // The shallowest state is always allowed, so the kernel can still
+        // wait for interrupts.
+        states
+            .iter()
+            .take(MAX_SLEEP_STATES)
+            .rposition(|state| state.wakeup_latency_us <= max_latency)
+            .unwrap_or(0)
+    }
+
+    fn sleep_started(&self) {
+        self.sleep_start.set(self.time.now());
+    }
+
+    fn sleep_ended(&self, state: usize) {
+        let start = match self.sleep_start.take() {
+            Some(start) => start,
+            None => return,
+        };
+        let ticks = self.time.now().wrapping_sub(start).into_u32() as u64;
+        let us = ticks * 1_000_000 / T::Frequency::frequency() as u64;
+        if let Some(residency) = self.residency.get(state) {
+            let mut r = residency.get();
+            r.entries = r.entries.wrapping_add(1);
+            r.time_us += us;
+            residency.set(r);
+        }
+    }
+
+    fn residency(&self, state: usize) -> Option<StateResidency> {
+        self.residency.get(state).map(|residency| residency.get())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hil::time::{Freq1MHz, Ticks32};
+
+    struct FakeTime(Cell<u32>);
+
+    impl Time for FakeTime {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            self.0.get().into()
+        }
+    }
+
+    const STATES: [SleepState; 3] = [
+        SleepState {
+            name: "sleep",
+            wakeup_latency_us: 0,
+        },
+        SleepState {
+            name: "standby",
+            wakeup_latency_us: 50,
+        },
+        SleepState {
+            name: "deep sleep",
+            wakeup_latency_us: 1000,
+        },
+    ];
+
+    #[test]
+    fn selects_deepest_allowed_state() {
+        let time = FakeTime(Cell::new(0));
+        let pm = PowerManager::new(&time);
+        let a = PowerConstraint::new();
+        let b = PowerConstraint::new();
+        pm.add_constraint(&a);
+        pm.add_constraint(&b);
+
+        assert_eq!(pm.select_sleep_state(&STATES), 2);
+        a.limit_latency(100);
+        assert_eq!(pm.select_sleep_state(&STATES), 1);
+        b.block_deep_sleep();
+        assert_eq!(pm.select_sleep_state(&STATES), 0);
+        b.release();
+        a.release();
+        assert_eq!(pm.select_sleep_state(&STATES), 2);
+        assert_eq!(pm.select_sleep_state(&[]), 0);
+    }
+
+    #[test]
+    fn accounts_time_per_state() {
+        let time = FakeTime(Cell::new(u32::MAX - 9));
+        let pm = PowerManager::new(&time);
+
+        pm.sleep_started();
+        time.0.set(10);
+        pm.sleep_ended(1);
+        pm.sleep_started();
+        time.0.set(30);
+        pm.sleep_ended(1);
+
+        assert_eq!(
+            pm.residency(1),
+            Some(StateResidency {
+                entries: 2,
+                time_us: 40
+            })
+        );
+        assert_eq!(pm.residency(0), Some(StateResidency::default()));
+        assert_eq!(pm.residency(MAX_SLEEP_STATES), None);
+    }
+}
@@ -13,7 +13,7 @@ use core::ptr::NonNull;
 
 use crate::callback::{AppId, Callback, CallbackId};
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
@@ -24,6 +24,7 @@ use crate::platform::mpu::MPU;
 use crate::platform::scheduler_timer::SchedulerTimer;
 use crate::platform::watchdog::WatchDog;
 use crate::platform::{Chip, Platform};
+use crate::power::{PowerManagement, StateResidency};
 use crate::process::{self, Task};
 use crate::returncode::ReturnCode;
 use crate::syscall::{ContextSwitchReason, Syscall};
@@ -141,6 +142,9 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// Selects the sleep state when there is no work, if the board set one.
+    power_manager: OptionalCell<&'static dyn PowerManagement>,
 }
 
 /// Enum used to inform scheduler why a process stopped executing (aka why
@@ -176,9 +180,24 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            power_manager: OptionalCell::empty(),
         }
     }
 
+    /// Let `power_manager` select the sleep state the chip enters when there
+    /// is no work. Without a power manager the kernel always asks for the
+    /// deepest state the chip supports.
+    pub fn set_power_manager(&self, power_manager: &'static dyn PowerManagement) {
+        self.power_manager.set(power_manager);
+    }
+
+    /// How often and for how long the chip was in sleep state `state`, if a
+    /// power manager is set.
+    pub(crate) fn sleep_residency(&self, state: usize) -> Option<StateResidency> {
+        self.power_manager
+            .and_then(|power_manager| power_manager.residency(state))
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     ///
     /// This is only exposed in the core kernel crate.
@@ -507,8 +526,17 @@ impl Kernel {
                                         && !DynamicDeferredCall::global_instance_calls_pending()
                                             .unwrap_or(false)
                                     {
+                                        let states = chip.sleep_states();
+                                        let state = self.power_manager.map_or(
+                                            states.len().saturating_sub(1),
+                                            |power_manager| {
+                                                power_manager.select_sleep_state(states)
+                                            },
+                                        );
                                         chip.watchdog().suspend();
-                                        chip.sleep();
+                                        self.power_manager.map(|pm| pm.sleep_started());
+                                        let state = chip.sleep_in(state);
+                                        self.power_manager.map(|pm| pm.sleep_ended(state));
                                         chip.watchdog().resume();
                                     }
                                 });